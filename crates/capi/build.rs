@@ -0,0 +1,23 @@
+//! With the `regenerate-header` feature, rewrite `include/fakenotify.h`
+//! from the exported functions.
+//!
+//! The header is checked in and `test_header_is_current` fails when it no
+//! longer matches, so ordinary builds never touch the source tree.
+
+fn main() {
+    #[cfg(feature = "regenerate-header")]
+    {
+        let crate_dir = std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+        println!("cargo:rerun-if-changed=src/lib.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+
+        let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+            .expect("cbindgen.toml is valid");
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(crate_dir.join("src").join("lib.rs"))
+            .generate()
+            .expect("header generates")
+            .write_to_file(crate_dir.join("include").join("fakenotify.h"));
+    }
+}
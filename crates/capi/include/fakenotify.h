@@ -0,0 +1,140 @@
+#ifndef FAKENOTIFY_H
+#define FAKENOTIFY_H
+
+/* Generated by cbindgen from crates/capi/src/lib.rs. Do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ Opaque connection handle.
+ */
+typedef struct fakenotify_client fakenotify_client;
+
+/*
+ A single event, mirroring the fixed part of `struct inotify_event`.
+ */
+typedef struct fakenotify_event {
+  /*
+   Watch descriptor.
+   */
+  int32_t wd;
+  /*
+   Event mask (`IN_*` flags).
+   */
+  uint32_t mask;
+  /*
+   Cookie pairing rename halves (0 if unused).
+   */
+  uint32_t cookie;
+  /*
+   Length of the name written to the caller's buffer, or needed for it
+   when that is too small, excluding the null terminator (0 if the
+   event has no name).
+   */
+  uint32_t name_len;
+} fakenotify_event;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Connect to the daemon.
+
+ `socket_path` may be NULL to connect as `Client::connect` does: to
+ `FAKENOTIFY_SOCKET`, else `$XDG_RUNTIME_DIR/fakenotify.sock`, else
+ `/run/fakenotify/fakenotify.sock`, sending `FAKENOTIFY_PATH_MAP` as the
+ client's path map if set. Unlike the preload, it neither discovers
+ other daemon instances nor starts a daemon when none is running.
+
+ Returns NULL and sets `errno` on failure. Release the handle with
+ [`fakenotify_close`].
+
+ # Safety
+
+ `socket_path` must be NULL or a valid null-terminated string.
+ */
+struct fakenotify_client *fakenotify_connect(const char *socket_path);
+
+/*
+ Add a watch. Returns the watch descriptor, or -1 on failure.
+
+ # Safety
+
+ `client` must come from [`fakenotify_connect`] and `path` must be a valid
+ null-terminated string.
+ */
+int fakenotify_add_watch(struct fakenotify_client *client, const char *path, uint32_t mask);
+
+/*
+ Remove a watch. Returns 0 on success, or -1 on failure.
+
+ # Safety
+
+ `client` must come from [`fakenotify_connect`].
+ */
+int fakenotify_remove_watch(struct fakenotify_client *client, int wd);
+
+/*
+ Block until the next event arrives.
+
+ The event name (if any) is copied into `name` as a null-terminated
+ string. If `name_cap` is too small for the name, the call fails with
+ `ENAMETOOLONG` and leaves the event queued, with `event->name_len` set
+ to the length it needs; `NAME_MAX + 1` bytes is always enough. `name`
+ may be NULL when `name_cap` is 0.
+
+ Returns 0 on success, or -1 on failure.
+
+ # Safety
+
+ `client` must come from [`fakenotify_connect`], `event` must point to
+ writable memory, and `name` must point to at least `name_cap` writable
+ bytes.
+ */
+int fakenotify_read_event(struct fakenotify_client *client,
+                          struct fakenotify_event *event,
+                          char *name,
+                          uintptr_t name_cap);
+
+/*
+ Whether [`fakenotify_read_event`] has an event to return without
+ waiting on the socket. Returns 1 if so, 0 if not, or -1 on failure.
+
+ # Safety
+
+ `client` must come from [`fakenotify_connect`].
+ */
+int fakenotify_pending(struct fakenotify_client *client);
+
+/*
+ The underlying socket fd, for use with poll/select/epoll.
+
+ Readability means a frame has arrived; it may be a response rather than
+ an event, so [`fakenotify_read_event`] can still block briefly. Events
+ that arrive while another call waits for its answer are queued without
+ leaving the fd readable: read events until [`fakenotify_pending`]
+ returns 0 before polling.
+
+ # Safety
+
+ `client` must come from [`fakenotify_connect`].
+ */
+int fakenotify_fd(const struct fakenotify_client *client);
+
+/*
+ Close the connection and free the handle. Passing NULL is a no-op.
+
+ # Safety
+
+ `client` must come from [`fakenotify_connect`] and must not be used
+ afterwards.
+ */
+void fakenotify_close(struct fakenotify_client *client);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FAKENOTIFY_H */
@@ -0,0 +1,481 @@
+//! FakeNotify C API
+//!
+//! A C ABI over [`fakenotify_client`] so C/C++ programs (Samba VFS modules,
+//! custom indexers, ...) can talk to fakenotifyd explicitly instead of
+//! relying on LD_PRELOAD interception. The header `include/fakenotify.h` is
+//! generated by cbindgen from this file with the `regenerate-header`
+//! feature.
+//!
+//! # Conventions
+//!
+//! Functions returning `int` return `-1` on failure and set `errno`.
+//! Daemon-side rejections set the errno real inotify would report for the
+//! same failure; IO failures keep their OS error code where one exists and
+//! fall back to `EIO`. A panic inside the library is caught at the
+//! boundary and reported as a failure with `EIO`.
+//!
+//! A `fakenotify_client` is not thread-safe; callers must serialize access.
+
+use fakenotify_client::{Client, ClientError};
+use fakenotify_protocol::EventMask;
+use std::ffi::{CStr, c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::ptr;
+
+/// Opaque connection handle.
+pub struct FakenotifyClient {
+    inner: Client,
+}
+
+/// A single event, mirroring the fixed part of `struct inotify_event`.
+#[repr(C)]
+pub struct FakenotifyEvent {
+    /// Watch descriptor.
+    pub wd: i32,
+    /// Event mask (`IN_*` flags).
+    pub mask: u32,
+    /// Cookie pairing rename halves (0 if unused).
+    pub cookie: u32,
+    /// Length of the name written to the caller's buffer, or needed for it
+    /// when that is too small, excluding the null terminator (0 if the
+    /// event has no name).
+    pub name_len: u32,
+}
+
+/// The calling thread's `errno`
+#[cfg(target_vendor = "apple")]
+fn errno_location() -> *mut c_int {
+    // SAFETY: __error has no preconditions
+    unsafe { libc::__error() }
+}
+
+/// The calling thread's `errno`
+#[cfg(not(target_vendor = "apple"))]
+fn errno_location() -> *mut c_int {
+    // SAFETY: __errno_location has no preconditions
+    unsafe { libc::__errno_location() }
+}
+
+fn set_errno(err: c_int) {
+    // SAFETY: errno_location returns a valid pointer to the thread-local errno
+    unsafe {
+        *errno_location() = err;
+    }
+}
+
+fn errno_for(err: &ClientError) -> c_int {
+    match err {
+        ClientError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        ClientError::Daemon { code, .. } => code.errno(),
+        ClientError::Protocol(_) | ClientError::UnexpectedResponse(_) => libc::EIO,
+    }
+}
+
+fn fail(err: &ClientError) -> c_int {
+    set_errno(errno_for(err));
+    -1
+}
+
+/// Run an entry point's body, returning `on_panic` with `errno` set to
+/// `EIO` if it panics, so a panic never unwinds into C
+fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        set_errno(libc::EIO);
+        on_panic
+    })
+}
+
+/// Connect to the daemon.
+///
+/// `socket_path` may be NULL to connect as `Client::connect` does: to
+/// `FAKENOTIFY_SOCKET`, else `$XDG_RUNTIME_DIR/fakenotify.sock`, else
+/// `/run/fakenotify/fakenotify.sock`, sending `FAKENOTIFY_PATH_MAP` as the
+/// client's path map if set. Unlike the preload, it neither discovers
+/// other daemon instances nor starts a daemon when none is running.
+///
+/// Returns NULL and sets `errno` on failure. Release the handle with
+/// [`fakenotify_close`].
+///
+/// # Safety
+///
+/// `socket_path` must be NULL or a valid null-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_connect(socket_path: *const c_char) -> *mut FakenotifyClient {
+    guard(ptr::null_mut(), || {
+        let result = if socket_path.is_null() {
+            Client::connect()
+        } else {
+            // SAFETY: Caller guarantees socket_path is a valid C string
+            let bytes = unsafe { CStr::from_ptr(socket_path) }.to_bytes();
+            Client::connect_to(Path::new(std::ffi::OsStr::from_bytes(bytes)))
+        };
+
+        match result {
+            Ok(inner) => Box::into_raw(Box::new(FakenotifyClient { inner })),
+            Err(e) => {
+                fail(&e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Add a watch. Returns the watch descriptor, or -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from [`fakenotify_connect`] and `path` must be a valid
+/// null-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_add_watch(
+    client: *mut FakenotifyClient,
+    path: *const c_char,
+    mask: u32,
+) -> c_int {
+    guard(-1, || {
+        // SAFETY: Caller guarantees client is a live handle
+        let Some(client) = (unsafe { client.as_mut() }) else {
+            set_errno(libc::EBADF);
+            return -1;
+        };
+        if path.is_null() {
+            set_errno(libc::EFAULT);
+            return -1;
+        }
+
+        // SAFETY: Caller guarantees path is a valid C string
+        let bytes = unsafe { CStr::from_ptr(path) }.to_bytes();
+        let path = Path::new(std::ffi::OsStr::from_bytes(bytes));
+
+        match client
+            .inner
+            .add_watch(path, EventMask::from_bits_retain(mask))
+        {
+            Ok(wd) => wd,
+            Err(e) => fail(&e),
+        }
+    })
+}
+
+/// Remove a watch. Returns 0 on success, or -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from [`fakenotify_connect`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_remove_watch(
+    client: *mut FakenotifyClient,
+    wd: c_int,
+) -> c_int {
+    guard(-1, || {
+        // SAFETY: Caller guarantees client is a live handle
+        let Some(client) = (unsafe { client.as_mut() }) else {
+            set_errno(libc::EBADF);
+            return -1;
+        };
+
+        match client.inner.remove_watch(wd) {
+            Ok(()) => 0,
+            Err(e) => fail(&e),
+        }
+    })
+}
+
+/// Block until the next event arrives.
+///
+/// The event name (if any) is copied into `name` as a null-terminated
+/// string. If `name_cap` is too small for the name, the call fails with
+/// `ENAMETOOLONG` and leaves the event queued, with `event->name_len` set
+/// to the length it needs; `NAME_MAX + 1` bytes is always enough. `name`
+/// may be NULL when `name_cap` is 0.
+///
+/// Returns 0 on success, or -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from [`fakenotify_connect`], `event` must point to
+/// writable memory, and `name` must point to at least `name_cap` writable
+/// bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_read_event(
+    client: *mut FakenotifyClient,
+    event: *mut FakenotifyEvent,
+    name: *mut c_char,
+    name_cap: usize,
+) -> c_int {
+    guard(-1, || {
+        // SAFETY: Caller guarantees client is a live handle
+        let Some(client) = (unsafe { client.as_mut() }) else {
+            set_errno(libc::EBADF);
+            return -1;
+        };
+        if event.is_null() || (name_cap > 0 && name.is_null()) {
+            set_errno(libc::EFAULT);
+            return -1;
+        }
+
+        let received = match client.inner.read_event() {
+            Ok(ev) => ev,
+            Err(e) => return fail(&e),
+        };
+
+        let name_len = received.name.as_ref().map_or(0, |n| n.len());
+        if name_len > 0 && name_len + 1 > name_cap {
+            // SAFETY: event is non-null and the caller guarantees it is writable
+            unsafe {
+                event.write(FakenotifyEvent {
+                    wd: received.wd,
+                    mask: received.mask.bits(),
+                    cookie: received.cookie,
+                    name_len: name_len as u32,
+                });
+            }
+            // Like a short read of an inotify fd, the event stays to be read
+            client.inner.unread_event(received);
+            set_errno(libc::ENAMETOOLONG);
+            return -1;
+        }
+        let name_bytes = received.name.as_ref().map(|n| n.as_bytes()).unwrap_or(&[]);
+        if name_cap > 0 {
+            // SAFETY: name has at least name_cap bytes, and name_bytes.len() + 1 <= name_cap
+            unsafe {
+                ptr::copy_nonoverlapping(name_bytes.as_ptr(), name.cast::<u8>(), name_bytes.len());
+                *name.add(name_bytes.len()) = 0;
+            }
+        }
+
+        // SAFETY: event is non-null and the caller guarantees it is writable
+        unsafe {
+            event.write(FakenotifyEvent {
+                wd: received.wd,
+                mask: received.mask.bits(),
+                cookie: received.cookie,
+                name_len: name_bytes.len() as u32,
+            });
+        }
+
+        0
+    })
+}
+
+/// Whether [`fakenotify_read_event`] has an event to return without
+/// waiting on the socket. Returns 1 if so, 0 if not, or -1 on failure.
+///
+/// # Safety
+///
+/// `client` must come from [`fakenotify_connect`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_pending(client: *mut FakenotifyClient) -> c_int {
+    guard(-1, || {
+        // SAFETY: Caller guarantees client is a live handle
+        let Some(client) = (unsafe { client.as_mut() }) else {
+            set_errno(libc::EBADF);
+            return -1;
+        };
+
+        match client.inner.has_pending_event() {
+            Ok(pending) => c_int::from(pending),
+            Err(e) => fail(&e),
+        }
+    })
+}
+
+/// The underlying socket fd, for use with poll/select/epoll.
+///
+/// Readability means a frame has arrived; it may be a response rather than
+/// an event, so [`fakenotify_read_event`] can still block briefly. Events
+/// that arrive while another call waits for its answer are queued without
+/// leaving the fd readable: read events until [`fakenotify_pending`]
+/// returns 0 before polling.
+///
+/// # Safety
+///
+/// `client` must come from [`fakenotify_connect`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_fd(client: *const FakenotifyClient) -> c_int {
+    guard(-1, || {
+        // SAFETY: Caller guarantees client is a live handle
+        match unsafe { client.as_ref() } {
+            Some(client) => client.inner.as_raw_fd(),
+            None => {
+                set_errno(libc::EBADF);
+                -1
+            }
+        }
+    })
+}
+
+/// Close the connection and free the handle. Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `client` must come from [`fakenotify_connect`] and must not be used
+/// afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fakenotify_close(client: *mut FakenotifyClient) {
+    guard((), || {
+        if !client.is_null() {
+            // SAFETY: Caller guarantees client came from Box::into_raw in fakenotify_connect
+            drop(unsafe { Box::from_raw(client) });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_handles_set_ebadf() {
+        // SAFETY: NULL handles are explicitly supported
+        unsafe {
+            assert_eq!(fakenotify_remove_watch(ptr::null_mut(), 1), -1);
+            assert_eq!(last_errno(), libc::EBADF);
+            assert_eq!(fakenotify_fd(ptr::null()), -1);
+            assert_eq!(fakenotify_pending(ptr::null_mut()), -1);
+            fakenotify_close(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_panics_do_not_unwind_into_c() {
+        let result = guard(-1, || -> c_int { panic!("bug") });
+        assert_eq!(result, -1);
+        assert_eq!(last_errno(), libc::EIO);
+        assert_eq!(guard(-1, || 3), 3);
+    }
+
+    #[test]
+    fn test_header_is_current() {
+        let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+        let mut generated = Vec::new();
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(crate_dir.join("src").join("lib.rs"))
+            .generate()
+            .unwrap()
+            .write(&mut generated);
+        let checked_in = std::fs::read(crate_dir.join("include").join("fakenotify.h")).unwrap();
+        assert!(
+            generated == checked_in,
+            "include/fakenotify.h is stale; rebuild with --features regenerate-header"
+        );
+    }
+
+    #[test]
+    fn test_daemon_rejections_set_the_kernel_errno() {
+        use fakenotify_protocol::ErrorCode;
+
+        for (code, errno) in [
+            (ErrorCode::NotFound, libc::ENOENT),
+            (ErrorCode::NotDirectory, libc::ENOTDIR),
+            (ErrorCode::PermissionDenied, libc::EACCES),
+            (ErrorCode::WatchLimit, libc::ENOSPC),
+            (ErrorCode::InstanceLimit, libc::EMFILE),
+        ] {
+            let err = ClientError::Daemon {
+                code,
+                message: String::new(),
+            };
+            assert_eq!(errno_for(&err), errno, "{code:?}");
+        }
+    }
+
+    #[test]
+    fn test_connect_missing_socket_fails() {
+        let path = c"/nonexistent/fakenotify.sock";
+        // SAFETY: path is a valid C string
+        let client = unsafe { fakenotify_connect(path.as_ptr()) };
+        assert!(client.is_null());
+        assert_eq!(last_errno(), libc::ENOENT);
+    }
+
+    #[test]
+    fn test_queued_events_are_kept_and_reported() {
+        use fakenotify_protocol::{FramedMessage, InotifyEvent, Response};
+        use std::io::{Read, Write};
+
+        let path =
+            std::env::temp_dir().join(format!("fakenotify-capi-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let registered = Response::ClientRegistered {
+                client_id: 1,
+                session: String::new(),
+                capabilities: 0,
+            };
+            let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0)
+                .to_bytes_with_name(b"episode.mkv");
+            stream
+                .write_all(&FramedMessage::frame(&registered.to_bytes().unwrap()))
+                .unwrap();
+            stream
+                .write_all(&FramedMessage::frame_event(&event))
+                .unwrap();
+            // Another event ahead of the answer to an add_watch
+            let mut request = [0u8; 256];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(&FramedMessage::frame_event(&event))
+                .unwrap();
+            let added = Response::WatchAdded { wd: 1 };
+            stream
+                .write_all(&FramedMessage::frame(&added.to_bytes().unwrap()))
+                .unwrap();
+            stream
+        });
+
+        let socket = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut event = FakenotifyEvent {
+            wd: 0,
+            mask: 0,
+            cookie: 0,
+            name_len: 0,
+        };
+        let mut name = [0 as c_char; 64];
+        // SAFETY: socket is a valid C string, and event and name are writable
+        unsafe {
+            let client = fakenotify_connect(socket.as_ptr());
+            assert!(!client.is_null());
+            assert_eq!(
+                fakenotify_read_event(client, &mut event, ptr::null_mut(), 4),
+                -1
+            );
+            assert_eq!(last_errno(), libc::EFAULT);
+            assert_eq!(
+                fakenotify_read_event(client, &mut event, name.as_mut_ptr(), 4),
+                -1
+            );
+            assert_eq!(last_errno(), libc::ENAMETOOLONG);
+            assert_eq!(event.name_len, 11);
+            let cap = event.name_len as usize + 1;
+            assert_eq!(
+                fakenotify_read_event(client, &mut event, name.as_mut_ptr(), cap),
+                0
+            );
+            assert_eq!(CStr::from_ptr(name.as_ptr()), c"episode.mkv");
+
+            assert_eq!(fakenotify_add_watch(client, c"/mnt".as_ptr(), 0), 1);
+            assert_eq!(fakenotify_pending(client), 1);
+            assert_eq!(
+                fakenotify_read_event(client, &mut event, name.as_mut_ptr(), cap),
+                0
+            );
+            assert_eq!(fakenotify_pending(client), 0);
+            fakenotify_close(client);
+        }
+
+        drop(server.join().unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn last_errno() -> c_int {
+        std::io::Error::last_os_error().raw_os_error().unwrap()
+    }
+}
@@ -0,0 +1,1329 @@
+//! FakeNotify Client - Blocking client for the fakenotifyd daemon.
+//!
+//! This crate talks to the daemon explicitly over its socket, for
+//! programs that would rather not rely on LD_PRELOAD interception. It is the
+//! foundation for the C and language bindings.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use fakenotify_client::Client;
+//! use fakenotify_protocol::EventMask;
+//!
+//! let mut client = Client::connect().unwrap();
+//! let wd = client
+//!     .add_watch("/mnt/media", EventMask::IN_CREATE | EventMask::IN_DELETE)
+//!     .unwrap();
+//!
+//! loop {
+//!     let event = client.read_event().unwrap();
+//!     println!("wd={} mask={:?} name={:?}", event.wd, event.mask, event.name);
+//! #   let _ = wd;
+//! }
+//! ```
+//!
+//! # Resuming after a restart
+//!
+//! A watch added with [`Client::add_watch_since`] numbers its events, and
+//! the daemon keeps the latest in a journal. A consumer that stores its
+//! [`Client::cursor`] once it has handled an event gets everything after it
+//! again when it restarts, so no event is lost even if some arrive twice:
+//!
+//! ```no_run
+//! use fakenotify_client::{Client, CursorFile};
+//! use fakenotify_protocol::EventMask;
+//!
+//! let store = CursorFile::new("/var/lib/indexer/cursor");
+//! let mut client = Client::connect().unwrap();
+//! let watch = client
+//!     .add_watch_since("/mnt/media", EventMask::IN_ALL_EVENTS, store.load().unwrap())
+//!     .unwrap();
+//! if watch.gap {
+//!     // Events were missed: rescan /mnt/media
+//! }
+//!
+//! loop {
+//!     let event = client.read_event().unwrap();
+//!     // ... index the change, then remember it is done
+//!     store.store(client.cursor(watch.wd).unwrap()).unwrap();
+//! #   let _ = event;
+//! }
+//! ```
+
+use fakenotify_protocol::{
+    Capabilities, ClientInfo, Cursor, Endpoint, ErrorCode, EventBuffer, EventMask, FrameDecoder,
+    FrameKind, FramedMessage, InotifyEvent, NameFilter, PATH_MAP_ENV_VAR, PROTOCOL_VERSION,
+    PathMapping, ProtocolError, Request, Response, WatchEntry, WatchInfo, decompress_payload,
+    get_socket_path_with_xdg_fallback, parse_path_map,
+};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Optional protocol features this client can decode.
+const CAPABILITIES: Capabilities = Capabilities::EVENT_BATCH
+    .union(Capabilities::COMPRESSION)
+    .union(Capabilities::RESUME)
+    .union(Capabilities::JOURNAL)
+    .union(Capabilities::MASK_CLAMP);
+
+/// Error type for client operations.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// IO error talking to the daemon.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Malformed message on the wire.
+    #[error("protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+
+    /// The daemon rejected the request.
+    #[error("daemon error: {message}")]
+    Daemon {
+        /// Why, as the errno-mappable code the daemon gave.
+        code: ErrorCode,
+        /// Human-readable error message.
+        message: String,
+    },
+
+    /// The daemon sent a response that does not match the request.
+    #[error("unexpected response: {0:?}")]
+    UnexpectedResponse(Box<Response>),
+}
+
+/// A decoded inotify event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Watch descriptor the event belongs to.
+    pub wd: i32,
+    /// Event mask.
+    pub mask: EventMask,
+    /// Cookie pairing rename halves (0 if unused).
+    pub cookie: u32,
+    /// Name relative to the watched path, if any.
+    pub name: Option<OsString>,
+    /// Number of the event in its watch's journal, for watches added with
+    /// [`Client::add_watch_since`].
+    pub seq: Option<u64>,
+}
+
+impl Event {
+    /// Decode an event from its raw inotify byte representation.
+    ///
+    /// Returns `None` if the buffer is truncated.
+    #[must_use]
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let (header, name) = EventBuffer::new(buf).next()?;
+        Some(Self::from_parts(&header, name))
+    }
+
+    fn from_parts(header: &InotifyEvent, name: Option<OsString>) -> Self {
+        Self {
+            wd: header.wd,
+            mask: header.event_mask(),
+            cookie: header.cookie,
+            name,
+            seq: None,
+        }
+    }
+}
+
+/// A watch added with [`Client::add_watch_since`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournaledWatch {
+    /// Watch descriptor.
+    pub wd: i32,
+    /// Events missed since the cursor, which arrive first.
+    pub replayed: u64,
+    /// The journal's position as the watch was added.
+    pub cursor: Cursor,
+    /// Some events since the cursor are lost, because the daemon restarted
+    /// or no longer keeps them; what the watch covers should be rescanned.
+    pub gap: bool,
+}
+
+/// A [`Cursor`] kept in a file, for a consumer to find its place again
+/// after restarting.
+#[derive(Debug, Clone)]
+pub struct CursorFile {
+    path: PathBuf,
+}
+
+impl CursorFile {
+    /// Keep the cursor at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The stored cursor, or `None` if none was stored yet.
+    pub fn load(&self) -> Result<Option<Cursor>, ClientError> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(text) => Ok(Some(text.parse()?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the stored cursor.
+    ///
+    /// The cursor is written beside the file and renamed over it, so a
+    /// crash leaves either the old cursor or the new one.
+    pub fn store(&self, cursor: Cursor) -> Result<(), ClientError> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{cursor}")?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Socket to the daemon: a Unix socket, or TCP for a daemon on another OS.
+///
+/// A vsock connection to a VM's host is held as a `UnixStream`, which reads
+/// and writes any stream socket.
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn connect(endpoint: &Endpoint) -> std::io::Result<Self> {
+        Ok(match endpoint {
+            Endpoint::Unix(path) => Self::Unix(UnixStream::connect(path)?),
+            Endpoint::Tcp(addr) => {
+                let stream = TcpStream::connect(addr.as_str())?;
+                stream.set_nodelay(true)?;
+                Self::Tcp(stream)
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            &Endpoint::Vsock { cid, port } => Self::Unix(UnixStream::from(
+                fakenotify_protocol::connect_vsock(cid, port)?,
+            )),
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Endpoint::Vsock { .. } => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "vsock is only available on Linux",
+                ));
+            }
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Self::Unix(s) => s.set_read_timeout(timeout),
+            Self::Tcp(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Self::Unix(s) => s.set_nonblocking(nonblocking),
+            Self::Tcp(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Unix(s) => s.read(buf),
+            Self::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Unix(s) => s.write(buf),
+            Self::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Unix(s) => s.flush(),
+            Self::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Unix(s) => s.as_raw_fd(),
+            Self::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+/// Blocking connection to the daemon.
+pub struct Client {
+    stream: Stream,
+    /// Bytes read off the stream that do not yet make up a whole frame.
+    decoder: FrameDecoder,
+    /// Where the daemon was reached, for reconnecting.
+    endpoint: Endpoint,
+    client_id: u64,
+    /// Secret to resume this client with after a lost connection.
+    session: String,
+    /// Features in use with the daemon.
+    capabilities: Capabilities,
+    /// Events that arrived while waiting for a response.
+    pending: VecDeque<Event>,
+    /// Position of the last event read on each journaled watch.
+    cursors: HashMap<i32, Cursor>,
+    /// Masks of watches added without some of the events asked for.
+    clamped: HashMap<i32, EventMask>,
+}
+
+impl Client {
+    /// Connect using the default socket resolution (`FAKENOTIFY_SOCKET`,
+    /// then `$XDG_RUNTIME_DIR`, then `/run/fakenotify/fakenotify.sock`).
+    ///
+    /// If `FAKENOTIFY_PATH_MAP` is set, it is sent as this client's path map.
+    pub fn connect() -> Result<Self, ClientError> {
+        let mut client = Self::connect_to(get_socket_path_with_xdg_fallback())?;
+        let path_map = std::env::var(PATH_MAP_ENV_VAR)
+            .map(|value| parse_path_map(&value))
+            .unwrap_or_default();
+        if !path_map.is_empty() {
+            client.set_path_map(path_map)?;
+        }
+        Ok(client)
+    }
+
+    /// Connect to a daemon listening on the given socket path, on a TCP
+    /// address given as `tcp://host:port`, or over vsock at
+    /// `vsock://cid:port`.
+    pub fn connect_to(socket_path: impl AsRef<Path>) -> Result<Self, ClientError> {
+        Self::open(Endpoint::from_path(socket_path.as_ref()))
+    }
+
+    /// Connect to `socket_path` and take over the client holding `session`
+    /// after its connection was lost, keeping its watches and settings and
+    /// receiving the events queued meanwhile.
+    ///
+    /// Only works within the daemon's `session_timeout` of the loss.
+    pub fn resume(socket_path: impl AsRef<Path>, session: &str) -> Result<Self, ClientError> {
+        let mut client = Self::connect_to(socket_path)?;
+        client.resume_session(session)?;
+        Ok(client)
+    }
+
+    /// Replace a lost connection with a new one to the same daemon,
+    /// resuming this client's session. Events received but not yet read
+    /// are kept.
+    pub fn reconnect(&mut self) -> Result<(), ClientError> {
+        let mut client = Self::open(self.endpoint.clone())?;
+        client.resume_session(&self.session)?;
+        client.pending = std::mem::take(&mut self.pending)
+            .into_iter()
+            .chain(client.pending)
+            .collect();
+        client.cursors = std::mem::take(&mut self.cursors);
+        client.clamped = std::mem::take(&mut self.clamped);
+        *self = client;
+        Ok(())
+    }
+
+    fn open(endpoint: Endpoint) -> Result<Self, ClientError> {
+        let stream = Stream::connect(&endpoint)?;
+        let mut client = Self {
+            stream,
+            decoder: FrameDecoder::new(),
+            endpoint,
+            client_id: 0,
+            session: String::new(),
+            capabilities: Capabilities::empty(),
+            pending: VecDeque::new(),
+            cursors: HashMap::new(),
+            clamped: HashMap::new(),
+        };
+
+        // The daemon greets every connection with its client ID
+        match client.read_response()? {
+            Response::ClientRegistered {
+                client_id, session, ..
+            } => {
+                client.client_id = client_id;
+                client.session = session;
+            }
+            other => return Err(unexpected(other)),
+        }
+
+        Ok(client)
+    }
+
+    fn resume_session(&mut self, session: &str) -> Result<(), ClientError> {
+        let request = Request::Resume {
+            session: session.to_string(),
+        };
+        match self.request(&request)? {
+            Response::Resumed {
+                client_id,
+                capabilities,
+            } => {
+                self.client_id = client_id;
+                self.session = session.to_string();
+                self.capabilities = Capabilities::from_bits_truncate(capabilities);
+                Ok(())
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// The client ID assigned by the daemon.
+    #[must_use]
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    /// Secret naming this client to the daemon, for [`Client::resume`].
+    #[must_use]
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Optional protocol features in use with the daemon; none until
+    /// [`Client::set_info`] negotiates them.
+    #[must_use]
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Add a watch and return its watch descriptor.
+    ///
+    /// Events the daemon denies on `path` are left out of the watch; see
+    /// [`clamped_mask`](Self::clamped_mask).
+    pub fn add_watch(
+        &mut self,
+        path: impl AsRef<Path>,
+        mask: EventMask,
+    ) -> Result<i32, ClientError> {
+        let request = Request::AddWatch {
+            path: path.as_ref().to_path_buf(),
+            mask: mask.bits(),
+        };
+        let response = self.request(&request)?;
+        self.added(response)
+    }
+
+    fn added(&mut self, response: Response) -> Result<i32, ClientError> {
+        match response {
+            Response::WatchAdded { wd } => {
+                self.clamped.remove(&wd);
+                Ok(wd)
+            }
+            Response::WatchClamped { wd, mask } => {
+                self.clamped.insert(wd, EventMask::from_bits_truncate(mask));
+                Ok(wd)
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// The mask a watch was added with, if the daemon left out events it
+    /// denies on the watched path; `None` if it got all it asked for.
+    #[must_use]
+    pub fn clamped_mask(&self, wd: i32) -> Option<EventMask> {
+        self.clamped.get(&wd).copied()
+    }
+
+    /// Add a watch whose events are numbered and journaled by the daemon,
+    /// first receiving those after `since`, where a previous run of the
+    /// consumer left off; `None` starts from now.
+    ///
+    /// Events replayed and ones that were already on their way may overlap;
+    /// those read before are skipped, so each is read once per run.
+    pub fn add_watch_since(
+        &mut self,
+        path: impl AsRef<Path>,
+        mask: EventMask,
+        since: Option<Cursor>,
+    ) -> Result<JournaledWatch, ClientError> {
+        let request = Request::AddWatchSince {
+            path: path.as_ref().to_path_buf(),
+            mask: mask.bits(),
+            since,
+        };
+        match self.request(&request)? {
+            Response::WatchAddedSince {
+                wd,
+                replayed,
+                cursor,
+                gap,
+            } => {
+                // Replayed events lead up to `cursor`; without a cursor
+                // there are none, and only later events are new
+                let read = match since {
+                    Some(since) if since.epoch == cursor.epoch => since.seq,
+                    Some(_) => 0,
+                    None => cursor.seq,
+                };
+                self.cursors.insert(
+                    wd,
+                    Cursor {
+                        epoch: cursor.epoch,
+                        seq: read,
+                    },
+                );
+                Ok(JournaledWatch {
+                    wd,
+                    replayed,
+                    cursor,
+                    gap,
+                })
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Position of the last event read on a watch added with
+    /// [`add_watch_since`](Self::add_watch_since), to store once it is
+    /// handled and resume from after a restart.
+    #[must_use]
+    pub fn cursor(&self, wd: i32) -> Option<Cursor> {
+        self.cursors.get(&wd).copied()
+    }
+
+    /// Remove a watch by descriptor.
+    pub fn remove_watch(&mut self, wd: i32) -> Result<(), ClientError> {
+        match self.request(&Request::RemoveWatch { wd })? {
+            Response::WatchRemoved => {
+                self.cursors.remove(&wd);
+                self.clamped.remove(&wd);
+                Ok(())
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Describe a watch: its path, mask and subscribers, and the size and
+    /// age of the scan behind it.
+    pub fn watch_info(&mut self, wd: i32) -> Result<WatchInfo, ClientError> {
+        match self.request(&Request::GetWatchInfo { wd })? {
+            Response::WatchInfo { info } => Ok(info),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Label a watch, e.g. with the team or application it belongs to;
+    /// replaces the tags set before, and an empty list clears them.
+    pub fn tag_watch(
+        &mut self,
+        wd: i32,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<(), ClientError> {
+        let tags = tags.into_iter().map(Into::into).collect();
+        match self.request(&Request::TagWatch { wd, tags })? {
+            Response::WatchTagged => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Add several watches in one round trip.
+    ///
+    /// The outer error is for the exchange as a whole; each entry gets its
+    /// own result, in order, so one bad path does not fail the rest. No
+    /// watches means no round trip.
+    pub fn add_watches<P: AsRef<Path>>(
+        &mut self,
+        watches: impl IntoIterator<Item = (P, EventMask)>,
+    ) -> Result<Vec<Result<i32, ClientError>>, ClientError> {
+        let entries: Vec<_> = watches
+            .into_iter()
+            .map(|(path, mask)| WatchEntry {
+                path: path.as_ref().to_path_buf(),
+                mask: mask.bits(),
+            })
+            .collect();
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let results = self.batch(&Request::AddWatchBatch { entries })?;
+        Ok(results
+            .into_iter()
+            .map(|response| self.added(response))
+            .collect())
+    }
+
+    /// Remove several watches in one round trip, with a result per
+    /// descriptor as for [`add_watches`](Self::add_watches).
+    pub fn remove_watches(
+        &mut self,
+        wds: impl IntoIterator<Item = i32>,
+    ) -> Result<Vec<Result<(), ClientError>>, ClientError> {
+        let wds: Vec<_> = wds.into_iter().collect();
+        if wds.is_empty() {
+            return Ok(Vec::new());
+        }
+        let results = self.batch(&Request::RemoveWatchBatch { wds })?;
+        Ok(results
+            .into_iter()
+            .map(|response| match response {
+                Response::WatchRemoved => Ok(()),
+                other => Err(unexpected(other)),
+            })
+            .collect())
+    }
+
+    /// Translate this client's watch paths to the daemon's view, e.g. from a
+    /// container mount to the host directory behind it. An empty table
+    /// reverts to the daemon's configured one.
+    pub fn set_path_map(&mut self, mappings: Vec<PathMapping>) -> Result<(), ClientError> {
+        match self.request(&Request::SetPathMap { mappings })? {
+            Response::PathMapSet => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Only receive file events whose names pass `filter`, e.g. a media
+    /// indexer asking for `mkv` and `jpg` files only. Directory events always
+    /// arrive. An empty filter lets everything through again.
+    pub fn set_filter(&mut self, filter: NameFilter) -> Result<(), ClientError> {
+        match self.request(&Request::SetFilter { filter })? {
+            Response::FilterSet => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Tell the daemon who this client is, shown by `fakenotifyd clients`
+    /// to tell many otherwise identical connections apart, and agree on
+    /// the optional features this client understands.
+    pub fn set_info(&mut self, info: ClientInfo) -> Result<(), ClientError> {
+        let request = Request::RegisterClient {
+            protocol_version: PROTOCOL_VERSION,
+            info,
+            capabilities: CAPABILITIES.bits(),
+        };
+        match self.request(&request)? {
+            Response::ClientRegistered { capabilities, .. } => {
+                self.capabilities = Capabilities::from_bits_truncate(capabilities);
+                Ok(())
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Put this client's watches in `namespace` on a multi-tenant daemon,
+    /// which then lists and changes only the watches of clients sharing
+    /// it. Call it before adding watches.
+    pub fn join_namespace(&mut self, namespace: &str) -> Result<(), ClientError> {
+        let request = Request::HelloNamespace {
+            namespace: namespace.to_string(),
+        };
+        match self.request(&request)? {
+            Response::Hello { .. } => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Round-trip a keepalive ping.
+    pub fn ping(&mut self) -> Result<(), ClientError> {
+        match self.request(&Request::Ping)? {
+            Response::Pong => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Block until the next event is available.
+    pub fn read_event(&mut self) -> Result<Event, ClientError> {
+        loop {
+            if let Some(event) = self.try_pending_event() {
+                return Ok(event);
+            }
+            let (kind, payload) = self.read_frame()?;
+            // Any response has no outstanding request waiting on it
+            let _ = self.take_events(kind, &payload)?;
+        }
+    }
+
+    /// Wait up to `timeout` for the next event, returning `None` if none
+    /// arrives in time. `None` waits forever; a zero timeout only takes
+    /// what has already arrived.
+    ///
+    /// A frame cut off by the timeout stays buffered for the next read.
+    pub fn read_event_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Event>, ClientError> {
+        let Some(timeout) = timeout else {
+            return self.read_event().map(Some);
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.try_pending_event() {
+                return Ok(Some(event));
+            }
+            if let Some((kind, payload)) = self.buffered_frame()? {
+                let _ = self.take_events(kind, &payload)?;
+                continue;
+            }
+            if !self.fill_within(deadline.saturating_duration_since(Instant::now()))? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Whether an event can be read without waiting on the socket.
+    ///
+    /// Events that arrived while waiting for a response, or were read off
+    /// the socket along with one, leave the socket unreadable, so callers
+    /// polling it must read events until this is false first.
+    pub fn has_pending_event(&mut self) -> Result<bool, ClientError> {
+        loop {
+            if let Some(event) = self.try_pending_event() {
+                self.unread_event(event);
+                return Ok(true);
+            }
+            let Some((kind, payload)) = self.buffered_frame()? else {
+                return Ok(false);
+            };
+            // Any response has no outstanding request waiting on it
+            let _ = self.take_events(kind, &payload)?;
+        }
+    }
+
+    /// Put back an event just read, to be read again next.
+    pub fn unread_event(&mut self, event: Event) {
+        // Let it past the cursor it advanced
+        if let Some(seq) = event.seq
+            && let Some(cursor) = self.cursors.get_mut(&event.wd)
+        {
+            cursor.seq = cursor.seq.min(seq - 1);
+        }
+        self.pending.push_front(event);
+    }
+
+    /// Return a queued event without blocking, if one is buffered.
+    #[must_use]
+    pub fn try_pending_event(&mut self) -> Option<Event> {
+        while let Some(event) = self.pending.pop_front() {
+            // Skip journaled events read already, advancing past the rest
+            if let Some(seq) = event.seq
+                && let Some(cursor) = self.cursors.get_mut(&event.wd)
+            {
+                if seq <= cursor.seq {
+                    continue;
+                }
+                cursor.seq = seq;
+            }
+            return Some(event);
+        }
+        None
+    }
+
+    /// Send a batch request and unpack its per-entry responses.
+    fn batch(&mut self, request: &Request) -> Result<Vec<Response>, ClientError> {
+        match self.request(request)? {
+            Response::Batch { results, .. } => Ok(results),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Send a request and wait for its response, queueing any events that
+    /// arrive in between.
+    fn request(&mut self, request: &Request) -> Result<Response, ClientError> {
+        let payload = request.to_bytes()?;
+        self.stream.write_all(&FramedMessage::frame(&payload))?;
+        self.read_response()
+    }
+
+    fn read_response(&mut self) -> Result<Response, ClientError> {
+        loop {
+            let (kind, payload) = self.read_frame()?;
+            if let Some(response) = self.take_events(kind, &payload)? {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Queue the events a frame carries and answer heartbeats; returns any
+    /// other response.
+    fn take_events(
+        &mut self,
+        kind: FrameKind,
+        payload: &[u8],
+    ) -> Result<Option<Response>, ClientError> {
+        if kind == FrameKind::Event {
+            let event = decode_event(payload)?;
+            self.pending.push_back(event);
+            return Ok(None);
+        }
+        match Response::from_bytes(payload)? {
+            Response::Heartbeat => self.ack_heartbeat()?,
+            Response::EventBatch { events, .. } => self.queue_batch(&events),
+            Response::Sequenced { seq, event } => {
+                let event = decode_event(&event)?;
+                self.pending.push_back(Event {
+                    seq: Some(seq),
+                    ..event
+                });
+            }
+            response => return Ok(Some(response)),
+        }
+        Ok(None)
+    }
+
+    /// Queue each event of an `EventBatch`, to be read one at a time.
+    fn queue_batch(&mut self, events: &[u8]) {
+        for (header, name) in EventBuffer::new(events) {
+            self.pending.push_back(Event::from_parts(&header, name));
+        }
+    }
+
+    /// Tell the daemon we are still alive so it keeps the connection.
+    fn ack_heartbeat(&mut self) -> Result<(), ClientError> {
+        let payload = Request::HeartbeatAck.to_bytes()?;
+        self.stream.write_all(&FramedMessage::frame(&payload))?;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> Result<(FrameKind, Vec<u8>), ClientError> {
+        loop {
+            if let Some(frame) = self.buffered_frame()? {
+                return Ok(frame);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// The next whole frame read off the socket already, if any.
+    fn buffered_frame(&mut self) -> Result<Option<(FrameKind, Vec<u8>)>, ClientError> {
+        let Some((kind, payload)) = self.decoder.next_kind_and_frame()? else {
+            return Ok(None);
+        };
+        // Unwrap compressed frames here, so callers only see what they held
+        if self.capabilities.contains(Capabilities::COMPRESSION)
+            && kind == FrameKind::Message
+            && let Ok(Response::Compressed { kind, payload }) = Response::from_bytes(payload)
+        {
+            return Ok(Some((kind, decompress_payload(&payload)?)));
+        }
+        Ok(Some((kind, payload.to_vec())))
+    }
+
+    /// Read what the socket has into the decoder, blocking until something
+    /// arrives.
+    fn fill(&mut self) -> Result<(), ClientError> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    self.decoder.push(&chunk[..n]);
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// [`fill`](Self::fill), waiting at most `timeout`; false if nothing
+    /// arrived in time.
+    fn fill_within(&mut self, timeout: Duration) -> Result<bool, ClientError> {
+        if timeout.is_zero() {
+            self.stream.set_nonblocking(true)?;
+        } else {
+            self.stream.set_read_timeout(Some(timeout))?;
+        }
+        let filled = self.fill();
+        self.stream.set_nonblocking(false)?;
+        self.stream.set_read_timeout(None)?;
+        match filled {
+            Ok(()) => Ok(true),
+            Err(ClientError::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+fn decode_event(payload: &[u8]) -> Result<Event, ClientError> {
+    Event::from_bytes(payload)
+        .ok_or_else(|| ProtocolError::InvalidMessage("truncated event".to_string()).into())
+}
+
+fn unexpected(response: Response) -> ClientError {
+    match response {
+        Response::Error { code, message } => ClientError::Daemon { code, message },
+        Response::LimitExceeded { limit } => ClientError::Daemon {
+            code: ErrorCode::from_name(&limit).unwrap_or_default(),
+            message: format!("{limit} exceeded"),
+        },
+        other => ClientError::UnexpectedResponse(Box::new(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "fakenotify-client-{}-{}.sock",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn send(stream: &mut impl Write, payload: &[u8]) {
+        stream.write_all(&FramedMessage::frame(payload)).unwrap();
+    }
+
+    fn send_event(stream: &mut impl Write, event: &[u8]) {
+        stream
+            .write_all(&FramedMessage::frame_event(event))
+            .unwrap();
+    }
+
+    fn recv_request(stream: &mut impl Read) -> Request {
+        let mut len_buf = [0u8; 4];
+        stream.read_exact(&mut len_buf).unwrap();
+        let mut payload = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        Request::from_bytes(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_event_from_bytes() {
+        let bytes =
+            InotifyEvent::new(3, EventMask::IN_DELETE.bits(), 9).to_bytes_with_name(b"x.mkv");
+        let event = Event::from_bytes(&bytes).unwrap();
+        assert_eq!(event.wd, 3);
+        assert_eq!(event.mask, EventMask::IN_DELETE);
+        assert_eq!(event.cookie, 9);
+        assert_eq!(event.name, Some(OsString::from("x.mkv")));
+    }
+
+    #[test]
+    fn test_add_watch_queues_interleaved_events() {
+        let path = socket_path("interleave");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(
+                &mut stream,
+                &Response::ClientRegistered {
+                    client_id: 42,
+                    session: String::new(),
+                    capabilities: 0,
+                }
+                .to_bytes()
+                .unwrap(),
+            );
+
+            assert!(matches!(
+                recv_request(&mut stream),
+                Request::AddWatch { .. }
+            ));
+            // An event from an earlier watch lands before the response
+            send_event(
+                &mut stream,
+                &InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"new"),
+            );
+            send(&mut stream, &Response::Heartbeat.to_bytes().unwrap());
+            send(
+                &mut stream,
+                &Response::WatchAdded { wd: 2 }.to_bytes().unwrap(),
+            );
+            assert_eq!(recv_request(&mut stream), Request::HeartbeatAck);
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        assert_eq!(client.client_id(), 42);
+        assert_eq!(
+            client
+                .add_watch("/mnt/media", EventMask::IN_ALL_EVENTS)
+                .unwrap(),
+            2
+        );
+
+        let event = client.read_event().unwrap();
+        assert_eq!(event.wd, 1);
+        assert_eq!(event.name, Some(OsString::from("new")));
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_reconnect_resumes_session() {
+        let path = socket_path("resume");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let registered = |client_id, session: &str| Response::ClientRegistered {
+                client_id,
+                session: session.to_string(),
+                capabilities: 0,
+            };
+            let (mut stream, _) = listener.accept().unwrap();
+            send(&mut stream, &registered(4, "s4").to_bytes().unwrap());
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            send(&mut stream, &registered(9, "s9").to_bytes().unwrap());
+            assert_eq!(
+                recv_request(&mut stream),
+                Request::Resume {
+                    session: "s4".to_string()
+                }
+            );
+            let resumed = Response::Resumed {
+                client_id: 4,
+                capabilities: 0,
+            };
+            send(&mut stream, &resumed.to_bytes().unwrap());
+            // Queued while the client was away
+            send_event(
+                &mut stream,
+                &InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"new"),
+            );
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        assert_eq!(client.session(), "s4");
+        assert!(client.read_event().is_err());
+        client.reconnect().unwrap();
+        assert_eq!((client.client_id(), client.session()), (4, "s4"));
+        assert_eq!(
+            client.read_event().unwrap().name,
+            Some(OsString::from("new"))
+        );
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_compressed_batch_yields_each_event() {
+        let path = socket_path("batch");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let registered = |capabilities: Capabilities| Response::ClientRegistered {
+                client_id: 5,
+                session: String::new(),
+                capabilities: capabilities.bits(),
+            };
+            send(
+                &mut stream,
+                &registered(Capabilities::empty()).to_bytes().unwrap(),
+            );
+            let Request::RegisterClient { capabilities, .. } = recv_request(&mut stream) else {
+                panic!("expected RegisterClient");
+            };
+            assert_eq!(capabilities, CAPABILITIES.bits());
+            send(&mut stream, &registered(CAPABILITIES).to_bytes().unwrap());
+
+            let events: Vec<u8> = (0..50)
+                .flat_map(|i| {
+                    InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0)
+                        .to_bytes_with_name(format!("{i}.mkv").as_bytes())
+                })
+                .collect();
+            let batch = Response::EventBatch { count: 50, events };
+            send(
+                &mut stream,
+                &fakenotify_protocol::compress_payload(
+                    FrameKind::Message,
+                    &batch.to_bytes().unwrap(),
+                )
+                .unwrap(),
+            );
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        client.set_info(ClientInfo::default()).unwrap();
+        assert_eq!(client.capabilities(), CAPABILITIES);
+        for i in 0..50 {
+            let event = client.read_event().unwrap();
+            assert_eq!(event.name, Some(OsString::from(format!("{i}.mkv"))));
+        }
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_add_watch_since_resumes_once() {
+        let path = socket_path("since");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let since = Cursor { epoch: 7, seq: 2 };
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(
+                &mut stream,
+                &Response::ClientRegistered {
+                    client_id: 1,
+                    session: String::new(),
+                    capabilities: 0,
+                }
+                .to_bytes()
+                .unwrap(),
+            );
+            let Request::AddWatchSince {
+                since: Some(cursor),
+                ..
+            } = recv_request(&mut stream)
+            else {
+                panic!("expected AddWatchSince");
+            };
+            assert_eq!(cursor, since);
+            let sequenced = |seq: u64, name: &str| {
+                let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0)
+                    .to_bytes_with_name(name.as_bytes());
+                Response::Sequenced { seq, event }.to_bytes().unwrap()
+            };
+            // Replayed ahead of the answer, then again live
+            send(&mut stream, &sequenced(3, "c"));
+            send(&mut stream, &sequenced(4, "d"));
+            let added = Response::WatchAddedSince {
+                wd: 1,
+                replayed: 2,
+                cursor: Cursor { epoch: 7, seq: 4 },
+                gap: false,
+            };
+            send(&mut stream, &added.to_bytes().unwrap());
+            send(&mut stream, &sequenced(4, "d"));
+            send(&mut stream, &sequenced(5, "e"));
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        let watch = client
+            .add_watch_since("/mnt/media", EventMask::IN_CREATE, Some(since))
+            .unwrap();
+        assert_eq!((watch.wd, watch.replayed, watch.gap), (1, 2, false));
+        assert_eq!(client.cursor(1), Some(since));
+        for (seq, name) in [(3, "c"), (4, "d"), (5, "e")] {
+            let event = client.read_event().unwrap();
+            assert_eq!(event.seq, Some(seq));
+            assert_eq!(event.name, Some(OsString::from(name)));
+            assert_eq!(client.cursor(1), Some(Cursor { epoch: 7, seq }));
+        }
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_cursor_file() {
+        let path =
+            std::env::temp_dir().join(format!("fakenotify-client-cursor-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = CursorFile::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+
+        let cursor = Cursor { epoch: 7, seq: 42 };
+        store.store(cursor).unwrap();
+        store.store(Cursor { seq: 43, ..cursor }).unwrap();
+        assert_eq!(store.load().unwrap(), Some(Cursor { seq: 43, ..cursor }));
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(store.load().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_daemon_error_is_surfaced() {
+        let path = socket_path("error");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(
+                &mut stream,
+                &Response::ClientRegistered {
+                    client_id: 1,
+                    session: String::new(),
+                    capabilities: 0,
+                }
+                .to_bytes()
+                .unwrap(),
+            );
+            let _ = recv_request(&mut stream);
+            send(&mut stream, &Response::error("nope").to_bytes().unwrap());
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        match client.remove_watch(5) {
+            Err(ClientError::Daemon { code, message }) => {
+                assert_eq!(code, ErrorCode::Invalid);
+                assert_eq!(message, "nope");
+            }
+            other => panic!("expected daemon error, got {other:?}"),
+        }
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_timeout_keeps_partial_frame() {
+        let path = socket_path("timeout");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (go, wait) = std::sync::mpsc::channel();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(
+                &mut stream,
+                &Response::ClientRegistered {
+                    client_id: 1,
+                    session: String::new(),
+                    capabilities: 0,
+                }
+                .to_bytes()
+                .unwrap(),
+            );
+            let event = FramedMessage::frame_event(
+                &InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a"),
+            );
+            stream.write_all(&event[..6]).unwrap();
+            wait.recv().unwrap();
+            stream.write_all(&event[6..]).unwrap();
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        assert_eq!(
+            client.read_event_timeout(Some(Duration::ZERO)).unwrap(),
+            None
+        );
+        assert_eq!(
+            client
+                .read_event_timeout(Some(Duration::from_millis(50)))
+                .unwrap(),
+            None
+        );
+        go.send(()).unwrap();
+        let event = client.read_event_timeout(None).unwrap().unwrap();
+        assert_eq!(event.name, Some(OsString::from("a")));
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_connect_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(
+                &mut stream,
+                &Response::ClientRegistered {
+                    client_id: 7,
+                    session: String::new(),
+                    capabilities: 0,
+                }
+                .to_bytes()
+                .unwrap(),
+            );
+            assert!(matches!(recv_request(&mut stream), Request::Ping));
+            send(&mut stream, &Response::Pong.to_bytes().unwrap());
+        });
+
+        let mut client = Client::connect_to(format!("tcp://{addr}")).unwrap();
+        assert_eq!(client.client_id(), 7);
+        client.ping().unwrap();
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_add_watches_batch() {
+        let path = socket_path("batch");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send(
+                &mut stream,
+                &Response::ClientRegistered {
+                    client_id: 3,
+                    session: String::new(),
+                    capabilities: 0,
+                }
+                .to_bytes()
+                .unwrap(),
+            );
+            let Request::AddWatchBatch { entries } = recv_request(&mut stream) else {
+                panic!("expected AddWatchBatch");
+            };
+            assert_eq!(entries.len(), 3);
+            let results = vec![
+                Response::WatchAdded { wd: 1 },
+                Response::WatchClamped {
+                    wd: 2,
+                    mask: EventMask::IN_CREATE.bits(),
+                },
+                Response::LimitExceeded {
+                    limit: "max_user_watches".to_string(),
+                },
+            ];
+            send(&mut stream, &Response::batch(results).to_bytes().unwrap());
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        let results = client
+            .add_watches([
+                ("/mnt/a", EventMask::IN_CREATE),
+                ("/mnt/b", EventMask::IN_CREATE | EventMask::IN_ACCESS),
+                ("/mnt/c", EventMask::IN_CREATE),
+            ])
+            .unwrap();
+        assert!(matches!(
+            results[..],
+            [
+                Ok(1),
+                Ok(2),
+                Err(ClientError::Daemon {
+                    code: ErrorCode::WatchLimit,
+                    ..
+                })
+            ]
+        ));
+        assert_eq!(client.clamped_mask(1), None);
+        assert_eq!(client.clamped_mask(2), Some(EventMask::IN_CREATE));
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
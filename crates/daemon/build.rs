@@ -0,0 +1,13 @@
+//! Generates the gRPC gateway's service code when the `grpc` feature is on.
+
+fn main() {
+    println!("cargo:rerun-if-changed=proto/fakenotify.proto");
+    #[cfg(feature = "grpc")]
+    {
+        // A vendored protoc, so building needs nothing installed
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        // SAFETY: build scripts are single-threaded
+        unsafe { std::env::set_var("PROTOC", protoc) };
+        tonic_prost_build::compile_protos("proto/fakenotify.proto").expect("compile protos");
+    }
+}
@@ -0,0 +1,278 @@
+//! Audit trail of who connects and what they watch.
+//!
+//! Every connect, disconnect, `AddWatch` and `RemoveWatch` is recorded with
+//! the peer's uid, pid and executable, one JSON object per line, so watch
+//! load and unexpected access can be traced back to a process. Records go to
+//! `audit_log` and, with `audit_journal`, to the daemon's own log under the
+//! `audit` target (the systemd journal when run as a service).
+
+use crate::state::{ClientId, Peer, WatchDescriptor};
+use fakenotify_protocol::Response;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sink for audit records
+pub struct AuditLog {
+    /// The dedicated audit file, if one is configured
+    file: Option<Mutex<File>>,
+    /// Also emit records through `tracing`
+    journal: bool,
+    /// Who is behind each connected client, resolved when it connected
+    peers: Mutex<HashMap<ClientId, Identity>>,
+}
+
+/// A peer's credentials plus the executable it was running on connect
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Identity {
+    uid: u32,
+    pid: Option<i32>,
+    exe: Option<PathBuf>,
+}
+
+impl Identity {
+    fn resolve(peer: Peer) -> Self {
+        Self {
+            uid: peer.uid,
+            pid: peer.pid,
+            exe: peer.pid.and_then(executable),
+        }
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// Seconds since the Unix epoch
+    time: u64,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<ClientId>,
+    uid: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exe: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mask: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wd: Option<WatchDescriptor>,
+    /// `ok`, or why the daemon said no
+    result: &'a str,
+}
+
+impl AuditLog {
+    /// Append to `path` (created `0640` if missing) and, with `journal`,
+    /// log records too
+    pub fn open(path: Option<&Path>, journal: bool) -> std::io::Result<Self> {
+        let file = match path {
+            Some(path) => {
+                if let Some(parent) = path.parent()
+                    && !parent.as_os_str().is_empty()
+                {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut options = std::fs::OpenOptions::new();
+                options.create(true).append(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o640);
+                Some(Mutex::new(options.open(path)?))
+            }
+            None => None,
+        };
+        Ok(Self {
+            file,
+            journal,
+            peers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// A client connected as `peer`
+    pub fn connected(&self, client_id: ClientId, peer: Peer) {
+        let identity = Identity::resolve(peer);
+        self.write(Record {
+            client_id: Some(client_id),
+            ..record("connect", &identity, "ok")
+        });
+        self.peers.lock().insert(client_id, identity);
+    }
+
+    /// A connection from `peer` was turned away
+    pub fn refused(&self, peer: Peer, reason: &str) {
+        let identity = Identity::resolve(peer);
+        self.write(record("connect", &identity, reason));
+    }
+
+    /// A client went away
+    pub fn disconnected(&self, client_id: ClientId) {
+        let identity = self.peers.lock().remove(&client_id).unwrap_or_default();
+        self.write(Record {
+            client_id: Some(client_id),
+            ..record("disconnect", &identity, "ok")
+        });
+    }
+
+    /// A client asked to watch `path` and got `response`
+    pub fn add_watch(&self, client_id: ClientId, path: &Path, mask: u32, response: &Response) {
+        let (wd, result) = match response {
+            Response::WatchAdded { wd } => (Some(*wd), "ok"),
+            Response::WatchClamped { wd, .. } => (Some(*wd), "clamped"),
+            Response::LimitExceeded { limit } => (None, limit.as_str()),
+            Response::Error { message, .. } => (None, message.as_str()),
+            _ => (None, "unexpected response"),
+        };
+        let identity = self.identity(client_id);
+        self.write(Record {
+            client_id: Some(client_id),
+            path: Some(path),
+            mask: Some(mask),
+            wd,
+            ..record("add_watch", &identity, result)
+        });
+    }
+
+    /// A client asked to drop watch `wd` of `path`
+    pub fn remove_watch(
+        &self,
+        client_id: ClientId,
+        wd: WatchDescriptor,
+        path: Option<&Path>,
+        removed: bool,
+    ) {
+        let identity = self.identity(client_id);
+        self.write(Record {
+            client_id: Some(client_id),
+            path,
+            wd: Some(wd),
+            ..record(
+                "remove_watch",
+                &identity,
+                if removed { "ok" } else { "not found" },
+            )
+        });
+    }
+
+    fn identity(&self, client_id: ClientId) -> Identity {
+        self.peers
+            .lock()
+            .get(&client_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn write(&self, record: Record<'_>) {
+        if self.journal {
+            tracing::info!(
+                target: "audit",
+                action = record.action,
+                client_id = ?record.client_id,
+                uid = record.uid,
+                pid = ?record.pid,
+                exe = ?record.exe,
+                path = ?record.path,
+                wd = ?record.wd,
+                result = record.result,
+            );
+        }
+        let Some(file) = &self.file else {
+            return;
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to encode audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = file.lock().write_all(&line) {
+            tracing::warn!(error = %e, "Failed to write audit log");
+        }
+    }
+}
+
+/// A record of `action` by `identity` with nothing else filled in
+fn record<'a>(action: &'static str, identity: &'a Identity, result: &'a str) -> Record<'a> {
+    Record {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0),
+        action,
+        client_id: None,
+        uid: identity.uid,
+        pid: identity.pid,
+        exe: identity.exe.as_deref(),
+        path: None,
+        mask: None,
+        wd: None,
+        result,
+    }
+}
+
+/// The executable `pid` is running, if it can be seen from here
+#[cfg(target_os = "linux")]
+fn executable(pid: i32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{pid}/exe")).ok()
+}
+
+/// The executable `pid` is running (not available on this platform)
+#[cfg(not(target_os = "linux"))]
+fn executable(_pid: i32) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_peer_and_watches() {
+        let path = std::env::temp_dir().join(format!("fakenotify-audit-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = AuditLog::open(Some(&path), false).unwrap();
+        let peer = Peer {
+            uid: 1000,
+            pid: Some(std::process::id() as i32),
+            local: true,
+        };
+
+        audit.connected(7, peer);
+        audit.add_watch(
+            7,
+            Path::new("/mnt/media"),
+            0x100,
+            &Response::WatchAdded { wd: 3 },
+        );
+        audit.remove_watch(7, 9, None, false);
+        audit.disconnected(7);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0]["action"], "connect");
+        assert_eq!(records[0]["uid"], 1000);
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            records[0]["exe"],
+            std::env::current_exe().unwrap().to_str().unwrap()
+        );
+        assert_eq!(records[1]["action"], "add_watch");
+        assert_eq!(records[1]["path"], "/mnt/media");
+        assert_eq!(records[1]["wd"], 3);
+        assert_eq!(records[1]["pid"], records[0]["pid"]);
+        assert_eq!(records[2]["result"], "not found");
+        assert_eq!(records[3]["action"], "disconnect");
+        assert_eq!(records[3]["client_id"], 7);
+    }
+}
@@ -0,0 +1,422 @@
+//! `fakenotifyd bench`: synthetic load against a throwaway daemon.
+//!
+//! Builds a tree (optionally on the mount you plan to watch), starts a
+//! private daemon on it with the current tuning and limits, connects simulated
+//! clients, and creates files at a fixed rate. Each client timestamps the
+//! `IN_CREATE` for every file; the report gives latency percentiles from
+//! file creation to delivery and how many creates each client missed.
+//!
+//! Churned files are deleted again once `live_files` exist, so a poll
+//! interval longer than a file's lifetime shows up as drops: the file came
+//! and went between two scans.
+
+use crate::config::{Backend, Config, WatchConfig};
+use crate::output::BenchReport;
+use color_eyre::eyre::{Result, bail};
+use fakenotify_client::Client;
+use fakenotify_protocol::EventMask;
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long the private daemon may take to accept connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Files pre-created in the tree are spread over this many directories
+const TREE_DIRS: usize = 10;
+
+/// Directory under the root the churn happens in
+const CHURN_DIR: &str = "churn";
+
+/// Shape of the load
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Simulated clients, each watching the churn directory
+    pub clients: usize,
+    /// Files created per second
+    pub rate: u32,
+    /// How long to create files for
+    pub duration: Duration,
+    /// Poll interval of the benchmarked root
+    pub poll_interval: Duration,
+    /// Static files in the tree, scanned on every poll
+    pub tree_files: usize,
+    /// Churned files kept before the oldest is deleted
+    pub live_files: usize,
+    /// Directory to build the tree in
+    pub dir: PathBuf,
+}
+
+/// Run the benchmark, using `config` for everything but the watch roots
+pub fn run(config: &Config, daemon: &Path, options: &BenchOptions) -> Result<BenchReport> {
+    if options.clients == 0 || options.rate == 0 {
+        bail!("--clients and --rate must be at least 1");
+    }
+
+    // The tree goes where the user wants to measure; socket and config stay
+    // local
+    let name = format!("fakenotify-bench-{}", std::process::id());
+    let root = options.dir.join(&name);
+    let work = std::env::temp_dir().join(&name);
+    let _cleanup = Cleanup(vec![root.clone(), work.clone()]);
+    build_tree(&root, options.tree_files)?;
+    fs::create_dir_all(&work)?;
+
+    let socket = work.join("bench.sock");
+    let config_path = work.join("config.toml");
+    fs::write(&config_path, bench_config(config, &root, options)?)?;
+    let mut daemon = Daemon::start(daemon, &config_path, &socket)?;
+
+    let churn = root.join(CHURN_DIR);
+    let created = Arc::new(RwLock::new(Vec::new()));
+    let received = Arc::new(AtomicU64::new(0));
+    let readers = (0..options.clients)
+        .map(|_| Reader::spawn(&socket, &churn, &created, &received))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Create files on a fixed schedule, deleting the oldest beyond live_files
+    let start = Instant::now();
+    let mut seq = 0u64;
+    while start.elapsed() < options.duration {
+        let due = start + Duration::from_secs_f64(seq as f64 / f64::from(options.rate));
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        fs::write(churn.join(format!("f{seq}")), b"x")?;
+        created.write().push(Instant::now());
+        if seq >= options.live_files as u64 {
+            let _ = fs::remove_file(churn.join(format!("f{}", seq - options.live_files as u64)));
+        }
+        seq += 1;
+    }
+    let churn_secs = start.elapsed().as_secs_f64();
+
+    // Give the last creates a couple of scans to arrive
+    let expected = seq * options.clients as u64;
+    let deadline = Instant::now() + options.poll_interval * 2 + Duration::from_secs(2);
+    while received.load(Ordering::Relaxed) < expected && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // Stopping the daemon closes every client connection, ending the readers
+    daemon.stop();
+    let mut latencies = Vec::new();
+    let mut overflows = 0;
+    for reader in readers {
+        let (mut seen, overflowed) = reader.join();
+        latencies.append(&mut seen);
+        overflows += overflowed;
+    }
+    latencies.sort_unstable();
+
+    let percentile = |q: f64| {
+        let index = ((latencies.len().saturating_sub(1)) as f64 * q).round() as usize;
+        latencies
+            .get(index)
+            .map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+    };
+    Ok(BenchReport {
+        clients: options.clients,
+        files_created: seq,
+        create_rate: seq as f64 / churn_secs,
+        poll_interval_ms: options.poll_interval.as_millis() as u64,
+        tree_files: options.tree_files,
+        events_expected: expected,
+        events_received: latencies.len() as u64,
+        events_dropped: expected.saturating_sub(latencies.len() as u64),
+        overflows,
+        latency_p50_ms: percentile(0.50),
+        latency_p90_ms: percentile(0.90),
+        latency_p99_ms: percentile(0.99),
+        latency_max_ms: percentile(1.0),
+    })
+}
+
+/// Static files spread over a few directories, plus the empty churn directory
+fn build_tree(root: &Path, files: usize) -> Result<()> {
+    fs::create_dir_all(root.join(CHURN_DIR))?;
+    for dir in 0..TREE_DIRS {
+        fs::create_dir_all(root.join(format!("d{dir}")))?;
+    }
+    for file in 0..files {
+        fs::write(
+            root.join(format!("d{}", file % TREE_DIRS))
+                .join(format!("s{file}")),
+            b"",
+        )?;
+    }
+    Ok(())
+}
+
+/// The tuning and limits of `config`, with the bench tree as the only root,
+/// as TOML
+///
+/// Nothing else carries over: the private daemon must not compete with a
+/// production one for its listeners, nor write to its logs, checkpoints or
+/// trigger files, call its media servers or run its hooks.
+fn bench_config(config: &Config, root: &Path, options: &BenchOptions) -> Result<String> {
+    let mut daemon = config.daemon.clone();
+    daemon.tcp_listen = None;
+    daemon.vsock_listen = None;
+    daemon.grpc_listen = None;
+    daemon.watchman_socket = None;
+    daemon.audit_log = None;
+    daemon.audit_journal = false;
+    daemon.checkpoint_dir = None;
+    daemon.watch_config = false;
+    let watch = vec![WatchConfig {
+        path: root.to_path_buf(),
+        poll_interval: options.poll_interval,
+        recursive: true,
+        backend: Backend::Poll,
+        events: EventMask::IN_ALL_EVENTS,
+        extensions: Vec::new(),
+        name_regex: None,
+        change_detection: Default::default(),
+        revalidate: false,
+        case_insensitive: None,
+        confirm_deletes: 1,
+        restat_deletes: false,
+        settle_scans: 0,
+        partial_suffixes: Vec::new(),
+        tags: Vec::new(),
+        denied_events: EventMask::empty(),
+    }];
+    let config = Config {
+        daemon,
+        watch,
+        limits: config.limits,
+        client_class: config.client_class.clone(),
+        ..Config::default()
+    };
+    Ok(toml::to_string(&config)?)
+}
+
+/// The private daemon, stopped on drop
+struct Daemon(Child);
+
+impl Daemon {
+    fn start(daemon: &Path, config: &Path, socket: &Path) -> Result<Self> {
+        let child = Command::new(daemon)
+            .arg("--config")
+            .arg(config)
+            .arg("start")
+            .arg("--socket")
+            .arg(socket)
+            .env_remove("LD_PRELOAD")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut daemon = Self(child);
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while Client::connect_to(socket).is_err() {
+            if let Some(status) = daemon.0.try_wait()? {
+                bail!("Benchmark daemon exited with {status}");
+            }
+            if Instant::now() > deadline {
+                bail!("Benchmark daemon did not start listening");
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(daemon)
+    }
+
+    /// SIGTERM, then SIGKILL if draining takes too long
+    fn stop(&mut self) {
+        // SAFETY: signalling our own child, which has not been reaped yet
+        unsafe { libc::kill(self.0.id() as libc::pid_t, libc::SIGTERM) };
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while Instant::now() < deadline {
+            if !matches!(self.0.try_wait(), Ok(None)) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if matches!(self.0.try_wait(), Ok(None)) {
+            self.stop();
+        }
+    }
+}
+
+/// One simulated client timestamping creates in the churn directory
+struct Reader(JoinHandle<(Vec<Duration>, u64)>);
+
+impl Reader {
+    fn spawn(
+        socket: &Path,
+        churn: &Path,
+        created: &Arc<RwLock<Vec<Instant>>>,
+        received: &Arc<AtomicU64>,
+    ) -> Result<Self> {
+        let mut client = Client::connect_to(socket)?;
+        client.add_watch(churn, EventMask::IN_CREATE | EventMask::IN_DELETE)?;
+        let created = Arc::clone(created);
+        let received = Arc::clone(received);
+
+        Ok(Self(std::thread::spawn(move || {
+            let mut seen = HashSet::new();
+            let mut latencies = Vec::new();
+            let mut overflows = 0;
+            // Ends when the daemon shuts down and closes the connection
+            while let Ok(event) = client.read_event() {
+                let at = Instant::now();
+                if event.mask.contains(EventMask::IN_Q_OVERFLOW) {
+                    overflows += 1;
+                }
+                if !event.mask.contains(EventMask::IN_CREATE) {
+                    continue;
+                }
+                let Some(seq) = event
+                    .name
+                    .and_then(|name| name.to_str()?.strip_prefix('f')?.parse::<usize>().ok())
+                else {
+                    continue;
+                };
+                let Some(&made) = created.read().get(seq) else {
+                    continue;
+                };
+                if seen.insert(seq) {
+                    latencies.push(at.saturating_duration_since(made));
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            (latencies, overflows)
+        })))
+    }
+
+    /// Latency of every create seen, and overflow events received
+    fn join(self) -> (Vec<Duration>, u64) {
+        self.0.join().unwrap_or_default()
+    }
+}
+
+/// Removes the bench directories, however the run ends
+struct Cleanup(Vec<PathBuf>);
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        for dir in &self.0 {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_config_watches_only_the_tree() {
+        let config = Config {
+            watch: vec![WatchConfig {
+                path: PathBuf::from("/mnt/media"),
+                poll_interval: Duration::from_secs(30),
+                recursive: false,
+                backend: Backend::Poll,
+                events: EventMask::IN_CREATE,
+                extensions: vec!["mkv".to_string()],
+                name_regex: None,
+                change_detection: Default::default(),
+                revalidate: false,
+                case_insensitive: None,
+                confirm_deletes: 1,
+                restat_deletes: false,
+                settle_scans: 0,
+                partial_suffixes: Vec::new(),
+                tags: Vec::new(),
+                denied_events: EventMask::empty(),
+            }],
+            ..Config::default()
+        };
+        let options = BenchOptions {
+            clients: 1,
+            rate: 1,
+            duration: Duration::from_secs(1),
+            poll_interval: Duration::from_millis(250),
+            tree_files: 0,
+            live_files: 10,
+            dir: std::env::temp_dir(),
+        };
+
+        let text = bench_config(&config, Path::new("/tmp/bench"), &options).unwrap();
+        let parsed: Config = toml::from_str(&text).unwrap();
+        assert_eq!(parsed.watch.len(), 1);
+        assert_eq!(parsed.watch[0].path, PathBuf::from("/tmp/bench"));
+        assert_eq!(parsed.watch[0].poll_interval, Duration::from_millis(250));
+        assert!(parsed.watch[0].recursive);
+        assert!(parsed.watch[0].extensions.is_empty());
+    }
+
+    #[test]
+    fn test_bench_config_leaves_production_side_effects_behind() {
+        let config: Config = toml::from_str(
+            r#"
+            [daemon]
+            tcp_listen = "0.0.0.0:7070"
+            vsock_listen = 7070
+            watchman_socket = "/run/fakenotify/watchman.sock"
+            audit_log = "/var/log/fakenotify/audit.log"
+            audit_journal = true
+            checkpoint_dir = "/var/cache/fakenotify"
+            event_queue = 1234
+
+            [limits]
+            max_user_watches = 99
+
+            [[media_server]]
+            kind = "plex"
+            url = "http://127.0.0.1:32400"
+            api_key = "token"
+
+            [[trigger]]
+            file = "/var/lib/legacyapp/rescan.flag"
+            under = "/mnt/media"
+
+            [[event_log]]
+            target = "syslog"
+
+            [[hook]]
+            name = "thumbnail"
+            command = ["/usr/local/bin/make-thumbnail"]
+            "#,
+        )
+        .unwrap();
+        let options = BenchOptions {
+            clients: 1,
+            rate: 1,
+            duration: Duration::from_secs(1),
+            poll_interval: Duration::from_millis(250),
+            tree_files: 0,
+            live_files: 10,
+            dir: std::env::temp_dir(),
+        };
+
+        let text = bench_config(&config, Path::new("/tmp/bench"), &options).unwrap();
+        let parsed: Config = toml::from_str(&text).unwrap();
+        let daemon = &parsed.daemon;
+        assert!(daemon.tcp_listen.is_none() && daemon.vsock_listen.is_none());
+        assert!(daemon.grpc_listen.is_none() && daemon.watchman_socket.is_none());
+        assert!(daemon.audit_log.is_none() && !daemon.audit_journal);
+        assert!(daemon.checkpoint_dir.is_none());
+        assert!(parsed.media_server.is_empty() && parsed.trigger.is_empty());
+        assert!(parsed.event_log.is_empty() && parsed.hook.is_empty());
+        // Tuning still applies
+        assert_eq!(daemon.event_queue, 1234);
+        assert_eq!(parsed.limits.max_user_watches, 99);
+    }
+}
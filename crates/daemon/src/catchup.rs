@@ -0,0 +1,178 @@
+//! Catch-up events for clients that reconnect after a restart.
+//!
+//! A root resumed from a checkpoint reports what changed while the daemon
+//! was down on its first scan, but right after a restart no client has
+//! re-added its watches yet, so those events reach nobody. With `catch_up`
+//! set, they are kept for that many seconds, and a client adding a watch
+//! in that time is sent the ones under it, as if it had stayed connected.
+
+use crate::state::{Client, WatchInfo};
+use crate::watcher::{WatcherEvent, event_mask, frame_event, next_cookie};
+use bytes::Bytes;
+use fakenotify_protocol::{EventMask, InotifyEvent};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Changes found on resuming, kept for watches added soon after startup
+pub struct CatchUp {
+    until: Instant,
+    events: Mutex<Vec<WatcherEvent>>,
+}
+
+impl CatchUp {
+    /// Keep events for `window` from now
+    pub fn new(window: Duration) -> Self {
+        Self {
+            until: Instant::now() + window,
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Keep `events` from a resumed root's first scan
+    pub fn record(&self, events: &[WatcherEvent]) {
+        if Instant::now() < self.until {
+            self.events.lock().extend_from_slice(events);
+        }
+    }
+
+    /// Queue the kept events under `watch` for `client`; returns how many
+    /// were queued
+    pub fn replay(&self, watch: &WatchInfo, client: &Client) -> usize {
+        let mut events = self.events.lock();
+        if Instant::now() >= self.until {
+            *events = Vec::new();
+            return 0;
+        }
+
+        replay_frames(watch, client, events.iter().map(|event| ((), event)))
+            .into_iter()
+            .filter(|((), framed)| client.queue_event(framed.clone()))
+            .count()
+    }
+}
+
+/// Frame the events a client of `watch` is sent, as the dispatcher would
+/// have, each with the tag it came with
+///
+/// Renames get cookies of their own, paired by inode as the dispatcher
+/// pairs them.
+pub fn replay_frames<'a, T>(
+    watch: &WatchInfo,
+    client: &Client,
+    events: impl IntoIterator<Item = (T, &'a WatcherEvent)>,
+) -> Vec<(T, Bytes)> {
+    let mut cookies: HashMap<u64, u32> = HashMap::new();
+    let mut frames = Vec::new();
+    for (tag, event) in events {
+        if !covers(watch, &event.path) {
+            continue;
+        }
+        let Some(mask) = event_mask(event, &watch.path) else {
+            continue;
+        };
+        if !watch.mask.intersects(mask)
+            || (event.unlinked && watch.mask.contains(EventMask::IN_EXCL_UNLINK))
+        {
+            continue;
+        }
+        let cookie = if mask.intersects(EventMask::IN_MOVED_FROM) {
+            let cookie = next_cookie();
+            cookies.insert(event.ino, cookie);
+            cookie
+        } else if mask.intersects(EventMask::IN_MOVED_TO) {
+            cookies.remove(&event.ino).unwrap_or_else(next_cookie)
+        } else {
+            0
+        };
+
+        let name = event
+            .path
+            .strip_prefix(&watch.path)
+            .ok()
+            .filter(|_| event.path != watch.path)
+            .and_then(|p| p.to_str());
+        let wanted =
+            client.filter.read().as_ref().is_none_or(|filter| {
+                filter.matches(Path::new(name.unwrap_or_default()), event.is_dir)
+            });
+        if !wanted {
+            continue;
+        }
+        let inotify_event = InotifyEvent::new(watch.wd, mask.bits(), cookie);
+        frames.push((tag, frame_event(&inotify_event, name.map(str::as_bytes))));
+    }
+    frames
+}
+
+/// Whether a watch on `watch.path` sees events on `path`
+fn covers(watch: &WatchInfo, path: &Path) -> bool {
+    if watch.recursive {
+        path.starts_with(&watch.path)
+    } else {
+        path == watch.path || path.parent() == Some(&watch.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::state::Peer;
+    use notify::EventKind;
+    use notify::event::{CreateKind, ModifyKind, RenameMode};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_replays_events_under_new_watch() {
+        let (client, mut rx) = Client::new(
+            1,
+            Peer::from_uid(1000),
+            Box::new(tokio::io::sink()),
+            LimitsConfig::default().queue_policy(),
+        );
+        let event = |path: &str, kind| WatcherEvent {
+            path: PathBuf::from(path),
+            kind,
+            is_dir: false,
+            unlinked: false,
+            ino: 7,
+        };
+        let catch_up = CatchUp::new(Duration::from_secs(60));
+        catch_up.record(&[
+            event("/mnt/media/new.mkv", EventKind::Create(CreateKind::File)),
+            event(
+                "/mnt/media/old.mkv",
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+            ),
+            event(
+                "/mnt/media/renamed.mkv",
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            ),
+            event("/mnt/other/a.mkv", EventKind::Create(CreateKind::File)),
+        ]);
+
+        let watch = WatchInfo {
+            wd: 3,
+            path: PathBuf::from("/mnt/media"),
+            mask: EventMask::IN_CREATE | EventMask::IN_MOVE,
+            recursive: false,
+            clients: vec![1],
+            missing_since: None,
+            tags: Vec::new(),
+        };
+        assert_eq!(catch_up.replay(&watch, &client), 3);
+
+        let mut cookies = Vec::new();
+        while let Some(framed) = rx.try_recv() {
+            let event = InotifyEvent::from_bytes(&framed[4..]).unwrap();
+            assert_eq!(event.wd, 3);
+            cookies.push(event.cookie);
+        }
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies[0], 0);
+        assert_ne!(cookies[1], 0);
+        assert_eq!(cookies[1], cookies[2]);
+    }
+}
@@ -0,0 +1,537 @@
+//! Snapshot checkpoints for fast restarts.
+//!
+//! Every `checkpoint_interval` each polled root's [`Snapshot`] is written to
+//! `checkpoint_dir`. On startup a root whose checkpoint was taken with the
+//! same scan settings starts from it instead of a fresh baseline walk, so
+//! the first scan after a restart reports what changed while the daemon was
+//! down rather than missing it, and nothing that was already known.
+//!
+//! With `checkpoint_history` set, the checkpoints a save replaces are kept
+//! alongside it, so `fakenotifyd diff` can compare a tree against how it
+//! was at an earlier time.
+//!
+//! Checkpoints are [`store`] files holding the [`ScanKey`] and the snapshot.
+//! Those written before the store header, which began with their version
+//! and nothing else to check, are still read and upgraded.
+//!
+//! A save replaces the checkpoint atomically and keeps the one it replaced,
+//! in the history or else as a [`store::previous`] copy. A checkpoint that
+//! is damaged anyway, or missing after a crash mid-save, gives way to the
+//! newest of those that reads.
+
+use crate::scanner::Snapshot;
+use crate::source::EntryMeta;
+use crate::store::{self, Format};
+use fakenotify_protocol::ChangeDetection;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Checkpoint files; add a migration whenever the payload or [`EntryMeta`]
+/// changes
+const FORMAT: Format = Format::new(*b"SNAP", &[migrate_v1]);
+
+/// Where and how often snapshots are checkpointed
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    dir: PathBuf,
+    interval: Duration,
+    /// Superseded checkpoints kept per root
+    history: usize,
+}
+
+/// A checkpoint file and when it was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    pub path: PathBuf,
+    pub taken: SystemTime,
+}
+
+/// What a snapshot was taken with; a checkpoint taken with anything else
+/// would report differences that are not changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanKey {
+    pub root: PathBuf,
+    pub recursive: bool,
+    pub detection: ChangeDetection,
+    pub fold_case: bool,
+}
+
+impl Checkpoints {
+    /// Checkpoint into `dir` every `interval`
+    pub fn new(dir: PathBuf, interval: Duration) -> Self {
+        Self {
+            dir,
+            interval,
+            history: 0,
+        }
+    }
+
+    /// Keep the `history` most recent checkpoints each save replaces
+    pub fn with_history(mut self, history: usize) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Time between checkpoints of a root
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Write `snapshot`, replacing the root's previous checkpoint only once
+    /// the new one is complete and synced
+    pub fn save(&self, key: &ScanKey, snapshot: &Snapshot) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.file(&key.root);
+        let file = FORMAT.encode(|out| {
+            bincode::serialize_into(&mut *out, key).map_err(io::Error::other)?;
+            bincode::serialize_into(out, snapshot).map_err(io::Error::other)
+        })?;
+        store::write_keeping_previous(&path, &file, &FORMAT)?;
+        if self.history > 0 {
+            self.retire(&key.root, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Move the checkpoint the save of `current` replaced into the root's
+    /// history, dropping the oldest beyond `history`
+    fn retire(&self, root: &Path, current: &Path) -> io::Result<()> {
+        let replaced = store::previous(current);
+        let taken = match std::fs::metadata(&replaced).and_then(|meta| meta.modified()) {
+            Ok(taken) => taken,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let nanos = taken
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        std::fs::rename(
+            replaced,
+            current.with_extension(format!("{nanos}.snapshot")),
+        )?;
+        // After the current checkpoint, the newest is the one just retired
+        for old in self.stored(root).into_iter().skip(1 + self.history) {
+            std::fs::remove_file(old.path)?;
+        }
+        Ok(())
+    }
+
+    /// The root's checkpoint, if there is one taken with `key`
+    ///
+    /// Falls back to the checkpoints it replaced, newest first, when it is
+    /// damaged or missing.
+    pub fn load(&self, key: &ScanKey) -> Option<Snapshot> {
+        let current = self.file(&key.root);
+        let mut candidates = vec![current.clone(), store::previous(&current)];
+        candidates.extend(
+            self.stored(&key.root)
+                .into_iter()
+                .map(|stored| stored.path)
+                .filter(|path| *path != current),
+        );
+        for path in candidates.iter().filter(|path| path.exists()) {
+            match read(path) {
+                Ok((taken_with, _)) if taken_with != *key => {
+                    tracing::info!(path = %path.display(), "Ignoring checkpoint taken with other settings");
+                    return None;
+                }
+                Ok((_, snapshot)) => {
+                    if *path != current {
+                        tracing::warn!(path = %path.display(), "Resuming from an earlier checkpoint");
+                    }
+                    return Some(snapshot);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    tracing::info!(path = %path.display(), error = %e, "Ignoring checkpoint from a newer version");
+                    return None;
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    tracing::warn!(path = %path.display(), error = %e, "Ignoring damaged checkpoint");
+                }
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "Failed to read checkpoint");
+                }
+            }
+        }
+        None
+    }
+
+    /// The root's current checkpoint followed by its history, newest first
+    pub fn stored(&self, root: &Path) -> Vec<Stored> {
+        let current = self.file(root);
+        let stem = current
+            .file_stem()
+            .expect("checkpoint files are named")
+            .to_string_lossy()
+            .into_owned();
+        let mut history: Vec<Stored> = std::fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.strip_prefix(stem.as_str())
+                    .and_then(|rest| rest.strip_prefix('.'))
+                    .and_then(|rest| rest.strip_suffix(".snapshot"))
+                    .is_some_and(|nanos| nanos.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .filter_map(|entry| {
+                let taken = entry.metadata().and_then(|meta| meta.modified()).ok()?;
+                Some(Stored {
+                    path: entry.path(),
+                    taken,
+                })
+            })
+            .collect();
+        history.sort_by_key(|stored| std::cmp::Reverse(stored.taken));
+        if let Ok(taken) = std::fs::metadata(&current).and_then(|meta| meta.modified()) {
+            history.insert(
+                0,
+                Stored {
+                    path: current,
+                    taken,
+                },
+            );
+        }
+        history
+    }
+
+    /// Forget the root's checkpoints
+    pub fn remove(&self, root: &Path) {
+        for stored in self.stored(root) {
+            let _ = std::fs::remove_file(stored.path);
+        }
+        let _ = std::fs::remove_file(store::previous(&self.file(root)));
+    }
+
+    /// The checkpoint file for `root`, named by a hash that stays the same
+    /// across builds
+    fn file(&self, root: &Path) -> PathBuf {
+        // FNV-1a
+        let hash = root
+            .as_os_str()
+            .as_encoded_bytes()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+            });
+        self.dir.join(format!("{hash:016x}.snapshot"))
+    }
+}
+
+/// Read a checkpoint file and the settings it was taken with
+pub fn read(path: &Path) -> io::Result<(ScanKey, Snapshot)> {
+    let file = std::fs::read(path)?;
+    let payload = if store::has_header(&file) {
+        FORMAT.decode(file)?
+    } else {
+        // Before the header, a checkpoint started with its version
+        let Some(version) = file.get(..4) else {
+            return Err(store::invalid("not a checkpoint"));
+        };
+        let version = u32::from_le_bytes(version.try_into().expect("4 bytes"));
+        FORMAT.upgrade(version, file[4..].to_vec())?
+    };
+    let mut input = payload.as_slice();
+    let key = decode(&mut input)?;
+    let snapshot = decode(&mut input)?;
+    Ok((key, snapshot))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(input: &mut &[u8]) -> io::Result<T> {
+    bincode::deserialize_from(input).map_err(|e| store::invalid(e.to_string()))
+}
+
+/// Version 1 entries had no atime; theirs is left unknown, which the
+/// scanner does not count as an access when it moves
+fn migrate_v1(payload: Vec<u8>) -> io::Result<Vec<u8>> {
+    #[derive(Deserialize)]
+    struct EntryMetaV1 {
+        is_dir: bool,
+        size: u64,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        ino: u64,
+        nlink: u64,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        xattrs: u64,
+        content: u64,
+    }
+
+    let mut input = payload.as_slice();
+    let key: ScanKey = decode(&mut input)?;
+    let entries: BTreeMap<PathBuf, EntryMetaV1> = decode(&mut input)?;
+    let snapshot: Snapshot = entries
+        .into_iter()
+        .map(|(path, meta)| {
+            let meta = EntryMeta {
+                is_dir: meta.is_dir,
+                size: meta.size,
+                mtime: meta.mtime,
+                ctime: meta.ctime,
+                atime: SystemTime::UNIX_EPOCH,
+                ino: meta.ino,
+                nlink: meta.nlink,
+                mode: meta.mode,
+                uid: meta.uid,
+                gid: meta.gid,
+                xattrs: meta.xattrs,
+                content: meta.content,
+            };
+            (path, meta)
+        })
+        .collect();
+    let mut upgraded = Vec::new();
+    bincode::serialize_into(&mut upgraded, &key).map_err(io::Error::other)?;
+    bincode::serialize_into(&mut upgraded, &snapshot).map_err(io::Error::other)?;
+    Ok(upgraded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+    use crate::source::sim::SimSource;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_resume_reports_changes_while_down() {
+        let dir = std::env::temp_dir().join(format!(
+            "fakenotify-checkpoint-resume-{}",
+            std::process::id()
+        ));
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(60));
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media");
+        sim.write("/mnt/media/a.mkv", 10);
+        let key = ScanKey {
+            root: PathBuf::from("/mnt/media"),
+            recursive: true,
+            detection: ChangeDetection::Mtime,
+            fold_case: false,
+        };
+        let scanner = Scanner::new(sim.clone(), key.root.clone(), true);
+        checkpoints.save(&key, scanner.snapshot()).unwrap();
+        drop(scanner);
+
+        sim.write("/mnt/media/b.mkv", 10);
+        let baseline = checkpoints.load(&key).unwrap();
+        let mut scanner = Scanner::resume(sim, key.root.clone(), true, baseline);
+        let events = scanner.poll();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path, Path::new("/mnt/media/b.mkv"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_round_trip_and_key_mismatch() {
+        let dir =
+            std::env::temp_dir().join(format!("fakenotify-checkpoint-{}", std::process::id()));
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(60));
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media/show");
+        sim.write("/mnt/media/show/ep1.mkv", 10);
+        let scanner = Scanner::new(sim, PathBuf::from("/mnt/media"), true);
+        let key = ScanKey {
+            root: PathBuf::from("/mnt/media"),
+            recursive: true,
+            detection: ChangeDetection::Mtime,
+            fold_case: false,
+        };
+
+        checkpoints.save(&key, scanner.snapshot()).unwrap();
+        assert_eq!(checkpoints.load(&key).as_ref(), Some(scanner.snapshot()));
+        let other = ScanKey {
+            detection: ChangeDetection::Hash,
+            ..key.clone()
+        };
+        assert!(checkpoints.load(&other).is_none());
+
+        checkpoints.remove(&key.root);
+        assert!(checkpoints.load(&key).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_history_keeps_superseded_checkpoints() {
+        let dir = std::env::temp_dir().join(format!(
+            "fakenotify-checkpoint-history-{}",
+            std::process::id()
+        ));
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(60)).with_history(2);
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media");
+        let key = ScanKey {
+            root: PathBuf::from("/mnt/media"),
+            recursive: true,
+            detection: ChangeDetection::Mtime,
+            fold_case: false,
+        };
+        for file in 0..4 {
+            sim.write(format!("/mnt/media/{file}.mkv"), 10);
+            let scanner = Scanner::new(sim.clone(), key.root.clone(), true);
+            checkpoints.save(&key, scanner.snapshot()).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        // The current checkpoint and the two before it
+        let stored = checkpoints.stored(&key.root);
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[0].path, checkpoints.file(&key.root));
+        let sizes: Vec<usize> = stored
+            .iter()
+            .map(|stored| read(&stored.path).unwrap().1.len())
+            .collect();
+        assert_eq!(sizes, [5, 4, 3]);
+
+        checkpoints.remove(&key.root);
+        assert!(checkpoints.stored(&key.root).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The snapshot every fixture under `testdata/` was written from
+    fn fixture_key() -> ScanKey {
+        ScanKey {
+            root: PathBuf::from("/mnt/media"),
+            recursive: true,
+            detection: ChangeDetection::Mtime,
+            fold_case: false,
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_from_older_versions_load() {
+        let dir = std::env::temp_dir().join(format!(
+            "fakenotify-checkpoint-fixtures-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(60));
+        let key = fixture_key();
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+        for (fixture, atime) in [
+            (
+                &include_bytes!("../testdata/checkpoint-v1.snapshot")[..],
+                SystemTime::UNIX_EPOCH,
+            ),
+            (
+                &include_bytes!("../testdata/checkpoint-v2.snapshot")[..],
+                at(1_700_000_200),
+            ),
+        ] {
+            std::fs::write(checkpoints.file(&key.root), fixture).unwrap();
+            let snapshot = checkpoints.load(&key).unwrap();
+            let paths: Vec<&Path> = snapshot.keys().map(PathBuf::as_path).collect();
+            assert_eq!(
+                paths,
+                ["/mnt/media", "/mnt/media/show", "/mnt/media/show/ep1.mkv"].map(Path::new)
+            );
+            let file = &snapshot[Path::new("/mnt/media/show/ep1.mkv")];
+            assert!(!file.is_dir);
+            assert_eq!(file.size, 1_048_576);
+            assert_eq!(file.mtime, at(1_700_000_000));
+            assert_eq!(file.ctime, at(1_700_000_100));
+            assert_eq!(file.atime, atime);
+            assert_eq!((file.ino, file.mode, file.uid), (4, 0o100644, 1000));
+
+            // Saved again in the current version, nothing is lost
+            checkpoints.save(&key, &snapshot).unwrap();
+            let saved = std::fs::read(checkpoints.file(&key.root)).unwrap();
+            assert!(store::has_header(&saved));
+            assert_eq!(checkpoints.load(&key), Some(snapshot));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_or_newer_checkpoint_is_ignored() {
+        let dir = std::env::temp_dir().join(format!(
+            "fakenotify-checkpoint-damaged-{}",
+            std::process::id()
+        ));
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(60));
+        let key = fixture_key();
+        let snapshot = read(Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/checkpoint-v2.snapshot"
+        )))
+        .unwrap()
+        .1;
+        checkpoints.save(&key, &snapshot).unwrap();
+        let path = checkpoints.file(&key.root);
+        let file = std::fs::read(&path).unwrap();
+
+        let mut flipped = file.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, flipped).unwrap();
+        assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(checkpoints.load(&key).is_none());
+
+        let mut newer = file;
+        newer[12..16].copy_from_slice(&(FORMAT.version() + 1).to_le_bytes());
+        std::fs::write(&path, newer).unwrap();
+        assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert!(checkpoints.load(&key).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_checkpoint_falls_back_to_earlier_one() {
+        let dir = std::env::temp_dir().join(format!(
+            "fakenotify-checkpoint-fallback-{}",
+            std::process::id()
+        ));
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media");
+        let key = fixture_key();
+        let snapshots: Vec<Snapshot> = (0..3)
+            .map(|file| {
+                sim.write(format!("/mnt/media/{file}.mkv"), 10);
+                Scanner::new(sim.clone(), key.root.clone(), true)
+                    .snapshot()
+                    .clone()
+            })
+            .collect();
+        let tear = |path: &Path| {
+            let file = std::fs::read(path).unwrap();
+            std::fs::write(path, &file[..file.len() / 2]).unwrap();
+        };
+
+        // The replaced checkpoint is kept as the previous copy
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(60));
+        let path = checkpoints.file(&key.root);
+        for snapshot in &snapshots {
+            checkpoints.save(&key, snapshot).unwrap();
+        }
+        tear(&path);
+        assert_eq!(checkpoints.load(&key).as_ref(), Some(&snapshots[1]));
+        // Saving over the damaged one keeps the last whole copy
+        checkpoints.save(&key, &snapshots[2]).unwrap();
+        tear(&path);
+        assert_eq!(checkpoints.load(&key).as_ref(), Some(&snapshots[1]));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoints.load(&key).as_ref(), Some(&snapshots[1]));
+        checkpoints.remove(&key.root);
+        assert!(checkpoints.load(&key).is_none());
+
+        // or goes into the history
+        let checkpoints = checkpoints.with_history(2);
+        for snapshot in &snapshots {
+            checkpoints.save(&key, snapshot).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!store::previous(&path).exists());
+        tear(&path);
+        tear(&checkpoints.stored(&key.root)[1].path);
+        assert_eq!(checkpoints.load(&key).as_ref(), Some(&snapshots[0]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
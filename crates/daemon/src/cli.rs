@@ -2,8 +2,17 @@
 //!
 //! Provides commands for starting, stopping, and managing the daemon.
 
-use clap::{Parser, Subcommand};
+use crate::config::parse_duration;
+use crate::diff::{Since, parse_since};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+#[cfg(unix)]
+use fakenotify_protocol::EventMask;
+#[cfg(unix)]
+use std::ffi::OsString;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// FakeNotify Daemon - NFS filesystem watcher that emulates inotify events
 #[derive(Debug, Parser)]
@@ -18,6 +27,17 @@ pub struct Cli {
     #[arg(short, long, global = true, env = "FAKENOTIFYD_LOG_LEVEL")]
     pub log_level: Option<String>,
 
+    /// Output format of the commands that query or change a running daemon
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        env = "FAKENOTIFYD_OUTPUT"
+    )]
+    pub output: OutputFormat,
+
     #[command(subcommand)]
     pub command: Command,
 }
@@ -30,7 +50,8 @@ pub enum Command {
         #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
         socket: Option<PathBuf>,
 
-        /// Run in background (daemonize)
+        /// Run in background (daemonize), in a session of its own and with
+        /// no inherited files past stdio
         #[arg(short, long)]
         daemonize: bool,
 
@@ -40,10 +61,28 @@ pub enum Command {
     },
 
     /// Stop the running daemon
+    ///
+    /// Exits 0 once the daemon was asked to stop (or, with --wait, has
+    /// stopped), 3 if no daemon was running, and 4 if it had to be signalled
+    /// because it did not answer or did not exit within --timeout.
     Stop {
         /// Override socket path
         #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
         socket: Option<PathBuf>,
+
+        /// Wait until the daemon has exited
+        #[arg(short, long)]
+        wait: bool,
+
+        /// Stop waiting after this long and kill the daemon, SIGTERM first
+        /// and SIGKILL if that does not help (implies --wait)
+        #[arg(short, long, value_parser = parse_duration)]
+        timeout: Option<Duration>,
+
+        /// PID file of a daemon started with --pid-file, used to signal it
+        /// when it does not answer on its socket
+        #[arg(long)]
+        pid_file: Option<PathBuf>,
     },
 
     /// Show daemon status
@@ -58,9 +97,9 @@ pub enum Command {
         /// Path to watch
         path: PathBuf,
 
-        /// Polling interval in seconds
-        #[arg(short = 'i', long, default_value = "5")]
-        poll_interval: u64,
+        /// Polling interval (`500ms`, `2s`, or a number of seconds)
+        #[arg(short = 'i', long, default_value = "5s", value_parser = parse_duration)]
+        poll_interval: Duration,
 
         /// Watch recursively (default: true)
         #[arg(short, long, default_value = "true")]
@@ -71,10 +110,16 @@ pub enum Command {
         socket: Option<PathBuf>,
     },
 
-    /// Remove a watch path
+    /// Remove a watch path, or every watch with a tag
     Remove {
         /// Path to stop watching
-        path: PathBuf,
+        #[arg(required_unless_present = "tag")]
+        path: Option<PathBuf>,
+
+        /// Remove every watch carrying this tag, set on the watch or on a
+        /// `[[watch]]` root it lies under, for all clients
+        #[arg(long, conflicts_with = "path")]
+        tag: Option<String>,
 
         /// Override socket path
         #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
@@ -83,28 +128,332 @@ pub enum Command {
 
     /// List watched paths
     List {
+        /// Show only this watch, in detail
+        #[arg(long)]
+        wd: Option<i32>,
+
+        /// Show only watches carrying this tag
+        #[arg(long, conflicts_with = "wd")]
+        tag: Option<String>,
+
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Replace the tags of a watch, to attribute it to a team or
+    /// application
+    Tag {
+        /// Watch descriptor, as shown by `list`
+        wd: i32,
+
+        /// The new tags; none clears them
+        tags: Vec<String>,
+
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Remove watches whose paths no longer exist
+    Prune {
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Print the daemon's watched roots as `[[watch]]` TOML
+    Export {
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Start watching the `[[watch]]` roots of a file (an export or a
+    /// config file) in the running daemon
+    Import {
+        /// File to read roots from
+        file: PathBuf,
+
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// List connected clients
+    Clients {
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Show daemon counters and the paths producing the most events
+    Stats {
+        /// How many watches and directories to list
+        #[arg(short = 'n', long, default_value = "10")]
+        top: u32,
+
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Inspect the configuration without starting the daemon
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Check the configuration and the mounts it watches for anything that
+    /// delays or loses events, such as NFS attribute caching
+    Doctor,
+
+    /// Measure event latency and drops under synthetic file churn, against
+    /// a private daemon using the current configuration
+    #[cfg(unix)]
+    Bench {
+        /// Simulated clients watching the churned directory
+        #[arg(long, default_value = "4")]
+        clients: usize,
+
+        /// Files created per second
+        #[arg(long, default_value = "100")]
+        rate: u32,
+
+        /// How long to create files for
+        #[arg(long, default_value = "10s", value_parser = parse_duration)]
+        duration: Duration,
+
+        /// Poll interval of the benchmarked tree
+        #[arg(short = 'i', long, default_value = "1s", value_parser = parse_duration)]
+        poll_interval: Duration,
+
+        /// Static files in the tree, scanned on every poll
+        #[arg(long, default_value = "1000")]
+        tree_files: usize,
+
+        /// Churned files kept before the oldest is deleted again
+        #[arg(long, default_value = "1000")]
+        live_files: usize,
+
+        /// Where to build the tree, e.g. the NFS mount you plan to watch
+        /// (default: the temp directory)
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+
+    /// Estimate the memory and scan time of watching a tree, and a poll
+    /// interval for it, from a sample of its directories
+    Estimate {
+        /// Tree to estimate, scanned as the configured root covering it would be
+        path: PathBuf,
+
+        /// Directories to list at most; smaller trees are counted exactly
+        #[arg(long, default_value = "5000")]
+        sample: usize,
+    },
+
+    /// Print what was created, modified and deleted under a path since a
+    /// stored checkpoint, as JSON or `rsync --itemize-changes` lines
+    Diff {
+        /// Tree to compare, under a root with checkpoints in `checkpoint_dir`
+        path: PathBuf,
+
+        /// `checkpoint` for the latest, or a time (RFC 3339, or a duration
+        /// ago such as `1h`) to use the newest checkpoint from then or before
+        #[arg(long, default_value = "checkpoint", value_parser = parse_since)]
+        since: Since,
+    },
+
+    /// Write systemd units, tmpfiles.d and preload drop-ins for this host
+    #[cfg(target_os = "linux")]
+    Install(InstallArgs),
+
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
+    /// Print the man page (roff) to stdout
+    Man,
+
+    /// Run a command with its inotify calls served by the daemon
+    #[cfg(unix)]
+    Exec {
+        /// Intercept the inotify syscalls with seccomp instead of LD_PRELOAD
+        /// (covers static and Go binaries; needs Linux 5.9+)
+        #[arg(long)]
+        trace: bool,
+
+        /// Preload library, per-arch directory, or path with a `$LIB` /
+        /// `$PLATFORM` loader token (ignored with --trace)
+        #[arg(long, env = "FAKENOTIFY_PRELOAD")]
+        preload: Option<PathBuf>,
+
         /// Override socket path
         #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
         socket: Option<PathBuf>,
+
+        /// Command to run, followed by its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<OsString>,
     },
+
+    /// Block until an event happens in a directory, like a one-shot
+    /// `inotifywait`
+    ///
+    /// Prints the event as `inotifywait` does and exits 0, or exits 1 if
+    /// --timeout passes first.
+    #[cfg(unix)]
+    Wait {
+        /// Directory, or single file, to watch
+        path: PathBuf,
+
+        /// Event to wait for (`create`, `moved_to`, `close_write`, ...);
+        /// repeatable or comma-separated (default: any)
+        #[arg(short, long = "event", value_name = "EVENT")]
+        events: Vec<EventMask>,
+
+        /// Only names matching this glob, e.g. `*.csv`
+        #[arg(short, long)]
+        glob: Option<String>,
+
+        /// Give up after this long (`300`, `5m`)
+        #[arg(short, long, value_parser = parse_duration)]
+        timeout: Option<Duration>,
+
+        /// Override socket path
+        #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration after merging all sources
+    Dump {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = DumpFormat::Toml)]
+        format: DumpFormat,
+    },
+
+    /// Check a configuration file for unknown keys, missing paths and
+    /// conflicting watches
+    Validate {
+        /// File to check (defaults to --config or the default location)
+        file: Option<PathBuf>,
+    },
+}
+
+/// Options of `install`
+#[cfg(target_os = "linux")]
+#[derive(Debug, Args)]
+pub struct InstallArgs {
+    /// Install a per-user unit (~/.config/systemd/user) instead of a
+    /// system one
+    #[arg(long)]
+    pub user: bool,
+
+    /// Service to start with the preload library; repeatable
+    #[arg(long = "service", value_name = "UNIT")]
+    pub services: Vec<String>,
+
+    /// Also add the preload library to /etc/ld.so.preload, injecting it
+    /// into EVERY process on the host (needs --yes)
+    #[arg(long)]
+    pub ld_so_preload: bool,
+
+    /// Confirm --ld-so-preload
+    #[arg(long)]
+    pub yes: bool,
+
+    /// Preload library, per-arch directory, or path with a `$LIB` /
+    /// `$PLATFORM` loader token
+    #[arg(long, env = "FAKENOTIFY_PRELOAD")]
+    pub preload: Option<PathBuf>,
+
+    /// Write every file under this directory, e.g. to stage a package
+    #[arg(long, value_name = "DIR")]
+    pub root: Option<PathBuf>,
+
+    /// Print the files instead of writing them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Socket for the daemon and the services (default: the system
+    /// socket, or the runtime directory with --user)
+    #[arg(short, long, env = "FAKENOTIFY_SOCKET")]
+    pub socket: Option<PathBuf>,
+}
+
+/// Output format of `config dump`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    Toml,
+    Json,
+}
+
+/// Output format of the commands that talk to a running daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// One JSON object, see `output::SCHEMA_VERSION`
+    Json,
 }
 
 impl Cli {
     /// Get the socket path from command arguments or default
     pub fn socket_path(&self) -> PathBuf {
-        match &self.command {
+        let socket = match &self.command {
             Command::Start { socket, .. }
-            | Command::Stop { socket }
+            | Command::Stop { socket, .. }
             | Command::Status { socket }
             | Command::Add { socket, .. }
             | Command::Remove { socket, .. }
-            | Command::List { socket } => socket
-                .clone()
-                .unwrap_or_else(fakenotify_protocol::get_socket_path_with_xdg_fallback),
-        }
+            | Command::List { socket, .. }
+            | Command::Tag { socket, .. }
+            | Command::Clients { socket }
+            | Command::Export { socket }
+            | Command::Import { socket, .. }
+            | Command::Prune { socket }
+            | Command::Stats { socket, .. } => socket,
+            Command::Config { .. }
+            | Command::Doctor
+            | Command::Estimate { .. }
+            | Command::Diff { .. }
+            | Command::Completions { .. }
+            | Command::Man => &None,
+            #[cfg(unix)]
+            Command::Bench { .. } => &None,
+            #[cfg(unix)]
+            Command::Exec { socket, .. } | Command::Wait { socket, .. } => socket,
+            #[cfg(target_os = "linux")]
+            Command::Install(args) => &args.socket,
+        };
+        socket
+            .clone()
+            .unwrap_or_else(fakenotify_protocol::get_socket_path_with_xdg_fallback)
     }
 }
 
+/// Write the completion script for `shell`, generated from [`Cli`]
+pub fn write_completions(shell: Shell, out: &mut dyn Write) -> io::Result<()> {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    // clap_complete panics on write errors, so render first (e.g. `| head`)
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, name, &mut script);
+    out.write_all(&script)
+}
+
+/// Write the man page for [`Cli`] in roff format
+pub fn write_man(out: &mut dyn Write) -> io::Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(out)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -145,9 +494,154 @@ mod tests {
                 ..
             } => {
                 assert_eq!(path, PathBuf::from("/mnt/media"));
-                assert_eq!(poll_interval, 10);
+                assert_eq!(poll_interval, Duration::from_secs(10));
             }
             _ => panic!("expected Add command"),
         }
     }
+
+    #[test]
+    fn test_cli_parse_remove_by_tag() {
+        let cli = Cli::parse_from(["fakenotifyd", "remove", "--tag", "ci"]);
+        match cli.command {
+            Command::Remove { path, tag, .. } => {
+                assert_eq!(path, None);
+                assert_eq!(tag.as_deref(), Some("ci"));
+            }
+            _ => panic!("expected Remove command"),
+        }
+        assert!(Cli::try_parse_from(["fakenotifyd", "remove"]).is_err());
+        assert!(Cli::try_parse_from(["fakenotifyd", "remove", "/mnt", "--tag", "ci"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parse_config_dump() {
+        let cli = Cli::parse_from(["fakenotifyd", "config", "dump", "--format", "json"]);
+        assert!(matches!(
+            cli.command,
+            Command::Config {
+                command: ConfigCommand::Dump {
+                    format: DumpFormat::Json
+                }
+            }
+        ));
+    }
+
+    #[test]
+    fn test_cli_parse_estimate() {
+        let cli = Cli::parse_from(["fakenotifyd", "estimate", "/mnt/media", "--sample", "200"]);
+        match cli.command {
+            Command::Estimate { path, sample } => {
+                assert_eq!(path, PathBuf::from("/mnt/media"));
+                assert_eq!(sample, 200);
+            }
+            _ => panic!("expected Estimate command"),
+        }
+    }
+
+    #[test]
+    fn test_cli_parse_diff() {
+        let cli = Cli::parse_from(["fakenotifyd", "diff", "/mnt/media/tv"]);
+        match cli.command {
+            Command::Diff { path, since } => {
+                assert_eq!(path, PathBuf::from("/mnt/media/tv"));
+                assert_eq!(since, Since::Checkpoint);
+            }
+            _ => panic!("expected Diff command"),
+        }
+        let cli = Cli::parse_from(["fakenotifyd", "diff", "/mnt/media", "--since", "1d"]);
+        assert!(matches!(
+            cli.command,
+            Command::Diff {
+                since: Since::Time(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cli_parse_exec() {
+        let cli = Cli::parse_from([
+            "fakenotifyd",
+            "exec",
+            "--trace",
+            "--",
+            "sonarr",
+            "-nobrowser",
+        ]);
+        match cli.command {
+            Command::Exec { trace, command, .. } => {
+                assert!(trace);
+                assert_eq!(
+                    command,
+                    vec![OsString::from("sonarr"), OsString::from("-nobrowser")]
+                );
+            }
+            _ => panic!("expected Exec command"),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_cli_parse_wait() {
+        let cli = Cli::parse_from([
+            "fakenotifyd",
+            "wait",
+            "/mnt/drop",
+            "--event",
+            "create",
+            "-e",
+            "moved_to,close_write",
+            "--glob",
+            "*.csv",
+            "--timeout",
+            "300",
+        ]);
+        match cli.command {
+            Command::Wait {
+                path,
+                events,
+                glob,
+                timeout,
+                ..
+            } => {
+                assert_eq!(path, PathBuf::from("/mnt/drop"));
+                assert_eq!(
+                    events,
+                    vec![
+                        EventMask::IN_CREATE,
+                        EventMask::IN_MOVED_TO | EventMask::IN_CLOSE_WRITE
+                    ]
+                );
+                assert_eq!(glob.as_deref(), Some("*.csv"));
+                assert_eq!(timeout, Some(Duration::from_secs(300)));
+            }
+            _ => panic!("expected Wait command"),
+        }
+    }
+
+    #[test]
+    fn test_completions_cover_subcommands() {
+        let cli = Cli::parse_from(["fakenotifyd", "completions", "bash"]);
+        assert!(matches!(
+            cli.command,
+            Command::Completions { shell: Shell::Bash }
+        ));
+
+        let mut script = Vec::new();
+        write_completions(Shell::Bash, &mut script).unwrap();
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("fakenotifyd"));
+        assert!(script.contains("completions"));
+    }
+
+    #[test]
+    fn test_man_page_renders() {
+        let mut page = Vec::new();
+        write_man(&mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.starts_with(".ie"));
+        assert!(page.contains("fakenotifyd"));
+    }
 }
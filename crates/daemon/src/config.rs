@@ -3,15 +3,26 @@
 //! Uses figment to merge configuration from multiple sources:
 //! 1. Default values
 //! 2. Config file (TOML)
-//! 3. Environment variables
-//! 4. Command-line arguments
+//! 3. Fragments: files named by its `include` key, then `conf.d/*.toml`
+//!    next to it, in lexical order
+//! 4. Environment variables
+//! 5. Command-line arguments
+//!
+//! Fragments append to lists such as `[[watch]]` and override other keys.
 
+use crate::media::MediaServerKind;
+use crate::queue::{QueueOverflow, QueuePolicy};
+use crate::rate::NoisyAction;
+pub use fakenotify_protocol::PathMapping;
+use fakenotify_protocol::{ChangeDetection, EventMask, NameFilter, WatchRoot};
 use figment::{
     Figment,
     providers::{Env, Format, Serialized, Toml},
 };
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Main configuration structure
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -23,8 +34,55 @@ pub struct Config {
     /// Watch paths configured at startup
     #[serde(default)]
     pub watch: Vec<WatchConfig>,
+
+    /// Emulated inotify limits
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    /// `[[path_map]]` client-to-host path prefixes (e.g. `/mnt/c/share` in
+    /// WSL to `C:\share` on the host), applied to every client's watch paths
+    /// unless the client set its own table
+    #[serde(default)]
+    pub path_map: Vec<PathMapping>,
+
+    /// `[[client_class]]` queue settings for particular clients, overriding
+    /// those in `[limits]`; the first matching class applies
+    #[serde(default)]
+    pub client_class: Vec<ClientClass>,
+
+    /// `[[media_server]]` Jellyfin or Plex servers whose libraries are
+    /// rescanned as files under them settle
+    #[serde(default)]
+    pub media_server: Vec<MediaServer>,
+
+    /// `[[plugin]]` modules that filter and rewrite events before dispatch,
+    /// in order
+    #[serde(default)]
+    pub plugin: Vec<PluginConfig>,
+
+    /// `[[hook]]` commands plugins can route events to, by name
+    #[serde(default)]
+    pub hook: Vec<HookConfig>,
+
+    /// `[[event_log]]` selections of events written to syslog or the
+    /// systemd journal
+    #[serde(default)]
+    pub event_log: Vec<EventLogConfig>,
+
+    /// `[[trigger]]` files updated when things change under a directory,
+    /// for applications that poll a file rather than watch a tree
+    #[serde(default)]
+    pub trigger: Vec<TriggerConfig>,
+
+    /// Extra config files (or directories of `*.toml` files) merged after
+    /// this one, relative to its directory
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
 }
 
+/// Directory of drop-in fragments next to the main config file
+const FRAGMENT_DIR: &str = "conf.d";
+
 /// Daemon-specific configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DaemonConfig {
@@ -40,9 +98,481 @@ pub struct DaemonConfig {
     #[serde(default = "default_max_clients")]
     pub max_clients: usize,
 
-    /// Enable metrics/stats collection
+    /// Enable metrics/stats collection: rolling event rates per watch and
+    /// directory, reported by `fakenotifyd stats`
     #[serde(default)]
     pub enable_stats: bool,
+
+    /// Events per second above which a directory counts as noisy (0
+    /// disables detection; needs `enable_stats`)
+    #[serde(default)]
+    pub noisy_threshold: u64,
+
+    /// `warn` about noisy directories, or also `exclude` their events until
+    /// they calm down
+    #[serde(default)]
+    pub noisy_action: NoisyAction,
+
+    /// Also accept clients over TCP at this address (e.g. `127.0.0.1:7878`),
+    /// for clients that cannot reach the Unix socket such as WSL talking to a
+    /// daemon on the Windows host
+    #[serde(default)]
+    pub tcp_listen: Option<SocketAddr>,
+
+    /// Also accept VM guests over vsock on this port (Linux), for guests of
+    /// a host exporting the watched filesystems to them over virtiofs, 9p
+    /// or NFS; guests connect to `vsock://2:<port>`
+    #[serde(default)]
+    pub vsock_listen: Option<u32>,
+
+    /// Serve the gRPC gateway (`proto/fakenotify.proto`) at this address,
+    /// for services that would rather use standard tooling than the binary
+    /// protocol; needs a daemon built with the `grpc` feature
+    #[serde(default)]
+    pub grpc_listen: Option<SocketAddr>,
+
+    /// Answer Watchman clients such as Jest and Buck at this Unix socket;
+    /// point `WATCHMAN_SOCK` at it
+    #[serde(default)]
+    pub watchman_socket: Option<PathBuf>,
+
+    /// Translate paths from clients in other mount namespaces (containers)
+    /// through `/proc/<pid>`, so they can watch paths as they see them
+    #[serde(default = "default_resolve_namespaces")]
+    pub resolve_namespaces: bool,
+
+    /// Seconds between heartbeats sent to each client (0 disables them)
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+
+    /// Seconds without hearing from a client that negotiated heartbeats,
+    /// acks included, before it is disconnected and its watches removed
+    /// (0 never)
+    #[serde(default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+
+    /// Idle seconds before the OS starts TCP keepalive probes on TCP
+    /// clients (0 disables keepalive)
+    #[serde(default = "default_tcp_keepalive")]
+    pub tcp_keepalive: u64,
+
+    /// Directory reads in flight per file server across all scans (0
+    /// removes the cap); queued reads are served in arrival order
+    #[serde(default = "default_scan_concurrency")]
+    pub scan_concurrency: usize,
+
+    /// Seconds a single stat or directory listing may take before the
+    /// scan skips that path for the cycle (0 disables the watchdog)
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+
+    /// Seconds a mount may take to answer a health probe before scans
+    /// under it are paused (0 disables probing)
+    #[serde(default = "default_mount_timeout")]
+    pub mount_timeout: u64,
+
+    /// Longest pause, in seconds, between probes of an unresponsive mount
+    #[serde(default = "default_mount_max_backoff")]
+    pub mount_max_backoff: u64,
+
+    /// Hash each entry's extended attributes so `setfattr` and ACL changes
+    /// are reported as IN_ATTRIB (Linux; two extra reads per entry per scan)
+    #[serde(default)]
+    pub track_xattrs: bool,
+
+    /// What watches asking for IN_ACCESS or IN_OPEN get on polled roots,
+    /// where neither can be seen
+    #[serde(default)]
+    pub access_events: AccessEvents,
+
+    /// Events buffered between the watchers and the dispatcher; when full,
+    /// events are coalesced and then dropped with IN_Q_OVERFLOW
+    #[serde(default = "default_event_queue")]
+    pub event_queue: usize,
+
+    /// Seconds after startup during which events are held back, so the
+    /// first scans of large trees do not reach clients as one burst
+    #[serde(default)]
+    pub startup_quiet: u64,
+
+    /// Events per second dispatched to clients across all watches; excess
+    /// events wait in the queue (0 is unlimited)
+    #[serde(default)]
+    pub max_events_per_sec: u64,
+
+    /// Seconds a client has to send the rest of a request once its length
+    /// prefix arrived (0 waits forever)
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+
+    /// Requests a client may have waiting for an answer before it is
+    /// disconnected
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+
+    /// Seconds a client holding no watches may go without a request before
+    /// it is disconnected (0 never)
+    #[serde(default)]
+    pub idle_timeout: u64,
+
+    /// Seconds to spend on shutdown flushing queued events to clients
+    /// before closing their connections
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+
+    /// Seconds between checks for watches whose path no longer exists
+    /// (0 disables automatic pruning)
+    #[serde(default = "default_prune_interval")]
+    pub prune_interval: u64,
+
+    /// Seconds a watched path must stay missing before its watch is
+    /// removed, so brief NFS outages do not drop watches
+    #[serde(default = "default_prune_grace")]
+    pub prune_grace: u64,
+
+    /// File to append an audit record to (JSON lines) for every client
+    /// connect, disconnect, AddWatch and RemoveWatch, naming the peer's
+    /// uid, pid and executable
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Also write audit records to the daemon's log under the `audit`
+    /// target, which ends up in the systemd journal when run as a service
+    #[serde(default)]
+    pub audit_journal: bool,
+
+    /// Directory to checkpoint each polled root's snapshot into, so a
+    /// restarted daemon reports what changed while it was down instead of
+    /// taking a new baseline
+    #[serde(default)]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// Seconds between checkpoints of a root
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: u64,
+
+    /// Superseded checkpoints kept per root, for `fakenotifyd diff --since`
+    /// to compare against (0 keeps only the latest)
+    #[serde(default)]
+    pub checkpoint_history: usize,
+
+    /// Seconds after startup during which a client adding a watch is sent
+    /// what changed under it while the daemon was down, as found by roots
+    /// resumed from `checkpoint_dir` (0 disables)
+    #[serde(default)]
+    pub catch_up: u64,
+
+    /// Frames of at least this many bytes are zstd-compressed for clients
+    /// that ask for it, typically remote ones over TCP (0 disables)
+    #[serde(default = "default_compress_threshold")]
+    pub compress_threshold: usize,
+
+    /// Seconds a client that loses its connection is kept, with its
+    /// watches and the events queued meanwhile, for a new connection to
+    /// resume (0 drops it at once)
+    #[serde(default)]
+    pub session_timeout: u64,
+
+    /// Events kept per path a client watches with a cursor, so a consumer
+    /// that restarts is sent what it missed (0 disables the journal)
+    #[serde(default = "default_journal_size")]
+    pub journal_size: usize,
+
+    /// Journals kept for consumers to resume from once no client watches
+    /// their path; the least recently left are closed first
+    #[serde(default = "default_journal_idle_paths")]
+    pub journal_idle_paths: usize,
+
+    /// Re-read the config file and `conf.d` when they change and apply
+    /// their `[[watch]]` entries, as SIGHUP does
+    #[serde(default = "default_watch_config")]
+    pub watch_config: bool,
+
+    /// Show each client only the watches of clients in its namespace, by
+    /// default the one named after its uid, and let it change only those
+    #[serde(default)]
+    pub multi_tenant: bool,
+}
+
+/// Emulated inotify limits, named after their `/proc/sys/fs/inotify` files
+///
+/// Limits apply per peer uid, as the kernel's do.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LimitsConfig {
+    /// Watches a single user may hold across all connections
+    #[serde(default = "default_max_user_watches")]
+    pub max_user_watches: usize,
+
+    /// Events queued per connection before it overflows
+    #[serde(default = "default_max_queued_events")]
+    pub max_queued_events: usize,
+
+    /// Connections (inotify instances) a single user may hold
+    #[serde(default = "default_max_user_instances")]
+    pub max_user_instances: usize,
+
+    /// Bytes of events queued per connection before it overflows (0 no cap)
+    #[serde(default)]
+    pub max_queued_bytes: usize,
+
+    /// Seconds an event may wait in a connection's queue, or a disconnected
+    /// session's, before the queue counts as overflowed (0 no cap)
+    #[serde(default)]
+    pub max_queue_age: u64,
+
+    /// What an overflowing queue does: `drop_newest` like the kernel,
+    /// `drop_oldest`, `drop_session` or `disconnect`
+    #[serde(default)]
+    pub queue_overflow: QueueOverflow,
+
+    /// Events no client may watch for, by name (`["access", "open"]`);
+    /// they are taken out of every mask a client asks for
+    #[serde(default = "EventMask::empty")]
+    pub denied_events: EventMask,
+}
+
+impl LimitsConfig {
+    /// The queue settings for clients no `[[client_class]]` matches
+    pub fn queue_policy(&self) -> QueuePolicy {
+        QueuePolicy {
+            max_events: self.max_queued_events,
+            max_bytes: self.max_queued_bytes,
+            max_age: Duration::from_secs(self.max_queue_age),
+            overflow: self.queue_overflow,
+        }
+    }
+}
+
+/// Queue settings for the clients matching a `[[client_class]]`
+///
+/// A class with neither `name` nor `uid` matches every client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientClass {
+    /// Program name the client reports on registering, e.g. `jellyfin`
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Peer uid
+    #[serde(default)]
+    pub uid: Option<u32>,
+
+    #[serde(default)]
+    pub max_queued_events: Option<usize>,
+
+    #[serde(default)]
+    pub max_queued_bytes: Option<usize>,
+
+    #[serde(default)]
+    pub max_queue_age: Option<u64>,
+
+    #[serde(default)]
+    pub queue_overflow: Option<QueueOverflow>,
+}
+
+impl ClientClass {
+    /// Whether a client reporting `name`, if it has yet, connected as `uid`
+    /// is in this class
+    pub fn matches(&self, name: Option<&str>, uid: u32) -> bool {
+        self.name.as_deref().is_none_or(|n| Some(n) == name) && self.uid.is_none_or(|u| u == uid)
+    }
+
+    /// `policy` with this class's settings applied
+    pub fn apply(&self, policy: QueuePolicy) -> QueuePolicy {
+        QueuePolicy {
+            max_events: self.max_queued_events.unwrap_or(policy.max_events),
+            max_bytes: self.max_queued_bytes.unwrap_or(policy.max_bytes),
+            max_age: self
+                .max_queue_age
+                .map_or(policy.max_age, Duration::from_secs),
+            overflow: self.queue_overflow.unwrap_or(policy.overflow),
+        }
+    }
+}
+
+/// A Jellyfin or Plex server to ask for partial library scans
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MediaServer {
+    /// `jellyfin` (or Emby) or `plex`
+    pub kind: MediaServerKind,
+
+    /// Base URL, e.g. `http://127.0.0.1:8096`
+    pub url: String,
+
+    /// Jellyfin API key or `X-Plex-Token`
+    #[serde(default)]
+    pub api_key: String,
+
+    /// Quiet time a directory needs before it is scanned: `"30s"`, or a
+    /// bare number of seconds
+    #[serde(
+        default = "default_media_settle",
+        serialize_with = "humantime_serde::serialize",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub settle: Duration,
+
+    /// `[[media_server.library]]` watched directories and their sections
+    #[serde(default)]
+    pub library: Vec<MediaLibrary>,
+
+    /// `[[media_server.path_map]]` prefixes translating the daemon's paths
+    /// to the server's, e.g. when it runs in a container
+    #[serde(default)]
+    pub path_map: Vec<MediaPathMap>,
+}
+
+/// A library directory on a [`MediaServer`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MediaLibrary {
+    /// Directory as the daemon sees it
+    pub path: PathBuf,
+
+    /// Plex library section ID; Jellyfin finds the library by path
+    #[serde(default)]
+    pub section: Option<String>,
+}
+
+/// Path prefix translation for a [`MediaServer`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MediaPathMap {
+    /// Prefix as the daemon sees it
+    pub local: PathBuf,
+
+    /// The same prefix as the server sees it
+    pub remote: PathBuf,
+}
+
+impl MediaServer {
+    /// The library `path` is in, the innermost if they nest
+    pub fn library_for(&self, path: &Path) -> Option<&MediaLibrary> {
+        self.library
+            .iter()
+            .filter(|library| path.starts_with(&library.path))
+            .max_by_key(|library| library.path.components().count())
+    }
+
+    /// `path` as the server sees it, by the longest matching `local` prefix
+    pub fn remote_path(&self, path: &Path) -> PathBuf {
+        self.path_map
+            .iter()
+            .filter_map(|m| Some((m, path.strip_prefix(&m.local).ok()?)))
+            .max_by_key(|(m, _)| m.local.components().count())
+            .map(|(m, rest)| {
+                if rest.as_os_str().is_empty() {
+                    m.remote.clone()
+                } else {
+                    m.remote.join(rest)
+                }
+            })
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+/// A `[[plugin]]` module (see [`crate::plugin`])
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PluginConfig {
+    /// Lua script (`*.lua`), or WASM module in binary or text format
+    pub path: PathBuf,
+
+    /// Instructions a WASM plugin may spend on one event before it is cut
+    /// off and the event passed on unchanged
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+
+    /// Time a Lua script may spend on one event, likewise: `"10ms"`
+    #[serde(
+        default = "default_plugin_budget",
+        serialize_with = "humantime_serde::serialize",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub budget: Duration,
+}
+
+/// A command plugins can route events to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HookConfig {
+    pub name: String,
+
+    /// Program and arguments, run without a shell
+    pub command: Vec<String>,
+
+    /// Only run for events under roots carrying one of these tags; empty
+    /// runs for every routed event
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// A file updated once changes under a directory settle
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TriggerConfig {
+    /// File to update, created if missing
+    pub file: PathBuf,
+
+    /// Directory whose changes update it, under a `[[watch]]`
+    pub under: PathBuf,
+
+    /// Events that count, by name; creations, deletions, moves and
+    /// finished writes by default
+    #[serde(default = "default_trigger_events")]
+    pub events: EventMask,
+
+    /// `touch` to bump the file's modification time, `serial` to write a
+    /// number one higher than it held
+    #[serde(default)]
+    pub mode: TriggerMode,
+
+    /// Quiet time before the file is updated: `"1s"`, or a bare number of
+    /// seconds
+    #[serde(
+        default = "default_trigger_settle",
+        serialize_with = "humantime_serde::serialize",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub settle: Duration,
+}
+
+/// How a `[[trigger]]` file is updated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerMode {
+    /// Bump the modification time, leaving the contents alone
+    #[default]
+    Touch,
+    /// Replace the contents with the next number
+    Serial,
+}
+
+/// Events to write to syslog or the systemd journal
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EventLogConfig {
+    /// `journald` or `syslog`
+    #[serde(default)]
+    pub target: EventLogTarget,
+
+    /// Events to log, by name (`["create", "close_write"]`); all by default
+    #[serde(default = "default_events")]
+    pub events: EventMask,
+
+    /// Only log paths matching one of these globs: whole paths for
+    /// patterns with a `/`, file names for the others; empty logs every
+    /// path
+    #[serde(default)]
+    pub glob: Vec<String>,
+
+    /// Program name the records carry, to filter by with `journalctl -t`
+    #[serde(default = "default_event_log_identifier")]
+    pub identifier: String,
+}
+
+/// Where an `[[event_log]]` writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventLogTarget {
+    /// The systemd journal, with a field per detail
+    #[default]
+    Journald,
+    /// The local syslog socket
+    Syslog,
 }
 
 /// Watch path configuration
@@ -51,13 +581,223 @@ pub struct WatchConfig {
     /// Path to watch
     pub path: PathBuf,
 
-    /// Polling interval in seconds
-    #[serde(default = "default_poll_interval")]
-    pub poll_interval: u64,
+    /// Time between scans: `"500ms"`, `"2s"`, or a bare number of seconds
+    #[serde(
+        default = "default_poll_interval",
+        serialize_with = "humantime_serde::serialize",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub poll_interval: Duration,
 
     /// Whether to watch recursively
     #[serde(default = "default_recursive")]
     pub recursive: bool,
+
+    /// How changes are detected
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// Events reported for this root, by name (`["create", "delete"]`)
+    #[serde(default = "default_events")]
+    pub events: EventMask,
+
+    /// Only report files with these extensions (`["mkv", "jpg"]`)
+    #[serde(default)]
+    pub extensions: Vec<String>,
+
+    /// Only report files whose path under the root matches this regex
+    #[serde(default)]
+    pub name_regex: Option<String>,
+
+    /// What counts as a file modification when polling: `"mtime"`,
+    /// `"ctime"` or `"hash"`
+    #[serde(default)]
+    pub change_detection: ChangeDetection,
+
+    /// Open every directory before reading it, so NFS drops cached
+    /// listings instead of serving them for up to `acdirmax`
+    #[serde(default)]
+    pub revalidate: bool,
+
+    /// Compare paths under the root without regard to case; unset turns
+    /// it on for CIFS mounts
+    #[serde(default)]
+    pub case_insensitive: Option<bool>,
+
+    /// Report an entry deleted only once this many scans in a row missed
+    /// it, riding out NFS listings that briefly come back short
+    #[serde(default = "default_confirm_deletes")]
+    pub confirm_deletes: u32,
+
+    /// Stat an entry a scan missed before reporting it deleted: ENOENT
+    /// confirms the deletion at once, an answer means the listing lied
+    #[serde(default)]
+    pub restat_deletes: bool,
+
+    /// Hold a file's writes back until its size and mtime stayed the same
+    /// for this many scans, then report IN_MODIFY and IN_CLOSE_WRITE
+    /// (0 reports writes as each scan sees them)
+    #[serde(default)]
+    pub settle_scans: u32,
+
+    /// Name endings of temporary files that finished files are renamed
+    /// from (`[".part", ".tmp"]`): such files report nothing, and the
+    /// rename reports IN_MOVED_TO and IN_CLOSE_WRITE
+    #[serde(default)]
+    pub partial_suffixes: Vec<String>,
+
+    /// Labels naming the team or application the root belongs to, shown
+    /// by `list` and `stats` and selected by `remove --tag` and hooks
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Events clients may not watch for under this root, on top of
+    /// `limits.denied_events`
+    #[serde(default = "EventMask::empty")]
+    pub denied_events: EventMask,
+}
+
+impl WatchConfig {
+    /// Whether paths under this root compare without regard to case
+    pub fn folds_case(&self) -> bool {
+        self.case_insensitive
+            .unwrap_or_else(|| crate::fold::is_case_insensitive_fs(&self.path))
+    }
+
+    /// The name filter for this root's events
+    pub fn name_filter(&self) -> NameFilter {
+        NameFilter {
+            extensions: self.extensions.clone(),
+            regex: self.name_regex.clone(),
+        }
+    }
+}
+
+impl From<&WatchConfig> for WatchRoot {
+    fn from(config: &WatchConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            poll_interval_ms: config.poll_interval.as_millis() as u64,
+            recursive: config.recursive,
+            native: config.backend == Backend::Native,
+            mask: config.events.bits(),
+            extensions: config.extensions.clone(),
+            name_regex: config.name_regex.clone(),
+            change_detection: config.change_detection,
+            revalidate: config.revalidate,
+            case_insensitive: config.case_insensitive,
+            confirm_deletes: config.confirm_deletes,
+            restat_deletes: config.restat_deletes,
+            tags: config.tags.clone(),
+            denied_mask: config.denied_events.bits(),
+            settle_scans: config.settle_scans,
+            partial_suffixes: config.partial_suffixes.clone(),
+        }
+    }
+}
+
+impl From<WatchRoot> for WatchConfig {
+    fn from(root: WatchRoot) -> Self {
+        Self {
+            path: root.path,
+            poll_interval: Duration::from_millis(root.poll_interval_ms),
+            recursive: root.recursive,
+            backend: if root.native {
+                Backend::Native
+            } else {
+                Backend::Poll
+            },
+            events: EventMask::from_bits_truncate(root.mask),
+            extensions: root.extensions,
+            name_regex: root.name_regex,
+            change_detection: root.change_detection,
+            revalidate: root.revalidate,
+            case_insensitive: root.case_insensitive,
+            confirm_deletes: root.confirm_deletes,
+            restat_deletes: root.restat_deletes,
+            settle_scans: root.settle_scans,
+            partial_suffixes: root.partial_suffixes,
+            tags: root.tags,
+            denied_events: EventMask::from_bits_truncate(root.denied_mask),
+        }
+    }
+}
+
+/// The `[[watch]]` tables of a file, as written by `export` and read by
+/// `import`; any other keys (a full config file) are ignored
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WatchList {
+    #[serde(default)]
+    pub watch: Vec<WatchConfig>,
+}
+
+/// Change detection backend for a watched root
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Re-scan the tree every `poll_interval` (works on any filesystem)
+    #[default]
+    Poll,
+    /// The OS notification API: ReadDirectoryChangesW on Windows, FSEvents
+    /// on macOS, inotify on Linux. Only useful where the OS actually sees
+    /// the changes, e.g. a Windows host watching its own disks or SMB shares
+    /// on behalf of WSL
+    Native,
+}
+
+/// What to do about IN_ACCESS and IN_OPEN on polled roots, which a scan
+/// cannot observe
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessEvents {
+    /// Add the watch; those events just never arrive
+    #[default]
+    Ignore,
+    /// Refuse watches asking for them with `ErrorCode::UnsupportedMask`
+    Reject,
+    /// Report IN_ACCESS when a file's atime moves between scans; IN_OPEN
+    /// is still never reported
+    Atime,
+}
+
+/// Translate a client path to the daemon's view using the longest matching
+/// `client` prefix; paths outside every mapping are returned unchanged
+pub fn map_to_host(map: &[PathMapping], path: &Path) -> PathBuf {
+    map.iter()
+        .filter_map(|m| Some((m, path.strip_prefix(&m.client).ok()?)))
+        .max_by_key(|(m, _)| m.client.components().count())
+        .map(|(m, rest)| {
+            if rest.as_os_str().is_empty() {
+                m.host.clone()
+            } else {
+                m.host.join(rest)
+            }
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Parse a duration such as `500ms` or `1m 30s`; a bare number is seconds
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    if let Ok(secs) = text.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime_serde::re::humantime::parse_duration(text).map_err(|e| e.to_string())
+}
+
+/// Accept a duration string or, as older configs have it, integer seconds
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(u64),
+        Text(String),
+    }
+
+    match Raw::deserialize(deserializer)? {
+        Raw::Seconds(secs) => Ok(Duration::from_secs(secs)),
+        Raw::Text(text) => parse_duration(&text).map_err(serde::de::Error::custom),
+    }
 }
 
 fn default_socket_path() -> PathBuf {
@@ -72,14 +812,138 @@ fn default_max_clients() -> usize {
     100
 }
 
-fn default_poll_interval() -> u64 {
-    5
+fn default_max_user_watches() -> usize {
+    8192
+}
+
+fn default_max_queued_events() -> usize {
+    16384
+}
+
+fn default_max_user_instances() -> usize {
+    128
+}
+
+fn default_scan_concurrency() -> usize {
+    4
+}
+
+fn default_event_queue() -> usize {
+    65536
+}
+
+fn default_scan_timeout() -> u64 {
+    30
+}
+
+fn default_mount_timeout() -> u64 {
+    10
+}
+
+fn default_mount_max_backoff() -> u64 {
+    300
+}
+
+fn default_poll_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_event_log_identifier() -> String {
+    "fakenotifyd".to_string()
+}
+
+fn default_trigger_events() -> EventMask {
+    EventMask::IN_CREATE
+        | EventMask::IN_DELETE
+        | EventMask::IN_MOVED_FROM
+        | EventMask::IN_MOVED_TO
+        | EventMask::IN_CLOSE_WRITE
+}
+
+fn default_trigger_settle() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_media_settle() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_plugin_fuel() -> u64 {
+    1_000_000
+}
+
+fn default_plugin_budget() -> Duration {
+    Duration::from_millis(10)
 }
 
 fn default_recursive() -> bool {
     true
 }
 
+fn default_confirm_deletes() -> u32 {
+    1
+}
+
+fn default_resolve_namespaces() -> bool {
+    true
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30
+}
+
+fn default_heartbeat_timeout() -> u64 {
+    90
+}
+
+fn default_tcp_keepalive() -> u64 {
+    60
+}
+
+fn default_events() -> EventMask {
+    EventMask::IN_ALL_EVENTS
+}
+
+fn default_request_timeout() -> u64 {
+    10
+}
+
+fn default_max_in_flight() -> usize {
+    64
+}
+
+fn default_drain_timeout() -> u64 {
+    5
+}
+
+fn default_prune_interval() -> u64 {
+    60
+}
+
+fn default_prune_grace() -> u64 {
+    600
+}
+
+fn default_checkpoint_interval() -> u64 {
+    300
+}
+
+fn default_compress_threshold() -> usize {
+    1024
+}
+
+fn default_watch_config() -> bool {
+    true
+}
+
+fn default_journal_size() -> usize {
+    4096
+}
+
+fn default_journal_idle_paths() -> usize {
+    64
+}
+
 impl Default for DaemonConfig {
     fn default() -> Self {
         Self {
@@ -87,6 +951,57 @@ impl Default for DaemonConfig {
             log_level: default_log_level(),
             max_clients: default_max_clients(),
             enable_stats: false,
+            noisy_threshold: 0,
+            noisy_action: NoisyAction::default(),
+            scan_concurrency: default_scan_concurrency(),
+            scan_timeout: default_scan_timeout(),
+            mount_timeout: default_mount_timeout(),
+            mount_max_backoff: default_mount_max_backoff(),
+            track_xattrs: false,
+            access_events: AccessEvents::default(),
+            event_queue: default_event_queue(),
+            startup_quiet: 0,
+            max_events_per_sec: 0,
+            tcp_listen: None,
+            vsock_listen: None,
+            grpc_listen: None,
+            watchman_socket: None,
+            resolve_namespaces: default_resolve_namespaces(),
+            heartbeat_interval: default_heartbeat_interval(),
+            heartbeat_timeout: default_heartbeat_timeout(),
+            tcp_keepalive: default_tcp_keepalive(),
+            request_timeout: default_request_timeout(),
+            max_in_flight: default_max_in_flight(),
+            idle_timeout: 0,
+            drain_timeout: default_drain_timeout(),
+            prune_interval: default_prune_interval(),
+            prune_grace: default_prune_grace(),
+            audit_log: None,
+            audit_journal: false,
+            checkpoint_dir: None,
+            checkpoint_interval: default_checkpoint_interval(),
+            checkpoint_history: 0,
+            catch_up: 0,
+            compress_threshold: default_compress_threshold(),
+            session_timeout: 0,
+            journal_size: default_journal_size(),
+            journal_idle_paths: default_journal_idle_paths(),
+            watch_config: default_watch_config(),
+            multi_tenant: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_user_watches: default_max_user_watches(),
+            max_queued_events: default_max_queued_events(),
+            max_user_instances: default_max_user_instances(),
+            max_queued_bytes: 0,
+            max_queue_age: 0,
+            queue_overflow: QueueOverflow::default(),
+            denied_events: EventMask::empty(),
         }
     }
 }
@@ -97,23 +1012,11 @@ impl Config {
     pub fn load(config_file: Option<&PathBuf>) -> Result<Self, figment::Error> {
         let mut figment = Figment::new().merge(Serialized::defaults(Config::default()));
 
-        // Add config file if provided
-        if let Some(path) = config_file {
-            figment = figment.merge(Toml::file(path));
-        } else {
-            // Try default config locations
-            let default_paths = [
-                PathBuf::from("/etc/fakenotify/config.toml"),
-                dirs::config_dir()
-                    .unwrap_or_default()
-                    .join("fakenotify/config.toml"),
-            ];
-
-            for path in &default_paths {
-                if path.exists() {
-                    figment = figment.merge(Toml::file(path));
-                    break;
-                }
+        // The given config file, else the first default location in use
+        if let Some(path) = config_file.cloned().or_else(find_config_file) {
+            figment = figment.merge(Toml::file(&path));
+            for fragment in fragments(&path)? {
+                figment = figment.admerge(Toml::file(fragment));
             }
         }
 
@@ -140,6 +1043,68 @@ impl Config {
     }
 }
 
+/// The first default location that has a config file or a fragment
+/// directory
+pub fn find_config_file() -> Option<PathBuf> {
+    let default_paths = [
+        PathBuf::from("/etc/fakenotify/config.toml"),
+        dirs::config_dir()
+            .unwrap_or_default()
+            .join("fakenotify/config.toml"),
+    ];
+    default_paths
+        .into_iter()
+        .find(|path| path.exists() || fragment_dir(path).is_dir())
+}
+
+/// `conf.d` next to the main config file
+pub fn fragment_dir(main: &Path) -> PathBuf {
+    main.parent().unwrap_or(Path::new(".")).join(FRAGMENT_DIR)
+}
+
+/// Files merged on top of `main`, in order: its `include` entries, then
+/// `conf.d/*.toml`
+#[allow(clippy::result_large_err)]
+pub fn fragments(main: &Path) -> Result<Vec<PathBuf>, figment::Error> {
+    let base = main.parent().unwrap_or(Path::new("."));
+    let includes: Vec<PathBuf> = match Figment::from(Toml::file(main)).extract_inner("include") {
+        Ok(includes) => includes,
+        Err(e) if e.missing() => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut files = Vec::new();
+    for include in includes {
+        let path = base.join(include);
+        if path.is_dir() {
+            files.extend(toml_files(&path)?);
+        } else if path.exists() {
+            files.push(path);
+        } else {
+            return Err(format!("included config {} does not exist", path.display()).into());
+        }
+    }
+
+    let dir = fragment_dir(main);
+    if dir.is_dir() {
+        files.extend(toml_files(&dir)?);
+    }
+    Ok(files)
+}
+
+/// The `*.toml` files in `dir`, in lexical order
+#[allow(clippy::result_large_err)]
+fn toml_files(dir: &Path) -> Result<Vec<PathBuf>, figment::Error> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("failed to read {}: {e}", dir.display()))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml") && path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -150,6 +1115,274 @@ mod tests {
         assert_eq!(config.daemon.log_level, "info");
         assert_eq!(config.daemon.max_clients, 100);
         assert!(config.watch.is_empty());
+        assert_eq!(config.limits.max_user_watches, 8192);
+        assert_eq!(config.limits.max_queued_events, 16384);
+        assert_eq!(config.limits.max_user_instances, 128);
+        assert_eq!(config.daemon.heartbeat_interval, 30);
+        assert_eq!(config.daemon.heartbeat_timeout, 90);
+        assert_eq!(config.daemon.drain_timeout, 5);
+        assert_eq!(config.daemon.prune_grace, 600);
+    }
+
+    #[test]
+    fn test_partial_limits_keep_defaults() {
+        let config: Config = Figment::new()
+            .merge(Toml::string("[limits]\nmax_user_watches = 10\n"))
+            .extract()
+            .unwrap();
+        assert_eq!(config.limits.max_user_watches, 10);
+        assert_eq!(config.limits.max_user_instances, 128);
+    }
+
+    #[test]
+    fn test_client_class_overrides_limits() {
+        let config: Config = Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(Toml::string(
+                r#"
+                [limits]
+                max_queued_bytes = 1048576
+                queue_overflow = "drop_oldest"
+
+                [[client_class]]
+                name = "jellyfin"
+                max_queue_age = 60
+                queue_overflow = "drop_session"
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        let policy = config.limits.queue_policy();
+        assert_eq!(policy.max_bytes, 1048576);
+        assert_eq!(policy.overflow, QueueOverflow::DropOldest);
+
+        let class = &config.client_class[0];
+        assert!(class.matches(Some("jellyfin"), 1000));
+        assert!(!class.matches(None, 1000));
+        let policy = class.apply(policy);
+        assert_eq!(policy.max_events, 16384);
+        assert_eq!(policy.max_bytes, 1048576);
+        assert_eq!(policy.max_age, Duration::from_secs(60));
+        assert_eq!(policy.overflow, QueueOverflow::DropSession);
+    }
+
+    #[test]
+    fn test_media_server_config() {
+        let config: Config = Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(Toml::string(
+                r#"
+                [[media_server]]
+                kind = "plex"
+                url = "http://127.0.0.1:32400"
+                api_key = "token"
+                settle = "1m"
+
+                [[media_server.library]]
+                path = "/mnt/media"
+                section = "1"
+
+                [[media_server.library]]
+                path = "/mnt/media/tv"
+                section = "2"
+
+                [[media_server.path_map]]
+                local = "/mnt/media"
+                remote = "/data"
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        let server = &config.media_server[0];
+        assert_eq!(server.kind, MediaServerKind::Plex);
+        assert_eq!(server.settle, Duration::from_secs(60));
+
+        let show = Path::new("/mnt/media/tv/Show/Season 1");
+        assert_eq!(
+            server.library_for(show).unwrap().section.as_deref(),
+            Some("2")
+        );
+        assert!(server.library_for(Path::new("/mnt/other")).is_none());
+        assert_eq!(
+            server.remote_path(show),
+            PathBuf::from("/data/tv/Show/Season 1")
+        );
+        assert_eq!(
+            server.remote_path(Path::new("/srv/x")),
+            PathBuf::from("/srv/x")
+        );
+    }
+
+    #[test]
+    fn test_wsl_interop_config() {
+        let config: Config = Figment::new()
+            .merge(Serialized::defaults(Config::default()))
+            .merge(Toml::string(
+                r#"
+                [daemon]
+                tcp_listen = "127.0.0.1:7878"
+
+                [[watch]]
+                path = 'C:\share'
+                backend = "native"
+
+                [[path_map]]
+                host = 'C:\share'
+                client = "/mnt/c/share"
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            config.daemon.tcp_listen,
+            Some("127.0.0.1:7878".parse().unwrap())
+        );
+        assert_eq!(config.watch[0].backend, Backend::Native);
+        assert_eq!(config.path_map.len(), 1);
+        assert!(Config::default().daemon.tcp_listen.is_none());
+    }
+
+    #[test]
+    fn test_load_merges_fragments() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-conf-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+
+        write(
+            "config.toml",
+            "include = [\"extra.toml\"]\n[daemon]\nlog_level = \"debug\"\n\n[[watch]]\npath = \"/mnt/a\"\n",
+        );
+        write("extra.toml", "[limits]\nmax_user_watches = 10\n");
+        write("conf.d/20-c.toml", "[[watch]]\npath = \"/mnt/c\"\n");
+        write(
+            "conf.d/10-b.toml",
+            "[daemon]\nlog_level = \"trace\"\n\n[[watch]]\npath = \"/mnt/b\"\n",
+        );
+        write("conf.d/README", "not a fragment");
+
+        let config = Config::load(Some(&dir.join("config.toml"))).unwrap();
+        let paths: Vec<_> = config.watch.iter().map(|w| w.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/mnt/a"),
+                PathBuf::from("/mnt/b"),
+                PathBuf::from("/mnt/c")
+            ]
+        );
+        assert_eq!(config.daemon.log_level, "trace");
+        assert_eq!(config.limits.max_user_watches, 10);
+
+        write("config.toml", "include = [\"missing.toml\"]\n");
+        assert!(Config::load(Some(&dir.join("config.toml"))).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_watch_poll_interval() {
+        let config: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [[watch]]
+                path = "/mnt/a"
+                poll_interval = "500ms"
+
+                [[watch]]
+                path = "/mnt/b"
+                poll_interval = 10
+
+                [[watch]]
+                path = "/mnt/c"
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        let intervals: Vec<_> = config.watch.iter().map(|w| w.poll_interval).collect();
+        assert_eq!(
+            intervals,
+            vec![
+                Duration::from_millis(500),
+                Duration::from_secs(10),
+                Duration::from_secs(5)
+            ]
+        );
+
+        let dumped = toml::to_string(&config).unwrap();
+        assert!(dumped.contains("poll_interval = \"500ms\""));
+        assert_eq!(parse_duration("1m 30s"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("fast").is_err());
+    }
+
+    #[test]
+    fn test_watch_events() {
+        let config: Config = Figment::new()
+            .merge(Toml::string(
+                r#"
+                [[watch]]
+                path = "/mnt/media"
+                events = ["create", "delete", "move", "delete_self"]
+
+                [[watch]]
+                path = "/mnt/downloads"
+                denied_events = ["access", "open"]
+                "#,
+            ))
+            .extract()
+            .unwrap();
+        assert_eq!(
+            config.watch[0].events,
+            EventMask::IN_CREATE
+                | EventMask::IN_DELETE
+                | EventMask::IN_MOVE
+                | EventMask::IN_DELETE_SELF
+        );
+        assert_eq!(config.watch[1].events, EventMask::IN_ALL_EVENTS);
+        assert!(config.watch[0].denied_events.is_empty());
+        assert_eq!(
+            config.watch[1].denied_events,
+            EventMask::IN_ACCESS | EventMask::IN_OPEN
+        );
+
+        let bad = Figment::new()
+            .merge(Toml::string(
+                "[[watch]]\npath = \"/a\"\nevents = [\"created\"]\n",
+            ))
+            .extract::<Config>();
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn test_map_to_host() {
+        let map = vec![
+            PathMapping {
+                host: "/srv/share".into(),
+                client: "/mnt/c/share".into(),
+            },
+            PathMapping {
+                host: "/srv/tv".into(),
+                client: "/mnt/c/share/tv".into(),
+            },
+        ];
+        assert_eq!(
+            map_to_host(&map, Path::new("/mnt/c/share/movies")),
+            PathBuf::from("/srv/share/movies")
+        );
+        // Longest prefix wins
+        assert_eq!(
+            map_to_host(&map, Path::new("/mnt/c/share/tv/show")),
+            PathBuf::from("/srv/tv/show")
+        );
+        assert_eq!(
+            map_to_host(&map, Path::new("/mnt/c/share")),
+            PathBuf::from("/srv/share")
+        );
+        // Component-wise, not string prefixes
+        assert_eq!(
+            map_to_host(&map, Path::new("/mnt/c/shared")),
+            PathBuf::from("/mnt/c/shared")
+        );
     }
 
     #[test]
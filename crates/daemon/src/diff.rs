@@ -0,0 +1,235 @@
+//! `fakenotifyd diff`: what changed in a tree since a stored snapshot.
+//!
+//! The tree is scanned now, through the same [`ScanSource`] stack as its
+//! root, and compared with a checkpoint of that root: the latest, or the
+//! newest taken at or before a given time when `checkpoint_history` keeps
+//! older ones. Renames show up as a deletion and a creation, and only
+//! changes to a file's data count as modifications, as the daemon itself
+//! would report them with `IN_MODIFY`.
+//!
+//! [`ScanSource`]: crate::source::ScanSource
+
+use crate::checkpoint::Stored;
+use crate::scanner::{Snapshot, data_changed};
+use crate::source::EntryMeta;
+use fakenotify_protocol::ChangeDetection;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Which stored snapshot to compare against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Since {
+    /// The root's current checkpoint
+    Checkpoint,
+    /// The newest checkpoint taken at or before this time
+    Time(SystemTime),
+}
+
+/// Parse `checkpoint`, an RFC 3339 time such as `2024-05-01T06:00:00Z`,
+/// or a duration ago such as `1h`
+pub fn parse_since(text: &str) -> Result<Since, String> {
+    let text = text.trim();
+    if text == "checkpoint" {
+        return Ok(Since::Checkpoint);
+    }
+    if let Ok(time) = humantime_serde::re::humantime::parse_rfc3339_weak(text) {
+        return Ok(Since::Time(time));
+    }
+    let ago = crate::config::parse_duration(text).map_err(|_| {
+        format!("expected `checkpoint`, a time such as 2024-05-01T06:00:00Z, or a duration such as 1h; got {text:?}")
+    })?;
+    SystemTime::now()
+        .checked_sub(ago)
+        .map(Since::Time)
+        .ok_or_else(|| format!("{text} ago is out of range"))
+}
+
+/// The snapshot `since` selects from `stored`, newest first
+pub fn pick(stored: &[Stored], since: Since) -> Option<&Stored> {
+    match since {
+        Since::Checkpoint => stored.first(),
+        Since::Time(time) => stored.iter().find(|stored| stored.taken <= time),
+    }
+}
+
+/// How an entry changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    /// The file's data changed; `size` and `mtime` say which of those moved
+    Modified {
+        size: bool,
+        mtime: bool,
+    },
+    Deleted,
+}
+
+/// One changed entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Changed {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub change: Change,
+}
+
+/// The entries under `under` (not `under` itself) that differ between
+/// `old` and `new`, in path order; an entry that changed kind is deleted
+/// and created again
+pub fn changes(
+    old: &Snapshot,
+    new: &Snapshot,
+    under: &Path,
+    detection: ChangeDetection,
+) -> Vec<Changed> {
+    let (mut old, mut new) = (inside(old, under).peekable(), inside(new, under).peekable());
+    let mut changes = Vec::new();
+    let changed = |path: &PathBuf, is_dir, change| Changed {
+        path: path.clone(),
+        is_dir,
+        change,
+    };
+    loop {
+        let order = match (old.peek(), new.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((old, _)), Some((new, _))) => old.cmp(new),
+        };
+        match order {
+            Ordering::Less => {
+                let (path, meta) = old.next().expect("peeked");
+                changes.push(changed(path, meta.is_dir, Change::Deleted));
+            }
+            Ordering::Greater => {
+                let (path, meta) = new.next().expect("peeked");
+                changes.push(changed(path, meta.is_dir, Change::Created));
+            }
+            Ordering::Equal => {
+                let (path, before) = old.next().expect("peeked");
+                let (_, after) = new.next().expect("peeked");
+                if before.is_dir != after.is_dir {
+                    changes.push(changed(path, before.is_dir, Change::Deleted));
+                    changes.push(changed(path, after.is_dir, Change::Created));
+                } else if !after.is_dir && data_changed(before, after, detection) {
+                    let change = Change::Modified {
+                        size: before.size != after.size,
+                        mtime: before.mtime != after.mtime,
+                    };
+                    changes.push(changed(path, false, change));
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// Entries of `snapshot` below `under`
+fn inside<'a>(
+    snapshot: &'a Snapshot,
+    under: &'a Path,
+) -> impl Iterator<Item = (&'a PathBuf, &'a EntryMeta)> + 'a {
+    snapshot
+        .range(under.to_path_buf()..)
+        .take_while(move |(path, _)| path.starts_with(under))
+        .filter(move |(path, _)| path.as_path() != under)
+}
+
+/// The line `rsync --itemize-changes` would print for `changed`, with its
+/// path relative to `under`
+pub fn itemize(changed: &Changed, under: &Path) -> String {
+    let mut name = changed
+        .path
+        .strip_prefix(under)
+        .unwrap_or(&changed.path)
+        .display()
+        .to_string();
+    if changed.is_dir {
+        name.push('/');
+    }
+    let kind = if changed.is_dir { 'd' } else { 'f' };
+    match changed.change {
+        Change::Created if changed.is_dir => format!("c{kind}+++++++++ {name}"),
+        Change::Created => format!(">{kind}+++++++++ {name}"),
+        Change::Modified { size, mtime } => format!(
+            ">{kind}.{}{}...... {name}",
+            if size { 's' } else { '.' },
+            if mtime { 't' } else { '.' }
+        ),
+        Change::Deleted => format!("*deleting   {name}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+    use crate::source::sim::SimSource;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_changes_under_a_subtree() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media/tv/show");
+        sim.mkdir_all("/mnt/media/movies");
+        sim.write("/mnt/media/tv/show/ep1.mkv", 10);
+        sim.write("/mnt/media/tv/show/ep2.mkv", 10);
+        sim.write("/mnt/media/tv/old.nfo", 1);
+        sim.write("/mnt/media/movies/a.mkv", 10);
+        let root = PathBuf::from("/mnt/media");
+        let before = Scanner::new(sim.clone(), root.clone(), true)
+            .snapshot()
+            .clone();
+
+        sim.advance(Duration::from_secs(60));
+        sim.write("/mnt/media/tv/show/ep1.mkv", 20);
+        sim.mkdir_all("/mnt/media/tv/show/extras");
+        sim.write("/mnt/media/tv/show/extras/b.mkv", 5);
+        sim.remove("/mnt/media/tv/old.nfo");
+        sim.write("/mnt/media/movies/b.mkv", 10);
+        let after = Scanner::new(sim, root, true).snapshot().clone();
+
+        let under = Path::new("/mnt/media/tv");
+        let changes = changes(&before, &after, under, ChangeDetection::Mtime);
+        let lines: Vec<String> = changes
+            .iter()
+            .map(|changed| itemize(changed, under))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "*deleting   old.nfo",
+                ">f.st...... show/ep1.mkv",
+                "cd+++++++++ show/extras/",
+                ">f+++++++++ show/extras/b.mkv",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_since() {
+        assert_eq!(parse_since("checkpoint"), Ok(Since::Checkpoint));
+        assert_eq!(
+            parse_since("2024-05-01T06:00:00Z"),
+            Ok(Since::Time(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_543_200)
+            ))
+        );
+        let Ok(Since::Time(ago)) = parse_since("1h") else {
+            panic!("expected a time");
+        };
+        assert!(ago < SystemTime::now() - Duration::from_secs(3599));
+        assert!(parse_since("yesterday").is_err());
+
+        let at = |secs| Stored {
+            path: PathBuf::from(format!("{secs}")),
+            taken: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let stored = [at(300), at(200), at(100)];
+        assert_eq!(pick(&stored, Since::Checkpoint), Some(&stored[0]));
+        assert_eq!(pick(&stored, Since::Time(at(250).taken)), Some(&stored[1]));
+        assert_eq!(pick(&stored, Since::Time(at(200).taken)), Some(&stored[1]));
+        assert_eq!(pick(&stored, Since::Time(at(50).taken)), None);
+    }
+}
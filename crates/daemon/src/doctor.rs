@@ -0,0 +1,213 @@
+//! Host checks for `fakenotifyd doctor`.
+//!
+//! `config validate` looks at the configuration; this also looks at what
+//! the watches sit on. NFS and SMB clients cache attributes, which bounds
+//! how soon any scan can see a change however short `poll_interval` is.
+//! With `access_events = "atime"`, the atime option of each mount also
+//! decides whether reads can be seen at all.
+
+use crate::config::{AccessEvents, Backend, Config, WatchConfig};
+use crate::validate::Issue;
+use std::time::Duration;
+
+/// How long a mount's client may serve cached attributes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttrCache {
+    /// Longest a file's attributes are trusted (`acregmax`)
+    pub files: Duration,
+    /// Longest a directory's attributes, and with them its listing, are
+    /// trusted (`acdirmax`)
+    pub dirs: Duration,
+}
+
+impl AttrCache {
+    /// The attribute cache of a `fs_type` mount with `options`, or `None`
+    /// for filesystems without one
+    pub fn of_mount(fs_type: &str, options: &str) -> Option<Self> {
+        let default = match fs_type {
+            "nfs" | "nfs4" => Duration::from_secs(60),
+            "cifs" | "smb3" => Duration::from_secs(1),
+            _ => return None,
+        };
+        let mut cache = Self {
+            files: default,
+            dirs: default,
+        };
+        for option in options.split(',') {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            let secs = value.parse().ok().map(Duration::from_secs);
+            match (key, secs) {
+                ("noac", _) => {
+                    return Some(Self {
+                        files: Duration::ZERO,
+                        dirs: Duration::ZERO,
+                    });
+                }
+                ("actimeo", Some(secs)) => {
+                    cache.files = secs;
+                    cache.dirs = secs;
+                }
+                ("acregmax", Some(secs)) => cache.files = secs,
+                ("acdirmax", Some(secs)) => cache.dirs = secs,
+                _ => {}
+            }
+        }
+        Some(cache)
+    }
+}
+
+/// Problems with the mounts under the configured watches
+#[cfg(target_os = "linux")]
+pub fn check(config: &Config) -> Vec<Issue> {
+    use crate::namespace::{containing_mount, parse_mountinfo};
+
+    let Ok(contents) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return vec![Issue::warning("cannot read /proc/self/mountinfo")];
+    };
+    let mounts = parse_mountinfo(&contents);
+    config
+        .watch
+        .iter()
+        .filter_map(|watch| {
+            let mount = containing_mount(&mounts, &watch.path)?;
+            let mut issues = check_watch(watch, &mount.fs_type, &mount.options);
+            if config.daemon.access_events == AccessEvents::Atime {
+                issues.extend(check_atime(watch, &mount.mount_options));
+            }
+            Some(issues)
+        })
+        .flatten()
+        .collect()
+}
+
+/// Problems with the mounts under the configured watches (mount options
+/// are not inspected on this platform)
+#[cfg(not(target_os = "linux"))]
+pub fn check(_config: &Config) -> Vec<Issue> {
+    Vec::new()
+}
+
+/// Where the attribute cache of `watch`'s mount delays its events past
+/// the poll interval
+pub fn check_watch(watch: &WatchConfig, fs_type: &str, options: &str) -> Vec<Issue> {
+    let Some(cache) = AttrCache::of_mount(fs_type, options) else {
+        return Vec::new();
+    };
+    let mut issues = Vec::new();
+    if cache.dirs > watch.poll_interval && !watch.revalidate {
+        issues.push(Issue::warning(format!(
+            "{} is on {fs_type} with acdirmax={}: new and deleted entries can show up that late \
+             despite poll_interval {:?}; set revalidate = true or mount with a lower acdirmax",
+            watch.path.display(),
+            cache.dirs.as_secs(),
+            watch.poll_interval
+        )));
+    }
+    if cache.files > watch.poll_interval {
+        issues.push(Issue::warning(format!(
+            "{} is on {fs_type} with acregmax={}: file changes can show up that late \
+             despite poll_interval {:?}; mount with a lower acregmax, actimeo or noac",
+            watch.path.display(),
+            cache.files.as_secs(),
+            watch.poll_interval
+        )));
+    }
+    issues
+}
+
+/// Whether `watch`'s mount, with `mount_options`, keeps the atimes
+/// `access_events = "atime"` relies on
+pub fn check_atime(watch: &WatchConfig, mount_options: &str) -> Option<Issue> {
+    if watch.backend != Backend::Poll {
+        return None;
+    }
+    let options: Vec<&str> = mount_options.split(',').collect();
+    if options.contains(&"noatime") {
+        Some(Issue::warning(format!(
+            "{} is mounted noatime, so access_events = \"atime\" never sees it read; \
+             remount with strictatime",
+            watch.path.display()
+        )))
+    } else if options.contains(&"relatime") {
+        Some(Issue::warning(format!(
+            "{} is mounted relatime, so access_events = \"atime\" sees a file read at most \
+             once a day or after it changes; remount with strictatime to see every read",
+            watch.path.display()
+        )))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn watch(revalidate: bool) -> WatchConfig {
+        WatchConfig {
+            path: PathBuf::from("/mnt/media"),
+            poll_interval: Duration::from_secs(5),
+            recursive: true,
+            backend: Default::default(),
+            events: fakenotify_protocol::EventMask::IN_ALL_EVENTS,
+            extensions: Vec::new(),
+            name_regex: None,
+            change_detection: Default::default(),
+            revalidate,
+            case_insensitive: None,
+            confirm_deletes: 1,
+            restat_deletes: false,
+            settle_scans: 0,
+            partial_suffixes: Vec::new(),
+            tags: Vec::new(),
+            denied_events: fakenotify_protocol::EventMask::empty(),
+        }
+    }
+
+    #[test]
+    fn test_attr_cache_of_mount() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            AttrCache::of_mount("nfs4", "rw,vers=4.2,acdirmax=30"),
+            Some(AttrCache {
+                files: secs(60),
+                dirs: secs(30)
+            })
+        );
+        assert_eq!(
+            AttrCache::of_mount("nfs", "rw,actimeo=3"),
+            Some(AttrCache {
+                files: secs(3),
+                dirs: secs(3)
+            })
+        );
+        assert_eq!(
+            AttrCache::of_mount("nfs", "rw,noac,acregmax=60"),
+            Some(AttrCache {
+                files: Duration::ZERO,
+                dirs: Duration::ZERO
+            })
+        );
+        assert_eq!(AttrCache::of_mount("cifs", "rw").unwrap().dirs, secs(1));
+        assert_eq!(AttrCache::of_mount("ext4", "rw"), None);
+    }
+
+    #[test]
+    fn test_check_watch() {
+        assert_eq!(check_watch(&watch(false), "nfs4", "rw").len(), 2);
+        let issues = check_watch(&watch(true), "nfs4", "rw");
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].message.contains("acregmax=60"));
+        assert!(check_watch(&watch(false), "nfs4", "rw,actimeo=5").is_empty());
+        assert!(check_watch(&watch(false), "cifs", "rw,actimeo=1").is_empty());
+    }
+
+    #[test]
+    fn test_check_atime() {
+        let issue = check_atime(&watch(false), "rw,noatime").unwrap();
+        assert!(issue.message.contains("noatime"));
+        assert!(check_atime(&watch(false), "rw,relatime").is_some());
+        assert!(check_atime(&watch(false), "rw").is_none());
+    }
+}
@@ -0,0 +1,344 @@
+//! `fakenotifyd estimate`: what scanning a tree would cost.
+//!
+//! The tree is listed through the same [`ScanSource`] stack a scan of it
+//! would use, timing every listing. Trees larger than the sample are
+//! listed breadth-first for half of it; the directories left are
+//! extrapolated from random root-to-leaf probes of the rest (Knuth's
+//! estimator: each probe weighs what it finds by the branching above it),
+//! which is unbiased and, for evenly shaped trees, close.
+//!
+//! A root is scanned one listing at a time, so a scan takes about the sum
+//! of its listings. Concurrency only helps across roots: the estimates per
+//! `scan_concurrency` are for each top-level directory being a root of its
+//! own, scanned side by side, assuming the server keeps up.
+
+use crate::config::{Config, WatchConfig};
+use crate::source::content::ContentSource;
+use crate::source::revalidate::RevalidateSource;
+use crate::source::{EntryMeta, FsSource, ScanSource};
+use fakenotify_protocol::ChangeDetection;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// `scan_concurrency` values the scan time is estimated for
+pub const CONCURRENCY: [usize; 5] = [1, 2, 4, 8, 16];
+
+/// Poll intervals worth recommending, shortest first
+const INTERVALS: [Duration; 12] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(15),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+    Duration::from_secs(120),
+    Duration::from_secs(300),
+    Duration::from_secs(600),
+    Duration::from_secs(1800),
+    Duration::from_secs(3600),
+];
+
+/// Longest share of the poll interval a scan should take
+const SCAN_SHARE: u32 = 4;
+
+/// Counts for part of the tree; estimated parts have fractional counts
+#[derive(Debug, Default, Clone, Copy)]
+struct Tally {
+    dirs: f64,
+    files: f64,
+    /// Total length of the entries' paths
+    path_bytes: f64,
+    /// Time spent listing
+    secs: f64,
+}
+
+impl Tally {
+    fn add(&mut self, other: Tally, weight: f64) {
+        self.dirs += other.dirs * weight;
+        self.files += other.files * weight;
+        self.path_bytes += other.path_bytes * weight;
+        self.secs += other.secs * weight;
+    }
+}
+
+/// What scanning a tree costs
+#[derive(Debug, Clone)]
+pub struct Estimate {
+    /// Directories under the root, the root included
+    pub dirs: u64,
+    /// Entries that are not directories
+    pub files: u64,
+    /// Directories actually listed
+    pub listed: u64,
+    /// Whether every directory was listed, so the counts are exact
+    pub exact: bool,
+    /// Memory one snapshot of the tree takes
+    pub snapshot_bytes: u64,
+    /// Time one scan spends listing the whole tree
+    pub scan: Duration,
+    /// Scan time with the top-level directories as roots scanned side by
+    /// side, per `scan_concurrency` in [`CONCURRENCY`]
+    pub concurrent: Vec<(usize, Duration)>,
+}
+
+impl Estimate {
+    /// Shortest poll interval the scan takes at most a quarter of
+    pub fn poll_interval(&self) -> Duration {
+        let needed = self.scan * SCAN_SHARE;
+        INTERVALS
+            .into_iter()
+            .find(|interval| *interval >= needed)
+            .unwrap_or(needed)
+    }
+
+    /// Memory while a scan is diffed against the previous snapshot
+    pub fn peak_bytes(&self) -> u64 {
+        self.snapshot_bytes * 2
+    }
+}
+
+/// The scan source a root at `path` would be scanned through under `config`
+pub fn source_for(config: &Config, path: &Path) -> Arc<dyn ScanSource> {
+    let mut source: Arc<dyn ScanSource> = Arc::new(FsSource);
+    #[cfg(target_os = "linux")]
+    if config.daemon.track_xattrs {
+        source = Arc::new(crate::source::xattr::XattrSource::new(source));
+    }
+    let root = config
+        .watch
+        .iter()
+        .filter(|watch| path.starts_with(&watch.path))
+        .max_by_key(|watch| watch.path.components().count());
+    if root.is_some_and(|root: &WatchConfig| root.revalidate) {
+        source = Arc::new(RevalidateSource::new(source));
+    }
+    if root.is_some_and(|root| root.change_detection == ChangeDetection::Hash) {
+        source = Arc::new(ContentSource::new(source));
+    }
+    source
+}
+
+/// Estimate the cost of scanning `root`, listing at most `sample`
+/// directories; `seed` picks the probes
+pub fn estimate(source: &dyn ScanSource, root: &Path, sample: usize, seed: u64) -> Estimate {
+    let sample = sample.max(2);
+    let mut rng = XorShift(seed | 1);
+    let mut listed = 0;
+    // One tally per top-level directory, the root's own listing first
+    let mut tops = vec![Tally::default()];
+    let mut pending = VecDeque::new();
+
+    let (root_tally, children) = list(source, root);
+    listed += 1;
+    tops[0].add(root_tally, 1.0);
+    for child in children {
+        tops.push(Tally::default());
+        pending.push_back((child, tops.len() - 1));
+    }
+
+    // Breadth-first for half the sample, or all of a small tree
+    while listed < sample / 2
+        && let Some((dir, top)) = pending.pop_front()
+    {
+        let (tally, children) = list(source, &dir);
+        listed += 1;
+        tops[top].add(tally, 1.0);
+        pending.extend(children.into_iter().map(|child| (child, top)));
+    }
+
+    let exact = pending.is_empty();
+    if !exact {
+        // Each probe stands for a random one of the directories left
+        let pending: Vec<(PathBuf, usize)> = pending.into();
+        let mut probes = Vec::new();
+        while listed < sample || probes.is_empty() {
+            let (dir, _) = &pending[rng.below(pending.len())];
+            let (tally, cost) = probe(source, dir, &mut rng);
+            listed += cost;
+            probes.push(tally);
+        }
+        let mut mean = Tally::default();
+        for tally in &probes {
+            mean.add(*tally, 1.0 / probes.len() as f64);
+        }
+        for (_, top) in &pending {
+            tops[*top].add(mean, 1.0);
+        }
+    }
+
+    let mut total = Tally::default();
+    for tally in &tops {
+        total.add(*tally, 1.0);
+    }
+    let entries = total.dirs + total.files;
+    // B-tree nodes run about two thirds full
+    let per_entry =
+        (std::mem::size_of::<PathBuf>() + std::mem::size_of::<EntryMeta>()) as f64 * 1.5;
+    let concurrent = CONCURRENCY
+        .into_iter()
+        .map(|lanes| {
+            let secs = tops[0].secs + makespan(&tops[1..], lanes);
+            (lanes, Duration::from_secs_f64(secs))
+        })
+        .collect();
+    Estimate {
+        dirs: total.dirs.round() as u64,
+        files: total.files.round() as u64,
+        listed: listed as u64,
+        exact,
+        snapshot_bytes: (entries * per_entry + total.path_bytes).round() as u64,
+        scan: Duration::from_secs_f64(total.secs),
+        concurrent,
+    }
+}
+
+/// List `dir` as a scan does, returning its tally (without subdirectories'
+/// contents) and its subdirectories
+fn list(source: &dyn ScanSource, dir: &Path) -> (Tally, Vec<PathBuf>) {
+    let started = Instant::now();
+    let entries = source.read_dir(dir).unwrap_or_default();
+    let mut tally = Tally {
+        dirs: 1.0,
+        secs: started.elapsed().as_secs_f64(),
+        ..Tally::default()
+    };
+    let mut children = Vec::new();
+    for entry in entries {
+        tally.path_bytes += entry.path.as_os_str().len() as f64;
+        if entry.meta.is_dir {
+            children.push(entry.path);
+        } else {
+            tally.files += 1.0;
+        }
+    }
+    (tally, children)
+}
+
+/// Estimate the subtree under `dir` from one random path down it,
+/// returning the estimate and how many directories were listed
+fn probe(source: &dyn ScanSource, dir: &Path, rng: &mut XorShift) -> (Tally, usize) {
+    let mut estimate = Tally::default();
+    let mut weight = 1.0;
+    let mut dir = dir.to_path_buf();
+    let mut listed = 0;
+    loop {
+        let (tally, children) = list(source, &dir);
+        listed += 1;
+        estimate.add(tally, weight);
+        if children.is_empty() {
+            return (estimate, listed);
+        }
+        weight *= children.len() as f64;
+        dir = children[rng.below(children.len())].clone();
+    }
+}
+
+/// Time to list `subtrees` on `lanes` in parallel, longest first onto the
+/// least busy lane
+fn makespan(subtrees: &[Tally], lanes: usize) -> f64 {
+    let mut times: Vec<f64> = subtrees.iter().map(|tally| tally.secs).collect();
+    times.sort_by(|a, b| b.total_cmp(a));
+    let mut busy = vec![0.0_f64; lanes.max(1)];
+    for time in times {
+        let lane = busy
+            .iter_mut()
+            .min_by(|a, b| a.total_cmp(b))
+            .expect("at least one lane");
+        *lane += time;
+    }
+    busy.into_iter().fold(0.0, f64::max)
+}
+
+/// Small PRNG for picking probes; quality hardly matters here
+struct XorShift(u64);
+
+impl XorShift {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::sim::SimSource;
+
+    /// `width` directories per level, `depth` levels, `files` per directory
+    fn tree(sim: &SimSource, dir: &str, width: usize, depth: usize, files: usize) {
+        sim.mkdir_all(dir);
+        for file in 0..files {
+            sim.write(format!("{dir}/f{file}"), 1);
+        }
+        if depth > 0 {
+            for sub in 0..width {
+                tree(sim, &format!("{dir}/d{sub}"), width, depth - 1, files);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_tree_is_exact() {
+        let sim = SimSource::new();
+        tree(&sim, "/mnt/media", 3, 2, 2);
+        let estimate = estimate(&sim, Path::new("/mnt/media"), 1000, 7);
+        assert!(estimate.exact);
+        assert_eq!(estimate.dirs, 13);
+        assert_eq!(estimate.files, 26);
+        assert_eq!(estimate.listed, 13);
+        assert!(estimate.snapshot_bytes > 39 * std::mem::size_of::<EntryMeta>() as u64);
+        assert_eq!(estimate.peak_bytes(), estimate.snapshot_bytes * 2);
+    }
+
+    #[test]
+    fn test_even_tree_is_extrapolated_exactly() {
+        let sim = SimSource::new();
+        tree(&sim, "/mnt/media", 4, 4, 3);
+        // Breadth-first stops after the 21 directories of the first three
+        // levels, leaving the probes nothing but alike subtrees
+        let estimate = estimate(&sim, Path::new("/mnt/media"), 42, 7);
+        assert!(!estimate.exact);
+        assert!(estimate.listed < 341);
+        assert_eq!(estimate.dirs, 341);
+        assert_eq!(estimate.files, 1023);
+    }
+
+    #[test]
+    fn test_makespan_and_poll_interval() {
+        let secs = |secs| Tally {
+            secs,
+            ..Tally::default()
+        };
+        let subtrees = [secs(4.0), secs(3.0), secs(2.0), secs(1.0)];
+        assert_eq!(makespan(&subtrees, 1), 10.0);
+        assert_eq!(makespan(&subtrees, 2), 5.0);
+        assert_eq!(makespan(&subtrees, 8), 4.0);
+
+        let estimate = |scan| Estimate {
+            dirs: 1,
+            files: 0,
+            listed: 1,
+            exact: true,
+            snapshot_bytes: 0,
+            scan,
+            concurrent: Vec::new(),
+        };
+        assert_eq!(
+            estimate(Duration::from_millis(100)).poll_interval(),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            estimate(Duration::from_secs(2)).poll_interval(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            estimate(Duration::from_secs(7200)).poll_interval(),
+            Duration::from_secs(28800)
+        );
+    }
+}
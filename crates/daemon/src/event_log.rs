@@ -0,0 +1,248 @@
+//! Event history in syslog or the systemd journal.
+//!
+//! Each `[[event_log]]` writes the events it selects, by inotify name and
+//! path glob, as one record per event: to the journal with the fields
+//! `FAKENOTIFY_PATH`, `FAKENOTIFY_EVENT` and `FAKENOTIFY_TAGS`, or to syslog
+//! with the same as `key="value"` pairs in the message. Like media
+//! libraries, the log sees every change under a root, whether or not a
+//! client watches it. Records are sent without waiting; ones the logger
+//! has no room for are dropped and counted.
+
+use crate::config::{EventLogConfig, EventLogTarget};
+use crate::watcher::{WatcherEvent, notify_to_inotify_mask};
+use fakenotify_protocol::EventMask;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::io::ErrorKind;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Socket the journal reads native records from
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sockets syslog listens on, the first that exists being used
+const SYSLOG_SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+/// syslog facility `daemon` at severity `info`
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+/// One configured log and the events it takes
+struct Sink {
+    target: EventLogTarget,
+    events: EventMask,
+    /// Patterns with a `/` match the whole path, others the file name
+    paths: GlobSet,
+    names: GlobSet,
+    identifier: String,
+}
+
+impl Sink {
+    fn wants(&self, path: &Path, mask: EventMask) -> bool {
+        if !mask.intersects(self.events) {
+            return false;
+        }
+        if self.paths.is_empty() && self.names.is_empty() {
+            return true;
+        }
+        self.paths.is_match(path)
+            || path
+                .file_name()
+                .is_some_and(|name| self.names.is_match(name))
+    }
+
+    /// The record for an event, as the target expects it
+    fn record(&self, path: &Path, mask: EventMask, tags: &[String]) -> Vec<u8> {
+        let path = path.to_string_lossy();
+        let event = mask.to_string();
+        let tags = tags.join(",");
+        match self.target {
+            EventLogTarget::Journald => {
+                let mut record = Vec::new();
+                for (field, value) in [
+                    ("MESSAGE", format!("{event} {path}").as_str()),
+                    ("PRIORITY", "6"),
+                    ("SYSLOG_IDENTIFIER", &self.identifier),
+                    ("FAKENOTIFY_PATH", &path),
+                    ("FAKENOTIFY_EVENT", &event),
+                    ("FAKENOTIFY_TAGS", &tags),
+                ] {
+                    journal_field(&mut record, field, value);
+                }
+                record
+            }
+            EventLogTarget::Syslog => format!(
+                "<{SYSLOG_PRIORITY}>{}[{}]: {event} {path} path={path:?} event={event:?} tags={tags:?}",
+                self.identifier,
+                std::process::id()
+            )
+            .into_bytes(),
+        }
+    }
+}
+
+/// Append a field in the journal's native format; values holding a
+/// newline are sent with their length instead
+fn journal_field(record: &mut Vec<u8>, field: &str, value: &str) {
+    record.extend_from_slice(field.as_bytes());
+    if value.contains('\n') {
+        record.push(b'\n');
+        record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        record.push(b'=');
+    }
+    record.extend_from_slice(value.as_bytes());
+    record.push(b'\n');
+}
+
+/// Writes selected events to syslog or the journal
+pub struct EventLog {
+    sinks: Vec<Sink>,
+    socket: UnixDatagram,
+    /// Records dropped because the logger was busy or away
+    dropped: AtomicU64,
+}
+
+impl EventLog {
+    /// Set up the configured logs, failing on a glob that does not parse
+    pub fn new(logs: &[EventLogConfig]) -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        let sinks = logs
+            .iter()
+            .map(|log| {
+                let mut paths = GlobSetBuilder::new();
+                let mut names = GlobSetBuilder::new();
+                for pattern in &log.glob {
+                    let glob = GlobBuilder::new(pattern)
+                        .literal_separator(true)
+                        .build()
+                        .map_err(|e| {
+                            std::io::Error::new(
+                                ErrorKind::InvalidInput,
+                                format!("invalid glob {pattern:?}: {e}"),
+                            )
+                        })?;
+                    if pattern.contains('/') {
+                        paths.add(glob);
+                    } else {
+                        names.add(glob);
+                    }
+                }
+                let build = |set: GlobSetBuilder| {
+                    set.build()
+                        .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))
+                };
+                Ok(Sink {
+                    target: log.target,
+                    events: log.events,
+                    paths: build(paths)?,
+                    names: build(names)?,
+                    identifier: log.identifier.clone(),
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            sinks,
+            socket,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Log a change to every sink that selects it; `tags` is only asked
+    /// for once one does
+    pub fn observe(&self, event: &WatcherEvent, tags: impl FnOnce() -> Vec<String>) {
+        let Some(mask) = notify_to_inotify_mask(&event.kind, event.is_dir) else {
+            return;
+        };
+        let mut tags = Some(tags);
+        let mut resolved = Vec::new();
+        for sink in self
+            .sinks
+            .iter()
+            .filter(|sink| sink.wants(&event.path, mask))
+        {
+            if let Some(tags) = tags.take() {
+                resolved = tags();
+            }
+            let record = sink.record(&event.path, mask, &resolved);
+            self.send(sink.target, &record);
+        }
+    }
+
+    fn send(&self, target: EventLogTarget, record: &[u8]) {
+        let socket = match target {
+            EventLogTarget::Journald => JOURNAL_SOCKET,
+            EventLogTarget::Syslog => SYSLOG_SOCKETS
+                .into_iter()
+                .find(|socket| Path::new(socket).exists())
+                .unwrap_or(SYSLOG_SOCKETS[0]),
+        };
+        if let Err(e) = self.socket.send_to(record, socket) {
+            // Warn on the first drop, then every thousandth
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if dropped.is_multiple_of(1000) {
+                tracing::warn!(socket, error = %e, dropped = dropped + 1, "Event log records dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(target: EventLogTarget, events: EventMask, glob: &[&str]) -> EventLog {
+        EventLog::new(&[EventLogConfig {
+            target,
+            events,
+            glob: glob.iter().map(|glob| glob.to_string()).collect(),
+            identifier: "fakenotifyd".to_string(),
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sinks_select_by_mask_and_glob() {
+        let sink = &log(
+            EventLogTarget::Journald,
+            EventMask::IN_CREATE | EventMask::IN_CLOSE_WRITE,
+            &["*.mkv", "/mnt/media/incoming/**"],
+        )
+        .sinks[0];
+        let created = EventMask::IN_CREATE;
+        assert!(sink.wants(Path::new("/mnt/media/tv/a.mkv"), created));
+        assert!(sink.wants(Path::new("/mnt/media/incoming/x/a.nfo"), created));
+        assert!(!sink.wants(Path::new("/mnt/media/tv/a.nfo"), created));
+        assert!(!sink.wants(Path::new("/mnt/media/tv/a.mkv"), EventMask::IN_DELETE));
+
+        let everything = &log(EventLogTarget::Syslog, EventMask::IN_ALL_EVENTS, &[]).sinks[0];
+        assert!(everything.wants(Path::new("/mnt/media/tv/a.nfo"), EventMask::IN_DELETE));
+    }
+
+    #[test]
+    fn test_records() {
+        let journal = log(EventLogTarget::Journald, EventMask::IN_ALL_EVENTS, &[]);
+        let record = journal.sinks[0].record(
+            Path::new("/mnt/media/a.mkv"),
+            EventMask::IN_CREATE,
+            &["team-media".to_string()],
+        );
+        let record = String::from_utf8(record).unwrap();
+        assert!(record.contains("MESSAGE=IN_CREATE /mnt/media/a.mkv\n"));
+        assert!(record.contains("FAKENOTIFY_PATH=/mnt/media/a.mkv\n"));
+        assert!(record.contains("FAKENOTIFY_TAGS=team-media\n"));
+
+        let mut field = Vec::new();
+        journal_field(&mut field, "FAKENOTIFY_PATH", "a\nb");
+        assert_eq!(field, b"FAKENOTIFY_PATH\n\x03\0\0\0\0\0\0\0a\nb\n");
+
+        let syslog = log(EventLogTarget::Syslog, EventMask::IN_ALL_EVENTS, &[]);
+        let record =
+            syslog.sinks[0].record(Path::new("/mnt/media/a.mkv"), EventMask::IN_CREATE, &[]);
+        let record = String::from_utf8(record).unwrap();
+        assert!(record.starts_with("<30>fakenotifyd["));
+        assert!(record.ends_with(
+            "]: IN_CREATE /mnt/media/a.mkv path=\"/mnt/media/a.mkv\" event=\"IN_CREATE\" tags=\"\""
+        ));
+    }
+}
@@ -0,0 +1,177 @@
+//! `fakenotifyd exec`: run a command with inotify redirected to the daemon.
+//!
+//! By default the child is started with `LD_PRELOAD` (`DYLD_INSERT_LIBRARIES`
+//! on macOS) pointing at the preload library, which is all dynamically linked
+//! programs need. With `--trace` the inotify syscalls themselves are
+//! intercepted instead, through a seccomp user-notification supervisor (see
+//! [`seccomp`], Linux only). That also covers statically linked binaries and
+//! runtimes such as Go that never go through the libc wrappers.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+#[cfg(target_os = "linux")]
+pub mod seccomp;
+
+/// Where install.sh and the container images put the preload library
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_PRELOAD_PATH: &str = "/usr/local/lib/libfakenotify_preload.so";
+
+/// Where install.sh puts the macOS interposition library
+#[cfg(target_os = "macos")]
+pub const DEFAULT_PRELOAD_PATH: &str = "/usr/local/lib/libfakenotify_preload_macos.dylib";
+
+/// Loader variable that injects the preload library
+#[cfg(not(target_os = "macos"))]
+const PRELOAD_ENV_VAR: &str = "LD_PRELOAD";
+#[cfg(target_os = "macos")]
+const PRELOAD_ENV_VAR: &str = "DYLD_INSERT_LIBRARIES";
+
+/// Where install.sh puts one preload build per architecture, as
+/// `<dir>/<platform>/libfakenotify_preload.so`
+pub const DEFAULT_PRELOAD_DIR: &str = "/usr/local/lib/fakenotify";
+
+const PRELOAD_FILE_NAME: &str = "libfakenotify_preload.so";
+
+/// Loader token that expands to the loading process's `AT_PLATFORM`
+/// (`x86_64`, `i686`, `aarch64`, ...)
+const PLATFORM_TOKEN: &str = "$PLATFORM";
+
+/// Work out the `LD_PRELOAD` entry for `library`
+///
+/// Mixed 32/64-bit process trees need the loader to pick the right build per
+/// process, or every child of the other class prints "wrong ELF class"
+/// warnings. So:
+///
+/// - paths containing a loader token (`$LIB`, `$PLATFORM`, `$ORIGIN`) are
+///   passed through for the loader to expand
+/// - a directory is taken as a per-arch layout and becomes
+///   `<dir>/$PLATFORM/libfakenotify_preload.so`
+/// - with no explicit library, the per-arch layout under
+///   [`DEFAULT_PRELOAD_DIR`] is used when it has a build for this machine,
+///   otherwise [`DEFAULT_PRELOAD_PATH`]
+///
+/// dyld has no `$PLATFORM`; on macOS ship a universal dylib instead.
+pub fn resolve_preload(library: Option<&Path>) -> PathBuf {
+    match library {
+        Some(library) if library.as_os_str().to_string_lossy().contains('$') => {
+            library.to_path_buf()
+        }
+        Some(library) if library.is_dir() => per_arch_path(library),
+        Some(library) => library.to_path_buf(),
+        None => {
+            let dir = Path::new(DEFAULT_PRELOAD_DIR);
+            if dir
+                .join(std::env::consts::ARCH)
+                .join(PRELOAD_FILE_NAME)
+                .is_file()
+            {
+                per_arch_path(dir)
+            } else {
+                PathBuf::from(DEFAULT_PRELOAD_PATH)
+            }
+        }
+    }
+}
+
+fn per_arch_path(dir: &Path) -> PathBuf {
+    dir.join(PLATFORM_TOKEN).join(PRELOAD_FILE_NAME)
+}
+
+/// Build the command for `argv`, pointed at the daemon on `socket`
+fn base_command(argv: &[OsString], socket: &Path) -> io::Result<Command> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command given"))?;
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env(fakenotify_protocol::SOCKET_ENV_VAR, socket);
+    Ok(command)
+}
+
+/// Prepend `library` to an existing `LD_PRELOAD`/`DYLD_INSERT_LIBRARIES` value
+///
+/// The Linux loader accepts both spaces and colons as separators and dyld
+/// only colons, so colons are used everywhere.
+pub fn preload_value(library: &Path, existing: Option<&OsStr>) -> OsString {
+    let mut value = library.as_os_str().to_os_string();
+    if let Some(existing) = existing.filter(|v| !v.is_empty()) {
+        value.push(":");
+        value.push(existing);
+    }
+    value
+}
+
+/// Run `argv` with the preload library injected by the dynamic loader
+pub fn run_preload(argv: &[OsString], socket: &Path, library: &Path) -> io::Result<ExitStatus> {
+    let existing = std::env::var_os(PRELOAD_ENV_VAR);
+    let mut command = base_command(argv, socket)?;
+    command.env(PRELOAD_ENV_VAR, preload_value(library, existing.as_deref()));
+    // Our inotify_* must take precedence over libinotify's two-level bindings
+    #[cfg(target_os = "macos")]
+    command.env("DYLD_FORCE_FLAT_NAMESPACE", "1");
+    command.status()
+}
+
+/// Run `argv` with its inotify syscalls serviced by the daemon on `socket`
+#[cfg(target_os = "linux")]
+pub fn run_traced(argv: &[OsString], socket: &Path) -> io::Result<ExitStatus> {
+    seccomp::supervise(base_command(argv, socket)?, socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run_traced(_argv: &[OsString], _socket: &Path) -> io::Result<ExitStatus> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--trace needs seccomp and is only available on Linux",
+    ))
+}
+
+/// Shell-style exit code for a finished child (128 + signal if killed)
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|sig| 128 + sig))
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preload_value_prepends() {
+        let lib = Path::new("/usr/local/lib/libfakenotify_preload.so");
+        assert_eq!(preload_value(lib, None), lib.as_os_str());
+        assert_eq!(preload_value(lib, Some(OsStr::new(""))), lib.as_os_str());
+        assert_eq!(
+            preload_value(lib, Some(OsStr::new("/opt/libother.so"))),
+            OsString::from("/usr/local/lib/libfakenotify_preload.so:/opt/libother.so")
+        );
+    }
+
+    #[test]
+    fn test_resolve_preload() {
+        let lib = Path::new("/opt/fakenotify/$LIB/libfakenotify_preload.so");
+        assert_eq!(resolve_preload(Some(lib)), lib);
+        let file = Path::new("/nonexistent/libfakenotify_preload.so");
+        assert_eq!(resolve_preload(Some(file)), file);
+
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            resolve_preload(Some(&dir)),
+            dir.join("$PLATFORM/libfakenotify_preload.so")
+        );
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGKILL)), 128 + 9);
+    }
+}
@@ -0,0 +1,800 @@
+//! Syscall-level inotify interception via seccomp user notification.
+//!
+//! Just before exec, the child installs a filter that turns its
+//! `inotify_init`, `inotify_init1`, `inotify_add_watch` and
+//! `inotify_rm_watch` syscalls into notifications on a listener fd, and hands
+//! that fd back to us over a socketpair. For each notification we:
+//!
+//! - `inotify_init*`: connect to the daemon and inject one end of a
+//!   `SOCK_SEQPACKET` pair into the child as its "inotify fd". A pump thread
+//!   forwards each daemon event as one packet, so read() only ever returns
+//!   whole events, as it would from the kernel.
+//! - `inotify_add_watch`/`inotify_rm_watch`: read the path out of the
+//!   child's memory and forward the request on that fd's daemon connection.
+//!
+//! Each notification is answered on a thread of its own, so a request the
+//! daemon is slow to answer holds up only the thread that made it, and
+//! gives up after [`REQUEST_TIMEOUT`] with EIO.
+//!
+//! Calls on fds we did not hand out continue to the real kernel, as does
+//! `inotify_init` if the daemon is unreachable. The filter is inherited
+//! across fork, so the whole process tree is covered. Injecting fds needs
+//! `SECCOMP_IOCTL_NOTIF_ADDFD` (Linux 5.9+).
+
+use fakenotify_protocol::{FrameKind, FramedMessage, Request, Response};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::ffi::{OsString, c_int};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// <linux/seccomp.h> ioctls, which libc does not export
+const SECCOMP_IOCTL_NOTIF_RECV: libc::c_ulong = 0xC050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: libc::c_ulong = 0xC018_2101;
+const SECCOMP_IOCTL_NOTIF_ID_VALID: libc::c_ulong = 0x4008_2102;
+const SECCOMP_IOCTL_NOTIF_ADDFD: libc::c_ulong = 0x4018_2103;
+
+/// `AUDIT_ARCH_*` of the syscalls we trap; other ABIs (e.g. 32-bit
+/// compat calls) pass through untouched
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH_NATIVE: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH_NATIVE: u32 = 0xC000_00B7;
+
+/// Syscalls routed to the supervisor
+#[cfg(target_arch = "x86_64")]
+const TRAPPED: &[libc::c_long] = &[
+    libc::SYS_inotify_init,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+];
+#[cfg(target_arch = "aarch64")]
+const TRAPPED: &[libc::c_long] = &[
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+];
+
+/// How long to wait for the daemon to answer a forwarded request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// ============================================================================
+// Filter
+// ============================================================================
+
+const fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Build a filter that notifies on `syscalls` for `arch` and allows the rest
+fn build_filter(arch: u32, syscalls: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    // Offsets into struct seccomp_data
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    let n = syscalls.len() as u8;
+    let mut prog = vec![
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+        // Foreign arch: skip the nr loads and compares straight to ALLOW
+        bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 0, n + 1),
+        bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+    ];
+    for (i, &nr) in syscalls.iter().enumerate() {
+        // Match: jump past the remaining compares and ALLOW to USER_NOTIF
+        prog.push(bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            nr as u32,
+            n - i as u8,
+            0,
+        ));
+    }
+    prog.push(bpf_stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ALLOW,
+    ));
+    prog.push(bpf_stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_USER_NOTIF,
+    ));
+    prog
+}
+
+/// Install the filter and send its listener fd over `chan`
+///
+/// Runs in the forked child before exec, so it must stay async-signal-safe:
+/// raw syscalls only, no allocation.
+fn install_filter(filter: &[libc::sock_filter], chan: RawFd) -> io::Result<()> {
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr().cast_mut(),
+    };
+    // SAFETY: prog points at a valid filter for the duration of the call
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let listener = libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &prog as *const libc::sock_fprog,
+        );
+        if listener < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sent = send_fd(chan, listener as RawFd);
+        libc::syscall(libc::SYS_close, listener);
+        sent
+    }
+}
+
+// ============================================================================
+// Fd passing
+// ============================================================================
+
+/// Control message buffer with the alignment `cmsghdr` needs
+#[repr(C, align(8))]
+struct CmsgBuf([u8; 64]);
+
+fn seqpacket_pair() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as c_int; 2];
+    // SAFETY: fds has room for the two descriptors
+    let ret = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: socketpair just returned these fds and nothing else owns them
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Send `fd` as SCM_RIGHTS (async-signal-safe)
+fn send_fd(chan: RawFd, fd: RawFd) -> io::Result<()> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: (&raw mut byte).cast(),
+        iov_len: 1,
+    };
+    let mut buf = CmsgBuf([0; 64]);
+    // SAFETY: msghdr is plain old data, and the CMSG_* macros stay within buf
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = buf.0.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(size_of::<c_int>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<c_int>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<c_int>(), fd);
+        if libc::sendmsg(chan, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receive one fd sent with [`send_fd`]
+fn recv_fd(chan: RawFd) -> io::Result<OwnedFd> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: (&raw mut byte).cast(),
+        iov_len: 1,
+    };
+    let mut buf = CmsgBuf([0; 64]);
+    // SAFETY: msghdr is plain old data, and the CMSG_* macros stay within buf
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = buf.0.as_mut_ptr().cast();
+        msg.msg_controllen = buf.0.len() as _;
+        if libc::recvmsg(chan, &mut msg, libc::MSG_CMSG_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "child did not send a seccomp listener",
+            ));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<c_int>());
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+/// Identity of an open file, stable across fds and processes
+fn fstat_key(fd: RawFd) -> io::Result<(u64, u64)> {
+    // SAFETY: stat is plain old data and fstat fills it in
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    // SAFETY: st is a valid, writable stat buffer
+    if unsafe { libc::fstat(fd, &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((st.st_dev, st.st_ino))
+}
+
+// ============================================================================
+// Daemon connections
+// ============================================================================
+
+/// Live instances keyed by the (dev, ino) of the fd handed to the child
+type Instances = HashMap<(u64, u64), Arc<Instance>>;
+
+/// One emulated inotify instance in the child
+struct Instance {
+    /// Daemon connection used for requests; the pump thread reads a clone
+    stream: Mutex<UnixStream>,
+    /// Responses split off the daemon stream by the pump thread, and how
+    /// many of those still to come belong to requests that gave up
+    responses: Mutex<(mpsc::Receiver<Response>, usize)>,
+}
+
+impl Instance {
+    /// Send `request` and wait for its response until `deadline`
+    ///
+    /// Responses come back in request order, so a request holds the
+    /// receiving end from sending until answered, and one that gives up
+    /// leaves its response to be skipped.
+    fn request(&self, request: &Request, deadline: Instant) -> Option<Response> {
+        let payload = request.to_bytes().ok()?;
+        let mut responses = self.responses.try_lock_until(deadline)?;
+        self.stream
+            .lock()
+            .write_all(&FramedMessage::frame(&payload))
+            .ok()?;
+        loop {
+            let (rx, abandoned) = &mut *responses;
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(_) if *abandoned > 0 => *abandoned -= 1,
+                Ok(response) => return Some(response),
+                Err(_) => {
+                    *abandoned += 1;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<(FrameKind, Vec<u8>)> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix)?;
+    let (kind, len) = FramedMessage::read_header(&prefix)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    let len = len as usize;
+    if len > FramedMessage::MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((kind, payload))
+}
+
+/// Connect and wait for the daemon's greeting
+fn connect(socket: &Path) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let greeting = Response::from_bytes(&read_frame(&mut stream)?.1)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    stream.set_read_timeout(None)?;
+    match greeting {
+        Response::ClientRegistered { .. } => Ok(stream),
+        other => Err(io::Error::other(format!("daemon refused: {other:?}"))),
+    }
+}
+
+/// Forward events from the daemon to the child until either side goes away
+fn pump(
+    mut daemon: UnixStream,
+    app: OwnedFd,
+    responses: mpsc::Sender<Response>,
+    key: (u64, u64),
+    instances: Arc<Mutex<Instances>>,
+) {
+    loop {
+        let mut pfds = [
+            libc::pollfd {
+                fd: daemon.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: app.as_raw_fd(),
+                events: 0,
+                revents: 0,
+            },
+        ];
+        // SAFETY: pfds holds two valid pollfd entries
+        let ready = unsafe { libc::poll(pfds.as_mut_ptr(), 2, -1) };
+        if ready < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+        // Every copy of the child's fd is closed
+        if pfds[1].revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+            break;
+        }
+        if pfds[0].revents == 0 {
+            continue;
+        }
+
+        let Ok((kind, payload)) = read_frame(&mut daemon) else {
+            break;
+        };
+        if kind == FrameKind::Event {
+            // SAFETY: payload is a valid buffer; one packet per event
+            let sent = unsafe {
+                libc::send(
+                    app.as_raw_fd(),
+                    payload.as_ptr().cast(),
+                    payload.len(),
+                    libc::MSG_NOSIGNAL,
+                )
+            };
+            if sent < 0 {
+                break;
+            }
+        } else if let Ok(Response::Heartbeat) = Response::from_bytes(&payload) {
+            // One small write, so it cannot interleave with a request frame
+            if let Ok(ack) = Request::HeartbeatAck.to_bytes() {
+                let _ = daemon.write_all(&FramedMessage::frame(&ack));
+            }
+        } else if let Ok(response) = Response::from_bytes(&payload) {
+            let _ = responses.send(response);
+        }
+    }
+
+    // Unblock any request still waiting before touching the shared map
+    drop(responses);
+    let _ = daemon.shutdown(std::net::Shutdown::Both);
+    instances.lock().remove(&key);
+}
+
+// ============================================================================
+// Supervisor
+// ============================================================================
+
+/// How a notification was answered
+enum Reply {
+    /// Complete the syscall with this return value
+    Value(i64),
+    /// Fail the syscall with this errno
+    Errno(c_int),
+    /// Let the real syscall run
+    Continue,
+    /// Already answered (or the caller is gone)
+    Done,
+}
+
+struct Supervisor {
+    listener: OwnedFd,
+    socket: PathBuf,
+    instances: Arc<Mutex<Instances>>,
+}
+
+impl Supervisor {
+    fn serve(self: &Arc<Self>) {
+        loop {
+            let mut pfd = libc::pollfd {
+                fd: self.listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: pfd is a valid pollfd
+            if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+            // No task uses the filter any more
+            if pfd.revents & libc::POLLHUP != 0 {
+                return;
+            }
+
+            // SAFETY: seccomp_notif is plain old data; the kernel wants it zeroed
+            let mut notif: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+            // SAFETY: notif is a valid, writable seccomp_notif
+            if unsafe {
+                libc::ioctl(
+                    self.listener.as_raw_fd(),
+                    SECCOMP_IOCTL_NOTIF_RECV as _,
+                    &mut notif,
+                )
+            } < 0
+            {
+                match io::Error::last_os_error().raw_os_error() {
+                    // Interrupted, or the caller died before we picked it up
+                    Some(libc::EINTR | libc::ENOENT) => continue,
+                    _ => return,
+                }
+            }
+
+            let supervisor = Arc::clone(self);
+            let spawned = thread::Builder::new()
+                .name("fakenotify-seccomp".into())
+                .spawn(move || supervisor.answer(&notif));
+            if spawned.is_err() {
+                self.answer(&notif);
+            }
+        }
+    }
+
+    /// Handle `notif` and complete the syscall behind it
+    fn answer(&self, notif: &libc::seccomp_notif) {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let (val, error, flags) = match self.handle(notif, deadline) {
+            Reply::Value(val) => (val, 0, 0),
+            Reply::Errno(err) => (0, -err, 0),
+            Reply::Continue => (0, 0, libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32),
+            Reply::Done => return,
+        };
+        let mut resp = libc::seccomp_notif_resp {
+            id: notif.id,
+            val,
+            error,
+            flags,
+        };
+        // SAFETY: resp is a valid seccomp_notif_resp; failure means the caller is gone
+        unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_SEND as _,
+                &mut resp,
+            )
+        };
+    }
+
+    /// How to answer `notif`, giving up on the daemon at `deadline`
+    fn handle(&self, notif: &libc::seccomp_notif, deadline: Instant) -> Reply {
+        let nr = notif.data.nr as libc::c_long;
+        let args = notif.data.args;
+        #[cfg(target_arch = "x86_64")]
+        if nr == libc::SYS_inotify_init {
+            return self.init(notif, 0);
+        }
+        if nr == libc::SYS_inotify_init1 {
+            self.init(notif, args[0] as c_int)
+        } else if nr == libc::SYS_inotify_add_watch {
+            self.add_watch(notif, args[0] as c_int, args[1], args[2] as u32, deadline)
+        } else if nr == libc::SYS_inotify_rm_watch {
+            self.rm_watch(notif.pid, args[0] as c_int, args[1] as c_int, deadline)
+        } else {
+            Reply::Continue
+        }
+    }
+
+    /// Whether the notification's caller is still waiting on it
+    fn id_valid(&self, id: u64) -> bool {
+        let mut id = id;
+        // SAFETY: id is a valid u64 for the ioctl to read
+        unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_ID_VALID as _,
+                &mut id,
+            ) == 0
+        }
+    }
+
+    fn init(&self, notif: &libc::seccomp_notif, flags: c_int) -> Reply {
+        let daemon = match connect(&self.socket) {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::debug!(pid = notif.pid, error = %e, "Daemon unavailable, using real inotify");
+                return Reply::Continue;
+            }
+        };
+        let Ok(reader) = daemon.try_clone() else {
+            return Reply::Errno(libc::EMFILE);
+        };
+        let Ok((app_end, our_end)) = seqpacket_pair() else {
+            return Reply::Errno(libc::EMFILE);
+        };
+        if flags & libc::IN_NONBLOCK != 0 {
+            // SAFETY: app_end is a valid fd; O_NONBLOCK lives on the shared file description
+            unsafe { libc::fcntl(app_end.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        }
+        let Ok(key) = fstat_key(app_end.as_raw_fd()) else {
+            return Reply::Errno(libc::EMFILE);
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let instance = Arc::new(Instance {
+            stream: Mutex::new(daemon),
+            responses: Mutex::new((rx, 0)),
+        });
+        self.instances.lock().insert(key, instance);
+
+        // Install the fd in the child; with ADDFD_FLAG_SEND this also
+        // completes the syscall with the new fd number
+        let addfd = libc::seccomp_notif_addfd {
+            id: notif.id,
+            flags: libc::SECCOMP_ADDFD_FLAG_SEND as u32,
+            srcfd: app_end.as_raw_fd() as u32,
+            newfd: 0,
+            newfd_flags: if flags & libc::IN_CLOEXEC != 0 {
+                libc::O_CLOEXEC as u32
+            } else {
+                0
+            },
+        };
+        // SAFETY: addfd is a valid seccomp_notif_addfd
+        let fd = unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_ADDFD as _,
+                &addfd,
+            )
+        };
+        drop(app_end);
+        if fd < 0 {
+            self.instances.lock().remove(&key);
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENOENT) => Reply::Done,
+                _ => Reply::Errno(libc::EMFILE),
+            };
+        }
+
+        tracing::debug!(
+            pid = notif.pid,
+            fd = fd,
+            "Injected daemon-backed inotify fd"
+        );
+        let instances = Arc::clone(&self.instances);
+        thread::spawn(move || pump(reader, our_end, tx, key, instances));
+        Reply::Done
+    }
+
+    /// The instance behind `fd` in process `pid`, if we created it
+    fn instance_for(&self, pid: u32, fd: c_int) -> Option<Arc<Instance>> {
+        let meta = std::fs::metadata(format!("/proc/{pid}/fd/{fd}")).ok()?;
+        self.instances
+            .lock()
+            .get(&(meta.dev(), meta.ino()))
+            .cloned()
+    }
+
+    fn add_watch(
+        &self,
+        notif: &libc::seccomp_notif,
+        fd: c_int,
+        addr: u64,
+        mask: u32,
+        deadline: Instant,
+    ) -> Reply {
+        let Some(instance) = self.instance_for(notif.pid, fd) else {
+            return Reply::Continue;
+        };
+        let path = match read_c_string(notif.pid, addr) {
+            Ok(path) => path,
+            Err(_) => return Reply::Errno(libc::EFAULT),
+        };
+        // The memory we read must still belong to the caller
+        if !self.id_valid(notif.id) {
+            return Reply::Done;
+        }
+        let path = if path.is_relative() {
+            match std::fs::read_link(format!("/proc/{}/cwd", notif.pid)) {
+                Ok(cwd) => cwd.join(path),
+                Err(_) => return Reply::Errno(libc::ENOENT),
+            }
+        } else {
+            path
+        };
+
+        match instance.request(&Request::AddWatch { path, mask }, deadline) {
+            Some(Response::WatchAdded { wd }) => Reply::Value(wd.into()),
+            // The kernel reports max_user_watches as ENOSPC
+            Some(Response::LimitExceeded { .. }) => Reply::Errno(libc::ENOSPC),
+            Some(Response::Error { code, .. }) => Reply::Errno(code.errno()),
+            _ => Reply::Errno(libc::EIO),
+        }
+    }
+
+    fn rm_watch(&self, pid: u32, fd: c_int, wd: c_int, deadline: Instant) -> Reply {
+        let Some(instance) = self.instance_for(pid, fd) else {
+            return Reply::Continue;
+        };
+        match instance.request(&Request::RemoveWatch { wd }, deadline) {
+            Some(Response::WatchRemoved) => Reply::Value(0),
+            Some(Response::Error { code, .. }) => Reply::Errno(code.errno()),
+            _ => Reply::Errno(libc::EIO),
+        }
+    }
+}
+
+/// Read a NUL-terminated string from another process's memory
+fn read_c_string(pid: u32, addr: u64) -> io::Result<PathBuf> {
+    let mem = File::open(format!("/proc/{pid}/mem"))?;
+    let mut out = Vec::new();
+    let mut addr = addr;
+    // Chunks never straddle a page, so a string ending just before an
+    // unmapped page still reads cleanly
+    let mut chunk = [0u8; 256];
+    while out.len() < libc::PATH_MAX as usize {
+        let len = chunk.len() - (addr % chunk.len() as u64) as usize;
+        let n = mem.read_at(&mut chunk[..len], addr)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(end) = chunk[..n].iter().position(|&b| b == 0) {
+            out.extend_from_slice(&chunk[..end]);
+            return Ok(PathBuf::from(OsString::from_vec(out)));
+        }
+        out.extend_from_slice(&chunk[..n]);
+        addr += n as u64;
+    }
+    Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG))
+}
+
+/// Spawn `command` with inotify syscalls trapped, serve them until the
+/// whole process tree has exited, and return the child's exit status
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn supervise(mut command: Command, socket: &Path) -> io::Result<ExitStatus> {
+    let filter = build_filter(AUDIT_ARCH_NATIVE, TRAPPED);
+    let (ours, theirs) = seqpacket_pair()?;
+    let chan = theirs.as_raw_fd();
+    // SAFETY: install_filter only makes async-signal-safe syscalls and does
+    // not allocate; the filter was built before fork
+    unsafe {
+        command.pre_exec(move || install_filter(&filter, chan));
+    }
+    let mut child = command.spawn()?;
+    drop(theirs);
+
+    let listener = match recv_fd(ours.as_raw_fd()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+    };
+
+    let supervisor = Arc::new(Supervisor {
+        listener,
+        socket: socket.to_path_buf(),
+        instances: Arc::new(Mutex::new(HashMap::new())),
+    });
+    // Reap the child concurrently: its filter only stops counting as in use
+    // once it has been reaped
+    let waiter = thread::spawn(move || child.wait());
+    supervisor.serve();
+    waiter
+        .join()
+        .map_err(|_| io::Error::other("wait thread panicked"))?
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn supervise(_command: Command, _socket: &Path) -> io::Result<ExitStatus> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--trace is only supported on x86_64 and aarch64",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate a filter built by [`build_filter`] against one syscall
+    fn run_filter(prog: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let ins = prog[pc];
+            match ins.code as u32 {
+                c if c == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    acc = if ins.k == 4 { arch } else { nr };
+                    pc += 1;
+                }
+                c if c == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    pc += 1 + if acc == ins.k { ins.jt } else { ins.jf } as usize;
+                }
+                _ => return ins.k,
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter_traps_only_listed_syscalls() {
+        let prog = build_filter(0xC000_003E, &[10, 20, 30]);
+        for nr in [10, 20, 30] {
+            assert_eq!(
+                run_filter(&prog, 0xC000_003E, nr),
+                libc::SECCOMP_RET_USER_NOTIF
+            );
+        }
+        assert_eq!(run_filter(&prog, 0xC000_003E, 11), libc::SECCOMP_RET_ALLOW);
+        // Same number under another ABI passes through
+        assert_eq!(run_filter(&prog, 0x4000_0003, 10), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn test_fd_passing() {
+        let (a, b) = seqpacket_pair().unwrap();
+        let file = File::open("/proc/self/exe").unwrap();
+        send_fd(a.as_raw_fd(), file.as_raw_fd()).unwrap();
+        let received = recv_fd(b.as_raw_fd()).unwrap();
+        assert_eq!(
+            fstat_key(received.as_raw_fd()).unwrap(),
+            fstat_key(file.as_raw_fd()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_request_skips_responses_it_gave_up_on() {
+        let (ours, _daemon) = UnixStream::pair().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let instance = Instance {
+            stream: Mutex::new(ours),
+            responses: Mutex::new((rx, 0)),
+        };
+        let request = Request::RemoveWatch { wd: 1 };
+
+        let soon = Instant::now() + Duration::from_millis(10);
+        assert_eq!(instance.request(&request, soon), None);
+        // The late answer to the first request is not taken for the second's
+        tx.send(Response::Error {
+            code: fakenotify_protocol::ErrorCode::NotFound,
+            message: String::new(),
+        })
+        .unwrap();
+        tx.send(Response::WatchRemoved).unwrap();
+        let later = Instant::now() + Duration::from_secs(5);
+        assert_eq!(
+            instance.request(&request, later),
+            Some(Response::WatchRemoved)
+        );
+    }
+
+    #[test]
+    fn test_read_c_string_from_self() {
+        let text = c"/mnt/media/tv";
+        let path = read_c_string(std::process::id(), text.as_ptr() as u64).unwrap();
+        assert_eq!(path, PathBuf::from("/mnt/media/tv"));
+    }
+}
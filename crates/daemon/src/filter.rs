@@ -0,0 +1,96 @@
+//! Compiled event name filters.
+//!
+//! The same [`NameFilter`] rules come from two places: a `[[watch]]` root
+//! in the configuration, applied before events leave the scan task, and a
+//! client's `SetFilter` request, applied per client in the dispatcher.
+
+use fakenotify_protocol::NameFilter;
+use regex::Regex;
+use std::path::Path;
+
+/// A [`NameFilter`] ready to test event names against
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+    /// Lowercased extensions without the dot
+    extensions: Vec<String>,
+    regex: Option<Regex>,
+}
+
+impl EventFilter {
+    /// Compile `filter`; `None` if it lets everything through
+    pub fn compile(filter: &NameFilter) -> Result<Option<Self>, regex::Error> {
+        if filter.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            extensions: filter
+                .extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            regex: filter.regex.as_deref().map(Regex::new).transpose()?,
+        }))
+    }
+
+    /// Whether an event named `name` (relative to its watch) is kept
+    ///
+    /// Directories and the watched path itself (an empty name) always pass.
+    pub fn matches(&self, name: &Path, is_dir: bool) -> bool {
+        if is_dir || name.as_os_str().is_empty() {
+            return true;
+        }
+        let extension_ok = self.extensions.is_empty()
+            || name
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| self.extensions.contains(&ext.to_ascii_lowercase()));
+        extension_ok
+            && self
+                .regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(&name.to_string_lossy()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(extensions: &[&str], regex: Option<&str>) -> EventFilter {
+        EventFilter::compile(&NameFilter {
+            extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            regex: regex.map(str::to_string),
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_extension_allowlist() {
+        let media = filter(&["mkv", ".JPG"], None);
+        assert!(media.matches(Path::new("show/ep1.MKV"), false));
+        assert!(media.matches(Path::new("poster.jpg"), false));
+        assert!(!media.matches(Path::new("ep1.mkv.part"), false));
+        assert!(!media.matches(Path::new("README"), false));
+        assert!(media.matches(Path::new("scratch"), true));
+        assert!(media.matches(Path::new(""), false));
+    }
+
+    #[test]
+    fn test_regex() {
+        let visible = filter(&["mkv"], Some(r"(^|/)[^.][^/]*$"));
+        assert!(visible.matches(Path::new("show/ep1.mkv"), false));
+        assert!(!visible.matches(Path::new("show/.ep1.mkv"), false));
+
+        assert!(
+            EventFilter::compile(&NameFilter::default())
+                .unwrap()
+                .is_none()
+        );
+        let broken = NameFilter {
+            regex: Some("(".to_string()),
+            ..NameFilter::default()
+        };
+        assert!(EventFilter::compile(&broken).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Case folding for case-insensitive filesystems.
+//!
+//! A CIFS server may hand back the same file as `Movie.mkv` in one listing
+//! and `movie.mkv` in the next, which a case-sensitive snapshot reports as
+//! a delete and a create. Roots that fold case compare paths without
+//! regard to case and keep the spelling they saw first.
+
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+/// Filesystem types whose names compare without regard to case
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const CASE_INSENSITIVE_FS_TYPES: &[&str] = &["cifs", "smb3"];
+
+/// `path` with every letter lowercased, for comparisons
+pub fn key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Whether `a` and `b` name the same entry ignoring case
+fn same_name(a: &OsStr, b: &OsStr) -> bool {
+    a == b || a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+}
+
+/// [`Path::starts_with`] ignoring case
+pub fn starts_with(path: &Path, base: &Path) -> bool {
+    let mut components = path.components();
+    base.components().all(|base| {
+        components
+            .next()
+            .is_some_and(|component| same_name(component.as_os_str(), base.as_os_str()))
+    })
+}
+
+/// `path`, which lies under `root` ignoring case, spelled the way the
+/// directory listings under `root` spell it
+///
+/// Components that cannot be listed or found are kept as given.
+pub fn on_disk_case(root: &Path, path: &Path) -> PathBuf {
+    let mut resolved = root.to_path_buf();
+    for component in path.components().skip(root.components().count()) {
+        let Component::Normal(name) = component else {
+            resolved.push(component);
+            continue;
+        };
+        let listed = std::fs::read_dir(&resolved)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| entry.file_name())
+                    .filter(|listed| same_name(listed, name))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        // An exact match wins where both spellings exist
+        let spelled = listed
+            .iter()
+            .find(|listed| listed.as_os_str() == name)
+            .or(listed.first())
+            .map_or(name, |listed| listed.as_os_str());
+        resolved.push(spelled);
+    }
+    resolved
+}
+
+/// Whether `path` lives on a filesystem that ignores case
+#[cfg(target_os = "linux")]
+pub fn is_case_insensitive_fs(path: &Path) -> bool {
+    use crate::namespace::{containing_mount, parse_mountinfo};
+
+    let Ok(contents) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return false;
+    };
+    containing_mount(&parse_mountinfo(&contents), path)
+        .is_some_and(|mount| CASE_INSENSITIVE_FS_TYPES.contains(&mount.fs_type.as_str()))
+}
+
+/// Whether `path` lives on a filesystem that ignores case (not detected on
+/// this platform)
+#[cfg(not(target_os = "linux"))]
+pub fn is_case_insensitive_fs(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_starts_with() {
+        assert!(starts_with(
+            Path::new("/mnt/Share/TV/a.mkv"),
+            Path::new("/mnt/share/tv")
+        ));
+        assert!(!starts_with(
+            Path::new("/mnt/share"),
+            Path::new("/mnt/share/tv")
+        ));
+        assert!(!starts_with(
+            Path::new("/mnt/shared"),
+            Path::new("/mnt/share")
+        ));
+        assert_eq!(key(Path::new("/mnt/Share")), "/mnt/share");
+    }
+
+    #[test]
+    fn test_on_disk_case() {
+        let root = std::env::temp_dir().join(format!("fakenotify-fold-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("Movies/Extras")).unwrap();
+
+        assert_eq!(
+            on_disk_case(&root, &root.join("MOVIES/extras")),
+            root.join("Movies/Extras")
+        );
+        // Missing components keep the caller's spelling
+        assert_eq!(
+            on_disk_case(&root, &root.join("movies/New")),
+            root.join("Movies/New")
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
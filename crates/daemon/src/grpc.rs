@@ -0,0 +1,292 @@
+//! gRPC gateway for services that would rather not speak the binary
+//! protocol (`grpc` feature).
+//!
+//! Serves `proto/fakenotify.proto` on `grpc_listen`. Each `Subscribe` stream
+//! is an ordinary client of the daemon, so limits, queue caps, filters and
+//! the audit log apply to it as to any connection; the gateway only turns
+//! its event frames into messages. Like TCP peers, gRPC clients have no
+//! credentials and are accounted against the daemon's own uid.
+
+use crate::server::{add_watch, remove_watch};
+use crate::state::{Client, DaemonState, LimitExceeded, Peer};
+use fakenotify_protocol::{
+    Capabilities, ClientInfo, ErrorCode, FrameKind, FramedMessage, InotifyEvent,
+    Response as DaemonResponse,
+};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+/// Code generated from `proto/fakenotify.proto`
+pub mod proto {
+    tonic::include_proto!("fakenotify.v1");
+}
+
+use proto::fake_notify_server::{FakeNotify, FakeNotifyServer};
+use proto::subscribe_response::Message;
+use proto::{
+    AddWatchRequest, AddWatchResponse, Event, RemoveWatchRequest, RemoveWatchResponse,
+    SubscribeRequest, SubscribeResponse,
+};
+
+/// Bytes of event frames buffered between a client's queue and its stream
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Messages buffered for a stream the peer is slow to read
+const STREAM_BUFFER: usize = 256;
+
+/// Serve the gateway on `addr` until shutdown
+pub async fn serve(
+    addr: SocketAddr,
+    state: Arc<DaemonState>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> color_eyre::Result<()> {
+    tracing::info!(addr = %addr, "gRPC gateway listening");
+    tonic::transport::Server::builder()
+        .add_service(FakeNotifyServer::new(Gateway::new(state)))
+        .serve_with_shutdown(addr, async move {
+            let _ = shutdown_rx.recv().await;
+        })
+        .await?;
+    Ok(())
+}
+
+/// The `FakeNotify` service, backed by the daemon's state
+pub struct Gateway {
+    state: Arc<DaemonState>,
+}
+
+impl Gateway {
+    pub fn new(state: Arc<DaemonState>) -> Self {
+        Self { state }
+    }
+
+    /// The subscribed client holding `session`
+    fn client(&self, session: &str) -> Result<Arc<Client>, Status> {
+        self.state
+            .client_by_session(session)
+            .ok_or_else(|| Status::not_found("no such session"))
+    }
+}
+
+#[tonic::async_trait]
+impl FakeNotify for Gateway {
+    type SubscribeStream = ReceiverStream<Result<SubscribeResponse, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let name = request.into_inner().name;
+        let peer = Peer::remote();
+        let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+        let client = match self.state.register_client(Box::new(writer), peer) {
+            Ok(client) => client,
+            Err(_) => {
+                let limit = LimitExceeded::Instances.to_string();
+                if let Some(audit) = self.state.audit() {
+                    audit.refused(peer, &limit);
+                }
+                return Err(Status::resource_exhausted(limit));
+            }
+        };
+        if let Some(audit) = self.state.audit() {
+            audit.connected(client.id, peer);
+        }
+        let info = ClientInfo {
+            name: (!name.is_empty()).then_some(name),
+            ..ClientInfo::default()
+        };
+        self.state
+            .set_client_info(client.id, info, Capabilities::empty());
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let session = Message::Session(client.session.clone());
+        let _ = tx
+            .send(Ok(SubscribeResponse {
+                message: Some(session),
+            }))
+            .await;
+        tokio::spawn(forward_events(Arc::clone(&self.state), client, reader, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn add_watch(
+        &self,
+        request: Request<AddWatchRequest>,
+    ) -> Result<Response<AddWatchResponse>, Status> {
+        let request = request.into_inner();
+        let client = self.client(&request.session)?;
+        match add_watch(
+            &self.state,
+            client.id,
+            Path::new(&request.path),
+            request.mask,
+        ) {
+            DaemonResponse::WatchAdded { wd } | DaemonResponse::WatchClamped { wd, .. } => {
+                Ok(Response::new(AddWatchResponse { wd }))
+            }
+            response => Err(status(response)),
+        }
+    }
+
+    async fn remove_watch(
+        &self,
+        request: Request<RemoveWatchRequest>,
+    ) -> Result<Response<RemoveWatchResponse>, Status> {
+        let request = request.into_inner();
+        let client = self.client(&request.session)?;
+        match remove_watch(&self.state, client.id, request.wd) {
+            DaemonResponse::WatchRemoved => Ok(Response::new(RemoveWatchResponse {})),
+            response => Err(status(response)),
+        }
+    }
+}
+
+/// Stream a client's events until either side goes away, then unregister it
+async fn forward_events(
+    state: Arc<DaemonState>,
+    client: Arc<Client>,
+    mut reader: impl AsyncRead + Unpin,
+    tx: mpsc::Sender<Result<SubscribeResponse, Status>>,
+) {
+    loop {
+        let payload = tokio::select! {
+            payload = read_frame(&mut reader) => payload,
+            _ = tx.closed() => break,
+        };
+        let Some((kind, payload)) = payload else {
+            break;
+        };
+        // Without batching negotiated, each event frame is a single event
+        if kind != FrameKind::Event {
+            continue;
+        }
+        let Some(header) = InotifyEvent::from_bytes(&payload) else {
+            continue;
+        };
+        let name = &payload[InotifyEvent::HEADER_SIZE..];
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        let event = Event {
+            wd: header.wd,
+            mask: header.mask,
+            cookie: header.cookie,
+            name: name[..name_len].to_vec(),
+        };
+        let message = SubscribeResponse {
+            message: Some(Message::Event(event)),
+        };
+        if tx.send(Ok(message)).await.is_err() {
+            break;
+        }
+    }
+    state.unregister_client(client.id);
+    if let Some(audit) = state.audit() {
+        audit.disconnected(client.id);
+    }
+}
+
+/// The kind and payload of the next frame, or `None` once the pipe is
+/// closed
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> Option<(FrameKind, Vec<u8>)> {
+    let mut prefix = [0u8; 4];
+    reader.read_exact(&mut prefix).await.ok()?;
+    let (kind, len) = FramedMessage::read_header(&prefix)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await.ok()?;
+    Some((kind, payload))
+}
+
+/// A failed request's answer as a gRPC status
+fn status(response: DaemonResponse) -> Status {
+    match response {
+        DaemonResponse::Error { code, message } => match code {
+            ErrorCode::NotFound | ErrorCode::NoSuchWatch => Status::not_found(message),
+            ErrorCode::NotDirectory | ErrorCode::VersionMismatch => {
+                Status::failed_precondition(message)
+            }
+            ErrorCode::PermissionDenied => Status::permission_denied(message),
+            ErrorCode::Invalid => Status::invalid_argument(message),
+            ErrorCode::UnsupportedMask => Status::unimplemented(message),
+            ErrorCode::WatchLimit | ErrorCode::InstanceLimit => Status::resource_exhausted(message),
+        },
+        DaemonResponse::LimitExceeded { limit } => Status::resource_exhausted(limit),
+        other => Status::internal(format!("unexpected answer: {other:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::frame_event;
+    use fakenotify_protocol::EventMask;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_subscribe_streams_events_for_added_watch() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-grpc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = Arc::new(DaemonState::new());
+        let gateway = Gateway::new(Arc::clone(&state));
+
+        let mut stream = gateway
+            .subscribe(Request::new(SubscribeRequest {
+                name: "indexer".into(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let Some(Ok(SubscribeResponse {
+            message: Some(Message::Session(session)),
+        })) = stream.next().await
+        else {
+            panic!("expected the session first");
+        };
+
+        let wd = gateway
+            .add_watch(Request::new(AddWatchRequest {
+                session: session.clone(),
+                path: dir.to_string_lossy().into_owned(),
+                mask: EventMask::IN_CREATE.bits(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .wd;
+        let client = state.client_by_session(&session).unwrap();
+        assert_eq!(client.info.read().name.as_deref(), Some("indexer"));
+        let event = InotifyEvent::new(wd, EventMask::IN_CREATE.bits(), 0);
+        assert!(client.queue_event(frame_event(&event, Some(b"new.mkv"))));
+
+        let Some(Ok(SubscribeResponse {
+            message: Some(Message::Event(event)),
+        })) = stream.next().await
+        else {
+            panic!("expected an event");
+        };
+        assert_eq!(event.wd, wd);
+        assert_eq!(event.mask, EventMask::IN_CREATE.bits());
+        assert_eq!(event.name, b"new.mkv");
+
+        let missing = gateway
+            .remove_watch(Request::new(RemoveWatchRequest { session, wd: 99 }))
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        // Closing the stream ends the session
+        drop(stream);
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while state.get_client(client.id).is_some() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
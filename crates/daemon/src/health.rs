@@ -0,0 +1,269 @@
+//! Health checks for the mounts under watched roots.
+//!
+//! A hung NFS server does not fail `stat`, it blocks it, and a scan stuck in
+//! the kernel holds its thread until the server comes back. Before each scan
+//! a watch task stats its root on the blocking pool and waits at most the
+//! configured timeout for an answer. A mount that does not answer is marked
+//! degraded: its watches skip scans and probe again after a delay that
+//! doubles with every failed probe, and go back to scanning as soon as a
+//! probe answers. A probe that timed out is not abandoned; the next attempt
+//! waits on the same stat, so a hung mount ties up one thread per watch
+//! rather than one per attempt.
+
+use crate::source::{EntryMeta, ScanSource};
+use fakenotify_protocol::{DegradedMount, MountStatus};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// A root stat that may still be blocked in the kernel
+pub type Probe = JoinHandle<io::Result<EntryMeta>>;
+
+/// How long to wait for a mount and how far to back off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthPolicy {
+    /// Time a probe may take before the mount is degraded (zero disables
+    /// probing)
+    pub timeout: Duration,
+    /// Longest delay between probes of a degraded mount
+    pub max_backoff: Duration,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A mount that stopped answering
+#[derive(Debug)]
+struct Degraded {
+    since: Instant,
+    failed_probes: u32,
+}
+
+/// Health of every mount watched roots live on
+#[derive(Debug)]
+pub struct MountMonitor {
+    policy: HealthPolicy,
+    /// Remote mount points, deepest first
+    mount_points: Vec<PathBuf>,
+    /// Mounts currently degraded; healthy mounts are not listed
+    degraded: Mutex<HashMap<PathBuf, Degraded>>,
+}
+
+impl MountMonitor {
+    pub fn new(policy: HealthPolicy, mut mount_points: Vec<PathBuf>) -> Self {
+        mount_points.sort_by_key(|point| std::cmp::Reverse(point.components().count()));
+        Self {
+            policy,
+            mount_points,
+            degraded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The mount `path` lives on, or `path` itself if it is not on a known
+    /// remote mount
+    pub fn mount_of(&self, path: &Path) -> PathBuf {
+        self.mount_points
+            .iter()
+            .find(|point| path.starts_with(point))
+            .map_or_else(|| path.to_path_buf(), Clone::clone)
+    }
+
+    /// Probe `root` before a scan
+    ///
+    /// Returns how long to wait before probing again if its mount did not
+    /// answer in time, or `None` if the scan can go ahead. `probe` carries a
+    /// timed-out stat over to the next call.
+    pub async fn check(
+        &self,
+        source: &Arc<dyn ScanSource>,
+        root: &Path,
+        probe: &mut Option<Probe>,
+    ) -> Option<Duration> {
+        if self.policy.timeout.is_zero() {
+            return None;
+        }
+        let pending = probe.get_or_insert_with(|| {
+            let source = Arc::clone(source);
+            let root = root.to_path_buf();
+            tokio::task::spawn_blocking(move || source.metadata(&root))
+        });
+        let answered = match tokio::time::timeout(self.policy.timeout, pending).await {
+            Err(_) => false,
+            Ok(result) => {
+                *probe = None;
+                // Any answer counts, errors included, except a stat the
+                // watchdog gave up on
+                !matches!(result, Ok(Err(e)) if e.kind() == io::ErrorKind::TimedOut)
+            }
+        };
+        self.record(&self.mount_of(root), answered)
+    }
+
+    /// Update the state of `mount` after a probe
+    fn record(&self, mount: &Path, answered: bool) -> Option<Duration> {
+        let mut degraded = self.degraded.lock();
+        if answered {
+            if let Some(state) = degraded.remove(mount) {
+                tracing::info!(
+                    mount = %mount.display(),
+                    down_for = ?state.since.elapsed(),
+                    "Mount is responding again, resuming scans"
+                );
+            }
+            return None;
+        }
+
+        let state = degraded.entry(mount.to_path_buf()).or_insert_with(|| {
+            tracing::warn!(
+                mount = %mount.display(),
+                timeout = ?self.policy.timeout,
+                "Mount is not responding, pausing scans"
+            );
+            Degraded {
+                since: Instant::now(),
+                failed_probes: 0,
+            }
+        });
+        state.failed_probes += 1;
+        Some(self.backoff(state.failed_probes))
+    }
+
+    /// Delay after the `failed_probes`th failed probe in a row
+    fn backoff(&self, failed_probes: u32) -> Duration {
+        let factor = 1u32 << failed_probes.saturating_sub(1).min(16);
+        self.policy
+            .timeout
+            .saturating_mul(factor)
+            .min(self.policy.max_backoff)
+    }
+
+    /// Mounts currently degraded, ordered by path
+    pub fn report(&self) -> Vec<DegradedMount> {
+        let mut mounts: Vec<_> = self
+            .degraded
+            .lock()
+            .iter()
+            .map(|(path, state)| DegradedMount {
+                path: path.clone(),
+                degraded_secs: state.since.elapsed().as_secs(),
+                failed_probes: state.failed_probes,
+            })
+            .collect();
+        mounts.sort_by(|a, b| a.path.cmp(&b.path));
+        mounts
+    }
+
+    /// Health of every mount `roots` live on, ordered by path
+    pub fn status(&self, roots: &[PathBuf]) -> Vec<MountStatus> {
+        let mut mounts: BTreeMap<PathBuf, u32> = BTreeMap::new();
+        for root in roots {
+            *mounts.entry(self.mount_of(root)).or_default() += 1;
+        }
+        let degraded = self.degraded.lock();
+        mounts
+            .into_iter()
+            .map(|(path, roots)| {
+                let state = degraded.get(&path);
+                MountStatus {
+                    degraded_secs: state.map(|state| state.since.elapsed().as_secs()),
+                    failed_probes: state.map_or(0, |state| state.failed_probes),
+                    path,
+                    roots,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for MountMonitor {
+    fn default() -> Self {
+        Self::new(HealthPolicy::default(), Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::sim::SimSource;
+
+    #[test]
+    fn test_mount_of_and_backoff() {
+        let monitor = MountMonitor::new(
+            HealthPolicy {
+                timeout: Duration::from_secs(2),
+                max_backoff: Duration::from_secs(10),
+            },
+            vec![PathBuf::from("/mnt"), PathBuf::from("/mnt/nas")],
+        );
+        assert_eq!(
+            monitor.mount_of(Path::new("/mnt/nas/show")),
+            Path::new("/mnt/nas")
+        );
+        assert_eq!(
+            monitor.mount_of(Path::new("/srv/local")),
+            Path::new("/srv/local")
+        );
+
+        let mount = Path::new("/mnt/nas");
+        let delays: Vec<_> = (0..4)
+            .map(|_| monitor.record(mount, false).unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, vec![2, 4, 8, 10]);
+        assert_eq!(monitor.report()[0].failed_probes, 4);
+
+        let roots = [
+            PathBuf::from("/mnt/nas/tv"),
+            PathBuf::from("/mnt/nas/movies"),
+            PathBuf::from("/srv/local"),
+        ];
+        let status = monitor.status(&roots);
+        assert_eq!(status.len(), 2);
+        assert_eq!(status[0].path, Path::new("/mnt/nas"));
+        assert_eq!(status[0].roots, 2);
+        assert_eq!(status[0].failed_probes, 4);
+        assert!(status[0].degraded_secs.is_some());
+        assert_eq!(status[1].degraded_secs, None);
+
+        assert_eq!(monitor.record(mount, true), None);
+        assert!(monitor.report().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hung_mount_degrades_and_recovers() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/nas/show");
+        sim.hang("/mnt/nas/show");
+        let source: Arc<dyn ScanSource> = sim.clone();
+        let monitor = MountMonitor::new(
+            HealthPolicy {
+                timeout: Duration::from_millis(20),
+                max_backoff: Duration::from_secs(1),
+            },
+            vec![PathBuf::from("/mnt/nas")],
+        );
+        let root = Path::new("/mnt/nas/show");
+        let mut probe = None;
+
+        assert!(monitor.check(&source, root, &mut probe).await.is_some());
+        assert!(monitor.check(&source, root, &mut probe).await.is_some());
+        let report = monitor.report();
+        assert_eq!(report[0].path, Path::new("/mnt/nas"));
+        assert_eq!(report[0].failed_probes, 2);
+
+        // The stat that hung answers; the same probe is picked up again
+        sim.unhang("/mnt/nas/show");
+        assert_eq!(monitor.check(&source, root, &mut probe).await, None);
+        assert!(probe.is_none());
+        assert!(monitor.report().is_empty());
+    }
+}
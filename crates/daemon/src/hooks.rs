@@ -0,0 +1,136 @@
+//! Named commands that plugins route events to.
+//!
+//! A `[[hook]]` is run once per routed event, with the event in its
+//! environment: `FAKENOTIFY_PATH` (the daemon's path), `FAKENOTIFY_EVENT`
+//! (inotify names such as `IN_CREATE|IN_ISDIR`), `FAKENOTIFY_TAGS` (the
+//! tags of the roots the path lies under, comma-separated). A hook with
+//! `tags` only runs for events under roots carrying one of them. Hooks run
+//! in the background; their output goes to the daemon's and their exit
+//! status is only logged.
+
+use crate::config::HookConfig;
+use fakenotify_protocol::EventMask;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Hook commands running at once; routed events past this wait their turn
+const MAX_RUNNING: usize = 16;
+
+/// The configured hooks, by name
+pub struct Hooks {
+    hooks: HashMap<String, Hook>,
+    running: Arc<Semaphore>,
+}
+
+struct Hook {
+    command: Vec<String>,
+    tags: Vec<String>,
+}
+
+impl Hooks {
+    pub fn new(hooks: &[HookConfig]) -> Self {
+        Self {
+            hooks: hooks
+                .iter()
+                .filter(|hook| !hook.command.is_empty())
+                .map(|hook| {
+                    let command = hook.command.clone();
+                    let tags = hook.tags.clone();
+                    (hook.name.clone(), Hook { command, tags })
+                })
+                .collect(),
+            running: Arc::new(Semaphore::new(MAX_RUNNING)),
+        }
+    }
+
+    /// Start hook `name` for an event with `mask` on `path`, under roots
+    /// tagged `tags`; returns false if there is no such hook
+    pub fn run(&self, name: &str, path: &Path, mask: EventMask, tags: &[String]) -> bool {
+        let Some(hook) = self.hooks.get(name) else {
+            tracing::warn!(hook = name, "Event routed to unknown hook");
+            return false;
+        };
+        if !hook.tags.is_empty() && !hook.tags.iter().any(|tag| tags.contains(tag)) {
+            return true;
+        }
+        let command = &hook.command;
+        let mut child = tokio::process::Command::new(&command[0]);
+        child
+            .args(&command[1..])
+            .env("FAKENOTIFY_PATH", path)
+            .env("FAKENOTIFY_EVENT", mask.to_string())
+            .env("FAKENOTIFY_TAGS", tags.join(","))
+            .stdin(Stdio::null())
+            .kill_on_drop(false);
+
+        let running = Arc::clone(&self.running);
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let Ok(_permit) = running.acquire_owned().await else {
+                return;
+            };
+            match child.status().await {
+                Ok(status) if status.success() => {
+                    tracing::debug!(hook = %name, "Hook finished");
+                }
+                Ok(status) => tracing::warn!(hook = %name, status = %status, "Hook failed"),
+                Err(e) => tracing::warn!(hook = %name, error = %e, "Failed to run hook"),
+            }
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_hook_gets_event_in_environment() {
+        let out = std::env::temp_dir().join(format!("fakenotify-hook-{}", std::process::id()));
+        let hooks = Hooks::new(&[HookConfig {
+            name: "record".into(),
+            command: vec![
+                "sh".into(),
+                "-c".into(),
+                format!(
+                    "echo \"$FAKENOTIFY_EVENT $FAKENOTIFY_PATH $FAKENOTIFY_TAGS\" > {}",
+                    out.display()
+                ),
+            ],
+            tags: vec!["media".into()],
+        }]);
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        assert!(!hooks.run("missing", Path::new("/x"), EventMask::IN_CREATE, &[]));
+        // Not under a root tagged `media`, so skipped
+        assert!(hooks.run(
+            "record",
+            Path::new("/mnt/ci/a.log"),
+            EventMask::IN_CREATE,
+            &tags(&["ci"])
+        ));
+        assert!(hooks.run(
+            "record",
+            Path::new("/mnt/media/a.mkv"),
+            EventMask::IN_CLOSE_WRITE,
+            &tags(&["media", "tv"])
+        ));
+
+        let written = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match std::fs::read_to_string(&out) {
+                    Ok(text) if text.ends_with('\n') => break text,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(written, "IN_CLOSE_WRITE /mnt/media/a.mkv media,tv\n");
+        std::fs::remove_file(&out).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! `fakenotifyd install`: systemd integration without hand-written units.
+//!
+//! Writes the daemon's unit (system-wide, or per user with `--user`), a
+//! tmpfiles.d entry so `/run/fakenotify` exists for container bind mounts
+//! before the daemon first starts, and a drop-in for each `--service` that
+//! starts it with the preload library. `/etc/ld.so.preload` is only touched
+//! on request, since it injects the library into every process on the host.
+//!
+//! Nothing is started or reloaded; the caller prints the `systemctl` steps.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the daemon's unit
+pub const UNIT_NAME: &str = "fakenotify.service";
+
+/// Where system units and drop-ins go
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// tmpfiles.d entry creating the socket directory
+pub const TMPFILES_PATH: &str = "/etc/tmpfiles.d/fakenotify.conf";
+
+/// Preloads applied to every dynamically linked program on the host
+pub const LD_SO_PRELOAD: &str = "/etc/ld.so.preload";
+
+/// Socket of a per-user daemon: `%t` is the user's runtime directory
+const USER_SOCKET: &str = "%t/fakenotify.sock";
+
+/// What to install
+#[derive(Debug, Clone)]
+pub struct InstallOptions {
+    /// Per-user units in `unit_dir` instead of system ones
+    pub user: bool,
+    /// Directory the units go in
+    pub unit_dir: PathBuf,
+    /// Daemon binary for `ExecStart`
+    pub daemon: PathBuf,
+    /// Config file passed to the daemon, if not the default one
+    pub config: Option<PathBuf>,
+    /// Socket the daemon listens on; defaults per system or user mode
+    pub socket: Option<PathBuf>,
+    /// Preload library exported to `services`
+    pub preload: PathBuf,
+    /// Services to start with the preload library
+    pub services: Vec<String>,
+    /// Also add `preload` to `/etc/ld.so.preload`
+    pub ld_so_preload: bool,
+}
+
+impl InstallOptions {
+    /// System-wide install of `daemon`, preloading `preload`
+    pub fn system(daemon: PathBuf, preload: PathBuf) -> Self {
+        Self {
+            user: false,
+            unit_dir: PathBuf::from(SYSTEM_UNIT_DIR),
+            daemon,
+            config: None,
+            socket: None,
+            preload,
+            services: Vec::new(),
+            ld_so_preload: false,
+        }
+    }
+
+    /// Per-user install into `unit_dir` (usually `~/.config/systemd/user`)
+    pub fn user(unit_dir: PathBuf, daemon: PathBuf, preload: PathBuf) -> Self {
+        Self {
+            user: true,
+            unit_dir,
+            ..Self::system(daemon, preload)
+        }
+    }
+
+    pub fn with_config(mut self, config: Option<PathBuf>) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_socket(mut self, socket: Option<PathBuf>) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    pub fn with_services(mut self, services: Vec<String>) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn with_ld_so_preload(mut self, enabled: bool) -> Self {
+        self.ld_so_preload = enabled;
+        self
+    }
+
+    /// Socket as it appears in unit files
+    fn socket_spec(&self) -> String {
+        match &self.socket {
+            Some(socket) => escape_specifiers(socket),
+            None if self.user => USER_SOCKET.to_string(),
+            None => escape_specifiers(Path::new(fakenotify_protocol::DEFAULT_SOCKET_PATH)),
+        }
+    }
+}
+
+/// One file the install writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedFile {
+    /// Absolute path on the target system
+    pub path: PathBuf,
+    /// Full contents, or the line to add when `append` is set
+    pub contents: String,
+    /// Add `contents` as a line unless the file already has it, instead of
+    /// replacing the file
+    pub append: bool,
+}
+
+/// Work out every file `options` installs
+pub fn plan(options: &InstallOptions) -> Vec<PlannedFile> {
+    let mut files = vec![PlannedFile {
+        path: options.unit_dir.join(UNIT_NAME),
+        contents: daemon_unit(options),
+        append: false,
+    }];
+    if !options.user {
+        files.push(PlannedFile {
+            path: PathBuf::from(TMPFILES_PATH),
+            contents: tmpfiles_entry(options),
+            append: false,
+        });
+    }
+    for service in &options.services {
+        files.push(PlannedFile {
+            path: options
+                .unit_dir
+                .join(format!("{}.d", unit_name(service)))
+                .join("fakenotify.conf"),
+            contents: preload_drop_in(options),
+            append: false,
+        });
+    }
+    if options.ld_so_preload {
+        files.push(PlannedFile {
+            path: PathBuf::from(LD_SO_PRELOAD),
+            contents: options.preload.display().to_string(),
+            append: true,
+        });
+    }
+    files
+}
+
+/// Write `files`, with every path placed under `root` if given
+///
+/// Returns the paths actually changed; an append whose line is already
+/// present leaves its file alone.
+pub fn apply(files: &[PlannedFile], root: Option<&Path>) -> io::Result<Vec<PathBuf>> {
+    let mut changed = Vec::new();
+    for file in files {
+        let path = match root {
+            Some(root) => root.join(file.path.strip_prefix("/").unwrap_or(&file.path)),
+            None => file.path.clone(),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if file.append {
+            let existing = match fs::read_to_string(&path) {
+                Ok(existing) => existing,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e),
+            };
+            if existing.lines().any(|line| line.trim() == file.contents) {
+                continue;
+            }
+            let mut out = OpenOptions::new().create(true).append(true).open(&path)?;
+            if !existing.is_empty() && !existing.ends_with('\n') {
+                out.write_all(b"\n")?;
+            }
+            writeln!(out, "{}", file.contents)?;
+        } else {
+            fs::write(&path, &file.contents)?;
+        }
+        changed.push(path);
+    }
+    Ok(changed)
+}
+
+/// The daemon's own unit
+fn daemon_unit(options: &InstallOptions) -> String {
+    let mut exec = vec![
+        quote(&escape_specifiers(&options.daemon)),
+        "start".to_string(),
+        "--socket".to_string(),
+        quote(&options.socket_spec()),
+    ];
+    if let Some(config) = &options.config {
+        exec.push("--config".to_string());
+        exec.push(quote(&escape_specifiers(config)));
+    }
+
+    let mut unit = String::from(
+        "# Written by fakenotifyd install\n\
+         [Unit]\n\
+         Description=FakeNotify - inotify injection for NFS\n\
+         After=network.target remote-fs.target\n\
+         \n\
+         [Service]\n\
+         Type=simple\n",
+    );
+    unit.push_str(&format!("ExecStart={}\n", exec.join(" ")));
+    unit.push_str(
+        "ExecReload=/bin/kill -HUP $MAINPID\n\
+         Restart=on-failure\n\
+         RestartSec=5\n",
+    );
+    if options.user {
+        unit.push_str("\n[Install]\nWantedBy=default.target\n");
+    } else {
+        unit.push_str(
+            "\n\
+             # Socket directory; kept across restarts so bind mounts of it stay valid\n\
+             RuntimeDirectory=fakenotify\n\
+             RuntimeDirectoryMode=0755\n\
+             RuntimeDirectoryPreserve=yes\n\
+             \n\
+             NoNewPrivileges=true\n\
+             ProtectSystem=strict\n\
+             ProtectHome=read-only\n\
+             PrivateTmp=true\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+        );
+    }
+    unit
+}
+
+/// tmpfiles.d entry for the directory holding the system socket
+fn tmpfiles_entry(options: &InstallOptions) -> String {
+    let dir = match &options.socket {
+        Some(socket) => socket.parent().unwrap_or(Path::new("/")).to_path_buf(),
+        None => Path::new(fakenotify_protocol::DEFAULT_SOCKET_PATH)
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .to_path_buf(),
+    };
+    format!(
+        "# Written by fakenotifyd install\nd {} 0755 root root -\n",
+        dir.display()
+    )
+}
+
+/// Drop-in starting a service with the preload library
+fn preload_drop_in(options: &InstallOptions) -> String {
+    format!(
+        "# Written by fakenotifyd install\n\
+         [Unit]\n\
+         Wants={UNIT_NAME}\n\
+         After={UNIT_NAME}\n\
+         \n\
+         [Service]\n\
+         Environment={}\n\
+         Environment={}\n",
+        quote(&format!(
+            "LD_PRELOAD={}",
+            escape_specifiers(&options.preload)
+        )),
+        quote(&format!(
+            "{}={}",
+            fakenotify_protocol::SOCKET_ENV_VAR,
+            options.socket_spec()
+        )),
+    )
+}
+
+/// `jellyfin` -> `jellyfin.service`; names with a unit suffix are kept
+fn unit_name(service: &str) -> String {
+    if service.contains('.') {
+        service.to_string()
+    } else {
+        format!("{service}.service")
+    }
+}
+
+/// A path as a unit file value, with `%` kept from expanding as a specifier
+fn escape_specifiers(path: &Path) -> String {
+    path.display().to_string().replace('%', "%%")
+}
+
+/// Double-quote a unit file word if it has whitespace or quotes in it
+fn quote(word: &str) -> String {
+    if word.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        word.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> InstallOptions {
+        InstallOptions::system(
+            PathBuf::from("/usr/local/bin/fakenotifyd"),
+            PathBuf::from("/usr/local/lib/libfakenotify_preload.so"),
+        )
+    }
+
+    #[test]
+    fn test_system_plan() {
+        let files = plan(&options().with_services(vec!["jellyfin".to_string()]));
+        let paths: Vec<_> = files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/etc/systemd/system/fakenotify.service"),
+                PathBuf::from("/etc/tmpfiles.d/fakenotify.conf"),
+                PathBuf::from("/etc/systemd/system/jellyfin.service.d/fakenotify.conf"),
+            ]
+        );
+        assert!(files[0].contents.contains(
+            "ExecStart=/usr/local/bin/fakenotifyd start --socket /run/fakenotify/fakenotify.sock\n"
+        ));
+        assert!(files[0].contents.contains("WantedBy=multi-user.target"));
+        assert!(
+            files[1]
+                .contents
+                .contains("d /run/fakenotify 0755 root root -")
+        );
+        assert!(
+            files[2]
+                .contents
+                .contains("Environment=LD_PRELOAD=/usr/local/lib/libfakenotify_preload.so\n")
+        );
+        assert!(
+            files[2]
+                .contents
+                .contains("Environment=FAKENOTIFY_SOCKET=/run/fakenotify/fakenotify.sock\n")
+        );
+    }
+
+    #[test]
+    fn test_user_plan() {
+        let options = InstallOptions::user(
+            PathBuf::from("/home/me/.config/systemd/user"),
+            PathBuf::from("/home/me/my bin/fakenotifyd"),
+            PathBuf::from("/usr/local/lib/libfakenotify_preload.so"),
+        )
+        .with_services(vec!["sonarr.service".to_string()]);
+        let files = plan(&options);
+        assert_eq!(files.len(), 2);
+        assert!(files[0].contents.contains(
+            "ExecStart=\"/home/me/my bin/fakenotifyd\" start --socket %t/fakenotify.sock\n"
+        ));
+        assert!(files[0].contents.contains("WantedBy=default.target"));
+        assert_eq!(
+            files[1].path,
+            PathBuf::from("/home/me/.config/systemd/user/sonarr.service.d/fakenotify.conf")
+        );
+        assert!(
+            files[1]
+                .contents
+                .contains("Environment=FAKENOTIFY_SOCKET=%t/fakenotify.sock\n")
+        );
+    }
+
+    #[test]
+    fn test_apply_under_root_appends_preload_once() {
+        let root = std::env::temp_dir().join(format!("fakenotify-install-{}", std::process::id()));
+        let files = plan(&options().with_ld_so_preload(true));
+
+        let preload = root.join("etc/ld.so.preload");
+        fs::create_dir_all(preload.parent().unwrap()).unwrap();
+        fs::write(&preload, "/opt/libother.so").unwrap();
+
+        let changed = apply(&files, Some(&root)).unwrap();
+        assert_eq!(changed.len(), 3);
+        assert!(root.join("etc/systemd/system/fakenotify.service").is_file());
+        assert_eq!(
+            fs::read_to_string(&preload).unwrap(),
+            "/opt/libother.so\n/usr/local/lib/libfakenotify_preload.so\n"
+        );
+
+        // Reinstalling rewrites the units but doesn't repeat the preload
+        let changed = apply(&files, Some(&root)).unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(
+            fs::read_to_string(&preload).unwrap(),
+            "/opt/libother.so\n/usr/local/lib/libfakenotify_preload.so\n"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
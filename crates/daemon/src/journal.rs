@@ -0,0 +1,410 @@
+//! Event journal for consumers that resume after a restart.
+//!
+//! A client adding a watch with `AddWatchSince` opens the journal of its
+//! path: from then on, every event under the path is numbered and kept, up
+//! to `journal_size` of them, and the client's events on the watch carry
+//! their numbers. An indexing pipeline stores how far it got as a
+//! [`Cursor`]; after restarting, it adds the watch again since that cursor
+//! and is first sent what it missed, so each event is delivered at least
+//! once.
+//!
+//! Journals live in memory. A journal closes when its watch is removed
+//! on purpose; when it is only left because its clients went away, it is
+//! kept for them to resume from, but only the `journal_idle_paths` most
+//! recently left are. A restarted daemon has a new epoch, so cursors from
+//! before are told there is a gap, as are cursors older than anything
+//! still kept.
+
+use crate::catchup::replay_frames;
+use crate::state::{Client, ClientId, WatchInfo};
+use crate::watcher::WatcherEvent;
+use bytes::Bytes;
+use fakenotify_protocol::{Cursor, FramedMessage, Response};
+use notify::EventKind;
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Numbered events under the paths watched with `AddWatchSince`
+pub struct Journal {
+    epoch: u64,
+    /// Events kept per path
+    capacity: usize,
+    /// Journals kept once no client holds them
+    max_idle: usize,
+    paths: Mutex<Paths>,
+}
+
+/// Every open journal, by path
+#[derive(Default)]
+struct Paths {
+    journals: HashMap<PathBuf, PathJournal>,
+    /// Paths whose journal no client holds, least recently left first
+    idle: VecDeque<PathBuf>,
+}
+
+/// The journal of one watched path
+#[derive(Default)]
+struct PathJournal {
+    /// Sequence number of the last event journaled
+    last: u64,
+    /// Highest sequence number no longer kept
+    dropped: u64,
+    /// Events shared with the journals of enclosing paths
+    events: VecDeque<(u64, Arc<WatcherEvent>)>,
+    /// Clients whose watch on the path is journaled
+    holders: HashSet<ClientId>,
+}
+
+impl Journal {
+    /// Keep `capacity` events per path, and the journals of `max_idle`
+    /// paths no client holds, under a new epoch
+    pub fn new(capacity: usize, max_idle: usize) -> Self {
+        Self {
+            epoch: new_epoch(),
+            capacity,
+            max_idle,
+            paths: Mutex::new(Paths::default()),
+        }
+    }
+
+    /// Number `event` in the journal of every path it is under; returns
+    /// each path with the number the event got there
+    pub fn record(&self, event: &WatcherEvent) -> Vec<(PathBuf, u64)> {
+        // Overflow markers reach clients unnumbered
+        if event.kind == EventKind::Other {
+            return Vec::new();
+        }
+        let mut paths = self.paths.lock();
+        if paths.journals.is_empty() {
+            return Vec::new();
+        }
+        let mut shared = None;
+        let mut numbered = Vec::new();
+        for path in event.path.ancestors() {
+            let Some(journal) = paths.journals.get_mut(path) else {
+                continue;
+            };
+            let event = shared.get_or_insert_with(|| Arc::new(event.clone()));
+            journal.last += 1;
+            journal.events.push_back((journal.last, Arc::clone(event)));
+            while journal.events.len() > self.capacity {
+                if let Some((seq, _)) = journal.events.pop_front() {
+                    journal.dropped = seq;
+                }
+            }
+            numbered.push((path.to_path_buf(), journal.last));
+        }
+        numbered
+    }
+
+    /// Let go of `client`'s hold on the journal of `path`. Once no client
+    /// holds it, the journal is closed, or with `keep`, set aside for a
+    /// consumer to resume from.
+    pub fn release(&self, path: &Path, client: ClientId, keep: bool) {
+        let mut paths = self.paths.lock();
+        let Some(journal) = paths.journals.get_mut(path) else {
+            return;
+        };
+        if !journal.holders.remove(&client) || !journal.holders.is_empty() {
+            return;
+        }
+        if !keep {
+            paths.journals.remove(path);
+            tracing::debug!(path = %path.display(), "Journal closed");
+            return;
+        }
+        paths.idle.push_back(path.to_path_buf());
+        while paths.idle.len() > self.max_idle {
+            if let Some(oldest) = paths.idle.pop_front() {
+                paths.journals.remove(&oldest);
+                tracing::debug!(path = %oldest.display(), "Idle journal closed");
+            }
+        }
+    }
+
+    /// Number of open journals, held or idle
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.paths.lock().journals.len()
+    }
+
+    /// Hold off [`Self::record`] while a watch is added, so each event is
+    /// either replayed by [`Paused::open`] or dispatched once the watch is
+    /// in place
+    pub fn pause(&self) -> Paused<'_> {
+        Paused {
+            journal: self,
+            paths: self.paths.lock(),
+        }
+    }
+
+    fn cursor(&self, seq: u64) -> Cursor {
+        Cursor {
+            epoch: self.epoch,
+            seq,
+        }
+    }
+}
+
+/// A [`Journal`] that records nothing until dropped
+pub struct Paused<'a> {
+    journal: &'a Journal,
+    paths: MutexGuard<'a, Paths>,
+}
+
+impl Paused<'_> {
+    /// Open the journal of `watch`'s path for `client`, queueing the
+    /// journaled events after `since` ahead of any later ones; returns the
+    /// answer to `AddWatchSince`
+    pub fn open(mut self, watch: &WatchInfo, client: &Client, since: Option<Cursor>) -> Response {
+        let this = self.journal;
+        let paths = &mut *self.paths;
+        let opened = paths.journals.contains_key(&watch.path);
+        let journal = paths.journals.entry(watch.path.clone()).or_default();
+        if journal.holders.is_empty() {
+            paths.idle.retain(|path| *path != watch.path);
+        }
+        journal.holders.insert(client.id);
+        client.journaled.write().insert(watch.wd);
+
+        let Some(since) = since else {
+            return Response::WatchAddedSince {
+                wd: watch.wd,
+                replayed: 0,
+                cursor: this.cursor(journal.last),
+                gap: false,
+            };
+        };
+        // A cursor from another run, from a path never journaled, or one
+        // the journal no longer reaches back to
+        let gap = since.epoch != this.epoch
+            || !opened
+            || since.seq < journal.dropped
+            || since.seq > journal.last;
+        let after = if since.epoch == this.epoch {
+            since.seq
+        } else {
+            0
+        };
+        let missed = journal
+            .events
+            .iter()
+            .filter(|(seq, _)| *seq > after)
+            .map(|(seq, event)| (*seq, &**event));
+        let mut replayed = 0;
+        for (seq, framed) in replay_frames(watch, client, missed) {
+            if client.queue_event(sequenced_frame(seq, &framed)) {
+                replayed += 1;
+            }
+        }
+        if replayed > 0 {
+            tracing::info!(
+                client_id = client.id,
+                wd = watch.wd,
+                events = replayed,
+                "Replayed journaled events"
+            );
+        }
+        Response::WatchAddedSince {
+            wd: watch.wd,
+            replayed,
+            cursor: this.cursor(journal.last),
+            gap,
+        }
+    }
+}
+
+/// A framed event as the [`Response::Sequenced`] frame carrying it as
+/// event `seq`
+pub fn sequenced_frame(seq: u64, framed: &[u8]) -> Bytes {
+    let sequenced = Response::Sequenced {
+        seq,
+        event: framed[4..].to_vec(),
+    };
+    match sequenced.to_bytes() {
+        Ok(payload) => FramedMessage::frame(&payload).into(),
+        // Cannot fail for plain bytes; the event still gets through
+        Err(_) => Bytes::copy_from_slice(framed),
+    }
+}
+
+/// An epoch no earlier run is likely to have had
+fn new_epoch() -> u64 {
+    let mut bytes = [0u8; 8];
+    if getrandom::fill(&mut bytes).is_err() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        return now.as_nanos() as u64 ^ u64::from(std::process::id());
+    }
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LimitsConfig;
+    use crate::state::Peer;
+    use fakenotify_protocol::{EventMask, InotifyEvent};
+    use notify::event::CreateKind;
+
+    fn create(path: &str) -> WatcherEvent {
+        WatcherEvent {
+            path: PathBuf::from(path),
+            kind: EventKind::Create(CreateKind::File),
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+
+    /// Open `/mnt/media` since `since` for a new client; returns the
+    /// answer and the sequence numbers of the events replayed
+    fn open(journal: &Journal, since: Option<Cursor>) -> (Response, Vec<u64>) {
+        open_as(journal, 1, "/mnt/media", since)
+    }
+
+    /// [`open`] for client `id` on `path`
+    fn open_as(
+        journal: &Journal,
+        id: ClientId,
+        path: &str,
+        since: Option<Cursor>,
+    ) -> (Response, Vec<u64>) {
+        let (client, mut rx) = Client::new(
+            id,
+            Peer::from_uid(1000),
+            Box::new(tokio::io::sink()),
+            LimitsConfig::default().queue_policy(),
+        );
+        let watch = WatchInfo {
+            wd: 3,
+            path: PathBuf::from(path),
+            mask: EventMask::IN_CREATE,
+            recursive: true,
+            clients: vec![id],
+            missing_since: None,
+            tags: Vec::new(),
+        };
+        let response = journal.pause().open(&watch, &client, since);
+        assert!(client.journaled.read().contains(&3));
+
+        let mut seqs = Vec::new();
+        while let Some(framed) = rx.try_recv() {
+            let Ok(Response::Sequenced { seq, event }) = Response::from_bytes(&framed[4..]) else {
+                panic!("expected Sequenced");
+            };
+            assert_eq!(InotifyEvent::from_bytes(&event).unwrap().wd, 3);
+            seqs.push(seq);
+        }
+        (response, seqs)
+    }
+
+    #[test]
+    fn test_resumes_from_cursor() {
+        let journal = Journal::new(3, 1);
+        assert!(journal.record(&create("/mnt/media/a.mkv")).is_empty());
+
+        let (response, seqs) = open(&journal, None);
+        let Response::WatchAddedSince {
+            cursor, gap: false, ..
+        } = response
+        else {
+            panic!("unexpected answer {response:?}");
+        };
+        assert_eq!(cursor.seq, 0);
+        assert!(seqs.is_empty());
+
+        for name in ["b", "c", "d"] {
+            journal.record(&create(&format!("/mnt/media/{name}.mkv")));
+        }
+        assert_eq!(
+            journal.record(&create("/mnt/media/e.mkv")),
+            vec![(PathBuf::from("/mnt/media"), 4)]
+        );
+        assert!(journal.record(&create("/mnt/other/f.mkv")).is_empty());
+
+        // Events after 2 are still kept
+        let since = Cursor { seq: 2, ..cursor };
+        let (response, seqs) = open(&journal, Some(since));
+        assert_eq!(
+            response,
+            Response::WatchAddedSince {
+                wd: 3,
+                replayed: 2,
+                cursor: Cursor { seq: 4, ..cursor },
+                gap: false,
+            }
+        );
+        assert_eq!(seqs, vec![3, 4]);
+
+        // Event 1 was dropped to make room
+        let (response, seqs) = open(&journal, Some(Cursor { seq: 0, ..cursor }));
+        assert!(matches!(
+            response,
+            Response::WatchAddedSince { gap: true, .. }
+        ));
+        assert_eq!(seqs, vec![2, 3, 4]);
+
+        // A cursor from before a restart gets everything kept
+        let (response, seqs) = open(
+            &journal,
+            Some(Cursor {
+                epoch: cursor.epoch ^ 1,
+                seq: 3,
+            }),
+        );
+        assert!(matches!(
+            response,
+            Response::WatchAddedSince { gap: true, .. }
+        ));
+        assert_eq!(seqs, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_nested_journals_share_events() {
+        let journal = Journal::new(3, 1);
+        open(&journal, None);
+        open_as(&journal, 1, "/mnt/media/shows", None);
+        assert_eq!(
+            journal.record(&create("/mnt/media/shows/a.mkv")),
+            vec![
+                (PathBuf::from("/mnt/media/shows"), 1),
+                (PathBuf::from("/mnt/media"), 1)
+            ]
+        );
+        assert_eq!(
+            journal.record(&create("/mnt/media/b.mkv")),
+            vec![(PathBuf::from("/mnt/media"), 2)]
+        );
+        assert!(journal.record(&create("/mnt/mediaX/c.mkv")).is_empty());
+    }
+
+    #[test]
+    fn test_removing_the_watch_frees_the_journal() {
+        let journal = Journal::new(3, 1);
+        let media = Path::new("/mnt/media");
+        open(&journal, None);
+        open_as(&journal, 2, "/mnt/media", None);
+        journal.record(&create("/mnt/media/a.mkv"));
+
+        // Still held by the other client
+        journal.release(media, 1, false);
+        assert_eq!(journal.len(), 1);
+        journal.release(media, 2, false);
+        assert_eq!(journal.len(), 0);
+        assert!(journal.record(&create("/mnt/media/b.mkv")).is_empty());
+
+        // Left by clients that disconnected, kept until others push it out
+        open(&journal, None);
+        journal.release(media, 1, true);
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal.record(&create("/mnt/media/c.mkv")).len(), 1);
+        open_as(&journal, 1, "/mnt/other", None);
+        journal.release(Path::new("/mnt/other"), 1, true);
+        assert_eq!(journal.len(), 1);
+        assert!(journal.record(&create("/mnt/media/d.mkv")).is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+//! Newline-delimited JSON for clients without a client library.
+//!
+//! A client that registers with [`Capabilities::JSON`] is sent one JSON
+//! object per line instead of binary frames, so a shell script reading the
+//! socket with `socat` or `nc -U` can hand events straight to `jq`. Events
+//! look like
+//!
+//! ```json
+//! {"wd":1,"mask":256,"events":["IN_CREATE"],"cookie":0,"name":"a.mkv"}
+//! ```
+//!
+//! with `seq` added for watches added since a cursor; answers are the
+//! response itself, e.g. `{"WatchAdded":{"wd":1}}`. Requests may be sent
+//! the same way, as JSON lines, from any connection. The greeting every
+//! connection gets comes before registration and stays binary, so the
+//! answer to registering starts with a newline, leaving the greeting on a
+//! line of its own.
+//!
+//! [`Capabilities::JSON`]: fakenotify_protocol::Capabilities::JSON
+
+use fakenotify_protocol::{
+    EventBuffer, FrameKind, FramedMessage, InotifyEvent, ProtocolError, Request, Response,
+    decompress_payload,
+};
+use serde_json::{Value, json};
+use std::ffi::OsString;
+
+/// Whether a request starting with `prefix`, at least four bytes of it, is
+/// a JSON line rather than a frame
+///
+/// Only a line opening with `{` or `"` whose first four bytes, read as a
+/// length prefix, exceed [`FramedMessage::MAX_SIZE`] counts; the low byte
+/// of a valid length may well be one of those characters.
+pub fn starts_line(prefix: &[u8]) -> bool {
+    match FramedMessage::read_length(prefix) {
+        Some(len) => matches!(prefix[0], b'{' | b'"') && len as usize > FramedMessage::MAX_SIZE,
+        None => false,
+    }
+}
+
+/// A request sent as a JSON line
+pub fn parse_request(line: &[u8]) -> Result<Request, ProtocolError> {
+    serde_json::from_slice(line)
+        .map_err(|e| ProtocolError::InvalidMessage(format!("invalid JSON request: {e}")))
+}
+
+/// Each length-prefixed frame in `framed` as JSON lines
+pub fn lines(mut framed: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some((kind, len)) = FramedMessage::read_header(framed) {
+        let Some(payload) = framed[4..].get(..len as usize) else {
+            break;
+        };
+        payload_lines(kind, payload, &mut out);
+        framed = &framed[4 + payload.len()..];
+    }
+    out
+}
+
+fn payload_lines(kind: FrameKind, payload: &[u8], out: &mut Vec<u8>) {
+    if kind == FrameKind::Event {
+        for (header, name) in EventBuffer::new(payload) {
+            push_line(event(&header, name, None), out);
+        }
+        return;
+    }
+    let response = match Response::from_bytes(payload) {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::debug!(error = %e, "Frame not sent as JSON");
+            return;
+        }
+    };
+    match response {
+        Response::EventBatch { events, .. } => {
+            for (header, name) in EventBuffer::new(&events) {
+                push_line(event(&header, name, None), out);
+            }
+        }
+        Response::Sequenced { seq, event: bytes } => {
+            if let Some((header, name)) = EventBuffer::new(&bytes).next() {
+                push_line(event(&header, name, Some(seq)), out);
+            }
+        }
+        Response::Compressed { kind, payload } => {
+            if let Ok(payload) = decompress_payload(&payload) {
+                payload_lines(kind, &payload, out);
+            }
+        }
+        response => {
+            if matches!(response, Response::ClientRegistered { .. }) {
+                out.push(b'\n');
+            }
+            match serde_json::to_value(&response) {
+                Ok(value) => push_line(value, out),
+                Err(e) => tracing::debug!(error = %e, "Response not sent as JSON"),
+            }
+        }
+    }
+}
+
+fn event(header: &InotifyEvent, name: Option<OsString>, seq: Option<u64>) -> Value {
+    let mut event = json!({
+        "wd": header.wd,
+        "mask": header.mask,
+        "events": header.event_mask(),
+        "cookie": header.cookie,
+        "name": name.map(|name| name.to_string_lossy().into_owned()),
+    });
+    if let Some(seq) = seq {
+        event["seq"] = json!(seq);
+    }
+    event
+}
+
+fn push_line(value: Value, out: &mut Vec<u8>) {
+    if serde_json::to_writer(&mut *out, &value).is_ok() {
+        out.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fakenotify_protocol::EventMask;
+
+    #[test]
+    fn test_frames_become_lines() {
+        let create = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0);
+        let framed = [
+            FramedMessage::frame_event(&create.to_bytes_with_name(b"a.mkv")),
+            FramedMessage::frame(
+                &Response::Sequenced {
+                    seq: 7,
+                    event: create.header_to_bytes().to_vec(),
+                }
+                .to_bytes()
+                .unwrap(),
+            ),
+            FramedMessage::frame(&Response::WatchAdded { wd: 2 }.to_bytes().unwrap()),
+        ]
+        .concat();
+
+        let lines: Vec<Value> = String::from_utf8(lines(&framed))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                json!({"wd": 1, "mask": 256, "events": ["IN_CREATE"], "cookie": 0, "name": "a.mkv"}),
+                json!({"wd": 1, "mask": 256, "events": ["IN_CREATE"], "cookie": 0, "name": null, "seq": 7}),
+                json!({"WatchAdded": {"wd": 2}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_responses_are_never_taken_for_events() {
+        // Read as a response, an event on wd 1 is a `WatchAdded`
+        let create =
+            InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a.mkv");
+        let event: Value =
+            serde_json::from_slice(&lines(&FramedMessage::frame_event(&create))).unwrap();
+        assert_eq!(event["name"], "a.mkv");
+        let response: Value =
+            serde_json::from_slice(&lines(&FramedMessage::frame(&create))).unwrap();
+        assert_eq!(response, json!({"WatchAdded": {"wd": 256}}));
+    }
+
+    #[test]
+    fn test_parses_requests() {
+        assert_eq!(parse_request(br#""Ping""#).unwrap(), Request::Ping);
+        assert_eq!(
+            parse_request(br#"{"AddWatch":{"path":"/mnt/media","mask":256}}"#).unwrap(),
+            Request::AddWatch {
+                path: "/mnt/media".into(),
+                mask: 256,
+            }
+        );
+        assert!(matches!(
+            parse_request(
+                br#"{"RegisterClient":{"protocol_version":3,"info":{},"capabilities":64}}"#
+            )
+            .unwrap(),
+            Request::RegisterClient {
+                protocol_version: 3,
+                capabilities: 64,
+                ..
+            }
+        ));
+        assert!(parse_request(b"{").is_err());
+        assert!(starts_line(br#"{"Ad"#) && starts_line(br#""Ping""#));
+        assert!(!starts_line(&[4, 0, 0, 0]) && !starts_line(b"{"));
+    }
+
+    #[test]
+    fn test_frames_with_line_like_lengths_are_not_lines() {
+        for len in [34, 123, 256 + 34, 256 + 123] {
+            let framed = FramedMessage::frame(&vec![0; len]);
+            assert!(!starts_line(&framed), "length {len}");
+        }
+    }
+}
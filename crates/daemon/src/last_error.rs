@@ -0,0 +1,102 @@
+//! The last warning or error the daemon logged, for `fakenotifyd status`.
+//!
+//! A tracing layer next to the log output keeps a copy of every `WARN` and
+//! `ERROR` event, so whatever went wrong last is visible without access to
+//! the daemon's log.
+
+use fakenotify_protocol::LoggedError;
+use parking_lot::Mutex;
+use std::fmt::{self, Write};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// The last event recorded and when
+static LAST_ERROR: Mutex<Option<(Level, String, Instant)>> = Mutex::new(None);
+
+/// Records warnings and errors for [`last_error`]
+pub struct LastErrorLayer;
+
+impl<S: Subscriber> Layer<S> for LastErrorLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+        let mut message = Message::default();
+        event.record(&mut message);
+        *LAST_ERROR.lock() = Some((level, message.finish(), Instant::now()));
+    }
+}
+
+/// The last warning or error logged since startup
+pub fn last_error() -> Option<LoggedError> {
+    LAST_ERROR
+        .lock()
+        .as_ref()
+        .map(|(level, message, at)| LoggedError {
+            level: level.to_string(),
+            message: message.clone(),
+            age_secs: at.elapsed().as_secs(),
+        })
+}
+
+/// Event message followed by its fields, as the log shows them
+#[derive(Default)]
+struct Message {
+    message: String,
+    fields: String,
+}
+
+impl Message {
+    fn finish(self) -> String {
+        if self.fields.is_empty() {
+            self.message
+        } else if self.message.is_empty() {
+            self.fields
+        } else {
+            format!("{} {}", self.message, self.fields)
+        }
+    }
+}
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            let _ = write!(self.fields, "{}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_records_warnings_not_info() {
+        let subscriber = tracing_subscriber::registry().with(LastErrorLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(mount = "/mnt/nas", "Mount is not responding");
+            tracing::info!("Mount is responding again");
+        });
+
+        let logged = last_error().unwrap();
+        assert_eq!(logged.level, "WARN");
+        assert_eq!(logged.message, "Mount is not responding mount=\"/mnt/nas\"");
+    }
+}
@@ -0,0 +1,48 @@
+//! launchd socket activation (macOS).
+//!
+//! When the plist declares a `Sockets` entry, launchd creates the listening
+//! socket itself, starts us on the first connection, and hands the socket over
+//! through `launch_activate_socket(3)`.
+
+use std::ffi::{CStr, c_char, c_int};
+use std::io;
+use std::os::fd::FromRawFd;
+use std::os::unix::net::UnixListener;
+
+/// Name of the `Sockets` entry in the plist that holds our listener
+const SOCKET_NAME: &CStr = c"Listeners";
+
+unsafe extern "C" {
+    fn launch_activate_socket(name: *const c_char, fds: *mut *mut c_int, cnt: *mut usize) -> c_int;
+}
+
+/// The listener launchd created for us, or `None` if we were not
+/// socket-activated
+pub fn activated_listener() -> io::Result<Option<UnixListener>> {
+    let mut fds: *mut c_int = std::ptr::null_mut();
+    let mut count = 0usize;
+    // SAFETY: both out-pointers are valid for writes
+    let err = unsafe { launch_activate_socket(SOCKET_NAME.as_ptr(), &mut fds, &mut count) };
+    match err {
+        0 => {}
+        // Not started by launchd, or the plist has no such socket
+        libc::ESRCH | libc::ENOENT => return Ok(None),
+        err => return Err(io::Error::from_raw_os_error(err)),
+    }
+
+    // SAFETY: on success launchd returns a malloc'd array of `count` fds
+    // that now belong to us
+    let owned = unsafe { std::slice::from_raw_parts(fds, count) }.to_vec();
+    // SAFETY: fds came from malloc and is not used again
+    unsafe { libc::free(fds.cast()) };
+
+    // One SockPathName yields one listener; close anything extra
+    let mut owned = owned.into_iter();
+    let listener = owned.next();
+    for extra in owned {
+        // SAFETY: extra is an fd we own and never use
+        unsafe { libc::close(extra) };
+    }
+    // SAFETY: launchd handed us this listening socket
+    Ok(listener.map(|fd| unsafe { UnixListener::from_raw_fd(fd) }))
+}
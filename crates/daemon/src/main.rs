@@ -3,18 +3,74 @@
 //! A daemon that polls NFS filesystems and emits inotify-compatible events
 //! to connected clients via a Unix domain socket.
 
+mod audit;
+#[cfg(unix)]
+mod bench;
+mod catchup;
+mod checkpoint;
 mod cli;
 mod config;
+mod diff;
+mod doctor;
+mod estimate;
+#[cfg(unix)]
+mod event_log;
+#[cfg(unix)]
+mod exec;
+mod filter;
+mod fold;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod health;
+mod hooks;
+#[cfg(target_os = "linux")]
+mod install;
+mod journal;
+mod json;
+mod last_error;
+#[cfg(target_os = "macos")]
+mod launchd;
+mod media;
+#[cfg(target_os = "linux")]
+mod namespace;
+mod output;
+mod plugin;
+mod prune;
+mod queue;
+mod rate;
+mod reload;
+mod scanner;
+mod schedule;
 mod server;
+mod settle;
+mod source;
 mod state;
+mod stop;
+mod store;
+mod throttle;
+mod trigger;
+mod validate;
+#[cfg(unix)]
+mod vsock;
+#[cfg(unix)]
+mod wait;
+mod watchdog;
 mod watcher;
+#[cfg(unix)]
+mod watchman;
 
 use clap::Parser;
-use cli::{Cli, Command};
+use cli::{Cli, Command, ConfigCommand, DumpFormat, OutputFormat};
 use color_eyre::eyre::{Result, bail};
 use config::Config;
-use fakenotify_protocol::Request;
-use server::{Server, is_daemon_running, send_daemon_request};
+use fakenotify_protocol::{EventMask, Request};
+use output::{
+    AddReport, ClientsReport, ExportReport, Health, ImportFailure, ImportReport, ListReport,
+    PruneReport, RemoveReport, RemoveTaggedReport, StatsReport, StatusReport, TagReport,
+    WatchInfoReport,
+};
+use rate::NoisyPolicy;
+use server::{Server, inherited_listener, is_daemon_running, send_daemon_request};
 use state::DaemonState;
 use std::sync::Arc;
 use tokio::sync::broadcast;
@@ -26,6 +82,23 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    // Validation reports a broken config instead of failing to load it
+    if let Command::Config {
+        command: ConfigCommand::Validate { file },
+    } = &cli.command
+    {
+        return cmd_config_validate(file.clone().or_else(|| cli.config.clone()));
+    }
+
+    // Packaging helpers need neither a config nor a daemon
+    match &cli.command {
+        Command::Completions { shell } => {
+            return Ok(cli::write_completions(*shell, &mut std::io::stdout())?);
+        }
+        Command::Man => return Ok(cli::write_man(&mut std::io::stdout())?),
+        _ => {}
+    }
+
     // Load configuration
     let config = Config::load(cli.config.as_ref())?
         .with_socket(Some(cli.socket_path()))
@@ -43,22 +116,114 @@ async fn main() -> Result<()> {
         }
     }
 
+    let output = cli.output;
     match cli.command {
         Command::Start {
             socket,
             daemonize,
             pid_file,
-        } => cmd_start(config, socket, daemonize, pid_file).await,
-        Command::Stop { socket } => cmd_stop(&config, socket).await,
-        Command::Status { socket } => cmd_status(&config, socket).await,
+        } => cmd_start(config, cli.config, socket, daemonize, pid_file).await,
+        Command::Stop {
+            socket,
+            wait,
+            timeout,
+            pid_file,
+        } => cmd_stop(&config, socket, wait, timeout, pid_file).await,
+        Command::Status { socket } => cmd_status(&config, socket, output).await,
         Command::Add {
             path,
             poll_interval,
             recursive,
             socket,
-        } => cmd_add(&config, socket, path, poll_interval, recursive).await,
-        Command::Remove { path, socket } => cmd_remove(&config, socket, path).await,
-        Command::List { socket } => cmd_list(&config, socket).await,
+        } => cmd_add(&config, socket, path, poll_interval, recursive, output).await,
+        Command::Remove {
+            path: Some(path),
+            socket,
+            ..
+        } => cmd_remove(&config, socket, path, output).await,
+        Command::Remove {
+            path: None,
+            tag: Some(tag),
+            socket,
+        } => cmd_remove_tagged(&config, socket, tag, output).await,
+        Command::Remove { .. } => unreachable!("clap requires a path or --tag"),
+        Command::List {
+            wd: Some(wd),
+            socket,
+            ..
+        } => cmd_watch_info(&config, socket, wd, output).await,
+        Command::List {
+            wd: None,
+            tag,
+            socket,
+        } => cmd_list(&config, socket, tag, output).await,
+        Command::Tag { wd, tags, socket } => cmd_tag(&config, socket, wd, tags, output).await,
+        Command::Clients { socket } => cmd_clients(&config, socket, output).await,
+        Command::Export { socket } => cmd_export(&config, socket, output).await,
+        Command::Import { file, socket } => cmd_import(&config, socket, file, output).await,
+        Command::Prune { socket } => cmd_prune(&config, socket, output).await,
+        Command::Stats { top, socket } => cmd_stats(&config, socket, top, output).await,
+        Command::Config { command } => match command {
+            ConfigCommand::Dump { format } => cmd_config_dump(&config, format),
+            ConfigCommand::Validate { .. } => unreachable!("handled before loading the config"),
+        },
+        Command::Doctor => cmd_doctor(&config),
+        #[cfg(unix)]
+        Command::Bench {
+            clients,
+            rate,
+            duration,
+            poll_interval,
+            tree_files,
+            live_files,
+            dir,
+        } => cmd_bench(
+            &config,
+            bench::BenchOptions {
+                clients,
+                rate,
+                duration,
+                poll_interval,
+                tree_files,
+                live_files,
+                dir: dir.unwrap_or_else(std::env::temp_dir),
+            },
+            output,
+        ),
+        Command::Estimate { path, sample } => cmd_estimate(&config, path, sample, output),
+        Command::Diff { path, since } => cmd_diff(&config, path, since, output),
+        #[cfg(target_os = "linux")]
+        Command::Install(args) => cmd_install(cli.config, args),
+        Command::Completions { .. } | Command::Man => {
+            unreachable!("handled before loading the config")
+        }
+        #[cfg(unix)]
+        Command::Exec {
+            trace,
+            preload,
+            socket,
+            command,
+        } => cmd_exec(&config, socket, trace, preload, command).await,
+        #[cfg(unix)]
+        Command::Wait {
+            path,
+            events,
+            glob,
+            timeout,
+            socket,
+        } => cmd_wait(
+            &config,
+            socket,
+            wait::WaitOptions {
+                path,
+                mask: events
+                    .into_iter()
+                    .fold(EventMask::empty(), |mask, m| mask | m),
+                glob,
+                timeout,
+            },
+            output,
+        ),
     }
 }
 
@@ -67,6 +232,7 @@ fn init_logging(level: &str) -> Result<()> {
 
     tracing_subscriber::registry()
         .with(fmt::layer().with_target(true))
+        .with(last_error::LastErrorLayer)
         .with(filter)
         .init();
 
@@ -75,14 +241,22 @@ fn init_logging(level: &str) -> Result<()> {
 
 async fn cmd_start(
     config: Config,
+    config_file: Option<std::path::PathBuf>,
     socket_override: Option<std::path::PathBuf>,
     daemonize: bool,
     pid_file: Option<std::path::PathBuf>,
 ) -> Result<()> {
     let socket_path = socket_override.unwrap_or(config.daemon.socket.clone());
 
-    // Check if already running
-    if is_daemon_running(&socket_path).await {
+    // Under socket activation the socket already accepts connections and is
+    // ours to serve, so only check for another daemon when binding ourselves
+    #[cfg(unix)]
+    let inherited = inherited_listener()?;
+    #[cfg(unix)]
+    let activated = inherited.is_some();
+    #[cfg(not(unix))]
+    let activated = false;
+    if !activated && is_daemon_running(&socket_path).await {
         bail!("Daemon is already running at {}", socket_path.display());
     }
 
@@ -90,6 +264,7 @@ async fn cmd_start(
         // Fork to background
         #[cfg(unix)]
         {
+            use std::os::unix::process::CommandExt;
             use std::process::Command;
 
             // Re-exec ourselves without --daemonize
@@ -99,17 +274,21 @@ async fn cmd_start(
             // Remove --daemonize flag
             args.retain(|arg| arg != "--daemonize" && arg != "-d");
 
-            // Fork and exec
-            let child = Command::new(&exe)
+            // Fork and exec, in a session of its own
+            let mut command = Command::new(&exe);
+            command
                 .args(&args[1..])
                 .stdin(std::process::Stdio::null())
                 .stdout(std::process::Stdio::null())
-                .stderr(std::process::Stdio::null())
-                .spawn()?;
+                .stderr(std::process::Stdio::null());
+            // SAFETY: detach only makes async-signal-safe calls
+            unsafe { command.pre_exec(detach) };
+            let child = command.spawn()?;
 
-            // Write PID file if requested
+            // Write PID file if requested; no previous copy is kept, as an
+            // older PID may since name an unrelated process
             if let Some(pid_path) = pid_file {
-                std::fs::write(&pid_path, child.id().to_string())?;
+                store::write_atomic(&pid_path, child.id().to_string().as_bytes())?;
             }
 
             println!("Daemon started with PID {}", child.id());
@@ -128,14 +307,122 @@ async fn cmd_start(
         "Starting fakenotifyd"
     );
 
-    // Create shared state
-    let state = Arc::new(DaemonState::new());
-
-    // Create shutdown channel
+    // Create shutdown channel, shared with clients allowed to stop us
     let (shutdown_tx, shutdown_rx) = broadcast::channel::<()>(1);
 
+    // Create shared state
+    let remote_mounts = schedule::remote_mounts();
+    let mount_health = health::MountMonitor::new(
+        health::HealthPolicy {
+            timeout: std::time::Duration::from_secs(config.daemon.mount_timeout),
+            max_backoff: std::time::Duration::from_secs(config.daemon.mount_max_backoff),
+        },
+        remote_mounts
+            .iter()
+            .map(|(point, _)| point.clone())
+            .collect(),
+    );
+    let mut state = DaemonState::with_limits(config.limits)
+        .with_path_map(config.path_map)
+        .with_namespace_resolution(config.daemon.resolve_namespaces)
+        .with_mount_health(mount_health)
+        .with_shutdown(shutdown_tx.clone())
+        .with_compression(config.daemon.compress_threshold)
+        .with_session_timeout(std::time::Duration::from_secs(
+            config.daemon.session_timeout,
+        ))
+        .with_access_events(config.daemon.access_events)
+        .with_multi_tenant(config.daemon.multi_tenant)
+        .with_client_classes(config.client_class.clone());
+    if config.daemon.audit_log.is_some() || config.daemon.audit_journal {
+        state = state.with_audit(audit::AuditLog::open(
+            config.daemon.audit_log.as_deref(),
+            config.daemon.audit_journal,
+        )?);
+    }
+    let watchdog = (config.daemon.scan_timeout > 0).then(|| {
+        Arc::new(watchdog::Watchdog::new(std::time::Duration::from_secs(
+            config.daemon.scan_timeout,
+        )))
+    });
+    if let Some(watchdog) = &watchdog {
+        state = state.with_watchdog(Arc::clone(watchdog));
+    }
+    if config.daemon.enable_stats {
+        state = state.with_rate_tracking(NoisyPolicy {
+            threshold: config.daemon.noisy_threshold,
+            action: config.daemon.noisy_action,
+        });
+    }
+    let catch_up =
+        (config.daemon.checkpoint_dir.is_some() && config.daemon.catch_up > 0).then(|| {
+            Arc::new(catchup::CatchUp::new(std::time::Duration::from_secs(
+                config.daemon.catch_up,
+            )))
+        });
+    if let Some(catch_up) = &catch_up {
+        state = state.with_catch_up(Arc::clone(catch_up));
+    }
+    if config.daemon.journal_size > 0 {
+        state = state.with_journal(journal::Journal::new(
+            config.daemon.journal_size,
+            config.daemon.journal_idle_paths,
+        ));
+    }
+    if !config.plugin.is_empty() {
+        state = state.with_plugins(plugin::load(
+            &config.plugin,
+            hooks::Hooks::new(&config.hook),
+        )?);
+    }
+    let media = (!config.media_server.is_empty())
+        .then(|| Arc::new(media::MediaRefresher::new(config.media_server.clone())));
+    if let Some(media) = &media {
+        state = state.with_media(Arc::clone(media));
+    }
+    let triggers = (!config.trigger.is_empty())
+        .then(|| Arc::new(trigger::Triggers::new(config.trigger.clone())));
+    if let Some(triggers) = &triggers {
+        state = state.with_triggers(Arc::clone(triggers));
+    }
+    // Scans give up on stuck reads and share each file server fairly
+    let mut source: Arc<dyn source::ScanSource> = Arc::new(source::FsSource);
+    #[cfg(target_os = "linux")]
+    if config.daemon.track_xattrs {
+        source = Arc::new(source::xattr::XattrSource::new(source));
+    }
+    if let Some(watchdog) = watchdog {
+        source = Arc::new(watchdog::WatchdogSource::new(source, watchdog));
+    }
+    if config.daemon.scan_concurrency > 0 {
+        source = Arc::new(schedule::GatedSource::new(
+            source,
+            remote_mounts,
+            config.daemon.scan_concurrency,
+        ));
+    }
+    #[cfg(unix)]
+    let watchman = config.daemon.watchman_socket.clone().map(|socket| {
+        (
+            socket,
+            Arc::new(watchman::Watchman::new(Arc::clone(&source))),
+        )
+    });
+    #[cfg(unix)]
+    if let Some((_, watchman)) = &watchman {
+        state = state.with_watchman(Arc::clone(watchman));
+    }
+    #[cfg(unix)]
+    if !config.event_log.is_empty() {
+        state = state.with_event_log(event_log::EventLog::new(&config.event_log)?);
+    }
+    let state = Arc::new(state);
+
     // Set up signal handlers
     let shutdown_tx_clone = shutdown_tx.clone();
+    let reload_trigger = Arc::new(tokio::sync::Notify::new());
+    #[cfg(unix)]
+    let sighup_trigger = Arc::clone(&reload_trigger);
     tokio::spawn(async move {
         #[cfg(unix)]
         {
@@ -145,16 +432,20 @@ async fn cmd_start(
             let mut sigint = signal(SignalKind::interrupt()).expect("Failed to set up SIGINT");
             let mut sighup = signal(SignalKind::hangup()).expect("Failed to set up SIGHUP");
 
-            tokio::select! {
-                _ = sigterm.recv() => {
-                    tracing::info!("Received SIGTERM");
-                }
-                _ = sigint.recv() => {
-                    tracing::info!("Received SIGINT");
-                }
-                _ = sighup.recv() => {
-                    tracing::info!("Received SIGHUP (reload not implemented)");
-                    return;
+            loop {
+                tokio::select! {
+                    _ = sigterm.recv() => {
+                        tracing::info!("Received SIGTERM");
+                        break;
+                    }
+                    _ = sigint.recv() => {
+                        tracing::info!("Received SIGINT");
+                        break;
+                    }
+                    _ = sighup.recv() => {
+                        tracing::info!("Received SIGHUP, reloading the configuration");
+                        sighup_trigger.notify_one();
+                    }
                 }
             }
 
@@ -172,81 +463,424 @@ async fn cmd_start(
     });
 
     // Start the file watcher
-    let default_poll_interval = config.watch.first().map(|w| w.poll_interval).unwrap_or(5);
-
-    let _watcher = watcher::start_watcher(
+    let throttle = throttle::Throttle::new(
+        std::time::Duration::from_secs(config.daemon.startup_quiet),
+        config.daemon.max_events_per_sec,
+    );
+    let watcher = watcher::start_watcher(
         Arc::clone(&state),
+        source,
         config.watch.clone(),
-        default_poll_interval,
+        throttle,
+        config.daemon.event_queue,
+        config.daemon.checkpoint_dir.clone().map(|dir| {
+            checkpoint::Checkpoints::new(
+                dir,
+                std::time::Duration::from_secs(config.daemon.checkpoint_interval),
+            )
+            .with_history(config.daemon.checkpoint_history)
+        }),
+        catch_up,
     )
     .await?;
+    state.attach_watcher(watcher);
+    reload::spawn(
+        Arc::clone(&state),
+        reload::Reloader::new(config_file, config.watch.clone()),
+        reload_trigger,
+        config.daemon.watch_config,
+    );
+
+    // Drop watches whose paths stay gone
+    if config.daemon.prune_interval > 0 {
+        prune::spawn(
+            Arc::clone(&state),
+            std::time::Duration::from_secs(config.daemon.prune_interval),
+            std::time::Duration::from_secs(config.daemon.prune_grace),
+        );
+    }
+
+    // Ask media servers to scan directories as their changes settle
+    if let Some(media) = media {
+        media::spawn(media);
+    }
+    // And update trigger files
+    if let Some(triggers) = triggers {
+        trigger::spawn(triggers);
+    }
+
+    #[cfg(unix)]
+    if let Some((socket, _)) = watchman {
+        tokio::spawn({
+            let state = Arc::clone(&state);
+            let shutdown_rx = shutdown_tx.subscribe();
+            async move {
+                if let Err(e) = watchman::serve(socket, state, shutdown_rx).await {
+                    tracing::error!(error = %e, "Watchman endpoint failed");
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    if config.daemon.watchman_socket.is_some() {
+        tracing::warn!("watchman_socket is not supported on this platform");
+    }
+
+    if let Some(addr) = config.daemon.grpc_listen {
+        #[cfg(feature = "grpc")]
+        tokio::spawn({
+            let state = Arc::clone(&state);
+            let shutdown_rx = shutdown_tx.subscribe();
+            async move {
+                if let Err(e) = grpc::serve(addr, state, shutdown_rx).await {
+                    tracing::error!(error = %e, "gRPC gateway failed");
+                }
+            }
+        });
+        #[cfg(not(feature = "grpc"))]
+        tracing::warn!(
+            addr = %addr,
+            "grpc_listen is set, but this daemon was built without the grpc feature"
+        );
+    }
 
     // Start the socket server
-    let server = Server::new(socket_path.clone(), Arc::clone(&state), shutdown_rx);
+    let mut server = Server::new(socket_path.clone(), Arc::clone(&state), shutdown_rx);
+    #[cfg(unix)]
+    if let Some(listener) = inherited {
+        server = server.with_listener(listener);
+    }
+    if let Some(addr) = config.daemon.tcp_listen {
+        server = server.with_tcp(addr);
+    }
+    if let Some(port) = config.daemon.vsock_listen {
+        server = server.with_vsock(port);
+    }
+    let seconds = |secs: u64| (secs > 0).then(|| std::time::Duration::from_secs(secs));
+    server = server.with_liveness(server::Liveness {
+        heartbeat_interval: seconds(config.daemon.heartbeat_interval),
+        timeout: seconds(config.daemon.heartbeat_timeout),
+        tcp_keepalive: seconds(config.daemon.tcp_keepalive),
+    });
+    server = server.with_request_limits(server::RequestLimits {
+        read_timeout: seconds(config.daemon.request_timeout),
+        max_in_flight: config.daemon.max_in_flight,
+        idle_timeout: seconds(config.daemon.idle_timeout),
+    });
+    server = server.with_drain_timeout(std::time::Duration::from_secs(config.daemon.drain_timeout));
     server.run().await?;
+    state.stop_scans();
 
     tracing::info!("Daemon stopped");
     Ok(())
 }
 
-async fn cmd_stop(config: &Config, socket_override: Option<std::path::PathBuf>) -> Result<()> {
+/// Start a new session and mark every fd past stdio close-on-exec, so a
+/// daemonized child keeps neither the terminal nor the files of whoever
+/// started it
+///
+/// Runs between fork and exec, so it only makes async-signal-safe calls.
+#[cfg(unix)]
+fn detach() -> std::io::Result<()> {
+    // SAFETY: setsid, syscall and fcntl take plain integers
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        if libc::syscall(
+            libc::SYS_close_range,
+            3,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        ) == 0
+        {
+            return Ok(());
+        }
+        // Kernels before 5.11, and other systems
+        let max = libc::sysconf(libc::_SC_OPEN_MAX).clamp(3, 65536) as libc::c_int;
+        for fd in 3..max {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    Ok(())
+}
+
+async fn cmd_stop(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    wait: bool,
+    timeout: Option<std::time::Duration>,
+    pid_file: Option<std::path::PathBuf>,
+) -> Result<()> {
     let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+    let wait = wait || timeout.is_some();
 
-    if !is_daemon_running(&socket_path).await {
-        println!("Daemon is not running");
-        return Ok(());
-    }
+    // Only PIDs from our own namespace get signalled, see `stop`
+    let mut pid = pid_file.as_deref().and_then(stop::read_pid_file);
+    let mut signalled = false;
 
-    // Send ping to verify we can communicate
-    match send_daemon_request(&socket_path, Request::Ping).await {
-        Ok(_) => {
-            // The daemon is running, we'd need a shutdown command
-            // For now, we'll just report that it's running
-            // A real implementation would send a shutdown command
-            println!(
-                "Daemon is running at {}. Use kill or systemctl to stop.",
-                socket_path.display()
-            );
-            println!("(Shutdown command not implemented - use SIGTERM)");
+    if is_daemon_running(&socket_path).await {
+        if pid.is_none() {
+            pid = stop::listener_pid(&socket_path).await;
         }
-        Err(e) => {
-            println!("Failed to communicate with daemon: {}", e);
+        let request = send_daemon_request(&socket_path, Request::Shutdown);
+        let answer = tokio::time::timeout(stop::ANSWER_TIMEOUT, request)
+            .await
+            .unwrap_or_else(|_| {
+                Err(color_eyre::eyre::eyre!(
+                    "no answer within {:?}",
+                    stop::ANSWER_TIMEOUT
+                ))
+            });
+        match answer {
+            Ok(fakenotify_protocol::Response::ShuttingDown { pid: daemon_pid }) => {
+                println!("Daemon (PID {daemon_pid}) is shutting down");
+            }
+            Ok(fakenotify_protocol::Response::Error { message, .. }) => {
+                bail!("Daemon refused to stop: {message}");
+            }
+            Ok(other) => bail!("Unexpected response: {:?}", other),
+            Err(e) => {
+                let Some(pid) = pid else {
+                    bail!("Failed to ask the daemon to stop: {e}");
+                };
+                println!("Daemon did not answer ({e}); sending SIGTERM to PID {pid}");
+                stop::signal(pid, false)?;
+                signalled = true;
+            }
         }
+    } else if let Some(pid) = pid {
+        println!(
+            "Daemon is not listening on {}; sending SIGTERM to PID {pid}",
+            socket_path.display()
+        );
+        stop::signal(pid, false)?;
+        signalled = true;
+    } else {
+        println!("Daemon is not running");
+        std::process::exit(stop::EXIT_NOT_RUNNING);
     }
 
-    Ok(())
+    let exit_code = if signalled { stop::EXIT_KILLED } else { 0 };
+    if !wait {
+        std::process::exit(exit_code);
+    }
+    if stop::wait_for_exit(&socket_path, pid, timeout).await {
+        println!("Daemon stopped");
+        std::process::exit(exit_code);
+    }
+
+    // Out of patience: escalate through the PID
+    let Some(pid) = pid else {
+        bail!("Daemon did not stop in time and its PID is unknown (use --pid-file)");
+    };
+    if !signalled {
+        println!("Daemon did not stop in time; sending SIGTERM to PID {pid}");
+        stop::signal(pid, false)?;
+        if stop::wait_for_exit(&socket_path, Some(pid), Some(stop::KILL_GRACE)).await {
+            println!("Daemon stopped");
+            std::process::exit(stop::EXIT_KILLED);
+        }
+    }
+    println!("Daemon still running; sending SIGKILL to PID {pid}");
+    stop::signal(pid, true)?;
+    if !stop::wait_for_exit(&socket_path, Some(pid), Some(stop::KILL_GRACE)).await {
+        bail!("PID {pid} survived SIGKILL");
+    }
+    println!("Daemon killed");
+    std::process::exit(stop::EXIT_KILLED);
 }
 
-async fn cmd_status(config: &Config, socket_override: Option<std::path::PathBuf>) -> Result<()> {
+async fn cmd_status(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    output: OutputFormat,
+) -> Result<()> {
     let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
 
-    if !is_daemon_running(&socket_path).await {
-        println!("Daemon is not running");
-        return Ok(());
+    let mut report = StatusReport {
+        socket: socket_path.clone(),
+        status: Health::NotRunning,
+        degraded_mounts: Vec::new(),
+        daemon: None,
+    };
+    if is_daemon_running(&socket_path).await {
+        report.status = match send_daemon_request(&socket_path, Request::Ping).await {
+            Ok(fakenotify_protocol::Response::Pong) => match daemon_status(&socket_path).await {
+                Ok((degraded_mounts, daemon)) => {
+                    report.daemon = Some(daemon);
+                    report.degraded_mounts = degraded_mounts;
+                    if report.degraded_mounts.is_empty() {
+                        Health::Ok
+                    } else {
+                        Health::Degraded
+                    }
+                }
+                Err(e) => {
+                    if output == OutputFormat::Text {
+                        println!("Failed to query daemon status: {}", e);
+                    }
+                    Health::Unreachable
+                }
+            },
+            Ok(resp) => {
+                if output == OutputFormat::Text {
+                    println!("Unexpected response: {:?}", resp);
+                }
+                Health::Unreachable
+            }
+            Err(e) => {
+                if output == OutputFormat::Text {
+                    println!("Failed to communicate with daemon: {}", e);
+                }
+                Health::Unreachable
+            }
+        };
     }
 
-    match send_daemon_request(&socket_path, Request::Ping).await {
-        Ok(fakenotify_protocol::Response::Pong) => {
+    if output == OutputFormat::Json {
+        return output::print_json(&report);
+    }
+    match report.status {
+        Health::NotRunning => println!("Daemon is not running"),
+        Health::Unreachable => {}
+        Health::Ok | Health::Degraded => {
             println!("Daemon is running at {}", socket_path.display());
-            println!("Status: OK");
-        }
-        Ok(resp) => {
-            println!("Unexpected response: {:?}", resp);
-        }
-        Err(e) => {
-            println!("Failed to communicate with daemon: {}", e);
+            if report.status == Health::Ok {
+                println!("Status: OK");
+            } else {
+                println!("Status: DEGRADED");
+            }
+            if let Some(daemon) = &report.daemon {
+                print_daemon_status(daemon);
+            }
         }
     }
 
     Ok(())
 }
 
+/// Collect what `status` shows from the Stats, Status and ListClients requests
+async fn daemon_status(
+    socket_path: &std::path::Path,
+) -> Result<(
+    Vec<fakenotify_protocol::DegradedMount>,
+    output::DaemonStatus,
+)> {
+    use fakenotify_protocol::Response;
+
+    let Response::Stats {
+        uptime_secs,
+        clients,
+        watches,
+        degraded_mounts,
+        stuck_scans,
+        dropped_events,
+        ..
+    } = send_daemon_request(socket_path, Request::Stats { top: 0 }).await?
+    else {
+        bail!("Unexpected response to Stats");
+    };
+    let Response::Status {
+        version,
+        protocol_version,
+        event_queue,
+        event_queue_capacity,
+        client_queue_capacity,
+        mounts,
+        last_error,
+    } = send_daemon_request(socket_path, Request::Status).await?
+    else {
+        bail!("Daemon does not support the Status request");
+    };
+    let Response::Clients { clients: connected } =
+        send_daemon_request(socket_path, Request::ListClients).await?
+    else {
+        bail!("Unexpected response to ListClients");
+    };
+
+    let daemon = output::DaemonStatus {
+        version,
+        protocol_version,
+        uptime_secs,
+        watches,
+        clients,
+        event_queue: output::QueueDepth {
+            len: event_queue.into(),
+            capacity: event_queue_capacity.into(),
+        },
+        queued_events: connected
+            .iter()
+            .map(|client| u64::from(client.queued_events))
+            .sum(),
+        fullest_client_queue: output::QueueDepth {
+            len: connected
+                .iter()
+                .map(|client| u64::from(client.queued_events))
+                .max()
+                .unwrap_or(0),
+            capacity: client_queue_capacity.into(),
+        },
+        dropped_events,
+        stuck_scans,
+        mounts,
+        last_error,
+    };
+    Ok((degraded_mounts, daemon))
+}
+
+/// Version, load and mounts of a running daemon
+fn print_daemon_status(daemon: &output::DaemonStatus) {
+    println!(
+        "Version: {} (protocol {})",
+        daemon.version, daemon.protocol_version
+    );
+    println!("Uptime:  {}s", daemon.uptime_secs);
+    println!("Clients: {}", daemon.clients);
+    println!("Watches: {}", daemon.watches);
+    println!(
+        "Event queue: {}/{} ({} dropped since start)",
+        daemon.event_queue.len, daemon.event_queue.capacity, daemon.dropped_events
+    );
+    println!(
+        "Client queues: {} events queued, fullest {}/{}",
+        daemon.queued_events, daemon.fullest_client_queue.len, daemon.fullest_client_queue.capacity
+    );
+    println!("Stuck scans: {}", daemon.stuck_scans);
+    if !daemon.mounts.is_empty() {
+        println!("\nMounts:");
+    }
+    for mount in &daemon.mounts {
+        let roots = if mount.roots == 1 { "root" } else { "roots" };
+        let health = match mount.degraded_secs {
+            None => "ok".to_string(),
+            Some(secs) => format!(
+                "not responding for {secs}s, {} failed probes",
+                mount.failed_probes
+            ),
+        };
+        println!(
+            "  {}  ({} {roots}): {health}",
+            mount.path.display(),
+            mount.roots
+        );
+    }
+    if let Some(error) = &daemon.last_error {
+        println!(
+            "\nLast error ({}s ago): {} {}",
+            error.age_secs, error.level, error.message
+        );
+    }
+}
+
 async fn cmd_add(
     config: &Config,
     socket_override: Option<std::path::PathBuf>,
     path: std::path::PathBuf,
-    _poll_interval: u64,
+    _poll_interval: std::time::Duration,
     _recursive: bool,
+    output: OutputFormat,
 ) -> Result<()> {
     let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
 
@@ -263,10 +897,11 @@ async fn cmd_add(
     };
 
     match send_daemon_request(&socket_path, request).await {
-        Ok(fakenotify_protocol::Response::WatchAdded { wd }) => {
-            println!("Watch added: wd={} path={}", wd, abs_path.display());
-        }
-        Ok(fakenotify_protocol::Response::Error { message }) => {
+        Ok(fakenotify_protocol::Response::WatchAdded { wd }) => match output {
+            OutputFormat::Text => println!("Watch added: wd={} path={}", wd, abs_path.display()),
+            OutputFormat::Json => output::print_json(&AddReport { wd, path: abs_path })?,
+        },
+        Ok(fakenotify_protocol::Response::Error { message, .. }) => {
             bail!("Failed to add watch: {}", message);
         }
         Ok(resp) => {
@@ -284,6 +919,7 @@ async fn cmd_remove(
     config: &Config,
     socket_override: Option<std::path::PathBuf>,
     path: std::path::PathBuf,
+    output: OutputFormat,
 ) -> Result<()> {
     let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
 
@@ -291,40 +927,852 @@ async fn cmd_remove(
         bail!("Daemon is not running");
     }
 
-    // For remove, we'd need to look up the wd for the path
-    // This would require a ListWatches command or similar
-    // For now, we just print a message
+    // Watches belong to the clients that added them; a one-shot connection
+    // can't drop another client's subscription, so just report it
+    if output == OutputFormat::Json {
+        return output::print_json(&RemoveReport {
+            path,
+            removed: false,
+        });
+    }
     println!(
         "Remove by path not fully implemented. Path: {}",
         path.display()
     );
-    println!("Use the watch descriptor from 'list' command with the RemoveWatch request.");
+    println!("Watches are removed when the clients holding them (see 'list') remove or exit.");
 
     Ok(())
 }
 
-async fn cmd_list(config: &Config, socket_override: Option<std::path::PathBuf>) -> Result<()> {
+async fn cmd_remove_tagged(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    tag: String,
+    output: OutputFormat,
+) -> Result<()> {
     let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
 
     if !is_daemon_running(&socket_path).await {
-        println!("Daemon is not running");
-        return Ok(());
+        bail!("Daemon is not running");
     }
 
-    // We'd need a ListWatches command to implement this properly
-    // For now, just verify the daemon is running
-    match send_daemon_request(&socket_path, Request::Ping).await {
-        Ok(fakenotify_protocol::Response::Pong) => {
-            println!("Daemon is running at {}", socket_path.display());
-            println!("(List watches command not yet implemented)");
+    let request = Request::RemoveTagged { tag: tag.clone() };
+    let removed = match send_daemon_request(&socket_path, request).await {
+        Ok(fakenotify_protocol::Response::Removed { paths }) => paths,
+        Ok(fakenotify_protocol::Response::Error { message, .. }) => {
+            bail!("Failed to remove watches: {}", message);
+        }
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    };
+
+    if output == OutputFormat::Json {
+        return output::print_json(&RemoveTaggedReport { tag, removed });
+    }
+    if removed.is_empty() {
+        println!("No watches tagged {tag}");
+    }
+    for path in removed {
+        println!("Removed: {}", path.display());
+    }
+
+    Ok(())
+}
+
+async fn cmd_tag(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    wd: i32,
+    tags: Vec<String>,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if let Some(tag) = tags
+        .iter()
+        .find(|tag| !fakenotify_protocol::is_valid_tag(tag))
+    {
+        bail!("Invalid tag `{tag}`: tags are non-empty, without whitespace or commas");
+    }
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    let request = Request::TagWatch {
+        wd,
+        tags: tags.clone(),
+    };
+    match send_daemon_request(&socket_path, request).await {
+        Ok(fakenotify_protocol::Response::WatchTagged) => {}
+        Ok(fakenotify_protocol::Response::Error { message, .. }) => {
+            bail!("Failed to tag watch: {}", message);
+        }
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    }
+
+    match output {
+        OutputFormat::Text if tags.is_empty() => println!("Watch {wd}: tags cleared"),
+        OutputFormat::Text => println!("Watch {wd}: tagged {}", tags.join(",")),
+        OutputFormat::Json => output::print_json(&TagReport { wd, tags })?,
+    }
+    Ok(())
+}
+
+async fn cmd_list(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    tag: Option<String>,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    let mut watches = match send_daemon_request(&socket_path, Request::ListWatches).await {
+        Ok(fakenotify_protocol::Response::Watches { watches }) => watches,
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    };
+
+    if let Some(tag) = &tag {
+        watches.retain(|watch| watch.tags.contains(tag));
+    }
+    if output == OutputFormat::Json {
+        return output::print_json(&ListReport { watches });
+    }
+    if watches.is_empty() {
+        println!("No watches");
+    }
+    for watch in watches {
+        let recursive = if watch.recursive { "  [recursive]" } else { "" };
+        let tags = if watch.tags.is_empty() {
+            String::new()
+        } else {
+            format!("  tags={}", watch.tags.join(","))
+        };
+        println!(
+            "{:>6}  {:#010x}  {:>3} client(s)  {}{recursive}{tags}",
+            watch.wd,
+            watch.mask,
+            watch.clients,
+            watch.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_watch_info(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    wd: i32,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    let watch = match send_daemon_request(&socket_path, Request::GetWatchInfo { wd }).await {
+        Ok(fakenotify_protocol::Response::WatchInfo { info }) => info,
+        Ok(fakenotify_protocol::Response::Error { message, .. }) => bail!("{message}"),
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    };
+
+    if output == OutputFormat::Json {
+        return output::print_json(&WatchInfoReport { watch });
+    }
+    println!("Watch:     {}", watch.wd);
+    println!("Path:      {}", watch.path.display());
+    println!("Mask:      {:#010x}", watch.mask);
+    println!("Recursive: {}", watch.recursive);
+    println!("Clients:   {}", watch.clients);
+    if !watch.tags.is_empty() {
+        println!("Tags:      {}", watch.tags.join(","));
+    }
+    match &watch.root {
+        Some(root) => println!("Root:      {}", root.display()),
+        None => println!("Root:      none; no watched root covers this path"),
+    }
+    println!("Snapshot:  {} entries", watch.snapshot_entries);
+    match watch.last_scan_ms_ago {
+        Some(ms) => println!("Scanned:   {:.1}s ago", ms as f64 / 1000.0),
+        None => println!("Scanned:   never"),
+    }
+
+    Ok(())
+}
+
+async fn cmd_clients(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    let clients = match send_daemon_request(&socket_path, Request::ListClients).await {
+        Ok(fakenotify_protocol::Response::Clients { clients }) => clients,
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    };
+
+    if output == OutputFormat::Json {
+        return output::print_json(&ClientsReport { clients });
+    }
+    // The connection asking is itself a client
+    for client in clients {
+        // Over TCP only the client itself knows its pid
+        let pid = client
+            .pid
+            .or(client.info.pid)
+            .map_or_else(|| "-".to_string(), |pid| pid.to_string());
+        let name = match (&client.info.name, &client.info.version) {
+            (Some(name), Some(version)) => format!("{name} {version}"),
+            (Some(name), None) => name.clone(),
+            (None, _) => "-".to_string(),
+        };
+        // Only worth a column when someone picked a namespace
+        let namespace = if client.namespace == client.uid.to_string() {
+            String::new()
+        } else {
+            format!("  namespace={}", client.namespace)
+        };
+        println!(
+            "{:>6}  uid={:<6} pid={:<8} watches={:<5} queued={:<5} connected {}s  {}{}",
+            client.id,
+            client.uid,
+            pid,
+            client.watches,
+            client.queued_events,
+            client.connected_secs,
+            name,
+            namespace
+        );
+    }
+
+    Ok(())
+}
+
+async fn cmd_export(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    let roots = match send_daemon_request(&socket_path, Request::ExportRoots).await {
+        Ok(fakenotify_protocol::Response::Roots { roots }) => roots,
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    };
+
+    let watch = roots.into_iter().map(config::WatchConfig::from).collect();
+    match output {
+        OutputFormat::Text => print!("{}", toml::to_string(&config::WatchList { watch })?),
+        OutputFormat::Json => output::print_json(&ExportReport { watch })?,
+    }
+    Ok(())
+}
+
+async fn cmd_import(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    file: std::path::PathBuf,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    let list: config::WatchList = toml::from_str(&std::fs::read_to_string(&file)?)?;
+    if list.watch.is_empty() {
+        bail!("{} has no [[watch]] entries", file.display());
+    }
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    let request = Request::ImportRoots {
+        roots: list.watch.iter().map(Into::into).collect(),
+    };
+    let (added, existing, failed) = match send_daemon_request(&socket_path, request).await {
+        Ok(fakenotify_protocol::Response::Imported {
+            added,
+            existing,
+            failed,
+        }) => (added, existing, failed),
+        Ok(fakenotify_protocol::Response::Error { message, .. }) => {
+            bail!("Failed to import watches: {}", message);
         }
         Ok(resp) => {
-            println!("Unexpected response: {:?}", resp);
+            bail!("Unexpected response: {:?}", resp);
         }
         Err(e) => {
             bail!("Failed to communicate with daemon: {}", e);
         }
+    };
+
+    let failures = failed.len();
+    match output {
+        OutputFormat::Text => {
+            for path in &added {
+                println!("Added: {}", path.display());
+            }
+            for path in &existing {
+                println!("Already watched: {}", path.display());
+            }
+            for (path, error) in &failed {
+                println!("Failed: {}: {}", path.display(), error);
+            }
+        }
+        OutputFormat::Json => output::print_json(&ImportReport {
+            added,
+            existing,
+            failed: failed
+                .into_iter()
+                .map(|(path, error)| ImportFailure { path, error })
+                .collect(),
+        })?,
+    }
+    if failures > 0 {
+        bail!("{failures} root(s) could not be imported");
+    }
+    Ok(())
+}
+
+async fn cmd_prune(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    match send_daemon_request(&socket_path, Request::Prune).await {
+        Ok(fakenotify_protocol::Response::Pruned { paths }) => {
+            if output == OutputFormat::Json {
+                return output::print_json(&PruneReport { pruned: paths });
+            }
+            if paths.is_empty() {
+                println!("No missing watch paths");
+            }
+            for path in paths {
+                println!("Pruned: {}", path.display());
+            }
+        }
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn cmd_stats(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    top: u32,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        bail!("Daemon is not running");
+    }
+
+    match send_daemon_request(&socket_path, Request::Stats { top }).await {
+        Ok(fakenotify_protocol::Response::Stats {
+            uptime_secs,
+            clients,
+            watches,
+            window_secs,
+            watch_rates,
+            dir_rates,
+            degraded_mounts,
+            stuck_scans,
+            dropped_events,
+            shared_roots,
+            scans_saved,
+            limits,
+            users,
+        }) => {
+            if output == OutputFormat::Json {
+                return output::print_json(&StatsReport {
+                    uptime_secs,
+                    clients,
+                    watches,
+                    stuck_scans,
+                    dropped_events,
+                    shared_roots,
+                    scans_saved,
+                    window_secs,
+                    watch_rates,
+                    dir_rates,
+                    degraded_mounts,
+                    limits,
+                    users,
+                });
+            }
+            println!("Uptime:  {uptime_secs}s");
+            println!("Clients: {clients}");
+            println!("Watches: {watches}");
+            println!("Stuck scans: {stuck_scans}");
+            println!("Dropped events: {dropped_events}");
+            if shared_roots > 0 {
+                println!("Shared scans: {shared_roots} roots, {scans_saved} scans saved");
+            }
+            print_user_usage(&limits, &users);
+            print_degraded_mounts(&degraded_mounts);
+            if window_secs == 0 {
+                println!("Event rates are not tracked; set enable_stats = true");
+                return Ok(());
+            }
+            let print = |title: &str, rates: &[fakenotify_protocol::PathRate]| {
+                println!("\n{title} (events/s over {window_secs}s):");
+                if rates.is_empty() {
+                    println!("  no events");
+                }
+                for rate in rates {
+                    let per_sec = rate.events as f64 / f64::from(window_secs);
+                    let noisy = if rate.noisy { "  [noisy]" } else { "" };
+                    let tags = if rate.tags.is_empty() {
+                        String::new()
+                    } else {
+                        format!("  tags={}", rate.tags.join(","))
+                    };
+                    println!("  {per_sec:>9.1}  {}{noisy}{tags}", rate.path.display());
+                }
+            };
+            print("Busiest watches", &watch_rates);
+            print("Busiest directories", &dir_rates);
+        }
+        Ok(resp) => {
+            bail!("Unexpected response: {:?}", resp);
+        }
+        Err(e) => {
+            bail!("Failed to communicate with daemon: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Each user's usage against the per-user limits
+fn print_user_usage(
+    limits: &fakenotify_protocol::UserLimits,
+    users: &[fakenotify_protocol::UserUsage],
+) {
+    if users.is_empty() {
+        return;
+    }
+    println!(
+        "\nUsers (limits: {} instances, {} watches, {} queued events per client):",
+        limits.max_user_instances, limits.max_user_watches, limits.max_queued_events
+    );
+    for usage in users {
+        println!(
+            "  uid {:<6} {} instances, {} watches, {} queued events",
+            usage.uid, usage.instances, usage.watches, usage.queued_events
+        );
+    }
+}
+
+/// List mounts whose scans are paused, if any
+fn print_degraded_mounts(mounts: &[fakenotify_protocol::DegradedMount]) {
+    if mounts.is_empty() {
+        return;
+    }
+    println!("\nUnresponsive mounts (scans paused):");
+    for mount in mounts {
+        println!(
+            "  {}  for {}s, {} failed probes",
+            mount.path.display(),
+            mount.degraded_secs,
+            mount.failed_probes
+        );
+    }
+}
+
+fn cmd_config_dump(config: &Config, format: DumpFormat) -> Result<()> {
+    let output = match format {
+        DumpFormat::Toml => toml::to_string_pretty(config)?,
+        DumpFormat::Json => serde_json::to_string_pretty(config)?,
+    };
+    println!("{}", output.trim_end());
+    Ok(())
+}
+
+fn cmd_config_validate(file: Option<std::path::PathBuf>) -> Result<()> {
+    let Some(file) = file.or_else(config::find_config_file) else {
+        bail!("No configuration file found");
+    };
+
+    let issues = validate::validate_file(&file);
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity == validate::Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!("{} has {} error(s)", file.display(), errors);
+    }
+    println!("{} is valid", file.display());
+    Ok(())
+}
+
+fn cmd_doctor(config: &Config) -> Result<()> {
+    let mut issues = validate::check(config);
+    issues.extend(doctor::check(config));
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity == validate::Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!("found {} error(s)", errors);
+    }
+    if issues.is_empty() {
+        println!("No problems found");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn cmd_wait(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    options: wait::WaitOptions,
+    output: OutputFormat,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+    let Some(report) = wait::run(&socket_path, &options)? else {
+        if output == OutputFormat::Text {
+            eprintln!("Timed out waiting for an event");
+        }
+        std::process::exit(wait::EXIT_TIMEOUT);
+    };
+    if output == OutputFormat::Json {
+        return output::print_json(&report);
+    }
+    println!("{}", wait::inotifywait_line(&report));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn cmd_bench(config: &Config, options: bench::BenchOptions, output: OutputFormat) -> Result<()> {
+    if output == OutputFormat::Text {
+        println!(
+            "Creating {} files/s for {:?} with {} client(s), polling every {:?}...",
+            options.rate, options.duration, options.clients, options.poll_interval
+        );
+    }
+    let report = bench::run(config, &std::env::current_exe()?, &options)?;
+    if output == OutputFormat::Json {
+        return output::print_json(&report);
+    }
+
+    println!(
+        "Files created: {} ({:.1}/s)",
+        report.files_created, report.create_rate
+    );
+    println!(
+        "Events:        {} of {} received, {} dropped, {} overflow(s)",
+        report.events_received, report.events_expected, report.events_dropped, report.overflows
+    );
+    println!(
+        "Latency (ms):  p50 {:.1}  p90 {:.1}  p99 {:.1}  max {:.1}",
+        report.latency_p50_ms, report.latency_p90_ms, report.latency_p99_ms, report.latency_max_ms
+    );
+    if report.events_dropped > 0 {
+        println!(
+            "Drops include files deleted before a scan saw them; raise --live-files \
+             or lower the poll interval"
+        );
+    }
+    Ok(())
+}
+
+fn cmd_estimate(
+    config: &Config,
+    path: std::path::PathBuf,
+    sample: usize,
+    output: OutputFormat,
+) -> Result<()> {
+    if !path.is_dir() {
+        bail!("{} is not a directory", path.display());
+    }
+    let mut seed = [0; 8];
+    getrandom::fill(&mut seed)?;
+    let source = estimate::source_for(config, &path);
+    let estimate = estimate::estimate(source.as_ref(), &path, sample, u64::from_ne_bytes(seed));
+    let report = output::EstimateReport {
+        path,
+        exact: estimate.exact,
+        dirs: estimate.dirs,
+        files: estimate.files,
+        listed_dirs: estimate.listed,
+        snapshot_bytes: estimate.snapshot_bytes,
+        peak_bytes: estimate.peak_bytes(),
+        scan_ms: estimate.scan.as_millis() as u64,
+        by_concurrency: estimate
+            .concurrent
+            .iter()
+            .map(|(concurrency, scan)| output::ConcurrentScan {
+                concurrency: *concurrency,
+                scan_ms: scan.as_millis() as u64,
+            })
+            .collect(),
+        poll_interval_ms: estimate.poll_interval().as_millis() as u64,
+    };
+    if output == OutputFormat::Json {
+        return output::print_json(&report);
+    }
+
+    const MIB: f64 = 1024.0 * 1024.0;
+    let about = if report.exact { "" } else { "~" };
+    println!(
+        "Tree:          {about}{} directories, {about}{} files ({} directories listed)",
+        report.dirs, report.files, report.listed_dirs
+    );
+    println!(
+        "Memory:        {about}{:.1} MiB per snapshot, {about}{:.1} MiB while diffing",
+        report.snapshot_bytes as f64 / MIB,
+        report.peak_bytes as f64 / MIB
+    );
+    println!("Scan:          {about}{} ms", report.scan_ms);
+    for scan in &report.by_concurrency {
+        println!(
+            "  split into top-level roots, scan_concurrency = {:<2}  {about}{} ms",
+            scan.concurrency, scan.scan_ms
+        );
+    }
+    println!("Poll interval: {:?} or longer", estimate.poll_interval());
+    Ok(())
+}
+
+fn cmd_diff(
+    config: &Config,
+    path: std::path::PathBuf,
+    since: diff::Since,
+    output: OutputFormat,
+) -> Result<()> {
+    let Some(dir) = config.daemon.checkpoint_dir.clone() else {
+        bail!("checkpoint_dir is not set, so there are no snapshots to compare with");
+    };
+    if !path.exists() {
+        bail!("{} does not exist", path.display());
+    }
+    let path: std::path::PathBuf = std::path::absolute(&path)?.components().collect();
+    let Some(watch) = config
+        .watch
+        .iter()
+        .filter(|watch| path.starts_with(&watch.path))
+        .max_by_key(|watch| watch.path.components().count())
+    else {
+        bail!("{} is not under a configured [[watch]]", path.display());
+    };
+    let checkpoints = checkpoint::Checkpoints::new(dir, std::time::Duration::ZERO);
+    let stored = checkpoints.stored(&watch.path);
+    let Some(stored) = diff::pick(&stored, since) else {
+        match stored.last() {
+            Some(oldest) => bail!(
+                "the oldest checkpoint of {} is from {}",
+                watch.path.display(),
+                humantime_serde::re::humantime::format_rfc3339_seconds(oldest.taken)
+            ),
+            None => bail!("{} has no checkpoint yet", watch.path.display()),
+        }
+    };
+    let (key, old) = checkpoint::read(&stored.path)?;
+
+    let source = estimate::source_for(config, &watch.path);
+    let new = scanner::Scanner::new(source, path.clone(), key.recursive)
+        .snapshot()
+        .clone();
+    let changes = diff::changes(&old, &new, &path, key.detection);
+
+    if output == OutputFormat::Text {
+        for changed in &changes {
+            println!("{}", diff::itemize(changed, &path));
+        }
+        return Ok(());
     }
+    let mut report = output::DiffReport {
+        path: path.clone(),
+        snapshot: stored.path.clone(),
+        since: humantime_serde::re::humantime::format_rfc3339_seconds(stored.taken).to_string(),
+        created: Vec::new(),
+        modified: Vec::new(),
+        deleted: Vec::new(),
+    };
+    for changed in changes {
+        let mut name = changed.path.display().to_string();
+        if changed.is_dir {
+            name.push('/');
+        }
+        match changed.change {
+            diff::Change::Created => report.created.push(name),
+            diff::Change::Modified { .. } => report.modified.push(name),
+            diff::Change::Deleted => report.deleted.push(name),
+        }
+    }
+    output::print_json(&report)
+}
+
+#[cfg(target_os = "linux")]
+fn cmd_install(config: Option<std::path::PathBuf>, args: cli::InstallArgs) -> Result<()> {
+    use install::{InstallOptions, LD_SO_PRELOAD, TMPFILES_PATH};
+    let cli::InstallArgs {
+        user,
+        services,
+        ld_so_preload,
+        yes,
+        preload,
+        root,
+        dry_run,
+        socket,
+    } = args;
 
+    if ld_so_preload {
+        if user {
+            bail!("--ld-so-preload affects the whole host and needs a system install");
+        }
+        if !yes && !dry_run {
+            eprintln!("WARNING: --ld-so-preload adds the preload library to {LD_SO_PRELOAD}.");
+            eprintln!("Every dynamically linked program on this host will load it, including");
+            eprintln!("sshd, systemd helpers and your shell. A broken or missing library there");
+            eprintln!("can make the system unusable; prefer --service for specific services.");
+            bail!("Refusing to edit {LD_SO_PRELOAD} without --yes");
+        }
+    }
+
+    let daemon = std::env::current_exe()?;
+    let preload = exec::resolve_preload(preload.as_deref());
+    // Relative paths would resolve against the service's working directory
+    let config = config.map(std::path::absolute).transpose()?;
+    let options = if user {
+        let Some(config_dir) = dirs::config_dir() else {
+            bail!("Cannot find the user configuration directory");
+        };
+        InstallOptions::user(config_dir.join("systemd/user"), daemon, preload)
+    } else {
+        InstallOptions::system(daemon, preload)
+    }
+    .with_config(config)
+    .with_socket(socket)
+    .with_services(services.clone())
+    .with_ld_so_preload(ld_so_preload);
+
+    let files = install::plan(&options);
+    if dry_run {
+        for file in &files {
+            let action = if file.append { "append to" } else { "write" };
+            println!("# {action} {}", file.path.display());
+            println!("{}", file.contents.trim_end());
+            println!();
+        }
+        return Ok(());
+    }
+
+    for path in install::apply(&files, root.as_deref())? {
+        println!("Wrote {}", path.display());
+    }
+    if root.is_some() {
+        return Ok(());
+    }
+
+    let systemctl = if user {
+        "systemctl --user"
+    } else {
+        "systemctl"
+    };
+    println!();
+    println!("Next steps:");
+    if !user {
+        println!("  systemd-tmpfiles --create {TMPFILES_PATH}");
+    }
+    println!("  {systemctl} daemon-reload");
+    println!("  {systemctl} enable --now {}", install::UNIT_NAME);
+    for service in &services {
+        println!("  {systemctl} restart {service}");
+    }
     Ok(())
 }
+
+#[cfg(unix)]
+async fn cmd_exec(
+    config: &Config,
+    socket_override: Option<std::path::PathBuf>,
+    trace: bool,
+    preload: Option<std::path::PathBuf>,
+    command: Vec<std::ffi::OsString>,
+) -> Result<()> {
+    let socket_path = socket_override.unwrap_or_else(|| config.daemon.socket.clone());
+
+    if !is_daemon_running(&socket_path).await {
+        tracing::warn!(
+            socket = %socket_path.display(),
+            "Daemon is not running; the command will fall back to kernel inotify"
+        );
+    }
+
+    let status = tokio::task::spawn_blocking(move || {
+        if trace {
+            exec::run_traced(&command, &socket_path)
+        } else {
+            let library = exec::resolve_preload(preload.as_deref());
+            exec::run_preload(&command, &socket_path, &library)
+        }
+    })
+    .await??;
+
+    std::process::exit(exec::exit_code(status));
+}
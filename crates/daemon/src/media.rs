@@ -0,0 +1,314 @@
+//! Library refreshes for Jellyfin and Plex.
+//!
+//! Media servers find new files by polling their libraries or through
+//! inotify, which sees nothing on network shares. With `[[media_server]]`
+//! configured, the daemon asks the server itself to scan a directory once
+//! changes in it have settled: Plex with a partial scan of the library
+//! section, Jellyfin with a media-updated notice for the path. A burst of
+//! changes in one directory becomes one scan, and paths are translated to
+//! the server's view through the server's `path_map`.
+
+use crate::config::MediaServer;
+use crate::watcher::WatcherEvent;
+use notify::EventKind;
+use notify::event::ModifyKind;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// How often settled directories are looked for
+const TICK: Duration = Duration::from_secs(1);
+
+/// Time allowed for a server to answer a scan request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Which API a `[[media_server]]` speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaServerKind {
+    /// Jellyfin, or Emby, which shares its API
+    #[serde(alias = "emby")]
+    Jellyfin,
+    Plex,
+}
+
+/// Debounces changes under media libraries into scan requests
+pub struct MediaRefresher {
+    servers: Vec<MediaServer>,
+    /// Directories waiting to settle, by server index, with their last change
+    pending: Mutex<HashMap<(usize, PathBuf), Instant>>,
+    agent: ureq::Agent,
+}
+
+impl MediaRefresher {
+    pub fn new(servers: Vec<MediaServer>) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(REQUEST_TIMEOUT))
+            .build()
+            .into();
+        Self {
+            servers,
+            pending: Mutex::new(HashMap::new()),
+            agent,
+        }
+    }
+
+    /// Note a change for every library it falls under
+    pub fn observe(&self, event: &WatcherEvent) {
+        if !changes_content(&event.kind) {
+            return;
+        }
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        for (index, server) in self.servers.iter().enumerate() {
+            let Some(library) = server.library_for(&event.path) else {
+                continue;
+            };
+            let dir = event
+                .path
+                .parent()
+                .filter(|parent| parent.starts_with(&library.path))
+                .unwrap_or(&event.path);
+            pending.insert((index, dir.to_path_buf()), now);
+        }
+    }
+
+    /// Take the directories quiet for their server's `settle` as of `now`,
+    /// leaving out those under another one being scanned
+    fn settled(&self, now: Instant) -> Vec<(usize, PathBuf)> {
+        let mut pending = self.pending.lock();
+        let mut due: Vec<_> = pending
+            .iter()
+            .filter(|((index, _), changed)| {
+                now.duration_since(**changed) >= self.servers[*index].settle
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &due {
+            pending.remove(key);
+        }
+
+        // A directory sorts before everything under it
+        due.sort();
+        due.dedup_by(|(index, dir), (kept_index, kept)| {
+            index == kept_index && dir.starts_with(kept)
+        });
+        due
+    }
+
+    /// Ask the servers to scan the directories settled as of `now`
+    pub async fn flush(&self, now: Instant) {
+        let due: Vec<_> = self
+            .settled(now)
+            .into_iter()
+            .map(|(index, dir)| (self.servers[index].clone(), dir))
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        let agent = self.agent.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            for (server, dir) in due {
+                match refresh(&agent, &server, &dir) {
+                    Ok(()) => tracing::info!(
+                        server = %server.url,
+                        dir = %dir.display(),
+                        "Requested library scan"
+                    ),
+                    Err(e) => tracing::warn!(
+                        server = %server.url,
+                        dir = %dir.display(),
+                        error = %e,
+                        "Library scan request failed"
+                    ),
+                }
+            }
+        })
+        .await;
+    }
+}
+
+/// Flush settled directories every second
+pub fn spawn(refresher: Arc<MediaRefresher>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            refresher.flush(Instant::now()).await;
+        }
+    })
+}
+
+/// Whether an event of `kind` can change what a library contains
+fn changes_content(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(modify) => !matches!(modify, ModifyKind::Metadata(_)),
+        _ => false,
+    }
+}
+
+/// Ask `server` to scan `dir`, or the nearest directory above it in its
+/// library that still exists
+fn refresh(agent: &ureq::Agent, server: &MediaServer, dir: &Path) -> Result<(), ureq::Error> {
+    let Some(library) = server.library_for(dir) else {
+        return Ok(());
+    };
+    let mut dir = dir;
+    while dir != library.path && !dir.exists() {
+        dir = dir.parent().unwrap_or(&library.path);
+    }
+    let remote = server.remote_path(dir);
+    let url = server.url.trim_end_matches('/');
+
+    match server.kind {
+        MediaServerKind::Plex => {
+            let Some(section) = &library.section else {
+                tracing::warn!(
+                    library = %library.path.display(),
+                    "Plex library has no section, not scanning"
+                );
+                return Ok(());
+            };
+            let mut request = agent
+                .get(format!("{url}/library/sections/{section}/refresh"))
+                .query("path", remote.to_string_lossy());
+            if !server.api_key.is_empty() {
+                request = request.header("X-Plex-Token", &server.api_key);
+            }
+            request.call()?;
+        }
+        MediaServerKind::Jellyfin => {
+            let body = serde_json::json!({
+                "Updates": [{ "Path": remote, "UpdateType": "Modified" }],
+            });
+            let mut request = agent.post(format!("{url}/Library/Media/Updated"));
+            if !server.api_key.is_empty() {
+                request = request.header(
+                    "Authorization",
+                    format!("MediaBrowser Token=\"{}\"", server.api_key),
+                );
+            }
+            request
+                .content_type("application/json")
+                .send(body.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MediaLibrary, MediaPathMap};
+    use notify::event::{CreateKind, MetadataKind};
+    use std::io::{Read, Write};
+
+    fn server(kind: MediaServerKind, url: String, library: &Path) -> MediaServer {
+        MediaServer {
+            kind,
+            url,
+            api_key: "secret".into(),
+            settle: Duration::from_secs(30),
+            library: vec![MediaLibrary {
+                path: library.to_path_buf(),
+                section: Some("2".into()),
+            }],
+            path_map: vec![MediaPathMap {
+                local: library.parent().unwrap().to_path_buf(),
+                remote: PathBuf::from("/data"),
+            }],
+        }
+    }
+
+    fn event(path: &Path, kind: EventKind) -> WatcherEvent {
+        WatcherEvent {
+            path: path.to_path_buf(),
+            kind,
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+
+    #[test]
+    fn test_changes_settle_into_one_scan_per_directory() {
+        let library = Path::new("/mnt/media/tv");
+        let refresher = MediaRefresher::new(vec![server(
+            MediaServerKind::Jellyfin,
+            String::new(),
+            library,
+        )]);
+        let create = EventKind::Create(CreateKind::File);
+        refresher.observe(&event(&library.join("Show/S01/a.mkv"), create));
+        refresher.observe(&event(&library.join("Show/S01/b.mkv"), create));
+        refresher.observe(&event(&library.join("Show/poster.jpg"), create));
+        refresher.observe(&event(&library.join("Movie/m.mkv"), create));
+        refresher.observe(&event(
+            &library.join("Other/m.mkv"),
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+        ));
+        refresher.observe(&event(Path::new("/mnt/elsewhere/x.mkv"), create));
+
+        let now = Instant::now();
+        assert!(refresher.settled(now).is_empty());
+        let due = refresher.settled(now + Duration::from_secs(30));
+        assert_eq!(
+            due,
+            vec![(0, library.join("Movie")), (0, library.join("Show"))]
+        );
+        assert!(refresher.settled(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_plex_partial_scan_request() {
+        let root = std::env::temp_dir().join(format!("fakenotify-media-{}", std::process::id()));
+        let library = root.join("tv");
+        std::fs::create_dir_all(library.join("Show")).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let http = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let refresher = MediaRefresher::new(vec![server(MediaServerKind::Plex, url, &library)]);
+        refresher.observe(&event(
+            &library.join("Show/e1.mkv"),
+            EventKind::Create(CreateKind::File),
+        ));
+        refresher
+            .flush(Instant::now() + Duration::from_secs(30))
+            .await;
+
+        let request = http.join().unwrap();
+        let request_line = request.lines().next().unwrap();
+        assert!(
+            request_line.starts_with("GET /library/sections/2/refresh?path=%2Fdata%2Ftv%2FShow "),
+            "{request_line}"
+        );
+        assert!(
+            request
+                .to_ascii_lowercase()
+                .contains("x-plex-token: secret")
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
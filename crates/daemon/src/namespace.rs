@@ -0,0 +1,197 @@
+//! Mount-namespace aware path resolution (Linux).
+//!
+//! A client in a container names paths in its own mount namespace. Using the
+//! peer pid from `SO_PEERCRED`, the daemon reads the client's
+//! `/proc/<pid>/mountinfo`, finds the mount a path lives on and the same
+//! filesystem in its own namespace, and watches the path there. When the
+//! filesystem is not mounted on the daemon's side at all, the path is watched
+//! through `/proc/<pid>/root`, which only works while the client lives.
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// One line of `/proc/<pid>/mountinfo`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    /// `major:minor` of the mounted filesystem
+    pub dev: String,
+    /// Directory within the filesystem that is mounted (non-`/` for binds)
+    pub root: PathBuf,
+    /// Where it is mounted in the namespace
+    pub mount_point: PathBuf,
+    /// Options of this mount, e.g. `rw,noatime`
+    pub mount_options: String,
+    /// Filesystem type, e.g. `nfs4`
+    pub fs_type: String,
+    /// Mount source, e.g. `nas:/export` for NFS
+    pub source: String,
+    /// Filesystem-specific options, e.g. `rw,vers=4.2,acdirmax=60`
+    pub options: String,
+}
+
+/// Parse the contents of a `mountinfo` file, skipping malformed lines
+pub fn parse_mountinfo(contents: &str) -> Vec<MountEntry> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (fields, tail) = line.split_once(" - ")?;
+            let mut fields = fields.split(' ').skip(2);
+            let mut tail = tail.split(' ');
+            Some(MountEntry {
+                dev: fields.next()?.to_string(),
+                root: unescape(fields.next()?),
+                mount_point: unescape(fields.next()?),
+                mount_options: fields.next()?.to_string(),
+                fs_type: tail.next()?.to_string(),
+                source: unescape(tail.next()?).to_string_lossy().into_owned(),
+                options: tail.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Undo the octal escapes (`\040` for space etc.) used in `mountinfo`
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(digits) = bytes.get(i + 1..i + 4)
+            && let Ok(value) = u8::from_str_radix(std::str::from_utf8(digits).unwrap_or(""), 8)
+        {
+            out.push(value);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+/// The mount in `mounts` that `path` lives on: the deepest mount point, and
+/// of mounts stacked on the same point the last (topmost) one
+pub fn containing_mount<'a>(mounts: &'a [MountEntry], path: &Path) -> Option<&'a MountEntry> {
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.components().count())
+}
+
+/// Translate `path` from the client's mounts to the same file in the
+/// daemon's mounts, if the filesystem is mounted on both sides
+pub fn translate(client: &[MountEntry], host: &[MountEntry], path: &Path) -> Option<PathBuf> {
+    let mount = containing_mount(client, path)?;
+    let rest = path.strip_prefix(&mount.mount_point).ok()?;
+    let in_fs = mount.root.join(rest);
+
+    // Prefer the host mount exposing the deepest part of the filesystem,
+    // then the shortest path to it
+    let host_mount = host
+        .iter()
+        .filter(|m| m.dev == mount.dev && in_fs.starts_with(&m.root))
+        .min_by_key(|m| {
+            (
+                std::cmp::Reverse(m.root.components().count()),
+                m.mount_point.components().count(),
+            )
+        })?;
+    let rest = in_fs.strip_prefix(&host_mount.root).ok()?;
+    Some(join(&host_mount.mount_point, rest))
+}
+
+/// `base.join(rest)` without a trailing slash when `rest` is empty
+fn join(base: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(rest)
+    }
+}
+
+/// Whether `pid` lives in a different mount namespace than the daemon
+fn in_other_namespace(pid: i32) -> bool {
+    let theirs = std::fs::read_link(format!("/proc/{pid}/ns/mnt"));
+    let ours = std::fs::read_link("/proc/self/ns/mnt");
+    matches!((theirs, ours), (Ok(theirs), Ok(ours)) if theirs != ours)
+}
+
+/// Resolve an absolute path as seen by process `pid` to the daemon's view
+///
+/// Returns `None` if `pid` shares the daemon's mount namespace or cannot be
+/// inspected, in which case the path is used as is.
+pub fn resolve(pid: i32, path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || !in_other_namespace(pid) {
+        return None;
+    }
+
+    let client = std::fs::read_to_string(format!("/proc/{pid}/mountinfo")).ok()?;
+    let host = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    if let Some(host_path) = translate(&parse_mountinfo(&client), &parse_mountinfo(&host), path) {
+        return Some(host_path);
+    }
+
+    let rest = path.strip_prefix("/").ok()?;
+    Some(join(Path::new(&format!("/proc/{pid}/root")), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:53 / /mnt/nfs rw,relatime shared:20 - nfs4 nas:/export rw
+41 22 0:53 /media /mnt/media rw,relatime shared:21 - nfs4 nas:/export/media rw
+";
+
+    const CONTAINER: &str = "\
+500 400 0:90 / / rw,relatime - overlay overlay rw
+501 500 0:53 /media /media rw,relatime - nfs4 nas:/export/media rw
+502 500 0:53 /media/tv /tv\\040shows rw,relatime - nfs4 nas:/export/media rw
+503 500 0:91 / /scratch rw - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(CONTAINER);
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(mounts[1].dev, "0:53");
+        assert_eq!(mounts[1].root, PathBuf::from("/media"));
+        assert_eq!(mounts[2].mount_point, PathBuf::from("/tv shows"));
+        assert_eq!(mounts[2].fs_type, "nfs4");
+        assert_eq!(mounts[2].source, "nas:/export/media");
+        assert_eq!(mounts[2].options, "rw");
+        assert_eq!(mounts[2].mount_options, "rw,relatime");
+    }
+
+    #[test]
+    fn test_translate_bind_mounts() {
+        let host = parse_mountinfo(HOST);
+        let container = parse_mountinfo(CONTAINER);
+
+        // The most specific host mount of the filesystem wins
+        assert_eq!(
+            translate(&container, &host, Path::new("/media/movies")),
+            Some(PathBuf::from("/mnt/media/movies"))
+        );
+        assert_eq!(
+            translate(&container, &host, Path::new("/tv shows/s01")),
+            Some(PathBuf::from("/mnt/media/tv/s01"))
+        );
+        assert_eq!(
+            translate(&container, &host, Path::new("/media")),
+            Some(PathBuf::from("/mnt/media"))
+        );
+        // Not mounted on the host side
+        assert_eq!(translate(&container, &host, Path::new("/scratch/x")), None);
+    }
+
+    #[test]
+    fn test_resolve_same_namespace() {
+        let pid = std::process::id() as i32;
+        assert_eq!(resolve(pid, Path::new("/tmp")), None);
+    }
+}
@@ -0,0 +1,281 @@
+//! Machine-readable command output.
+//!
+//! With `--output json`, the commands that talk to a running daemon (and
+//! `bench`) print a single JSON object instead of text. Every object carries `"version"`,
+//! which is bumped when a field is removed or changes meaning; fields may be
+//! added without a bump, so consumers should ignore keys they don't know.
+
+use color_eyre::Result;
+use fakenotify_protocol::{
+    ClientSummary, DegradedMount, EventMask, LoggedError, MountStatus, PathRate, UserLimits,
+    UserUsage, WatchInfo, WatchSummary,
+};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Version of the JSON schema described by the reports below
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A report with the schema version added at the top level
+#[derive(Serialize)]
+struct Versioned<'a, T> {
+    version: u32,
+    #[serde(flatten)]
+    report: &'a T,
+}
+
+/// Render `report` as a versioned JSON object
+pub fn to_json<T: Serialize>(report: &T) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&Versioned {
+        version: SCHEMA_VERSION,
+        report,
+    })?)
+}
+
+/// Print `report` as a versioned JSON object
+pub fn print_json<T: Serialize>(report: &T) -> Result<()> {
+    println!("{}", to_json(report)?);
+    Ok(())
+}
+
+/// Daemon health as seen by `status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    /// Answering, with every mount responsive
+    Ok,
+    /// Answering, but scans under some mounts are paused
+    Degraded,
+    /// Nothing listening on the socket
+    NotRunning,
+    /// Listening, but not answering requests
+    Unreachable,
+}
+
+/// `status`
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub socket: PathBuf,
+    pub status: Health,
+    pub degraded_mounts: Vec<DegradedMount>,
+    /// Details of a daemon that answered, `null` otherwise
+    pub daemon: Option<DaemonStatus>,
+}
+
+/// What `status` reports about a running daemon
+#[derive(Debug, Serialize)]
+pub struct DaemonStatus {
+    pub version: String,
+    pub protocol_version: u32,
+    pub uptime_secs: u64,
+    pub watches: u32,
+    pub clients: u32,
+    /// Events waiting for dispatch
+    pub event_queue: QueueDepth,
+    /// Events queued for all clients together
+    pub queued_events: u64,
+    /// The fullest client queue
+    pub fullest_client_queue: QueueDepth,
+    pub dropped_events: u64,
+    pub stuck_scans: u64,
+    pub mounts: Vec<MountStatus>,
+    pub last_error: Option<LoggedError>,
+}
+
+/// Fill level of a bounded queue
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QueueDepth {
+    pub len: u64,
+    pub capacity: u64,
+}
+
+/// `add`
+#[derive(Debug, Serialize)]
+pub struct AddReport {
+    pub wd: i32,
+    pub path: PathBuf,
+}
+
+/// `remove`
+#[derive(Debug, Serialize)]
+pub struct RemoveReport {
+    pub path: PathBuf,
+    pub removed: bool,
+}
+
+/// `remove --tag`
+#[derive(Debug, Serialize)]
+pub struct RemoveTaggedReport {
+    pub tag: String,
+    pub removed: Vec<PathBuf>,
+}
+
+/// `tag`
+#[derive(Debug, Serialize)]
+pub struct TagReport {
+    pub wd: i32,
+    pub tags: Vec<String>,
+}
+
+/// `list`
+#[derive(Debug, Serialize)]
+pub struct ListReport {
+    pub watches: Vec<WatchSummary>,
+}
+
+/// `list --wd`
+#[derive(Debug, Serialize)]
+pub struct WatchInfoReport {
+    pub watch: WatchInfo,
+}
+
+/// `clients`
+#[derive(Debug, Serialize)]
+pub struct ClientsReport {
+    pub clients: Vec<ClientSummary>,
+}
+
+/// `export`, in the same `[[watch]]` shape as the TOML output
+#[derive(Debug, Serialize)]
+pub struct ExportReport {
+    pub watch: Vec<crate::config::WatchConfig>,
+}
+
+/// A root `import` could not add
+#[derive(Debug, Serialize)]
+pub struct ImportFailure {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// `import`
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub added: Vec<PathBuf>,
+    pub existing: Vec<PathBuf>,
+    pub failed: Vec<ImportFailure>,
+}
+
+/// `prune`
+#[derive(Debug, Serialize)]
+pub struct PruneReport {
+    pub pruned: Vec<PathBuf>,
+}
+
+/// `stats`
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub uptime_secs: u64,
+    pub clients: u32,
+    pub watches: u32,
+    pub stuck_scans: u64,
+    pub dropped_events: u64,
+    /// Roots scanned as part of an enclosing root
+    pub shared_roots: u32,
+    pub scans_saved: u64,
+    /// 0 when rate tracking is off, in which case both rate lists are empty
+    pub window_secs: u32,
+    pub watch_rates: Vec<PathRate>,
+    pub dir_rates: Vec<PathRate>,
+    pub degraded_mounts: Vec<DegradedMount>,
+    /// Per-user limits, for comparison with `users`
+    pub limits: UserLimits,
+    /// Usage per uid, ordered by uid
+    pub users: Vec<UserUsage>,
+}
+
+/// `bench`; latencies run from file creation to delivery to a client
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub clients: usize,
+    pub files_created: u64,
+    /// Creates per second actually achieved
+    pub create_rate: f64,
+    pub poll_interval_ms: u64,
+    pub tree_files: usize,
+    /// `files_created` times `clients`
+    pub events_expected: u64,
+    pub events_received: u64,
+    pub events_dropped: u64,
+    pub overflows: u64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+    pub latency_max_ms: f64,
+}
+
+/// `estimate`; counts and times are extrapolated unless `exact`
+#[derive(Debug, Serialize)]
+pub struct EstimateReport {
+    pub path: PathBuf,
+    pub exact: bool,
+    pub dirs: u64,
+    pub files: u64,
+    pub listed_dirs: u64,
+    pub snapshot_bytes: u64,
+    /// Two snapshots, held while a scan is diffed
+    pub peak_bytes: u64,
+    pub scan_ms: u64,
+    /// With each top-level directory watched as a root of its own
+    pub by_concurrency: Vec<ConcurrentScan>,
+    /// Shortest interval the scan takes at most a quarter of
+    pub poll_interval_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConcurrentScan {
+    pub concurrency: usize,
+    pub scan_ms: u64,
+}
+
+/// `diff`; directories end in `/`
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub path: PathBuf,
+    /// Checkpoint compared against
+    pub snapshot: PathBuf,
+    /// When it was taken, RFC 3339
+    pub since: String,
+    pub created: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+/// `wait`, for the event that ended it
+#[derive(Debug, Serialize)]
+pub struct WaitReport {
+    /// Path given to `wait`
+    pub watched: PathBuf,
+    /// Path the event happened to
+    pub path: PathBuf,
+    /// Name within `watched`, `null` for events on `watched` itself
+    pub name: Option<String>,
+    pub events: EventMask,
+    pub cookie: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_carry_schema_version() {
+        let report = StatusReport {
+            socket: PathBuf::from("/run/fakenotify.sock"),
+            status: Health::NotRunning,
+            degraded_mounts: Vec::new(),
+            daemon: None,
+        };
+        let value: serde_json::Value = serde_json::from_str(&to_json(&report).unwrap()).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "version": SCHEMA_VERSION,
+                "socket": "/run/fakenotify.sock",
+                "status": "not_running",
+                "degraded_mounts": [],
+                "daemon": null,
+            })
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Plugins that filter and rewrite events before they are dispatched.
+//!
+//! Each `[[plugin]]` sees, in order, every event the watchers report and
+//! decides what becomes of it: keep it or drop it, emit events of its own
+//! (a renamed copy, or events derived from it), and route it to `[[hook]]`
+//! commands. Events a plugin emits go on to the plugins after it, then to
+//! clients and media servers like any other. A plugin that fails leaves the
+//! event as it was.
+//!
+//! A plugin is a WASM module (`wasm` feature) or, for simple rules, a Lua
+//! script (`lua` feature); [`wasm`] and [`lua`] describe what each
+//! implements.
+
+use crate::config::PluginConfig;
+use crate::hooks::Hooks;
+use crate::watcher::{WatcherEvent, notify_to_inotify_mask};
+use fakenotify_protocol::EventMask;
+use notify::EventKind;
+use notify::event::{
+    AccessKind, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "lua")]
+pub mod lua;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Events one plugin may emit for a single event; the rest are ignored
+pub const MAX_EMITTED: usize = 64;
+
+/// Memory a plugin may grow to
+#[cfg(any(feature = "lua", feature = "wasm"))]
+pub const MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+/// What a plugin made of an event
+#[derive(Debug, Default)]
+pub struct Decision {
+    /// Leave the event itself out
+    pub drop: bool,
+    /// Events to pass on as well, or instead
+    pub emitted: Vec<Emitted>,
+    /// Hooks to run for the event
+    pub hooks: Vec<String>,
+}
+
+/// An event a plugin emits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Emitted {
+    /// inotify mask, with IN_ISDIR for directories
+    pub mask: EventMask,
+    /// Absolute, or relative to the directory of the event it came from
+    pub path: PathBuf,
+}
+
+/// One loaded plugin
+pub trait EventPlugin: Send {
+    /// What to call the plugin in logs
+    fn name(&self) -> &str;
+
+    /// Decide on an event with `mask` on `path`
+    fn on_event(&mut self, path: &Path, mask: EventMask) -> color_eyre::Result<Decision>;
+}
+
+/// The configured plugins, applied in order
+pub struct Plugins {
+    plugins: Mutex<Vec<Box<dyn EventPlugin>>>,
+    hooks: Hooks,
+}
+
+impl Plugins {
+    pub fn new(plugins: Vec<Box<dyn EventPlugin>>, hooks: Hooks) -> Self {
+        Self {
+            plugins: Mutex::new(plugins),
+            hooks,
+        }
+    }
+
+    /// Run `event`, under roots tagged `tags`, through every plugin;
+    /// returns the events to dispatch
+    pub fn apply(&self, event: WatcherEvent, tags: &[String]) -> Vec<WatcherEvent> {
+        // Overflow markers are the daemon's own business
+        if event.kind == EventKind::Other {
+            return vec![event];
+        }
+
+        let mut events = vec![event];
+        for plugin in self.plugins.lock().iter_mut() {
+            let mut next = Vec::with_capacity(events.len());
+            for event in events {
+                let Some(mask) = notify_to_inotify_mask(&event.kind, event.is_dir) else {
+                    next.push(event);
+                    continue;
+                };
+                let decision = match plugin.on_event(&event.path, mask) {
+                    Ok(decision) => decision,
+                    Err(e) => {
+                        tracing::warn!(
+                            plugin = plugin.name(),
+                            path = %event.path.display(),
+                            error = %e,
+                            "Plugin failed, passing event on"
+                        );
+                        next.push(event);
+                        continue;
+                    }
+                };
+                for hook in &decision.hooks {
+                    self.hooks.run(hook, &event.path, mask, tags);
+                }
+                let derived: Vec<_> = decision
+                    .emitted
+                    .iter()
+                    .take(MAX_EMITTED)
+                    .filter_map(|emitted| derive(&event, emitted))
+                    .collect();
+                if !decision.drop {
+                    next.push(event);
+                }
+                next.extend(derived);
+            }
+            events = next;
+        }
+        events
+    }
+}
+
+/// Load the `[[plugin]]` modules, routing events to `hooks`
+pub fn load(configs: &[PluginConfig], hooks: Hooks) -> color_eyre::Result<Plugins> {
+    let mut plugins = Vec::new();
+    for config in configs {
+        match load_plugin(config)? {
+            Some(plugin) => plugins.push(plugin),
+            None => tracing::warn!(
+                plugin = %config.path.display(),
+                "Plugin ignored: this daemon was built without the {} feature",
+                plugin_feature(&config.path)
+            ),
+        }
+    }
+    Ok(Plugins::new(plugins, hooks))
+}
+
+/// The plugin `config` names, or `None` if this build cannot run it
+fn load_plugin(config: &PluginConfig) -> color_eyre::Result<Option<Box<dyn EventPlugin>>> {
+    match plugin_feature(&config.path) {
+        #[cfg(feature = "lua")]
+        "lua" => Ok(Some(Box::new(lua::LuaPlugin::load(config)?))),
+        #[cfg(feature = "wasm")]
+        "wasm" => Ok(Some(Box::new(wasm::WasmPlugin::load(config)?))),
+        _ => Ok(None),
+    }
+}
+
+/// The feature running the plugin at `path` needs: `lua` for `*.lua`
+/// scripts, `wasm` for anything else
+pub fn plugin_feature(path: &Path) -> &'static str {
+    if path.extension().is_some_and(|ext| ext == "lua") {
+        "lua"
+    } else {
+        "wasm"
+    }
+}
+
+/// Whether this build has `feature`, as named by [`plugin_feature`]
+pub fn supported_plugin(feature: &str) -> bool {
+    (feature == "lua" && cfg!(feature = "lua")) || (feature == "wasm" && cfg!(feature = "wasm"))
+}
+
+/// A path a plugin handed back as bytes
+#[cfg(all(unix, any(feature = "lua", feature = "wasm")))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(all(not(unix), any(feature = "lua", feature = "wasm")))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The event `emitted` for `from`, or `None` for a mask no watcher event
+/// has
+fn derive(from: &WatcherEvent, emitted: &Emitted) -> Option<WatcherEvent> {
+    let is_dir = emitted.mask.contains(EventMask::IN_ISDIR);
+    let mask = emitted.mask;
+    let kind = if mask.contains(EventMask::IN_CREATE) {
+        EventKind::Create(if is_dir {
+            CreateKind::Folder
+        } else {
+            CreateKind::File
+        })
+    } else if mask.contains(EventMask::IN_DELETE) {
+        EventKind::Remove(if is_dir {
+            RemoveKind::Folder
+        } else {
+            RemoveKind::File
+        })
+    } else if mask.contains(EventMask::IN_MOVED_FROM) {
+        EventKind::Modify(ModifyKind::Name(RenameMode::From))
+    } else if mask.contains(EventMask::IN_MOVED_TO) {
+        EventKind::Modify(ModifyKind::Name(RenameMode::To))
+    } else if mask.intersects(EventMask::IN_MODIFY | EventMask::IN_CLOSE_WRITE) {
+        EventKind::Modify(ModifyKind::Data(DataChange::Any))
+    } else if mask.contains(EventMask::IN_ATTRIB) {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))
+    } else if mask.contains(EventMask::IN_ACCESS) {
+        EventKind::Access(AccessKind::Any)
+    } else {
+        tracing::debug!(mask = %mask, "Ignoring emitted event with unsupported mask");
+        return None;
+    };
+
+    let path = match from.path.parent() {
+        Some(dir) if emitted.path.is_relative() => dir.join(&emitted.path),
+        _ => emitted.path.clone(),
+    };
+    Some(WatcherEvent {
+        path,
+        kind,
+        is_dir,
+        unlinked: false,
+        ino: from.ino,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drops `.part` files, announcing them under their final name instead,
+    /// and routes `.mkv` files to a hook
+    struct Partials;
+
+    impl EventPlugin for Partials {
+        fn name(&self) -> &str {
+            "partials"
+        }
+
+        fn on_event(&mut self, path: &Path, mask: EventMask) -> color_eyre::Result<Decision> {
+            let mut decision = Decision::default();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("part") => {
+                    decision.drop = true;
+                    decision.emitted.push(Emitted {
+                        mask,
+                        path: PathBuf::from(path.file_stem().unwrap()),
+                    });
+                }
+                Some("mkv") => decision.hooks.push("missing".into()),
+                Some("fail") => color_eyre::eyre::bail!("no"),
+                _ => {}
+            }
+            Ok(decision)
+        }
+    }
+
+    fn event(path: &str) -> WatcherEvent {
+        WatcherEvent {
+            path: PathBuf::from(path),
+            kind: EventKind::Create(CreateKind::File),
+            is_dir: false,
+            unlinked: false,
+            ino: 9,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugins_rewrite_and_pass_through() {
+        let plugins = Plugins::new(vec![Box::new(Partials)], Hooks::new(&[]));
+
+        let events = plugins.apply(event("/mnt/dl/a.mkv.part"), &[]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path, PathBuf::from("/mnt/dl/a.mkv"));
+        assert_eq!(events[0].kind, EventKind::Create(CreateKind::File));
+        assert_eq!(events[0].ino, 9);
+
+        assert_eq!(plugins.apply(event("/mnt/dl/a.mkv"), &[]).len(), 1);
+        // A failing plugin passes the event on
+        assert_eq!(plugins.apply(event("/mnt/dl/a.fail"), &[]).len(), 1);
+    }
+}
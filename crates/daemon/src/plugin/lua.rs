@@ -0,0 +1,287 @@
+//! Lua rule plugins (`lua` feature).
+//!
+//! For rules too small to be worth a WASM module, a `[[plugin]]` whose path
+//! ends in `.lua` is a Lua 5.4 script defining `on_event`:
+//!
+//! ```lua
+//! function on_event(event)
+//!   if event.name:match("%.part$") then
+//!     return { path = event.name:sub(1, -6), mask = IN.CLOSE_WRITE }
+//!   end
+//!   if event.name:match("%.mkv$") then
+//!     run_hook("thumbnail")
+//!   end
+//! end
+//! ```
+//!
+//! `event` has `path`, `name` (its last component), `mask`, `events` (the
+//! mask's names, e.g. `IN_CREATE|IN_ISDIR`) and `is_dir`. `on_event` returns
+//! nothing or `"keep"` to pass the event on, `false` or `"drop"` to drop it,
+//! or a table with a new `path` and/or `mask` to replace it; a relative
+//! `path` is taken relative to the event's directory. The `IN` table holds
+//! the mask bits by name.
+//!
+//! Scripts get Lua's string, table, math and utf8 libraries, `log(message)`
+//! and `run_hook(name)`, which runs the `[[hook]]` of that name for the
+//! event; nothing that reaches files, processes or the network. Each call
+//! must finish within the plugin's `budget` and [`MAX_MEMORY`], or it is
+//! cut off and the event passed on unchanged.
+
+use super::{Decision, Emitted, EventPlugin, MAX_EMITTED, MAX_MEMORY, path_from_bytes};
+use crate::config::PluginConfig;
+use color_eyre::eyre::{bail, eyre};
+use fakenotify_protocol::EventMask;
+use mlua::{Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// VM instructions between checks of the time budget
+const CHECK_EVERY: u32 = 1000;
+
+/// Base library functions scripts do without: file access, and catching
+/// the error that enforces the time budget
+const REMOVED_GLOBALS: &[&str] = &["dofile", "loadfile", "pcall", "xpcall"];
+
+/// State of the current `on_event` call
+struct Call {
+    deadline: Instant,
+    hooks: Vec<String>,
+}
+
+/// A loaded Lua script
+pub struct LuaPlugin {
+    name: String,
+    budget: Duration,
+    lua: Lua,
+    on_event: RegistryKey,
+}
+
+impl LuaPlugin {
+    /// Run the script `config` names and find its `on_event`
+    pub fn load(config: &PluginConfig) -> color_eyre::Result<Self> {
+        let name = config.path.display().to_string();
+        let source =
+            std::fs::read(&config.path).map_err(|e| eyre!("failed to read plugin {name}: {e}"))?;
+
+        let lua = Lua::new_with(
+            StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8,
+            LuaOptions::default(),
+        )?;
+        lua.set_memory_limit(MAX_MEMORY)?;
+        lua.set_app_data(Call {
+            deadline: Instant::now() + config.budget,
+            hooks: Vec::new(),
+        });
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(CHECK_EVERY),
+            |lua, _| match lua.app_data_ref::<Call>() {
+                Some(call) if Instant::now() > call.deadline => {
+                    Err(mlua::Error::runtime("time budget exceeded"))
+                }
+                _ => Ok(()),
+            },
+        );
+        install_api(&lua, &name)?;
+
+        // The script's top level gets one call's worth of time
+        lua.load(source.as_slice())
+            .set_name(name.as_str())
+            .exec()
+            .map_err(|e| eyre!("failed to run plugin {name}: {e}"))?;
+        let on_event: Function = lua
+            .globals()
+            .get("on_event")
+            .map_err(|_| eyre!("plugin {name} defines no on_event function"))?;
+        let on_event = lua.create_registry_value(on_event)?;
+
+        Ok(Self {
+            name,
+            budget: config.budget,
+            lua,
+            on_event,
+        })
+    }
+
+    /// The table `on_event` gets for an event with `mask` on `path`
+    fn event_table(&self, path: &Path, mask: EventMask) -> mlua::Result<Table<'_>> {
+        let event = self.lua.create_table()?;
+        event.set(
+            "path",
+            self.lua
+                .create_string(path.as_os_str().as_encoded_bytes())?,
+        )?;
+        let name = path
+            .file_name()
+            .map_or_else(Vec::new, |name| name.as_encoded_bytes().to_vec());
+        event.set("name", self.lua.create_string(name)?)?;
+        event.set("mask", mask.bits())?;
+        event.set("events", mask.to_string())?;
+        event.set("is_dir", mask.contains(EventMask::IN_ISDIR))?;
+        Ok(event)
+    }
+}
+
+impl EventPlugin for LuaPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_event(&mut self, path: &Path, mask: EventMask) -> color_eyre::Result<Decision> {
+        if let Some(mut call) = self.lua.app_data_mut::<Call>() {
+            call.deadline = Instant::now() + self.budget;
+            call.hooks.clear();
+        }
+        let on_event: Function = self.lua.registry_value(&self.on_event)?;
+        let verdict: Value = on_event.call(self.event_table(path, mask)?)?;
+
+        let mut decision = Decision {
+            hooks: self
+                .lua
+                .app_data_mut::<Call>()
+                .map(|mut call| std::mem::take(&mut call.hooks))
+                .unwrap_or_default(),
+            ..Decision::default()
+        };
+        match verdict {
+            Value::Nil | Value::Boolean(true) => {}
+            Value::Boolean(false) => decision.drop = true,
+            Value::String(verdict) => match verdict.to_str()? {
+                "keep" => {}
+                "drop" => decision.drop = true,
+                other => bail!("on_event returned unknown verdict {other:?}"),
+            },
+            Value::Table(modified) => {
+                let new_path: Option<mlua::String> = modified.get("path")?;
+                let new_mask: Option<u32> = modified.get("mask")?;
+                decision.drop = true;
+                decision.emitted.push(Emitted {
+                    mask: new_mask.map_or(mask, EventMask::from_bits_retain),
+                    path: new_path.map_or_else(
+                        || path.to_path_buf(),
+                        |new_path| path_from_bytes(new_path.as_bytes()),
+                    ),
+                });
+            }
+            other => bail!("on_event returned a {}", other.type_name()),
+        }
+        Ok(decision)
+    }
+}
+
+/// Give scripts the `IN` table, `log` and `run_hook`, and take away what
+/// they should not have
+fn install_api(lua: &Lua, name: &str) -> mlua::Result<()> {
+    let globals = lua.globals();
+    for removed in REMOVED_GLOBALS {
+        globals.set(*removed, Value::Nil)?;
+    }
+
+    let masks = lua.create_table()?;
+    for (flag, bits) in EventMask::all().iter_names() {
+        masks.set(flag.trim_start_matches("IN_"), bits.bits())?;
+    }
+    globals.set("IN", masks)?;
+
+    let plugin = name.to_string();
+    let log = lua.create_function(move |_, message: String| {
+        tracing::info!(plugin = %plugin, "{message}");
+        Ok(())
+    })?;
+    globals.set("log", log)?;
+
+    let run_hook = lua.create_function(|lua, hook: String| {
+        if let Some(mut call) = lua.app_data_mut::<Call>()
+            && call.hooks.len() < MAX_EMITTED
+        {
+            call.hooks.push(hook);
+        }
+        Ok(())
+    })?;
+    globals.set("run_hook", run_hook)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn load(name: &str, script: &str) -> LuaPlugin {
+        let path = std::env::temp_dir().join(format!(
+            "fakenotify-plugin-{name}-{}.lua",
+            std::process::id()
+        ));
+        std::fs::write(&path, script).unwrap();
+        let plugin = LuaPlugin::load(&PluginConfig {
+            path: path.clone(),
+            fuel: 0,
+            budget: Duration::from_millis(50),
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        plugin
+    }
+
+    #[test]
+    fn test_rules_keep_drop_and_modify() {
+        let mut plugin = load(
+            "rules",
+            r#"
+            function on_event(event)
+              if event.name:match("%.tmp$") then
+                return "drop"
+              end
+              if event.name:match("%.part$") then
+                return { path = event.name:sub(1, -6), mask = IN.CLOSE_WRITE }
+              end
+              if event.events:find("IN_CREATE") then
+                run_hook("index")
+              end
+            end
+            "#,
+        );
+
+        let create = EventMask::IN_CREATE;
+        assert!(
+            plugin
+                .on_event(Path::new("/mnt/dl/x.tmp"), create)
+                .unwrap()
+                .drop
+        );
+
+        let decision = plugin
+            .on_event(Path::new("/mnt/dl/a.mkv.part"), create)
+            .unwrap();
+        assert!(decision.drop);
+        assert_eq!(
+            decision.emitted,
+            vec![Emitted {
+                mask: EventMask::IN_CLOSE_WRITE,
+                path: PathBuf::from("a.mkv"),
+            }]
+        );
+
+        let decision = plugin.on_event(Path::new("/mnt/dl/a.mkv"), create).unwrap();
+        assert!(!decision.drop);
+        assert_eq!(decision.hooks, vec!["index".to_string()]);
+    }
+
+    #[test]
+    fn test_script_is_sandboxed_and_timed() {
+        let mut plugin = load(
+            "spin",
+            r#"
+            assert(io == nil and os == nil and require == nil and pcall == nil)
+            function on_event(event)
+              while true do end
+            end
+            "#,
+        );
+        let started = Instant::now();
+        let err = plugin
+            .on_event(Path::new("/mnt/a"), EventMask::IN_CREATE)
+            .unwrap_err();
+        assert!(err.to_string().contains("time budget"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,286 @@
+//! WASM event plugins (`wasm` feature).
+//!
+//! A plugin is a core WebAssembly module, in binary or text format, that
+//! exports
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, returning `len` bytes the host may write the
+//!   event's path to; the buffer is the plugin's to free or reuse
+//! - `on_event(mask: i32, path: i32, path_len: i32) -> i32`, called for each
+//!   event with its inotify mask and absolute path, returning 0 to keep the
+//!   event and anything else to drop it
+//!
+//! and may import, from module `fakenotify`,
+//!
+//! - `emit(mask: i32, path: i32, path_len: i32)` to emit an event; a path
+//!   that is not absolute is relative to the directory of the current event
+//! - `route(hook: i32, hook_len: i32)` to run the `[[hook]]` of that name
+//! - `log(level: i32, message: i32, message_len: i32)`, level 0 (error) to
+//!   3 (debug)
+//!
+//! Nothing else is importable: a plugin has no files, clock or network.
+//! Each call may spend the plugin's `fuel` and at most [`MAX_MEMORY`]
+//! bytes of memory; a plugin that runs out is cut off and the event passed
+//! on unchanged.
+
+use super::{Decision, Emitted, EventPlugin, MAX_EMITTED, MAX_MEMORY, path_from_bytes};
+use crate::config::PluginConfig;
+use color_eyre::eyre::eyre;
+use fakenotify_protocol::EventMask;
+use std::path::Path;
+use wasmtime::{
+    Caller, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+/// Module the host functions are imported from
+const HOST_MODULE: &str = "fakenotify";
+
+/// What the host keeps for a plugin between its calls
+struct Host {
+    decision: Decision,
+    limits: StoreLimits,
+}
+
+/// A loaded WASM plugin
+pub struct WasmPlugin {
+    name: String,
+    fuel: u64,
+    store: Store<Host>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_event: TypedFunc<(i32, i32, i32), i32>,
+}
+
+/// An engine that meters fuel
+fn engine() -> color_eyre::Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).map_err(report)
+}
+
+impl WasmPlugin {
+    /// Compile and instantiate the module `config` names
+    pub fn load(config: &PluginConfig) -> color_eyre::Result<Self> {
+        let name = config.path.display().to_string();
+        let engine = &engine()?;
+        let module = Module::from_file(engine, &config.path)
+            .map_err(|e| eyre!("failed to load plugin {name}: {e:#}"))?;
+
+        let mut linker = Linker::new(engine);
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "emit",
+                |mut caller: Caller<'_, Host>, mask: i32, ptr: i32, len: i32| {
+                    let path = path_from_bytes(&guest_bytes(&mut caller, ptr, len)?);
+                    let emitted = &mut caller.data_mut().decision.emitted;
+                    if emitted.len() < MAX_EMITTED {
+                        emitted.push(Emitted {
+                            mask: EventMask::from_bits_retain(mask as u32),
+                            path,
+                        });
+                    }
+                    Ok(())
+                },
+            )
+            .map_err(report)?;
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "route",
+                |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                    let hook =
+                        String::from_utf8_lossy(&guest_bytes(&mut caller, ptr, len)?).into_owned();
+                    let hooks = &mut caller.data_mut().decision.hooks;
+                    if hooks.len() < MAX_EMITTED {
+                        hooks.push(hook);
+                    }
+                    Ok(())
+                },
+            )
+            .map_err(report)?;
+        let plugin = name.clone();
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "log",
+                move |mut caller: Caller<'_, Host>, level: i32, ptr: i32, len: i32| {
+                    let bytes = guest_bytes(&mut caller, ptr, len)?;
+                    let message = String::from_utf8_lossy(&bytes);
+                    match level {
+                        0 => tracing::error!(plugin = %plugin, "{message}"),
+                        1 => tracing::warn!(plugin = %plugin, "{message}"),
+                        2 => tracing::info!(plugin = %plugin, "{message}"),
+                        _ => tracing::debug!(plugin = %plugin, "{message}"),
+                    }
+                    Ok(())
+                },
+            )
+            .map_err(report)?;
+
+        let host = Host {
+            decision: Decision::default(),
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        };
+        let mut store = Store::new(engine, host);
+        store.limiter(|host| &mut host.limits);
+        // The start function, if any, gets one call's worth
+        store.set_fuel(config.fuel).map_err(report)?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .map_err(|e| eyre!("failed to start plugin {name}: {e:#}"))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| eyre!("plugin {name} exports no memory"))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "alloc")
+            .map_err(|e| eyre!("plugin {name}: {e:#}"))?;
+        let on_event = instance
+            .get_typed_func(&mut store, "on_event")
+            .map_err(|e| eyre!("plugin {name}: {e:#}"))?;
+
+        Ok(Self {
+            name,
+            fuel: config.fuel,
+            store,
+            memory,
+            alloc,
+            on_event,
+        })
+    }
+}
+
+impl EventPlugin for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_event(&mut self, path: &Path, mask: EventMask) -> color_eyre::Result<Decision> {
+        let bytes = path.as_os_str().as_encoded_bytes();
+        let len = i32::try_from(bytes.len())?;
+        self.store.data_mut().decision = Decision::default();
+        self.store.set_fuel(self.fuel).map_err(report)?;
+
+        let ptr = self.alloc.call(&mut self.store, len).map_err(report)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, bytes)?;
+        let verdict = self
+            .on_event
+            .call(&mut self.store, (mask.bits() as i32, ptr, len))
+            .map_err(report)?;
+
+        let mut decision = std::mem::take(&mut self.store.data_mut().decision);
+        decision.drop = verdict != 0;
+        Ok(decision)
+    }
+}
+
+/// `len` bytes of the calling plugin's memory at `ptr`
+fn guest_bytes(caller: &mut Caller<'_, Host>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| wasmtime::Error::msg("plugin exports no memory"))?;
+    let start = ptr as u32 as usize;
+    let end = start + len as u32 as usize;
+    memory
+        .data(&caller)
+        .get(start..end)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmtime::Error::msg(format!("buffer at {start:#x} is out of bounds")))
+}
+
+/// A wasmtime error as a report, with its causes
+fn report(e: wasmtime::Error) -> color_eyre::Report {
+    eyre!("{e:#}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Drops events on names ending in `.part` and emits IN_CLOSE_WRITE
+    /// for the name without it; routes everything else to hook `index`.
+    /// The path buffer is always at 1024.
+    const PARTIALS: &str = r#"
+        (module
+          (import "fakenotify" "emit" (func $emit (param i32 i32 i32)))
+          (import "fakenotify" "route" (func $route (param i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "index")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "on_event") (param $mask i32) (param $ptr i32) (param $len i32) (result i32)
+            (local $end i32)
+            (local.set $end (i32.add (local.get $ptr) (local.get $len)))
+            (if (i32.and
+                  (i32.ge_u (local.get $len) (i32.const 5))
+                  (i32.eq (i32.load8_u (i32.sub (local.get $end) (i32.const 5))) (i32.const 46)))
+              (then
+                (call $emit (i32.const 8) (local.get $ptr) (i32.sub (local.get $len) (i32.const 5)))
+                (return (i32.const 1))))
+            (call $route (i32.const 0) (i32.const 5))
+            (i32.const 0)))
+    "#;
+
+    const SPIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "on_event") (param i32 i32 i32) (result i32)
+            (loop $l (br $l))
+            (i32.const 0)))
+    "#;
+
+    fn load(name: &str, wat: &str) -> WasmPlugin {
+        let path = std::env::temp_dir().join(format!(
+            "fakenotify-plugin-{name}-{}.wat",
+            std::process::id()
+        ));
+        std::fs::write(&path, wat).unwrap();
+        let plugin = WasmPlugin::load(&PluginConfig {
+            path: path.clone(),
+            fuel: 100_000,
+            budget: Duration::ZERO,
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        plugin
+    }
+
+    #[test]
+    fn test_plugin_rewrites_and_routes() {
+        let mut plugin = load("partials", PARTIALS);
+
+        let decision = plugin
+            .on_event(Path::new("/mnt/dl/a.mkv.part"), EventMask::IN_CREATE)
+            .unwrap();
+        assert!(decision.drop);
+        assert_eq!(
+            decision.emitted,
+            vec![Emitted {
+                mask: EventMask::IN_CLOSE_WRITE,
+                path: PathBuf::from("/mnt/dl/a.mkv"),
+            }]
+        );
+        assert!(decision.hooks.is_empty());
+
+        let decision = plugin
+            .on_event(Path::new("/mnt/dl/a.mkv"), EventMask::IN_CREATE)
+            .unwrap();
+        assert!(!decision.drop);
+        assert!(decision.emitted.is_empty());
+        assert_eq!(decision.hooks, vec!["index".to_string()]);
+    }
+
+    #[test]
+    fn test_runaway_plugin_is_cut_off() {
+        let mut plugin = load("spin", SPIN);
+        let err = plugin
+            .on_event(Path::new("/mnt/a"), EventMask::IN_CREATE)
+            .unwrap_err();
+        assert!(err.to_string().contains("fuel"), "{err}");
+    }
+}
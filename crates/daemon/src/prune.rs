@@ -0,0 +1,73 @@
+//! Removal of watches whose paths no longer exist.
+//!
+//! A watch on a deleted directory never fires again but still costs a slot
+//! in the client's limits. Only `NotFound` counts as missing: an NFS server
+//! that is down answers with `EIO` or `ESTALE`, and the grace period covers
+//! mounts that briefly come back empty.
+
+use crate::state::{DaemonState, WatchDescriptor};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Watches whose path is currently not found
+///
+/// Stats run on the blocking pool, since a hung NFS mount blocks them.
+pub async fn missing_watches(state: &DaemonState) -> HashSet<WatchDescriptor> {
+    let paths = state.watch_paths();
+    tokio::task::spawn_blocking(move || {
+        paths
+            .into_iter()
+            .filter(|(_, path)| {
+                matches!(std::fs::symlink_metadata(path), Err(e) if e.kind() == ErrorKind::NotFound)
+            })
+            .map(|(wd, _)| wd)
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Check watches every `interval` and remove those missing for `grace`
+pub fn spawn(state: Arc<DaemonState>, interval: Duration, grace: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            let missing = missing_watches(&state).await;
+            state.prune_missing(&missing, grace);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Peer;
+    use fakenotify_protocol::EventMask;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn test_missing_watches() {
+        let state = DaemonState::new();
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let present = state
+            .add_watch(client.id, std::env::temp_dir(), EventMask::IN_CREATE, false)
+            .unwrap();
+        let gone = PathBuf::from("/nonexistent/fakenotify/prune");
+        let missing = state
+            .add_watch(client.id, gone, EventMask::IN_CREATE, false)
+            .unwrap();
+
+        let found = missing_watches(&state).await;
+        assert!(found.contains(&missing));
+        assert!(!found.contains(&present));
+    }
+}
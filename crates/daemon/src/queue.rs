@@ -0,0 +1,320 @@
+//! Per-client event queues and what happens when one fills up.
+//!
+//! Events wait here for a slow client's socket, or for a disconnected
+//! client to resume its session. A queue is capped by event count, and
+//! optionally by bytes and by the age of its oldest event; `queue_overflow`
+//! decides what a full queue does, globally or per `[[client_class]]`.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// What to do when a client's event queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflow {
+    /// Drop new events, reporting IN_Q_OVERFLOW after the queued ones, as
+    /// the kernel does
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued events to make room, reporting IN_Q_OVERFLOW
+    /// in their place
+    DropOldest,
+    /// Forget the client and its watches; its session cannot be resumed
+    DropSession,
+    /// Discard the queue and close the connection; the session can still be
+    /// resumed, starting with IN_Q_OVERFLOW
+    Disconnect,
+}
+
+/// How much a queue holds and what happens past that
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueuePolicy {
+    /// Events held
+    pub max_events: usize,
+    /// Bytes of framed events held (0 no cap)
+    pub max_bytes: usize,
+    /// Age of the oldest event held, checked as events arrive (zero no cap)
+    pub max_age: Duration,
+    pub overflow: QueueOverflow,
+}
+
+/// Outcome of [`EventQueue::push`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// Events were lost: this one, or with `DropOldest` older ones
+    Overflowed {
+        queued: bool,
+        dropped: usize,
+    },
+    /// The queue was full and its policy gives up on the client; the
+    /// `dropped` queued events were discarded
+    GaveUp {
+        action: QueueOverflow,
+        dropped: usize,
+    },
+    /// The client is gone
+    Closed,
+}
+
+struct Inner {
+    events: VecDeque<(Instant, Bytes)>,
+    bytes: usize,
+    policy: QueuePolicy,
+    closed: bool,
+}
+
+impl Inner {
+    fn fits(&self, len: usize) -> bool {
+        // An event bigger than the byte cap still gets through on its own
+        self.events.len() < self.policy.max_events.max(1)
+            && (self.policy.max_bytes == 0
+                || self.events.is_empty()
+                || self.bytes + len <= self.policy.max_bytes)
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        !self.policy.max_age.is_zero()
+            && self
+                .events
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > self.policy.max_age)
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        let (_, framed) = self.events.pop_front()?;
+        self.bytes -= framed.len();
+        Some(framed)
+    }
+
+    fn clear(&mut self) -> usize {
+        let dropped = self.events.len();
+        self.events.clear();
+        self.bytes = 0;
+        dropped
+    }
+}
+
+/// Framed events waiting to be written to one client
+pub struct EventQueue {
+    inner: Mutex<Inner>,
+    ready: Notify,
+}
+
+impl EventQueue {
+    /// An empty queue and the receiving end its writer drains
+    pub fn new(policy: QueuePolicy) -> (Arc<Self>, QueueReceiver) {
+        let queue = Arc::new(Self {
+            inner: Mutex::new(Inner {
+                events: VecDeque::new(),
+                bytes: 0,
+                policy,
+                closed: false,
+            }),
+            ready: Notify::new(),
+        });
+        let rx = QueueReceiver {
+            queue: Arc::clone(&queue),
+        };
+        (queue, rx)
+    }
+
+    /// Replace the caps, which apply from the next event on
+    pub fn set_policy(&self, policy: QueuePolicy) {
+        self.inner.lock().policy = policy;
+    }
+
+    /// Queue a framed event, applying the policy if the queue is full
+    pub fn push(&self, framed: Bytes) -> Pushed {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Pushed::Closed;
+        }
+        let full = inner.is_stale(now) || !inner.fits(framed.len());
+        let mut dropped = 0;
+        if full {
+            match inner.policy.overflow {
+                action @ (QueueOverflow::DropSession | QueueOverflow::Disconnect) => {
+                    return Pushed::GaveUp {
+                        action,
+                        dropped: inner.clear(),
+                    };
+                }
+                overflow => {
+                    while inner.is_stale(now)
+                        || (overflow == QueueOverflow::DropOldest && !inner.fits(framed.len()))
+                    {
+                        inner.pop();
+                        dropped += 1;
+                    }
+                    if !inner.fits(framed.len()) {
+                        return Pushed::Overflowed {
+                            queued: false,
+                            dropped,
+                        };
+                    }
+                }
+            }
+        }
+        inner.bytes += framed.len();
+        inner.events.push_back((now, framed));
+        self.ready.notify_one();
+        if full {
+            Pushed::Overflowed {
+                queued: true,
+                dropped,
+            }
+        } else {
+            Pushed::Queued
+        }
+    }
+
+    /// Wake the writer to find the queue closed
+    pub fn close(&self) {
+        self.inner.lock().closed = true;
+        self.ready.notify_one();
+    }
+}
+
+/// The writer's end of an [`EventQueue`]
+pub struct QueueReceiver {
+    queue: Arc<EventQueue>,
+}
+
+impl QueueReceiver {
+    /// Wait for events and move up to `limit` of them into `frames`;
+    /// returns 0 once the queue is closed and empty
+    pub async fn recv_many(&mut self, frames: &mut Vec<Bytes>, limit: usize) -> usize {
+        loop {
+            let ready = self.queue.ready.notified();
+            {
+                let mut inner = self.queue.inner.lock();
+                if !inner.events.is_empty() {
+                    let count = inner.events.len().min(limit);
+                    frames.extend((0..count).filter_map(|_| inner.pop()));
+                    return count;
+                }
+                if inner.closed {
+                    return 0;
+                }
+            }
+            ready.await;
+        }
+    }
+
+    /// The next event, if one is queued
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Option<Bytes> {
+        self.queue.inner.lock().pop()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.inner.lock().events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_events: usize, overflow: QueueOverflow) -> QueuePolicy {
+        QueuePolicy {
+            max_events,
+            max_bytes: 0,
+            max_age: Duration::ZERO,
+            overflow,
+        }
+    }
+
+    fn event(byte: u8) -> Bytes {
+        Bytes::from(vec![byte; 4])
+    }
+
+    #[test]
+    fn test_drop_oldest_keeps_newest() {
+        let (queue, mut rx) = EventQueue::new(policy(2, QueueOverflow::DropOldest));
+        assert_eq!(queue.push(event(1)), Pushed::Queued);
+        assert_eq!(queue.push(event(2)), Pushed::Queued);
+        assert_eq!(
+            queue.push(event(3)),
+            Pushed::Overflowed {
+                queued: true,
+                dropped: 1
+            }
+        );
+        assert_eq!(rx.try_recv(), Some(event(2)));
+        assert_eq!(rx.try_recv(), Some(event(3)));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_byte_cap() {
+        let (queue, mut rx) = EventQueue::new(QueuePolicy {
+            max_bytes: 10,
+            ..policy(8, QueueOverflow::DropNewest)
+        });
+        assert_eq!(queue.push(event(1)), Pushed::Queued);
+        assert_eq!(queue.push(event(2)), Pushed::Queued);
+        assert_eq!(
+            queue.push(event(3)),
+            Pushed::Overflowed {
+                queued: false,
+                dropped: 0
+            }
+        );
+
+        // One event over the cap still fits an empty queue
+        while rx.try_recv().is_some() {}
+        assert_eq!(queue.push(Bytes::from(vec![0; 64])), Pushed::Queued);
+    }
+
+    #[test]
+    fn test_stale_events_expire() {
+        let (queue, mut rx) = EventQueue::new(QueuePolicy {
+            max_age: Duration::from_millis(1),
+            ..policy(8, QueueOverflow::DropNewest)
+        });
+        queue.push(event(1));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            queue.push(event(2)),
+            Pushed::Overflowed {
+                queued: true,
+                dropped: 1
+            }
+        );
+        assert_eq!(rx.try_recv(), Some(event(2)));
+    }
+
+    #[test]
+    fn test_give_up_discards_queue() {
+        let (queue, mut rx) = EventQueue::new(policy(1, QueueOverflow::Disconnect));
+        queue.push(event(1));
+        assert_eq!(
+            queue.push(event(2)),
+            Pushed::GaveUp {
+                action: QueueOverflow::Disconnect,
+                dropped: 1
+            }
+        );
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[tokio::test]
+    async fn test_closed_queue_ends_receiver() {
+        let (queue, mut rx) = EventQueue::new(policy(8, QueueOverflow::DropNewest));
+        queue.push(event(1));
+        queue.close();
+        assert_eq!(queue.push(event(2)), Pushed::Closed);
+
+        let mut frames = Vec::new();
+        assert_eq!(rx.recv_many(&mut frames, 8).await, 1);
+        assert_eq!(rx.recv_many(&mut frames, 8).await, 0);
+    }
+}
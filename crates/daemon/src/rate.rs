@@ -0,0 +1,222 @@
+//! Rolling event rates per watch and per directory.
+//!
+//! Counts are kept in one-second buckets over the last [`RATE_WINDOW_SECS`]
+//! seconds, so a burst shows up immediately and fades out once it stops.
+//! Directories that exceed the configured threshold are reported as noisy
+//! and, if configured, excluded from dispatch until they calm down.
+
+use crate::state::WatchDescriptor;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Length of the window rates are averaged over
+pub const RATE_WINDOW_SECS: u64 = 10;
+
+/// What to do about a directory above `noisy_threshold`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoisyAction {
+    /// Log a warning with a suggested rule
+    #[default]
+    Warn,
+    /// Also stop dispatching its events while it stays above the threshold
+    Exclude,
+}
+
+/// When a directory counts as noisy and what happens then
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoisyPolicy {
+    /// Events per second (0 disables detection)
+    pub threshold: u64,
+    pub action: NoisyAction,
+}
+
+/// Events per second-bucket over the window
+#[derive(Debug, Clone, Default)]
+struct Counter {
+    buckets: [u64; RATE_WINDOW_SECS as usize],
+    /// Second of the most recent bucket
+    last: u64,
+}
+
+impl Counter {
+    fn add(&mut self, now: u64) {
+        self.advance(now);
+        self.buckets[(now % RATE_WINDOW_SECS) as usize] += 1;
+    }
+
+    /// Clear buckets that fell out of the window since `last`
+    fn advance(&mut self, now: u64) {
+        if now.saturating_sub(self.last) >= RATE_WINDOW_SECS {
+            self.buckets = [0; RATE_WINDOW_SECS as usize];
+        } else {
+            for second in self.last + 1..=now {
+                self.buckets[(second % RATE_WINDOW_SECS) as usize] = 0;
+            }
+        }
+        self.last = self.last.max(now);
+    }
+
+    /// Events within the window ending at `now`
+    fn total(&self, now: u64) -> u64 {
+        if now.saturating_sub(self.last) >= RATE_WINDOW_SECS {
+            return 0;
+        }
+        // Buckets for seconds after `last` have not been cleared yet
+        (now.saturating_sub(RATE_WINDOW_SECS - 1)..=self.last)
+            .map(|second| self.buckets[(second % RATE_WINDOW_SECS) as usize])
+            .sum()
+    }
+}
+
+/// Rolling rates for every watch and directory seen recently
+#[derive(Debug)]
+pub struct RateTracker {
+    started: Instant,
+    policy: NoisyPolicy,
+    watches: HashMap<WatchDescriptor, Counter>,
+    dirs: HashMap<PathBuf, Counter>,
+    /// Directories currently above the threshold
+    noisy: HashSet<PathBuf>,
+    /// Second of the last sweep of idle counters
+    swept: u64,
+}
+
+impl RateTracker {
+    pub fn new(policy: NoisyPolicy) -> Self {
+        Self {
+            started: Instant::now(),
+            policy,
+            watches: HashMap::new(),
+            dirs: HashMap::new(),
+            noisy: HashSet::new(),
+            swept: 0,
+        }
+    }
+
+    /// Count an event for `path` under watch `wd`
+    ///
+    /// Returns false if the event should be dropped because its directory
+    /// is noisy and the policy excludes noisy directories.
+    pub fn record(&mut self, wd: WatchDescriptor, path: &Path) -> bool {
+        let now = self.started.elapsed().as_secs();
+        self.record_at(wd, path, now)
+    }
+
+    fn record_at(&mut self, wd: WatchDescriptor, path: &Path, now: u64) -> bool {
+        if now != self.swept {
+            self.sweep(now);
+        }
+        self.watches.entry(wd).or_default().add(now);
+
+        let dir = path.parent().unwrap_or(path);
+        let counter = self.dirs.entry(dir.to_path_buf()).or_default();
+        counter.add(now);
+        if self.policy.threshold == 0 {
+            return true;
+        }
+
+        let rate = counter.total(now) / RATE_WINDOW_SECS;
+        if rate < self.policy.threshold {
+            if self.noisy.remove(dir) {
+                tracing::info!(path = %dir.display(), rate, "Directory is no longer noisy");
+            }
+            return true;
+        }
+        if self.noisy.insert(dir.to_path_buf()) {
+            tracing::warn!(
+                path = %dir.display(),
+                rate,
+                threshold = self.policy.threshold,
+                excluded = self.policy.action == NoisyAction::Exclude,
+                "Noisy directory; consider excluding it with `name_regex` or `extensions` \
+                 on its watch, or a longer `poll_interval`"
+            );
+        }
+        self.policy.action != NoisyAction::Exclude
+    }
+
+    /// Drop counters that saw nothing during the window
+    fn sweep(&mut self, now: u64) {
+        self.swept = now;
+        self.watches.retain(|_, counter| counter.total(now) > 0);
+        self.dirs.retain(|_, counter| counter.total(now) > 0);
+        let dirs = &self.dirs;
+        self.noisy.retain(|dir| dirs.contains_key(dir));
+    }
+
+    /// The `n` busiest watches with their event counts over the window
+    pub fn top_watches(&self, n: usize) -> Vec<(WatchDescriptor, u64)> {
+        let now = self.started.elapsed().as_secs();
+        top(self.watches.iter().map(|(wd, c)| (*wd, c.total(now))), n)
+    }
+
+    /// The `n` busiest directories with their event counts over the window
+    pub fn top_dirs(&self, n: usize) -> Vec<(PathBuf, u64)> {
+        let now = self.started.elapsed().as_secs();
+        top(
+            self.dirs.iter().map(|(dir, c)| (dir.clone(), c.total(now))),
+            n,
+        )
+    }
+
+    /// Whether `dir` is currently above the threshold
+    pub fn is_noisy(&self, dir: &Path) -> bool {
+        self.noisy.contains(dir)
+    }
+}
+
+/// The `n` entries with the highest non-zero counts, busiest first
+fn top<K: Ord>(counts: impl Iterator<Item = (K, u64)>, n: usize) -> Vec<(K, u64)> {
+    let mut counts: Vec<_> = counts.filter(|(_, count)| *count > 0).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(n);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_window() {
+        let mut counter = Counter::default();
+        for now in 0..5 {
+            counter.add(now);
+            counter.add(now);
+        }
+        assert_eq!(counter.total(4), 10);
+        assert_eq!(counter.total(10), 8);
+        assert_eq!(counter.total(14), 0);
+
+        counter.add(20);
+        assert_eq!(counter.total(20), 1);
+    }
+
+    #[test]
+    fn test_top_dirs_and_noisy_exclusion() {
+        let mut tracker = RateTracker::new(NoisyPolicy {
+            threshold: 2,
+            action: NoisyAction::Exclude,
+        });
+        let scratch = Path::new("/mnt/media/scratch/tmp1");
+        let show = Path::new("/mnt/media/show/ep1.mkv");
+
+        for _ in 0..19 {
+            assert!(tracker.record_at(1, scratch, 0));
+        }
+        assert!(!tracker.record_at(1, scratch, 0));
+        assert!(tracker.record_at(1, show, 0));
+        assert!(tracker.is_noisy(Path::new("/mnt/media/scratch")));
+
+        assert_eq!(tracker.top_watches(5), vec![(1, 21)]);
+        let dirs = tracker.top_dirs(1);
+        assert_eq!(dirs, vec![(PathBuf::from("/mnt/media/scratch"), 20)]);
+
+        // Quiet for a full window
+        assert!(tracker.record_at(1, scratch, RATE_WINDOW_SECS + 1));
+        assert!(!tracker.is_noisy(Path::new("/mnt/media/scratch")));
+    }
+}
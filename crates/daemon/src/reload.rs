@@ -0,0 +1,287 @@
+//! Applying edits to the configuration while running.
+//!
+//! On SIGHUP and, unless `watch_config` is off, whenever the config file or
+//! a fragment changes, the configuration is read again and the `[[watch]]`
+//! entries added, removed or changed since the last load are applied to the
+//! scanner. The files are watched with the OS notification API, as they sit
+//! on a local disk. A configuration that does not load or validate is
+//! logged and left unapplied; other settings still take a restart.
+
+use crate::config::{Config, WatchConfig, find_config_file, fragment_dir, fragments};
+use crate::state::DaemonState;
+use crate::validate::{self, Severity};
+use fakenotify_protocol::WatchRoot;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// How long to wait after a change before reading the files, since editors
+/// save in several steps
+const SETTLE: Duration = Duration::from_millis(300);
+
+/// How the watches of one configuration differ from the last
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Roots new to the configuration
+    pub added: Vec<WatchConfig>,
+    /// Roots whose settings changed
+    pub changed: Vec<WatchConfig>,
+    /// Roots no longer configured
+    pub removed: Vec<PathBuf>,
+}
+
+impl Plan {
+    /// What turns the watches `old` into `new`
+    pub fn new(old: &[WatchConfig], new: &[WatchConfig]) -> Self {
+        let mut plan = Self::default();
+        for watch in new {
+            match old.iter().find(|old| old.path == watch.path) {
+                None => plan.added.push(watch.clone()),
+                Some(old) if WatchRoot::from(old) != WatchRoot::from(watch) => {
+                    plan.changed.push(watch.clone());
+                }
+                Some(_) => {}
+            }
+        }
+        plan.removed = old
+            .iter()
+            .filter(|old| !new.iter().any(|watch| watch.path == old.path))
+            .map(|old| old.path.clone())
+            .collect();
+        plan
+    }
+
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Re-reads the configuration and applies its watches
+pub struct Reloader {
+    /// The config file given or found at startup, if any
+    config_file: Option<PathBuf>,
+    /// The watches applied from the configuration
+    watches: Vec<WatchConfig>,
+}
+
+impl Reloader {
+    /// Reload from `config_file`, or the default location, where the
+    /// configuration with `watches` was loaded from
+    pub fn new(config_file: Option<PathBuf>, watches: Vec<WatchConfig>) -> Self {
+        Self {
+            config_file: config_file.or_else(find_config_file),
+            watches,
+        }
+    }
+
+    /// Read the configuration again and apply what changed in its watches
+    ///
+    /// Roots that cannot be added are left out, so the next reload tries
+    /// them again.
+    pub fn reload(&mut self, state: &DaemonState) -> Result<Plan, String> {
+        let config = Config::load(self.config_file.as_ref()).map_err(|e| e.to_string())?;
+        let errors: Vec<String> = validate::check(&config)
+            .into_iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .map(|issue| issue.message)
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors.join("; "));
+        }
+
+        let plan = Plan::new(&self.watches, &config.watch);
+        for path in &plan.removed {
+            state.remove_root(path);
+        }
+        let mut failed = BTreeSet::new();
+        for root in plan.added.iter().chain(&plan.changed) {
+            if let Err(e) = state.replace_root(root.clone()) {
+                tracing::warn!(path = %root.path.display(), error = %e, "Failed to apply watch");
+                failed.insert(root.path.clone());
+            }
+        }
+        self.watches = config
+            .watch
+            .into_iter()
+            .filter(|watch| !failed.contains(&watch.path))
+            .collect();
+        Ok(plan)
+    }
+}
+
+/// Whether a change to `path` can change the configuration loaded from
+/// `main`
+fn is_config_file(path: &Path, main: &Path, dirs: &BTreeSet<PathBuf>) -> bool {
+    path == main
+        || path.extension().is_some_and(|ext| ext == "toml")
+            && path.parent().is_some_and(|dir| dirs.contains(dir))
+}
+
+/// Watch `main` and the directories of its fragments, waking `trigger`
+/// whenever one of the files is written, created or removed
+fn watch_files(main: &Path, trigger: Arc<Notify>) -> notify::Result<RecommendedWatcher> {
+    let mut dirs: BTreeSet<PathBuf> = fragments(main)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|fragment| fragment.parent().map(Path::to_path_buf))
+        .collect();
+    let conf_d = fragment_dir(main);
+    if conf_d.is_dir() {
+        dirs.insert(conf_d);
+    }
+    let watched = dirs.clone();
+    // Editors replace files by renaming, so the directories are watched
+    // rather than the files
+    dirs.insert(main.parent().unwrap_or(Path::new(".")).to_path_buf());
+
+    let file = main.to_path_buf();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        let Ok(event) = result else {
+            return;
+        };
+        // Reading the files for a reload must not trigger another
+        let edit = matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        );
+        if edit
+            && event
+                .paths
+                .iter()
+                .any(|path| is_config_file(path, &file, &watched))
+        {
+            trigger.notify_one();
+        }
+    })?;
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+/// Reload whenever `trigger` is woken and, with `watch_config`, whenever
+/// the configuration files change
+pub fn spawn(
+    state: Arc<DaemonState>,
+    mut reloader: Reloader,
+    trigger: Arc<Notify>,
+    watch_config: bool,
+) -> JoinHandle<()> {
+    let watcher = match (&reloader.config_file, watch_config) {
+        (Some(main), true) => match watch_files(main, Arc::clone(&trigger)) {
+            Ok(watcher) => {
+                tracing::info!(path = %main.display(), "Watching the configuration for changes");
+                Some(watcher)
+            }
+            Err(e) => {
+                tracing::warn!(path = %main.display(), error = %e, "Cannot watch the configuration");
+                None
+            }
+        },
+        _ => None,
+    };
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        loop {
+            trigger.notified().await;
+            tokio::time::sleep(SETTLE).await;
+            // Adding a root stats it, which can block on a hung mount
+            let state = Arc::clone(&state);
+            let Ok((returned, result)) = tokio::task::spawn_blocking(move || {
+                let result = reloader.reload(&state);
+                (reloader, result)
+            })
+            .await
+            else {
+                return;
+            };
+            reloader = returned;
+            match result {
+                Ok(plan) if plan.is_empty() => {
+                    tracing::info!("Configuration reloaded, watches unchanged");
+                }
+                Ok(plan) => tracing::info!(
+                    added = plan.added.len(),
+                    changed = plan.changed.len(),
+                    removed = plan.removed.len(),
+                    "Configuration reloaded"
+                ),
+                Err(e) => tracing::warn!(error = %e, "Configuration not reloaded"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Figment;
+    use figment::providers::{Format, Toml};
+
+    fn watches(toml: &str) -> Vec<WatchConfig> {
+        Figment::new()
+            .merge(Toml::string(toml))
+            .extract::<Config>()
+            .unwrap()
+            .watch
+    }
+
+    #[test]
+    fn test_plan() {
+        let old = watches(
+            r#"
+            [[watch]]
+            path = "/mnt/a"
+            [[watch]]
+            path = "/mnt/b"
+            [[watch]]
+            path = "/mnt/c"
+            "#,
+        );
+        let new = watches(
+            r#"
+            [[watch]]
+            path = "/mnt/b"
+            poll_interval = "1s"
+            [[watch]]
+            path = "/mnt/c"
+            [[watch]]
+            path = "/mnt/d"
+            "#,
+        );
+
+        let plan = Plan::new(&old, &new);
+        assert_eq!(plan.added[0].path, PathBuf::from("/mnt/d"));
+        assert_eq!(plan.changed[0].path, PathBuf::from("/mnt/b"));
+        assert_eq!(plan.removed, [PathBuf::from("/mnt/a")]);
+        assert!(Plan::new(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_is_config_file() {
+        let main = Path::new("/etc/fakenotify/config.toml");
+        let dirs = BTreeSet::from([PathBuf::from("/etc/fakenotify/conf.d")]);
+        assert!(is_config_file(main, main, &dirs));
+        assert!(is_config_file(
+            Path::new("/etc/fakenotify/conf.d/10-media.toml"),
+            main,
+            &dirs
+        ));
+        assert!(!is_config_file(
+            Path::new("/etc/fakenotify/conf.d/.10-media.toml.swp"),
+            main,
+            &dirs
+        ));
+        assert!(!is_config_file(
+            Path::new("/etc/fakenotify/other.toml"),
+            main,
+            &dirs
+        ));
+    }
+}
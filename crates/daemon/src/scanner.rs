@@ -0,0 +1,1308 @@
+//! Poll-based change detection.
+//!
+//! A [`Scanner`] walks a watched root through a [`ScanSource`], keeps the
+//! previous [`Snapshot`], and diffs each new walk against it to produce
+//! [`WatcherEvent`]s. The scanner itself never sleeps or reads the clock, so
+//! driving it with a simulated source gives fully deterministic results.
+
+use crate::fold;
+use crate::settle::Settler;
+use crate::source::{EntryMeta, ScanSource};
+use crate::watcher::WatcherEvent;
+use fakenotify_protocol::ChangeDetection;
+use notify::EventKind;
+use notify::event::{
+    AccessKind, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Metadata for every entry under a root, ordered by path
+pub type Snapshot = BTreeMap<PathBuf, EntryMeta>;
+
+/// Scans a single watched root and reports changes between polls
+pub struct Scanner {
+    source: Arc<dyn ScanSource>,
+    root: PathBuf,
+    recursive: bool,
+    detection: ChangeDetection,
+    /// Compare paths without regard to case
+    fold_case: bool,
+    snapshot: Snapshot,
+    /// Inodes of entries unlinked while still open
+    unlinked: HashSet<u64>,
+    /// Consecutive scans that must miss an entry before it counts as gone
+    confirm_deletes: u32,
+    /// Stat missed entries before counting them as gone
+    restat_deletes: bool,
+    /// Report files whose atime moves as accessed
+    track_access: bool,
+    /// Entries the scans missed but whose deletion is unconfirmed, with
+    /// how many scans in a row missed them
+    missing: HashMap<PathBuf, u32>,
+    /// Holds writes back until files are complete, when enabled
+    settler: Option<Settler>,
+}
+
+impl Scanner {
+    /// Create a scanner and take the initial snapshot
+    ///
+    /// The initial snapshot is the baseline; it does not produce events.
+    pub fn new(source: Arc<dyn ScanSource>, root: PathBuf, recursive: bool) -> Self {
+        let mut scanner = Self::resume(source, root, recursive, Snapshot::new());
+        scanner.snapshot = scanner.scan();
+        scanner
+    }
+
+    /// Create a scanner that takes `baseline`, such as a checkpoint, as its
+    /// previous snapshot instead of walking the root now
+    pub fn resume(
+        source: Arc<dyn ScanSource>,
+        root: PathBuf,
+        recursive: bool,
+        baseline: Snapshot,
+    ) -> Self {
+        Self {
+            source,
+            root,
+            recursive,
+            detection: ChangeDetection::default(),
+            fold_case: false,
+            snapshot: baseline,
+            unlinked: HashSet::new(),
+            confirm_deletes: 1,
+            restat_deletes: false,
+            track_access: false,
+            missing: HashMap::new(),
+            settler: None,
+        }
+    }
+
+    /// Decide what counts as a file modification
+    ///
+    /// [`ChangeDetection::Hash`] only helps if the source fills in
+    /// [`EntryMeta::content`].
+    pub fn with_change_detection(mut self, detection: ChangeDetection) -> Self {
+        self.detection = detection;
+        self
+    }
+
+    /// Treat names that differ only in case as the same entry, keeping
+    /// the spelling first seen
+    pub fn with_case_folding(mut self, fold_case: bool) -> Self {
+        self.fold_case = fold_case;
+        self
+    }
+
+    /// Hold back deletions until `scans` scans in a row miss the entry or,
+    /// with `restat`, a stat of it fails with ENOENT
+    ///
+    /// An entry a stat still finds is kept, however many scans miss it.
+    pub fn with_delete_confirmation(mut self, scans: u32, restat: bool) -> Self {
+        self.confirm_deletes = scans;
+        self.restat_deletes = restat;
+        self
+    }
+
+    /// Report a file whose atime moved since the previous poll as
+    /// accessed, which is as close as polling gets to IN_ACCESS
+    ///
+    /// Only as good as the mount's atime option: `noatime` never moves it
+    /// and `relatime` about once a day.
+    pub fn with_access_tracking(mut self, track: bool) -> Self {
+        self.track_access = track;
+        self
+    }
+
+    /// Report writes only once a file stayed unchanged for `scans` scans,
+    /// as IN_MODIFY and IN_CLOSE_WRITE, and leave out files ending in one
+    /// of `partial_suffixes` until renamed to their final name
+    pub fn with_settling(mut self, scans: u32, partial_suffixes: Vec<String>) -> Self {
+        self.settler = (scans > 0 || !partial_suffixes.is_empty())
+            .then(|| Settler::new(scans, partial_suffixes));
+        self
+    }
+
+    /// The root this scanner watches
+    #[allow(dead_code)]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The most recent snapshot
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// The most recent snapshot, for a new scanner to resume from
+    pub fn into_snapshot(self) -> Snapshot {
+        self.snapshot
+    }
+
+    /// Walk the root and return a fresh snapshot without updating state
+    pub fn scan(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+
+        let root_meta = match self.source.metadata(&self.root) {
+            Ok(meta) => meta,
+            // Stuck in IO; report nothing this cycle
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return self.snapshot.clone(),
+            Err(e) => {
+                tracing::trace!(path = %self.root.display(), error = %e, "Root not accessible");
+                return snapshot;
+            }
+        };
+        snapshot.insert(self.root.clone(), root_meta);
+
+        if !root_meta.is_dir {
+            return snapshot;
+        }
+
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            let entries = match self.source.read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    // Keep the subtree as it was so it reports nothing
+                    let previous = self
+                        .snapshot
+                        .range(dir.clone()..)
+                        .skip(1)
+                        .take_while(|(path, _)| path.starts_with(&dir));
+                    snapshot.extend(previous.map(|(path, meta)| (path.clone(), *meta)));
+                    continue;
+                }
+                Err(e) => {
+                    tracing::trace!(path = %dir.display(), error = %e, "Failed to read directory");
+                    continue;
+                }
+            };
+            for entry in entries {
+                if entry.meta.is_dir && self.recursive {
+                    pending.push(entry.path.clone());
+                }
+                snapshot.insert(entry.path, entry.meta);
+            }
+        }
+
+        snapshot
+    }
+
+    /// Rescan and return the events since the previous poll
+    pub fn poll(&mut self) -> Vec<WatcherEvent> {
+        let mut current = self.scan();
+        if self.fold_case {
+            current = keep_case(&self.snapshot, current);
+        }
+        self.confirm_missing(&mut current);
+        let events = match self.root_gone(&current) {
+            // Like inotify, a moved root reports only itself
+            Some(RootGone::Renamed { is_dir }) => vec![WatcherEvent {
+                path: self.root.clone(),
+                kind: EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                is_dir,
+                unlinked: false,
+                ino: self.snapshot.get(&self.root).map_or(0, |meta| meta.ino),
+            }],
+            // The inode lives on under another name, so only its link
+            // count changed
+            Some(RootGone::Unlinked) => vec![self.root_attrib()],
+            None => {
+                let mut events = diff(&self.snapshot, &current, self.detection);
+                if self.root_links_changed(&current)
+                    && !events.iter().any(|event| {
+                        event.path == self.root
+                            && matches!(event.kind, EventKind::Modify(ModifyKind::Metadata(_)))
+                    })
+                {
+                    events.push(self.root_attrib());
+                }
+                if self.track_access {
+                    events.extend(accesses(&self.snapshot, &current));
+                }
+                events
+            }
+        };
+        let mut events = self.track_unlinked(events, &current);
+        if let Some(settler) = &mut self.settler {
+            events = settler.filter(events, &current);
+        }
+        self.snapshot = current;
+        events
+    }
+
+    /// Put back entries `current` lacks until their deletion is confirmed
+    ///
+    /// Only the topmost entry of a vanished subtree is checked; whatever
+    /// is below it comes back or goes with it. Entries that reappear under
+    /// another name are renames and are left to the diff.
+    fn confirm_missing(&mut self, current: &mut Snapshot) {
+        if self.confirm_deletes <= 1 && !self.restat_deletes {
+            return;
+        }
+        let arrived: HashSet<u64> = current
+            .iter()
+            .filter(|(path, _)| !self.snapshot.contains_key(*path))
+            .map(|(_, meta)| meta.ino)
+            .filter(|&ino| ino != 0)
+            .collect();
+
+        let mut missing = HashMap::new();
+        let mut kept: Vec<(&PathBuf, Option<EntryMeta>)> = Vec::new();
+        let mut top: Option<&PathBuf> = None;
+        for (path, meta) in &self.snapshot {
+            if current.contains_key(path) || top.is_some_and(|top| path.starts_with(top)) {
+                continue;
+            }
+            top = Some(path);
+            if arrived.contains(&meta.ino)
+                || (*path == self.root && self.root_gone(current).is_some())
+            {
+                continue;
+            }
+            if self.restat_deletes {
+                match self.source.metadata(path) {
+                    // The listing left out an entry that is still there
+                    Ok(meta) => {
+                        kept.push((path, Some(meta)));
+                        continue;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(_) => {}
+                }
+            }
+            let scans = self.missing.get(path).map_or(1, |scans| scans + 1);
+            if scans < self.confirm_deletes {
+                missing.insert(path.clone(), scans);
+                kept.push((path, None));
+            }
+        }
+
+        for (path, restated) in kept {
+            let subtree = self
+                .snapshot
+                .range(path.clone()..)
+                .take_while(|(entry, _)| entry.starts_with(path));
+            current.extend(subtree.map(|(entry, meta)| (entry.clone(), *meta)));
+            if let Some(meta) = restated {
+                current.insert(path.clone(), meta);
+            }
+        }
+        self.missing = missing;
+    }
+
+    /// Report silly-renamed files as deleted and flag their later events
+    ///
+    /// An NFS client cannot unlink a file it still has open, so it renames
+    /// it to `.nfsXXXX` and removes that once the file is closed. Polling
+    /// never sees unlinked files otherwise, so this is the only case
+    /// IN_EXCL_UNLINK can apply to.
+    fn track_unlinked(
+        &mut self,
+        events: Vec<WatcherEvent>,
+        current: &Snapshot,
+    ) -> Vec<WatcherEvent> {
+        let mut tracked: Vec<WatcherEvent> = Vec::with_capacity(events.len());
+        for mut event in events {
+            let ino = current
+                .get(&event.path)
+                .or_else(|| self.snapshot.get(&event.path))
+                .map(|meta| meta.ino)
+                .filter(|&ino| ino != 0);
+
+            if event.kind == EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                && is_silly_rename(&event.path)
+                && let Some(ino) = ino
+            {
+                // The rename's other half is the unlink itself
+                if let Some(from) = tracked.last_mut()
+                    && from.kind == EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                {
+                    from.kind = EventKind::Remove(if from.is_dir {
+                        RemoveKind::Folder
+                    } else {
+                        RemoveKind::File
+                    });
+                }
+                self.unlinked.insert(ino);
+                continue;
+            }
+
+            event.unlinked = ino.is_some_and(|ino| self.unlinked.contains(&ino));
+            tracked.push(event);
+        }
+
+        self.unlinked
+            .retain(|ino| current.values().any(|meta| meta.ino == *ino));
+        tracked
+    }
+
+    /// Why the root vanished, if it is still somewhere in its parent
+    ///
+    /// The diff only sees entries under the root, so a renamed file root
+    /// would otherwise look deleted, as would one of several hard links.
+    fn root_gone(&self, current: &Snapshot) -> Option<RootGone> {
+        if current.contains_key(&self.root) {
+            return None;
+        }
+        let old = self.snapshot.get(&self.root).filter(|meta| meta.ino != 0)?;
+        let parent = self.root.parent()?;
+        let entries = self.source.read_dir(parent).ok()?;
+        let found = entries.iter().find(|entry| entry.meta.ino == old.ino)?;
+        Some(
+            if !old.is_dir && old.nlink > 1 && found.meta.nlink < old.nlink {
+                RootGone::Unlinked
+            } else {
+                RootGone::Renamed { is_dir: old.is_dir }
+            },
+        )
+    }
+
+    /// Whether a file root gained or lost a hard link elsewhere
+    ///
+    /// Like inotify, only the file's own watch hears of it: directory
+    /// watches see the new or removed name instead.
+    fn root_links_changed(&self, current: &Snapshot) -> bool {
+        match (self.snapshot.get(&self.root), current.get(&self.root)) {
+            (Some(old), Some(new)) => !new.is_dir && old.nlink != new.nlink,
+            _ => false,
+        }
+    }
+
+    fn root_attrib(&self) -> WatcherEvent {
+        WatcherEvent {
+            path: self.root.clone(),
+            kind: EventKind::Modify(ModifyKind::Metadata(MetadataKind::Other)),
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+}
+
+/// How a root that is missing from the new scan went away
+enum RootGone {
+    /// Moved to another name within its parent
+    Renamed { is_dir: bool },
+    /// One of several hard links was removed; the inode lives on
+    Unlinked,
+}
+
+/// `new` with entries that only changed case spelled as in `old`
+fn keep_case(old: &Snapshot, new: Snapshot) -> Snapshot {
+    let vanished: HashMap<String, &PathBuf> = old
+        .keys()
+        .filter(|path| !new.contains_key(*path))
+        .map(|path| (fold::key(path), path))
+        .collect();
+    if vanished.is_empty() {
+        return new;
+    }
+    new.into_iter()
+        .map(|(path, meta)| {
+            if old.contains_key(&path) {
+                return (path, meta);
+            }
+            match vanished.get(&fold::key(&path)) {
+                Some(spelled) => ((*spelled).clone(), meta),
+                None => (path, meta),
+            }
+        })
+        .collect()
+}
+
+/// Whether `path` is the name an NFS client gives an unlinked open file
+fn is_silly_rename(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(".nfs"))
+}
+
+/// Compute the events that turn `old` into `new`
+///
+/// The order is deterministic and every event names a path that exists at
+/// that point of the sequence:
+///
+/// 1. removals, children before their directory, except of directories a
+///    rename leaves from
+/// 2. creations of directories a rename goes into, parents first
+/// 3. renames as adjacent from/to pairs, deepest source first
+/// 4. removals of the directories renames left, children first
+/// 5. entries replaced by a different kind, as a removal and a creation
+/// 6. all other creations, parents first
+/// 7. modifications
+///
+/// Within each step events follow path order, so a directory's children
+/// appear in the order the scan lists them.
+pub fn diff(old: &Snapshot, new: &Snapshot, detection: ChangeDetection) -> Vec<WatcherEvent> {
+    let removed: Vec<(&PathBuf, &EntryMeta)> =
+        old.iter().filter(|(p, _)| !new.contains_key(*p)).collect();
+    let created: Vec<(&PathBuf, &EntryMeta)> =
+        new.iter().filter(|(p, _)| !old.contains_key(*p)).collect();
+
+    // Pair removals and creations that share an inode as renames
+    let mut created_by_ino: HashMap<u64, &PathBuf> = created
+        .iter()
+        .filter(|(_, m)| m.ino != 0)
+        .map(|(p, m)| (m.ino, *p))
+        .collect();
+
+    let mut renames: Vec<(&PathBuf, &PathBuf, bool)> = Vec::new();
+    for (path, meta) in &removed {
+        if meta.ino == 0 {
+            continue;
+        }
+        if let Some(to) = created_by_ino.remove(&meta.ino) {
+            renames.push((*path, to, meta.is_dir));
+        }
+    }
+
+    let renamed_from: Vec<&PathBuf> = renames.iter().map(|(from, _, _)| *from).collect();
+    let renamed_to: Vec<&PathBuf> = renames.iter().map(|(_, to, _)| *to).collect();
+
+    // Children of a renamed directory move with it and are not reported
+    let moved_dirs: Vec<(&PathBuf, &PathBuf)> = renames
+        .iter()
+        .filter(|(_, _, is_dir)| *is_dir)
+        .map(|(from, to, _)| (*from, *to))
+        .collect();
+    let inside_moved_dir = |from: &Path, to: &Path| {
+        moved_dirs.iter().any(|(dir_from, dir_to)| {
+            from != dir_from.as_path()
+                && from
+                    .strip_prefix(dir_from)
+                    .is_ok_and(|suffix| dir_to.join(suffix) == to)
+        })
+    };
+    renames.retain(|(from, to, _)| !inside_moved_dir(from, to));
+    renames.sort_by(|(a_from, a_to, _), (b_from, b_to, _)| {
+        depth(b_from)
+            .cmp(&depth(a_from))
+            .then(depth(a_to).cmp(&depth(b_to)))
+            .then(a_from.cmp(b_from))
+    });
+
+    // Directories renames leave from and go into
+    let left = |path: &Path| {
+        renames
+            .iter()
+            .any(|(from, _, _)| from.as_path() != path && from.starts_with(path))
+    };
+    let entered = |path: &Path| {
+        renames
+            .iter()
+            .any(|(_, to, _)| to.as_path() != path && to.starts_with(path))
+    };
+
+    let removal = |path: &PathBuf, meta: &EntryMeta| WatcherEvent {
+        path: path.clone(),
+        kind: EventKind::Remove(if meta.is_dir {
+            RemoveKind::Folder
+        } else {
+            RemoveKind::File
+        }),
+        is_dir: meta.is_dir,
+        unlinked: false,
+        ino: meta.ino,
+    };
+    let creation = |path: &PathBuf, meta: &EntryMeta| WatcherEvent {
+        path: path.clone(),
+        kind: EventKind::Create(if meta.is_dir {
+            CreateKind::Folder
+        } else {
+            CreateKind::File
+        }),
+        is_dir: meta.is_dir,
+        unlinked: false,
+        ino: meta.ino,
+    };
+
+    let removals: Vec<(&PathBuf, &EntryMeta)> = removed
+        .iter()
+        .rev()
+        .filter(|(path, _)| !renamed_from.contains(path))
+        .copied()
+        .collect();
+    let creations: Vec<(&PathBuf, &EntryMeta)> = created
+        .iter()
+        .filter(|(path, _)| !renamed_to.contains(path))
+        .copied()
+        .collect();
+
+    let mut events = Vec::new();
+
+    events.extend(
+        removals
+            .iter()
+            .filter(|(path, _)| !left(path))
+            .map(|(path, meta)| removal(path, meta)),
+    );
+    events.extend(
+        creations
+            .iter()
+            .filter(|(path, _)| entered(path))
+            .map(|(path, meta)| creation(path, meta)),
+    );
+
+    for (from, to, is_dir) in &renames {
+        let ino = old[*from].ino;
+        events.push(WatcherEvent {
+            path: (*from).clone(),
+            kind: EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+            is_dir: *is_dir,
+            unlinked: false,
+            ino,
+        });
+        events.push(WatcherEvent {
+            path: (*to).clone(),
+            kind: EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+            is_dir: *is_dir,
+            unlinked: false,
+            ino,
+        });
+    }
+
+    events.extend(
+        removals
+            .iter()
+            .filter(|(path, _)| left(path))
+            .map(|(path, meta)| removal(path, meta)),
+    );
+
+    let mut modifications = Vec::new();
+    for (path, new_meta) in new {
+        let Some(old_meta) = old.get(path) else {
+            continue;
+        };
+        if old_meta.is_dir != new_meta.is_dir {
+            // Replaced by a different kind of entry
+            events.push(WatcherEvent {
+                path: path.clone(),
+                kind: EventKind::Remove(RemoveKind::Any),
+                is_dir: old_meta.is_dir,
+                unlinked: false,
+                ino: 0,
+            });
+            events.push(WatcherEvent {
+                path: path.clone(),
+                kind: EventKind::Create(CreateKind::Any),
+                is_dir: new_meta.is_dir,
+                unlinked: false,
+                ino: 0,
+            });
+            continue;
+        }
+        // Directory mtimes change whenever children do; inotify does not
+        // report those as modifications of the directory itself
+        if !new_meta.is_dir && data_changed(old_meta, new_meta, detection) {
+            modifications.push(WatcherEvent {
+                path: path.clone(),
+                kind: EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                is_dir: false,
+                unlinked: false,
+                ino: 0,
+            });
+        }
+        if let Some(kind) = attrib_change(old_meta, new_meta) {
+            modifications.push(WatcherEvent {
+                path: path.clone(),
+                kind: EventKind::Modify(ModifyKind::Metadata(kind)),
+                is_dir: new_meta.is_dir,
+                unlinked: false,
+                ino: 0,
+            });
+        }
+    }
+
+    events.extend(
+        creations
+            .iter()
+            .filter(|(path, _)| !entered(path))
+            .map(|(path, meta)| creation(path, meta)),
+    );
+    events.extend(modifications);
+    events
+}
+
+/// Files whose atime moved forward between `old` and `new`, as accesses
+///
+/// Directories are left out, as every scan reads them, and so are files
+/// whose previous atime is unknown.
+fn accesses<'a>(old: &'a Snapshot, new: &'a Snapshot) -> impl Iterator<Item = WatcherEvent> + 'a {
+    new.iter()
+        .filter(|(path, meta)| {
+            !meta.is_dir
+                && old.get(*path).is_some_and(|old| {
+                    old.ino == meta.ino
+                        && old.atime != SystemTime::UNIX_EPOCH
+                        && meta.atime > old.atime
+                })
+        })
+        .map(|(path, meta)| WatcherEvent {
+            path: path.clone(),
+            kind: EventKind::Access(AccessKind::Read),
+            is_dir: false,
+            unlinked: false,
+            ino: meta.ino,
+        })
+}
+
+/// Number of components in `path`
+fn depth(path: &Path) -> usize {
+    path.components().count()
+}
+
+/// Whether a file's contents changed between `old` and `new`
+///
+/// Size and mtime miss overwrites within the mtime resolution. Any other
+/// ctime change is only taken as a write if no metadata change explains it.
+pub fn data_changed(old: &EntryMeta, new: &EntryMeta, detection: ChangeDetection) -> bool {
+    if old.mtime != new.mtime || old.size != new.size {
+        return true;
+    }
+    let ctime_changed =
+        || old.ctime != new.ctime && old.nlink == new.nlink && attrib_change(old, new).is_none();
+    match detection {
+        ChangeDetection::Mtime => false,
+        ChangeDetection::Ctime => ctime_changed(),
+        ChangeDetection::Hash => old.content != new.content || ctime_changed(),
+    }
+}
+
+/// Which metadata changed between `old` and `new`, as one IN_ATTRIB
+///
+/// Several kinds of change within one poll are reported as
+/// [`MetadataKind::Any`] rather than one event each.
+fn attrib_change(old: &EntryMeta, new: &EntryMeta) -> Option<MetadataKind> {
+    let changes = [
+        (old.mode != new.mode, MetadataKind::Permissions),
+        (
+            old.uid != new.uid || old.gid != new.gid,
+            MetadataKind::Ownership,
+        ),
+        (old.xattrs != new.xattrs, MetadataKind::Extended),
+    ];
+    let mut changed = changes
+        .into_iter()
+        .filter_map(|(changed, kind)| changed.then_some(kind));
+    let first = changed.next()?;
+    Some(if changed.next().is_some() {
+        MetadataKind::Any
+    } else {
+        first
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::sim::SimSource;
+    use std::time::Duration;
+
+    fn setup() -> (Arc<SimSource>, Scanner) {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media");
+        let scanner = Scanner::new(sim.clone(), PathBuf::from("/mnt/media"), true);
+        (sim, scanner)
+    }
+
+    fn kinds(events: &[WatcherEvent]) -> Vec<(String, EventKind)> {
+        events
+            .iter()
+            .map(|e| (e.path.display().to_string(), e.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_no_changes_no_events() {
+        let (sim, mut scanner) = setup();
+        sim.advance(Duration::from_secs(5));
+        assert!(scanner.poll().is_empty());
+    }
+
+    #[test]
+    fn test_create_modify_delete() {
+        let (sim, mut scanner) = setup();
+
+        sim.write("/mnt/media/a.mkv", 10);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Create(CreateKind::File)
+            )]
+        );
+
+        sim.advance(Duration::from_secs(1));
+        sim.write("/mnt/media/a.mkv", 20);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Modify(ModifyKind::Data(DataChange::Any))
+            )]
+        );
+
+        sim.remove("/mnt/media/a.mkv");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Remove(RemoveKind::File)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rename_is_paired() {
+        let (sim, mut scanner) = setup();
+        sim.write("/mnt/media/old.mkv", 10);
+        scanner.poll();
+
+        sim.advance(Duration::from_secs(1));
+        sim.rename("/mnt/media/old.mkv", "/mnt/media/new.mkv");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                (
+                    "/mnt/media/old.mkv".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                ),
+                (
+                    "/mnt/media/new.mkv".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directory_rename_hides_children() {
+        let (sim, mut scanner) = setup();
+        sim.mkdir_all("/mnt/media/show");
+        sim.write("/mnt/media/show/ep1.mkv", 1);
+        scanner.poll();
+
+        sim.rename("/mnt/media/show", "/mnt/media/series");
+        let events = scanner.poll();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.is_dir));
+    }
+
+    #[test]
+    fn test_recursive_delete_is_deepest_first() {
+        let (sim, mut scanner) = setup();
+        sim.mkdir_all("/mnt/media/show");
+        sim.write("/mnt/media/show/ep1.mkv", 1);
+        scanner.poll();
+
+        sim.remove("/mnt/media/show");
+        let paths: Vec<String> = kinds(&scanner.poll()).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, vec!["/mnt/media/show/ep1.mkv", "/mnt/media/show"]);
+    }
+
+    #[test]
+    fn test_chmod_reports_metadata() {
+        let (sim, mut scanner) = setup();
+        sim.write("/mnt/media/a.mkv", 1);
+        scanner.poll();
+
+        sim.chmod("/mnt/media/a.mkv", 0o600);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_atime_reports_access() {
+        let (sim, scanner) = setup();
+        // An atime at the epoch counts as unknown
+        sim.advance(Duration::from_millis(10));
+        sim.write("/mnt/media/a.mkv", 1);
+        let mut scanner = scanner.with_access_tracking(true);
+        scanner.poll();
+
+        sim.advance(Duration::from_millis(10));
+        sim.read("/mnt/media/a.mkv");
+        sim.read("/mnt/media");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Access(AccessKind::Read)
+            )]
+        );
+        assert!(scanner.poll().is_empty());
+    }
+
+    #[test]
+    fn test_chown_and_xattrs_report_metadata() {
+        let (sim, mut scanner) = setup();
+        sim.write("/mnt/media/a.mkv", 1);
+        scanner.poll();
+
+        sim.chown("/mnt/media/a.mkv", 1000, 1000);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership))
+            )]
+        );
+
+        sim.set_xattrs("/mnt/media/a.mkv", 42);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Extended))
+            )]
+        );
+
+        // chmod and chown between two polls are one IN_ATTRIB
+        sim.chmod("/mnt/media/a.mkv", 0o600);
+        sim.chown("/mnt/media/a.mkv", 0, 0);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_ctime_detects_preserved_mtime() {
+        let (sim, scanner) = setup();
+        let mut scanner = scanner.with_change_detection(ChangeDetection::Ctime);
+        sim.write("/mnt/media/a.db", 1);
+        scanner.poll();
+        let mtime = scanner.snapshot()[Path::new("/mnt/media/a.db")].mtime;
+
+        // Rewritten, then the mtime put back as `rsync -t` would
+        sim.advance(Duration::from_millis(10));
+        sim.write("/mnt/media/a.db", 1);
+        sim.set_mtime("/mnt/media/a.db", mtime);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.db".to_string(),
+                EventKind::Modify(ModifyKind::Data(DataChange::Any))
+            )]
+        );
+
+        // A ctime change explained by chmod is only IN_ATTRIB
+        sim.advance(Duration::from_millis(10));
+        sim.chmod("/mnt/media/a.db", 0o600);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.db".to_string(),
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_hash_detects_same_second_overwrite() {
+        let (sim, mut scanner) = setup();
+        sim.set_resolution(Duration::from_secs(1));
+        sim.write("/mnt/media/a.db", 1);
+        scanner.poll();
+
+        sim.advance(Duration::from_millis(300));
+        sim.write("/mnt/media/a.db", 1);
+        sim.set_content("/mnt/media/a.db", 7);
+        assert!(scanner.poll().is_empty());
+
+        let mut scanner = scanner.with_change_detection(ChangeDetection::Hash);
+        sim.set_content("/mnt/media/a.db", 8);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.db".to_string(),
+                EventKind::Modify(ModifyKind::Data(DataChange::Any))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_case_folding_ignores_respelled_names() {
+        let (sim, scanner) = setup();
+        let mut scanner = scanner.with_case_folding(true);
+        sim.mkdir_all("/mnt/media/Show");
+        sim.write("/mnt/media/Show/Ep1.mkv", 1);
+        scanner.poll();
+
+        // The server spells the same entries differently, with new inodes
+        sim.remove("/mnt/media/Show");
+        sim.mkdir_all("/mnt/media/show");
+        sim.write("/mnt/media/show/EP1.mkv", 1);
+        assert!(scanner.poll().is_empty());
+        assert!(
+            scanner
+                .snapshot()
+                .contains_key(Path::new("/mnt/media/Show/Ep1.mkv"))
+        );
+
+        sim.advance(Duration::from_secs(1));
+        sim.write("/mnt/media/show/EP1.mkv", 2);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/Show/Ep1.mkv".to_string(),
+                EventKind::Modify(ModifyKind::Data(DataChange::Any))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_deep_tree_order() {
+        let (sim, mut scanner) = setup();
+        sim.mkdir_all("/mnt/media/a/b/c");
+        sim.write("/mnt/media/a/b/c/ep1.mkv", 1);
+        sim.write("/mnt/media/a/z.mkv", 1);
+        let created = EventKind::Create;
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                ("/mnt/media/a".to_string(), created(CreateKind::Folder)),
+                ("/mnt/media/a/b".to_string(), created(CreateKind::Folder)),
+                ("/mnt/media/a/b/c".to_string(), created(CreateKind::Folder)),
+                (
+                    "/mnt/media/a/b/c/ep1.mkv".to_string(),
+                    created(CreateKind::File)
+                ),
+                ("/mnt/media/a/z.mkv".to_string(), created(CreateKind::File)),
+            ]
+        );
+
+        sim.remove("/mnt/media/a");
+        let removed = EventKind::Remove;
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                ("/mnt/media/a/z.mkv".to_string(), removed(RemoveKind::File)),
+                (
+                    "/mnt/media/a/b/c/ep1.mkv".to_string(),
+                    removed(RemoveKind::File)
+                ),
+                ("/mnt/media/a/b/c".to_string(), removed(RemoveKind::Folder)),
+                ("/mnt/media/a/b".to_string(), removed(RemoveKind::Folder)),
+                ("/mnt/media/a".to_string(), removed(RemoveKind::Folder)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_order_around_parents() {
+        let (sim, mut scanner) = setup();
+        sim.mkdir_all("/mnt/media/old");
+        sim.write("/mnt/media/old/ep1.mkv", 1);
+        sim.write("/mnt/media/old/ep2.mkv", 1);
+        scanner.poll();
+
+        // Moved into a new directory out of one that is then removed
+        sim.mkdir_all("/mnt/media/new/season");
+        sim.rename("/mnt/media/old/ep1.mkv", "/mnt/media/new/season/ep1.mkv");
+        sim.remove("/mnt/media/old");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                (
+                    "/mnt/media/old/ep2.mkv".to_string(),
+                    EventKind::Remove(RemoveKind::File)
+                ),
+                (
+                    "/mnt/media/new".to_string(),
+                    EventKind::Create(CreateKind::Folder)
+                ),
+                (
+                    "/mnt/media/new/season".to_string(),
+                    EventKind::Create(CreateKind::Folder)
+                ),
+                (
+                    "/mnt/media/old/ep1.mkv".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                ),
+                (
+                    "/mnt/media/new/season/ep1.mkv".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                ),
+                (
+                    "/mnt/media/old".to_string(),
+                    EventKind::Remove(RemoveKind::Folder)
+                ),
+            ]
+        );
+
+        // A file created in a directory that was just renamed follows it
+        sim.rename("/mnt/media/new", "/mnt/media/shows");
+        sim.write("/mnt/media/shows/season/ep2.mkv", 1);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                (
+                    "/mnt/media/new".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                ),
+                (
+                    "/mnt/media/shows".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                ),
+                (
+                    "/mnt/media/shows/season/ep2.mkv".to_string(),
+                    EventKind::Create(CreateKind::File)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_silly_rename_is_unlink() {
+        let (sim, mut scanner) = setup();
+        sim.write("/mnt/media/a.log", 1);
+        scanner.poll();
+
+        sim.rename("/mnt/media/a.log", "/mnt/media/.nfs0000000000ab0001");
+        let events = scanner.poll();
+        assert_eq!(
+            kinds(&events),
+            vec![(
+                "/mnt/media/a.log".to_string(),
+                EventKind::Remove(RemoveKind::File)
+            )]
+        );
+        assert!(!events[0].unlinked);
+
+        sim.advance(Duration::from_secs(1));
+        sim.write("/mnt/media/.nfs0000000000ab0001", 2);
+        let events = scanner.poll();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].unlinked);
+
+        sim.remove("/mnt/media/.nfs0000000000ab0001");
+        let events = scanner.poll();
+        assert!(events[0].unlinked);
+        assert!(scanner.unlinked.is_empty());
+    }
+
+    #[test]
+    fn test_file_root() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/etc/app");
+        sim.write("/etc/app/config.toml", 1);
+        let mut scanner = Scanner::new(sim.clone(), PathBuf::from("/etc/app/config.toml"), true);
+
+        sim.advance(Duration::from_secs(1));
+        sim.write("/etc/app/config.toml", 2);
+        sim.chmod("/etc/app/config.toml", 0o600);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                (
+                    "/etc/app/config.toml".to_string(),
+                    EventKind::Modify(ModifyKind::Data(DataChange::Any))
+                ),
+                (
+                    "/etc/app/config.toml".to_string(),
+                    EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions))
+                ),
+            ]
+        );
+
+        sim.rename("/etc/app/config.toml", "/etc/app/config.toml.bak");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/etc/app/config.toml".to_string(),
+                EventKind::Modify(ModifyKind::Name(RenameMode::From))
+            )]
+        );
+
+        sim.write("/etc/app/config.toml", 3);
+        scanner.poll();
+        sim.remove("/etc/app/config.toml");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/etc/app/config.toml".to_string(),
+                EventKind::Remove(RemoveKind::File)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_hard_links() {
+        let (sim, mut scanner) = setup();
+        sim.write("/mnt/media/a.mkv", 1);
+        scanner.poll();
+
+        sim.link("/mnt/media/a.mkv", "/mnt/media/b.mkv");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/b.mkv".to_string(),
+                EventKind::Create(CreateKind::File)
+            )]
+        );
+
+        sim.remove("/mnt/media/a.mkv");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/a.mkv".to_string(),
+                EventKind::Remove(RemoveKind::File)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_file_root_hard_links() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/srv/data");
+        sim.write("/srv/data/a.db", 1);
+        let mut a = Scanner::new(sim.clone(), PathBuf::from("/srv/data/a.db"), false);
+
+        sim.link("/srv/data/a.db", "/srv/data/b.db");
+        let mut b = Scanner::new(sim.clone(), PathBuf::from("/srv/data/b.db"), false);
+        let attrib = |path: &str| {
+            vec![(
+                path.to_string(),
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Other)),
+            )]
+        };
+        assert_eq!(kinds(&a.poll()), attrib("/srv/data/a.db"));
+
+        // Unlinking one name is only a link count change for both
+        sim.remove("/srv/data/a.db");
+        assert_eq!(kinds(&a.poll()), attrib("/srv/data/a.db"));
+        assert_eq!(kinds(&b.poll()), attrib("/srv/data/b.db"));
+
+        sim.remove("/srv/data/b.db");
+        assert_eq!(
+            kinds(&b.poll()),
+            vec![(
+                "/srv/data/b.db".to_string(),
+                EventKind::Remove(RemoveKind::File)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_non_recursive_ignores_nested() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media/show");
+        let mut scanner = Scanner::new(sim.clone(), PathBuf::from("/mnt/media"), false);
+
+        sim.write("/mnt/media/show/ep1.mkv", 1);
+        assert!(scanner.poll().is_empty());
+
+        sim.write("/mnt/media/top.mkv", 1);
+        assert_eq!(scanner.poll().len(), 1);
+    }
+
+    #[test]
+    fn test_confirm_deletes_rides_out_flap() {
+        let (sim, scanner) = setup();
+        let mut scanner = scanner.with_delete_confirmation(3, false);
+        sim.mkdir_all("/mnt/media/show");
+        sim.write("/mnt/media/show/ep1.mkv", 1);
+        scanner.poll();
+
+        sim.hide("/mnt/media/show");
+        assert!(scanner.poll().is_empty());
+        assert!(scanner.poll().is_empty());
+        sim.unhide("/mnt/media/show");
+        assert!(scanner.poll().is_empty());
+
+        sim.remove("/mnt/media/show");
+        assert!(scanner.poll().is_empty());
+        assert!(scanner.poll().is_empty());
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                (
+                    "/mnt/media/show/ep1.mkv".to_string(),
+                    EventKind::Remove(RemoveKind::File)
+                ),
+                (
+                    "/mnt/media/show".to_string(),
+                    EventKind::Remove(RemoveKind::Folder)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restat_confirms_deletes() {
+        let (sim, scanner) = setup();
+        let mut scanner = scanner.with_delete_confirmation(3, true);
+        sim.write("/mnt/media/a.mkv", 1);
+        sim.write("/mnt/media/b.mkv", 1);
+        scanner.poll();
+
+        // Still there as far as stat can tell
+        sim.hide("/mnt/media/a.mkv");
+        for _ in 0..5 {
+            assert!(scanner.poll().is_empty());
+        }
+
+        // ENOENT needs no further scans, and renames are not held back
+        sim.remove("/mnt/media/a.mkv");
+        sim.rename("/mnt/media/b.mkv", "/mnt/media/c.mkv");
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![
+                (
+                    "/mnt/media/a.mkv".to_string(),
+                    EventKind::Remove(RemoveKind::File)
+                ),
+                (
+                    "/mnt/media/b.mkv".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                ),
+                (
+                    "/mnt/media/c.mkv".to_string(),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stuck_directory_is_skipped() {
+        use crate::watchdog::{Watchdog, WatchdogSource};
+
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/media/huge/deep");
+        sim.write("/mnt/media/huge/deep/a.mkv", 1);
+        let watchdog = Arc::new(Watchdog::new(Duration::from_millis(20)));
+        let source = Arc::new(WatchdogSource::new(sim.clone(), watchdog.clone()));
+        let mut scanner = Scanner::new(source, PathBuf::from("/mnt/media"), true);
+
+        sim.hang("/mnt/media/huge");
+        sim.write("/mnt/media/ep1.mkv", 1);
+        assert_eq!(
+            kinds(&scanner.poll()),
+            vec![(
+                "/mnt/media/ep1.mkv".to_string(),
+                EventKind::Create(CreateKind::File)
+            )]
+        );
+        assert_eq!(watchdog.stuck_scans(), 1);
+        assert!(
+            scanner
+                .snapshot()
+                .contains_key(Path::new("/mnt/media/huge/deep/a.mkv"))
+        );
+        sim.unhang("/mnt/media/huge");
+    }
+}
@@ -0,0 +1,237 @@
+//! Fair scan scheduling across watches on the same file server.
+//!
+//! Every watched root scans on its own task, so a huge tree on one server
+//! would otherwise keep that server busy while smaller watches on it wait
+//! for their turn and time out their poll interval. [`GatedSource`] makes
+//! each directory read take a ticket from its server's [`FairGate`], which
+//! admits at most `limit` reads at a time in arrival order. Scans on one
+//! server therefore interleave directory by directory, and a slow server
+//! never holds up scans of another.
+
+use crate::source::{EntryMeta, ScanSource, SourceEntry};
+use parking_lot::{Condvar, Mutex};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Filesystem types whose reads go over the network
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const REMOTE_FS_TYPES: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "ceph",
+    "glusterfs",
+    "9p",
+    "fuse.sshfs",
+];
+
+/// FIFO counting semaphore for blocking scan threads
+#[derive(Debug)]
+pub struct FairGate {
+    limit: usize,
+    state: Mutex<GateState>,
+    turn: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    /// Reads in progress
+    active: usize,
+    /// Ticket handed to the next caller
+    next_ticket: u64,
+    /// Lowest ticket not yet admitted
+    serving: u64,
+}
+
+impl FairGate {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            state: Mutex::new(GateState::default()),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Block until it is this caller's turn and a slot is free
+    pub fn enter(&self) -> GatePass<'_> {
+        let mut state = self.state.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket || state.active >= self.limit {
+            self.turn.wait(&mut state);
+        }
+        state.serving += 1;
+        state.active += 1;
+        // The next ticket may fit in a free slot too
+        self.turn.notify_all();
+        GatePass { gate: self }
+    }
+}
+
+/// A slot in a [`FairGate`], released on drop
+pub struct GatePass<'a> {
+    gate: &'a FairGate,
+}
+
+impl Drop for GatePass<'_> {
+    fn drop(&mut self) {
+        self.gate.state.lock().active -= 1;
+        self.gate.turn.notify_all();
+    }
+}
+
+/// [`ScanSource`] that queues reads on remote mounts per server
+pub struct GatedSource {
+    inner: Arc<dyn ScanSource>,
+    /// Mount point and server of every remote mount, deepest first
+    mounts: Vec<(PathBuf, String)>,
+    gates: HashMap<String, FairGate>,
+}
+
+impl GatedSource {
+    /// Allow `limit` concurrent reads per server among `mounts`
+    pub fn new(
+        inner: Arc<dyn ScanSource>,
+        mut mounts: Vec<(PathBuf, String)>,
+        limit: usize,
+    ) -> Self {
+        mounts.sort_by_key(|(point, _)| std::cmp::Reverse(point.components().count()));
+        let gates = mounts
+            .iter()
+            .map(|(_, server)| (server.clone(), FairGate::new(limit)))
+            .collect();
+        Self {
+            inner,
+            mounts,
+            gates,
+        }
+    }
+
+    /// The gate of the server `path` lives on, if it is on a remote mount
+    fn gate(&self, path: &Path) -> Option<&FairGate> {
+        let (_, server) = self
+            .mounts
+            .iter()
+            .find(|(point, _)| path.starts_with(point))?;
+        self.gates.get(server)
+    }
+}
+
+impl ScanSource for GatedSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        let _pass = self.gate(path).map(FairGate::enter);
+        self.inner.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let _pass = self.gate(path).map(FairGate::enter);
+        self.inner.read_dir(path)
+    }
+}
+
+/// The server part of a mount source: `nas` for `nas:/export`,
+/// `user@nas:/x` and `//nas/share`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub fn server_of(source: &str) -> &str {
+    if let Some(unc) = source.strip_prefix("//") {
+        return unc.split('/').next().unwrap_or(unc);
+    }
+    let host = if source.starts_with('[') {
+        source.find(']').map_or(source, |end| &source[..=end])
+    } else {
+        source.split(':').next().unwrap_or(source)
+    };
+    host.rsplit('@').next().unwrap_or(host)
+}
+
+/// Remote mounts visible to the daemon, as (mount point, server)
+#[cfg(target_os = "linux")]
+pub fn remote_mounts() -> Vec<(PathBuf, String)> {
+    let Ok(contents) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return Vec::new();
+    };
+    crate::namespace::parse_mountinfo(&contents)
+        .into_iter()
+        .filter(|mount| REMOTE_FS_TYPES.contains(&mount.fs_type.as_str()))
+        .map(|mount| {
+            let server = server_of(&mount.source).to_string();
+            (mount.mount_point, server)
+        })
+        .collect()
+}
+
+/// Remote mounts visible to the daemon (not detected on this platform)
+#[cfg(not(target_os = "linux"))]
+pub fn remote_mounts() -> Vec<(PathBuf, String)> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::sim::SimSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_server_of() {
+        assert_eq!(server_of("nas:/export/media"), "nas");
+        assert_eq!(server_of("backup@nas:/srv"), "nas");
+        assert_eq!(server_of("//fileserver/share"), "fileserver");
+        assert_eq!(server_of("[fd00::1]:/export"), "[fd00::1]");
+        assert_eq!(server_of("tmpfs"), "tmpfs");
+    }
+
+    #[test]
+    fn test_gate_admits_in_order_within_limit() {
+        let gate = Arc::new(FairGate::new(2));
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let held = gate.enter();
+        let threads: Vec<_> = (0..6)
+            .map(|i| {
+                let (gate, active, peak, order) =
+                    (gate.clone(), active.clone(), peak.clone(), order.clone());
+                // Stagger arrivals so tickets are taken in index order
+                std::thread::sleep(Duration::from_millis(5));
+                std::thread::spawn(move || {
+                    let _pass = gate.enter();
+                    order.lock().push(i);
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        drop(held);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert_eq!(order.lock()[0], 0);
+        assert_eq!(order.lock().len(), 6);
+    }
+
+    #[test]
+    fn test_gated_source_only_gates_remote_mounts() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/nas/show");
+        sim.mkdir_all("/srv/local");
+        let source = GatedSource::new(sim, vec![(PathBuf::from("/mnt/nas"), "nas".to_string())], 1);
+
+        assert!(source.gate(Path::new("/mnt/nas/show")).is_some());
+        assert!(source.gate(Path::new("/srv/local")).is_none());
+
+        // A held slot on the server does not block local reads
+        let _held = source.gate(Path::new("/mnt/nas")).unwrap().enter();
+        assert!(source.read_dir(Path::new("/srv")).is_ok());
+    }
+}
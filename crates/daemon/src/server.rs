@@ -1,14 +1,35 @@
-//! Unix domain socket server for client connections.
+//! Socket server for client connections.
 //!
-//! Handles client requests and manages client lifecycle.
+//! Clients connect over the Unix socket and, when `tcp_listen` or
+//! `vsock_listen` is set, over TCP or from VM guests over vsock. Handles
+//! client requests and manages client lifecycle.
 
-use crate::state::{ClientId, DaemonState};
-use fakenotify_protocol::{EventMask, FramedMessage, Request, Response};
+use crate::config::AccessEvents;
+use crate::filter::EventFilter;
+use crate::json;
+use crate::rate::RATE_WINDOW_SECS;
+use crate::state::{
+    ClientId, ClientWriter, DRAIN_GOODBYE_TIMEOUT, DaemonState, LimitExceeded, Peer, Scope,
+};
+#[cfg(unix)]
+use crate::vsock::VsockListener;
+use fakenotify_protocol::{
+    Capabilities, Cursor, Endpoint, ErrorCode, EventMask, FrameDecoder, FramedMessage,
+    PROTOCOL_VERSION, ProtocolError, Request, Response, UserLimits, WatchRoot, is_valid_tag,
+};
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::broadcast;
+use tokio::sync::{Notify, broadcast, mpsc};
+use tokio::task::JoinSet;
+
+/// Read half of a client connection, whatever the transport
+type ClientReader = Box<dyn AsyncRead + Send + Unpin>;
 
 /// Socket server for handling client connections
 pub struct Server {
@@ -18,6 +39,53 @@ pub struct Server {
     state: Arc<DaemonState>,
     /// Shutdown signal receiver
     shutdown_rx: broadcast::Receiver<()>,
+    /// Listener handed over by the service manager, used instead of binding
+    #[cfg(unix)]
+    inherited: Option<std::os::unix::net::UnixListener>,
+    /// Additional TCP address to accept clients on
+    tcp_addr: Option<SocketAddr>,
+    /// vsock port to accept VM guests on
+    vsock_port: Option<u32>,
+    /// Liveness probing of connected clients
+    liveness: Liveness,
+    /// Per-connection request limits
+    limits: RequestLimits,
+    /// How long clients get to receive their queued events on shutdown
+    drain_timeout: Duration,
+}
+
+/// Bounds on how clients may use their connection
+///
+/// Violating any of them disconnects the client.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Time allowed between a request's length prefix and the end of its body
+    pub read_timeout: Option<Duration>,
+    /// Requests received but not yet answered
+    pub max_in_flight: usize,
+    /// Time a client without watches may go without sending a request
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            read_timeout: Some(Duration::from_secs(10)),
+            max_in_flight: 64,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// How the server notices clients that went away without closing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Liveness {
+    /// Send a heartbeat this often
+    pub heartbeat_interval: Option<Duration>,
+    /// Drop clients silent for this long
+    pub timeout: Option<Duration>,
+    /// Idle time before TCP keepalive probes start
+    pub tcp_keepalive: Option<Duration>,
 }
 
 impl Server {
@@ -31,11 +99,180 @@ impl Server {
             socket_path,
             state,
             shutdown_rx,
+            #[cfg(unix)]
+            inherited: None,
+            tcp_addr: None,
+            vsock_port: None,
+            liveness: Liveness::default(),
+            limits: RequestLimits::default(),
+            drain_timeout: Duration::from_secs(5),
         }
     }
 
+    /// Serve on an already-listening socket instead of binding `socket_path`
+    ///
+    /// The socket file belongs to whoever created the listener, so it is
+    /// neither replaced nor removed on shutdown.
+    #[cfg(unix)]
+    pub fn with_listener(mut self, listener: std::os::unix::net::UnixListener) -> Self {
+        self.inherited = Some(listener);
+        self
+    }
+
+    /// Also accept clients over TCP at `addr`
+    ///
+    /// TCP peers have no credentials, so their limits are accounted against
+    /// the daemon's own uid.
+    pub fn with_tcp(mut self, addr: SocketAddr) -> Self {
+        self.tcp_addr = Some(addr);
+        self
+    }
+
+    /// Also accept VM guests over vsock on `port`
+    ///
+    /// Like TCP peers, guests have no credentials here and are accounted
+    /// against the daemon's own uid.
+    pub fn with_vsock(mut self, port: u32) -> Self {
+        self.vsock_port = Some(port);
+        self
+    }
+
+    /// Probe clients with heartbeats and keepalives
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = liveness;
+        self
+    }
+
+    /// Disconnect clients that break `limits`
+    pub fn with_request_limits(mut self, limits: RequestLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Give clients up to `timeout` on shutdown to receive queued events
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
     /// Run the server
+    ///
+    /// On shutdown, stops accepting, drains every client (queued events,
+    /// then IN_IGNORED for each watch), and only then removes the socket.
     pub async fn run(mut self) -> color_eyre::Result<()> {
+        #[cfg(unix)]
+        let owns_socket = self.inherited.is_none();
+        #[cfg(unix)]
+        let unix = match self.inherited.take() {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                tracing::info!(
+                    socket = %self.socket_path.display(),
+                    "Server listening on inherited socket"
+                );
+                UnixListener::from_std(listener)?
+            }
+            None => self.bind()?,
+        };
+
+        let tcp = match self.tcp_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                tracing::info!(addr = %addr, "Server listening on TCP");
+                Some(listener)
+            }
+            None => None,
+        };
+        #[cfg(unix)]
+        let vsock = match self.vsock_port {
+            Some(port) => {
+                let listener = VsockListener::bind(port)?;
+                tracing::info!(port = port, "Server listening on vsock");
+                Some(listener)
+            }
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if tcp.is_none() {
+            color_eyre::eyre::bail!("daemon.tcp_listen must be set on this platform");
+        }
+        #[cfg(not(unix))]
+        if self.vsock_port.is_some() {
+            color_eyre::eyre::bail!("daemon.vsock_listen is not supported on this platform");
+        }
+
+        let mut handlers = JoinSet::new();
+        loop {
+            #[cfg(unix)]
+            let accepted = tokio::select! {
+                result = accept_unix(&unix) => result,
+                result = accept_tcp(tcp.as_ref(), self.liveness.tcp_keepalive) => result,
+                result = accept_vsock(vsock.as_ref()) => result,
+                Some(_) = handlers.join_next() => continue,
+                _ = self.shutdown_rx.recv() => break,
+            };
+            #[cfg(not(unix))]
+            let accepted = tokio::select! {
+                result = accept_tcp(tcp.as_ref(), self.liveness.tcp_keepalive) => result,
+                Some(_) = handlers.join_next() => continue,
+                _ = self.shutdown_rx.recv() => break,
+            };
+
+            match accepted {
+                Ok((reader, writer, peer)) => {
+                    let state = Arc::clone(&self.state);
+                    let shutdown_rx = self.shutdown_rx.resubscribe();
+                    let policy = ClientPolicy {
+                        liveness: self.liveness,
+                        limits: self.limits,
+                        drain_timeout: self.drain_timeout,
+                    };
+                    handlers.spawn(async move {
+                        if let Err(e) =
+                            handle_client(reader, writer, peer, state, policy, shutdown_rx).await
+                        {
+                            tracing::error!(error = %e, "Client handler error");
+                        }
+                    });
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Accept error");
+                }
+            }
+        }
+        tracing::info!(
+            clients = handlers.len(),
+            "Server shutting down, draining clients"
+        );
+
+        // Stop accepting, then let the handlers drain their clients
+        #[cfg(unix)]
+        drop((unix, vsock));
+        drop(tcp);
+        let drained = tokio::time::timeout(self.drain_timeout + DRAIN_GOODBYE_TIMEOUT, async {
+            while handlers.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                clients = handlers.len(),
+                "Drain timed out, closing remaining clients"
+            );
+            handlers.shutdown().await;
+        }
+
+        // Clean up socket file
+        #[cfg(unix)]
+        if owns_socket && self.socket_path.exists() {
+            let _ = std::fs::remove_file(&self.socket_path);
+        }
+
+        Ok(())
+    }
+
+    /// Replace any stale socket file and bind a fresh listener
+    #[cfg(unix)]
+    fn bind(&self) -> color_eyre::Result<UnixListener> {
         // Remove existing socket file if present
         if self.socket_path.exists() {
             std::fs::remove_file(&self.socket_path)?;
@@ -53,164 +290,1030 @@ impl Server {
         tracing::info!(socket = %self.socket_path.display(), "Server listening");
 
         // Set socket permissions (allow all users to connect)
-        #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
             let permissions = std::fs::Permissions::from_mode(0o666);
             std::fs::set_permissions(&self.socket_path, permissions)?;
         }
 
-        loop {
-            tokio::select! {
-                accept_result = listener.accept() => {
-                    match accept_result {
-                        Ok((stream, _addr)) => {
-                            let state = Arc::clone(&self.state);
-                            let shutdown_rx = self.shutdown_rx.resubscribe();
-                            tokio::spawn(async move {
-                                if let Err(e) = handle_client(stream, state, shutdown_rx).await {
-                                    tracing::error!(error = %e, "Client handler error");
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            tracing::error!(error = %e, "Accept error");
-                        }
-                    }
-                }
-                _ = self.shutdown_rx.recv() => {
-                    tracing::info!("Server shutting down");
-                    break;
-                }
-            }
-        }
+        Ok(listener)
+    }
+}
 
-        // Clean up socket file
-        if self.socket_path.exists() {
-            let _ = std::fs::remove_file(&self.socket_path);
-        }
+/// Accept a Unix socket client along with its peer credentials
+#[cfg(unix)]
+async fn accept_unix(
+    listener: &UnixListener,
+) -> std::io::Result<(ClientReader, ClientWriter, Peer)> {
+    let (stream, _addr) = listener.accept().await?;
+    // A peer whose credentials are unknown is trusted no more than a
+    // network one
+    let peer = stream
+        .peer_cred()
+        .map(|cred| Peer {
+            uid: cred.uid(),
+            // 0 when the peer's pid namespace is not visible from ours
+            pid: cred.pid().filter(|&pid| pid > 0),
+            local: true,
+        })
+        .unwrap_or_else(|_| Peer::remote());
+    let (read_half, write_half) = stream.into_split();
+    Ok((Box::new(read_half), Box::new(write_half), peer))
+}
 
-        Ok(())
+/// Accept a TCP client, or wait forever if TCP is not enabled
+async fn accept_tcp(
+    listener: Option<&TcpListener>,
+    keepalive: Option<Duration>,
+) -> std::io::Result<(ClientReader, ClientWriter, Peer)> {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    let (stream, addr) = listener.accept().await?;
+    tracing::debug!(peer = %addr, "TCP client connected");
+    // Events are small and latency matters more than throughput
+    stream.set_nodelay(true)?;
+    // Let the OS notice peers that vanished, e.g. a suspended laptop
+    if let Some(idle) = keepalive {
+        let params = socket2::TcpKeepalive::new()
+            .with_time(idle)
+            .with_interval(idle / 4);
+        socket2::SockRef::from(&stream).set_tcp_keepalive(&params)?;
     }
+    let (read_half, write_half) = stream.into_split();
+    Ok((Box::new(read_half), Box::new(write_half), Peer::remote()))
+}
+
+/// Accept a VM guest over vsock, or wait forever if vsock is not enabled
+#[cfg(unix)]
+async fn accept_vsock(
+    listener: Option<&VsockListener>,
+) -> std::io::Result<(ClientReader, ClientWriter, Peer)> {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    let (stream, cid) = listener.accept().await?;
+    tracing::debug!(cid = ?cid, "vsock client connected");
+    let (read_half, write_half) = stream.into_split();
+    // Every guest may run anything as any user, so none is trusted
+    Ok((Box::new(read_half), Box::new(write_half), Peer::remote()))
+}
+
+/// The daemon's own uid
+#[cfg(unix)]
+pub fn own_uid() -> u32 {
+    // SAFETY: getuid has no preconditions
+    unsafe { libc::getuid() }
+}
+
+/// The daemon's own uid (uids do not exist on this platform)
+#[cfg(not(unix))]
+pub fn own_uid() -> u32 {
+    0
+}
+
+/// Server settings that apply to each connection
+#[derive(Debug, Clone, Copy, Default)]
+struct ClientPolicy {
+    liveness: Liveness,
+    limits: RequestLimits,
+    /// How long the client gets to receive queued events on shutdown
+    drain_timeout: Duration,
 }
 
 /// Handle a single client connection
 async fn handle_client(
-    stream: UnixStream,
+    mut reader: ClientReader,
+    writer: ClientWriter,
+    peer: Peer,
     state: Arc<DaemonState>,
+    policy: ClientPolicy,
     mut shutdown_rx: broadcast::Receiver<()>,
 ) -> color_eyre::Result<()> {
-    let (read_half, write_half) = stream.into_split();
-
     // Register the client
-    let client = state.register_client(write_half);
-    let client_id = client.id;
+    let client = match state.register_client(writer, peer) {
+        Ok(client) => client,
+        Err(mut writer) => {
+            let limit = LimitExceeded::Instances.to_string();
+            if let Some(audit) = state.audit() {
+                audit.refused(peer, &limit);
+            }
+            let response = Response::LimitExceeded { limit };
+            writer
+                .write_all(&FramedMessage::frame(&response.to_bytes()?))
+                .await?;
+            return Ok(());
+        }
+    };
+    let mut client_id = client.id;
+    if let Some(audit) = state.audit() {
+        audit.connected(client_id, peer);
+    }
+    let ClientPolicy {
+        liveness,
+        limits,
+        drain_timeout,
+    } = policy;
 
     // Send registration response
-    let response = Response::ClientRegistered { client_id };
+    let response = Response::ClientRegistered {
+        client_id,
+        session: client.session.clone(),
+        capabilities: 0,
+    };
     send_response(&client, &response).await?;
 
-    // Read loop
-    let mut reader = tokio::io::BufReader::new(read_half);
-    let mut len_buf = [0u8; 4];
+    let dead = Arc::new(Notify::new());
+    let mut heartbeat = tokio::spawn(run_heartbeat(
+        Arc::clone(&client),
+        liveness,
+        Arc::clone(&dead),
+    ));
 
-    loop {
-        tokio::select! {
-            read_result = reader.read_exact(&mut len_buf) => {
-                match read_result {
-                    Ok(_) => {
-                        let len = u32::from_le_bytes(len_buf) as usize;
-
-                        // Sanity check message size
-                        if len > FramedMessage::MAX_SIZE {
-                            tracing::warn!(client_id = client_id, len = len, "Message too large");
-                            break;
-                        }
+    // Requests are answered in order on their own task, so a client that
+    // stops reading responses is noticed by its queue filling up
+    let (request_tx, request_rx) = mpsc::channel(limits.max_in_flight.max(1));
+    let mut responder = tokio::spawn(answer_requests(
+        Arc::clone(&state),
+        Arc::clone(&client),
+        request_rx,
+        Arc::clone(&dead),
+    ));
+    let mut client = client;
 
-                        // Read the message payload
-                        let mut payload = vec![0u8; len];
-                        if reader.read_exact(&mut payload).await.is_err() {
-                            break;
-                        }
+    // Read loop. Reads go into the decoder as they come, so one cut short
+    // by another branch firing never loses the bytes of a request
+    let mut decoder = FrameDecoder::new();
+    let mut chunk = vec![0u8; 8192];
+    // When the request now partly received started arriving
+    let mut partial_since = None;
+    let mut draining = false;
+    let mut last_request = tokio::time::Instant::now();
 
-                        // Parse and handle the request
-                        match Request::from_bytes(&payload) {
-                            Ok(request) => {
-                                let response = handle_request(&state, client_id, request).await;
-                                if let Err(e) = send_response(&client, &response).await {
-                                    tracing::error!(
-                                        client_id = client_id,
-                                        error = %e,
-                                        "Failed to send response"
-                                    );
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                tracing::warn!(
-                                    client_id = client_id,
-                                    error = %e,
-                                    "Invalid request"
-                                );
-                                let response = Response::Error {
-                                    message: format!("Invalid request: {}", e),
-                                };
-                                let _ = send_response(&client, &response).await;
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        // Client disconnected
-                        break;
-                    }
+    'read: loop {
+        let mut received = false;
+        loop {
+            let request = match next_request(&mut decoder) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(len) => {
+                    tracing::warn!(client_id = client_id, len = len, "Message too large");
+                    break 'read;
                 }
+            };
+            received = true;
+            client.touch();
+            if matches!(request, Ok(Request::HeartbeatAck)) {
+                continue;
+            }
+            last_request = tokio::time::Instant::now();
+            if request_tx.try_send(request).is_err() {
+                tracing::warn!(
+                    client_id = client_id,
+                    max_in_flight = limits.max_in_flight,
+                    "Too many requests in flight, disconnecting"
+                );
+                break 'read;
+            }
+        }
+        // The rest of a request must follow its start promptly
+        partial_since = match (decoder.is_empty(), partial_since) {
+            (true, _) => None,
+            (false, Some(since)) if !received => Some(since),
+            (false, _) => Some(tokio::time::Instant::now()),
+        };
+        let body_deadline = partial_since
+            .zip(limits.read_timeout)
+            .map(|(since, timeout)| since + timeout);
+
+        let idle_deadline = limits.idle_timeout.map(|timeout| last_request + timeout);
+        tokio::select! {
+            read = reader.read(&mut chunk) => {
+                match read {
+                    // Client disconnected
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => decoder.push(&chunk[..n]),
+                }
+            }
+            _ = sleep_until_deadline(body_deadline) => {
+                tracing::warn!(
+                    client_id = client_id,
+                    buffered = decoder.buffered().len(),
+                    "Request body not received in time, disconnecting"
+                );
+                break;
+            }
+            _ = sleep_until_deadline(idle_deadline) => {
+                if client.watches.read().is_empty() {
+                    tracing::info!(client_id = client_id, "Idle client without watches, disconnecting");
+                    break;
+                }
+                last_request = tokio::time::Instant::now();
+            }
+            answered = &mut responder => {
+                // The responder only stops early when the connection was
+                // handed to a resumed client, which it then serves
+                let Ok(Some((resumed, requests))) = answered else {
+                    break;
+                };
+                tracing::info!(client_id = client_id, resumed = resumed.id, "Session resumed");
+                client = resumed;
+                client_id = client.id;
+                client.touch();
+                heartbeat.abort();
+                heartbeat = tokio::spawn(run_heartbeat(
+                    Arc::clone(&client),
+                    liveness,
+                    Arc::clone(&dead),
+                ));
+                responder = tokio::spawn(answer_requests(
+                    Arc::clone(&state),
+                    Arc::clone(&client),
+                    requests,
+                    Arc::clone(&dead),
+                ));
+            }
+            _ = dead.notified() => {
+                break;
+            }
+            _ = client.broken() => {
+                break;
+            }
+            _ = client.evicted() => {
+                // Released below as a client that is already gone
+                state.unregister_client(client_id);
+                break;
             }
             _ = shutdown_rx.recv() => {
                 tracing::debug!(client_id = client_id, "Client handler received shutdown signal");
+                draining = true;
                 break;
             }
         }
     }
 
-    // Unregister the client
-    state.unregister_client(client_id);
+    heartbeat.abort();
+    responder.abort();
+    if draining {
+        client.drain(drain_timeout).await;
+        state.unregister_client(client_id);
+    } else {
+        release_client(&state, &client);
+    }
+    if let Some(audit) = state.audit() {
+        audit.disconnected(client_id);
+    }
 
     Ok(())
 }
 
-/// Handle a single request
-async fn handle_request(state: &DaemonState, client_id: ClientId, request: Request) -> Response {
-    match request {
-        Request::RegisterClient => {
-            // Already registered during connection
-            Response::ClientRegistered { client_id }
+/// The next whole request the decoder holds, binary or a JSON line
+///
+/// Fails with the length of a frame or line too large to accept.
+fn next_request(
+    decoder: &mut FrameDecoder,
+) -> Result<Option<Result<Request, ProtocolError>>, usize> {
+    let buffered = decoder.buffered();
+    if buffered.len() < 4 {
+        return Ok(None);
+    }
+    if json::starts_line(buffered) {
+        let end = buffered.iter().position(|&b| b == b'\n');
+        return match end {
+            Some(end) if end < FramedMessage::MAX_SIZE => {
+                let request = json::parse_request(&buffered[..=end]);
+                decoder.consume(end + 1);
+                Ok(Some(request))
+            }
+            Some(end) => Err(end),
+            None if buffered.len() > FramedMessage::MAX_SIZE => Err(buffered.len()),
+            None => Ok(None),
+        };
+    }
+    match decoder.next_frame() {
+        Ok(frame) => Ok(frame.map(Request::from_bytes)),
+        Err(_) => Err(FramedMessage::read_length(decoder.buffered()).unwrap_or(0) as usize),
+    }
+}
+
+/// Unregister a client whose connection is gone, or with
+/// `session_timeout` set, keep it that long for a connection to resume
+fn release_client(state: &Arc<DaemonState>, client: &Arc<crate::state::Client>) {
+    let timeout = state.session_timeout();
+    // A control connection's own client is gone already
+    if timeout.is_zero() || state.get_client(client.id).is_none() {
+        state.unregister_client(client.id);
+        return;
+    }
+    let detached = client.detach();
+    tracing::info!(
+        client_id = client.id,
+        timeout_secs = timeout.as_secs(),
+        "Client detached, keeping its session"
+    );
+    let state = Arc::clone(state);
+    let client = Arc::clone(client);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => {
+                if client.detached_since(detached) {
+                    tracing::info!(client_id = client.id, "Session expired");
+                    state.unregister_client(client.id);
+                }
+            }
+            _ = client.evicted() => {
+                state.unregister_client(client.id);
+            }
         }
+    });
+}
 
-        Request::AddWatch { path, mask } => {
-            let event_mask = EventMask::from_bits_truncate(mask);
+/// Requests still to answer, handed over with the client a connection
+/// resumed
+type Resumed = (
+    Arc<crate::state::Client>,
+    mpsc::Receiver<Result<Request, ProtocolError>>,
+);
 
-            // Validate path exists
-            if !path.exists() {
-                return Response::Error {
-                    message: format!("Path does not exist: {}", path.display()),
-                };
+/// Answer a client's requests in the order they arrived
+///
+/// Raises `dead` if a response cannot be written. Stops at a successful
+/// `Resume`, returning the resumed client, which the connection now
+/// belongs to, and the requests after it.
+async fn answer_requests(
+    state: Arc<DaemonState>,
+    client: Arc<crate::state::Client>,
+    mut requests: mpsc::Receiver<Result<Request, ProtocolError>>,
+    dead: Arc<Notify>,
+) -> Option<Resumed> {
+    let client_id = client.id;
+    // The client requests act for, another one once this is a control
+    // connection
+    let mut acting_for = client_id;
+    while let Some(request) = requests.recv().await {
+        let response = match request {
+            Ok(Request::AttachControl { session }) => {
+                attach_control(&state, &client, &mut acting_for, &session)
+            }
+            Ok(Request::Resume { session }) if acting_for == client_id => {
+                match resume_session(&state, &client, &session).await {
+                    Ok(resumed) => return Some((resumed, requests)),
+                    Err(response) => response,
+                }
+            }
+            Ok(request) => match handle_request(&state, acting_for, request).await {
+                Some(response) => response,
+                None => continue,
+            },
+            Err(ProtocolError::VersionMismatch(version)) => {
+                tracing::warn!(
+                    client_id = client_id,
+                    protocol_version = version,
+                    "Client speaks another protocol version"
+                );
+                version_mismatch(version)
             }
+            Err(e) => {
+                tracing::warn!(client_id = client_id, error = %e, "Invalid request");
+                Response::error(format!("Invalid request: {}", e))
+            }
+        };
+        if let Err(e) = send_answer(&state, &client, &response).await {
+            tracing::error!(client_id = client_id, error = %e, "Failed to send response");
+            dead.notify_one();
+            return None;
+        }
+        let response = match response {
+            Response::Tagged { response, .. } => *response,
+            response => response,
+        };
+        // Only once the caller has its answer, which shutdown would cut off
+        if matches!(response, Response::ShuttingDown { .. }) {
+            tracing::info!(client_id = client_id, "Shutdown requested by client");
+            state.request_shutdown();
+        }
+        // After the answer too, so the caller knows the descriptor first
+        match &response {
+            Response::WatchAdded { wd } | Response::WatchClamped { wd, .. } => {
+                state.catch_up(acting_for, *wd)
+            }
+            Response::Batch { results, .. } => {
+                for result in results {
+                    if let Response::WatchAdded { wd } | Response::WatchClamped { wd, .. } = result
+                    {
+                        state.catch_up(acting_for, *wd);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
 
-            let wd = state.add_watch(client_id, path, event_mask, true);
-            Response::WatchAdded { wd }
+/// Make `client`'s connection a control connection of the client holding
+/// `session`, pointing `acting_for` at that client
+fn attach_control(
+    state: &DaemonState,
+    client: &crate::state::Client,
+    acting_for: &mut ClientId,
+    session: &str,
+) -> Response {
+    if *acting_for != client.id || !client.watches.read().is_empty() {
+        return Response::error("AttachControl must be the first request on a connection");
+    }
+    match state.client_by_session(session) {
+        Some(owner) if owner.uid == client.uid && owner.id != client.id => {
+            // The connection stops being a client of its own, so it holds
+            // no instance and is sent no events
+            state.unregister_client(client.id);
+            *acting_for = owner.id;
+            tracing::debug!(
+                client_id = client.id,
+                owner = owner.id,
+                "Control connection attached"
+            );
+            Response::ControlAttached {
+                client_id: owner.id,
+            }
         }
+        _ => Response::error_with(ErrorCode::PermissionDenied, "Unknown session"),
+    }
+}
+
+/// Hand `client`'s connection to the detached client holding `session`,
+/// answering on it before any of the events queued while it was away
+///
+/// Returns the resumed client, or the refusal to send.
+async fn resume_session(
+    state: &DaemonState,
+    client: &crate::state::Client,
+    session: &str,
+) -> Result<Arc<crate::state::Client>, Response> {
+    if !client.watches.read().is_empty() {
+        return Err(Response::error(
+            "Resume must be the first request on a connection",
+        ));
+    }
+    let unknown = || Response::error_with(ErrorCode::PermissionDenied, "Unknown session");
+    let resumed = state
+        .client_by_session(session)
+        .filter(|resumed| resumed.uid == client.uid && resumed.id != client.id)
+        .ok_or_else(unknown)?;
+
+    // Holding the writer keeps the event writer off it until the answer
+    // is out
+    let mut writer = resumed.writer.lock().await;
+    if !resumed.reattach() {
+        return Err(unknown());
+    }
+    *writer = std::mem::replace(
+        &mut *client.writer.lock().await,
+        Box::new(tokio::io::sink()),
+    );
+    state.unregister_client(client.id);
+    let answer = Response::Resumed {
+        client_id: resumed.id,
+        capabilities: resumed.capabilities().bits(),
+    };
+    if let Ok(payload) = answer.to_bytes()
+        && let Err(e) = writer.write_all(&FramedMessage::frame(&payload)).await
+    {
+        tracing::debug!(client_id = resumed.id, error = %e, "Failed to answer resume");
+    }
+    drop(writer);
+    Ok(resumed)
+}
 
-        Request::RemoveWatch { wd } => {
-            if state.remove_watch(client_id, wd) {
-                Response::WatchRemoved
+/// Wait until `deadline`, or forever if there is none
+async fn sleep_until_deadline(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Send heartbeats to a client and raise `dead` once it has been silent
+/// for longer than the timeout
+async fn run_heartbeat(client: Arc<crate::state::Client>, liveness: Liveness, dead: Arc<Notify>) {
+    let period = match (liveness.heartbeat_interval, liveness.timeout) {
+        (Some(interval), _) => interval,
+        // Without heartbeats, still check for silence now and then
+        (None, Some(timeout)) => timeout / 2,
+        (None, None) => return,
+    };
+    let mut ticker = tokio::time::interval(period);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        // Raw JSON consumers cannot answer heartbeats; dead ones are found
+        // when writes fail
+        if client.capabilities().contains(Capabilities::JSON) {
+            continue;
+        }
+
+        // Only clients that answer from a background reader are expected
+        // to keep up; one whose user is slow to read is still alive
+        if let Some(timeout) = liveness.timeout
+            && client.capabilities().contains(Capabilities::HEARTBEAT)
+            && client.idle() > timeout
+        {
+            tracing::warn!(
+                client_id = client.id,
+                idle_secs = client.idle().as_secs(),
+                "Client stopped responding, disconnecting"
+            );
+            dead.notify_one();
+            return;
+        }
+
+        if liveness.heartbeat_interval.is_some() {
+            // A peer that stopped reading must not wedge this task
+            let send = send_response(&client, &Response::Heartbeat);
+            if tokio::time::timeout(period, send).await.is_err() {
+                tracing::debug!(client_id = client.id, "Heartbeat write timed out");
+            }
+        }
+    }
+}
+
+/// The refusal of a client registering with protocol `version`
+fn version_mismatch(version: u32) -> Response {
+    Response::error_with(
+        ErrorCode::VersionMismatch,
+        format!("Client speaks protocol version {version}, the daemon {PROTOCOL_VERSION}"),
+    )
+}
+
+/// Handle a single request, returning the response to send (if any)
+async fn handle_request(
+    state: &DaemonState,
+    client_id: ClientId,
+    request: Request,
+) -> Option<Response> {
+    let response = match request {
+        Request::RegisterClient {
+            protocol_version, ..
+        } if protocol_version != PROTOCOL_VERSION => {
+            tracing::warn!(
+                client_id = client_id,
+                protocol_version,
+                "Client speaks another protocol version"
+            );
+            version_mismatch(protocol_version)
+        }
+
+        Request::RegisterClient {
+            info, capabilities, ..
+        } => {
+            // Already registered during connection; this only names it and
+            // settles which features to use
+            let capabilities = Capabilities::from_bits_truncate(capabilities);
+            let registered = state
+                .set_client_info(client_id, info, capabilities)
+                .zip(state.get_client(client_id));
+            match registered {
+                Some((capabilities, client)) => Response::ClientRegistered {
+                    client_id,
+                    session: client.session.clone(),
+                    capabilities: capabilities.bits(),
+                },
+                None => Response::error("Client not registered"),
+            }
+        }
+
+        Request::AddWatch { path, mask } => add_watch(state, client_id, &path, mask),
+
+        Request::AddWatchSince { path, mask, since } => {
+            add_watch_since(state, client_id, &path, mask, since)
+        }
+
+        Request::RemoveWatch { wd } => remove_watch(state, client_id, wd),
+
+        Request::Ping => Response::Pong,
+
+        Request::SetPathMap { mappings } => {
+            if state.set_client_path_map(client_id, mappings) {
+                Response::PathMapSet
             } else {
-                Response::Error {
-                    message: format!("Watch descriptor {} not found", wd),
+                Response::error("Client not registered")
+            }
+        }
+
+        Request::SetFilter { filter } => match EventFilter::compile(&filter) {
+            Ok(filter) => {
+                if state.set_client_filter(client_id, filter) {
+                    Response::FilterSet
+                } else {
+                    Response::error("Client not registered")
                 }
             }
+            Err(e) => Response::error(format!("Invalid name filter: {e}")),
+        },
+
+        Request::Stats { top } => {
+            let stats = state.stats();
+            let (mut clients, mut watches) = (stats.total_clients, stats.total_watches);
+            let mut users = stats.users;
+            let confined = state.scope(client_id) != Scope::All;
+            // Other namespaces' paths are filtered out before picking the top
+            let busiest = state.busiest(if confined { usize::MAX } else { top as usize });
+            let window_secs = if busiest.is_some() {
+                RATE_WINDOW_SECS as u32
+            } else {
+                0
+            };
+            let (mut watch_rates, mut dir_rates) = busiest.unwrap_or_default();
+            let (mut shared_roots, scans_saved) = state.scan_sharing();
+            let mut degraded_mounts = state.mount_health().report();
+            if confined {
+                let visible = state.list_watches(client_id);
+                watch_rates.retain(|rate| visible.iter().any(|watch| watch.path == rate.path));
+                dir_rates.retain(|rate| {
+                    visible
+                        .iter()
+                        .any(|watch| rate.path.starts_with(&watch.path))
+                });
+                watch_rates.truncate(top as usize);
+                dir_rates.truncate(top as usize);
+                let seen = state.list_clients(client_id);
+                clients = seen.len();
+                watches = visible.len();
+                // Only the users a confined client sees clients of
+                users.retain(|usage| seen.iter().any(|client| client.uid == usage.uid));
+                // Only the roots and mounts its own watches are on
+                shared_roots.retain(|root| visible.iter().any(|watch| watch.path == *root));
+                degraded_mounts.retain(|mount| {
+                    visible
+                        .iter()
+                        .any(|watch| watch.path.starts_with(&mount.path))
+                });
+            }
+            Response::Stats {
+                uptime_secs: stats.uptime_secs,
+                clients: clients as u32,
+                watches: watches as u32,
+                window_secs,
+                watch_rates,
+                dir_rates,
+                degraded_mounts,
+                stuck_scans: state.stuck_scans(),
+                dropped_events: state.dropped_events(),
+                shared_roots: shared_roots.len() as u32,
+                scans_saved,
+                limits: UserLimits {
+                    max_user_watches: stats.limits.max_user_watches as u64,
+                    max_user_instances: stats.limits.max_user_instances as u64,
+                    max_queued_events: stats.limits.max_queued_events as u64,
+                },
+                users,
+            }
         }
 
-        Request::Ping => Response::Pong,
+        Request::AddWatchBatch { entries } if entries.is_empty() => {
+            Response::error("Batch has no watches")
+        }
+        Request::AddWatchBatch { entries } => Response::batch(
+            entries
+                .iter()
+                .map(|entry| add_watch(state, client_id, &entry.path, entry.mask))
+                .collect(),
+        ),
+
+        Request::RemoveWatchBatch { wds } if wds.is_empty() => {
+            Response::error("Batch has no watch descriptors")
+        }
+        Request::RemoveWatchBatch { wds } => Response::batch(
+            wds.into_iter()
+                .map(|wd| remove_watch(state, client_id, wd))
+                .collect(),
+        ),
+
+        // Receiving it already counted as activity
+        Request::HeartbeatAck => return None,
+
+        Request::Prune => {
+            let mut missing = crate::prune::missing_watches(state).await;
+            // Other namespaces' watches are left to their own clients
+            if let Some(owned) = state.owned_watches(client_id) {
+                missing.retain(|wd| owned.contains(wd));
+            }
+            Response::Pruned {
+                paths: state
+                    .prune_missing(&missing, Duration::ZERO)
+                    .into_iter()
+                    .map(|watch| watch.path)
+                    .collect(),
+            }
+        }
+
+        Request::TagWatch { wd, tags } => {
+            let subscribed = state
+                .visible_watch(client_id, wd)
+                .map(|watch| watch.clients.contains(&client_id));
+            match subscribed {
+                None => Response::error_with(
+                    ErrorCode::NoSuchWatch,
+                    format!("Watch descriptor {} not found", wd),
+                ),
+                Some(false) if !is_administrator(state, client_id) => Response::error_with(
+                    ErrorCode::PermissionDenied,
+                    "Only root or the daemon's user may tag other clients' watches",
+                ),
+                Some(_) => {
+                    if let Some(tag) = tags.iter().find(|tag| !is_valid_tag(tag)) {
+                        Response::error(format!(
+                            "Invalid tag `{tag}`: tags are non-empty, without whitespace or commas"
+                        ))
+                    } else if state.tag_watch(wd, tags) {
+                        Response::WatchTagged
+                    } else {
+                        Response::error_with(
+                            ErrorCode::NoSuchWatch,
+                            format!("Watch descriptor {} not found", wd),
+                        )
+                    }
+                }
+            }
+        }
+
+        Request::RemoveTagged { tag } => {
+            if !is_administrator(state, client_id) {
+                Response::error_with(
+                    ErrorCode::PermissionDenied,
+                    "Only root or the daemon's user may remove other clients' watches",
+                )
+            } else {
+                // An administrator in a namespace stays within it
+                let owned = state.owned_watches(client_id);
+                Response::Removed {
+                    paths: state
+                        .remove_tagged(&tag, owned.as_ref())
+                        .into_iter()
+                        .map(|watch| watch.path)
+                        .collect(),
+                }
+            }
+        }
+
+        Request::Hello => Response::Hello {
+            roots: state.roots().to_vec(),
+            capabilities: state.capabilities().bits(),
+        },
+
+        Request::HelloNamespace { namespace } => {
+            if !is_valid_tag(&namespace) {
+                Response::error(format!(
+                    "Invalid namespace `{namespace}`: namespaces are non-empty, without whitespace or commas"
+                ))
+            } else if state.join_namespace(client_id, &namespace).is_none() {
+                Response::error("Client not registered")
+            } else {
+                Response::Hello {
+                    roots: state.roots().to_vec(),
+                    capabilities: state.capabilities().bits(),
+                }
+            }
+        }
+
+        Request::ListWatches => Response::Watches {
+            watches: state.list_watches(client_id),
+        },
+
+        Request::GetWatchInfo { wd } => match state.visible_watch(client_id, wd) {
+            Some(watch) => {
+                let scan = state.scan_info(&watch.path);
+                let tags = state.watch_tags(&watch);
+                Response::WatchInfo {
+                    info: fakenotify_protocol::WatchInfo {
+                        wd,
+                        path: watch.path,
+                        mask: watch.mask.bits(),
+                        recursive: watch.recursive,
+                        clients: watch.clients.len() as u32,
+                        snapshot_entries: scan.as_ref().map_or(0, |scan| scan.entries),
+                        last_scan_ms_ago: scan
+                            .as_ref()
+                            .and_then(|scan| scan.scanned_at)
+                            .map(|at| at.elapsed().as_millis() as u64),
+                        root: scan.map(|scan| scan.root),
+                        tags,
+                    },
+                }
+            }
+            None => Response::error_with(
+                ErrorCode::NoSuchWatch,
+                format!("Watch descriptor {} not found", wd),
+            ),
+        },
+
+        Request::ListClients => Response::Clients {
+            clients: state.list_clients(client_id),
+        },
+
+        Request::ExportRoots => Response::Roots {
+            roots: state.root_configs().iter().map(WatchRoot::from).collect(),
+        },
+
+        Request::ImportRoots { roots } => {
+            // New roots are scanned with the daemon's rights
+            if !is_administrator(state, client_id) {
+                Response::error_with(
+                    ErrorCode::PermissionDenied,
+                    "Only root or the daemon's user may import watches",
+                )
+            } else {
+                let outcome = state.import_roots(roots.into_iter().map(Into::into).collect());
+                Response::Imported {
+                    added: outcome.added,
+                    existing: outcome.existing,
+                    failed: outcome.failed,
+                }
+            }
+        }
+
+        Request::Shutdown => {
+            if !is_administrator(state, client_id) {
+                Response::error_with(
+                    ErrorCode::PermissionDenied,
+                    "Only root or the daemon's user may stop the daemon",
+                )
+            } else if !state.can_shut_down() {
+                Response::error("Shutdown is not available")
+            } else {
+                Response::ShuttingDown {
+                    pid: std::process::id(),
+                }
+            }
+        }
+
+        Request::Status => {
+            let (event_queue, event_queue_capacity) = state.event_queue();
+            Response::Status {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol_version: PROTOCOL_VERSION,
+                event_queue: event_queue as u32,
+                event_queue_capacity: event_queue_capacity as u32,
+                client_queue_capacity: state.client_queue_capacity() as u32,
+                mounts: state.mount_health().status(&state.roots()),
+                last_error: crate::last_error::last_error(),
+            }
+        }
+
+        // Only meaningful untagged, where `answer_requests` takes them
+        Request::AttachControl { .. } => {
+            Response::error("AttachControl must be the first request on a connection")
+        }
+        Request::Resume { .. } => {
+            Response::error("Resume must be the first request on a connection")
+        }
+
+        Request::Tagged { id, request } => {
+            let response = if matches!(*request, Request::Tagged { .. }) {
+                Response::error("Tagged requests do not nest")
+            } else {
+                Box::pin(handle_request(state, client_id, *request)).await?
+            };
+            Response::Tagged {
+                id,
+                response: Box::new(response),
+            }
+        }
+    };
+    Some(response)
+}
+
+/// Whether the client is a local process running as root or as the
+/// daemon's own user
+fn is_administrator(state: &DaemonState, client_id: ClientId) -> bool {
+    state
+        .get_client(client_id)
+        .is_some_and(|client| client.is_administrator())
+}
+
+/// Add one watch for a client, recording the outcome in the audit trail
+pub fn add_watch(state: &DaemonState, client_id: ClientId, path: &Path, mask: u32) -> Response {
+    let response = try_add_watch(state, client_id, path, mask);
+    if let Some(audit) = state.audit() {
+        audit.add_watch(client_id, path, mask, &response);
+    }
+    response
+}
+
+/// Add one watch for a client
+fn try_add_watch(state: &DaemonState, client_id: ClientId, path: &Path, mask: u32) -> Response {
+    let event_mask = EventMask::from_bits_truncate(mask);
+    let path = state.on_disk_case(&state.host_path(client_id, path));
+
+    // Validate path exists
+    if !path.exists() {
+        return Response::error_with(
+            ErrorCode::NotFound,
+            format!("Path does not exist: {}", path.display()),
+        );
+    }
+    // Files can be watched too, unless the caller asked for a directory
+    if event_mask.contains(EventMask::IN_ONLYDIR) && !path.is_dir() {
+        return Response::error_with(
+            ErrorCode::NotDirectory,
+            format!("Not a directory: {}", path.display()),
+        );
+    }
+
+    // Events the daemon denies here are dropped from the mask, not refused,
+    // so a consumer asking for everything still gets what it may have
+    let denied = state.denied_events(&path) & event_mask & EventMask::IN_ALL_EVENTS;
+    let event_mask = event_mask - denied;
+    if !denied.is_empty() {
+        if !event_mask.intersects(EventMask::IN_ALL_EVENTS) {
+            return Response::error_with(
+                ErrorCode::PermissionDenied,
+                format!(
+                    "Every event asked for is denied on {}: {denied:?}",
+                    path.display()
+                ),
+            );
+        }
+        tracing::warn!(
+            client_id = client_id,
+            path = %path.display(),
+            denied = ?denied,
+            "Watch mask clamped"
+        );
+    }
+    // Scans see neither reads nor opens; say so rather than never send them
+    let unseen = event_mask & (EventMask::IN_ACCESS | EventMask::IN_OPEN);
+    if state.access_events() == AccessEvents::Reject && !unseen.is_empty() && state.is_polled(&path)
+    {
+        return Response::error_with(
+            ErrorCode::UnsupportedMask,
+            format!(
+                "{} is polled, which cannot observe {unseen:?}",
+                path.display()
+            ),
+        );
+    }
+    let clamp = !denied.is_empty()
+        && state
+            .get_client(client_id)
+            .is_some_and(|client| client.capabilities().contains(Capabilities::MASK_CLAMP));
+
+    match state.add_watch(client_id, path, event_mask, true) {
+        Ok(wd) if clamp => Response::WatchClamped {
+            wd,
+            mask: event_mask.bits(),
+        },
+        Ok(wd) => Response::WatchAdded { wd },
+        Err(limit) => Response::LimitExceeded {
+            limit: limit.to_string(),
+        },
+    }
+}
+
+/// Add a watch whose events are journaled, sending the client what it
+/// missed since `since`
+fn add_watch_since(
+    state: &DaemonState,
+    client_id: ClientId,
+    path: &Path,
+    mask: u32,
+    since: Option<Cursor>,
+) -> Response {
+    let Some(journal) = state.journal() else {
+        return Response::error("The event journal is disabled (journal_size = 0)");
+    };
+    let paused = journal.pause();
+    let wd = match add_watch(state, client_id, path, mask) {
+        Response::WatchAdded { wd } | Response::WatchClamped { wd, .. } => wd,
+        response => return response,
+    };
+    match (state.get_watch(wd), state.get_client(client_id)) {
+        (Some(watch), Some(client)) => paused.open(&watch, &client, since),
+        _ => Response::error("Client not registered"),
+    }
+}
+
+/// Remove one of a client's watches
+pub fn remove_watch(state: &DaemonState, client_id: ClientId, wd: i32) -> Response {
+    let removed = match state.audit() {
+        Some(audit) => {
+            let path = state.get_watch(wd).map(|watch| watch.path);
+            let removed = state.remove_watch(client_id, wd);
+            audit.remove_watch(client_id, wd, path.as_deref(), removed);
+            removed
+        }
+        None => state.remove_watch(client_id, wd),
+    };
+    if removed {
+        Response::WatchRemoved
+    } else {
+        Response::error_with(
+            ErrorCode::NoSuchWatch,
+            format!("Watch descriptor {} not found", wd),
+        )
     }
 }
 
@@ -219,15 +1322,59 @@ async fn send_response(
     client: &crate::state::Client,
     response: &Response,
 ) -> color_eyre::Result<()> {
-    let payload = response.to_bytes()?;
-    let framed = FramedMessage::frame(&payload);
-    client.send_event(&framed).await?;
+    let mut framed = Vec::new();
+    FramedMessage::encode_into(response, &mut framed)?;
+    client.send_response(&framed).await?;
+    Ok(())
+}
+
+/// Send the answer to a request, compressed if the client asked for that
+/// and it is large enough
+async fn send_answer(
+    state: &DaemonState,
+    client: &crate::state::Client,
+    response: &Response,
+) -> color_eyre::Result<()> {
+    if !client.capabilities().contains(Capabilities::COMPRESSION) {
+        return send_response(client, response).await;
+    }
+    let mut framed = Vec::new();
+    FramedMessage::encode_into(response, &mut framed)?;
+    let framed = state
+        .compress_frame(&framed)
+        .map_or(framed, |compressed| compressed.to_vec());
+    client.send_response(&framed).await?;
     Ok(())
 }
 
+/// Listener handed over by launchd, if we were socket-activated
+#[cfg(target_os = "macos")]
+pub fn inherited_listener() -> std::io::Result<Option<std::os::unix::net::UnixListener>> {
+    crate::launchd::activated_listener()
+}
+
+/// Listener handed over by the service manager (none on this platform)
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn inherited_listener() -> std::io::Result<Option<std::os::unix::net::UnixListener>> {
+    Ok(None)
+}
+
 /// Check if the daemon is running by attempting to connect to the socket
+///
+/// `socket_path` may also be a `tcp://host:port` or `vsock://cid:port`
+/// endpoint.
 pub async fn is_daemon_running(socket_path: &Path) -> bool {
-    UnixStream::connect(socket_path).await.is_ok()
+    match Endpoint::from_path(socket_path) {
+        #[cfg(unix)]
+        Endpoint::Unix(path) => UnixStream::connect(path).await.is_ok(),
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => false,
+        Endpoint::Tcp(addr) => TcpStream::connect(addr).await.is_ok(),
+        #[cfg(target_os = "linux")]
+        Endpoint::Vsock { cid, port } => connect_vsock(cid, port).await.is_ok(),
+        #[cfg(not(target_os = "linux"))]
+        Endpoint::Vsock { .. } => false,
+    }
 }
 
 /// Send a request to the daemon and receive a response
@@ -235,38 +1382,1766 @@ pub async fn send_daemon_request(
     socket_path: &Path,
     request: Request,
 ) -> color_eyre::Result<Response> {
-    let mut stream = UnixStream::connect(socket_path).await?;
+    match Endpoint::from_path(socket_path) {
+        #[cfg(unix)]
+        Endpoint::Unix(path) => exchange(UnixStream::connect(path).await?, request).await,
+        #[cfg(not(unix))]
+        Endpoint::Unix(path) => color_eyre::eyre::bail!(
+            "Unix sockets are not supported on this platform: {}",
+            path.display()
+        ),
+        Endpoint::Tcp(addr) => exchange(TcpStream::connect(addr).await?, request).await,
+        #[cfg(target_os = "linux")]
+        Endpoint::Vsock { cid, port } => exchange(connect_vsock(cid, port).await?, request).await,
+        #[cfg(not(target_os = "linux"))]
+        Endpoint::Vsock { .. } => {
+            color_eyre::eyre::bail!("vsock is not supported on this platform")
+        }
+    }
+}
+
+/// Connect to a daemon on this VM's host, or another CID, over vsock
+#[cfg(target_os = "linux")]
+async fn connect_vsock(cid: u32, port: u32) -> std::io::Result<UnixStream> {
+    let socket = tokio::task::spawn_blocking(move || fakenotify_protocol::connect_vsock(cid, port))
+        .await??;
+    let stream = std::os::unix::net::UnixStream::from(socket);
+    stream.set_nonblocking(true)?;
+    UnixStream::from_std(stream)
+}
+
+/// Perform a single request/response exchange on a fresh connection
+async fn exchange<S>(mut stream: S, request: Request) -> color_eyre::Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Read one frame's response
+    async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> color_eyre::Result<Response> {
+        let mut len_buf = [0u8; 4];
+        stream.read_exact(&mut len_buf).await?;
+        let len = FramedMessage::read_length(&len_buf).unwrap_or(0) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        Ok(Response::from_bytes(&payload)?)
+    }
 
     // Read the initial ClientRegistered response
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_le_bytes(len_buf) as usize;
-    let mut payload = vec![0u8; len];
-    stream.read_exact(&mut payload).await?;
-    let _ = Response::from_bytes(&payload)?;
+    let _ = read(&mut stream).await?;
 
     // Send our request
     let request_bytes = request.to_bytes()?;
     let framed = FramedMessage::frame(&request_bytes);
     stream.write_all(&framed).await?;
 
-    // Read the response
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_le_bytes(len_buf) as usize;
-    let mut payload = vec![0u8; len];
-    stream.read_exact(&mut payload).await?;
-
-    let response = Response::from_bytes(&payload)?;
-    Ok(response)
+    // Read the response; heartbeats go to every connection, and one that
+    // takes longer than the interval to answer gets some first
+    loop {
+        match read(&mut stream).await? {
+            Response::Heartbeat => continue,
+            response => return Ok(response),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::LimitsConfig;
+    use fakenotify_protocol::{ClientInfo, FrameKind, InotifyEvent};
+    use tokio::io::AsyncBufReadExt;
 
     #[tokio::test]
     async fn test_is_daemon_running_nonexistent() {
         let result = is_daemon_running(Path::new("/nonexistent/path.sock")).await;
         assert!(!result);
     }
+
+    #[tokio::test]
+    async fn test_request_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                state,
+                ClientPolicy::default(),
+                shutdown_rx,
+            )
+            .await
+        });
+
+        let response = send_daemon_request(Path::new(&endpoint), Request::Ping)
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::Pong));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_skips_heartbeats() {
+        let (client, mut daemon) = tokio::io::duplex(1024);
+        let exchange = tokio::spawn(exchange(client, Request::Ping));
+        for response in [
+            Response::ClientRegistered {
+                client_id: 1,
+                session: String::new(),
+                capabilities: 0,
+            },
+            Response::Heartbeat,
+            Response::Heartbeat,
+            Response::Pong,
+        ] {
+            daemon
+                .write_all(&FramedMessage::frame(&response.to_bytes().unwrap()))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(exchange.await.unwrap().unwrap(), Response::Pong));
+    }
+
+    #[tokio::test]
+    async fn test_requests_split_across_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                state,
+                ClientPolicy::default(),
+                shutdown_rx,
+            )
+            .await
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut chunk = [0u8; 1024];
+        let mut next_response = async |stream: &mut TcpStream| loop {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                return Response::from_bytes(frame).unwrap();
+            }
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert_ne!(n, 0, "connection closed");
+            decoder.push(&chunk[..n]);
+        };
+        assert!(matches!(
+            next_response(&mut stream).await,
+            Response::ClientRegistered { .. }
+        ));
+
+        // Two pings, cut inside the first length prefix and between them
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            FramedMessage::encode_into(&Request::Ping, &mut requests).unwrap();
+        }
+        for part in [&requests[..2], &requests[2..6], &requests[6..]] {
+            stream.write_all(part).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for _ in 0..2 {
+            assert_eq!(next_response(&mut stream).await, Response::Pong);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_with_line_like_lengths_are_binary() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+                tokio::spawn(handle_client(
+                    reader,
+                    writer,
+                    peer,
+                    Arc::clone(&state),
+                    ClientPolicy::default(),
+                    shutdown_rx.resubscribe(),
+                ));
+            }
+        });
+
+        // Payloads whose length's low byte is `"` and `{`
+        for len in [34, 123] {
+            let mut path = String::from("/");
+            let request = loop {
+                let request = Request::AddWatch {
+                    path: path.clone().into(),
+                    mask: EventMask::IN_CREATE.bits(),
+                };
+                if request.to_bytes().unwrap().len() == len {
+                    break request;
+                }
+                path.push('a');
+            };
+            let response = tokio::time::timeout(
+                Duration::from_secs(5),
+                send_daemon_request(Path::new(&endpoint), request),
+            )
+            .await
+            .expect("request taken for a JSON line")
+            .unwrap();
+            assert!(
+                matches!(response, Response::Error { .. }),
+                "length {len}: {response:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_for_raw_consumers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let handler_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                handler_state,
+                ClientPolicy::default(),
+                shutdown_rx,
+            )
+            .await
+        });
+
+        let mut stream = tokio::io::BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut len_buf = [0u8; 4];
+        stream.read_exact(&mut len_buf).await.unwrap();
+        let mut greeting = vec![0u8; FramedMessage::read_length(&len_buf).unwrap() as usize];
+        stream.read_exact(&mut greeting).await.unwrap();
+
+        let dir = std::env::temp_dir();
+        let requests = format!(
+            "{{\"RegisterClient\":{{\"protocol_version\":{},\"info\":{{\"name\":\"sh\"}},\"capabilities\":{}}}}}\n{}\n",
+            PROTOCOL_VERSION,
+            (Capabilities::JSON | Capabilities::EVENT_BATCH).bits(),
+            serde_json::json!({"AddWatch": {"path": dir, "mask": EventMask::IN_CREATE.bits()}}),
+        );
+        stream.write_all(requests.as_bytes()).await.unwrap();
+
+        let mut lines = stream.lines();
+        // The greeting's line ends before the first JSON one
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "");
+        let line = lines.next_line().await.unwrap().unwrap();
+        let registered: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            registered["ClientRegistered"]["capabilities"],
+            Capabilities::JSON.bits()
+        );
+        let line = lines.next_line().await.unwrap().unwrap();
+        let added: serde_json::Value = serde_json::from_str(&line).unwrap();
+        let wd = added["WatchAdded"]["wd"].as_i64().unwrap() as i32;
+
+        let client_id = registered["ClientRegistered"]["client_id"]
+            .as_u64()
+            .unwrap();
+        let event = InotifyEvent::new(wd, EventMask::IN_CREATE.bits(), 0);
+        let client = state.get_client(client_id).unwrap();
+        client.queue_event(FramedMessage::frame_event(&event.to_bytes_with_name(b"a.mkv")).into());
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&line).unwrap(),
+            serde_json::json!({
+                "wd": wd,
+                "mask": 256,
+                "events": ["IN_CREATE"],
+                "cookie": 0,
+                "name": "a.mkv",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let liveness = Liveness {
+            heartbeat_interval: Some(Duration::from_millis(20)),
+            timeout: Some(Duration::from_millis(100)),
+            tcp_keepalive: Some(Duration::from_secs(10)),
+        };
+        let handler_state = Arc::clone(&state);
+        let handler = tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), liveness.tcp_keepalive)
+                .await
+                .unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                handler_state,
+                ClientPolicy {
+                    liveness,
+                    ..ClientPolicy::default()
+                },
+                shutdown_rx,
+            )
+            .await
+        });
+
+        // Promise heartbeat acks but never send them
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let register = Request::RegisterClient {
+            protocol_version: PROTOCOL_VERSION,
+            info: ClientInfo::default(),
+            capabilities: Capabilities::HEARTBEAT.bits(),
+        };
+        stream
+            .write_all(&FramedMessage::frame(&register.to_bytes().unwrap()))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handler)
+            .await
+            .expect("silent client was not dropped")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.stats().total_clients, 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_reader_is_kept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let liveness = Liveness {
+            heartbeat_interval: Some(Duration::from_millis(20)),
+            timeout: Some(Duration::from_millis(100)),
+            tcp_keepalive: None,
+        };
+        let handler_state = Arc::clone(&state);
+        let handler = tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                handler_state,
+                ClientPolicy {
+                    liveness,
+                    ..ClientPolicy::default()
+                },
+                shutdown_rx,
+            )
+            .await
+        });
+
+        // Like an app busy with one event, neither reading nor acking
+        let _stream = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!handler.is_finished());
+        assert_eq!(state.stats().total_clients, 1);
+        handler.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_request_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let limits = RequestLimits {
+            read_timeout: Some(Duration::from_millis(50)),
+            ..RequestLimits::default()
+        };
+        let handler_state = Arc::clone(&state);
+        let handler = tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                handler_state,
+                ClientPolicy {
+                    limits,
+                    ..ClientPolicy::default()
+                },
+                shutdown_rx,
+            )
+            .await
+        });
+
+        // Promise a body and never send it
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&64u32.to_le_bytes()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handler)
+            .await
+            .expect("stalled client was not dropped")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.stats().total_clients, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shutdown_request_answers_first() {
+        let path = std::env::temp_dir().join(format!(
+            "fakenotify-server-shutdown-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let mut server_rx = shutdown_tx.subscribe();
+        let state = Arc::new(DaemonState::new().with_shutdown(shutdown_tx));
+        let handler = tokio::spawn(async move {
+            let (reader, writer, peer) = accept_unix(&listener).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                state,
+                ClientPolicy::default(),
+                shutdown_rx,
+            )
+            .await
+        });
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        async fn read_response(stream: &mut UnixStream) -> Option<Response> {
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.ok()?;
+            let mut payload = vec![0u8; FramedMessage::read_length(&len_buf).unwrap() as usize];
+            stream.read_exact(&mut payload).await.ok()?;
+            Response::from_bytes(&payload).ok()
+        }
+        assert!(matches!(
+            read_response(&mut stream).await,
+            Some(Response::ClientRegistered { .. })
+        ));
+
+        let request = Request::Shutdown.to_bytes().unwrap();
+        stream
+            .write_all(&FramedMessage::frame(&request))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_response(&mut stream).await,
+            Some(Response::ShuttingDown {
+                pid: std::process::id()
+            })
+        );
+
+        // The answer went out, then the shutdown was raised
+        server_rx.recv().await.unwrap();
+        handler.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// The peer a TCP connection is accepted as
+    async fn tcp_peer() -> Peer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        accept_tcp(Some(&listener), None).await.unwrap().2
+    }
+
+    #[tokio::test]
+    async fn test_tcp_peer_cannot_stop_the_daemon() {
+        let (shutdown_tx, _shutdown_rx) = broadcast::channel(1);
+        let state = DaemonState::new().with_shutdown(shutdown_tx);
+        let peer = tcp_peer().await;
+        assert_eq!(peer.uid, own_uid());
+        assert!(!peer.local);
+        let remote = state
+            .register_client(Box::new(tokio::io::sink()), peer)
+            .ok()
+            .unwrap();
+        assert!(!remote.is_administrator());
+        assert!(matches!(
+            handle_request(&state, remote.id, Request::Shutdown).await,
+            Some(Response::Error {
+                code: ErrorCode::PermissionDenied,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resumed_session_keeps_watches_and_queued_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new().with_session_timeout(Duration::from_secs(60)));
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let server_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+                tokio::spawn(handle_client(
+                    reader,
+                    writer,
+                    peer,
+                    Arc::clone(&server_state),
+                    ClientPolicy::default(),
+                    shutdown_rx.resubscribe(),
+                ));
+            }
+        });
+        async fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut payload = vec![0u8; FramedMessage::read_length(&len_buf).unwrap() as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            payload
+        }
+        async fn request(stream: &mut TcpStream, request: Request) -> Response {
+            let payload = request.to_bytes().unwrap();
+            stream
+                .write_all(&FramedMessage::frame(&payload))
+                .await
+                .unwrap();
+            Response::from_bytes(&read_frame(stream).await).unwrap()
+        }
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let Ok(Response::ClientRegistered {
+            client_id, session, ..
+        }) = Response::from_bytes(&read_frame(&mut first).await)
+        else {
+            panic!("expected ClientRegistered");
+        };
+        let add = Request::AddWatch {
+            path: std::env::temp_dir(),
+            mask: EventMask::IN_ALL_EVENTS.bits(),
+        };
+        let Response::WatchAdded { wd } = request(&mut first, add).await else {
+            panic!("expected WatchAdded");
+        };
+        drop(first);
+
+        let client = state.get_client(client_id).unwrap();
+        while client.is_attached() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let event = InotifyEvent::new(wd, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        assert!(client.queue_event(FramedMessage::frame_event(&event).into()));
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let Ok(Response::ClientRegistered {
+            client_id: stand_in,
+            ..
+        }) = Response::from_bytes(&read_frame(&mut second).await)
+        else {
+            panic!("expected ClientRegistered");
+        };
+        assert_eq!(
+            request(&mut second, Request::Resume { session }).await,
+            Response::Resumed {
+                client_id,
+                capabilities: 0,
+            }
+        );
+        assert_eq!(read_frame(&mut second).await, event);
+        assert!(state.get_client(stand_in).is_none());
+        assert_eq!(*client.watches.read(), vec![wd]);
+        assert_eq!(
+            request(&mut second, Request::RemoveWatch { wd }).await,
+            Response::WatchRemoved
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let handler_state = Arc::clone(&state);
+        let handler = tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            let policy = ClientPolicy {
+                drain_timeout: Duration::from_secs(5),
+                ..ClientPolicy::default()
+            };
+            handle_client(reader, writer, peer, handler_state, policy, shutdown_rx).await
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        async fn read_frame(stream: &mut TcpStream) -> Option<Vec<u8>> {
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.ok()?;
+            let mut payload = vec![0u8; FramedMessage::read_length(&len_buf).unwrap() as usize];
+            stream.read_exact(&mut payload).await.ok()?;
+            Some(payload)
+        }
+        let Ok(Response::ClientRegistered { client_id, .. }) =
+            Response::from_bytes(&read_frame(&mut stream).await.unwrap())
+        else {
+            panic!("expected ClientRegistered");
+        };
+        let request = Request::AddWatch {
+            path: std::env::temp_dir(),
+            mask: EventMask::IN_ALL_EVENTS.bits(),
+        };
+        stream
+            .write_all(&FramedMessage::frame(&request.to_bytes().unwrap()))
+            .await
+            .unwrap();
+        let Ok(Response::WatchAdded { wd }) =
+            Response::from_bytes(&read_frame(&mut stream).await.unwrap())
+        else {
+            panic!("expected WatchAdded");
+        };
+
+        // Queue an event and shut down before it can be read
+        let client = state.get_client(client_id).unwrap();
+        let event = InotifyEvent::new(wd, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        assert!(client.queue_event(FramedMessage::frame_event(&event).into()));
+        drop(client);
+        shutdown_tx.send(()).unwrap();
+
+        assert_eq!(read_frame(&mut stream).await.unwrap(), event);
+        let ignored = read_frame(&mut stream).await.unwrap();
+        let header = InotifyEvent::from_bytes(&ignored).unwrap();
+        assert_eq!(header.wd, wd);
+        assert_eq!(header.mask, EventMask::IN_IGNORED.bits());
+        assert!(read_frame(&mut stream).await.is_none());
+
+        handler.await.unwrap().unwrap();
+        assert_eq!(state.stats().total_clients, 0);
+    }
+
+    #[tokio::test]
+    async fn test_add_watch_maps_client_path() {
+        let host = std::env::temp_dir();
+        let state = DaemonState::new().with_path_map(vec![crate::config::PathMapping {
+            host: host.clone(),
+            client: PathBuf::from("/mnt/c/share"),
+        }]);
+
+        let request = Request::AddWatch {
+            path: PathBuf::from("/mnt/c/share"),
+            mask: EventMask::IN_ALL_EVENTS.bits(),
+        };
+        let Some(Response::WatchAdded { wd }) = handle_request(&state, 1, request).await else {
+            panic!("expected WatchAdded");
+        };
+        assert_eq!(state.get_watch(wd).unwrap().path, host);
+    }
+
+    #[tokio::test]
+    async fn test_refusals_carry_error_codes() {
+        let state = DaemonState::new();
+        let file = std::env::temp_dir().join(format!("fakenotify-code-{}", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+
+        let code = |response: Option<Response>| match response {
+            Some(Response::Error { code, .. }) => code,
+            other => panic!("expected Error, got {other:?}"),
+        };
+        let missing = Request::AddWatch {
+            path: file.join("missing"),
+            mask: EventMask::IN_CREATE.bits(),
+        };
+        assert_eq!(
+            code(handle_request(&state, 1, missing).await),
+            ErrorCode::NotFound
+        );
+        let only_dir = Request::AddWatch {
+            path: file.clone(),
+            mask: (EventMask::IN_CREATE | EventMask::IN_ONLYDIR).bits(),
+        };
+        assert_eq!(
+            code(handle_request(&state, 1, only_dir).await),
+            ErrorCode::NotDirectory
+        );
+        let unknown = Request::RemoveWatch { wd: 99 };
+        assert_eq!(
+            code(handle_request(&state, 1, unknown).await),
+            ErrorCode::NoSuchWatch
+        );
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stats_reports_busiest_paths() {
+        let state = DaemonState::new();
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let Some(Response::Stats { window_secs, .. }) =
+            handle_request(&state, client.id, Request::Stats { top: 5 }).await
+        else {
+            panic!("expected Stats");
+        };
+        assert_eq!(window_secs, 0);
+
+        let state = DaemonState::new().with_rate_tracking(crate::rate::NoisyPolicy::default());
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let root = std::env::temp_dir();
+        let wd = state
+            .add_watch(client.id, root.clone(), EventMask::IN_ALL_EVENTS, true)
+            .unwrap();
+        for _ in 0..3 {
+            assert!(state.record_event(wd, &root.join("busy/a.tmp")));
+        }
+        state.record_event(wd, &root.join("quiet.mkv"));
+
+        let Some(Response::Stats {
+            watches,
+            watch_rates,
+            dir_rates,
+            limits,
+            users,
+            ..
+        }) = handle_request(&state, client.id, Request::Stats { top: 1 }).await
+        else {
+            panic!("expected Stats");
+        };
+        assert_eq!(watches, 1);
+        assert_eq!(
+            limits.max_user_watches,
+            LimitsConfig::default().max_user_watches as u64
+        );
+        assert_eq!(
+            users,
+            [fakenotify_protocol::UserUsage {
+                uid: 1000,
+                instances: 1,
+                watches: 1,
+                queued_events: 0,
+            }]
+        );
+        assert_eq!(watch_rates[0].path, root);
+        assert_eq!(watch_rates[0].events, 4);
+        assert_eq!(dir_rates.len(), 1);
+        assert_eq!(dir_rates[0].path, root.join("busy"));
+        assert_eq!(dir_rates[0].events, 3);
+    }
+
+    fn scan_root(path: &Path) -> WatchRoot {
+        WatchRoot {
+            path: path.to_path_buf(),
+            poll_interval_ms: 60_000,
+            recursive: true,
+            native: false,
+            mask: EventMask::IN_ALL_EVENTS.bits(),
+            extensions: Vec::new(),
+            name_regex: None,
+            change_detection: Default::default(),
+            revalidate: false,
+            case_insensitive: None,
+            confirm_deletes: 1,
+            restat_deletes: false,
+            settle_scans: 0,
+            partial_suffixes: Vec::new(),
+            tags: Vec::new(),
+            denied_mask: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roots_advertise_export_and_import() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-roots-{}", std::process::id()));
+        let (media, photos) = (dir.join("media"), dir.join("photos"));
+        std::fs::create_dir_all(&media).unwrap();
+        std::fs::create_dir_all(&photos).unwrap();
+
+        let state = DaemonState::new();
+        let (mut watcher, _) =
+            crate::watcher::WatcherManager::new(Arc::new(crate::source::FsSource), 16);
+        watcher.add_watch(scan_root(&media).into()).unwrap();
+        state.attach_watcher(watcher);
+        assert_eq!(
+            handle_request(&state, 1, Request::Hello).await,
+            Some(Response::Hello {
+                roots: vec![media.clone()],
+                capabilities: state.capabilities().bits(),
+            })
+        );
+
+        let owner = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(own_uid()))
+            .ok()
+            .unwrap();
+        let missing = dir.join("missing");
+        let import = Request::ImportRoots {
+            roots: vec![scan_root(&media), scan_root(&photos), scan_root(&missing)],
+        };
+        let Some(Response::Imported {
+            added,
+            existing,
+            failed,
+        }) = handle_request(&state, owner.id, import.clone()).await
+        else {
+            panic!("expected Imported");
+        };
+        assert_eq!(added, vec![photos.clone()]);
+        assert_eq!(existing, vec![media.clone()]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, missing);
+
+        assert_eq!(
+            handle_request(&state, owner.id, Request::ExportRoots).await,
+            Some(Response::Roots {
+                roots: vec![scan_root(&media), scan_root(&photos)]
+            })
+        );
+
+        // Other users can't make the daemon scan for them
+        let other = state
+            .register_client(
+                Box::new(tokio::io::sink()),
+                Peer::from_uid(own_uid().wrapping_add(1).max(1)),
+            )
+            .ok()
+            .unwrap();
+        assert!(matches!(
+            handle_request(&state, other.id, import.clone()).await,
+            Some(Response::Error { .. })
+        ));
+
+        // Nor can network peers, although they count as the daemon's user
+        let remote = state
+            .register_client(Box::new(tokio::io::sink()), tcp_peer().await)
+            .ok()
+            .unwrap();
+        assert!(matches!(
+            handle_request(&state, remote.id, import).await,
+            Some(Response::Error {
+                code: ErrorCode::PermissionDenied,
+                ..
+            })
+        ));
+        assert_eq!(state.root_configs().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_watches_and_clients() {
+        let state = DaemonState::new();
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let root = std::env::temp_dir();
+        let wd = state
+            .add_watch(client.id, root.clone(), EventMask::IN_CREATE, false)
+            .unwrap();
+
+        let Some(Response::Watches { watches }) =
+            handle_request(&state, client.id, Request::ListWatches).await
+        else {
+            panic!("expected Watches");
+        };
+        assert_eq!(watches.len(), 1);
+        assert_eq!(watches[0].wd, wd);
+        assert_eq!(watches[0].path, root);
+        assert_eq!(watches[0].mask, EventMask::IN_CREATE.bits());
+        assert_eq!(watches[0].clients, 1);
+
+        let Some(Response::WatchInfo { info }) =
+            handle_request(&state, client.id, Request::GetWatchInfo { wd }).await
+        else {
+            panic!("expected WatchInfo");
+        };
+        assert_eq!(info.path, root);
+        assert_eq!(info.clients, 1);
+        // No scanner is attached, so no root covers it
+        assert_eq!((info.root, info.last_scan_ms_ago), (None, None));
+        assert!(matches!(
+            handle_request(&state, client.id, Request::GetWatchInfo { wd: wd + 1 }).await,
+            Some(Response::Error {
+                code: ErrorCode::NoSuchWatch,
+                ..
+            })
+        ));
+
+        let Some(Response::Clients { clients }) =
+            handle_request(&state, client.id, Request::ListClients).await
+        else {
+            panic!("expected Clients");
+        };
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].id, client.id);
+        assert_eq!(clients[0].uid, 1000);
+        assert_eq!(clients[0].watches, 1);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_confine_clients() {
+        let state = DaemonState::new().with_multi_tenant(true);
+        let register = |uid| {
+            state
+                .register_client(Box::new(tokio::io::sink()), Peer::from_uid(uid))
+                .ok()
+                .unwrap()
+        };
+        let admin = register(own_uid());
+        let alice_uid = own_uid().wrapping_add(1).max(1);
+        let alice = register(alice_uid);
+        let bob = register(alice_uid + 1);
+        let root = std::env::temp_dir();
+        let shared = state
+            .add_watch(alice.id, root.clone(), EventMask::IN_CREATE, false)
+            .unwrap();
+        state
+            .add_watch(bob.id, root.clone(), EventMask::IN_CREATE, false)
+            .unwrap();
+        let private = state
+            .add_watch(
+                bob.id,
+                root.join("fakenotify-namespaced"),
+                EventMask::IN_CREATE,
+                false,
+            )
+            .unwrap();
+        let watches = |client| {
+            let state = &state;
+            async move {
+                match handle_request(state, client, Request::ListWatches).await {
+                    Some(Response::Watches { watches }) => watches
+                        .into_iter()
+                        .map(|watch| (watch.wd, watch.clients))
+                        .collect::<Vec<_>>(),
+                    other => panic!("expected Watches, got {other:?}"),
+                }
+            }
+        };
+
+        assert_eq!(watches(alice.id).await, [(shared, 1)]);
+        assert_eq!(watches(admin.id).await, [(shared, 2), (private, 1)]);
+        for request in [
+            Request::GetWatchInfo { wd: private },
+            Request::RemoveWatch { wd: private },
+            Request::TagWatch {
+                wd: private,
+                tags: vec!["ci".into()],
+            },
+        ] {
+            assert!(matches!(
+                handle_request(&state, alice.id, request).await,
+                Some(Response::Error {
+                    code: ErrorCode::NoSuchWatch,
+                    ..
+                })
+            ));
+        }
+        assert!(state.get_watch(private).is_some());
+        let Some(Response::Clients { clients }) =
+            handle_request(&state, alice.id, Request::ListClients).await
+        else {
+            panic!("expected Clients");
+        };
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].namespace, alice_uid.to_string());
+
+        // Names picked by users stay under their uid
+        let join = |namespace: &str| Request::HelloNamespace {
+            namespace: namespace.to_string(),
+        };
+        assert!(matches!(
+            handle_request(&state, bob.id, join("media")).await,
+            Some(Response::Hello { .. })
+        ));
+        assert_eq!(bob.namespace(), format!("{}/media", alice_uid + 1));
+        assert_eq!(watches(alice.id).await, [(shared, 1)]);
+        assert!(matches!(
+            handle_request(&state, admin.id, join("two words")).await,
+            Some(Response::Error { .. })
+        ));
+        handle_request(&state, admin.id, join(&bob.namespace())).await;
+        assert_eq!(watches(admin.id).await, [(shared, 1), (private, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_remote_peers_and_namespaced_admins_stay_confined() {
+        let state = DaemonState::new().with_multi_tenant(true);
+        let register = |peer| {
+            state
+                .register_client(Box::new(tokio::io::sink()), peer)
+                .ok()
+                .unwrap()
+        };
+        let local = register(Peer::from_uid(own_uid()));
+        let user = register(Peer::from_uid(own_uid().wrapping_add(1).max(1)));
+        let remote = register(tcp_peer().await);
+        let other = register(tcp_peer().await);
+        let root = std::env::temp_dir();
+        let tag = |client, wd| {
+            let state = &state;
+            async move {
+                let request = Request::TagWatch {
+                    wd,
+                    tags: vec!["ci".into()],
+                };
+                assert_eq!(
+                    handle_request(state, client, request).await,
+                    Some(Response::WatchTagged)
+                );
+            }
+        };
+        let ours = state
+            .add_watch(user.id, root.clone(), EventMask::IN_CREATE, false)
+            .unwrap();
+        tag(user.id, ours).await;
+        let theirs = state
+            .add_watch(
+                remote.id,
+                root.join("fakenotify-remote"),
+                EventMask::IN_CREATE,
+                false,
+            )
+            .unwrap();
+        tag(remote.id, theirs).await;
+
+        // Sharing the daemon's uid puts a remote peer in no one's namespace
+        assert_eq!(remote.namespace(), format!("remote/client/{}", remote.id));
+        assert_ne!(state.scope(remote.id), Scope::All);
+        let Some(Response::Watches { watches }) =
+            handle_request(&state, remote.id, Request::ListWatches).await
+        else {
+            panic!("expected Watches");
+        };
+        assert_eq!(watches.len(), 1);
+        assert_eq!(watches[0].wd, theirs);
+        let Some(Response::Clients { clients }) =
+            handle_request(&state, other.id, Request::ListClients).await
+        else {
+            panic!("expected Clients");
+        };
+        assert_eq!(clients.len(), 1);
+
+        // Naming another peer's id reaches a joinable namespace, not its own
+        let join = Request::HelloNamespace {
+            namespace: remote.id.to_string(),
+        };
+        handle_request(&state, other.id, join).await;
+        assert_eq!(other.namespace(), format!("remote/ns/{}", remote.id));
+        let Some(Response::Watches { watches }) =
+            handle_request(&state, other.id, Request::ListWatches).await
+        else {
+            panic!("expected Watches");
+        };
+        assert!(watches.is_empty());
+        let request = Request::RemoveTagged { tag: "ci".into() };
+        handle_request(&state, other.id, request).await;
+        assert!(state.get_watch(theirs).is_some());
+
+        let join = Request::HelloNamespace {
+            namespace: own_uid().to_string(),
+        };
+        handle_request(&state, remote.id, join).await;
+        assert_eq!(remote.namespace(), format!("remote/ns/{}", own_uid()));
+
+        // An administrator that joined a namespace removes only its tags
+        let join = Request::HelloNamespace {
+            namespace: remote.namespace(),
+        };
+        handle_request(&state, local.id, join).await;
+        let Some(Response::Removed { paths }) =
+            handle_request(&state, local.id, Request::RemoveTagged { tag: "ci".into() }).await
+        else {
+            panic!("expected Removed");
+        };
+        assert_eq!(paths, [root.join("fakenotify-remote")]);
+        assert!(state.get_watch(ours).is_some());
+        assert!(state.get_watch(theirs).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_confined_stats_hide_foreign_roots() {
+        use crate::health::{HealthPolicy, MountMonitor};
+        use crate::source::{ScanSource, sim::SimSource};
+
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/m/ours");
+        sim.mkdir_all("/m/foreign");
+        let state = DaemonState::new()
+            .with_multi_tenant(true)
+            .with_mount_health(MountMonitor::new(
+                HealthPolicy {
+                    timeout: Duration::from_millis(20),
+                    max_backoff: Duration::from_secs(1),
+                },
+                Vec::new(),
+            ));
+        // Both roots share the scan of /m, and both stop answering
+        let (mut watcher, _) = crate::watcher::WatcherManager::new(sim.clone(), 16);
+        for root in ["/m", "/m/ours", "/m/foreign"] {
+            watcher
+                .add_watch(scan_root(Path::new(root)).into())
+                .unwrap();
+        }
+        state.attach_watcher(watcher);
+        let source: Arc<dyn ScanSource> = sim.clone();
+        for root in ["/m/ours", "/m/foreign"] {
+            sim.hang(root);
+            let probe = &mut None;
+            assert!(
+                state
+                    .mount_health()
+                    .check(&source, Path::new(root), probe)
+                    .await
+                    .is_some()
+            );
+        }
+
+        let register = |peer| {
+            state
+                .register_client(Box::new(tokio::io::sink()), peer)
+                .ok()
+                .unwrap()
+        };
+        let local = register(Peer::from_uid(own_uid()));
+        let remote = register(tcp_peer().await);
+        state
+            .add_watch(
+                remote.id,
+                PathBuf::from("/m/ours"),
+                EventMask::IN_CREATE,
+                false,
+            )
+            .unwrap();
+        let stats = |client| {
+            let state = &state;
+            async move {
+                let Some(Response::Stats {
+                    shared_roots,
+                    degraded_mounts,
+                    ..
+                }) = handle_request(state, client, Request::Stats { top: 5 }).await
+                else {
+                    panic!("expected Stats");
+                };
+                let mounts: Vec<_> = degraded_mounts.into_iter().map(|m| m.path).collect();
+                (shared_roots, mounts)
+            }
+        };
+
+        assert_eq!(
+            stats(local.id).await,
+            (
+                2,
+                vec![PathBuf::from("/m/foreign"), PathBuf::from("/m/ours")]
+            )
+        );
+        assert_ne!(state.scope(remote.id), Scope::All);
+        assert_eq!(stats(remote.id).await, (1, vec![PathBuf::from("/m/ours")]));
+        // Let the blocked probes finish so the runtime can shut down
+        for root in ["/m/ours", "/m/foreign"] {
+            sim.unhang(root);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watches_are_tagged_and_removed_by_tag() {
+        let state = DaemonState::new();
+        let admin = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(own_uid()))
+            .ok()
+            .unwrap();
+        let other = state
+            .register_client(
+                Box::new(tokio::io::sink()),
+                Peer::from_uid(own_uid().wrapping_add(1).max(1)),
+            )
+            .ok()
+            .unwrap();
+        let root = std::env::temp_dir();
+        let wd = state
+            .add_watch(other.id, root.clone(), EventMask::IN_CREATE, true)
+            .unwrap();
+        let own = state
+            .add_watch(
+                admin.id,
+                root.join("fakenotify-untagged"),
+                EventMask::IN_CREATE,
+                false,
+            )
+            .unwrap();
+        let tag = |wd, tags: &[&str]| Request::TagWatch {
+            wd,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+
+        assert_eq!(
+            handle_request(&state, other.id, tag(wd, &["team-media", "ci", "ci"])).await,
+            Some(Response::WatchTagged)
+        );
+        for (client, request) in [
+            (other.id, tag(wd, &["two words"])),
+            (other.id, tag(own, &["ci"])),
+            (other.id, Request::RemoveTagged { tag: "ci".into() }),
+        ] {
+            assert!(matches!(
+                handle_request(&state, client, request).await,
+                Some(Response::Error { .. })
+            ));
+        }
+
+        let Some(Response::Watches { watches }) =
+            handle_request(&state, admin.id, Request::ListWatches).await
+        else {
+            panic!("expected Watches");
+        };
+        assert_eq!(watches[0].tags, ["ci", "team-media"]);
+        assert!(watches[1].tags.is_empty());
+
+        assert_eq!(
+            handle_request(&state, admin.id, Request::RemoveTagged { tag: "ci".into() }).await,
+            Some(Response::Removed { paths: vec![root] })
+        );
+        assert!(state.get_watch(wd).is_none());
+        assert!(other.watches.read().is_empty());
+        assert!(state.get_watch(own).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unobservable_events_are_rejected() {
+        let state = DaemonState::new().with_access_events(AccessEvents::Reject);
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(own_uid()))
+            .ok()
+            .unwrap();
+        let add = |mask: EventMask| Request::AddWatch {
+            path: std::env::temp_dir(),
+            mask: mask.bits(),
+        };
+
+        assert!(matches!(
+            handle_request(
+                &state,
+                client.id,
+                add(EventMask::IN_CREATE | EventMask::IN_OPEN)
+            )
+            .await,
+            Some(Response::Error {
+                code: ErrorCode::UnsupportedMask,
+                ..
+            })
+        ));
+        assert!(matches!(
+            handle_request(&state, client.id, add(EventMask::IN_CREATE)).await,
+            Some(Response::WatchAdded { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_denied_events_are_clamped() {
+        let state = DaemonState::with_limits(LimitsConfig {
+            denied_events: EventMask::IN_ACCESS | EventMask::IN_OPEN,
+            ..LimitsConfig::default()
+        });
+        let clamping = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(own_uid()))
+            .ok()
+            .unwrap();
+        let plain = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(own_uid()))
+            .ok()
+            .unwrap();
+        handle_request(
+            &state,
+            clamping.id,
+            Request::RegisterClient {
+                protocol_version: PROTOCOL_VERSION,
+                info: ClientInfo::default(),
+                capabilities: Capabilities::MASK_CLAMP.bits(),
+            },
+        )
+        .await;
+        let root = std::env::temp_dir();
+        let add = |mask: EventMask| Request::AddWatch {
+            path: root.clone(),
+            mask: mask.bits(),
+        };
+
+        let Some(Response::WatchClamped { wd, mask }) = handle_request(
+            &state,
+            clamping.id,
+            add(EventMask::IN_CREATE | EventMask::IN_ACCESS),
+        )
+        .await
+        else {
+            panic!("expected WatchClamped");
+        };
+        assert_eq!(mask, EventMask::IN_CREATE.bits());
+        assert_eq!(state.get_watch(wd).unwrap().mask, EventMask::IN_CREATE);
+
+        // Clients that cannot tell are answered as usual
+        let Some(Response::WatchAdded { wd }) =
+            handle_request(&state, plain.id, add(EventMask::IN_ALL_EVENTS)).await
+        else {
+            panic!("expected WatchAdded");
+        };
+        assert_eq!(
+            state.get_watch(wd).unwrap().mask,
+            EventMask::IN_ALL_EVENTS - EventMask::IN_ACCESS - EventMask::IN_OPEN
+        );
+
+        assert!(matches!(
+            handle_request(&state, plain.id, add(EventMask::IN_OPEN)).await,
+            Some(Response::Error {
+                code: ErrorCode::PermissionDenied,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_register_client_info_is_listed() {
+        let state = DaemonState::new();
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let info = ClientInfo {
+            name: Some("worker".to_string()),
+            pid: Some(31),
+            version: Some("1.2.3".to_string()),
+        };
+
+        let response = handle_request(
+            &state,
+            client.id,
+            Request::RegisterClient {
+                protocol_version: PROTOCOL_VERSION,
+                info: info.clone(),
+                capabilities: u16::MAX,
+            },
+        )
+        .await;
+        // JSON rules out batching and compression
+        let granted =
+            state.capabilities() - (Capabilities::EVENT_BATCH | Capabilities::COMPRESSION);
+        assert_eq!(
+            response,
+            Some(Response::ClientRegistered {
+                client_id: client.id,
+                session: client.session.clone(),
+                capabilities: granted.bits(),
+            })
+        );
+        let Some(Response::Clients { clients }) =
+            handle_request(&state, client.id, Request::ListClients).await
+        else {
+            panic!("expected Clients");
+        };
+        assert_eq!(clients[0].info, info);
+        assert_eq!(client.capabilities(), granted);
+    }
+
+    #[tokio::test]
+    async fn test_other_protocol_versions_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let handler_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                handler_state,
+                ClientPolicy::default(),
+                shutdown_rx,
+            )
+            .await
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        async fn exchange(stream: &mut TcpStream, payload: &[u8]) -> Response {
+            stream
+                .write_all(&FramedMessage::frame(payload))
+                .await
+                .unwrap();
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut payload = vec![0u8; FramedMessage::read_length(&len_buf).unwrap() as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            Response::from_bytes(&payload).unwrap()
+        }
+        let mut greeting = [0u8; 4];
+        stream.read_exact(&mut greeting).await.unwrap();
+        let mut greeting = vec![0u8; FramedMessage::read_length(&greeting).unwrap() as usize];
+        stream.read_exact(&mut greeting).await.unwrap();
+
+        // Version 1 registered with the bare variant, which no longer decodes
+        let refused = exchange(&mut stream, &[0, 0, 0, 0]).await;
+        let Response::Error {
+            code: ErrorCode::VersionMismatch,
+            message,
+        } = refused
+        else {
+            panic!("expected VersionMismatch, got {refused:?}");
+        };
+        assert!(message.contains("version 1"), "{message}");
+
+        // A later version decodes, and is refused all the same
+        let register = |protocol_version| Request::RegisterClient {
+            protocol_version,
+            info: ClientInfo::default(),
+            capabilities: 0,
+        };
+        let later = register(PROTOCOL_VERSION + 1).to_bytes().unwrap();
+        assert!(matches!(
+            exchange(&mut stream, &later).await,
+            Response::Error {
+                code: ErrorCode::VersionMismatch,
+                ..
+            }
+        ));
+        let current = register(PROTOCOL_VERSION).to_bytes().unwrap();
+        assert!(matches!(
+            exchange(&mut stream, &current).await,
+            Response::ClientRegistered { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_large_answers_are_compressed() {
+        let state = DaemonState::new().with_compression(256);
+        let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let client = state
+            .register_client(Box::new(writer), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let register = Request::RegisterClient {
+            protocol_version: PROTOCOL_VERSION,
+            info: fakenotify_protocol::ClientInfo::default(),
+            capabilities: Capabilities::COMPRESSION.bits(),
+        };
+        handle_request(&state, client.id, register).await;
+        assert!(client.capabilities().contains(Capabilities::COMPRESSION));
+
+        let pruned = Response::Pruned {
+            paths: (0..100)
+                .map(|i| PathBuf::from(format!("/mnt/media/show/season {i}")))
+                .collect(),
+        };
+        send_answer(&state, &client, &pruned).await.unwrap();
+        send_answer(&state, &client, &Response::Pong).await.unwrap();
+
+        let mut read_payload = async || {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len).await.unwrap();
+            let mut payload = vec![0u8; FramedMessage::read_length(&len).unwrap() as usize];
+            reader.read_exact(&mut payload).await.unwrap();
+            Response::from_bytes(&payload).unwrap()
+        };
+        let Response::Compressed {
+            kind: FrameKind::Message,
+            payload,
+        } = read_payload().await
+        else {
+            panic!("expected Compressed");
+        };
+        let payload = fakenotify_protocol::decompress_payload(&payload).unwrap();
+        assert_eq!(Response::from_bytes(&payload).unwrap(), pruned);
+        assert_eq!(read_payload().await, Response::Pong);
+    }
+
+    #[tokio::test]
+    async fn test_control_connection_acts_for_its_owner() {
+        let state = DaemonState::new();
+        let connect = |uid| {
+            state
+                .register_client(Box::new(tokio::io::sink()), Peer::from_uid(uid))
+                .ok()
+                .unwrap()
+        };
+        let owner = connect(1000);
+        let control = connect(1000);
+        let stranger = connect(1001);
+
+        let mut acting_for = stranger.id;
+        assert!(matches!(
+            attach_control(&state, &stranger, &mut acting_for, &owner.session),
+            Response::Error { .. }
+        ));
+        assert_eq!(acting_for, stranger.id);
+
+        let mut acting_for = control.id;
+        assert_eq!(
+            attach_control(&state, &control, &mut acting_for, &owner.session),
+            Response::ControlAttached {
+                client_id: owner.id
+            }
+        );
+        assert_eq!(acting_for, owner.id);
+        assert!(state.get_client(control.id).is_none());
+
+        let request = Request::AddWatch {
+            path: std::env::temp_dir(),
+            mask: EventMask::IN_ALL_EVENTS.bits(),
+        };
+        let Some(Response::WatchAdded { wd }) = handle_request(&state, acting_for, request).await
+        else {
+            panic!("expected WatchAdded");
+        };
+        assert_eq!(*owner.watches.read(), vec![wd]);
+    }
+
+    #[tokio::test]
+    async fn test_tagged_requests_are_answered_with_their_id() {
+        let state = DaemonState::new();
+        let tagged = |id, request| Request::Tagged {
+            id,
+            request: Box::new(request),
+        };
+        assert_eq!(
+            handle_request(&state, 1, tagged(7, Request::Ping)).await,
+            Some(Response::Tagged {
+                id: 7,
+                response: Box::new(Response::Pong),
+            })
+        );
+        assert_eq!(
+            handle_request(&state, 1, tagged(8, Request::HeartbeatAck)).await,
+            None
+        );
+        let Some(Response::Tagged { id: 9, response }) =
+            handle_request(&state, 1, tagged(9, tagged(10, Request::Ping))).await
+        else {
+            panic!("expected Tagged");
+        };
+        assert!(matches!(*response, Response::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_status_reports_queues_and_mounts() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-status-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = DaemonState::new();
+        let (mut watcher, _) =
+            crate::watcher::WatcherManager::new(Arc::new(crate::source::FsSource), 16);
+        watcher.add_watch(scan_root(&dir).into()).unwrap();
+        state.attach_watcher(watcher);
+
+        let Some(Response::Status {
+            version,
+            protocol_version,
+            event_queue,
+            event_queue_capacity,
+            mounts,
+            ..
+        }) = handle_request(&state, 1, Request::Status).await
+        else {
+            panic!("expected Status");
+        };
+        assert_eq!(version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(protocol_version, fakenotify_protocol::PROTOCOL_VERSION);
+        assert_eq!((event_queue, event_queue_capacity), (0, 16));
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].path, dir);
+        assert_eq!(mounts[0].degraded_secs, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_filter() {
+        let state = DaemonState::new();
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let set = |extensions: &[&str], regex: Option<&str>| Request::SetFilter {
+            filter: fakenotify_protocol::NameFilter {
+                extensions: extensions.iter().map(|e| e.to_string()).collect(),
+                regex: regex.map(str::to_string),
+            },
+        };
+
+        assert!(matches!(
+            handle_request(&state, client.id, set(&[], Some("("))).await,
+            Some(Response::Error { .. })
+        ));
+        assert!(client.filter.read().is_none());
+
+        assert_eq!(
+            handle_request(&state, client.id, set(&["mkv"], None)).await,
+            Some(Response::FilterSet)
+        );
+        let filter = client.filter.read().clone().unwrap();
+        assert!(filter.matches(Path::new("ep1.mkv"), false));
+        assert!(!filter.matches(Path::new("ep1.mkv.part"), false));
+
+        handle_request(&state, client.id, set(&[], None)).await;
+        assert!(client.filter.read().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_path_map_overrides_config() {
+        let host = std::env::temp_dir();
+        let state = DaemonState::new().with_path_map(vec![crate::config::PathMapping {
+            host: host.clone(),
+            client: PathBuf::from("/mnt/c/share"),
+        }]);
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+
+        let request = Request::SetPathMap {
+            mappings: vec![crate::config::PathMapping {
+                host: host.clone(),
+                client: PathBuf::from("/data"),
+            }],
+        };
+        assert_eq!(
+            handle_request(&state, client.id, request).await,
+            Some(Response::PathMapSet)
+        );
+
+        let add = |path: &str| Request::AddWatch {
+            path: PathBuf::from(path),
+            mask: EventMask::IN_ALL_EVENTS.bits(),
+        };
+        let Some(Response::WatchAdded { wd }) =
+            handle_request(&state, client.id, add("/data")).await
+        else {
+            panic!("expected WatchAdded");
+        };
+        assert_eq!(state.get_watch(wd).unwrap().path, host);
+
+        // The client's table replaces the configured one
+        assert!(matches!(
+            handle_request(&state, client.id, add("/mnt/c/share")).await,
+            Some(Response::Error { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_batch_requests() {
+        let state = DaemonState::new();
+        let dir = std::env::temp_dir();
+        let entries = vec![
+            fakenotify_protocol::WatchEntry {
+                path: dir.clone(),
+                mask: EventMask::IN_CREATE.bits(),
+            },
+            fakenotify_protocol::WatchEntry {
+                path: PathBuf::from("/nonexistent/fakenotify"),
+                mask: EventMask::IN_CREATE.bits(),
+            },
+        ];
+        let Some(Response::Batch { results, .. }) =
+            handle_request(&state, 1, Request::AddWatchBatch { entries }).await
+        else {
+            panic!("expected Batch");
+        };
+        let [Response::WatchAdded { wd }, Response::Error { .. }] = results[..] else {
+            panic!("unexpected results: {results:?}");
+        };
+        assert_eq!(state.get_watch(wd).unwrap().path, dir);
+
+        let request = Request::RemoveWatchBatch { wds: vec![wd, wd] };
+        assert_eq!(
+            handle_request(&state, 1, request).await,
+            Some(Response::batch(vec![
+                Response::WatchRemoved,
+                Response::error_with(
+                    ErrorCode::NoSuchWatch,
+                    format!("Watch descriptor {wd} not found")
+                ),
+            ]))
+        );
+
+        // An empty answer would be mistaken for an event
+        for request in [
+            Request::AddWatchBatch {
+                entries: Vec::new(),
+            },
+            Request::RemoveWatchBatch { wds: Vec::new() },
+        ] {
+            assert!(matches!(
+                handle_request(&state, 1, request).await,
+                Some(Response::Error {
+                    code: ErrorCode::Invalid,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_removed_watches_close_their_journal() {
+        let state = DaemonState::new().with_journal(crate::journal::Journal::new(16, 4));
+        let journal = || state.journal().unwrap();
+        let add_since = || Request::AddWatchSince {
+            path: std::env::temp_dir(),
+            mask: EventMask::IN_CREATE.bits(),
+            since: None,
+        };
+
+        let client = state
+            .register_client(Box::new(tokio::io::sink()), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let Some(Response::WatchAddedSince { wd, .. }) =
+            handle_request(&state, client.id, add_since()).await
+        else {
+            panic!("expected WatchAddedSince");
+        };
+        assert_eq!(journal().len(), 1);
+        handle_request(&state, client.id, Request::RemoveWatch { wd }).await;
+        assert_eq!(journal().len(), 0);
+
+        // A client that went away may come back for it
+        handle_request(&state, client.id, add_since()).await;
+        state.unregister_client(client.id);
+        assert_eq!(journal().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_empty_batches_need_no_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        let state = Arc::new(DaemonState::new());
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        tokio::spawn(async move {
+            let (reader, writer, peer) = accept_tcp(Some(&listener), None).await.unwrap();
+            handle_client(
+                reader,
+                writer,
+                peer,
+                state,
+                ClientPolicy::default(),
+                shutdown_rx,
+            )
+            .await
+        });
+
+        tokio::task::spawn_blocking(move || {
+            let mut client = fakenotify_client::Client::connect_to(&endpoint).unwrap();
+            let none: [(PathBuf, EventMask); 0] = [];
+            assert!(client.add_watches(none).unwrap().is_empty());
+            assert!(client.remove_watches([]).unwrap().is_empty());
+
+            // Nothing was queued as an event, and the next answer is read
+            let dir = std::env::temp_dir();
+            let added = client.add_watches([(dir, EventMask::IN_CREATE)]).unwrap();
+            assert!(matches!(added[..], [Ok(_)]));
+            assert!(!client.has_pending_event().unwrap());
+        })
+        .await
+        .unwrap();
+    }
 }
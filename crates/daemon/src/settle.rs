@@ -0,0 +1,292 @@
+//! Holding back writes until a file is complete.
+//!
+//! A large copy onto a share shows up as a file that grows from one scan
+//! to the next, each scan reporting IN_MODIFY. With `settle_scans` set, a
+//! polled root reports the creation right away but holds the writes back
+//! until the file's size and mtime stayed the same for that many scans in
+//! a row, then reports one IN_MODIFY and a synthesized IN_CLOSE_WRITE, the
+//! event downstream processing usually waits for.
+//!
+//! Tools that write to a temporary name and rename the finished file are
+//! covered by `partial_suffixes`: files ending in one (`.part`, `.tmp`)
+//! report nothing, and renaming one to its final name reports the
+//! IN_MOVED_TO followed by IN_CLOSE_WRITE.
+
+use crate::scanner::Snapshot;
+use crate::watcher::WatcherEvent;
+use notify::EventKind;
+use notify::event::{AccessKind, AccessMode, DataChange, ModifyKind, RenameMode};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A file written to since it last settled
+#[derive(Debug, Clone, Copy)]
+struct Unsettled {
+    size: u64,
+    mtime: SystemTime,
+    /// Scans in a row that found it unchanged
+    stable: u32,
+    /// Whether anything was written, so the settling reports IN_MODIFY
+    written: bool,
+}
+
+/// Holds back writes to a root's files until they settle
+#[derive(Debug)]
+pub struct Settler {
+    scans: u32,
+    suffixes: Vec<String>,
+    unsettled: HashMap<PathBuf, Unsettled>,
+}
+
+impl Settler {
+    /// Settle files after `scans` unchanged scans (0 reports writes as they
+    /// are seen), and treat names ending in one of `suffixes` as partial
+    pub fn new(scans: u32, suffixes: Vec<String>) -> Self {
+        Self {
+            scans,
+            suffixes,
+            unsettled: HashMap::new(),
+        }
+    }
+
+    /// Whether `path` is a temporary name a finished file is renamed from
+    fn is_partial(&self, path: &Path) -> bool {
+        path.file_name()
+            .map(|name| name.to_string_lossy())
+            .is_some_and(|name| {
+                self.suffixes
+                    .iter()
+                    .any(|suffix| name.len() > suffix.len() && name.ends_with(suffix.as_str()))
+            })
+    }
+
+    /// Start or restart the wait for `path` to settle
+    fn unsettle(&mut self, path: &Path, current: &Snapshot, written: bool) {
+        let Some(meta) = current.get(path) else {
+            return;
+        };
+        let unsettled = self
+            .unsettled
+            .entry(path.to_path_buf())
+            .or_insert(Unsettled {
+                size: meta.size,
+                mtime: meta.mtime,
+                stable: 0,
+                written,
+            });
+        unsettled.size = meta.size;
+        unsettled.mtime = meta.mtime;
+        unsettled.stable = 0;
+        unsettled.written |= written;
+    }
+
+    /// Rewrite one poll's events, `current` being the scan they came from:
+    /// writes to unsettled files and anything on partial files are held
+    /// back, files that settled are reported as written and closed
+    pub fn filter(&mut self, events: Vec<WatcherEvent>, current: &Snapshot) -> Vec<WatcherEvent> {
+        let mut out = Vec::with_capacity(events.len());
+        let mut seen = HashSet::new();
+        let mut events = events.into_iter().peekable();
+        while let Some(event) = events.next() {
+            let partial = !event.is_dir && self.is_partial(&event.path);
+            match event.kind {
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                    let to = events.next_if(|to| {
+                        to.ino == event.ino
+                            && matches!(
+                                to.kind,
+                                EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                            )
+                    });
+                    let unsettled = self.unsettled.remove(&event.path);
+                    if !partial {
+                        out.push(event);
+                    }
+                    let Some(to) = to else {
+                        continue;
+                    };
+                    if !to.is_dir && self.is_partial(&to.path) {
+                        continue;
+                    }
+                    let (path, ino) = (to.path.clone(), to.ino);
+                    out.push(to);
+                    if partial {
+                        out.push(closed(path, ino));
+                    } else if let Some(unsettled) = unsettled {
+                        // Still being written under its new name
+                        self.unsettled.insert(path.clone(), unsettled);
+                        seen.insert(path);
+                    }
+                }
+                _ if partial => {}
+                EventKind::Create(_) if self.scans > 0 && !event.is_dir => {
+                    let written = current.get(&event.path).is_some_and(|meta| meta.size > 0);
+                    self.unsettle(&event.path, current, written);
+                    seen.insert(event.path.clone());
+                    out.push(event);
+                }
+                EventKind::Modify(ModifyKind::Data(_)) if self.scans > 0 && !event.is_dir => {
+                    self.unsettle(&event.path, current, true);
+                    seen.insert(event.path);
+                }
+                EventKind::Remove(_) => {
+                    self.unsettled.remove(&event.path);
+                    out.push(event);
+                }
+                _ => out.push(event),
+            }
+        }
+
+        let mut settled = Vec::new();
+        let scans = self.scans;
+        self.unsettled.retain(|path, unsettled| {
+            if seen.contains(path) {
+                return true;
+            }
+            let Some(meta) = current.get(path) else {
+                return false;
+            };
+            if meta.size != unsettled.size || meta.mtime != unsettled.mtime {
+                unsettled.size = meta.size;
+                unsettled.mtime = meta.mtime;
+                unsettled.stable = 0;
+                return true;
+            }
+            unsettled.stable += 1;
+            if unsettled.stable < scans {
+                return true;
+            }
+            settled.push((path.clone(), unsettled.written, meta.ino));
+            false
+        });
+        settled.sort();
+        for (path, written, ino) in settled {
+            if written {
+                out.push(WatcherEvent {
+                    path: path.clone(),
+                    kind: EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                    is_dir: false,
+                    unlinked: false,
+                    ino,
+                });
+            }
+            out.push(closed(path, ino));
+        }
+        out
+    }
+}
+
+/// The synthesized IN_CLOSE_WRITE for a finished file
+fn closed(path: PathBuf, ino: u64) -> WatcherEvent {
+    WatcherEvent {
+        path,
+        kind: EventKind::Access(AccessKind::Close(AccessMode::Write)),
+        is_dir: false,
+        unlinked: false,
+        ino,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+    use crate::source::sim::SimSource;
+    use crate::watcher::notify_to_inotify_mask;
+    use fakenotify_protocol::EventMask;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn poll(scanner: &mut Scanner) -> Vec<(String, EventMask)> {
+        scanner
+            .poll()
+            .into_iter()
+            .map(|event| {
+                let name = event.path.file_name().unwrap().to_string_lossy();
+                (
+                    name.into_owned(),
+                    notify_to_inotify_mask(&event.kind, event.is_dir).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn name(name: &str, mask: EventMask) -> (String, EventMask) {
+        (name.to_string(), mask)
+    }
+
+    #[test]
+    fn test_growing_file_settles_once() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/drop");
+        let mut scanner = Scanner::new(sim.clone(), PathBuf::from("/mnt/drop"), true)
+            .with_settling(2, Vec::new());
+
+        sim.write("/mnt/drop/a.mkv", 10);
+        assert_eq!(poll(&mut scanner), [name("a.mkv", EventMask::IN_CREATE)]);
+        for size in [20, 30] {
+            sim.advance(Duration::from_secs(1));
+            sim.write("/mnt/drop/a.mkv", size);
+            assert!(poll(&mut scanner).is_empty());
+        }
+        // Unchanged for one scan, then for two
+        assert!(poll(&mut scanner).is_empty());
+        assert_eq!(
+            poll(&mut scanner),
+            [
+                name("a.mkv", EventMask::IN_MODIFY),
+                name("a.mkv", EventMask::IN_CLOSE_WRITE)
+            ]
+        );
+        assert!(poll(&mut scanner).is_empty());
+
+        // An empty file is only closed
+        sim.write("/mnt/drop/empty", 0);
+        assert_eq!(poll(&mut scanner), [name("empty", EventMask::IN_CREATE)]);
+        poll(&mut scanner);
+        assert_eq!(
+            poll(&mut scanner),
+            [name("empty", EventMask::IN_CLOSE_WRITE)]
+        );
+
+        // Deleted before it settled
+        sim.write("/mnt/drop/gone", 5);
+        poll(&mut scanner);
+        sim.remove("/mnt/drop/gone");
+        assert_eq!(poll(&mut scanner), [name("gone", EventMask::IN_DELETE)]);
+        assert!(poll(&mut scanner).is_empty());
+        assert!(poll(&mut scanner).is_empty());
+    }
+
+    #[test]
+    fn test_partial_file_renamed_to_final_name() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/drop");
+        let mut scanner = Scanner::new(sim.clone(), PathBuf::from("/mnt/drop"), true)
+            .with_settling(0, vec![".part".to_string(), ".tmp".to_string()]);
+
+        sim.write("/mnt/drop/a.mkv.part", 10);
+        assert!(poll(&mut scanner).is_empty());
+        sim.advance(Duration::from_secs(1));
+        sim.write("/mnt/drop/a.mkv.part", 20);
+        assert!(poll(&mut scanner).is_empty());
+        sim.rename("/mnt/drop/a.mkv.part", "/mnt/drop/a.mkv");
+        assert_eq!(
+            poll(&mut scanner),
+            [
+                name("a.mkv", EventMask::IN_MOVED_TO),
+                name("a.mkv", EventMask::IN_CLOSE_WRITE)
+            ]
+        );
+
+        // Without settling, other files report writes as they are seen
+        sim.advance(Duration::from_secs(1));
+        sim.write("/mnt/drop/a.mkv", 30);
+        assert_eq!(poll(&mut scanner), [name("a.mkv", EventMask::IN_MODIFY)]);
+        sim.write("/mnt/drop/b.tmp", 1);
+        assert!(poll(&mut scanner).is_empty());
+        sim.remove("/mnt/drop/b.tmp");
+        assert!(poll(&mut scanner).is_empty());
+    }
+}
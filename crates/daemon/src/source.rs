@@ -0,0 +1,166 @@
+//! Filesystem access abstraction for the scanner.
+//!
+//! The scanner never touches `std::fs` directly. Instead it goes through a
+//! [`ScanSource`], which lets the real filesystem be swapped for an in-memory
+//! simulated tree in tests (see [`sim`]).
+
+use serde::{Deserialize, Serialize};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub mod content;
+pub mod revalidate;
+#[cfg(test)]
+pub mod sim;
+#[cfg(target_os = "linux")]
+pub mod xattr;
+
+/// The subset of file metadata the scanner compares between polls
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryMeta {
+    /// Whether the entry is a directory
+    pub is_dir: bool,
+    /// File size in bytes
+    pub size: u64,
+    /// Last modification time
+    pub mtime: SystemTime,
+    /// Last status change, to the nanosecond where the filesystem keeps it
+    pub ctime: SystemTime,
+    /// Last access, as far as the mount's atime option keeps it, or the
+    /// epoch if unknown
+    pub atime: SystemTime,
+    /// Inode number (used to pair renames)
+    pub ino: u64,
+    /// Number of hard links to the inode
+    pub nlink: u64,
+    /// Permission and file type bits
+    pub mode: u32,
+    /// Owning user
+    pub uid: u32,
+    /// Owning group
+    pub gid: u32,
+    /// Hash of the extended attributes, or 0 if there are none or they are
+    /// not tracked
+    pub xattrs: u64,
+    /// Hash of the first and last blocks of a file, or 0 if not hashed
+    pub content: u64,
+}
+
+impl EntryMeta {
+    /// Build from `std::fs::Metadata`
+    pub fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        Self {
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            mtime: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            #[cfg(unix)]
+            ctime: u64::try_from(meta.ctime())
+                .map(|secs| {
+                    SystemTime::UNIX_EPOCH
+                        + std::time::Duration::new(secs, meta.ctime_nsec() as u32)
+                })
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            // Nothing else changes a Windows file's metadata timestamp
+            #[cfg(not(unix))]
+            ctime: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            atime: meta.accessed().unwrap_or(SystemTime::UNIX_EPOCH),
+            #[cfg(unix)]
+            ino: meta.ino(),
+            #[cfg(unix)]
+            nlink: meta.nlink(),
+            #[cfg(unix)]
+            mode: meta.mode(),
+            #[cfg(unix)]
+            uid: meta.uid(),
+            #[cfg(unix)]
+            gid: meta.gid(),
+            // No stable inode here; renames are reported as delete + create
+            #[cfg(not(unix))]
+            ino: 0,
+            #[cfg(not(unix))]
+            nlink: 1,
+            #[cfg(not(unix))]
+            mode: 0,
+            #[cfg(not(unix))]
+            uid: 0,
+            #[cfg(not(unix))]
+            gid: 0,
+            xattrs: 0,
+            content: 0,
+        }
+    }
+}
+
+/// A single directory entry returned by [`ScanSource::read_dir`]
+#[derive(Debug, Clone)]
+pub struct SourceEntry {
+    /// Full path of the entry
+    pub path: PathBuf,
+    /// Entry metadata (not following symlinks)
+    pub meta: EntryMeta,
+}
+
+/// Directory enumeration and stat, as seen by the scanner
+pub trait ScanSource: Send + Sync + 'static {
+    /// Stat a single path without following symlinks
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta>;
+
+    /// List the direct children of a directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>>;
+}
+
+/// [`ScanSource`] backed by the real filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsSource;
+
+impl ScanSource for FsSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        std::fs::symlink_metadata(path).map(|m| EntryMeta::from_metadata(&m))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    tracing::trace!(path = %path.display(), error = %e, "Skipping unreadable entry");
+                    continue;
+                }
+            };
+            // Entries can vanish between readdir and stat; skip them
+            if let Ok(meta) = entry.metadata() {
+                entries.push(SourceEntry {
+                    path: entry.path(),
+                    meta: EntryMeta::from_metadata(&meta),
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_source_reads_temp_dir() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-src-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+
+        let entries = FsSource.read_dir(&dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, dir.join("a.txt"));
+        assert_eq!(entries[0].meta.size, 5);
+        assert!(!entries[0].meta.is_dir);
+
+        assert!(FsSource.metadata(&dir).unwrap().is_dir);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Quick content hashing for [`ChangeDetection::Hash`].
+//!
+//! Hashes the first and last block of every regular file on each scan, so
+//! a same-size overwrite within the server's timestamp resolution still
+//! shows up. Reading data for every file is far costlier than a stat, which
+//! is why it is only enabled per watch.
+//!
+//! [`ChangeDetection::Hash`]: fakenotify_protocol::ChangeDetection::Hash
+
+use super::{EntryMeta, ScanSource, SourceEntry};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Bytes hashed from each end of a file
+const BLOCK: u64 = 4096;
+
+/// [`ScanSource`] that fills in [`EntryMeta::content`]
+pub struct ContentSource {
+    inner: Arc<dyn ScanSource>,
+}
+
+impl ContentSource {
+    pub fn new(inner: Arc<dyn ScanSource>) -> Self {
+        Self { inner }
+    }
+}
+
+impl ScanSource for ContentSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        let mut meta = self.inner.metadata(path)?;
+        meta.content = content_hash(path, &meta);
+        Ok(meta)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let mut entries = self.inner.read_dir(path)?;
+        for entry in &mut entries {
+            entry.meta.content = content_hash(&entry.path, &entry.meta);
+        }
+        Ok(entries)
+    }
+}
+
+/// Hash of the head and tail of `path`; 0 for anything but a readable
+/// regular file
+pub fn content_hash(path: &Path, meta: &EntryMeta) -> u64 {
+    // Opening a FIFO or device could block or have side effects
+    if !is_regular(meta) {
+        return 0;
+    }
+    match hash_ends(path, meta.size) {
+        // Keep 0 for "not hashed"
+        Ok(hash) => hash.max(1),
+        Err(e) => {
+            tracing::trace!(path = %path.display(), error = %e, "Failed to hash file");
+            0
+        }
+    }
+}
+
+fn hash_ends(path: &Path, size: u64) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = Vec::with_capacity(BLOCK as usize);
+    (&mut file).take(BLOCK).read_to_end(&mut buf)?;
+    hasher.write(&buf);
+    if size > BLOCK {
+        buf.clear();
+        file.seek(SeekFrom::Start(size.saturating_sub(BLOCK).max(BLOCK)))?;
+        file.take(BLOCK).read_to_end(&mut buf)?;
+        hasher.write(&buf);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(unix)]
+fn is_regular(meta: &EntryMeta) -> bool {
+    // S_IFMT and S_IFREG, which libc types differently per platform
+    meta.mode & 0o170_000 == 0o100_000
+}
+
+#[cfg(not(unix))]
+fn is_regular(meta: &EntryMeta) -> bool {
+    !meta.is_dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::FsSource;
+
+    #[test]
+    fn test_same_size_overwrite_changes_hash() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-content-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.db");
+        let source = ContentSource::new(Arc::new(FsSource));
+
+        let mut data = vec![b'a'; 3 * BLOCK as usize];
+        std::fs::write(&path, &data).unwrap();
+        let first = source.metadata(&path).unwrap().content;
+        assert_ne!(first, 0);
+
+        // Only the tail differs
+        *data.last_mut().unwrap() = b'b';
+        std::fs::write(&path, &data).unwrap();
+        let entries = source.read_dir(&dir).unwrap();
+        assert_ne!(entries[0].meta.content, first);
+
+        assert_eq!(source.metadata(&dir).unwrap().content, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+//! Forced attribute revalidation for NFS directories.
+//!
+//! The NFS client trusts cached directory attributes for up to `acdirmax`
+//! seconds, and with them its cached listing, so a scan can keep seeing a
+//! directory as it was. Opening a directory makes the client check its
+//! attributes with the server (close-to-open consistency), which drops a
+//! stale listing. [`RevalidateSource`] opens and closes every directory
+//! right before it is statted or listed, at the cost of one GETATTR per
+//! directory per scan; per watch through `revalidate`.
+
+use super::{EntryMeta, ScanSource, SourceEntry};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// [`ScanSource`] that revalidates directories before reading them
+pub struct RevalidateSource {
+    inner: Arc<dyn ScanSource>,
+}
+
+impl RevalidateSource {
+    pub fn new(inner: Arc<dyn ScanSource>) -> Self {
+        Self { inner }
+    }
+}
+
+impl ScanSource for RevalidateSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        let meta = self.inner.metadata(path)?;
+        if !meta.is_dir {
+            return Ok(meta);
+        }
+        // Stat again now that the cached attributes are fresh
+        revalidate(path);
+        self.inner.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        revalidate(path);
+        self.inner.read_dir(path)
+    }
+}
+
+/// Open and close the directory at `path`; failures are left to the
+/// stat or listing that follows
+fn revalidate(path: &Path) {
+    if let Err(e) = File::open(path) {
+        tracing::trace!(path = %path.display(), error = %e, "Failed to revalidate directory");
+    }
+}
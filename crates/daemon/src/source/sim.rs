@@ -0,0 +1,348 @@
+//! In-memory simulated filesystem for deterministic scanner tests.
+//!
+//! The tree carries its own virtual clock: every mutation stamps the
+//! affected entry with the current virtual time, and [`SimSource::advance`]
+//! moves the clock forward. Nothing here depends on wall-clock time or disk.
+
+use super::{EntryMeta, ScanSource, SourceEntry};
+use parking_lot::{Condvar, Mutex};
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const DIR_MODE: u32 = 0o040_755;
+const FILE_MODE: u32 = 0o100_644;
+
+struct SimTree {
+    entries: BTreeMap<PathBuf, EntryMeta>,
+    now: Duration,
+    next_ino: u64,
+    /// Granularity of stored timestamps; zero keeps them exact
+    resolution: Duration,
+}
+
+impl SimTree {
+    fn stamp(&self) -> SystemTime {
+        let now = if self.resolution.is_zero() {
+            self.now
+        } else {
+            let ticks = self.now.as_nanos() / self.resolution.as_nanos();
+            self.resolution * ticks as u32
+        };
+        SystemTime::UNIX_EPOCH + now
+    }
+
+    fn alloc_ino(&mut self) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        ino
+    }
+
+    /// Set the link count on every name of inode `ino`
+    fn set_nlink(&mut self, ino: u64, nlink: u64) {
+        let stamp = self.stamp();
+        for meta in self.entries.values_mut().filter(|meta| meta.ino == ino) {
+            meta.nlink = nlink;
+            meta.ctime = stamp;
+        }
+    }
+
+    /// Touch the parent directory's mtime, as a real filesystem would
+    fn touch_parent(&mut self, path: &Path) {
+        let stamp = self.stamp();
+        if let Some(parent) = path.parent()
+            && let Some(meta) = self.entries.get_mut(parent)
+        {
+            meta.mtime = stamp;
+        }
+    }
+}
+
+/// Mutable in-memory directory tree implementing [`ScanSource`]
+pub struct SimSource {
+    tree: Mutex<SimTree>,
+    /// Paths whose stat and listing block, like a hung NFS server
+    hung: Mutex<HashSet<PathBuf>>,
+    unhung: Condvar,
+    /// Paths left out of their parent's listing but still found by stat
+    hidden: Mutex<HashSet<PathBuf>>,
+}
+
+impl SimSource {
+    /// Create an empty tree containing only `/`
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            PathBuf::from("/"),
+            EntryMeta {
+                is_dir: true,
+                size: 0,
+                mtime: SystemTime::UNIX_EPOCH,
+                ctime: SystemTime::UNIX_EPOCH,
+                atime: SystemTime::UNIX_EPOCH,
+                ino: 1,
+                nlink: 2,
+                mode: DIR_MODE,
+                uid: 0,
+                gid: 0,
+                xattrs: 0,
+                content: 0,
+            },
+        );
+        Self {
+            tree: Mutex::new(SimTree {
+                entries,
+                now: Duration::ZERO,
+                next_ino: 2,
+                resolution: Duration::ZERO,
+            }),
+            hung: Mutex::new(HashSet::new()),
+            unhung: Condvar::new(),
+            hidden: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Make reads of `path` block until [`SimSource::unhang`]
+    pub fn hang(&self, path: impl AsRef<Path>) {
+        self.hung.lock().insert(path.as_ref().to_path_buf());
+    }
+
+    /// Let blocked and future reads of `path` through
+    pub fn unhang(&self, path: impl AsRef<Path>) {
+        self.hung.lock().remove(path.as_ref());
+        self.unhung.notify_all();
+    }
+
+    /// Leave `path` out of its parent's listing until
+    /// [`SimSource::unhide`], as a flapping NFS server's readdir does
+    pub fn hide(&self, path: impl AsRef<Path>) {
+        self.hidden.lock().insert(path.as_ref().to_path_buf());
+    }
+
+    /// List `path` again
+    pub fn unhide(&self, path: impl AsRef<Path>) {
+        self.hidden.lock().remove(path.as_ref());
+    }
+
+    fn wait_while_hung(&self, path: &Path) {
+        let mut hung = self.hung.lock();
+        while hung.contains(path) {
+            self.unhung.wait(&mut hung);
+        }
+    }
+
+    /// Move the virtual clock forward
+    pub fn advance(&self, by: Duration) {
+        self.tree.lock().now += by;
+    }
+
+    /// Store timestamps at this granularity, like a server with coarse
+    /// mtimes
+    pub fn set_resolution(&self, resolution: Duration) {
+        self.tree.lock().resolution = resolution;
+    }
+
+    /// Create a directory and any missing parents
+    pub fn mkdir_all(&self, path: impl AsRef<Path>) {
+        let mut tree = self.tree.lock();
+        let mut current = PathBuf::new();
+        for component in path.as_ref().components() {
+            current.push(component);
+            if !tree.entries.contains_key(&current) {
+                let ino = tree.alloc_ino();
+                let meta = EntryMeta {
+                    is_dir: true,
+                    size: 0,
+                    mtime: tree.stamp(),
+                    ctime: tree.stamp(),
+                    atime: tree.stamp(),
+                    ino,
+                    nlink: 2,
+                    mode: DIR_MODE,
+                    uid: 0,
+                    gid: 0,
+                    xattrs: 0,
+                    content: 0,
+                };
+                tree.entries.insert(current.clone(), meta);
+                tree.touch_parent(&current);
+            }
+        }
+    }
+
+    /// Create or overwrite a file with the given size
+    pub fn write(&self, path: impl AsRef<Path>, size: u64) {
+        let path = path.as_ref();
+        let mut tree = self.tree.lock();
+        let stamp = tree.stamp();
+        if let Some(meta) = tree.entries.get_mut(path) {
+            meta.size = size;
+            meta.mtime = stamp;
+            meta.ctime = stamp;
+            return;
+        }
+        let ino = tree.alloc_ino();
+        tree.entries.insert(
+            path.to_path_buf(),
+            EntryMeta {
+                is_dir: false,
+                size,
+                mtime: stamp,
+                ctime: stamp,
+                atime: stamp,
+                ino,
+                nlink: 1,
+                mode: FILE_MODE,
+                uid: 0,
+                gid: 0,
+                xattrs: 0,
+                content: 0,
+            },
+        );
+        tree.touch_parent(path);
+    }
+
+    /// Change permission bits without touching mtime
+    pub fn chmod(&self, path: impl AsRef<Path>, perm: u32) {
+        self.change(path.as_ref(), |meta| {
+            meta.mode = (meta.mode & !0o7777) | (perm & 0o7777);
+        });
+    }
+
+    /// Change owner and group without touching mtime
+    pub fn chown(&self, path: impl AsRef<Path>, uid: u32, gid: u32) {
+        self.change(path.as_ref(), |meta| {
+            meta.uid = uid;
+            meta.gid = gid;
+        });
+    }
+
+    /// Replace the extended attribute hash without touching mtime
+    pub fn set_xattrs(&self, path: impl AsRef<Path>, hash: u64) {
+        self.change(path.as_ref(), |meta| meta.xattrs = hash);
+    }
+
+    /// Set the mtime, as `touch -d` would
+    pub fn set_mtime(&self, path: impl AsRef<Path>, mtime: SystemTime) {
+        self.change(path.as_ref(), |meta| meta.mtime = mtime);
+    }
+
+    /// Read a file, which only moves its atime
+    pub fn read(&self, path: impl AsRef<Path>) {
+        let mut tree = self.tree.lock();
+        let stamp = tree.stamp();
+        if let Some(meta) = tree.entries.get_mut(path.as_ref()) {
+            meta.atime = stamp;
+        }
+    }
+
+    /// Replace the content hash without touching any timestamp, as an
+    /// overwrite within the timestamp resolution looks
+    pub fn set_content(&self, path: impl AsRef<Path>, hash: u64) {
+        if let Some(meta) = self.tree.lock().entries.get_mut(path.as_ref()) {
+            meta.content = hash;
+        }
+    }
+
+    /// Apply a metadata change, which updates ctime
+    fn change(&self, path: &Path, apply: impl FnOnce(&mut EntryMeta)) {
+        let mut tree = self.tree.lock();
+        let stamp = tree.stamp();
+        if let Some(meta) = tree.entries.get_mut(path) {
+            apply(meta);
+            meta.ctime = stamp;
+        }
+    }
+
+    /// Add another name for the file at `existing`, sharing its inode
+    pub fn link(&self, existing: impl AsRef<Path>, new: impl AsRef<Path>) {
+        let mut tree = self.tree.lock();
+        let Some(meta) = tree.entries.get(existing.as_ref()).copied() else {
+            return;
+        };
+        tree.set_nlink(meta.ino, meta.nlink + 1);
+        tree.entries.insert(
+            new.as_ref().to_path_buf(),
+            EntryMeta {
+                nlink: meta.nlink + 1,
+                ..meta
+            },
+        );
+        tree.touch_parent(new.as_ref());
+    }
+
+    /// Remove an entry and everything beneath it
+    ///
+    /// Other names of a removed file's inode keep it, one link fewer.
+    pub fn remove(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let mut tree = self.tree.lock();
+        let unlinked = tree.entries.get(path).filter(|meta| !meta.is_dir).copied();
+        tree.entries.retain(|p, _| !p.starts_with(path));
+        if let Some(meta) = unlinked {
+            tree.set_nlink(meta.ino, meta.nlink.saturating_sub(1));
+        }
+        tree.touch_parent(path);
+    }
+
+    /// Move an entry (and its subtree) to a new path, keeping inode numbers
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut tree = self.tree.lock();
+        let moved: Vec<(PathBuf, EntryMeta)> = tree
+            .entries
+            .iter()
+            .filter(|(p, _)| p.starts_with(from))
+            .map(|(p, m)| (p.clone(), *m))
+            .collect();
+        for (old, meta) in moved {
+            tree.entries.remove(&old);
+            let new = match old.strip_prefix(from) {
+                Ok(suffix) if !suffix.as_os_str().is_empty() => to.join(suffix),
+                _ => to.to_path_buf(),
+            };
+            tree.entries.insert(new, meta);
+        }
+        tree.touch_parent(from);
+        tree.touch_parent(to);
+    }
+}
+
+impl Default for SimSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScanSource for SimSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        self.wait_while_hung(path);
+        self.tree
+            .lock()
+            .entries
+            .get(path)
+            .copied()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        self.wait_while_hung(path);
+        let hidden = self.hidden.lock();
+        let tree = self.tree.lock();
+        match tree.entries.get(path) {
+            Some(meta) if meta.is_dir => {}
+            Some(_) => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+        Ok(tree
+            .entries
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(path) && !hidden.contains(*p))
+            .map(|(p, m)| SourceEntry {
+                path: p.clone(),
+                meta: *m,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,136 @@
+//! Extended attribute tracking (Linux).
+//!
+//! `setfattr` and ACL changes touch neither mtime nor mode, so the scanner
+//! only notices them through a hash of each entry's attributes. Reading them
+//! costs two extra round trips per entry on NFS, so this is opt-in through
+//! `track_xattrs`.
+
+use super::{EntryMeta, ScanSource, SourceEntry};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::{CString, c_char};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// [`ScanSource`] that fills in [`EntryMeta::xattrs`]
+pub struct XattrSource {
+    inner: Arc<dyn ScanSource>,
+}
+
+impl XattrSource {
+    pub fn new(inner: Arc<dyn ScanSource>) -> Self {
+        Self { inner }
+    }
+}
+
+impl ScanSource for XattrSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        let mut meta = self.inner.metadata(path)?;
+        meta.xattrs = xattr_hash(path);
+        Ok(meta)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let mut entries = self.inner.read_dir(path)?;
+        for entry in &mut entries {
+            entry.meta.xattrs = xattr_hash(&entry.path);
+        }
+        Ok(entries)
+    }
+}
+
+/// Hash of the names and values of `path`'s extended attributes, not
+/// following symlinks; 0 if it has none or they cannot be read
+pub fn xattr_hash(path: &Path) -> u64 {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return 0;
+    };
+    // SAFETY: c_path is a valid C string and buf has room for len bytes
+    let Ok(list) = read_sized(|buf, len| unsafe { libc::llistxattr(c_path.as_ptr(), buf, len) })
+    else {
+        return 0;
+    };
+    let mut names: Vec<&[u8]> = list.split(|&b| b == 0).filter(|n| !n.is_empty()).collect();
+    if names.is_empty() {
+        return 0;
+    }
+    // The listing order is up to the filesystem
+    names.sort_unstable();
+
+    let mut hasher = DefaultHasher::new();
+    for name in names {
+        let Ok(c_name) = CString::new(name) else {
+            continue;
+        };
+        // SAFETY: both are valid C strings and buf has room for len bytes
+        let value = read_sized(|buf, len| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+        });
+        name.hash(&mut hasher);
+        // An attribute removed between listing and reading hashes as empty
+        value.unwrap_or_default().hash(&mut hasher);
+    }
+    // Keep 0 for "no attributes"
+    hasher.finish().max(1)
+}
+
+/// Call a size-then-fill xattr function until the buffer is big enough
+fn read_sized(call: impl Fn(*mut c_char, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = call(buf.as_mut_ptr().cast(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        // Grew since we asked for the size
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::FsSource;
+
+    #[test]
+    fn test_xattr_change_changes_hash() {
+        let path = std::env::temp_dir().join(format!("fakenotify-xattr-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let source = XattrSource::new(Arc::new(FsSource));
+        assert_eq!(source.metadata(&path).unwrap().xattrs, 0);
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let set = |value: &[u8]| {
+            // SAFETY: valid C strings and a value buffer of the given length
+            unsafe {
+                libc::setxattr(
+                    c_path.as_ptr(),
+                    c"user.fakenotify".as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            }
+        };
+        if set(b"one") != 0 {
+            // The temp filesystem does not take user attributes
+            std::fs::remove_file(&path).unwrap();
+            return;
+        }
+        let first = source.metadata(&path).unwrap().xattrs;
+        assert_ne!(first, 0);
+        assert_eq!(set(b"two"), 0);
+        assert_ne!(source.metadata(&path).unwrap().xattrs, first);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
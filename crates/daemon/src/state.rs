@@ -4,51 +4,384 @@
 //! - Connected clients
 //! - Active watches
 //! - Watch descriptor allocation
+//! - Emulated per-user inotify limits
 
-use fakenotify_protocol::EventMask;
+use crate::audit::AuditLog;
+use crate::catchup::CatchUp;
+use crate::config::{
+    AccessEvents, ClientClass, LimitsConfig, PathMapping, WatchConfig, map_to_host,
+};
+#[cfg(unix)]
+use crate::event_log::EventLog;
+use crate::filter::EventFilter;
+use crate::health::MountMonitor;
+use crate::journal::Journal;
+use crate::json;
+use crate::media::MediaRefresher;
+use crate::plugin::Plugins;
+use crate::queue::{EventQueue, Pushed, QueueOverflow, QueuePolicy, QueueReceiver};
+use crate::rate::{NoisyPolicy, RateTracker};
+use crate::trigger::Triggers;
+use crate::watchdog::Watchdog;
+use crate::watcher::{ScanInfo, WatcherManager};
+#[cfg(unix)]
+use crate::watchman::Watchman;
+use bytes::Bytes;
+use fakenotify_protocol::{
+    Capabilities, ClientInfo, ClientSummary, EventMask, FrameKind, FramedMessage, InotifyEvent,
+    PathRate, Response, UserUsage, WatchSummary,
+};
 use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
-use std::time::Instant;
-use tokio::io::AsyncWriteExt;
-use tokio::net::unix::OwnedWriteHalf;
-use tokio::sync::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Mutex, Notify, broadcast, watch};
 
 /// Unique client identifier
 pub type ClientId = u64;
 
+/// Optional protocol features this daemon can use with a client
+pub const CAPABILITIES: Capabilities = Capabilities::EVENT_BATCH
+    .union(Capabilities::COMPRESSION)
+    .union(Capabilities::REQUEST_IDS)
+    .union(Capabilities::CONTROL)
+    .union(Capabilities::RESUME)
+    .union(Capabilities::JOURNAL)
+    .union(Capabilities::JSON)
+    .union(Capabilities::MASK_CLAMP)
+    .union(Capabilities::HEARTBEAT);
+
 /// Watch descriptor (matches inotify wd type)
 pub type WatchDescriptor = i32;
 
+/// An emulated `/proc/sys/fs/inotify` limit that a request would exceed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum LimitExceeded {
+    /// Too many connections for this uid
+    #[error("max_user_instances")]
+    Instances,
+    /// Too many watches for this uid
+    #[error("max_user_watches")]
+    Watches,
+}
+
+/// How long closing a drained client may take before it is abandoned
+pub const DRAIN_GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Consecutive failed event writes after which a client is given up on
+const MAX_SEND_FAILURES: u32 = 3;
+
+/// Most queued events written to a client at once
+const MAX_BATCH_EVENTS: usize = 512;
+
+/// Write half of a client connection, whatever the transport
+pub type ClientWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Credentials of the process on the other end of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    /// uid that limits are accounted against
+    pub uid: u32,
+    /// Process ID, known for local Unix socket peers
+    pub pid: Option<i32>,
+    /// Whether the kernel vouched for `uid`, as it does for Unix socket
+    /// peers; only those can be administrators
+    pub local: bool,
+}
+
+impl Peer {
+    /// A local peer known only by uid
+    #[cfg(test)]
+    pub fn from_uid(uid: u32) -> Self {
+        Self {
+            uid,
+            pid: None,
+            local: true,
+        }
+    }
+
+    /// A peer whose credentials are unknown, such as one on the network or
+    /// in a VM: accounted against the daemon's uid, but never an
+    /// administrator
+    pub fn remote() -> Self {
+        Self {
+            uid: crate::server::own_uid(),
+            pid: None,
+            local: false,
+        }
+    }
+}
+
 /// Information about a connected client
 pub struct Client {
     /// Unique client ID
     pub id: ClientId,
+    /// Secret a control connection presents to act for this client
+    pub session: String,
+    /// Peer uid, which limits are accounted against
+    pub uid: u32,
+    /// Peer pid, used to resolve paths in its mount namespace
+    pub pid: Option<i32>,
+    /// Whether the peer's uid was vouched for by the kernel
+    pub local: bool,
     /// Write half of the socket (for sending events)
-    pub writer: Mutex<OwnedWriteHalf>,
+    pub writer: Mutex<ClientWriter>,
     /// Watches owned by this client
     pub watches: RwLock<Vec<WatchDescriptor>>,
+    /// Watches added with `AddWatchSince`, whose events are sent numbered
+    pub journaled: RwLock<HashSet<WatchDescriptor>>,
+    /// Path translation set by the client, overriding the configured one
+    pub path_map: RwLock<Option<Vec<PathMapping>>>,
+    /// Event names this client wants, set with `SetFilter`
+    pub filter: RwLock<Option<EventFilter>>,
+    /// Name, pid and version the client reported with `RegisterClient`
+    pub info: RwLock<ClientInfo>,
+    /// Namespace the client joined with `HelloNamespace`, if any
+    namespace: RwLock<Option<String>>,
+    /// Features negotiated with `RegisterClient`, which decide how its
+    /// events are encoded
+    capabilities: RwLock<Capabilities>,
     /// Connection time
-    #[allow(dead_code)]
     pub connected_at: Instant,
+    /// When the client last sent anything
+    last_seen: parking_lot::Mutex<Instant>,
+    /// Framed events waiting to be written, capped by the client's
+    /// [`QueuePolicy`]; each buffer is shared with the other clients
+    /// receiving the event
+    queue: Arc<EventQueue>,
+    /// Number of events in `queue`
+    queued: AtomicUsize,
+    /// Set when an event was dropped because `queue` was full
+    overflowed: AtomicBool,
+    /// Raised once the socket is found dead, or the queue overflowed with
+    /// `disconnect`, for the connection handler
+    broken: Notify,
+    /// Set once `broken` was raised for an overflow, until a resume
+    disconnecting: AtomicBool,
+    /// Set once the queue overflowed with `drop_session`; `evict` wakes
+    /// the connection handler and session timer to unregister the client
+    evicting: AtomicBool,
+    evict: Notify,
+    /// Whether a connection is writing out the queue, and how many times
+    /// the client lost one; while detached, events stay queued for a
+    /// connection resuming the session
+    link: watch::Sender<(bool, u64)>,
 }
 
 impl Client {
-    pub fn new(id: ClientId, writer: OwnedWriteHalf) -> Self {
-        Self {
+    /// Create a client and the receiving end of its event queue
+    pub fn new(
+        id: ClientId,
+        peer: Peer,
+        writer: ClientWriter,
+        policy: QueuePolicy,
+    ) -> (Self, QueueReceiver) {
+        let (queue, rx) = EventQueue::new(policy);
+        let client = Self {
             id,
+            session: new_session_token(),
+            uid: peer.uid,
+            pid: peer.pid,
+            local: peer.local,
             writer: Mutex::new(writer),
             watches: RwLock::new(Vec::new()),
+            journaled: RwLock::new(HashSet::new()),
+            path_map: RwLock::new(None),
+            filter: RwLock::new(None),
+            info: RwLock::new(ClientInfo::default()),
+            namespace: RwLock::new(None),
+            capabilities: RwLock::new(Capabilities::empty()),
             connected_at: Instant::now(),
-        }
+            last_seen: parking_lot::Mutex::new(Instant::now()),
+            queue,
+            queued: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+            broken: Notify::new(),
+            disconnecting: AtomicBool::new(false),
+            evicting: AtomicBool::new(false),
+            evict: Notify::new(),
+            link: watch::Sender::new((true, 0)),
+        };
+        (client, rx)
     }
 
-    /// Send raw event bytes to this client
+    /// Send framed events, or responses queued among them, to this client,
+    /// as JSON lines if it asked for that
     pub async fn send_event(&self, event_bytes: &[u8]) -> std::io::Result<()> {
+        self.send_frames(event_bytes).await
+    }
+
+    /// Send framed responses to this client, as JSON lines if it asked for
+    /// that
+    pub async fn send_response(&self, framed: &[u8]) -> std::io::Result<()> {
+        self.send_frames(framed).await
+    }
+
+    async fn send_frames(&self, framed: &[u8]) -> std::io::Result<()> {
+        let lines;
+        let bytes = if self.capabilities().contains(Capabilities::JSON) {
+            lines = json::lines(framed);
+            &lines
+        } else {
+            framed
+        };
         let mut writer = self.writer.lock().await;
-        writer.write_all(event_bytes).await
+        writer.write_all(bytes).await
+    }
+
+    /// Queue a framed event for delivery without waiting on the socket
+    ///
+    /// By default, like the kernel, a full queue drops the event and the
+    /// client later receives a single IN_Q_OVERFLOW; the client's
+    /// [`QueueOverflow`] may drop older events instead, or give up on the
+    /// connection or the whole session. Returns false if the event was
+    /// dropped.
+    pub fn queue_event(&self, framed: Bytes) -> bool {
+        match self.queue.push(framed) {
+            Pushed::Queued => {
+                self.queued.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Pushed::Overflowed { queued, dropped } => {
+                self.queued
+                    .fetch_add(usize::from(queued), Ordering::Relaxed);
+                self.queued.fetch_sub(dropped, Ordering::Relaxed);
+                self.overflow();
+                queued
+            }
+            Pushed::GaveUp { action, dropped } => {
+                self.queued.fetch_sub(dropped, Ordering::Relaxed);
+                self.overflow();
+                match action {
+                    QueueOverflow::DropSession => {
+                        if !self.evicting.swap(true, Ordering::Relaxed) {
+                            tracing::warn!(client_id = self.id, "Dropping session");
+                            self.evict.notify_waiters();
+                        }
+                    }
+                    _ => {
+                        if self.is_attached() && !self.disconnecting.swap(true, Ordering::Relaxed) {
+                            tracing::warn!(client_id = self.id, "Disconnecting client");
+                            self.broken.notify_one();
+                        }
+                    }
+                }
+                false
+            }
+            Pushed::Closed => false,
+        }
+    }
+
+    /// Note that events were lost, for the client to be told
+    fn overflow(&self) {
+        if !self.overflowed.swap(true, Ordering::Relaxed) {
+            tracing::warn!(client_id = self.id, "Event queue overflowed");
+        }
+    }
+
+    /// Replace the caps on the client's queue
+    pub fn set_queue_policy(&self, policy: QueuePolicy) {
+        self.queue.set_policy(policy);
+    }
+
+    /// Features negotiated with the client
+    pub fn capabilities(&self) -> Capabilities {
+        *self.capabilities.read()
+    }
+
+    /// Wait until event delivery has given up on this client's socket
+    pub async fn broken(&self) {
+        self.broken.notified().await;
+    }
+
+    /// Wait until the client's queue overflowed with `drop_session`
+    pub async fn evicted(&self) {
+        loop {
+            let evict = self.evict.notified();
+            if self.evicting.load(Ordering::Relaxed) {
+                return;
+            }
+            evict.await;
+        }
+    }
+
+    /// Whether a connection currently receives this client's events
+    pub fn is_attached(&self) -> bool {
+        self.link.borrow().0
+    }
+
+    /// Stop writing events, keeping them queued for a resumed session;
+    /// returns a count to pass to [`Client::detached_since`]
+    pub fn detach(&self) -> u64 {
+        let mut detaches = 0;
+        self.link.send_modify(|(attached, count)| {
+            *attached = false;
+            *count += 1;
+            detaches = *count;
+        });
+        detaches
+    }
+
+    /// Whether the client has stayed detached since `detach` returned
+    /// `detaches`
+    pub fn detached_since(&self, detaches: u64) -> bool {
+        *self.link.borrow() == (false, detaches)
+    }
+
+    /// Claim a detached client for a resuming connection; false if it is
+    /// attached already
+    pub fn reattach(&self) -> bool {
+        let reattached = self
+            .link
+            .send_if_modified(|(attached, _)| !std::mem::replace(attached, true));
+        if reattached {
+            self.disconnecting.store(false, Ordering::Relaxed);
+        }
+        reattached
+    }
+
+    /// Record that the client just sent something
+    pub fn touch(&self) {
+        *self.last_seen.lock() = Instant::now();
+    }
+
+    /// How long since the client last sent anything
+    pub fn idle(&self) -> Duration {
+        self.last_seen.lock().elapsed()
+    }
+
+    /// Flush queued events, then tell the client each of its watches is gone
+    /// (IN_IGNORED) and close the connection
+    ///
+    /// Events still queued after `timeout` are dropped.
+    pub async fn drain(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while self.queued_events() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let wds = self.watches.read().clone();
+        let goodbye = async {
+            for wd in wds {
+                let ignored = InotifyEvent::new(wd, EventMask::IN_IGNORED.bits(), 0);
+                self.send_event(&FramedMessage::frame_event(&ignored.header_to_bytes()))
+                    .await?;
+            }
+            self.writer.lock().await.shutdown().await
+        };
+        // A peer that stopped reading must not hold up shutdown
+        if let Ok(Err(e)) = tokio::time::timeout(DRAIN_GOODBYE_TIMEOUT, goodbye).await {
+            tracing::debug!(client_id = self.id, error = %e, "Failed to close client");
+        }
+    }
+
+    /// Number of events waiting to be written
+    pub fn queued_events(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
     }
 
     /// Add a watch to this client's list
@@ -59,9 +392,226 @@ impl Client {
     /// Remove a watch from this client's list
     pub fn remove_watch(&self, wd: WatchDescriptor) {
         self.watches.write().retain(|&w| w != wd);
+        self.journaled.write().remove(&wd);
+    }
+
+    /// Whether the client is a local process running as root or as the
+    /// daemon's own user
+    pub fn is_administrator(&self) -> bool {
+        self.local && (self.uid == 0 || self.uid == crate::server::own_uid())
+    }
+
+    /// Namespace the client's watches belong to: the one it joined, or
+    /// the one named after its uid, or for a remote peer, which may share
+    /// the daemon's uid with anyone, one of its own, apart from those
+    /// remote peers can join
+    pub fn namespace(&self) -> String {
+        self.namespace.read().clone().unwrap_or_else(|| {
+            if self.local {
+                self.uid.to_string()
+            } else {
+                format!("remote/client/{}", self.id)
+            }
+        })
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Ends the event writer once it has written what is left
+        self.queue.close();
+    }
+}
+
+/// 128 random bits, hex-encoded
+fn new_session_token() -> String {
+    let mut bytes = [0u8; 16];
+    if let Err(e) = getrandom::fill(&mut bytes) {
+        // Such a client cannot be attached to, which only costs control
+        // connections
+        tracing::warn!(error = %e, "No randomness for a session token");
+        return String::new();
+    }
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Whether a write error means the peer is gone for good
+fn is_dead_socket(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::NotConnected
+    )
+}
+
+/// Drain a client's event queue onto its socket
+///
+/// Holds only a weak reference, so the task ends once the client is
+/// unregistered and dropped, closing its queue. While the client is
+/// detached, events are left queued, and those a dead socket did not take
+/// are kept, for whichever connection resumes the session.
+async fn run_event_writer(client: Weak<Client>, mut rx: QueueReceiver, compress_threshold: usize) {
+    let mut failures = 0;
+    let mut frames = Vec::new();
+    loop {
+        if frames.is_empty() && rx.recv_many(&mut frames, MAX_BATCH_EVENTS).await == 0 {
+            return;
+        }
+        let Some(strong) = client.upgrade() else {
+            return;
+        };
+        if !strong.is_attached() {
+            let mut link = strong.link.subscribe();
+            // Waiting must not keep the client alive
+            drop(strong);
+            if link.wait_for(|&(attached, _)| attached).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        let client = strong;
+        let count = frames.len();
+        let framed = encode_events(&frames, client.capabilities(), compress_threshold);
+        let sent = client.send_event(&framed).await;
+        if let Err(e) = sent {
+            failures += 1;
+            if failures < MAX_SEND_FAILURES && !is_dead_socket(&e) {
+                tracing::warn!(client_id = client.id, error = %e, "Failed to send event to client");
+                client.queued.fetch_sub(count, Ordering::Relaxed);
+                frames.clear();
+                continue;
+            }
+            tracing::warn!(
+                client_id = client.id,
+                pid = ?client.pid,
+                error = %e,
+                "Client socket is dead, disconnecting"
+            );
+            client.broken.notify_one();
+            failures = 0;
+            // The connection handler detaches the client; wait for a
+            // connection to resume it
+            let (_, detaches) = *client.link.borrow();
+            let mut link = client.link.subscribe();
+            drop(client);
+            let resumed = link
+                .wait_for(|&(attached, count)| attached && count != detaches)
+                .await;
+            if resumed.is_err() {
+                return;
+            }
+            continue;
+        }
+        // Only now, so an empty queue means everything reached the socket
+        client.queued.fetch_sub(count, Ordering::Relaxed);
+        frames.clear();
+        failures = 0;
+
+        // Report an overflow once everything queued before it is delivered
+        if rx.is_empty() && client.overflowed.swap(false, Ordering::Relaxed) {
+            let overflow = InotifyEvent::new(-1, EventMask::IN_Q_OVERFLOW.bits(), 0);
+            let _ = client
+                .send_event(&FramedMessage::frame_event(&overflow.header_to_bytes()))
+                .await;
+        }
     }
 }
 
+/// Everything a burst left queued, as one write
+///
+/// Clients that negotiated batching get it as one `EventBatch` frame,
+/// compressed if they negotiated that too; others, and bursts holding
+/// numbered events, get the frames back to back.
+fn encode_events(frames: &[Bytes], capabilities: Capabilities, compress_threshold: usize) -> Bytes {
+    if let [framed] = frames {
+        return framed.clone();
+    }
+    if !capabilities.contains(Capabilities::EVENT_BATCH)
+        || frames
+            .iter()
+            .any(|framed| FramedMessage::read_kind(framed) != Some(FrameKind::Event))
+    {
+        return frames.concat().into();
+    }
+    let batch = Response::EventBatch {
+        count: frames.len() as u32,
+        events: frames
+            .iter()
+            .flat_map(|framed| &framed[4..])
+            .copied()
+            .collect(),
+    };
+    let Ok(payload) = batch.to_bytes() else {
+        return frames.concat().into();
+    };
+    let framed = FramedMessage::frame(&payload);
+    if capabilities.contains(Capabilities::COMPRESSION)
+        && let Some(compressed) = compress_frame(&framed, compress_threshold)
+    {
+        return compressed;
+    }
+    framed.into()
+}
+
+/// `framed` compressed, if it is at least `threshold` bytes (0 never)
+/// and compressing shrinks it
+fn compress_frame(framed: &[u8], threshold: usize) -> Option<Bytes> {
+    if threshold == 0 || framed.len() < 4 + threshold {
+        return None;
+    }
+    let kind = FramedMessage::read_kind(framed)?;
+    let payload = fakenotify_protocol::compress_payload(kind, &framed[4..])?;
+    Some(Bytes::from(FramedMessage::frame(&payload)))
+}
+
+/// Read-only copy of the watch table, shared with the dispatcher
+#[derive(Default)]
+pub struct DispatchIndex {
+    /// [`DaemonState`] generation this was built from
+    generation: u64,
+    path_to_wd: HashMap<PathBuf, WatchDescriptor>,
+    watches: HashMap<WatchDescriptor, DispatchWatch>,
+}
+
+/// A watch and its subscribed clients, as of a [`DispatchIndex`]
+pub struct DispatchWatch {
+    pub info: WatchInfo,
+    pub clients: Vec<Arc<Client>>,
+}
+
+impl DispatchIndex {
+    /// Every watch covering `path`, innermost first: one on the path
+    /// itself, and the recursive ones on any of its parents
+    pub fn covering<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a DispatchWatch> {
+        path.ancestors()
+            .enumerate()
+            .filter_map(|(depth, path)| {
+                Some((depth, self.watches.get(self.path_to_wd.get(path)?)?))
+            })
+            .filter(|(depth, watch)| *depth == 0 || watch.info.recursive)
+            .map(|(_, watch)| watch)
+    }
+
+    /// Every watch that misses events when those under `root` are dropped:
+    /// the recursive ones enclosing `root` and every watch inside it
+    pub fn within<'a>(&'a self, root: &'a Path) -> impl Iterator<Item = &'a DispatchWatch> {
+        self.watches.values().filter(move |watch| {
+            watch.info.path.starts_with(root)
+                || (watch.info.recursive && root.starts_with(&watch.info.path))
+        })
+    }
+}
+
+/// Whose watches a client sees and may change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    /// Every client's
+    All,
+    /// Those of the clients in one namespace
+    Namespace(String),
+}
+
 /// Information about a watch
 #[derive(Debug, Clone)]
 pub struct WatchInfo {
@@ -75,6 +625,10 @@ pub struct WatchInfo {
     pub recursive: bool,
     /// Clients subscribed to this watch
     pub clients: Vec<ClientId>,
+    /// When the path was first found missing, if it still is
+    pub missing_since: Option<Instant>,
+    /// Tags set on the watch itself, sorted; roots add their own
+    pub tags: Vec<String>,
 }
 
 /// Shared daemon state
@@ -88,12 +642,92 @@ pub struct DaemonState {
     /// Path to watch descriptor mapping (for deduplication)
     path_to_wd: RwLock<HashMap<PathBuf, WatchDescriptor>>,
 
+    /// Bumped on every change to `clients`, `watches` or `path_to_wd`
+    generation: AtomicU64,
+
+    /// Snapshot of the above for the dispatcher, as of some generation
+    dispatch_index: RwLock<Arc<DispatchIndex>>,
+
+    /// Held by the writer rebuilding `dispatch_index`
+    publishing: parking_lot::Mutex<()>,
+
     /// Next client ID
     next_client_id: AtomicU64,
 
     /// Next watch descriptor
     next_wd: AtomicI32,
 
+    /// Emulated inotify limits
+    limits: LimitsConfig,
+
+    /// Queue settings for particular clients, overriding `limits`
+    client_classes: Vec<ClientClass>,
+
+    /// Client-to-host path prefixes for requested watch paths
+    path_map: Vec<PathMapping>,
+
+    /// Resolve paths of clients in other mount namespaces via `/proc/<pid>`
+    resolve_namespaces: bool,
+
+    /// Event rates, when `enable_stats` is set
+    rates: Option<parking_lot::Mutex<RateTracker>>,
+
+    /// Health of the mounts watched roots live on
+    mount_health: Arc<MountMonitor>,
+
+    /// Events dropped because the dispatcher fell behind the watchers
+    dropped_events: Arc<AtomicU64>,
+
+    /// Watchdog for stuck scanner reads, when `scan_timeout` is set
+    watchdog: Option<Arc<Watchdog>>,
+
+    /// Tells the server and client handlers to shut down
+    shutdown: Option<broadcast::Sender<()>>,
+
+    /// Audit trail of connections and watch requests, when enabled
+    audit: Option<AuditLog>,
+
+    /// Offline changes sent to clients re-adding watches after a restart
+    catch_up: Option<Arc<CatchUp>>,
+
+    /// Library scans requested from media servers, when configured
+    media: Option<Arc<MediaRefresher>>,
+
+    /// Trigger files updated as changes settle, when configured
+    triggers: Option<Arc<Triggers>>,
+
+    /// Plugins events pass through before dispatch, when configured
+    plugins: Option<Plugins>,
+
+    /// Numbered events for clients resuming from a cursor, when enabled
+    journal: Option<Journal>,
+
+    /// Roots watched by Watchman clients, when the endpoint is enabled
+    #[cfg(unix)]
+    watchman: Option<Arc<Watchman>>,
+
+    /// Events written to syslog or the journal, when configured
+    #[cfg(unix)]
+    event_log: Option<EventLog>,
+
+    /// Smallest frame compressed for clients that negotiated it; 0 when
+    /// compression is off
+    compress_threshold: usize,
+
+    /// How long a client that lost its connection is kept for resuming;
+    /// zero unregisters it at once
+    session_timeout: Duration,
+
+    /// What watches asking for IN_ACCESS or IN_OPEN on polled roots get
+    access_events: AccessEvents,
+
+    /// Confine clients to the watches of their own namespace
+    multi_tenant: bool,
+
+    /// Scanner of the watched roots, once started; its roots are advertised
+    /// to clients choosing an instance and can be exported and imported
+    watcher: OnceLock<parking_lot::Mutex<WatcherManager>>,
+
     /// Daemon start time
     #[allow(dead_code)]
     started_at: Instant,
@@ -101,23 +735,624 @@ pub struct DaemonState {
 
 impl DaemonState {
     pub fn new() -> Self {
+        Self::with_limits(LimitsConfig::default())
+    }
+
+    /// Create state enforcing the given limits
+    pub fn with_limits(limits: LimitsConfig) -> Self {
         Self {
             clients: RwLock::new(HashMap::new()),
             watches: RwLock::new(HashMap::new()),
             path_to_wd: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(1),
+            dispatch_index: RwLock::new(Arc::new(DispatchIndex::default())),
+            publishing: parking_lot::Mutex::new(()),
             next_client_id: AtomicU64::new(1),
             next_wd: AtomicI32::new(1),
+            limits,
+            client_classes: Vec::new(),
+            path_map: Vec::new(),
+            resolve_namespaces: true,
+            rates: None,
+            mount_health: Arc::new(MountMonitor::default()),
+            dropped_events: Arc::new(AtomicU64::new(0)),
+            watchdog: None,
+            shutdown: None,
+            audit: None,
+            catch_up: None,
+            media: None,
+            triggers: None,
+            plugins: None,
+            journal: None,
+            #[cfg(unix)]
+            watchman: None,
+            #[cfg(unix)]
+            event_log: None,
+            compress_threshold: 0,
+            session_timeout: Duration::ZERO,
+            access_events: AccessEvents::default(),
+            multi_tenant: false,
+            watcher: OnceLock::new(),
             started_at: Instant::now(),
         }
     }
 
-    /// Register a new client
-    pub fn register_client(&self, writer: OwnedWriteHalf) -> Arc<Client> {
+    /// Translate client watch paths through `path_map`
+    pub fn with_path_map(mut self, path_map: Vec<PathMapping>) -> Self {
+        self.path_map = path_map;
+        self
+    }
+
+    /// Enable or disable resolving paths through the client's mount namespace
+    pub fn with_namespace_resolution(mut self, enabled: bool) -> Self {
+        self.resolve_namespaces = enabled;
+        self
+    }
+
+    /// Track event rates per watch and directory, handling noisy
+    /// directories according to `policy`
+    pub fn with_rate_tracking(mut self, policy: NoisyPolicy) -> Self {
+        self.rates = Some(parking_lot::Mutex::new(RateTracker::new(policy)));
+        self
+    }
+
+    /// Probe mounts with `monitor` before scanning them
+    pub fn with_mount_health(mut self, monitor: MountMonitor) -> Self {
+        self.mount_health = Arc::new(monitor);
+        self
+    }
+
+    /// Mount health shared with the scan tasks
+    pub fn mount_health(&self) -> Arc<MountMonitor> {
+        Arc::clone(&self.mount_health)
+    }
+
+    /// Counter the watchers add dropped events to
+    pub fn drop_counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.dropped_events)
+    }
+
+    /// Events dropped since startup because dispatch fell behind
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Report reads abandoned by `watchdog`
+    pub fn with_watchdog(mut self, watchdog: Arc<Watchdog>) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Let clients shut the daemon down through `shutdown`
+    pub fn with_shutdown(mut self, shutdown: broadcast::Sender<()>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Record connections and watch requests to `audit`
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// The audit trail, if enabled
+    pub fn audit(&self) -> Option<&AuditLog> {
+        self.audit.as_ref()
+    }
+
+    /// Send clients the changes in `catch_up` under the watches they add
+    pub fn with_catch_up(mut self, catch_up: Arc<CatchUp>) -> Self {
+        self.catch_up = Some(catch_up);
+        self
+    }
+
+    /// Feed changes to `media`, which asks media servers to rescan them
+    pub fn with_media(mut self, media: Arc<MediaRefresher>) -> Self {
+        self.media = Some(media);
+        self
+    }
+
+    pub fn media(&self) -> Option<&MediaRefresher> {
+        self.media.as_deref()
+    }
+
+    /// Feed changes to `triggers`, which update trigger files for them
+    pub fn with_triggers(mut self, triggers: Arc<Triggers>) -> Self {
+        self.triggers = Some(triggers);
+        self
+    }
+
+    pub fn triggers(&self) -> Option<&Triggers> {
+        self.triggers.as_deref()
+    }
+
+    /// Run events through `plugins` before dispatching them
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = Some(plugins);
+        self
+    }
+
+    pub fn plugins(&self) -> Option<&Plugins> {
+        self.plugins.as_ref()
+    }
+
+    /// Journal events under the paths clients watch since a cursor
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Keep `watchman`'s roots current with every change
+    #[cfg(unix)]
+    pub fn with_watchman(mut self, watchman: Arc<Watchman>) -> Self {
+        self.watchman = Some(watchman);
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn watchman(&self) -> Option<&Arc<Watchman>> {
+        self.watchman.as_ref()
+    }
+
+    /// Write the changes `event_log` selects to syslog or the journal
+    #[cfg(unix)]
+    pub fn with_event_log(mut self, event_log: EventLog) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.event_log.as_ref()
+    }
+
+    /// Compress frames of at least `threshold` bytes for clients that
+    /// support it (0 leaves compression off)
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_threshold = threshold;
+        self
+    }
+
+    /// Handle IN_ACCESS and IN_OPEN on polled roots by `policy`
+    pub fn with_access_events(mut self, policy: AccessEvents) -> Self {
+        self.access_events = policy;
+        self
+    }
+
+    /// What watches asking for IN_ACCESS or IN_OPEN on polled roots get
+    pub fn access_events(&self) -> AccessEvents {
+        self.access_events
+    }
+
+    /// Confine each client to the watches of clients in its namespace
+    pub fn with_multi_tenant(mut self, enabled: bool) -> Self {
+        self.multi_tenant = enabled;
+        self
+    }
+
+    /// Keep clients that lose their connection for `timeout`, so a new
+    /// connection can resume them (zero disables)
+    pub fn with_session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = timeout;
+        self
+    }
+
+    /// How long a client that lost its connection is kept for resuming
+    pub fn session_timeout(&self) -> Duration {
+        self.session_timeout
+    }
+
+    /// Cap the queues of clients matching a class as it says
+    pub fn with_client_classes(mut self, classes: Vec<ClientClass>) -> Self {
+        self.client_classes = classes;
+        self
+    }
+
+    /// Queue settings for a client reporting `name`, connected as `uid`
+    fn queue_policy(&self, name: Option<&str>, uid: u32) -> QueuePolicy {
+        let policy = self.limits.queue_policy();
+        self.client_classes
+            .iter()
+            .find(|class| class.matches(name, uid))
+            .map_or(policy, |class| class.apply(policy))
+    }
+
+    /// Optional protocol features offered to clients
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = CAPABILITIES;
+        capabilities.set(Capabilities::COMPRESSION, self.compress_threshold > 0);
+        capabilities.set(Capabilities::RESUME, !self.session_timeout.is_zero());
+        capabilities.set(Capabilities::JOURNAL, self.journal.is_some());
+        capabilities
+    }
+
+    /// `framed` as a compressed frame for clients that negotiated
+    /// compression, or `None` to send it as it is
+    pub fn compress_frame(&self, framed: &[u8]) -> Option<Bytes> {
+        compress_frame(framed, self.compress_threshold)
+    }
+
+    /// Queue for `client_id` the offline changes under watch `wd`, while
+    /// the catch-up window lasts
+    pub fn catch_up(&self, client_id: ClientId, wd: WatchDescriptor) {
+        let Some(catch_up) = &self.catch_up else {
+            return;
+        };
+        let (Some(client), Some(watch)) = (self.get_client(client_id), self.get_watch(wd)) else {
+            return;
+        };
+        let queued = catch_up.replay(&watch, &client);
+        if queued > 0 {
+            tracing::info!(client_id, wd, events = queued, "Sent catch-up events");
+        }
+    }
+
+    /// Whether [`Self::request_shutdown`] can do anything
+    pub fn can_shut_down(&self) -> bool {
+        self.shutdown.is_some()
+    }
+
+    /// Start a graceful shutdown, as SIGTERM does
+    pub fn request_shutdown(&self) {
+        if let Some(shutdown) = &self.shutdown {
+            let _ = shutdown.send(());
+        }
+    }
+
+    /// Hand over the scanner, so its roots can be listed and extended
+    ///
+    /// Only the first call takes effect.
+    pub fn attach_watcher(&self, watcher: WatcherManager) {
+        let _ = self.watcher.set(parking_lot::Mutex::new(watcher));
+    }
+
+    /// Paths of the watched roots, ordered
+    pub fn roots(&self) -> Vec<PathBuf> {
+        self.root_configs()
+            .into_iter()
+            .map(|root| root.path)
+            .collect()
+    }
+
+    /// `path` spelled as on disk where it lies under a root that ignores
+    /// case, so it matches the paths that root's events carry
+    pub fn on_disk_case(&self, path: &Path) -> PathBuf {
+        self.watcher
+            .get()
+            .and_then(|watcher| watcher.lock().on_disk_case(path))
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// Every watched root with its settings, ordered by path
+    pub fn root_configs(&self) -> Vec<WatchConfig> {
+        self.watcher
+            .get()
+            .map(|watcher| watcher.lock().roots())
+            .unwrap_or_default()
+    }
+
+    /// Tags of the roots whose events reach `path`, sorted
+    pub fn root_tags(&self, path: &Path) -> Vec<String> {
+        self.watcher
+            .get()
+            .map(|watcher| watcher.lock().tags_for(path))
+            .unwrap_or_default()
+    }
+
+    /// Events clients may not watch for on `path`: those denied everywhere
+    /// and by the roots its events come from
+    pub fn denied_events(&self, path: &Path) -> EventMask {
+        self.limits.denied_events
+            | self.watcher.get().map_or(EventMask::empty(), |watcher| {
+                watcher.lock().denied_for(path)
+            })
+    }
+
+    /// Whether events for `path` come from polling rather than the OS
+    pub fn is_polled(&self, path: &Path) -> bool {
+        self.watcher
+            .get()
+            .is_none_or(|watcher| watcher.lock().is_polled(path))
+    }
+
+    /// Events waiting for dispatch, and how many the queue holds
+    pub fn event_queue(&self) -> (usize, usize) {
+        self.watcher
+            .get()
+            .map_or((0, 0), |watcher| watcher.lock().event_queue())
+    }
+
+    /// The root and scan behind a watch on `path`
+    pub fn scan_info(&self, path: &Path) -> Option<ScanInfo> {
+        self.watcher
+            .get()
+            .and_then(|watcher| watcher.lock().scan_info(path))
+    }
+
+    /// Roots sharing an enclosing root's scan, and the scans that saved
+    pub fn scan_sharing(&self) -> (Vec<PathBuf>, u64) {
+        self.watcher
+            .get()
+            .map_or((Vec::new(), 0), |watcher| watcher.lock().scan_sharing())
+    }
+
+    /// Events each client may have queued before it overflows
+    pub fn client_queue_capacity(&self) -> usize {
+        self.limits.max_queued_events
+    }
+
+    /// Start scanning `roots`, leaving roots already scanned alone
+    pub fn import_roots(&self, roots: Vec<WatchConfig>) -> ImportOutcome {
+        let mut outcome = ImportOutcome::default();
+        let Some(watcher) = self.watcher.get() else {
+            outcome.failed = roots
+                .into_iter()
+                .map(|root| (root.path, "scanner not running".to_string()))
+                .collect();
+            return outcome;
+        };
+
+        let mut watcher = watcher.lock();
+        for root in roots {
+            let path = root.path.clone();
+            if watcher.is_watched(&path) {
+                outcome.existing.push(path);
+                continue;
+            }
+            match watcher.add_watch(root) {
+                Ok(()) => outcome.added.push(path),
+                Err(e) => outcome.failed.push((path, e.to_string())),
+            }
+        }
+        outcome
+    }
+
+    /// Scan `root`, replacing the settings of a root already scanned at
+    /// its path
+    pub fn replace_root(&self, root: WatchConfig) -> std::io::Result<()> {
+        match self.watcher.get() {
+            Some(watcher) => watcher.lock().add_watch(root),
+            None => Err(std::io::Error::other("scanner not running")),
+        }
+    }
+
+    /// Stop every scan, checkpointing where they got to
+    pub fn stop_scans(&self) {
+        if let Some(watcher) = self.watcher.get() {
+            watcher.lock().shutdown();
+        }
+    }
+
+    /// Stop scanning the root at `path`
+    pub fn remove_root(&self, path: &PathBuf) {
+        if let Some(watcher) = self.watcher.get() {
+            watcher.lock().remove_watch(path);
+        }
+    }
+
+    /// Scanner reads abandoned as stuck since startup
+    pub fn stuck_scans(&self) -> u64 {
+        self.watchdog
+            .as_ref()
+            .map_or(0, |watchdog| watchdog.stuck_scans())
+    }
+
+    /// Count an event on `path` for watch `wd`
+    ///
+    /// Returns false if the event should not be dispatched because its
+    /// directory is noisy and excluded.
+    pub fn record_event(&self, wd: WatchDescriptor, path: &Path) -> bool {
+        self.rates
+            .as_ref()
+            .is_none_or(|rates| rates.lock().record(wd, path))
+    }
+
+    /// The `top` busiest watches and directories, or `None` if rate tracking
+    /// is off
+    pub fn busiest(&self, top: usize) -> Option<(Vec<PathRate>, Vec<PathRate>)> {
+        let rates = self.rates.as_ref()?.lock();
+        let busiest: Vec<_> = {
+            let watches = self.watches.read();
+            rates
+                .top_watches(top)
+                .into_iter()
+                .filter_map(|(wd, events)| Some((watches.get(&wd)?.clone(), events)))
+                .collect()
+        };
+        let watch_rates = busiest
+            .into_iter()
+            .map(|(watch, events)| PathRate {
+                tags: self.watch_tags(&watch),
+                path: watch.path,
+                events,
+                noisy: false,
+            })
+            .collect();
+        let dir_rates = rates
+            .top_dirs(top)
+            .into_iter()
+            .map(|(path, events)| PathRate {
+                noisy: rates.is_noisy(&path),
+                path,
+                events,
+                tags: Vec::new(),
+            })
+            .collect();
+        Some((watch_rates, dir_rates))
+    }
+
+    /// A path sent by `client_id` as the daemon sees it
+    ///
+    /// Uses the client's own table if it set one, then the client's mount
+    /// namespace if it differs from ours, then the configured table.
+    pub fn host_path(&self, client_id: ClientId, path: &Path) -> PathBuf {
+        let client = self.get_client(client_id);
+        if let Some(client) = &client {
+            if let Some(map) = client.path_map.read().as_deref() {
+                return map_to_host(map, path);
+            }
+            #[cfg(target_os = "linux")]
+            if self.resolve_namespaces
+                && let Some(pid) = client.pid
+                && let Some(resolved) = crate::namespace::resolve(pid, path)
+            {
+                tracing::debug!(
+                    client_id = client_id,
+                    pid = pid,
+                    path = %path.display(),
+                    resolved = %resolved.display(),
+                    "Resolved path through client mount namespace"
+                );
+                return resolved;
+            }
+        }
+        map_to_host(&self.path_map, path)
+    }
+
+    /// Replace a client's path table; an empty one reverts to the configured
+    /// table. Returns false if the client is unknown.
+    pub fn set_client_path_map(&self, client_id: ClientId, mappings: Vec<PathMapping>) -> bool {
+        let Some(client) = self.get_client(client_id) else {
+            return false;
+        };
+        tracing::debug!(client_id = client_id, mappings = ?mappings, "Client path map set");
+        *client.path_map.write() = (!mappings.is_empty()).then_some(mappings);
+        true
+    }
+
+    /// Replace a client's event filter; `None` lets everything through.
+    /// Returns false if the client is unknown.
+    pub fn set_client_filter(&self, client_id: ClientId, filter: Option<EventFilter>) -> bool {
+        let Some(client) = self.get_client(client_id) else {
+            return false;
+        };
+        tracing::debug!(client_id = client_id, filter = ?filter, "Client filter set");
+        *client.filter.write() = filter;
+        true
+    }
+
+    /// Place a client's watches in `namespace`, prefixed with its uid
+    /// (or `remote/ns` for remote peers) unless it is an administrator,
+    /// returning the namespace joined or `None` if the client is unknown
+    pub fn join_namespace(&self, client_id: ClientId, namespace: &str) -> Option<String> {
+        let client = self.get_client(client_id)?;
+        let namespace = if client.is_administrator() {
+            namespace.to_string()
+        } else if client.local {
+            format!("{}/{namespace}", client.uid)
+        } else {
+            format!("remote/ns/{namespace}")
+        };
+        tracing::debug!(client_id = client_id, namespace = %namespace, "Client joined namespace");
+        *client.namespace.write() = Some(namespace.clone());
+        Some(namespace)
+    }
+
+    /// Whose watches a client sees and may change
+    ///
+    /// Without multi-tenant mode, and for administrators that joined no
+    /// namespace, that is everyone's.
+    pub fn scope(&self, client_id: ClientId) -> Scope {
+        if !self.multi_tenant {
+            return Scope::All;
+        }
+        match self.get_client(client_id) {
+            Some(client) if client.is_administrator() && client.namespace.read().is_none() => {
+                Scope::All
+            }
+            Some(client) => Scope::Namespace(client.namespace()),
+            // No namespace is empty, so an unknown client sees nothing
+            None => Scope::Namespace(String::new()),
+        }
+    }
+
+    /// The clients `scope` covers, or `None` for all of them
+    fn members(&self, scope: &Scope) -> Option<HashSet<ClientId>> {
+        let Scope::Namespace(namespace) = scope else {
+            return None;
+        };
+        Some(
+            self.clients
+                .read()
+                .values()
+                .filter(|client| client.namespace() == *namespace)
+                .map(|client| client.id)
+                .collect(),
+        )
+    }
+
+    /// Record what a client reported about itself and the features it
+    /// understands.
+    ///
+    /// Returns the features both the client and this daemon support, now
+    /// used for the client, or `None` if there is no such client.
+    pub fn set_client_info(
+        &self,
+        client_id: ClientId,
+        info: ClientInfo,
+        capabilities: Capabilities,
+    ) -> Option<Capabilities> {
+        let client = self.get_client(client_id)?;
+        let mut capabilities = capabilities & self.capabilities();
+        // JSON lines carry events one by one, uncompressed
+        if capabilities.contains(Capabilities::JSON) {
+            capabilities -= Capabilities::EVENT_BATCH | Capabilities::COMPRESSION;
+        }
+        tracing::debug!(
+            client_id = client_id,
+            name = ?info.name,
+            pid = ?info.pid,
+            version = ?info.version,
+            capabilities = ?capabilities,
+            "Client identified"
+        );
+        client.set_queue_policy(self.queue_policy(info.name.as_deref(), client.uid));
+        *client.info.write() = info;
+        *client.capabilities.write() = capabilities;
+        Some(capabilities)
+    }
+
+    /// Register a new client connecting as `peer`
+    ///
+    /// Fails if the peer's uid already holds `max_user_instances`
+    /// connections. The writer is handed back so the caller can tell the peer
+    /// why.
+    pub fn register_client(
+        &self,
+        writer: ClientWriter,
+        peer: Peer,
+    ) -> Result<Arc<Client>, ClientWriter> {
+        let uid = peer.uid;
+        let mut clients = self.clients.write();
+        let instances = clients.values().filter(|c| c.uid == uid).count();
+        if instances >= self.limits.max_user_instances {
+            tracing::warn!(
+                uid = uid,
+                instances = instances,
+                "max_user_instances reached"
+            );
+            return Err(writer);
+        }
+
         let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
-        let client = Arc::new(Client::new(id, writer));
-        self.clients.write().insert(id, Arc::clone(&client));
-        tracing::info!(client_id = id, "Client connected");
-        client
+        let policy = self.queue_policy(None, uid);
+        let (client, rx) = Client::new(id, peer, writer, policy);
+        let client = Arc::new(client);
+        clients.insert(id, Arc::clone(&client));
+        self.changed();
+        drop(clients);
+        self.publish();
+        tokio::spawn(run_event_writer(
+            Arc::downgrade(&client),
+            rx,
+            self.compress_threshold,
+        ));
+        tracing::info!(client_id = id, uid = uid, pid = ?peer.pid, "Client connected");
+        Ok(client)
     }
 
     /// Unregister a client and clean up its watches
@@ -133,9 +1368,11 @@ impl DaemonState {
         let mut watches = self.watches.write();
         let mut path_to_wd = self.path_to_wd.write();
 
+        let mut left = Vec::new();
         for wd in watches_to_check {
             if let Some(watch) = watches.get_mut(&wd) {
                 watch.clients.retain(|&c| c != client_id);
+                left.push(watch.path.clone());
 
                 // If no clients are watching, remove the watch entirely
                 if watch.clients.is_empty() {
@@ -146,34 +1383,91 @@ impl DaemonState {
                 }
             }
         }
+        drop((watches, path_to_wd));
+        // Kept for the client to resume from after restarting
+        for path in &left {
+            self.release_journal(path, client_id, true);
+        }
 
         // Remove the client
         self.clients.write().remove(&client_id);
+        self.changed();
+        self.publish();
         tracing::info!(client_id = client_id, "Client disconnected");
     }
 
     /// Get a client by ID
-    #[allow(dead_code)]
     pub fn get_client(&self, client_id: ClientId) -> Option<Arc<Client>> {
         self.clients.read().get(&client_id).cloned()
     }
 
+    /// Get the client holding `session`
+    pub fn client_by_session(&self, session: &str) -> Option<Arc<Client>> {
+        if session.is_empty() {
+            return None;
+        }
+        self.clients
+            .read()
+            .values()
+            .find(|client| client.session == session)
+            .cloned()
+    }
+
+    /// Watches held by all of `uid`'s clients
+    fn watches_for_uid(&self, uid: u32) -> usize {
+        self.clients
+            .read()
+            .values()
+            .filter(|c| c.uid == uid)
+            .map(|c| c.watches.read().len())
+            .sum()
+    }
+
     /// Add or update a watch
     ///
     /// Returns the watch descriptor for the path.
     /// If the path is already being watched, adds the client to the existing watch.
+    /// Fails if this would put the client's uid over `max_user_watches`.
     pub fn add_watch(
         &self,
         client_id: ClientId,
         path: PathBuf,
         mask: EventMask,
         recursive: bool,
-    ) -> WatchDescriptor {
+    ) -> Result<WatchDescriptor, LimitExceeded> {
+        let added = self.subscribe(client_id, path, mask, recursive);
+        self.publish();
+        added
+    }
+
+    /// [`Self::add_watch`] without publishing the change to the dispatcher
+    fn subscribe(
+        &self,
+        client_id: ClientId,
+        path: PathBuf,
+        mask: EventMask,
+        recursive: bool,
+    ) -> Result<WatchDescriptor, LimitExceeded> {
         let mut watches = self.watches.write();
         let mut path_to_wd = self.path_to_wd.write();
 
+        let existing = path_to_wd
+            .get(&path)
+            .copied()
+            .filter(|wd| watches.contains_key(wd));
+        let already_subscribed =
+            existing.is_some_and(|wd| watches[&wd].clients.contains(&client_id));
+        let uid = self.clients.read().get(&client_id).map(|c| c.uid);
+        if !already_subscribed
+            && let Some(uid) = uid
+            && self.watches_for_uid(uid) >= self.limits.max_user_watches
+        {
+            tracing::warn!(uid = uid, path = %path.display(), "max_user_watches reached");
+            return Err(LimitExceeded::Watches);
+        }
+
         // Check if path is already being watched
-        if let Some(&wd) = path_to_wd.get(&path)
+        if let Some(wd) = existing
             && let Some(watch) = watches.get_mut(&wd)
         {
             // Add client to existing watch if not already present
@@ -185,11 +1479,12 @@ impl DaemonState {
             tracing::debug!(wd = wd, path = %path.display(), "Client added to existing watch");
 
             // Add watch to client's list
-            if let Some(client) = self.clients.read().get(&client_id) {
+            if !already_subscribed && let Some(client) = self.clients.read().get(&client_id) {
                 client.add_watch(wd);
             }
+            self.changed();
 
-            return wd;
+            return Ok(wd);
         }
 
         // Create new watch
@@ -200,10 +1495,13 @@ impl DaemonState {
             mask,
             recursive,
             clients: vec![client_id],
+            missing_since: None,
+            tags: Vec::new(),
         };
 
         watches.insert(wd, watch);
         path_to_wd.insert(path.clone(), wd);
+        self.changed();
 
         // Add watch to client's list
         if let Some(client) = self.clients.read().get(&client_id) {
@@ -211,18 +1509,29 @@ impl DaemonState {
         }
 
         tracing::info!(wd = wd, path = %path.display(), recursive = recursive, "Watch added");
-        wd
+        Ok(wd)
     }
 
     /// Remove a watch for a specific client
     ///
-    /// Returns true if the watch was removed, false if not found.
+    /// Returns true if the watch was removed, false if the client holds no
+    /// such watch.
     pub fn remove_watch(&self, client_id: ClientId, wd: WatchDescriptor) -> bool {
+        let removed = self.unsubscribe(client_id, wd);
+        self.publish();
+        removed
+    }
+
+    /// [`Self::remove_watch`] without publishing the change to the dispatcher
+    fn unsubscribe(&self, client_id: ClientId, wd: WatchDescriptor) -> bool {
         let mut watches = self.watches.write();
         let mut path_to_wd = self.path_to_wd.write();
 
-        if let Some(watch) = watches.get_mut(&wd) {
+        if let Some(watch) = watches.get_mut(&wd)
+            && watch.clients.contains(&client_id)
+        {
             watch.clients.retain(|&c| c != client_id);
+            let path = watch.path.clone();
 
             // Remove watch from client's list
             if let Some(client) = self.clients.read().get(&client_id) {
@@ -231,11 +1540,13 @@ impl DaemonState {
 
             // If no clients are watching, remove the watch entirely
             if watch.clients.is_empty() {
-                let path = watch.path.clone();
                 watches.remove(&wd);
                 path_to_wd.remove(&path);
                 tracing::info!(wd = wd, path = %path.display(), "Watch removed");
             }
+            self.changed();
+            drop((watches, path_to_wd));
+            self.release_journal(&path, client_id, false);
 
             return true;
         }
@@ -243,6 +1554,160 @@ impl DaemonState {
         false
     }
 
+    /// Remove watches whose path has been missing for at least `grace`
+    ///
+    /// `missing` holds the watches whose path was just found not to exist;
+    /// every other watch counts as present again. Like the kernel when a
+    /// watched file goes away, each subscribed client gets IN_IGNORED.
+    pub fn prune_missing(
+        &self,
+        missing: &HashSet<WatchDescriptor>,
+        grace: Duration,
+    ) -> Vec<WatchInfo> {
+        let mut watches = self.watches.write();
+        let mut path_to_wd = self.path_to_wd.write();
+        let now = Instant::now();
+
+        let mut expired = Vec::new();
+        for watch in watches.values_mut() {
+            if !missing.contains(&watch.wd) {
+                watch.missing_since = None;
+                continue;
+            }
+            let since = *watch.missing_since.get_or_insert(now);
+            if now.duration_since(since) >= grace {
+                expired.push(watch.wd);
+            }
+        }
+
+        let pruned = self.drop_watches(&mut watches, &mut path_to_wd, expired);
+        drop((watches, path_to_wd));
+        self.close_journals(&pruned);
+        self.publish();
+        for watch in &pruned {
+            tracing::info!(wd = watch.wd, path = %watch.path.display(), "Pruned missing watch");
+        }
+        pruned
+    }
+
+    /// Replace the tags set on watch `wd`
+    ///
+    /// Returns false if there is no such watch.
+    pub fn tag_watch(&self, wd: WatchDescriptor, mut tags: Vec<String>) -> bool {
+        let mut watches = self.watches.write();
+        let Some(watch) = watches.get_mut(&wd) else {
+            return false;
+        };
+        tags.sort();
+        tags.dedup();
+        tracing::info!(wd = wd, path = %watch.path.display(), tags = ?tags, "Watch tagged");
+        watch.tags = tags;
+        self.changed();
+        drop(watches);
+        self.publish();
+        true
+    }
+
+    /// Tags of `watch` and of the roots whose events reach it, sorted
+    pub fn watch_tags(&self, watch: &WatchInfo) -> Vec<String> {
+        let mut tags = self.root_tags(&watch.path);
+        if !watch.tags.is_empty() {
+            tags.extend(watch.tags.iter().cloned());
+            tags.sort();
+            tags.dedup();
+        }
+        tags
+    }
+
+    /// Remove every watch carrying `tag`, or with `only` every one of
+    /// those among it, for all their clients
+    ///
+    /// Each subscribed client gets IN_IGNORED, as with [`Self::prune_missing`].
+    pub fn remove_tagged(
+        &self,
+        tag: &str,
+        only: Option<&HashSet<WatchDescriptor>>,
+    ) -> Vec<WatchInfo> {
+        // Root tags come from the scanner, so look them up before locking
+        let watches: Vec<WatchInfo> = self.watches.read().values().cloned().collect();
+        let tagged = watches
+            .iter()
+            .filter(|watch| only.is_none_or(|only| only.contains(&watch.wd)))
+            .filter(|watch| self.watch_tags(watch).iter().any(|t| t == tag))
+            .map(|watch| watch.wd)
+            .collect();
+
+        let mut watches = self.watches.write();
+        let mut path_to_wd = self.path_to_wd.write();
+        let removed = self.drop_watches(&mut watches, &mut path_to_wd, tagged);
+        drop((watches, path_to_wd));
+        self.close_journals(&removed);
+        self.publish();
+        for watch in &removed {
+            tracing::info!(wd = watch.wd, path = %watch.path.display(), tag = tag, "Removed tagged watch");
+        }
+        removed
+    }
+
+    /// Take watches `wds` out of the tables, sending each subscribed client
+    /// IN_IGNORED as the kernel does when a watch goes away
+    fn drop_watches(
+        &self,
+        watches: &mut HashMap<WatchDescriptor, WatchInfo>,
+        path_to_wd: &mut HashMap<PathBuf, WatchDescriptor>,
+        wds: Vec<WatchDescriptor>,
+    ) -> Vec<WatchInfo> {
+        let clients = self.clients.read();
+        let mut dropped = Vec::with_capacity(wds.len());
+        for wd in wds {
+            let Some(watch) = watches.remove(&wd) else {
+                continue;
+            };
+            path_to_wd.remove(&watch.path);
+
+            let ignored = InotifyEvent::new(wd, EventMask::IN_IGNORED.bits(), 0);
+            let framed = Bytes::from(FramedMessage::frame_event(&ignored.header_to_bytes()));
+            for client in watch.clients.iter().filter_map(|id| clients.get(id)) {
+                client.remove_watch(wd);
+                client.queue_event(framed.clone());
+            }
+            dropped.push(watch);
+        }
+        if !dropped.is_empty() {
+            self.changed();
+        }
+        dropped
+    }
+
+    /// Let go of `client`'s journal of `path`, if it holds one; see
+    /// [`Journal::release`]
+    ///
+    /// Takes the journal's lock, which is held across adding a watch, so
+    /// must not be called with the watch tables locked.
+    fn release_journal(&self, path: &Path, client: ClientId, keep: bool) {
+        if let Some(journal) = &self.journal {
+            journal.release(path, client, keep);
+        }
+    }
+
+    /// Close the journals of `dropped` watches for all their clients
+    fn close_journals(&self, dropped: &[WatchInfo]) {
+        for watch in dropped {
+            for &client in &watch.clients {
+                self.release_journal(&watch.path, client, false);
+            }
+        }
+    }
+
+    /// Descriptor and path of every watch
+    pub fn watch_paths(&self) -> Vec<(WatchDescriptor, PathBuf)> {
+        self.watches
+            .read()
+            .values()
+            .map(|w| (w.wd, w.path.clone()))
+            .collect()
+    }
+
     /// Get all watched paths
     #[allow(dead_code)]
     pub fn get_watched_paths(&self) -> Vec<PathBuf> {
@@ -259,60 +1724,180 @@ impl DaemonState {
         self.watches.read().get(&wd).cloned()
     }
 
+    /// A watch as `viewer` may see it: only if a client in its scope
+    /// holds it, and with only those clients listed
+    pub fn visible_watch(&self, viewer: ClientId, wd: WatchDescriptor) -> Option<WatchInfo> {
+        let mut watch = self.get_watch(wd)?;
+        if let Some(members) = self.members(&self.scope(viewer)) {
+            watch.clients.retain(|client| members.contains(client));
+            if watch.clients.is_empty() {
+                return None;
+            }
+        }
+        Some(watch)
+    }
+
+    /// Watches held only by clients in `viewer`'s scope, or `None` if it
+    /// covers every client
+    pub fn owned_watches(&self, viewer: ClientId) -> Option<HashSet<WatchDescriptor>> {
+        let members = self.members(&self.scope(viewer))?;
+        Some(
+            self.watches
+                .read()
+                .values()
+                .filter(|watch| watch.clients.iter().all(|client| members.contains(client)))
+                .map(|watch| watch.wd)
+                .collect(),
+        )
+    }
+
     /// Get watch descriptor for a path
     #[allow(dead_code)]
     pub fn get_wd_for_path(&self, path: &PathBuf) -> Option<WatchDescriptor> {
         self.path_to_wd.read().get(path).copied()
     }
 
-    /// Find the watch descriptor for a path or any of its parent directories
-    pub fn find_watch_for_path(&self, path: &PathBuf) -> Option<WatchInfo> {
-        let watches = self.watches.read();
-        let path_to_wd = self.path_to_wd.read();
+    /// Record a change to the client or watch tables
+    ///
+    /// Called after the change, so a snapshot labelled with the new
+    /// generation never misses it. The writer then calls [`Self::publish`]
+    /// once it has let go of the tables.
+    fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
 
-        // First check exact match
-        if let Some(&wd) = path_to_wd.get(path) {
-            return watches.get(&wd).cloned();
-        }
+    /// Rebuild the dispatcher's snapshot if the tables changed since it was
+    /// last published
+    ///
+    /// Writers arriving while another one rebuilds leave their change to
+    /// it: that writer looks at the generation again once done and rebuilds
+    /// until nothing changed meanwhile, so a burst of changes costs a few
+    /// rebuilds rather than one each.
+    fn publish(&self) {
+        loop {
+            let Some(building) = self.publishing.try_lock() else {
+                return;
+            };
+            let generation = self.generation.load(Ordering::SeqCst);
+            if self.dispatch_index.read().generation == generation {
+                return;
+            }
 
-        // Check parent directories for recursive watches
-        let mut current = path.as_path();
-        while let Some(parent) = current.parent() {
-            if let Some(&wd) = path_to_wd.get(&parent.to_path_buf())
-                && let Some(watch) = watches.get(&wd)
-                && watch.recursive
-            {
-                return Some(watch.clone());
+            let index = {
+                let watches = self.watches.read();
+                let path_to_wd = self.path_to_wd.read();
+                let clients = self.clients.read();
+                DispatchIndex {
+                    generation,
+                    path_to_wd: path_to_wd.clone(),
+                    watches: watches
+                        .values()
+                        .map(|watch| {
+                            let subscribers = watch
+                                .clients
+                                .iter()
+                                .filter_map(|id| clients.get(id).cloned())
+                                .collect();
+                            let entry = DispatchWatch {
+                                info: watch.clone(),
+                                clients: subscribers,
+                            };
+                            (watch.wd, entry)
+                        })
+                        .collect(),
+                }
+            };
+            *self.dispatch_index.write() = Arc::new(index);
+            drop(building);
+
+            if self.generation.load(Ordering::SeqCst) == generation {
+                return;
             }
-            current = parent;
         }
+    }
 
-        None
+    /// The watch table as the dispatcher sees it
+    ///
+    /// Writers publish a new snapshot after each change, so this only
+    /// clones an `Arc` and dispatching never waits on watch or client
+    /// updates.
+    pub fn dispatch_index(&self) -> Arc<DispatchIndex> {
+        Arc::clone(&self.dispatch_index.read())
     }
 
-    /// Get all clients watching a specific watch descriptor
-    pub fn get_clients_for_watch(&self, wd: WatchDescriptor) -> Vec<Arc<Client>> {
-        let watches = self.watches.read();
-        let clients = self.clients.read();
+    /// Every watch `viewer` may see, ordered by descriptor
+    pub fn list_watches(&self, viewer: ClientId) -> Vec<WatchSummary> {
+        let members = self.members(&self.scope(viewer));
+        let watches: Vec<WatchInfo> = self.watches.read().values().cloned().collect();
+        let mut watches: Vec<_> = watches
+            .iter()
+            .filter_map(|w| {
+                let clients = match &members {
+                    Some(members) => w.clients.iter().filter(|c| members.contains(c)).count(),
+                    None => w.clients.len(),
+                };
+                (members.is_none() || clients > 0).then(|| WatchSummary {
+                    wd: w.wd,
+                    path: w.path.clone(),
+                    mask: w.mask.bits(),
+                    recursive: w.recursive,
+                    clients: clients as u32,
+                    tags: self.watch_tags(w),
+                })
+            })
+            .collect();
+        watches.sort_by_key(|w| w.wd);
+        watches
+    }
 
-        if let Some(watch) = watches.get(&wd) {
-            watch
-                .clients
-                .iter()
-                .filter_map(|&client_id| clients.get(&client_id).cloned())
-                .collect()
-        } else {
-            Vec::new()
-        }
+    /// Every connected client `viewer` may see, ordered by ID
+    pub fn list_clients(&self, viewer: ClientId) -> Vec<ClientSummary> {
+        let members = self.members(&self.scope(viewer));
+        let mut clients: Vec<_> = self
+            .clients
+            .read()
+            .values()
+            .filter(|c| {
+                members
+                    .as_ref()
+                    .is_none_or(|members| members.contains(&c.id))
+            })
+            .map(|c| ClientSummary {
+                id: c.id,
+                uid: c.uid,
+                pid: c.pid,
+                watches: c.watches.read().len() as u32,
+                queued_events: c.queued_events() as u32,
+                connected_secs: c.connected_at.elapsed().as_secs(),
+                info: c.info.read().clone(),
+                capabilities: c.capabilities().bits(),
+                namespace: c.namespace(),
+            })
+            .collect();
+        clients.sort_by_key(|c| c.id);
+        clients
     }
 
     /// Get daemon statistics
-    #[allow(dead_code)]
     pub fn stats(&self) -> DaemonStats {
+        let clients = self.clients.read();
+        let mut users: BTreeMap<u32, UserUsage> = BTreeMap::new();
+        for client in clients.values() {
+            let usage = users.entry(client.uid).or_insert_with(|| UserUsage {
+                uid: client.uid,
+                ..UserUsage::default()
+            });
+            usage.instances += 1;
+            usage.watches += client.watches.read().len() as u32;
+            usage.queued_events += client.queued_events() as u64;
+        }
+
         DaemonStats {
             uptime_secs: self.started_at.elapsed().as_secs(),
-            total_clients: self.clients.read().len(),
+            total_clients: clients.len(),
             total_watches: self.watches.read().len(),
+            limits: self.limits,
+            users: users.into_values().collect(),
         }
     }
 }
@@ -323,13 +1908,27 @@ impl Default for DaemonState {
     }
 }
 
+/// Result of [`DaemonState::import_roots`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportOutcome {
+    /// Roots now being scanned
+    pub added: Vec<PathBuf>,
+    /// Roots that were already being scanned
+    pub existing: Vec<PathBuf>,
+    /// Roots that could not be added, with the reason
+    pub failed: Vec<(PathBuf, String)>,
+}
+
 /// Daemon statistics
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct DaemonStats {
     pub uptime_secs: u64,
     pub total_clients: usize,
     pub total_watches: usize,
+    /// Configured limits, for comparison with `users`
+    pub limits: LimitsConfig,
+    /// Current usage per uid, ordered by uid
+    pub users: Vec<UserUsage>,
 }
 
 #[cfg(test)]
@@ -345,4 +1944,305 @@ mod tests {
         assert_eq!(state.clients.read().len(), 0);
         assert_eq!(state.watches.read().len(), 0);
     }
+
+    fn writer() -> ClientWriter {
+        let (a, _b) = tokio::net::UnixStream::pair().unwrap();
+        Box::new(a.into_split().1)
+    }
+
+    fn limits(watches: usize, instances: usize) -> LimitsConfig {
+        LimitsConfig {
+            max_user_watches: watches,
+            max_queued_events: 2,
+            max_user_instances: instances,
+            ..LimitsConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_instance_limit_is_per_uid() {
+        let state = DaemonState::with_limits(limits(10, 1));
+        assert!(
+            state
+                .register_client(writer(), Peer::from_uid(1000))
+                .is_ok()
+        );
+        assert!(
+            state
+                .register_client(writer(), Peer::from_uid(1000))
+                .is_err()
+        );
+        assert!(
+            state
+                .register_client(writer(), Peer::from_uid(1001))
+                .is_ok()
+        );
+
+        let stats = state.stats();
+        assert_eq!(stats.users.len(), 2);
+        assert_eq!(stats.users[0].uid, 1000);
+        assert_eq!(stats.users[0].instances, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dead_socket_is_reported() {
+        let state = DaemonState::new();
+        // The peer end is already closed, so the first write fails with EPIPE
+        let client = state
+            .register_client(writer(), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).header_to_bytes();
+        assert!(client.queue_event(FramedMessage::frame_event(&event).into()));
+
+        tokio::time::timeout(Duration::from_secs(5), client.broken())
+            .await
+            .expect("dead socket was not reported");
+    }
+
+    #[tokio::test]
+    async fn test_prune_missing_waits_for_grace() {
+        use tokio::io::AsyncReadExt;
+
+        let state = DaemonState::new();
+        let (ours, mut theirs) = tokio::net::UnixStream::pair().unwrap();
+        let client = state
+            .register_client(Box::new(ours.into_split().1), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let wd = state
+            .add_watch(
+                client.id,
+                PathBuf::from("/gone"),
+                EventMask::IN_CREATE,
+                false,
+            )
+            .unwrap();
+        let missing = HashSet::from([wd]);
+
+        // Still within the grace period, then back again
+        assert!(
+            state
+                .prune_missing(&missing, Duration::from_secs(60))
+                .is_empty()
+        );
+        assert!(state.get_watch(wd).unwrap().missing_since.is_some());
+        assert!(
+            state
+                .prune_missing(&HashSet::new(), Duration::ZERO)
+                .is_empty()
+        );
+        assert!(state.get_watch(wd).unwrap().missing_since.is_none());
+
+        let pruned = state.prune_missing(&missing, Duration::ZERO);
+        assert_eq!(pruned.len(), 1);
+        assert!(state.get_watch(wd).is_none());
+        assert!(client.watches.read().is_empty());
+
+        let mut frame = [0u8; 4 + InotifyEvent::HEADER_SIZE];
+        theirs.read_exact(&mut frame).await.unwrap();
+        let event = InotifyEvent::from_bytes(&frame[4..]).unwrap();
+        assert_eq!(event.wd, wd);
+        assert_eq!(event.mask, EventMask::IN_IGNORED.bits());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_index_follows_changes() {
+        let state = DaemonState::new();
+        let client = state
+            .register_client(writer(), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let wd = state
+            .add_watch(
+                client.id,
+                PathBuf::from("/mnt/media"),
+                EventMask::IN_CREATE,
+                true,
+            )
+            .unwrap();
+
+        let index = state.dispatch_index();
+        let entry = index
+            .covering(Path::new("/mnt/media/show/ep1.mkv"))
+            .next()
+            .unwrap();
+        assert_eq!(entry.info.wd, wd);
+        assert_eq!(entry.clients.len(), 1);
+        assert!(index.covering(Path::new("/srv/other")).next().is_none());
+        // Unchanged tables hand out the same snapshot
+        assert!(Arc::ptr_eq(&index, &state.dispatch_index()));
+
+        // An overflow under a root reaches every watch inside it too
+        let add = |path: &str| {
+            state
+                .add_watch(client.id, PathBuf::from(path), EventMask::IN_CREATE, false)
+                .unwrap()
+        };
+        let (show, other) = (add("/mnt/media/show"), add("/srv/other"));
+        let index = state.dispatch_index();
+        // Writers publish as they go, so the dispatcher never rebuilds
+        assert_eq!(index.generation, state.generation.load(Ordering::SeqCst));
+        // Nested watches all see what happens inside the inner one
+        let covering: Vec<_> = index
+            .covering(Path::new("/mnt/media/show/ep1.mkv"))
+            .map(|entry| entry.info.wd)
+            .collect();
+        assert_eq!(covering, [wd]);
+        let covering: Vec<_> = index
+            .covering(Path::new("/mnt/media/show"))
+            .map(|entry| entry.info.wd)
+            .collect();
+        assert_eq!(covering, [show, wd]);
+        let mut within: Vec<_> = index
+            .within(Path::new("/mnt/media/show"))
+            .map(|entry| entry.info.wd)
+            .collect();
+        within.sort_unstable();
+        assert_eq!(within, [wd, show]);
+        assert!(state.remove_watch(client.id, show));
+        assert!(state.remove_watch(client.id, other));
+
+        assert!(state.remove_watch(client.id, wd));
+        assert!(
+            state
+                .dispatch_index()
+                .covering(Path::new("/mnt/media"))
+                .next()
+                .is_none()
+        );
+        // Snapshots already handed out stay as they were
+        assert!(index.covering(Path::new("/mnt/media")).next().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_watch_limit_counts_subscriptions() {
+        let state = DaemonState::with_limits(limits(2, 10));
+        let a = state
+            .register_client(writer(), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let b = state
+            .register_client(writer(), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+
+        let mask = EventMask::IN_CREATE;
+        let wd = state.add_watch(a.id, "/mnt/a".into(), mask, true).unwrap();
+        // Re-adding the same path does not use up another watch
+        assert_eq!(state.add_watch(a.id, "/mnt/a".into(), mask, true), Ok(wd));
+        assert!(state.add_watch(b.id, "/mnt/a".into(), mask, true).is_ok());
+        assert_eq!(
+            state.add_watch(b.id, "/mnt/b".into(), mask, true),
+            Err(LimitExceeded::Watches)
+        );
+
+        // Freeing one makes room again
+        assert!(state.remove_watch(a.id, wd));
+        assert!(state.add_watch(b.id, "/mnt/b".into(), mask, true).is_ok());
+        assert_eq!(state.stats().users[0].watches, 2);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_events() {
+        let (client, _rx) = Client::new(
+            1,
+            Peer::from_uid(1000),
+            writer(),
+            limits(0, 0).queue_policy(),
+        );
+
+        assert!(client.queue_event(Bytes::from_static(&[1])));
+        assert!(client.queue_event(Bytes::from_static(&[2])));
+        assert!(!client.queue_event(Bytes::from_static(&[3])));
+        assert_eq!(client.queued_events(), 2);
+        assert!(client.overflowed.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_client_class_drops_session_on_overflow() {
+        let state =
+            DaemonState::with_limits(limits(10, 10)).with_client_classes(vec![ClientClass {
+                name: Some("jellyfin".into()),
+                queue_overflow: Some(QueueOverflow::DropSession),
+                ..ClientClass::default()
+            }]);
+        let other = state
+            .register_client(writer(), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let client = state
+            .register_client(writer(), Peer::from_uid(1000))
+            .ok()
+            .unwrap();
+        let info = ClientInfo {
+            name: Some("jellyfin".into()),
+            ..ClientInfo::default()
+        };
+        state.set_client_info(client.id, info, Capabilities::empty());
+
+        // Nobody reads the pair's other end, but the writer task may
+        // still take events off the queue, so fill it until it overflows
+        while other.queue_event(Bytes::from_static(&[1])) {}
+        assert!(!other.evicting.load(Ordering::Relaxed));
+        while client.queue_event(Bytes::from_static(&[1])) {}
+        tokio::time::timeout(Duration::from_secs(1), client.evicted())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_burst_is_batched_for_clients_that_ask() {
+        let frames: Vec<Bytes> = (0..40)
+            .map(|i| {
+                let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0);
+                Bytes::from(FramedMessage::frame_event(
+                    &event.to_bytes_with_name(format!("{i}.mkv").as_bytes()),
+                ))
+            })
+            .collect();
+
+        // Others get the same frames in one write
+        let plain = encode_events(&frames, Capabilities::empty(), 256);
+        assert_eq!(plain, frames.concat());
+
+        let batched = encode_events(&frames, Capabilities::EVENT_BATCH, 256);
+        let Ok(Response::EventBatch { count, events }) = Response::from_bytes(&batched[4..]) else {
+            panic!("expected EventBatch");
+        };
+        assert_eq!(count, 40);
+        assert_eq!(
+            events,
+            frames
+                .iter()
+                .flat_map(|f| f[4..].to_vec())
+                .collect::<Vec<_>>()
+        );
+
+        let compressed = encode_events(&frames, CAPABILITIES, 256);
+        let Ok(Response::Compressed {
+            kind: FrameKind::Message,
+            payload,
+        }) = Response::from_bytes(&compressed[4..])
+        else {
+            panic!("expected Compressed");
+        };
+        assert_eq!(
+            fakenotify_protocol::decompress_payload(&payload).unwrap(),
+            batched[4..]
+        );
+
+        // A lone event goes out as it is
+        assert_eq!(encode_events(&frames[..1], CAPABILITIES, 256), frames[0]);
+
+        // Numbered events keep their frames
+        let numbered = [
+            frames[0].clone(),
+            crate::journal::sequenced_frame(1, &frames[1]),
+        ];
+        assert_eq!(
+            encode_events(&numbered, CAPABILITIES, 256),
+            numbered.concat()
+        );
+    }
 }
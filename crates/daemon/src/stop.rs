@@ -0,0 +1,156 @@
+//! Process handling for `fakenotifyd stop`.
+//!
+//! A graceful stop goes through [`Request::Shutdown`]; signals are only the
+//! fallback for a daemon that does not answer or does not exit in time. They
+//! go to a PID from the PID file or from the socket's peer credentials, never
+//! to the PID the daemon reports about itself, which may belong to another
+//! PID namespace (a daemon in a container, stopped from the host).
+//!
+//! [`Request::Shutdown`]: fakenotify_protocol::Request::Shutdown
+
+use crate::server::is_daemon_running;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Exit status when there was no daemon to stop
+pub const EXIT_NOT_RUNNING: i32 = 3;
+
+/// Exit status when the daemon had to be signalled (2 is clap's usage error)
+pub const EXIT_KILLED: i32 = 4;
+
+/// How long a hung daemon gets to answer the shutdown request
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long SIGTERM gets before SIGKILL
+pub const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// How often to check whether the daemon is gone
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// PID recorded in `path`, if it names a live daemon
+///
+/// A PID file outlives a daemon that crashed, and its PID may since have
+/// gone to an unrelated process, which must not be signalled.
+pub fn read_pid_file(path: &Path) -> Option<i32> {
+    let pid = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    (is_alive(pid) && runs_this_program(pid)).then_some(pid)
+}
+
+/// Whether `pid` runs the same executable as this process
+#[cfg(target_os = "linux")]
+fn runs_this_program(pid: i32) -> bool {
+    let Ok(ours) = std::env::current_exe() else {
+        return false;
+    };
+    // An executable replaced by an upgrade reads as "<path> (deleted)"
+    let name = |path: &Path| {
+        path.file_name().map(|name| {
+            name.to_string_lossy()
+                .trim_end_matches(" (deleted)")
+                .to_owned()
+        })
+    };
+    match std::fs::read_link(format!("/proc/{pid}/exe")) {
+        Ok(exe) => exe == ours || name(&exe) == name(&ours),
+        // Another user's process only shows its name, cut to 15 bytes
+        Err(_) => {
+            let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default();
+            name(&ours).is_some_and(|ours| {
+                let ours = ours.as_bytes();
+                !comm.trim_end().is_empty()
+                    && comm.trim_end().as_bytes() == &ours[..ours.len().min(15)]
+            })
+        }
+    }
+}
+
+/// Whether `pid` runs the same executable as this process (not checkable
+/// here, so only liveness counts)
+#[cfg(not(target_os = "linux"))]
+fn runs_this_program(_pid: i32) -> bool {
+    true
+}
+
+/// PID of the process listening on `socket`, as seen from our namespace
+#[cfg(target_os = "linux")]
+pub async fn listener_pid(socket: &Path) -> Option<i32> {
+    let stream = tokio::net::UnixStream::connect(socket).await.ok()?;
+    // 0 when the daemon's PID namespace is not visible from ours
+    stream.peer_cred().ok()?.pid().filter(|&pid| pid > 0)
+}
+
+/// PID of the process listening on `socket` (not reliably known here)
+#[cfg(not(target_os = "linux"))]
+pub async fn listener_pid(_socket: &Path) -> Option<i32> {
+    None
+}
+
+/// Whether `pid` is still running
+#[cfg(unix)]
+pub fn is_alive(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks for existence and permission
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn is_alive(_pid: i32) -> bool {
+    false
+}
+
+/// Send `pid` SIGTERM, or SIGKILL with `force`
+#[cfg(unix)]
+pub fn signal(pid: i32, force: bool) -> std::io::Result<()> {
+    let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+    // SAFETY: kill has no memory-safety preconditions
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+pub fn signal(_pid: i32, _force: bool) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Wait until nothing answers on `socket` and `pid` (if known) has exited
+///
+/// Returns false if `timeout` passed first; `None` waits forever.
+pub async fn wait_for_exit(socket: &Path, pid: Option<i32>, timeout: Option<Duration>) -> bool {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if !pid.is_some_and(is_alive) && !is_daemon_running(socket).await {
+            return true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_pid_file() {
+        let path = std::env::temp_dir().join(format!("fakenotify-stop-{}.pid", std::process::id()));
+        std::fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        assert_eq!(read_pid_file(&path), Some(std::process::id() as i32));
+
+        // A PID that cannot exist
+        std::fs::write(&path, "2147483647").unwrap();
+        assert_eq!(read_pid_file(&path), None);
+
+        // A live process running something else, such as init
+        std::fs::write(&path, "1").unwrap();
+        assert_eq!(read_pid_file(&path), None);
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert_eq!(read_pid_file(&path), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+//! Versioned state files.
+//!
+//! Files the daemon reads back on a later run start with the same header,
+//! so a file is recognised and checked before its payload is decoded:
+//!
+//! | bytes | field |
+//! | ----- | ----- |
+//! | 8 | magic, `FAKENTFY` |
+//! | 4 | what the file holds, such as `SNAP` for checkpoints |
+//! | 4 | version of the payload's layout, little-endian |
+//! | 8 | payload length, little-endian |
+//! | 4 | CRC-32 of the payload, little-endian |
+//!
+//! A truncated or corrupted file fails the length or checksum check instead
+//! of decoding into garbage. A payload in an older layout is brought up to
+//! date by its [`Format`]'s migrations, one version at a time, and one
+//! written by a newer daemon is refused as unsupported rather than misread.
+//!
+//! Files are replaced with [`write_atomic`], so power loss leaves either
+//! the old contents or the new, never a mix. Those whose readers can fall
+//! back on older contents keep what they replace as a [`previous`] copy,
+//! unless it is damaged itself.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 8] = *b"FAKENTFY";
+
+/// Bytes before the payload
+pub const HEADER_LEN: usize = 28;
+
+/// Upgrades a payload from one version of its layout to the next
+pub type Migration = fn(Vec<u8>) -> io::Result<Vec<u8>>;
+
+/// The layout of one kind of file and how its older versions upgrade
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    kind: [u8; 4],
+    /// `migrations[n]` upgrades version `n + 1` to `n + 2`
+    migrations: &'static [Migration],
+}
+
+impl Format {
+    /// Files holding `kind`, whose current version is one past the last of
+    /// `migrations`
+    pub const fn new(kind: [u8; 4], migrations: &'static [Migration]) -> Self {
+        Self { kind, migrations }
+    }
+
+    /// The version files are written in
+    pub const fn version(&self) -> u32 {
+        self.migrations.len() as u32 + 1
+    }
+
+    /// A file whose payload `write` appends in the current version
+    pub fn encode(
+        &self,
+        write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<Vec<u8>> {
+        let mut file = vec![0; HEADER_LEN];
+        write(&mut file)?;
+        let payload = &file[HEADER_LEN..];
+        let (len, crc) = (payload.len() as u64, crc32fast::hash(payload));
+        file[..8].copy_from_slice(&MAGIC);
+        file[8..12].copy_from_slice(&self.kind);
+        file[12..16].copy_from_slice(&self.version().to_le_bytes());
+        file[16..24].copy_from_slice(&len.to_le_bytes());
+        file[24..28].copy_from_slice(&crc.to_le_bytes());
+        Ok(file)
+    }
+
+    /// The payload of `file`, checked and upgraded to the current version
+    pub fn decode(&self, mut file: Vec<u8>) -> io::Result<Vec<u8>> {
+        let version = self.check(&file)?;
+        file.drain(..HEADER_LEN);
+        self.upgrade(version, file)
+    }
+
+    /// Check `file`'s header, length and checksum; returns the version its
+    /// payload is laid out in
+    pub fn check(&self, file: &[u8]) -> io::Result<u32> {
+        if !has_header(file) || file.len() < HEADER_LEN {
+            return Err(invalid("not a fakenotify state file"));
+        }
+        let field = |at: usize, len: usize| &file[at..at + len];
+        if field(8, 4) != self.kind {
+            return Err(invalid(format!(
+                "holds {} rather than {}",
+                String::from_utf8_lossy(field(8, 4)),
+                String::from_utf8_lossy(&self.kind)
+            )));
+        }
+        let version = u32::from_le_bytes(field(12, 4).try_into().expect("4 bytes"));
+        let len = u64::from_le_bytes(field(16, 8).try_into().expect("8 bytes"));
+        let crc = u32::from_le_bytes(field(24, 4).try_into().expect("4 bytes"));
+        let payload = &file[HEADER_LEN..];
+        if payload.len() as u64 != len {
+            return Err(invalid(format!(
+                "payload is {} bytes, expected {len}",
+                payload.len()
+            )));
+        }
+        if crc32fast::hash(payload) != crc {
+            return Err(invalid("checksum mismatch"));
+        }
+        Ok(version)
+    }
+
+    /// Bring `payload`, laid out as `version`, up to the current version
+    pub fn upgrade(&self, version: u32, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        if version == 0 || version > self.version() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "version {version} is not supported, this build reads up to {}",
+                    self.version()
+                ),
+            ));
+        }
+        for migrate in &self.migrations[version as usize - 1..] {
+            payload = migrate(payload)?;
+        }
+        Ok(payload)
+    }
+}
+
+/// Whether `file` starts with the header, as opposed to being from before
+/// there was one
+pub fn has_header(file: &[u8]) -> bool {
+    file.starts_with(&MAGIC)
+}
+
+/// An error for a file that cannot be what it claims to be
+pub fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Replace `path` with `bytes`: they go to a temporary file that is synced
+/// and renamed over `path`, then the directory is synced
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    replace(path, bytes, None)
+}
+
+/// [`write_atomic`], moving the file it replaces to [`previous`] first if
+/// it passes `format`'s checks; a damaged one is overwritten instead, so
+/// [`previous`] keeps the last copy that was whole
+pub fn write_keeping_previous(path: &Path, bytes: &[u8], format: &Format) -> io::Result<()> {
+    replace(path, bytes, Some(format))
+}
+
+fn replace(path: &Path, bytes: &[u8], keep_previous: Option<&Format>) -> io::Result<()> {
+    let partial = with_suffix(path, ".tmp");
+    let mut file = File::create(&partial)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    // Either rename may be the last thing done before a crash; the
+    // complete copy is then at `previous` or `path`
+    if let Some(format) = keep_previous {
+        match std::fs::read(path) {
+            Ok(current) if format.check(&current).is_ok() => {
+                std::fs::rename(path, previous(path))?;
+            }
+            Ok(_) => {
+                tracing::warn!(path = %path.display(), "Not keeping a damaged copy as the previous one");
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    std::fs::rename(&partial, path)?;
+    sync_dir(path);
+    Ok(())
+}
+
+/// Make renames in `path`'s directory durable, where the filesystem can
+#[cfg(unix)]
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // Some filesystems refuse to sync a directory; the rename still stands
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Renames are durable once done on Windows
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) {}
+
+/// Where [`write_keeping_previous`] keeps the file it replaced
+pub fn previous(path: &Path) -> PathBuf {
+    with_suffix(path, ".prev")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: Format = Format::new(*b"TEST", &[to_v2, to_v3]);
+
+    fn to_v2(mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.push(2);
+        Ok(payload)
+    }
+
+    fn to_v3(mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.push(3);
+        Ok(payload)
+    }
+
+    fn sealed(payload: &[u8]) -> Vec<u8> {
+        FORMAT
+            .encode(|out| {
+                out.extend_from_slice(payload);
+                Ok(())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_migrations() {
+        assert_eq!(FORMAT.version(), 3);
+        let file = sealed(b"state");
+        assert!(has_header(&file));
+        assert_eq!(file.len(), HEADER_LEN + 5);
+        assert_eq!(FORMAT.decode(file).unwrap(), b"state");
+
+        // Older payloads pick up every migration after their version
+        assert_eq!(FORMAT.upgrade(1, vec![1]).unwrap(), [1, 2, 3]);
+        assert_eq!(FORMAT.upgrade(2, vec![1]).unwrap(), [1, 3]);
+        let mut file = sealed(b"v1");
+        file[12..16].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(FORMAT.decode(file).unwrap(), [b'v', b'1', 2, 3]);
+    }
+
+    #[test]
+    fn test_damage_and_newer_versions_are_refused() {
+        let file = sealed(b"state");
+        let kind = |file: Vec<u8>| FORMAT.decode(file).unwrap_err().kind();
+
+        let mut flipped = file.clone();
+        flipped[HEADER_LEN + 2] ^= 1;
+        assert_eq!(kind(flipped), io::ErrorKind::InvalidData);
+        assert_eq!(
+            kind(file[..file.len() - 1].to_vec()),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(kind(file[..10].to_vec()), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"state".to_vec()), io::ErrorKind::InvalidData);
+        let other = Format::new(*b"ELSE", &[]);
+        assert_eq!(
+            other.decode(file.clone()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut newer = file;
+        newer[12..16].copy_from_slice(&4u32.to_le_bytes());
+        assert_eq!(kind(newer), io::ErrorKind::Unsupported);
+        assert!(FORMAT.upgrade(0, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_atomic_writes_keep_previous_copy() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+
+        let [one, two, three] = [&b"one"[..], b"two", b"three"].map(sealed);
+
+        write_keeping_previous(&path, &one, &FORMAT).unwrap();
+        assert!(!previous(&path).exists());
+        write_keeping_previous(&path, &two, &FORMAT).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), two);
+        assert_eq!(std::fs::read(previous(&path)).unwrap(), one);
+        assert!(!with_suffix(&path, ".tmp").exists());
+
+        // Plain atomic writes keep nothing more
+        write_atomic(&path, &three).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), three);
+        assert_eq!(std::fs::read(previous(&path)).unwrap(), one);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_current_copy_is_not_kept() {
+        let dir =
+            std::env::temp_dir().join(format!("fakenotify-store-damaged-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let [one, two, three] = [&b"one"[..], b"two", b"three"].map(sealed);
+
+        write_keeping_previous(&path, &one, &FORMAT).unwrap();
+        write_keeping_previous(&path, &two, &FORMAT).unwrap();
+        // Torn after it was written
+        std::fs::write(&path, &two[..two.len() - 1]).unwrap();
+
+        write_keeping_previous(&path, &three, &FORMAT).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), three);
+        assert_eq!(std::fs::read(previous(&path)).unwrap(), one);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
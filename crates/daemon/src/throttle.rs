@@ -0,0 +1,105 @@
+//! Shaping of the event stream into clients.
+//!
+//! Right after startup the first scans of large trees can produce a burst
+//! far bigger than anything seen in steady state. The dispatcher passes
+//! every event through a [`Throttle`], which holds events back during a
+//! quiet period after startup and then releases them at no more than a
+//! configured rate. Held events wait in the dispatcher's queue; none are
+//! dropped.
+
+use std::time::{Duration, Instant};
+
+/// Startup quiet period followed by a token-bucket rate limit
+#[derive(Debug)]
+pub struct Throttle {
+    /// End of the quiet period
+    quiet_until: Option<Instant>,
+    /// Events per second (0 is unlimited); also the burst size
+    rate: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// Hold events for `quiet` from now, then allow `rate` per second
+    pub fn new(quiet: Duration, rate: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            quiet_until: (!quiet.is_zero()).then(|| now + quiet),
+            rate,
+            tokens: rate as f64,
+            refilled: now,
+        }
+    }
+
+    /// A throttle that never delays
+    pub fn unlimited() -> Self {
+        Self::new(Duration::ZERO, 0)
+    }
+
+    /// Wait until the next event may be dispatched
+    pub async fn admit(&mut self) {
+        if let Some(until) = self.quiet_until {
+            let now = Instant::now();
+            if until > now {
+                tracing::info!(remaining = ?(until - now), "Holding events until the startup quiet period ends");
+                tokio::time::sleep(until - now).await;
+            }
+            self.quiet_until = None;
+        }
+        while let Some(wait) = self.take(Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token at `now`, or return how long until one is available
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        if self.rate == 0 {
+            return None;
+        }
+        let rate = self.rate as f64;
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_allows_burst_then_spaces_events() {
+        let mut throttle = Throttle::new(Duration::ZERO, 4);
+        let start = throttle.refilled;
+
+        for _ in 0..4 {
+            assert_eq!(throttle.take(start), None);
+        }
+        let wait = throttle.take(start).unwrap();
+        assert_eq!(wait, Duration::from_millis(250));
+
+        assert_eq!(throttle.take(start + wait), None);
+        assert!(throttle.take(start + wait).is_some());
+
+        // Idle time refills no more than one second's worth
+        let later = start + Duration::from_secs(60);
+        for _ in 0..4 {
+            assert_eq!(throttle.take(later), None);
+        }
+        assert!(throttle.take(later).is_some());
+    }
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        let mut throttle = Throttle::unlimited();
+        assert!(throttle.quiet_until.is_none());
+        let now = Instant::now();
+        assert!((0..10_000).all(|_| throttle.take(now).is_none()));
+    }
+}
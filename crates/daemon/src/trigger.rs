@@ -0,0 +1,241 @@
+//! Trigger files for applications that can be neither preloaded nor told.
+//!
+//! Some closed-source applications rescan when a file they poll changes.
+//! Each `[[trigger]]` updates such a file once changes under its directory
+//! have settled: `touch` bumps its modification time, `serial` rewrites it
+//! with a number one higher than it held. A burst of changes becomes one
+//! update, and changes to trigger files themselves are ignored.
+
+use crate::config::{TriggerConfig, TriggerMode};
+use crate::watcher::{WatcherEvent, notify_to_inotify_mask};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+
+/// How often settled triggers are looked for
+const TICK: Duration = Duration::from_millis(250);
+
+/// Debounces changes into trigger file updates
+pub struct Triggers {
+    triggers: Vec<TriggerConfig>,
+    /// Last change per trigger index, for those waiting to settle
+    pending: Mutex<HashMap<usize, Instant>>,
+}
+
+impl Triggers {
+    pub fn new(triggers: Vec<TriggerConfig>) -> Self {
+        Self {
+            triggers,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Note a change for every trigger it falls under
+    pub fn observe(&self, event: &WatcherEvent) {
+        let Some(mask) = notify_to_inotify_mask(&event.kind, event.is_dir) else {
+            return;
+        };
+        if self
+            .triggers
+            .iter()
+            .any(|trigger| event.path == trigger.file)
+        {
+            return;
+        }
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            if event.path.starts_with(&trigger.under) && mask.intersects(trigger.events) {
+                pending.insert(index, now);
+            }
+        }
+    }
+
+    /// Take the triggers quiet for their `settle` as of `now`
+    fn settled(&self, now: Instant) -> Vec<usize> {
+        let mut pending = self.pending.lock();
+        let due: Vec<usize> = pending
+            .iter()
+            .filter(|(index, changed)| {
+                now.duration_since(**changed) >= self.triggers[**index].settle
+            })
+            .map(|(index, _)| *index)
+            .collect();
+        for index in &due {
+            pending.remove(index);
+        }
+        due
+    }
+
+    /// Update the trigger files settled as of `now`
+    pub async fn flush(&self, now: Instant) {
+        let due: Vec<TriggerConfig> = self
+            .settled(now)
+            .into_iter()
+            .map(|index| self.triggers[index].clone())
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        let _ = tokio::task::spawn_blocking(move || {
+            for trigger in due {
+                match fire(&trigger) {
+                    Ok(()) => {
+                        tracing::debug!(file = %trigger.file.display(), "Updated trigger file")
+                    }
+                    Err(e) => tracing::warn!(
+                        file = %trigger.file.display(),
+                        error = %e,
+                        "Failed to update trigger file"
+                    ),
+                }
+            }
+        })
+        .await;
+    }
+}
+
+/// Update trigger files as their changes settle
+pub fn spawn(triggers: Arc<Triggers>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            triggers.flush(Instant::now()).await;
+        }
+    })
+}
+
+/// Touch the trigger file, or write the next serial into it; either
+/// creates it if missing
+fn fire(trigger: &TriggerConfig) -> io::Result<()> {
+    match trigger.mode {
+        TriggerMode::Touch => {
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(&trigger.file)?;
+            file.set_modified(SystemTime::now())
+        }
+        TriggerMode::Serial => {
+            // Rewritten in place: pollers may hold it open or track its inode.
+            // Not truncated first, so a poller never reads it empty.
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&trigger.file)?;
+            let mut text = String::new();
+            // Anything but a number, such as an empty file, restarts at 1
+            let serial = match file.read_to_string(&mut text) {
+                Ok(_) => text.trim().parse::<u64>().unwrap_or(0),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => 0,
+                Err(e) => return Err(e),
+            };
+            let next = format!("{}\n", serial.wrapping_add(1));
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(next.as_bytes())?;
+            file.set_len(next.len() as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fakenotify_protocol::EventMask;
+    use notify::EventKind;
+    use notify::event::{AccessKind, CreateKind};
+    use std::path::{Path, PathBuf};
+
+    fn trigger(file: &Path, mode: TriggerMode) -> TriggerConfig {
+        TriggerConfig {
+            file: file.to_path_buf(),
+            under: PathBuf::from("/mnt/media"),
+            events: EventMask::IN_CREATE | EventMask::IN_DELETE,
+            mode,
+            settle: Duration::from_secs(2),
+        }
+    }
+
+    fn event(path: &str, kind: EventKind) -> WatcherEvent {
+        WatcherEvent {
+            path: PathBuf::from(path),
+            kind,
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+
+    #[test]
+    fn test_changes_settle_into_one_update() {
+        let triggers = Triggers::new(vec![
+            trigger(Path::new("/mnt/media/.rescan"), TriggerMode::Touch),
+            trigger(Path::new("/var/lib/app/serial"), TriggerMode::Serial),
+        ]);
+        let create = EventKind::Create(CreateKind::File);
+        // Unselected events, other trees, and trigger files
+        triggers.observe(&event(
+            "/mnt/media/a.mkv",
+            EventKind::Access(AccessKind::Any),
+        ));
+        triggers.observe(&event("/mnt/other/a.mkv", create));
+        triggers.observe(&event("/mnt/media/.rescan", create));
+        assert!(triggers.pending.lock().is_empty());
+
+        triggers.observe(&event("/mnt/media/a.mkv", create));
+        triggers.observe(&event("/mnt/media/b.mkv", create));
+        let now = Instant::now();
+        assert!(triggers.settled(now).is_empty());
+        let mut due = triggers.settled(now + Duration::from_secs(2));
+        due.sort();
+        assert_eq!(due, [0, 1]);
+        assert!(triggers.settled(now + Duration::from_secs(4)).is_empty());
+    }
+
+    #[test]
+    fn test_fire() {
+        let dir = std::env::temp_dir().join(format!("fakenotify-trigger-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let serial = trigger(&dir.join("serial"), TriggerMode::Serial);
+        fire(&serial).unwrap();
+        fire(&serial).unwrap();
+        assert_eq!(std::fs::read_to_string(&serial.file).unwrap(), "2\n");
+        std::fs::write(&serial.file, "99\n").unwrap();
+        fire(&serial).unwrap();
+        assert_eq!(std::fs::read_to_string(&serial.file).unwrap(), "100\n");
+
+        // Empty or garbled files restart the count, with no leftover bytes
+        for text in ["", "not a serial\n"] {
+            std::fs::write(&serial.file, text).unwrap();
+            fire(&serial).unwrap();
+            assert_eq!(std::fs::read_to_string(&serial.file).unwrap(), "1\n");
+        }
+        std::fs::write(&serial.file, [0xff, 0xfe, b'7']).unwrap();
+        fire(&serial).unwrap();
+        assert_eq!(std::fs::read_to_string(&serial.file).unwrap(), "1\n");
+
+        let touch = trigger(&dir.join("touch"), TriggerMode::Touch);
+        fire(&touch).unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        File::options()
+            .append(true)
+            .open(&touch.file)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        fire(&touch).unwrap();
+        let modified = std::fs::metadata(&touch.file).unwrap().modified().unwrap();
+        assert!(modified > old);
+        assert_eq!(std::fs::metadata(&touch.file).unwrap().len(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
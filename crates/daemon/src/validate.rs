@@ -0,0 +1,579 @@
+//! Static checks for configuration files (`fakenotifyd config validate`).
+//!
+//! Figment ignores keys it does not know and happily accepts watches that
+//! overlap, so mistakes only show up as missing events. These checks run
+//! without starting the daemon.
+
+use crate::config::{
+    AccessEvents, Backend, ClientClass, Config, EventLogConfig, HookConfig, MediaLibrary,
+    MediaPathMap, MediaServer, PathMapping, PluginConfig, TriggerConfig, WatchConfig, fragments,
+};
+use crate::filter::EventFilter;
+use crate::media::MediaServerKind;
+use crate::plugin::{plugin_feature, supported_plugin};
+use fakenotify_protocol::{ChangeDetection, is_valid_tag};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// How serious a finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The daemon runs, but probably not as intended
+    Warning,
+    /// The configuration is wrong
+    Error,
+}
+
+/// One problem found in a configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Check `file`, its fragments, and the configuration they merge into
+pub fn validate_file(file: &Path) -> Vec<Issue> {
+    if !file.exists() && !file.with_file_name("conf.d").is_dir() {
+        return vec![Issue::error(format!("{} does not exist", file.display()))];
+    }
+
+    let mut issues = Vec::new();
+    let mut files = vec![file.to_path_buf()];
+    match fragments(file) {
+        Ok(fragments) => files.extend(fragments),
+        Err(e) => issues.push(Issue::error(e.to_string())),
+    }
+
+    let known = known_keys();
+    for path in files.iter().filter(|path| path.exists()) {
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| contents.parse::<toml::Value>().map_err(|e| e.to_string()));
+        match parsed {
+            Ok(value) => {
+                let mut unknown = Vec::new();
+                unknown_keys(&value, &known, "", &mut unknown);
+                issues.extend(
+                    unknown.into_iter().map(|key| {
+                        Issue::error(format!("{}: unknown key `{key}`", path.display()))
+                    }),
+                );
+            }
+            Err(e) => issues.push(Issue::error(format!("{}: {e}", path.display()))),
+        }
+    }
+
+    match Config::load(Some(&file.to_path_buf())) {
+        Ok(config) => issues.extend(check(&config)),
+        Err(errors) => issues.extend(errors.into_iter().map(|e| Issue::error(e.to_string()))),
+    }
+    issues
+}
+
+/// Semantic checks on a merged configuration
+pub fn check(config: &Config) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    if let Err(e) = tracing_subscriber::EnvFilter::try_new(&config.daemon.log_level) {
+        issues.push(Issue::error(format!(
+            "invalid log_level `{}`: {e}",
+            config.daemon.log_level
+        )));
+    }
+
+    let daemon = &config.daemon;
+    if daemon.heartbeat_interval > 0
+        && daemon.heartbeat_timeout > 0
+        && daemon.heartbeat_timeout <= daemon.heartbeat_interval
+    {
+        issues.push(Issue::error(
+            "heartbeat_timeout must be longer than heartbeat_interval, or every client acking heartbeats is dropped",
+        ));
+    }
+
+    if daemon.noisy_threshold > 0 && !daemon.enable_stats {
+        issues.push(Issue::warning(
+            "noisy_threshold has no effect unless enable_stats is set",
+        ));
+    }
+
+    if daemon.catch_up > 0 && daemon.checkpoint_dir.is_none() {
+        issues.push(Issue::warning(
+            "catch_up has no effect unless checkpoint_dir is set",
+        ));
+    }
+
+    if daemon.checkpoint_history > 0 && daemon.checkpoint_dir.is_none() {
+        issues.push(Issue::warning(
+            "checkpoint_history has no effect unless checkpoint_dir is set",
+        ));
+    }
+
+    if cfg!(not(target_os = "linux")) && daemon.track_xattrs {
+        issues.push(Issue::warning("track_xattrs only works on Linux"));
+    }
+
+    if daemon.watchman_socket.is_some() {
+        if cfg!(not(unix)) {
+            issues.push(Issue::warning("watchman_socket only works on Unix"));
+        } else if !config.watch.iter().any(|watch| watch.recursive) {
+            issues.push(Issue::warning(
+                "watchman_socket is set, but Watchman clients can only watch under a recursive watch and there is none",
+            ));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for watch in &config.watch {
+        if !seen.insert(&watch.path) {
+            issues.push(Issue::error(format!(
+                "{} is watched more than once",
+                watch.path.display()
+            )));
+        }
+        if !watch.path.exists() {
+            issues.push(Issue::warning(format!(
+                "watch path {} does not exist",
+                watch.path.display()
+            )));
+        }
+        if let Err(e) = EventFilter::compile(&watch.name_filter()) {
+            issues.push(Issue::error(format!(
+                "invalid name_regex for {}: {e}",
+                watch.path.display()
+            )));
+        }
+        if watch.events.is_empty() {
+            issues.push(Issue::warning(format!(
+                "watch on {} selects no events",
+                watch.path.display()
+            )));
+        }
+        if daemon.access_events == AccessEvents::Atime
+            && watch.change_detection == ChangeDetection::Hash
+            && watch.backend == Backend::Poll
+        {
+            issues.push(Issue::warning(format!(
+                "{} uses change_detection = \"hash\", whose reads move atimes, so access_events = \"atime\" reports every hashed file as accessed",
+                watch.path.display()
+            )));
+        }
+        if watch.backend == Backend::Native
+            && (watch.settle_scans > 0 || !watch.partial_suffixes.is_empty())
+        {
+            issues.push(Issue::warning(format!(
+                "settle_scans and partial_suffixes on {} have no effect with backend = \"native\"",
+                watch.path.display()
+            )));
+        }
+        if watch.partial_suffixes.iter().any(String::is_empty) {
+            issues.push(Issue::error(format!(
+                "partial_suffixes on {} has an empty suffix, which would match every file",
+                watch.path.display()
+            )));
+        }
+        for tag in watch.tags.iter().filter(|tag| !is_valid_tag(tag)) {
+            issues.push(Issue::error(format!(
+                "invalid tag `{tag}` on {}: tags are non-empty, without whitespace or commas",
+                watch.path.display()
+            )));
+        }
+    }
+
+    for outer in config.watch.iter().filter(|w| w.recursive) {
+        for inner in &config.watch {
+            if inner.path != outer.path && inner.path.starts_with(&outer.path) {
+                issues.push(Issue::warning(format!(
+                    "{} is already covered by the recursive watch on {}, so its events are reported twice",
+                    inner.path.display(),
+                    outer.path.display()
+                )));
+            }
+        }
+    }
+
+    let mut prefixes = HashSet::new();
+    for mapping in &config.path_map {
+        if !prefixes.insert(&mapping.client) {
+            issues.push(Issue::error(format!(
+                "path_map client prefix {} is mapped more than once",
+                mapping.client.display()
+            )));
+        }
+        if !mapping.host.exists() {
+            issues.push(Issue::warning(format!(
+                "path_map host path {} does not exist",
+                mapping.host.display()
+            )));
+        }
+    }
+
+    for server in &config.media_server {
+        for library in &server.library {
+            if server.kind == MediaServerKind::Plex && library.section.is_none() {
+                issues.push(Issue::error(format!(
+                    "Plex library {} needs a section",
+                    library.path.display()
+                )));
+            }
+            if !config
+                .watch
+                .iter()
+                .any(|watch| library.path.starts_with(&watch.path))
+            {
+                issues.push(Issue::warning(format!(
+                    "media library {} is not under any watch, so it is never scanned",
+                    library.path.display()
+                )));
+            }
+        }
+    }
+
+    for plugin in &config.plugin {
+        if !plugin.path.is_file() {
+            issues.push(Issue::error(format!(
+                "plugin {} does not exist",
+                plugin.path.display()
+            )));
+        }
+        let feature = plugin_feature(&plugin.path);
+        if !supported_plugin(feature) {
+            issues.push(Issue::warning(format!(
+                "plugin {} is ignored: this daemon was built without the {feature} feature",
+                plugin.path.display()
+            )));
+        }
+    }
+
+    let mut hooks = HashSet::new();
+    for hook in &config.hook {
+        if !hooks.insert(&hook.name) {
+            issues.push(Issue::error(format!(
+                "hook {} is defined more than once",
+                hook.name
+            )));
+        }
+        if hook.command.is_empty() {
+            issues.push(Issue::error(format!("hook {} has no command", hook.name)));
+        }
+        for tag in &hook.tags {
+            if !config.watch.iter().any(|watch| watch.tags.contains(tag)) {
+                issues.push(Issue::warning(format!(
+                    "hook {} is limited to tag `{tag}`, which no [[watch]] carries",
+                    hook.name
+                )));
+            }
+        }
+    }
+
+    for trigger in &config.trigger {
+        if !config
+            .watch
+            .iter()
+            .any(|watch| trigger.under.starts_with(&watch.path))
+        {
+            issues.push(Issue::warning(format!(
+                "trigger {} is for {}, which no [[watch]] covers",
+                trigger.file.display(),
+                trigger.under.display()
+            )));
+        }
+        if trigger.events.is_empty() {
+            issues.push(Issue::warning(format!(
+                "trigger {} selects no events",
+                trigger.file.display()
+            )));
+        }
+        if trigger
+            .file
+            .parent()
+            .is_some_and(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+        {
+            issues.push(Issue::error(format!(
+                "the directory of trigger {} does not exist",
+                trigger.file.display()
+            )));
+        }
+    }
+
+    if !config.event_log.is_empty() && cfg!(not(unix)) {
+        issues.push(Issue::warning("[[event_log]] only works on Unix"));
+    }
+    for log in &config.event_log {
+        for pattern in &log.glob {
+            if let Err(e) = globset::Glob::new(pattern) {
+                issues.push(Issue::error(format!(
+                    "event_log glob {pattern:?} is invalid: {e}"
+                )));
+            }
+        }
+        if log.events.is_empty() {
+            issues.push(Issue::warning(format!(
+                "event_log for {} selects no events",
+                log.identifier
+            )));
+        }
+    }
+
+    issues
+}
+
+/// Every key the configuration understands, as a TOML document with all
+/// optional values set and one element in each list
+fn known_keys() -> toml::Value {
+    let mut sample = Config::default();
+    sample.daemon.tcp_listen = Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    sample.daemon.vsock_listen = Some(0);
+    sample.daemon.grpc_listen = Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+    sample.daemon.watchman_socket = Some(PathBuf::new());
+    sample.daemon.audit_log = Some(PathBuf::from("/"));
+    sample.daemon.checkpoint_dir = Some(PathBuf::from("/"));
+    sample.watch.push(WatchConfig {
+        path: PathBuf::from("/"),
+        poll_interval: std::time::Duration::ZERO,
+        recursive: false,
+        backend: Backend::default(),
+        events: fakenotify_protocol::EventMask::empty(),
+        extensions: vec![String::new()],
+        name_regex: Some(String::new()),
+        change_detection: Default::default(),
+        revalidate: false,
+        case_insensitive: Some(false),
+        confirm_deletes: 0,
+        restat_deletes: false,
+        settle_scans: 0,
+        partial_suffixes: vec![String::new()],
+        tags: vec![String::new()],
+        denied_events: fakenotify_protocol::EventMask::empty(),
+    });
+    sample.path_map.push(PathMapping {
+        host: PathBuf::from("/"),
+        client: PathBuf::from("/"),
+    });
+    sample.client_class.push(ClientClass {
+        name: Some(String::new()),
+        uid: Some(0),
+        max_queued_events: Some(0),
+        max_queued_bytes: Some(0),
+        max_queue_age: Some(0),
+        queue_overflow: Some(Default::default()),
+    });
+    sample.media_server.push(MediaServer {
+        kind: MediaServerKind::Plex,
+        url: String::new(),
+        api_key: String::new(),
+        settle: std::time::Duration::ZERO,
+        library: vec![MediaLibrary {
+            path: PathBuf::from("/"),
+            section: Some(String::new()),
+        }],
+        path_map: vec![MediaPathMap {
+            local: PathBuf::from("/"),
+            remote: PathBuf::from("/"),
+        }],
+    });
+    sample.plugin.push(PluginConfig {
+        path: PathBuf::from("/"),
+        fuel: 0,
+        budget: std::time::Duration::ZERO,
+    });
+    sample.hook.push(HookConfig {
+        name: String::new(),
+        command: vec![String::new()],
+        tags: vec![String::new()],
+    });
+    sample.trigger.push(TriggerConfig {
+        file: PathBuf::from("/"),
+        under: PathBuf::from("/"),
+        events: fakenotify_protocol::EventMask::empty(),
+        mode: Default::default(),
+        settle: std::time::Duration::ZERO,
+    });
+    sample.event_log.push(EventLogConfig {
+        target: Default::default(),
+        events: fakenotify_protocol::EventMask::empty(),
+        glob: vec![String::new()],
+        identifier: String::new(),
+    });
+    sample.include.push(PathBuf::from("/"));
+    toml::Value::try_from(sample).expect("config serializes to TOML")
+}
+
+/// Collect the dotted names of keys in `value` that `known` does not have
+fn unknown_keys(value: &toml::Value, known: &toml::Value, prefix: &str, out: &mut Vec<String>) {
+    match (value, known) {
+        (toml::Value::Table(table), toml::Value::Table(known)) => {
+            for (key, value) in table {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                match known.get(key) {
+                    Some(known) => unknown_keys(value, known, &name, out),
+                    None => out.push(name),
+                }
+            }
+        }
+        (toml::Value::Array(items), toml::Value::Array(known)) => {
+            if let Some(known) = known.first() {
+                for item in items {
+                    unknown_keys(item, known, prefix, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_keys() {
+        let value: toml::Value = r#"
+            includes = []
+
+            [daemon]
+            socket = "/run/fakenotify.sock"
+            audit_log = "/var/log/fakenotify/audit.log"
+            heartbeat_timout = 10
+
+            [[watch]]
+            path = "/mnt/media"
+            recursve = true
+        "#
+        .parse()
+        .unwrap();
+        let mut unknown = Vec::new();
+        unknown_keys(&value, &known_keys(), "", &mut unknown);
+        unknown.sort();
+        assert_eq!(
+            unknown,
+            vec!["daemon.heartbeat_timout", "includes", "watch.recursve"]
+        );
+    }
+
+    #[test]
+    fn test_check_conflicting_watches() {
+        let dir = std::env::temp_dir();
+        let watch = |path: PathBuf, recursive| WatchConfig {
+            path,
+            poll_interval: std::time::Duration::from_secs(5),
+            recursive,
+            backend: Backend::Poll,
+            events: fakenotify_protocol::EventMask::IN_ALL_EVENTS,
+            extensions: Vec::new(),
+            name_regex: None,
+            change_detection: Default::default(),
+            revalidate: false,
+            case_insensitive: None,
+            confirm_deletes: 1,
+            restat_deletes: false,
+            settle_scans: 0,
+            partial_suffixes: Vec::new(),
+            tags: Vec::new(),
+            denied_events: fakenotify_protocol::EventMask::empty(),
+        };
+        let config = Config {
+            watch: vec![
+                watch(dir.clone(), true),
+                watch(dir.join("nested"), false),
+                watch(dir.clone(), false),
+            ],
+            ..Config::default()
+        };
+
+        let issues = check(&config);
+        let errors: Vec<_> = issues
+            .iter()
+            .filter(|i| i.severity == Severity::Error)
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("more than once"));
+        assert!(issues.iter().any(|i| i.message.contains("already covered")));
+        assert!(issues.iter().any(|i| i.message.contains("does not exist")));
+    }
+
+    #[test]
+    fn test_check_heartbeat_timeout() {
+        let mut config = Config::default();
+        assert!(check(&config).is_empty());
+        config.daemon.heartbeat_timeout = config.daemon.heartbeat_interval;
+        assert_eq!(check(&config)[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_check_media_libraries() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[watch]]
+            path = "/mnt/media"
+
+            [[media_server]]
+            kind = "plex"
+            url = "http://127.0.0.1:32400"
+
+            [[media_server.library]]
+            path = "/mnt/media/tv"
+
+            [[media_server.library]]
+            path = "/srv/movies"
+            section = "1"
+            "#,
+        )
+        .unwrap();
+        config.watch[0].path = std::env::temp_dir();
+        config.media_server[0].library[0].path = std::env::temp_dir().join("tv");
+        let issues = check(&config);
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert_eq!(issues[1].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_check_event_logs() {
+        let config: Config = toml::from_str(
+            r#"
+            [[event_log]]
+            target = "syslog"
+            events = ["create", "close_write"]
+            glob = ["*.mkv", "/mnt/media/[incoming/**"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.event_log[0].identifier, "fakenotifyd");
+        let issues = check(&config);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert!(issues[0].message.contains("[incoming"));
+    }
+}
@@ -0,0 +1,94 @@
+//! `AF_VSOCK` listener for VM guests.
+//!
+//! A guest reading a filesystem its host exports (virtiofs, 9p, or NFS over
+//! the host's network) can reach a daemon on the host without networking:
+//! the daemon listens on a vsock port and the guest connects to CID 2 with
+//! `FAKENOTIFY_SOCKET=vsock://2:<port>`. Once accepted, a vsock connection is
+//! a plain stream socket.
+
+use socket2::{SockAddr, Socket};
+use std::io;
+use std::os::fd::OwnedFd;
+use tokio::io::unix::AsyncFd;
+use tokio::net::UnixStream;
+
+/// Listening vsock socket
+pub struct VsockListener {
+    socket: AsyncFd<Socket>,
+}
+
+impl VsockListener {
+    /// Listen on `port` for every CID
+    #[cfg(target_os = "linux")]
+    pub fn bind(port: u32) -> io::Result<Self> {
+        let socket = Socket::new(socket2::Domain::VSOCK, socket2::Type::STREAM, None)?;
+        socket.bind(&SockAddr::vsock(libc::VMADDR_CID_ANY, port))?;
+        socket.listen(128)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Listen on `port` (vsock is Linux only)
+    #[cfg(not(target_os = "linux"))]
+    pub fn bind(_port: u32) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "vsock is only available on Linux",
+        ))
+    }
+
+    /// Accept a connection, along with the peer's CID if known
+    ///
+    /// The stream is a `UnixStream` only in type: tokio reads and writes any
+    /// stream socket through it, and nothing asks it for Unix credentials.
+    pub async fn accept(&self) -> io::Result<(UnixStream, Option<u32>)> {
+        let (socket, addr) = loop {
+            let mut ready = self.socket.readable().await?;
+            if let Ok(accepted) = ready.try_io(|socket| socket.get_ref().accept()) {
+                break accepted?;
+            }
+        };
+        socket.set_nonblocking(true)?;
+        let stream = std::os::unix::net::UnixStream::from(OwnedFd::from(socket));
+        Ok((UnixStream::from_std(stream)?, peer_cid(&addr)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_cid(addr: &SockAddr) -> Option<u32> {
+    addr.as_vsock_address().map(|(cid, _)| cid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_cid(_addr: &SockAddr) -> Option<u32> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    #[ignore = "needs the vsock_loopback module, run with --ignored"]
+    async fn test_accepts_local_connection() {
+        let port = 40000 + std::process::id() % 20000;
+        let listener = VsockListener::bind(port).unwrap();
+        let client = tokio::task::spawn_blocking(move || {
+            let fd = fakenotify_protocol::connect_vsock(libc::VMADDR_CID_LOCAL, port).unwrap();
+            std::os::unix::net::UnixStream::from(fd)
+                .write_all(b"hello")
+                .unwrap();
+        });
+
+        let (mut stream, cid) = listener.accept().await.unwrap();
+        assert_eq!(cid, Some(libc::VMADDR_CID_LOCAL));
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        client.await.unwrap();
+    }
+}
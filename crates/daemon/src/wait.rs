@@ -0,0 +1,202 @@
+//! `fakenotifyd wait`: block until one matching event, like a one-shot
+//! `inotifywait`.
+//!
+//! Watches a path through the running daemon and returns on the first event
+//! of the requested kinds whose name matches the glob, so a shell pipeline
+//! can wait for a file to land on an NFS share, where `inotifywait` would
+//! never wake up. As with `inotifywait`, only events after the watch is in
+//! place count: check for files already there before waiting.
+
+use crate::output::WaitReport;
+use color_eyre::eyre::{Result, bail};
+use fakenotify_client::{Client, Event};
+use fakenotify_protocol::{ClientInfo, EventMask};
+use globset::{GlobBuilder, GlobMatcher};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Exit status when no matching event came before the timeout
+pub const EXIT_TIMEOUT: i32 = 1;
+
+/// What to wait for
+#[derive(Debug, Clone)]
+pub struct WaitOptions {
+    /// Directory, or single file, to watch
+    pub path: PathBuf,
+    /// Event kinds that end the wait; empty for any
+    pub mask: EventMask,
+    /// Names that end the wait, e.g. `*.csv`; `None` for any
+    pub glob: Option<String>,
+    /// Give up after this long
+    pub timeout: Option<Duration>,
+}
+
+/// Which events end the wait
+struct Matcher {
+    mask: EventMask,
+    glob: Option<GlobMatcher>,
+}
+
+impl Matcher {
+    fn new(options: &WaitOptions) -> Result<Self> {
+        let mask = if options.mask.is_empty() {
+            EventMask::IN_ALL_EVENTS
+        } else {
+            options.mask
+        };
+        let glob = match &options.glob {
+            Some(pattern) => Some(
+                GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| color_eyre::eyre::eyre!("invalid glob {pattern:?}: {e}"))?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+        Ok(Self { mask, glob })
+    }
+
+    /// Whether `event` on `watched` ends the wait; events on the watched
+    /// path itself are matched by its own name
+    fn matches(&self, watched: &Path, event: &Event) -> bool {
+        if !event.mask.intersects(self.mask) {
+            return false;
+        }
+        let Some(glob) = &self.glob else {
+            return true;
+        };
+        match &event.name {
+            Some(name) => glob.is_match(name),
+            None => watched.file_name().is_some_and(|name| glob.is_match(name)),
+        }
+    }
+}
+
+/// Wait on the daemon at `socket`; `None` if the timeout passed first
+pub fn run(socket: &Path, options: &WaitOptions) -> Result<Option<WaitReport>> {
+    let matcher = Matcher::new(options)?;
+    let mut client = Client::connect_to(socket)?;
+    client.set_info(ClientInfo {
+        name: Some("fakenotifyd wait".to_string()),
+        pid: Some(std::process::id() as i32),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    })?;
+    let wd = client.add_watch(&options.path, matcher.mask)?;
+
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let Some(event) = client.read_event_timeout(left)? else {
+            return Ok(None);
+        };
+        if event.mask.contains(EventMask::IN_Q_OVERFLOW) {
+            tracing::warn!("The daemon dropped events; a matching one may have been missed");
+            continue;
+        }
+        if event.wd != wd {
+            continue;
+        }
+        if event.mask.contains(EventMask::IN_IGNORED) {
+            bail!("{} is no longer watched", options.path.display());
+        }
+        if matcher.matches(&options.path, &event) {
+            return Ok(Some(report(&options.path, &event)));
+        }
+    }
+}
+
+fn report(watched: &Path, event: &Event) -> WaitReport {
+    let name = event
+        .name
+        .as_ref()
+        .map(|name| name.to_string_lossy().into_owned());
+    WaitReport {
+        watched: watched.to_path_buf(),
+        path: match &name {
+            Some(name) => watched.join(name),
+            None => watched.to_path_buf(),
+        },
+        name,
+        events: event.mask,
+        cookie: event.cookie,
+    }
+}
+
+/// `report` as `inotifywait` prints an event: the watched path (with a
+/// trailing `/` for events on a name in it), the events, the name
+pub fn inotifywait_line(report: &WaitReport) -> String {
+    let events: Vec<&str> = report
+        .events
+        .iter_names()
+        .map(|(name, _)| name.trim_start_matches("IN_"))
+        .collect();
+    let watched = report.watched.display().to_string();
+    match &report.name {
+        Some(name) => format!(
+            "{}/ {} {name}",
+            watched.trim_end_matches('/'),
+            events.join(",")
+        ),
+        None => format!("{watched} {} ", events.join(",")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn event(mask: EventMask, name: Option<&str>) -> Event {
+        Event {
+            wd: 1,
+            mask,
+            cookie: 0,
+            name: name.map(OsString::from),
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn test_matches_kind_and_glob() {
+        let options = WaitOptions {
+            path: PathBuf::from("/mnt/drop"),
+            mask: EventMask::IN_CREATE | EventMask::IN_MOVED_TO,
+            glob: Some("*.csv".to_string()),
+            timeout: None,
+        };
+        let matcher = Matcher::new(&options).unwrap();
+        let drop = Path::new("/mnt/drop");
+        assert!(matcher.matches(drop, &event(EventMask::IN_CREATE, Some("a.csv"))));
+        assert!(matcher.matches(drop, &event(EventMask::IN_MOVED_TO, Some("b.csv"))));
+        assert!(!matcher.matches(drop, &event(EventMask::IN_CREATE, Some("a.csv.part"))));
+        assert!(!matcher.matches(drop, &event(EventMask::IN_MODIFY, Some("a.csv"))));
+
+        // A watched file is matched by its own name
+        let file = Path::new("/mnt/drop/done.csv");
+        assert!(matcher.matches(file, &event(EventMask::IN_CREATE, None)));
+
+        let any = Matcher::new(&WaitOptions {
+            mask: EventMask::empty(),
+            glob: None,
+            ..options
+        })
+        .unwrap();
+        assert!(any.matches(drop, &event(EventMask::IN_DELETE, Some("x"))));
+    }
+
+    #[test]
+    fn test_prints_like_inotifywait() {
+        let create = event(EventMask::IN_CREATE | EventMask::IN_ISDIR, Some("batch"));
+        let in_dir = report(Path::new("/mnt/drop/"), &create);
+        assert_eq!(in_dir.path, PathBuf::from("/mnt/drop/batch"));
+        assert_eq!(inotifywait_line(&in_dir), "/mnt/drop/ CREATE,ISDIR batch");
+
+        let modify = event(EventMask::IN_CLOSE_WRITE, None);
+        let on_file = report(Path::new("/mnt/drop/done.csv"), &modify);
+        assert_eq!(
+            inotifywait_line(&on_file),
+            "/mnt/drop/done.csv CLOSE_WRITE "
+        );
+    }
+}
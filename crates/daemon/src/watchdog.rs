@@ -0,0 +1,183 @@
+//! Watchdog for scanner reads stuck in uninterruptible IO.
+//!
+//! A `stat` or `readdir` on a pathological NFS directory can sit in the
+//! kernel for minutes, and the scan that issued it cannot be cancelled. So
+//! that one such directory does not freeze a whole watch, [`WatchdogSource`]
+//! runs every read on a worker thread and gives up waiting after the
+//! configured threshold: the offending path is logged and counted, and the
+//! read fails with [`io::ErrorKind::TimedOut`], which the scanner treats as
+//! "unchanged this cycle". Until the stuck read returns, later reads of the
+//! same path fail straight away instead of tying up another worker.
+
+use crate::source::{EntryMeta, ScanSource, SourceEntry};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Tracks reads that outlived the threshold
+#[derive(Debug)]
+pub struct Watchdog {
+    threshold: Duration,
+    /// Paths with a read still stuck, and whether it has since returned
+    stuck: Mutex<HashMap<PathBuf, Arc<AtomicBool>>>,
+    /// Reads given up on since startup
+    stuck_scans: AtomicU64,
+    /// Workers waiting for a job; stuck workers are simply not returned
+    idle: Arc<Mutex<Vec<mpsc::Sender<Job>>>>,
+}
+
+impl Watchdog {
+    /// Give up on reads that take longer than `threshold`
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            stuck: Mutex::new(HashMap::new()),
+            stuck_scans: AtomicU64::new(0),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Reads given up on since startup
+    pub fn stuck_scans(&self) -> u64 {
+        self.stuck_scans.load(Ordering::Relaxed)
+    }
+
+    /// Run `op` on `path` on a worker, waiting at most the threshold
+    fn run<T: Send + 'static>(
+        &self,
+        path: &Path,
+        op: impl FnOnce() -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        if let Some(done) = self.stuck.lock().get(path)
+            && !done.load(Ordering::Acquire)
+        {
+            return Err(timed_out(path));
+        }
+        self.stuck.lock().remove(path);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let (result_tx, result_rx) = mpsc::channel();
+        let job_done = Arc::clone(&done);
+        self.submit(Box::new(move || {
+            let _ = result_tx.send(op());
+            job_done.store(true, Ordering::Release);
+        }));
+
+        match result_rx.recv_timeout(self.threshold) {
+            Ok(result) => result,
+            Err(_) => {
+                let count = self.stuck_scans.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::warn!(
+                    path = %path.display(),
+                    threshold = ?self.threshold,
+                    stuck_scans = count,
+                    "Read is stuck, skipping this path until it returns"
+                );
+                self.stuck.lock().insert(path.to_path_buf(), done);
+                Err(timed_out(path))
+            }
+        }
+    }
+
+    /// Hand `job` to an idle worker, starting one if none is free
+    fn submit(&self, job: Job) {
+        let mut job = job;
+        while let Some(worker) = self.idle.lock().pop() {
+            match worker.send(job) {
+                Ok(()) => return,
+                // The worker thread is gone; try the next one
+                Err(mpsc::SendError(returned)) => job = returned,
+            }
+        }
+
+        let (tx, rx) = mpsc::channel::<Job>();
+        let idle = Arc::clone(&self.idle);
+        let spawned = std::thread::Builder::new()
+            .name("fakenotify-io".to_string())
+            .spawn(move || {
+                let mut job = Some(job);
+                while let Some(run) = job.take() {
+                    run();
+                    idle.lock().push(tx.clone());
+                    job = rx.recv().ok();
+                }
+            });
+        if let Err(e) = spawned {
+            tracing::error!(error = %e, "Failed to start IO worker");
+        }
+    }
+}
+
+fn timed_out(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} is stuck in IO", path.display()),
+    )
+}
+
+/// [`ScanSource`] that gives up on reads stuck past the [`Watchdog`]
+/// threshold
+pub struct WatchdogSource {
+    inner: Arc<dyn ScanSource>,
+    watchdog: Arc<Watchdog>,
+}
+
+impl WatchdogSource {
+    pub fn new(inner: Arc<dyn ScanSource>, watchdog: Arc<Watchdog>) -> Self {
+        Self { inner, watchdog }
+    }
+}
+
+impl ScanSource for WatchdogSource {
+    fn metadata(&self, path: &Path) -> io::Result<EntryMeta> {
+        let inner = Arc::clone(&self.inner);
+        let owned = path.to_path_buf();
+        self.watchdog.run(path, move || inner.metadata(&owned))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let inner = Arc::clone(&self.inner);
+        let owned = path.to_path_buf();
+        self.watchdog.run(path, move || inner.read_dir(&owned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::sim::SimSource;
+
+    #[test]
+    fn test_stuck_read_is_skipped_until_it_returns() {
+        let sim = Arc::new(SimSource::new());
+        sim.mkdir_all("/mnt/nas/huge");
+        sim.mkdir_all("/mnt/nas/show");
+        sim.hang("/mnt/nas/huge");
+        let watchdog = Arc::new(Watchdog::new(Duration::from_millis(20)));
+        let source = WatchdogSource::new(sim.clone(), Arc::clone(&watchdog));
+
+        let err = source.read_dir(Path::new("/mnt/nas/huge")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Still stuck: fails at once and is not counted again
+        assert!(source.read_dir(Path::new("/mnt/nas/huge")).is_err());
+        assert_eq!(watchdog.stuck_scans(), 1);
+        // Other paths are unaffected
+        assert!(source.read_dir(Path::new("/mnt/nas")).is_ok());
+
+        sim.unhang("/mnt/nas/huge");
+        for _ in 0..100 {
+            if source.read_dir(Path::new("/mnt/nas/huge")).is_ok() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("path stayed stuck after its read returned");
+    }
+}
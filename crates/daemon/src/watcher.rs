@@ -1,32 +1,54 @@
 //! NFS filesystem watcher using polling.
 //!
-//! Uses the `notify` crate's `PollWatcher` which works on NFS filesystems
-//! where inotify does not function.
+//! Each watched root gets a [`Scanner`] that is re-walked on its poll
+//! interval, which works on NFS filesystems where inotify does not function.
+//! Roots configured with the native backend use the OS notification API via
+//! the `notify` crate instead. Event kinds reuse the `notify` crate's
+//! [`EventKind`] vocabulary.
 
-use crate::config::WatchConfig;
-use crate::state::DaemonState;
-use fakenotify_protocol::{EventMask, FramedMessage, InotifyEvent};
+use crate::catchup::CatchUp;
+use crate::checkpoint::{Checkpoints, ScanKey};
+use crate::config::{AccessEvents, Backend, WatchConfig};
+use crate::filter::EventFilter;
+use crate::fold;
+use crate::health::MountMonitor;
+use crate::journal::sequenced_frame;
+use crate::scanner::{Scanner, Snapshot};
+use crate::source::ScanSource;
+use crate::source::content::ContentSource;
+use crate::source::revalidate::RevalidateSource;
+use crate::state::{DaemonState, DispatchWatch};
+use crate::throttle::Throttle;
+use bytes::{BufMut, Bytes, BytesMut};
+use fakenotify_protocol::{Capabilities, ChangeDetection, EventMask, FramedMessage, InotifyEvent};
 use notify::{
-    Config, EventKind, PollWatcher, RecursiveMode, Watcher,
-    event::{CreateKind, ModifyKind, RemoveKind, RenameMode},
+    EventKind, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode, CreateKind, ModifyKind, RemoveKind, RenameMode},
 };
-use std::collections::HashMap;
-use std::path::PathBuf;
+use parking_lot::RwLock;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+/// Shortest scan period; a zero interval would spin a core
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// Cookie counter for rename events
 static COOKIE_COUNTER: AtomicU32 = AtomicU32::new(1);
 
 /// Generate a new unique cookie for rename events
-fn next_cookie() -> u32 {
+pub fn next_cookie() -> u32 {
     COOKIE_COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
 /// Convert notify EventKind to inotify EventMask
-fn notify_to_inotify_mask(kind: &EventKind, is_dir: bool) -> Option<EventMask> {
+pub fn notify_to_inotify_mask(kind: &EventKind, is_dir: bool) -> Option<EventMask> {
     let base_mask = match kind {
         EventKind::Create(create_kind) => match create_kind {
             CreateKind::File => EventMask::IN_CREATE,
@@ -50,6 +72,7 @@ fn notify_to_inotify_mask(kind: &EventKind, is_dir: bool) -> Option<EventMask> {
             RemoveKind::Any => EventMask::IN_DELETE,
             _ => EventMask::IN_DELETE,
         },
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => EventMask::IN_CLOSE_WRITE,
         EventKind::Access(_) => EventMask::IN_ACCESS,
         EventKind::Other => return None,
         EventKind::Any => EventMask::IN_ALL_EVENTS,
@@ -64,163 +87,1097 @@ fn notify_to_inotify_mask(kind: &EventKind, is_dir: bool) -> Option<EventMask> {
     Some(mask)
 }
 
+/// Convert an event on a watched path itself to the inotify mask
+///
+/// Removal and renaming become IN_DELETE_SELF and IN_MOVE_SELF. The path
+/// reappearing is not reported: inotify would have dropped the watch.
+fn self_mask(kind: &EventKind, is_dir: bool) -> Option<EventMask> {
+    match kind {
+        EventKind::Remove(_) => Some(EventMask::IN_DELETE_SELF),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => None,
+        EventKind::Modify(ModifyKind::Name(_)) => Some(EventMask::IN_MOVE_SELF),
+        EventKind::Create(_) => None,
+        _ => notify_to_inotify_mask(kind, is_dir),
+    }
+}
+
+/// The inotify mask of `event` as seen by a watch on `watched`
+pub fn event_mask(event: &WatcherEvent, watched: &Path) -> Option<EventMask> {
+    if event.path == watched {
+        self_mask(&event.kind, event.is_dir)
+    } else {
+        notify_to_inotify_mask(&event.kind, event.is_dir)
+    }
+}
+
+/// Whether `event` under `root` is one of the kinds selected by `mask`
+fn selected(event: &WatcherEvent, root: &Path, mask: EventMask) -> bool {
+    event_mask(event, root).is_some_and(|m| m.intersects(mask))
+}
+
+/// Whether `event` under `root` passes the root's name filter, if any
+fn named(event: &WatcherEvent, root: &Path, names: Option<&EventFilter>) -> bool {
+    names.is_none_or(|names| {
+        let name = event.path.strip_prefix(root).unwrap_or(&event.path);
+        names.matches(name, event.is_dir)
+    })
+}
+
+/// A watched root whose events one scan delivers
+#[derive(Debug, Clone)]
+struct Coverage {
+    path: PathBuf,
+    recursive: bool,
+    mask: EventMask,
+    names: Option<EventFilter>,
+}
+
+impl Coverage {
+    /// Whether `event` falls under this root and passes its filters
+    fn passes(&self, event: &WatcherEvent) -> bool {
+        let within = if self.recursive {
+            event.path.starts_with(&self.path)
+        } else {
+            event.path == self.path || event.path.parent() == Some(&self.path)
+        };
+        within
+            && selected(event, &self.path, self.mask)
+            && named(event, &self.path, self.names.as_ref())
+    }
+}
+
 /// Message sent from watcher to event dispatcher
-#[derive(Debug)]
+///
+/// A kind of [`EventKind::Other`] from [`WatcherEvent::overflow`] reports
+/// that events under the watch at `path` were dropped.
+#[derive(Debug, Clone)]
 pub struct WatcherEvent {
     pub path: PathBuf,
     pub kind: EventKind,
     pub is_dir: bool,
+    /// The entry was already unlinked but still open (IN_EXCL_UNLINK
+    /// suppresses these)
+    pub unlinked: bool,
+    /// Inode of the entry, or another ID the two halves of a rename share;
+    /// 0 if unknown
+    pub ino: u64,
+}
+
+impl WatcherEvent {
+    /// Marker for events dropped under the watch on `root`
+    fn overflow(root: &Path) -> Self {
+        Self {
+            path: root.to_path_buf(),
+            kind: EventKind::Other,
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+}
+
+/// Sending half of the bounded queue from one watch to the dispatcher
+///
+/// When the queue is nearly full a batch is coalesced first. Whatever still
+/// does not fit is dropped and counted, and the watch's clients receive
+/// IN_Q_OVERFLOW as soon as there is room again.
+#[derive(Clone)]
+struct EventSink {
+    tx: mpsc::Sender<WatcherEvent>,
+    root: PathBuf,
+    /// Events were dropped and the overflow marker is not queued yet
+    overflowed: Arc<AtomicBool>,
+    /// Events dropped across all watches
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSink {
+    /// Queue `events`; returns false once the dispatcher has stopped
+    fn send(&self, events: Vec<WatcherEvent>) -> bool {
+        if events.is_empty() {
+            return !self.tx.is_closed();
+        }
+        if self.overflowed.load(Ordering::Relaxed) {
+            match self.tx.try_send(WatcherEvent::overflow(&self.root)) {
+                Ok(()) => self.overflowed.store(false, Ordering::Relaxed),
+                Err(TrySendError::Full(_)) => {
+                    self.drop_events(events.len());
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+
+        let events = if self.tx.capacity() < self.tx.max_capacity() / 4 {
+            coalesce(events)
+        } else {
+            events
+        };
+        let mut events = events.into_iter();
+        while let Some(event) = events.next() {
+            match self.tx.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.drop_events(1 + events.len());
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+        true
+    }
+
+    fn drop_events(&self, count: usize) {
+        self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+        if !self.overflowed.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                path = %self.root.display(),
+                dropped = count,
+                "Dispatcher is falling behind, dropping events"
+            );
+        }
+    }
+}
+
+/// Keep only the last modify/attrib/access event per path and kind
+///
+/// Creates, deletes and renames are never merged, so the structure clients
+/// rebuild from the stream stays right.
+fn coalesce(events: Vec<WatcherEvent>) -> Vec<WatcherEvent> {
+    let mut seen = HashSet::new();
+    let mut kept: Vec<_> = events
+        .into_iter()
+        .rev()
+        .filter(|event| {
+            let mergeable = matches!(
+                event.kind,
+                EventKind::Access(_)
+                    | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_))
+            );
+            !mergeable || seen.insert((event.path.clone(), event.kind))
+        })
+        .collect();
+    kept.reverse();
+    kept
 }
 
 /// Manages NFS watchers
 pub struct WatcherManager {
-    /// The poll watcher instance
-    watcher: PollWatcher,
+    /// Filesystem access used by all scanners
+    source: Arc<dyn ScanSource>,
+    /// Sender handed to each scan task
+    event_tx: mpsc::Sender<WatcherEvent>,
     /// Channel for receiving events
-    event_rx: mpsc::UnboundedReceiver<WatcherEvent>,
+    event_rx: mpsc::Receiver<WatcherEvent>,
+    /// Events dropped because the queue was full
+    dropped: Arc<AtomicU64>,
     /// Currently watched paths and their intervals
     watched_paths: HashMap<PathBuf, WatchConfig>,
+    /// Running scan or native task for each watched path that has its own
+    tasks: HashMap<PathBuf, JoinHandle<()>>,
+    /// Each running scan, by the root it walks
+    scans: HashMap<PathBuf, Arc<SharedScan>>,
+    /// Scans of nested roots skipped because an enclosing root's scan
+    /// covered them
+    scans_saved: Arc<AtomicU64>,
+    /// Watched paths whose names compare without regard to case
+    folded_roots: HashSet<PathBuf>,
+    /// Mount health checked before each scan
+    health: Arc<MountMonitor>,
+    /// Where scans checkpoint their snapshots, if anywhere
+    checkpoints: Option<Arc<Checkpoints>>,
+    /// Roots scanned since startup; only their first scan resumes from a
+    /// checkpoint, later ones would replay what was already delivered
+    started: HashSet<PathBuf>,
+    /// Latest snapshots of scans just stopped, for the scans replacing
+    /// them to carry on from
+    handoff: HashMap<PathBuf, (ScanKey, Snapshot)>,
+    /// Keeps what resumed roots find on their first scan
+    catch_up: Option<Arc<CatchUp>>,
+    /// Report atime changes found by scans as IN_ACCESS
+    track_access: bool,
 }
 
 impl WatcherManager {
-    /// Create a new watcher manager
-    pub fn new(
-        poll_interval_secs: u64,
-    ) -> notify::Result<(Self, mpsc::UnboundedSender<WatcherEvent>)> {
-        let (event_tx, event_rx) = mpsc::unbounded_channel();
-        let event_tx_clone = event_tx.clone();
-
-        let config = Config::default()
-            .with_poll_interval(Duration::from_secs(poll_interval_secs))
-            .with_compare_contents(false); // Use mtime, not content hashing
-
-        let watcher = PollWatcher::new(
-            move |res: Result<notify::Event, notify::Error>| match res {
-                Ok(event) => {
-                    for path in event.paths {
-                        let is_dir = path.is_dir();
-                        let _ = event_tx_clone.send(WatcherEvent {
-                            path,
-                            kind: event.kind,
-                            is_dir,
-                        });
-                    }
-                }
-                Err(e) => {
-                    tracing::error!(error = %e, "Watch error");
-                }
-            },
-            config,
-        )?;
+    /// Create a new watcher manager reading through the given source,
+    /// queueing up to `capacity` events for the dispatcher
+    pub fn new(source: Arc<dyn ScanSource>, capacity: usize) -> (Self, mpsc::Sender<WatcherEvent>) {
+        let (event_tx, event_rx) = mpsc::channel(capacity.max(1));
 
-        Ok((
+        (
             Self {
-                watcher,
+                source,
+                event_tx: event_tx.clone(),
                 event_rx,
+                dropped: Arc::new(AtomicU64::new(0)),
                 watched_paths: HashMap::new(),
+                tasks: HashMap::new(),
+                scans: HashMap::new(),
+                scans_saved: Arc::new(AtomicU64::new(0)),
+                folded_roots: HashSet::new(),
+                health: Arc::new(MountMonitor::default()),
+                checkpoints: None,
+                started: HashSet::new(),
+                handoff: HashMap::new(),
+                catch_up: None,
+                track_access: false,
             },
             event_tx,
-        ))
+        )
+    }
+
+    /// Count dropped events in `dropped`
+    pub fn with_drop_counter(mut self, dropped: Arc<AtomicU64>) -> Self {
+        self.dropped = dropped;
+        self
+    }
+
+    /// Check mounts with `health` before scanning them
+    pub fn with_health(mut self, health: Arc<MountMonitor>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Start polled roots from their checkpoints and keep those up to date
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.checkpoints = Some(Arc::new(checkpoints));
+        self
+    }
+
+    /// Keep the first events of roots resumed from checkpoints in
+    /// `catch_up` for clients that re-add their watches
+    pub fn with_catch_up(mut self, catch_up: Arc<CatchUp>) -> Self {
+        self.catch_up = Some(catch_up);
+        self
+    }
+
+    /// Report files whose atime moves between scans as accessed
+    pub fn with_access_tracking(mut self, track: bool) -> Self {
+        self.track_access = track;
+        self
     }
 
     /// Add a path to watch
-    pub fn add_watch(&mut self, config: WatchConfig) -> notify::Result<()> {
-        let recursive_mode = if config.recursive {
-            RecursiveMode::Recursive
-        } else {
-            RecursiveMode::NonRecursive
-        };
+    ///
+    /// A polled root inside a recursive polled root with the same scan
+    /// settings and an equal or shorter interval is not scanned on its own;
+    /// the enclosing root's scan delivers its events.
+    pub fn add_watch(&mut self, config: WatchConfig) -> std::io::Result<()> {
+        // Fail early if the root is not reachable at all
+        self.source.metadata(&config.path)?;
+        let names = EventFilter::compile(&config.name_filter())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        self.stop_task(&config.path);
 
-        self.watcher.watch(&config.path, recursive_mode)?;
+        let fold_case = config.folds_case();
+        if fold_case {
+            self.folded_roots.insert(config.path.clone());
+        } else {
+            self.folded_roots.remove(&config.path);
+        }
+        if config.backend == Backend::Native {
+            let task = spawn_native_task(&config, names, self.sink(&config.path))
+                .map_err(std::io::Error::other)?;
+            self.tasks.insert(config.path.clone(), task);
+        }
         tracing::info!(
             path = %config.path.display(),
-            poll_interval = config.poll_interval,
+            backend = ?config.backend,
+            poll_interval = ?config.poll_interval,
             recursive = config.recursive,
+            fold_case,
             "Added watch"
         );
 
         self.watched_paths.insert(config.path.clone(), config);
+        self.replan();
         Ok(())
     }
 
+    /// Start, stop or re-share scans so every polled root is scanned once
+    fn replan(&mut self) {
+        let mut polled: Vec<&WatchConfig> = self
+            .watched_paths
+            .values()
+            .filter(|config| config.backend == Backend::Poll)
+            .collect();
+        // Enclosing roots sort before the roots inside them
+        polled.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut plan: Vec<(PathBuf, Vec<Coverage>)> = Vec::new();
+        for config in &polled {
+            let Ok(names) = EventFilter::compile(&config.name_filter()) else {
+                continue;
+            };
+            let coverage = Coverage {
+                path: config.path.clone(),
+                recursive: config.recursive,
+                mask: config.events,
+                names,
+            };
+            // The shallowest enclosing root; it cannot itself be enclosed,
+            // or its encloser would enclose this root too
+            let owner = polled
+                .iter()
+                .find(|outer| self.shares_scan(outer, config))
+                .map(|outer| &outer.path);
+            match owner.and_then(|owner| plan.iter_mut().find(|(path, _)| path == owner)) {
+                Some((_, covered)) => covered.push(coverage),
+                None => plan.push((config.path.clone(), vec![coverage])),
+            }
+        }
+
+        let polled: Vec<PathBuf> = polled.iter().map(|config| config.path.clone()).collect();
+        let orphaned: Vec<PathBuf> = self
+            .scans
+            .keys()
+            .filter(|path| !plan.iter().any(|(owner, _)| owner == *path))
+            .cloned()
+            .collect();
+        for path in orphaned {
+            self.hand_off(&path);
+            if polled.contains(&path)
+                && let Some(task) = self.tasks.remove(&path)
+            {
+                task.abort();
+            }
+        }
+
+        for (owner, covered) in plan {
+            if let Some(scan) = self.scans.get(&owner)
+                && self.tasks.contains_key(&owner)
+            {
+                *scan.coverage.write() = covered;
+                continue;
+            }
+            let config = self.watched_paths[&owner].clone();
+            let key = ScanKey {
+                root: owner.clone(),
+                recursive: config.recursive,
+                detection: config.change_detection,
+                fold_case: self.folded_roots.contains(&owner),
+            };
+            let first = self.started.insert(owner.clone());
+            let baseline = match self.handoff.remove(&owner) {
+                Some((handed, snapshot)) if handed == key => Baseline::Live(snapshot),
+                _ if first => Baseline::Checkpoint,
+                _ => Baseline::Walk,
+            };
+            let scan = Arc::new(SharedScan::new(key, covered));
+            let context = ScanContext {
+                source: Arc::clone(&self.source),
+                health: Arc::clone(&self.health),
+                scans_saved: Arc::clone(&self.scans_saved),
+                checkpoints: self.checkpoints.clone(),
+                catch_up: self.catch_up.clone(),
+                track_access: self.track_access,
+            };
+            let task = spawn_scan_task(
+                context,
+                config,
+                baseline,
+                Arc::clone(&scan),
+                self.sink(&owner),
+            );
+            self.tasks.insert(owner.clone(), task);
+            self.scans.insert(owner, scan);
+        }
+        // A snapshot not taken up right away goes stale
+        self.handoff.clear();
+    }
+
+    /// Whether a scan of `outer` can deliver the events of `inner`
+    fn shares_scan(&self, outer: &WatchConfig, inner: &WatchConfig) -> bool {
+        outer.path != inner.path
+            && outer.recursive
+            && inner.path.starts_with(&outer.path)
+            && inner.poll_interval >= outer.poll_interval
+            && inner.change_detection == outer.change_detection
+            && inner.revalidate == outer.revalidate
+            && inner.confirm_deletes == outer.confirm_deletes
+            && inner.restat_deletes == outer.restat_deletes
+            && inner.settle_scans == outer.settle_scans
+            && inner.partial_suffixes == outer.partial_suffixes
+            && self.folded_roots.contains(&inner.path) == self.folded_roots.contains(&outer.path)
+    }
+
+    fn sink(&self, root: &Path) -> EventSink {
+        EventSink {
+            tx: self.event_tx.clone(),
+            root: root.to_path_buf(),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            dropped: Arc::clone(&self.dropped),
+        }
+    }
+
+    fn stop_task(&mut self, path: &Path) {
+        if let Some(task) = self.tasks.remove(path) {
+            task.abort();
+        }
+        self.hand_off(path);
+    }
+
+    /// Forget the scan of `path`, keeping its snapshot for a replacement
+    ///
+    /// A scan stopped in the middle of a walk loses it.
+    fn hand_off(&mut self, path: &Path) {
+        if let Some(scan) = self.scans.remove(path)
+            && let Some(scanner) = scan.scanner.lock().take()
+        {
+            self.handoff.insert(
+                path.to_path_buf(),
+                (scan.key.clone(), scanner.into_snapshot()),
+            );
+        }
+    }
+
+    /// Roots scanned as part of an enclosing root, and how many scans of
+    /// them that saved since startup
+    pub fn scan_sharing(&self) -> (Vec<PathBuf>, u64) {
+        // The first root a scan covers is its own
+        let shared = self
+            .scans
+            .values()
+            .flat_map(|scan| {
+                let coverage = scan.coverage.read();
+                coverage
+                    .iter()
+                    .skip(1)
+                    .map(|c| c.path.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        (shared, self.scans_saved.load(Ordering::Relaxed))
+    }
+
+    /// The root whose events reach a watch on `path`, and its scan
+    pub fn scan_info(&self, path: &Path) -> Option<ScanInfo> {
+        let root = self
+            .watched_paths
+            .values()
+            .filter(|root| {
+                path.parent() == Some(&root.path)
+                    || if root.recursive {
+                        path.starts_with(&root.path)
+                    } else {
+                        path == root.path
+                    }
+            })
+            .max_by_key(|root| root.path.components().count())?;
+        let scan = self
+            .scans
+            .values()
+            .find(|scan| scan.coverage.read().iter().any(|c| c.path == root.path));
+        Some(ScanInfo {
+            root: root.path.clone(),
+            entries: scan.map_or(0, |scan| scan.entries.load(Ordering::Relaxed)),
+            scanned_at: scan.and_then(|scan| *scan.scanned_at.lock()),
+        })
+    }
+
+    /// Every root whose events reach a watch on `path`
+    fn covering<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a WatchConfig> {
+        self.watched_paths.values().filter(move |root| {
+            path.parent() == Some(&root.path)
+                || if root.recursive {
+                    path.starts_with(&root.path)
+                } else {
+                    path == root.path
+                }
+        })
+    }
+
+    /// Tags of every root whose events reach `path`, sorted
+    pub fn tags_for(&self, path: &Path) -> Vec<String> {
+        let tags: BTreeSet<&String> = self.covering(path).flat_map(|root| &root.tags).collect();
+        tags.into_iter().cloned().collect()
+    }
+
+    /// Events the roots whose events reach `path` deny to clients
+    pub fn denied_for(&self, path: &Path) -> EventMask {
+        self.covering(path)
+            .fold(EventMask::empty(), |denied, root| {
+                denied | root.denied_events
+            })
+    }
+
+    /// Whether no root using the OS notification API reaches `path`
+    pub fn is_polled(&self, path: &Path) -> bool {
+        !self
+            .covering(path)
+            .any(|root| root.backend == Backend::Native)
+    }
+
+    /// Whether `path` is already a watched root
+    pub fn is_watched(&self, path: &Path) -> bool {
+        self.watched_paths.contains_key(path)
+    }
+
+    /// `path` spelled as on disk, if it lies under a root whose names
+    /// compare without regard to case
+    pub fn on_disk_case(&self, path: &Path) -> Option<PathBuf> {
+        let root = self
+            .folded_roots
+            .iter()
+            .find(|root| fold::starts_with(path, root))?;
+        Some(fold::on_disk_case(root, path))
+    }
+
+    /// Every watched root, ordered by path
+    pub fn roots(&self) -> Vec<WatchConfig> {
+        let mut roots: Vec<_> = self.watched_paths.values().cloned().collect();
+        roots.sort_by(|a, b| a.path.cmp(&b.path));
+        roots
+    }
+
+    /// Events waiting for dispatch, and how many the queue holds
+    pub fn event_queue(&self) -> (usize, usize) {
+        let capacity = self.event_tx.max_capacity();
+        (capacity - self.event_tx.capacity(), capacity)
+    }
+
     /// Remove a watched path
-    #[allow(dead_code)]
-    pub fn remove_watch(&mut self, path: &PathBuf) -> notify::Result<()> {
-        self.watcher.unwatch(path)?;
+    pub fn remove_watch(&mut self, path: &PathBuf) {
+        self.stop_task(path);
         self.watched_paths.remove(path);
+        self.folded_roots.remove(path);
+        if let Some(checkpoints) = &self.checkpoints {
+            checkpoints.remove(path);
+        }
+        self.replan();
         tracing::info!(path = %path.display(), "Removed watch");
-        Ok(())
+    }
+
+    /// Stop every scan, checkpointing the snapshots they reached
+    pub fn shutdown(&mut self) {
+        for (_, task) in self.tasks.drain() {
+            task.abort();
+        }
+        for scan in std::mem::take(&mut self.scans).into_values() {
+            let (Some(checkpoints), Some(scanner)) =
+                (&self.checkpoints, scan.scanner.lock().take())
+            else {
+                continue;
+            };
+            if let Err(e) = checkpoints.save(&scan.key, scanner.snapshot()) {
+                tracing::warn!(path = %scan.key.root.display(), error = %e, "Failed to checkpoint snapshot");
+            }
+        }
     }
 
     /// Get the event receiver
-    pub fn take_event_rx(&mut self) -> mpsc::UnboundedReceiver<WatcherEvent> {
-        let (_, rx) = mpsc::unbounded_channel();
+    pub fn take_event_rx(&mut self) -> mpsc::Receiver<WatcherEvent> {
+        let (_, rx) = mpsc::channel(1);
         std::mem::replace(&mut self.event_rx, rx)
     }
 }
 
+impl Drop for WatcherManager {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A running scan as the manager sees it
+struct SharedScan {
+    /// What the scan's snapshots are taken with
+    key: ScanKey,
+    /// The scan's scanner, between walks
+    scanner: parking_lot::Mutex<Option<Scanner>>,
+    /// Roots the scan delivers events for, the scanned root first
+    coverage: RwLock<Vec<Coverage>>,
+    /// Entries in the latest snapshot
+    entries: AtomicU64,
+    /// When the latest scan finished
+    scanned_at: parking_lot::Mutex<Option<Instant>>,
+}
+
+impl SharedScan {
+    fn new(key: ScanKey, coverage: Vec<Coverage>) -> Self {
+        Self {
+            key,
+            scanner: parking_lot::Mutex::new(None),
+            coverage: RwLock::new(coverage),
+            entries: AtomicU64::new(0),
+            scanned_at: parking_lot::Mutex::new(None),
+        }
+    }
+
+    fn scanned(&self, scanner: &Scanner) {
+        self.entries
+            .store(scanner.snapshot().len() as u64, Ordering::Relaxed);
+        *self.scanned_at.lock() = Some(Instant::now());
+    }
+}
+
+/// The scan behind a watch, from [`WatcherManager::scan_info`]
+#[derive(Debug, Clone)]
+pub struct ScanInfo {
+    /// The watched root whose events reach the watch
+    pub root: PathBuf,
+    /// Entries in the latest snapshot of the scan covering that root; 0 for
+    /// native roots
+    pub entries: u64,
+    /// When that scan last finished, if it has
+    pub scanned_at: Option<Instant>,
+}
+
+/// What a new scan diffs its first walk against
+enum Baseline {
+    /// The root's checkpoint, if one was taken with the same settings
+    Checkpoint,
+    /// The snapshot of the scan it replaces
+    Live(Snapshot),
+    /// A walk taken now, which reports nothing
+    Walk,
+}
+
+/// What every scan task shares with the manager
+struct ScanContext {
+    source: Arc<dyn ScanSource>,
+    health: Arc<MountMonitor>,
+    /// Scans skipped by sharing, counted up by each task
+    scans_saved: Arc<AtomicU64>,
+    checkpoints: Option<Arc<Checkpoints>>,
+    catch_up: Option<Arc<CatchUp>>,
+    track_access: bool,
+}
+
+/// Spawn the polling loop for a single watched root
+///
+/// Walking the tree is blocking IO, so each scan runs on the blocking pool.
+/// Scans are skipped while the root's mount fails its health probe. Events
+/// go out if they pass the filters of any root `scan` covers.
+fn spawn_scan_task(
+    context: ScanContext,
+    config: WatchConfig,
+    baseline: Baseline,
+    scan: Arc<SharedScan>,
+    sink: EventSink,
+) -> JoinHandle<()> {
+    let ScanContext {
+        source,
+        health,
+        scans_saved,
+        checkpoints,
+        mut catch_up,
+        track_access,
+    } = context;
+    tokio::spawn(async move {
+        let key = scan.key.clone();
+        let (confirm_deletes, restat_deletes) = (config.confirm_deletes, config.restat_deletes);
+        let (settle_scans, partial_suffixes) =
+            (config.settle_scans, config.partial_suffixes.clone());
+        let mut scan_source = Arc::clone(&source);
+        if config.revalidate {
+            scan_source = Arc::new(RevalidateSource::new(scan_source));
+        }
+        if key.detection == ChangeDetection::Hash {
+            scan_source = Arc::new(ContentSource::new(scan_source));
+        }
+        let live = matches!(baseline, Baseline::Live(_));
+        let initial = {
+            let (key, checkpoints) = (key.clone(), checkpoints.clone());
+            tokio::task::spawn_blocking(move || {
+                let baseline = match baseline {
+                    Baseline::Checkpoint => {
+                        checkpoints.and_then(|checkpoints| checkpoints.load(&key))
+                    }
+                    Baseline::Live(snapshot) => Some(snapshot),
+                    Baseline::Walk => None,
+                };
+                let resumed = baseline.is_some();
+                let scanner = match baseline {
+                    Some(baseline) => {
+                        Scanner::resume(scan_source, key.root, key.recursive, baseline)
+                    }
+                    None => Scanner::new(scan_source, key.root, key.recursive),
+                };
+                let scanner = scanner
+                    .with_change_detection(key.detection)
+                    .with_case_folding(key.fold_case)
+                    .with_delete_confirmation(confirm_deletes, restat_deletes)
+                    .with_settling(settle_scans, partial_suffixes)
+                    .with_access_tracking(track_access);
+                (scanner, resumed)
+            })
+        };
+        let (scanner, resumed) = match initial.await {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::error!(path = %config.path.display(), error = %e, "Initial scan failed");
+                return;
+            }
+        };
+        if live {
+            // Its changes were delivered as they happened
+            catch_up = None;
+        } else if resumed {
+            tracing::info!(path = %config.path.display(), "Resuming from checkpoint");
+        } else {
+            catch_up = None;
+            scan.scanned(&scanner);
+        }
+        *scan.scanner.lock() = Some(scanner);
+
+        let mut ticker = tokio::time::interval(config.poll_interval.max(MIN_POLL_INTERVAL));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately; skip it if the baseline was
+        // just taken, but diff against a checkpoint right away
+        if !resumed {
+            ticker.tick().await;
+        }
+
+        let mut probe = None;
+        let mut checkpointed: Option<Instant> = None;
+        loop {
+            ticker.tick().await;
+
+            if let Some(backoff) = health.check(&source, &config.path, &mut probe).await {
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+
+            let checkpoint = checkpoints.clone().filter(|checkpoints| {
+                checkpointed.is_none_or(|at| at.elapsed() >= checkpoints.interval())
+            });
+            if checkpoint.is_some() {
+                checkpointed = Some(Instant::now());
+            }
+            // Gone if the manager shut down
+            let Some(mut scanner) = scan.scanner.lock().take() else {
+                return;
+            };
+            let key = key.clone();
+            let poll = tokio::task::spawn_blocking(move || {
+                let events = scanner.poll();
+                if let Some(checkpoints) = checkpoint
+                    && let Err(e) = checkpoints.save(&key, scanner.snapshot())
+                {
+                    tracing::warn!(path = %key.root.display(), error = %e, "Failed to checkpoint snapshot");
+                }
+                (scanner, events)
+            });
+            let (scanner, events) = match poll.await {
+                Ok(result) => result,
+                Err(e) => {
+                    tracing::error!(path = %config.path.display(), error = %e, "Scan failed");
+                    return;
+                }
+            };
+            scan.scanned(&scanner);
+            *scan.scanner.lock() = Some(scanner);
+
+            let events = {
+                let coverage = scan.coverage.read();
+                scans_saved.fetch_add(coverage.len().saturating_sub(1) as u64, Ordering::Relaxed);
+                events
+                    .into_iter()
+                    .filter(|e| coverage.iter().any(|c| c.passes(e)))
+                    .collect::<Vec<_>>()
+            };
+            // Only the first scan after resuming finds offline changes
+            if let Some(catch_up) = catch_up.take() {
+                catch_up.record(&events);
+            }
+            if !sink.send(events) {
+                return;
+            }
+        }
+    })
+}
+
+/// Start an OS-notification watcher for a single watched root
+///
+/// The returned task does nothing but own the watcher; aborting it drops the
+/// watcher, which cancels the OS watch.
+fn spawn_native_task(
+    config: &WatchConfig,
+    names: Option<EventFilter>,
+    sink: EventSink,
+) -> notify::Result<JoinHandle<()>> {
+    let root = config.path.clone();
+    let mask = config.events;
+    let mut watcher =
+        notify::recommended_watcher(move |result: notify::Result<notify::Event>| match result {
+            Ok(event) => {
+                let events = native_events(event)
+                    .into_iter()
+                    .filter(|e| selected(e, &root, mask) && named(e, &root, names.as_ref()))
+                    .collect();
+                sink.send(events);
+            }
+            Err(e) => {
+                tracing::warn!(path = %root.display(), error = %e, "Native watch error");
+            }
+        })?;
+
+    let mode = if config.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher.watch(&config.path, mode)?;
+
+    Ok(tokio::spawn(async move {
+        let _watcher = watcher;
+        std::future::pending::<()>().await
+    }))
+}
+
+/// Split a `notify` event into one [`WatcherEvent`] per path
+///
+/// Backends such as ReadDirectoryChangesW report `Any` kinds, so whether the
+/// entry is a directory is looked up when the kind does not say.
+fn native_events(event: notify::Event) -> Vec<WatcherEvent> {
+    if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind
+        && let [from, to] = &event.paths[..]
+    {
+        let is_dir = to.is_dir();
+        let ino = native_rename_id(event.attrs.tracker());
+        return vec![
+            WatcherEvent {
+                path: from.clone(),
+                kind: EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                is_dir,
+                unlinked: false,
+                ino,
+            },
+            WatcherEvent {
+                path: to.clone(),
+                kind: EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                is_dir,
+                unlinked: false,
+                ino,
+            },
+        ];
+    }
+
+    let folder = matches!(
+        event.kind,
+        EventKind::Create(CreateKind::Folder) | EventKind::Remove(RemoveKind::Folder)
+    );
+    // Separate From and To events are tied together by the tracker
+    let ino = match event.kind {
+        EventKind::Modify(ModifyKind::Name(_)) => event
+            .attrs
+            .tracker()
+            .map_or(0, |tracker| native_rename_id(Some(tracker))),
+        _ => 0,
+    };
+    event
+        .paths
+        .into_iter()
+        .map(|path| WatcherEvent {
+            is_dir: folder || path.is_dir(),
+            path,
+            kind: event.kind,
+            unlinked: false,
+            ino,
+        })
+        .collect()
+}
+
+/// Rename ID for a native rename: the backend's tracker, or a fresh ID for
+/// a rename reported as one event
+///
+/// The top bit keeps these apart from inode numbers.
+fn native_rename_id(tracker: Option<usize>) -> u64 {
+    let id = tracker.map_or_else(|| u64::from(next_cookie()), |tracker| tracker as u64);
+    id | 1 << 63
+}
+
+/// How long a MOVED_FROM waits for its MOVED_TO
+///
+/// Both halves of a scanned rename arrive in the same batch; the window only
+/// has to cover native backends and a busy dispatch queue.
+const RENAME_WINDOW: Duration = Duration::from_secs(5);
+
+/// Most MOVED_FROM events waiting for their MOVED_TO
+const MAX_PENDING_RENAMES: usize = 4096;
+
+/// A MOVED_FROM whose MOVED_TO has not been dispatched yet
+struct PendingRename {
+    cookie: u32,
+    since: Instant,
+}
+
 /// Event dispatcher - receives events from watcher and sends to clients
 pub struct EventDispatcher {
     state: Arc<DaemonState>,
-    event_rx: mpsc::UnboundedReceiver<WatcherEvent>,
-    /// Track rename cookies for pairing MOVED_FROM/MOVED_TO
-    pending_renames: HashMap<PathBuf, u32>,
+    event_rx: mpsc::Receiver<WatcherEvent>,
+    /// Cookies of recent MOVED_FROM events by inode, for their MOVED_TO
+    pending_renames: HashMap<u64, PendingRename>,
+    /// Startup quiet period and rate limit
+    throttle: Throttle,
 }
 
 impl EventDispatcher {
-    pub fn new(state: Arc<DaemonState>, event_rx: mpsc::UnboundedReceiver<WatcherEvent>) -> Self {
+    pub fn new(state: Arc<DaemonState>, event_rx: mpsc::Receiver<WatcherEvent>) -> Self {
         Self {
             state,
             event_rx,
             pending_renames: HashMap::new(),
+            throttle: Throttle::unlimited(),
         }
     }
 
+    /// Pace dispatch with `throttle`
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
     /// Run the event dispatcher loop
     pub async fn run(mut self) {
         tracing::info!("Event dispatcher started");
 
         while let Some(event) = self.event_rx.recv().await {
-            if let Err(e) = self.handle_event(event).await {
-                tracing::error!(error = %e, "Failed to dispatch event");
+            self.throttle.admit().await;
+            let state = Arc::clone(&self.state);
+            match state.plugins() {
+                Some(plugins) => {
+                    let tags = state.root_tags(&event.path);
+                    for event in plugins.apply(event, &tags) {
+                        self.dispatch(event).await;
+                    }
+                }
+                None => self.dispatch(event).await,
             }
         }
 
         tracing::info!("Event dispatcher stopped");
     }
 
+    async fn dispatch(&mut self, event: WatcherEvent) {
+        if let Err(e) = self.handle_event(event).await {
+            tracing::error!(error = %e, "Failed to dispatch event");
+        }
+    }
+
+    /// The cookie for an event with `mask` on inode `ino`
+    ///
+    /// MOVED_TO takes the cookie of the MOVED_FROM on the same inode within
+    /// [`RENAME_WINDOW`], whichever directories they are in. MOVED_FROM is
+    /// never held back: like the kernel's, one whose other half never comes
+    /// simply stays unmatched, and its entry expires.
+    fn rename_cookie(&mut self, mask: EventMask, ino: u64, now: Instant) -> u32 {
+        let moved_from = mask.intersects(EventMask::IN_MOVED_FROM);
+        if !moved_from && !mask.intersects(EventMask::IN_MOVED_TO) {
+            return 0;
+        }
+        self.pending_renames
+            .retain(|_, pending| now.duration_since(pending.since) < RENAME_WINDOW);
+        if ino == 0 {
+            return next_cookie();
+        }
+        if !moved_from {
+            return self
+                .pending_renames
+                .remove(&ino)
+                .map_or_else(next_cookie, |pending| pending.cookie);
+        }
+
+        if self.pending_renames.len() >= MAX_PENDING_RENAMES
+            && let Some(oldest) = self
+                .pending_renames
+                .iter()
+                .min_by_key(|(_, pending)| pending.since)
+                .map(|(ino, _)| *ino)
+        {
+            self.pending_renames.remove(&oldest);
+        }
+        let cookie = next_cookie();
+        self.pending_renames
+            .insert(ino, PendingRename { cookie, since: now });
+        cookie
+    }
+
     async fn handle_event(&mut self, event: WatcherEvent) -> color_eyre::Result<()> {
-        // Find the watch for this path
-        let watch = match self.state.find_watch_for_path(&event.path) {
-            Some(w) => w,
-            None => {
-                tracing::trace!(path = %event.path.display(), "No watch found for path");
-                return Ok(());
+        // Media libraries, trigger files, Watchman roots and event logs see
+        // changes whether or not a client watches them
+        if let Some(media) = self.state.media() {
+            media.observe(&event);
+        }
+        if let Some(triggers) = self.state.triggers() {
+            triggers.observe(&event);
+        }
+        #[cfg(unix)]
+        if let Some(watchman) = self.state.watchman() {
+            watchman.observe(&event);
+        }
+        #[cfg(unix)]
+        if let Some(log) = self.state.event_log() {
+            log.observe(&event, || self.state.root_tags(&event.path));
+        }
+        // Journals too, so consumers that went away find them on resuming
+        let numbered = self
+            .state
+            .journal()
+            .map(|journal| journal.record(&event))
+            .unwrap_or_default();
+
+        let index = self.state.dispatch_index();
+        // Dropped events could have been for any watch under the root
+        if event.kind == EventKind::Other {
+            for entry in index.within(&event.path) {
+                let overflow = InotifyEvent::new(entry.info.wd, EventMask::IN_Q_OVERFLOW.bits(), 0);
+                let framed = frame_event(&overflow, None);
+                for client in &entry.clients {
+                    client.queue_event(framed.clone());
+                }
             }
-        };
+            return Ok(());
+        }
+
+        // Every watch covering the path gets the event, named and masked
+        // as that watch sees it; the halves of a rename share one cookie
+        let mut cookie = None;
+        let mut covered = false;
+        for entry in index.covering(&event.path) {
+            covered = true;
+            self.dispatch_to(entry, &event, &numbered, &mut cookie);
+        }
+        if !covered {
+            tracing::trace!(path = %event.path.display(), "No watch found for path");
+        }
+
+        Ok(())
+    }
+
+    /// Queue `event` for the clients of one watch covering it
+    fn dispatch_to(
+        &mut self,
+        entry: &DispatchWatch,
+        event: &WatcherEvent,
+        numbered: &[(PathBuf, u64)],
+        cookie: &mut Option<u32>,
+    ) {
+        let watch = &entry.info;
+
+        // Events on the watched path itself carry no name; a file watch
+        // only ever sees these
+        let is_self = event.path == watch.path;
 
         // Convert to inotify mask
-        let mask = match notify_to_inotify_mask(&event.kind, event.is_dir) {
-            Some(m) => m,
-            None => return Ok(()),
+        let Some(mask) = event_mask(event, &watch.path) else {
+            return;
         };
 
         // Check if any client cares about this event type
         if !watch.mask.intersects(mask) {
-            return Ok(());
+            return;
+        }
+        if event.unlinked && watch.mask.contains(EventMask::IN_EXCL_UNLINK) {
+            return;
+        }
+        if !self.state.record_event(watch.wd, &event.path) {
+            return;
         }
 
-        // Determine cookie for rename events
-        let cookie = if mask.intersects(EventMask::IN_MOVED_FROM) {
-            let cookie = next_cookie();
-            self.pending_renames.insert(event.path.clone(), cookie);
-            cookie
-        } else if mask.intersects(EventMask::IN_MOVED_TO) {
-            // Try to find a matching MOVED_FROM event
-            // For simplicity, we use a new cookie if no match found
-            self.pending_renames
-                .remove(&event.path)
-                .unwrap_or_else(next_cookie)
+        let cookie = if mask.intersects(EventMask::IN_MOVE) {
+            *cookie.get_or_insert_with(|| self.rename_cookie(mask, event.ino, Instant::now()))
         } else {
             0
         };
@@ -230,53 +1187,125 @@ impl EventDispatcher {
             .path
             .strip_prefix(&watch.path)
             .ok()
-            .and_then(|p| p.to_str())
-            .map(|s| s.to_string());
+            .filter(|_| !is_self)
+            .and_then(|p| p.to_str());
 
-        // Create inotify event
+        // Serialize and frame once; every client queues the same buffer
         let inotify_event = InotifyEvent::new(watch.wd, mask.bits(), cookie);
+        let framed = frame_event(&inotify_event, name.map(str::as_bytes));
 
-        // Serialize the event
-        let event_bytes = if let Some(ref name_str) = name {
-            inotify_event.to_bytes_with_name(name_str.as_bytes())
-        } else {
-            inotify_event.header_to_bytes().to_vec()
-        };
-
-        // Frame the event for sending
-        let framed = FramedMessage::frame(&event_bytes);
-
-        // Send to all subscribed clients
-        let clients = self.state.get_clients_for_watch(watch.wd);
-        for client in clients {
-            if let Err(e) = client.send_event(&framed).await {
-                tracing::warn!(
-                    client_id = client.id,
-                    error = %e,
-                    "Failed to send event to client"
-                );
+        // Queue for all subscribed clients; a slow client only overflows
+        // its own queue. Those that negotiated compression share one
+        // compressed copy, made on first use; batching clients have the
+        // batch compressed instead.
+        let name_path = Path::new(name.unwrap_or_default());
+        let mut compressed = None;
+        // Clients resuming from a cursor get the event numbered as in the
+        // watch's journal
+        let seq = numbered
+            .iter()
+            .find(|(path, _)| *path == watch.path)
+            .map(|&(_, seq)| seq);
+        let (mut sequenced, mut sequenced_compressed) = (None, None);
+        for client in &entry.clients {
+            let wanted = client
+                .filter
+                .read()
+                .as_ref()
+                .is_none_or(|filter| filter.matches(name_path, event.is_dir));
+            if !wanted {
+                continue;
             }
+            let capabilities = client.capabilities();
+            if let Some(seq) = seq
+                && client.journaled.read().contains(&watch.wd)
+            {
+                let frame = sequenced
+                    .get_or_insert_with(|| sequenced_frame(seq, &framed))
+                    .clone();
+                let frame = if capabilities.contains(Capabilities::COMPRESSION) {
+                    sequenced_compressed
+                        .get_or_insert_with(|| self.state.compress_frame(&frame))
+                        .clone()
+                        .unwrap_or(frame)
+                } else {
+                    frame
+                };
+                client.queue_event(frame);
+                continue;
+            }
+            let frame = if capabilities.contains(Capabilities::COMPRESSION)
+                && !capabilities.contains(Capabilities::EVENT_BATCH)
+            {
+                compressed
+                    .get_or_insert_with(|| self.state.compress_frame(&framed))
+                    .clone()
+                    .unwrap_or_else(|| framed.clone())
+            } else {
+                framed.clone()
+            };
+            client.queue_event(frame);
         }
 
         tracing::debug!(
             wd = watch.wd,
             path = %event.path.display(),
-            mask = ?mask,
+            mask = %mask,
             name = ?name,
             "Dispatched event"
         );
-
-        Ok(())
     }
 }
 
+thread_local! {
+    /// Scratch space events are framed in; each event is split off as a
+    /// shared buffer, so the allocation is reused until it runs out
+    static FRAME_SCRATCH: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
+/// Frame `event` with `name` NUL-padded to a 4-byte boundary, as the
+/// kernel lays it out
+pub fn frame_event(event: &InotifyEvent, name: Option<&[u8]>) -> Bytes {
+    let padded_len = name.map_or(0, |name| (name.len() + 1 + 3) & !3);
+    let header = InotifyEvent {
+        len: padded_len as u32,
+        ..*event
+    };
+    let payload_len = InotifyEvent::HEADER_SIZE + padded_len;
+
+    FRAME_SCRATCH.with_borrow_mut(|scratch| {
+        scratch.reserve(4 + payload_len);
+        scratch.put_u32_le(payload_len as u32 | FramedMessage::EVENT_FLAG);
+        scratch.put_slice(&header.header_to_bytes());
+        if let Some(name) = name {
+            scratch.put_slice(name);
+            scratch.put_bytes(0, padded_len - name.len());
+        }
+        scratch.split().freeze()
+    })
+}
+
 /// Start the watcher with initial configuration
 pub async fn start_watcher(
     state: Arc<DaemonState>,
+    source: Arc<dyn ScanSource>,
     initial_watches: Vec<WatchConfig>,
-    default_poll_interval: u64,
+    throttle: Throttle,
+    queue_capacity: usize,
+    checkpoints: Option<Checkpoints>,
+    catch_up: Option<Arc<CatchUp>>,
 ) -> color_eyre::Result<WatcherManager> {
-    let (mut watcher, _event_tx) = WatcherManager::new(default_poll_interval)?;
+    let (watcher, _event_tx) = WatcherManager::new(source, queue_capacity);
+    let mut watcher = watcher
+        .with_health(state.mount_health())
+        .with_drop_counter(state.drop_counter())
+        .with_access_tracking(state.access_events() == AccessEvents::Atime);
+    if let Some(checkpoints) = checkpoints {
+        watcher = watcher.with_checkpoints(checkpoints);
+    }
+    if let Some(catch_up) = catch_up {
+        watcher = watcher.with_catch_up(catch_up);
+    }
 
     // Add initial watches
     for watch_config in initial_watches {
@@ -291,7 +1320,7 @@ pub async fn start_watcher(
 
     // Take the event receiver and start dispatcher
     let event_rx = watcher.take_event_rx();
-    let dispatcher = EventDispatcher::new(state, event_rx);
+    let dispatcher = EventDispatcher::new(state, event_rx).with_throttle(throttle);
 
     // Spawn dispatcher task
     tokio::spawn(dispatcher.run());
@@ -336,10 +1365,368 @@ mod tests {
         assert!(mask.unwrap().contains(EventMask::IN_DELETE));
     }
 
+    #[test]
+    fn test_frame_event_matches_kernel_layout() {
+        let event = InotifyEvent::new(3, EventMask::IN_CREATE.bits(), 9);
+        for name in ["a", "abc", "abcd", "show/ep1.mkv"] {
+            let expected = FramedMessage::frame_event(&event.to_bytes_with_name(name.as_bytes()));
+            assert_eq!(frame_event(&event, Some(name.as_bytes())), expected);
+        }
+        let expected = FramedMessage::frame_event(&event.header_to_bytes());
+        assert_eq!(frame_event(&event, None), expected);
+    }
+
+    fn modify(path: &str) -> WatcherEvent {
+        WatcherEvent {
+            path: PathBuf::from(path),
+            kind: EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Any)),
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+
+    #[test]
+    fn test_coalesce_keeps_structure() {
+        let create = WatcherEvent {
+            kind: EventKind::Create(CreateKind::File),
+            ..modify("/m/a")
+        };
+        let events = coalesce(vec![create, modify("/m/a"), modify("/m/b"), modify("/m/a")]);
+        let paths: Vec<_> = events
+            .iter()
+            .map(|e| (e.path.to_str().unwrap(), e.kind))
+            .collect();
+        assert_eq!(paths.len(), 3);
+        assert!(matches!(paths[0], ("/m/a", EventKind::Create(_))));
+        assert_eq!(paths[1].0, "/m/b");
+        assert_eq!(paths[2].0, "/m/a");
+    }
+
+    #[test]
+    fn test_full_queue_drops_and_reports_overflow() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let sink = EventSink {
+            tx,
+            root: PathBuf::from("/m"),
+            overflowed: Arc::new(AtomicBool::new(false)),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+
+        assert!(sink.send(vec![modify("/m/a"), modify("/m/b"), modify("/m/c")]));
+        assert_eq!(sink.dropped.load(Ordering::Relaxed), 1);
+        // Still full: the next batch is dropped whole
+        assert!(sink.send(vec![modify("/m/d")]));
+        assert_eq!(sink.dropped.load(Ordering::Relaxed), 2);
+
+        // Once drained, the overflow marker goes first
+        assert_eq!(rx.try_recv().unwrap().path, Path::new("/m/a"));
+        assert_eq!(rx.try_recv().unwrap().path, Path::new("/m/b"));
+        assert!(sink.send(vec![modify("/m/e")]));
+        assert_eq!(rx.try_recv().unwrap().kind, EventKind::Other);
+        assert_eq!(rx.try_recv().unwrap().path, Path::new("/m/e"));
+    }
+
+    #[tokio::test]
+    async fn test_rename_cookies_pair_by_inode() {
+        let (_tx, rx) = mpsc::channel(1);
+        let mut dispatcher = EventDispatcher::new(Arc::new(DaemonState::new()), rx);
+        let now = Instant::now();
+        let (from, to) = (EventMask::IN_MOVED_FROM, EventMask::IN_MOVED_TO);
+
+        // Across directories, and with another rename in between
+        let cookie = dispatcher.rename_cookie(from, 42, now);
+        let other = dispatcher.rename_cookie(from, 43, now);
+        assert_ne!(cookie, other);
+        assert_eq!(dispatcher.rename_cookie(to, 42, now), cookie);
+        assert_eq!(dispatcher.pending_renames.len(), 1);
+        assert_eq!(dispatcher.rename_cookie(EventMask::IN_CREATE, 43, now), 0);
+
+        // Unmatched halves expire after the window
+        let later = now + RENAME_WINDOW;
+        assert_ne!(dispatcher.rename_cookie(to, 43, later), other);
+        assert!(dispatcher.pending_renames.is_empty());
+
+        for ino in 1..=MAX_PENDING_RENAMES as u64 + 1 {
+            dispatcher.rename_cookie(from, ino, later);
+        }
+        assert_eq!(dispatcher.pending_renames.len(), MAX_PENDING_RENAMES);
+    }
+
+    #[tokio::test]
+    async fn test_nested_watches_all_get_the_event() {
+        use crate::state::Peer;
+        use fakenotify_protocol::EventBuffer;
+        use tokio::io::AsyncReadExt;
+
+        let state = Arc::new(DaemonState::new());
+        let watch = |path: &str| {
+            let (ours, theirs) = tokio::net::UnixStream::pair().unwrap();
+            let client = state
+                .register_client(Box::new(ours.into_split().1), Peer::from_uid(1000))
+                .ok()
+                .unwrap();
+            let wd = state
+                .add_watch(client.id, PathBuf::from(path), EventMask::IN_CREATE, true)
+                .unwrap();
+            (wd, theirs)
+        };
+        let (outer, mut a) = watch("/w");
+        let (inner, mut b) = watch("/w/sub");
+
+        let (_tx, rx) = mpsc::channel(1);
+        let mut dispatcher = EventDispatcher::new(Arc::clone(&state), rx);
+        dispatcher
+            .handle_event(WatcherEvent {
+                path: PathBuf::from("/w/sub/new.txt"),
+                kind: EventKind::Create(CreateKind::File),
+                is_dir: false,
+                unlinked: false,
+                ino: 0,
+            })
+            .await
+            .unwrap();
+
+        for (stream, wd, name) in [(&mut a, outer, "sub/new.txt"), (&mut b, inner, "new.txt")] {
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut payload = vec![0u8; FramedMessage::read_length(&len).unwrap() as usize];
+            stream.read_exact(&mut payload).await.unwrap();
+            let (header, got) = EventBuffer::new(&payload).next().unwrap();
+            assert_eq!(header.wd, wd);
+            assert_eq!(got.unwrap(), name);
+        }
+    }
+
     #[test]
     fn test_cookie_generation() {
         let c1 = next_cookie();
         let c2 = next_cookie();
         assert_ne!(c1, c2);
     }
+
+    #[test]
+    fn test_selected_by_mask() {
+        let event = WatcherEvent {
+            path: PathBuf::from("/share/a"),
+            kind: EventKind::Modify(ModifyKind::Data(notify::event::DataChange::Content)),
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        };
+        let root = Path::new("/share");
+        assert!(selected(&event, root, EventMask::IN_ALL_EVENTS));
+        assert!(selected(&event, root, EventMask::IN_MODIFY));
+        assert!(!selected(
+            &event,
+            root,
+            EventMask::IN_CREATE | EventMask::IN_DELETE
+        ));
+    }
+
+    #[test]
+    fn test_self_events() {
+        let event = |kind| WatcherEvent {
+            path: PathBuf::from("/etc/app.conf"),
+            kind,
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        };
+        let file = Path::new("/etc/app.conf");
+        let dir = Path::new("/etc");
+
+        let removed = event(EventKind::Remove(RemoveKind::File));
+        assert_eq!(event_mask(&removed, file), Some(EventMask::IN_DELETE_SELF));
+        assert_eq!(event_mask(&removed, dir), Some(EventMask::IN_DELETE));
+
+        let moved = event(EventKind::Modify(ModifyKind::Name(RenameMode::From)));
+        assert_eq!(event_mask(&moved, file), Some(EventMask::IN_MOVE_SELF));
+        assert!(selected(&moved, file, EventMask::IN_MOVE_SELF));
+        assert!(!selected(&moved, file, EventMask::IN_MOVED_FROM));
+
+        let created = event(EventKind::Create(CreateKind::File));
+        assert_eq!(event_mask(&created, file), None);
+        let modified = event(EventKind::Modify(ModifyKind::Data(
+            notify::event::DataChange::Any,
+        )));
+        assert_eq!(event_mask(&modified, file), Some(EventMask::IN_MODIFY));
+    }
+
+    #[test]
+    fn test_native_events_splits_rename() {
+        let event = notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("/share/a"))
+            .add_path(PathBuf::from("/share/b"));
+        let events = native_events(event);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].path, PathBuf::from("/share/a"));
+        assert_eq!(
+            events[0].kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::From))
+        );
+        assert_eq!(events[1].path, PathBuf::from("/share/b"));
+        assert_eq!(
+            events[1].kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_native_backend_reports_create() {
+        let root = std::env::temp_dir().join(format!("fakenotify-native-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let config = WatchConfig {
+            path: root.clone(),
+            poll_interval: Duration::from_secs(1),
+            recursive: false,
+            backend: Backend::Native,
+            events: EventMask::IN_ALL_EVENTS,
+            extensions: Vec::new(),
+            name_regex: None,
+            change_detection: Default::default(),
+            revalidate: false,
+            case_insensitive: None,
+            confirm_deletes: 1,
+            restat_deletes: false,
+            settle_scans: 0,
+            partial_suffixes: Vec::new(),
+            tags: Vec::new(),
+            denied_events: EventMask::empty(),
+        };
+        let (mut manager, _tx) = WatcherManager::new(Arc::new(crate::source::FsSource), 16);
+        let mut rx = manager.take_event_rx();
+        manager.add_watch(config).unwrap();
+
+        std::fs::write(root.join("new.txt"), b"x").unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.path, root.join("new.txt"));
+        assert!(matches!(event.kind, EventKind::Create(_)));
+
+        drop(manager);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nested_roots_share_one_scan() {
+        let sim = Arc::new(crate::source::sim::SimSource::new());
+        sim.mkdir_all("/m/a");
+        let watch = |path: &str, events: EventMask| WatchConfig {
+            path: PathBuf::from(path),
+            poll_interval: Duration::from_secs(1),
+            recursive: true,
+            backend: Backend::Poll,
+            events,
+            extensions: Vec::new(),
+            name_regex: None,
+            change_detection: Default::default(),
+            revalidate: false,
+            case_insensitive: Some(false),
+            confirm_deletes: 1,
+            restat_deletes: false,
+            settle_scans: 0,
+            partial_suffixes: Vec::new(),
+            tags: Vec::new(),
+            denied_events: EventMask::empty(),
+        };
+        let (mut manager, _tx) = WatcherManager::new(sim, 16);
+        manager
+            .add_watch(watch("/m/a", EventMask::IN_ALL_EVENTS))
+            .unwrap();
+        manager
+            .add_watch(watch("/m", EventMask::IN_CREATE))
+            .unwrap();
+        assert_eq!(manager.scan_sharing().0, [PathBuf::from("/m/a")]);
+        assert_eq!(manager.tasks.len(), 1);
+
+        // The outer root's mask does not hide what the inner root asked for
+        let covered = manager.scans[Path::new("/m")].coverage.read().clone();
+        let modify = |path: &str| WatcherEvent {
+            path: PathBuf::from(path),
+            kind: EventKind::Modify(ModifyKind::Any),
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        };
+        assert!(covered.iter().any(|c| c.passes(&modify("/m/a/file"))));
+        assert!(!covered.iter().any(|c| c.passes(&modify("/m/file"))));
+        let info = manager.scan_info(Path::new("/m/a/sub")).unwrap();
+        assert_eq!(info.root, Path::new("/m/a"));
+        assert!(manager.scan_info(Path::new("/elsewhere")).is_none());
+
+        manager.remove_watch(&PathBuf::from("/m"));
+        assert!(manager.scan_sharing().0.is_empty());
+        assert!(manager.tasks.contains_key(Path::new("/m/a")));
+    }
+
+    #[tokio::test]
+    async fn test_only_first_scan_resumes_from_checkpoint() {
+        let sim = Arc::new(crate::source::sim::SimSource::new());
+        sim.mkdir_all("/m");
+        sim.write("/m/old", 1);
+        let dir = std::env::temp_dir().join(format!("fakenotify-resume-{}", std::process::id()));
+        let checkpoints = Checkpoints::new(dir.clone(), Duration::from_secs(3600));
+        let key = ScanKey {
+            root: PathBuf::from("/m"),
+            recursive: true,
+            detection: Default::default(),
+            fold_case: false,
+        };
+        checkpoints.save(&key, &Snapshot::new()).unwrap();
+        let watch = WatchConfig {
+            path: PathBuf::from("/m"),
+            poll_interval: MIN_POLL_INTERVAL,
+            recursive: true,
+            backend: Backend::Poll,
+            events: EventMask::IN_ALL_EVENTS,
+            extensions: Vec::new(),
+            name_regex: None,
+            change_detection: Default::default(),
+            revalidate: false,
+            case_insensitive: Some(false),
+            confirm_deletes: 1,
+            restat_deletes: false,
+            settle_scans: 0,
+            partial_suffixes: Vec::new(),
+            tags: Vec::new(),
+            denied_events: EventMask::empty(),
+        };
+        let (manager, _tx) = WatcherManager::new(sim.clone(), 16);
+        let mut manager = manager.with_checkpoints(checkpoints.clone());
+        let mut rx = manager.take_event_rx();
+        let mut next = async || {
+            tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+                .path
+        };
+
+        // The checkpoint predates the file
+        manager.add_watch(watch.clone()).unwrap();
+        assert_eq!(next().await, Path::new("/m/old"));
+        sim.write("/m/mid", 1);
+        assert_eq!(next().await, Path::new("/m/mid"));
+
+        // Replacing the root carries on from the scan it replaces, rather
+        // than replaying what came after the checkpoint
+        manager.add_watch(watch).unwrap();
+        sim.write("/m/new", 1);
+        assert_eq!(next().await, Path::new("/m/new"));
+
+        // Shutting down checkpoints what the scan has seen since
+        sim.write("/m/last", 1);
+        assert_eq!(next().await, Path::new("/m/last"));
+        manager.shutdown();
+        assert!(
+            checkpoints
+                .load(&key)
+                .unwrap()
+                .contains_key(Path::new("/m/last"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
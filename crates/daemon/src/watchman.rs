@@ -0,0 +1,1555 @@
+//! Watchman-compatible endpoint.
+//!
+//! Build tools such as Jest and Buck ask Watchman what changed instead of
+//! crawling the tree. With `daemon.watchman_socket` set, the daemon answers
+//! the part of Watchman's protocol they rely on at that socket, so pointing
+//! `WATCHMAN_SOCK` at it gives them change tracking on network mounts
+//! without a Watchman install:
+//!
+//! - `version` (with capability checks) and `get-sockname`
+//! - `watch-project`, `watch`, `watch-list` and `watch-del`
+//! - `clock`, `query`, `subscribe` and `unsubscribe`, with `since` clocks
+//!   and named cursors, `relative_root`, the `suffix`, `glob` and `path`
+//!   generators and the common expression terms
+//!
+//! Requests are JSON, one per line, or [`bser`]; each response uses the
+//! encoding of its request. A root must lie under a recursive `[[watch]]`:
+//! it is crawled once with the daemon's scanner, then kept current from the
+//! events the watchers report, so queries are answered from memory. Clocks
+//! are `c:<start>:<pid>:<tick>`; one from another run of the daemon makes
+//! the query a fresh instance.
+
+use crate::scanner::{Scanner, Snapshot};
+use crate::source::{EntryMeta, ScanSource};
+use crate::state::DaemonState;
+use crate::watcher::WatcherEvent;
+use globset::{GlobBuilder, GlobMatcher};
+use notify::EventKind;
+use notify::event::{ModifyKind, RenameMode};
+use parking_lot::Mutex;
+use serde_json::{Map, Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::net::unix::OwnedReadHalf;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+
+pub mod bser;
+
+/// Watchman release whose protocol this follows, as `version` reports it
+const VERSION: &str = "2024.01.01.00";
+
+/// What `watch-project` reports watching with
+const WATCHER: &str = "fakenotify";
+
+/// Files marking a project root for `watch-project`, as in Watchman's
+/// default `root_files`
+const ROOT_FILES: &[&str] = &[".watchmanconfig", ".git", ".hg", ".svn"];
+
+/// Version control directories whose contents queries leave out, as
+/// Watchman does by default
+const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn"];
+
+/// What clients may require in `version`
+const CAPABILITIES: &[&str] = &[
+    "cmd-clock",
+    "cmd-get-sockname",
+    "cmd-query",
+    "cmd-subscribe",
+    "cmd-unsubscribe",
+    "cmd-version",
+    "cmd-watch",
+    "cmd-watch-del",
+    "cmd-watch-list",
+    "cmd-watch-project",
+    "field-cclock",
+    "field-ctime",
+    "field-ctime_ms",
+    "field-exists",
+    "field-gid",
+    "field-ino",
+    "field-mode",
+    "field-mtime",
+    "field-mtime_ms",
+    "field-name",
+    "field-new",
+    "field-nlink",
+    "field-oclock",
+    "field-size",
+    "field-type",
+    "field-uid",
+    "glob_generator",
+    "relative_root",
+    "suffix-set",
+    "term-allof",
+    "term-anyof",
+    "term-dirname",
+    "term-exists",
+    "term-false",
+    "term-idirname",
+    "term-imatch",
+    "term-iname",
+    "term-match",
+    "term-name",
+    "term-not",
+    "term-suffix",
+    "term-true",
+    "term-type",
+    "wildmatch",
+];
+
+/// Fields a query returns when it names none
+const DEFAULT_FIELDS: &[&str] = &["name", "exists", "new", "size", "mode"];
+
+/// Quiet time after a change before subscribers hear of it
+const SETTLE: Duration = Duration::from_millis(20);
+
+/// Responses and subscription updates queued for a slow client
+const OUTBOX: usize = 256;
+
+/// Largest request accepted
+const MAX_REQUEST: usize = 16 * 1024 * 1024;
+
+/// What is known of one file under a root
+#[derive(Debug, Clone)]
+struct FileState {
+    exists: bool,
+    is_dir: bool,
+    /// Metadata, or `None` until the file is looked at after a change
+    meta: Option<EntryMeta>,
+    /// Tick at which the file was last created
+    cclock: u64,
+    /// Tick at which it last changed
+    oclock: u64,
+}
+
+/// A watched project root
+#[derive(Default)]
+struct Root {
+    /// Files by path under the root
+    files: BTreeMap<PathBuf, FileState>,
+    /// Ticks of named cursors (`n:<name>`)
+    cursors: HashMap<String, u64>,
+}
+
+impl Root {
+    /// Mark `rel` and everything under it deleted
+    fn remove(&mut self, rel: &Path, tick: u64) {
+        let under = self
+            .files
+            .range_mut(rel.to_path_buf()..)
+            .take_while(|(path, _)| path.starts_with(rel));
+        for (_, file) in under {
+            if file.exists {
+                file.exists = false;
+                file.meta = None;
+                file.oclock = tick;
+            }
+        }
+    }
+
+    /// Note a change to `rel`; returns whether it was not there before
+    fn touch(&mut self, rel: &Path, is_dir: bool, tick: u64) -> bool {
+        let file = self.files.entry(rel.to_path_buf()).or_insert(FileState {
+            exists: false,
+            is_dir,
+            meta: None,
+            cclock: tick,
+            oclock: tick,
+        });
+        let appeared = !file.exists;
+        if appeared {
+            file.exists = true;
+            file.cclock = tick;
+        }
+        file.is_dir = is_dir;
+        file.meta = None;
+        file.oclock = tick;
+        appeared
+    }
+}
+
+struct Roots {
+    /// Bumped for every change to any root
+    tick: u64,
+    roots: BTreeMap<PathBuf, Root>,
+}
+
+/// The roots Watchman clients watch, and what changed under them when
+pub struct Watchman {
+    source: Arc<dyn ScanSource>,
+    /// Prefix of this run's clocks
+    instance: String,
+    roots: Mutex<Roots>,
+    /// The latest tick, for subscriptions to wait on
+    changed: watch::Sender<u64>,
+}
+
+impl Watchman {
+    /// Crawl roots through `source`
+    pub fn new(source: Arc<dyn ScanSource>) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Self {
+            source,
+            instance: format!("c:{started}:{}", std::process::id()),
+            roots: Mutex::new(Roots {
+                tick: 0,
+                roots: BTreeMap::new(),
+            }),
+            changed: watch::channel(0).0,
+        }
+    }
+
+    fn clock(&self, tick: u64) -> String {
+        format!("{}:{tick}", self.instance)
+    }
+
+    /// The tick a clock names, or `None` if it is from another run
+    fn parse_clock(&self, clock: &str) -> Option<u64> {
+        clock
+            .strip_prefix(self.instance.as_str())?
+            .strip_prefix(':')?
+            .parse()
+            .ok()
+    }
+
+    /// Record a change the watchers reported
+    pub fn observe(self: &Arc<Self>, event: &WatcherEvent) {
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) {
+            return;
+        }
+        let gone = matches!(
+            event.kind,
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From))
+        );
+
+        let mut appeared = Vec::new();
+        let tick = {
+            let mut roots = self.roots.lock();
+            let Roots { tick, roots } = &mut *roots;
+            let mut changed = false;
+            for (path, root) in roots.iter_mut() {
+                let Ok(rel) = event.path.strip_prefix(path) else {
+                    continue;
+                };
+                if rel.as_os_str().is_empty() {
+                    continue;
+                }
+                if !changed {
+                    *tick += 1;
+                    changed = true;
+                }
+                if gone {
+                    root.remove(rel, *tick);
+                } else if root.touch(rel, event.is_dir, *tick) && event.is_dir {
+                    appeared.push(path.clone());
+                }
+            }
+            changed.then_some(*tick)
+        };
+        let Some(tick) = tick else {
+            return;
+        };
+        self.changed.send_replace(tick);
+
+        // A directory moved in arrives as one event; its contents need a crawl
+        for root in appeared {
+            let watchman = Arc::clone(self);
+            let dir = event.path.clone();
+            tokio::spawn(async move { watchman.crawl(&root, dir).await });
+        }
+    }
+
+    /// Start tracking `root`, crawling it the first time
+    async fn watch(&self, root: &Path) {
+        let added = {
+            let mut roots = self.roots.lock();
+            match roots.roots.entry(root.to_path_buf()) {
+                std::collections::btree_map::Entry::Vacant(entry) => {
+                    entry.insert(Root::default());
+                    true
+                }
+                std::collections::btree_map::Entry::Occupied(_) => false,
+            }
+        };
+        if added {
+            tracing::info!(root = %root.display(), "Watchman client watching root");
+            self.crawl(root, root.to_path_buf()).await;
+        }
+    }
+
+    /// Stop tracking `root`; returns whether it was tracked
+    fn unwatch(&self, root: &Path) -> bool {
+        self.roots.lock().roots.remove(root).is_some()
+    }
+
+    fn is_watched(&self, root: &Path) -> bool {
+        self.roots.lock().roots.contains_key(root)
+    }
+
+    fn watched(&self) -> Vec<PathBuf> {
+        self.roots.lock().roots.keys().cloned().collect()
+    }
+
+    /// The current clock, if `root` is tracked
+    fn current_clock(&self, root: &Path) -> Option<String> {
+        let roots = self.roots.lock();
+        roots
+            .roots
+            .contains_key(root)
+            .then(|| self.clock(roots.tick))
+    }
+
+    /// Walk `dir` under `root` with the scanner, adding what events have
+    /// not already told us about
+    async fn crawl(&self, root: &Path, dir: PathBuf) {
+        let started = self.roots.lock().tick;
+        let source = Arc::clone(&self.source);
+        let walk = tokio::task::spawn_blocking(move || {
+            Scanner::resume(source, dir, true, Snapshot::new()).scan()
+        });
+        let Ok(snapshot) = walk.await else {
+            return;
+        };
+
+        let tick = {
+            let mut roots = self.roots.lock();
+            let Roots { tick, roots } = &mut *roots;
+            let Some(state) = roots.get_mut(root) else {
+                return;
+            };
+            let next = *tick + 1;
+            let mut added = false;
+            for (path, meta) in snapshot {
+                let Ok(rel) = path.strip_prefix(root) else {
+                    continue;
+                };
+                if rel.as_os_str().is_empty() {
+                    continue;
+                }
+                let file = state.files.entry(rel.to_path_buf()).or_insert(FileState {
+                    exists: false,
+                    is_dir: meta.is_dir,
+                    meta: None,
+                    cclock: 0,
+                    oclock: 0,
+                });
+                // Events since the crawl started know better
+                if file.exists || file.oclock > started {
+                    continue;
+                }
+                *file = FileState {
+                    exists: true,
+                    is_dir: meta.is_dir,
+                    meta: Some(meta),
+                    cclock: next,
+                    oclock: next,
+                };
+                added = true;
+            }
+            if !added {
+                return;
+            }
+            *tick = next;
+            next
+        };
+        self.changed.send_replace(tick);
+    }
+
+    /// Run `query` against `root`
+    async fn query(&self, root: &Path, query: &Query) -> Result<Results, String> {
+        let (tick, since, fresh, mut candidates) = {
+            let mut roots = self.roots.lock();
+            let tick = roots.tick;
+            let state = roots.roots.get_mut(root).ok_or_else(|| not_watched(root))?;
+            // `None` for everything that exists, `Some(None)` for a fresh
+            // instance
+            let since = match &query.since {
+                Since::All => None,
+                Since::Clock(clock) => Some(self.parse_clock(clock)),
+                Since::Cursor(name) => Some(state.cursors.insert(name.clone(), tick)),
+            };
+            let fresh = matches!(since, Some(None));
+            let since = since.flatten();
+            let candidates: Vec<_> = if fresh && query.empty_on_fresh_instance {
+                Vec::new()
+            } else {
+                state
+                    .files
+                    .iter()
+                    .filter(|(_, file)| since.map_or(file.exists, |since| file.oclock > since))
+                    .filter(|(path, _)| !in_vcs_dir(path))
+                    .filter_map(|(path, file)| {
+                        let name = query.name(path)?;
+                        query.generated(&name).then(|| Candidate {
+                            path: path.clone(),
+                            name,
+                            file: file.clone(),
+                        })
+                    })
+                    .collect()
+            };
+            (tick, since, fresh, candidates)
+        };
+
+        // Look at what changed since it was last looked at
+        if candidates
+            .iter()
+            .any(|candidate| candidate.file.exists && candidate.file.meta.is_none())
+        {
+            let source = Arc::clone(&self.source);
+            let dir = root.to_path_buf();
+            candidates = tokio::task::spawn_blocking(move || {
+                for candidate in candidates
+                    .iter_mut()
+                    .filter(|candidate| candidate.file.exists && candidate.file.meta.is_none())
+                {
+                    match source.metadata(&dir.join(&candidate.path)) {
+                        Ok(meta) => {
+                            candidate.file.is_dir = meta.is_dir;
+                            candidate.file.meta = Some(meta);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            candidate.file.exists = false;
+                        }
+                        Err(_) => {}
+                    }
+                }
+                candidates
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+
+            let mut roots = self.roots.lock();
+            if let Some(state) = roots.roots.get_mut(root) {
+                for candidate in &candidates {
+                    if let Some(file) = state.files.get_mut(&candidate.path)
+                        && file.oclock == candidate.file.oclock
+                        && file.meta.is_none()
+                        && candidate.file.meta.is_some()
+                    {
+                        file.meta = candidate.file.meta;
+                        file.is_dir = candidate.file.is_dir;
+                    }
+                }
+            }
+        }
+
+        let files = candidates
+            .iter()
+            .filter(|candidate| since.is_some() || candidate.file.exists)
+            .filter(|candidate| {
+                query
+                    .expression
+                    .as_ref()
+                    .is_none_or(|expression| expression.matches(candidate))
+            })
+            .map(|candidate| self.render(query, candidate, since))
+            .collect();
+        Ok(Results {
+            clock: self.clock(tick),
+            fresh,
+            files,
+        })
+    }
+
+    /// `candidate` as the query's fields ask for it
+    fn render(&self, query: &Query, candidate: &Candidate, since: Option<u64>) -> Value {
+        let file = &candidate.file;
+        let meta = file.meta.as_ref();
+        let value = |field: &str| match field {
+            "name" => json!(candidate.name),
+            "exists" => json!(file.exists),
+            "new" => json!(since.is_some_and(|since| file.cclock > since)),
+            "size" => json!(meta.map_or(0, |meta| meta.size)),
+            "mode" => json!(meta.map_or(0, |meta| meta.mode)),
+            "mtime" => json!(meta.map_or(0, |meta| unix_millis(meta.mtime) / 1000)),
+            "mtime_ms" => json!(meta.map_or(0, |meta| unix_millis(meta.mtime))),
+            "ctime" => json!(meta.map_or(0, |meta| unix_millis(meta.ctime) / 1000)),
+            "ctime_ms" => json!(meta.map_or(0, |meta| unix_millis(meta.ctime))),
+            "ino" => json!(meta.map_or(0, |meta| meta.ino)),
+            "nlink" => json!(meta.map_or(0, |meta| meta.nlink)),
+            "uid" => json!(meta.map_or(0, |meta| meta.uid)),
+            "gid" => json!(meta.map_or(0, |meta| meta.gid)),
+            "type" => json!(file_type(file)),
+            "cclock" => json!(self.clock(file.cclock)),
+            _ => json!(self.clock(file.oclock)),
+        };
+        match query.fields.as_slice() {
+            [field] => value(field),
+            fields => Value::Object(
+                fields
+                    .iter()
+                    .map(|field| (field.clone(), value(field)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Keep sending `subscription` the results of `query` on `root` as it
+    /// changes
+    fn subscribe(
+        self: &Arc<Self>,
+        root: PathBuf,
+        subscription: String,
+        mut query: Query,
+        encoding: Encoding,
+        outbox: mpsc::Sender<Vec<u8>>,
+    ) -> JoinHandle<()> {
+        let watchman = Arc::clone(self);
+        let mut changed = self.changed.subscribe();
+        tokio::spawn(async move {
+            let mut first = true;
+            loop {
+                // A root that is no longer watched ends its subscriptions
+                let Ok(results) = watchman.query(&root, &query).await else {
+                    return;
+                };
+                if first || results.fresh || !results.files.is_empty() {
+                    let update = json!({
+                        "version": VERSION,
+                        "subscription": subscription,
+                        "root": root,
+                        "clock": results.clock,
+                        "files": results.files,
+                        "is_fresh_instance": results.fresh,
+                        "unilateral": true,
+                    });
+                    if outbox.send(encoding.encode(&update)).await.is_err() {
+                        return;
+                    }
+                }
+                first = false;
+                query.since = Since::Clock(results.clock);
+
+                if changed.changed().await.is_err() {
+                    return;
+                }
+                loop {
+                    tokio::select! {
+                        result = changed.changed() => if result.is_err() {
+                            return;
+                        },
+                        _ = tokio::time::sleep(SETTLE) => break,
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// A file a query may return
+struct Candidate {
+    /// Path under the root
+    path: PathBuf,
+    /// Path under the query's `relative_root`
+    name: String,
+    file: FileState,
+}
+
+/// What a query found
+struct Results {
+    clock: String,
+    fresh: bool,
+    files: Vec<Value>,
+}
+
+/// Which changes a query asks for
+enum Since {
+    /// Every file that exists
+    All,
+    /// Changes after a clock
+    Clock(String),
+    /// Changes since the named cursor was last used
+    Cursor(String),
+}
+
+/// Which files a generator produces
+enum Generator {
+    /// Files with one of these extensions, lowercased
+    Suffix(Vec<String>),
+    /// Files matching one of these globs
+    Glob(Vec<GlobMatcher>),
+    /// Files under these directories, to an optional depth
+    Path(Vec<(String, Option<usize>)>),
+}
+
+impl Generator {
+    fn generates(&self, name: &str) -> bool {
+        match self {
+            Generator::Suffix(suffixes) => suffix_matches(suffixes, name),
+            Generator::Glob(globs) => globs.iter().any(|glob| glob.is_match(name)),
+            Generator::Path(paths) => paths.iter().any(|(dir, depth)| {
+                if name == dir {
+                    return true;
+                }
+                let rest = if dir.is_empty() {
+                    Some(name)
+                } else {
+                    name.strip_prefix(dir.as_str())
+                        .and_then(|rest| rest.strip_prefix('/'))
+                };
+                rest.is_some_and(|rest| {
+                    depth.is_none_or(|depth| rest.matches('/').count() <= depth)
+                })
+            }),
+        }
+    }
+}
+
+/// A parsed `query` or `subscribe` spec
+struct Query {
+    since: Since,
+    relative_root: Option<PathBuf>,
+    generators: Vec<Generator>,
+    expression: Option<Expr>,
+    fields: Vec<String>,
+    empty_on_fresh_instance: bool,
+}
+
+impl Query {
+    fn parse(spec: &Value) -> Result<Self, String> {
+        let spec = match spec {
+            Value::Object(spec) => spec,
+            Value::Null => &Map::new(),
+            _ => return Err("query must be an object".into()),
+        };
+
+        let since = match spec.get("since") {
+            None | Some(Value::Null) => Since::All,
+            Some(Value::String(clock)) => match clock.strip_prefix("n:") {
+                Some(name) => Since::Cursor(name.to_string()),
+                None if clock.starts_with("c:") => Since::Clock(clock.clone()),
+                None => return Err(format!("invalid clock {clock:?}")),
+            },
+            Some(_) => return Err("since must be a clock string".into()),
+        };
+
+        let mut generators = Vec::new();
+        if let Some(suffix) = spec.get("suffix") {
+            generators.push(Generator::Suffix(suffixes(suffix)?));
+        }
+        if let Some(globs) = spec.get("glob") {
+            let globs = strings(globs, "glob")?
+                .iter()
+                .map(|pattern| glob(pattern, true, false))
+                .collect::<Result<_, _>>()?;
+            generators.push(Generator::Glob(globs));
+        }
+        if let Some(Value::Array(paths)) = spec.get("path") {
+            let paths = paths
+                .iter()
+                .map(|path| match path {
+                    Value::String(dir) => Ok((dir.trim_end_matches('/').to_string(), None)),
+                    Value::Object(path) => {
+                        let dir = path.get("path").and_then(Value::as_str).unwrap_or_default();
+                        let depth = path
+                            .get("depth")
+                            .and_then(Value::as_i64)
+                            .and_then(|depth| usize::try_from(depth).ok());
+                        Ok((dir.trim_end_matches('/').to_string(), depth))
+                    }
+                    _ => Err("path entries must be strings or objects".to_string()),
+                })
+                .collect::<Result<_, _>>()?;
+            generators.push(Generator::Path(paths));
+        }
+
+        let fields = match spec.get("fields") {
+            Some(fields) => {
+                let fields = strings(fields, "fields")?;
+                if let Some(unknown) = fields
+                    .iter()
+                    .find(|field| !CAPABILITIES.contains(&format!("field-{field}").as_str()))
+                {
+                    return Err(format!("unknown field name '{unknown}'"));
+                }
+                fields
+            }
+            None => DEFAULT_FIELDS
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+        };
+
+        Ok(Self {
+            since,
+            relative_root: spec
+                .get("relative_root")
+                .and_then(Value::as_str)
+                .map(|root| PathBuf::from(root.trim_end_matches('/')))
+                .filter(|root| !root.as_os_str().is_empty()),
+            generators,
+            expression: spec.get("expression").map(Expr::parse).transpose()?,
+            fields,
+            empty_on_fresh_instance: spec
+                .get("empty_on_fresh_instance")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+
+    /// How the query names `path`, or `None` if it is outside
+    /// `relative_root`
+    fn name(&self, path: &Path) -> Option<String> {
+        let path = match &self.relative_root {
+            Some(root) => path.strip_prefix(root).ok()?,
+            None => path,
+        };
+        (!path.as_os_str().is_empty()).then(|| path.to_string_lossy().into_owned())
+    }
+
+    fn generated(&self, name: &str) -> bool {
+        self.generators.is_empty()
+            || self
+                .generators
+                .iter()
+                .any(|generator| generator.generates(name))
+    }
+}
+
+/// An expression term
+enum Expr {
+    True,
+    False,
+    AllOf(Vec<Expr>),
+    AnyOf(Vec<Expr>),
+    Not(Box<Expr>),
+    Exists,
+    Type(String),
+    Suffix(Vec<String>),
+    Name {
+        names: Vec<String>,
+        whole: bool,
+        fold_case: bool,
+    },
+    Match {
+        glob: GlobMatcher,
+        whole: bool,
+    },
+    Dirname {
+        dir: String,
+        depth: (String, usize),
+        fold_case: bool,
+    },
+}
+
+impl Expr {
+    fn parse(term: &Value) -> Result<Self, String> {
+        let (name, args) = match term {
+            Value::String(name) => (name.as_str(), &[][..]),
+            Value::Array(term) => match term.split_first() {
+                Some((Value::String(name), args)) => (name.as_str(), args),
+                _ => return Err("expression terms start with their name".into()),
+            },
+            _ => return Err("expression terms are strings or arrays".into()),
+        };
+        let arg = |index: usize| {
+            args.get(index)
+                .ok_or_else(|| format!("\"{name}\" term is missing an argument"))
+        };
+        let whole = |index: usize| match args.get(index).and_then(Value::as_str) {
+            None | Some("basename") => Ok(false),
+            Some("wholename") => Ok(true),
+            Some(scope) => Err(format!("invalid scope '{scope}' for \"{name}\"")),
+        };
+
+        Ok(match name {
+            "true" => Expr::True,
+            "false" => Expr::False,
+            "allof" | "anyof" => {
+                let terms = args.iter().map(Expr::parse).collect::<Result<_, _>>()?;
+                if name == "allof" {
+                    Expr::AllOf(terms)
+                } else {
+                    Expr::AnyOf(terms)
+                }
+            }
+            "not" => Expr::Not(Box::new(Expr::parse(arg(0)?)?)),
+            "exists" => Expr::Exists,
+            "type" => Expr::Type(
+                arg(0)?
+                    .as_str()
+                    .ok_or("\"type\" takes a type letter")?
+                    .to_string(),
+            ),
+            "suffix" => Expr::Suffix(suffixes(arg(0)?)?),
+            "name" | "iname" => Expr::Name {
+                names: match arg(0)? {
+                    Value::String(name) => vec![name.clone()],
+                    names => strings(names, "name")?,
+                },
+                whole: whole(1)?,
+                fold_case: name == "iname",
+            },
+            "match" | "imatch" => {
+                let whole = whole(1)?;
+                let pattern = arg(0)?.as_str().ok_or("\"match\" takes a pattern")?;
+                Expr::Match {
+                    glob: glob(pattern, whole, name == "imatch")?,
+                    whole,
+                }
+            }
+            "dirname" | "idirname" => {
+                let dir = arg(0)?.as_str().ok_or("\"dirname\" takes a directory")?;
+                let depth = match args.get(1) {
+                    None => ("ge".to_string(), 0),
+                    Some(Value::Array(depth)) => match depth.as_slice() {
+                        [Value::String(depth), Value::String(op), Value::Number(n)]
+                            if depth == "depth" =>
+                        {
+                            let n = n
+                                .as_u64()
+                                .and_then(|n| usize::try_from(n).ok())
+                                .ok_or("invalid depth")?;
+                            (op.clone(), n)
+                        }
+                        _ => return Err("invalid depth for \"dirname\"".into()),
+                    },
+                    Some(_) => return Err("invalid depth for \"dirname\"".into()),
+                };
+                Expr::Dirname {
+                    dir: dir.trim_end_matches('/').to_string(),
+                    depth,
+                    fold_case: name == "idirname",
+                }
+            }
+            _ => return Err(format!("unknown expression term '{name}'")),
+        })
+    }
+
+    fn matches(&self, candidate: &Candidate) -> bool {
+        let name = candidate.name.as_str();
+        let basename = name.rsplit('/').next().unwrap_or(name);
+        match self {
+            Expr::True => true,
+            Expr::False => false,
+            Expr::AllOf(terms) => terms.iter().all(|term| term.matches(candidate)),
+            Expr::AnyOf(terms) => terms.iter().any(|term| term.matches(candidate)),
+            Expr::Not(term) => !term.matches(candidate),
+            Expr::Exists => candidate.file.exists,
+            Expr::Type(kind) => file_type(&candidate.file) == kind,
+            Expr::Suffix(suffixes) => suffix_matches(suffixes, name),
+            Expr::Name {
+                names,
+                whole,
+                fold_case,
+            } => {
+                let name = if *whole { name } else { basename };
+                names.iter().any(|wanted| {
+                    if *fold_case {
+                        wanted.eq_ignore_ascii_case(name)
+                    } else {
+                        wanted == name
+                    }
+                })
+            }
+            Expr::Match { glob, whole } => glob.is_match(if *whole { name } else { basename }),
+            Expr::Dirname {
+                dir,
+                depth: (op, wanted),
+                fold_case,
+            } => {
+                let rest = if dir.is_empty() {
+                    Some(name)
+                } else if *fold_case {
+                    name.get(..dir.len())
+                        .filter(|prefix| prefix.eq_ignore_ascii_case(dir))
+                        .and_then(|_| name[dir.len()..].strip_prefix('/'))
+                } else {
+                    name.strip_prefix(dir.as_str())
+                        .and_then(|rest| rest.strip_prefix('/'))
+                };
+                rest.is_some_and(|rest| {
+                    let depth = rest.matches('/').count();
+                    match op.as_str() {
+                        "eq" => depth == *wanted,
+                        "ne" => depth != *wanted,
+                        "gt" => depth > *wanted,
+                        "lt" => depth < *wanted,
+                        "le" => depth <= *wanted,
+                        _ => depth >= *wanted,
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// The strings in `value`, an array
+fn strings(value: &Value, what: &str) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .and_then(|items| {
+            items
+                .iter()
+                .map(|item| item.as_str().map(str::to_string))
+                .collect()
+        })
+        .ok_or_else(|| format!("{what} must be an array of strings"))
+}
+
+/// The lowercased extensions in a `suffix`, a string or an array of them
+fn suffixes(value: &Value) -> Result<Vec<String>, String> {
+    let suffixes = match value {
+        Value::String(suffix) => vec![suffix.clone()],
+        suffixes => strings(suffixes, "suffix")?,
+    };
+    Ok(suffixes
+        .iter()
+        .map(|suffix| suffix.trim_start_matches('.').to_ascii_lowercase())
+        .collect())
+}
+
+fn suffix_matches(suffixes: &[String], name: &str) -> bool {
+    let basename = name.rsplit('/').next().unwrap_or(name);
+    basename.rsplit_once('.').is_some_and(|(_, ext)| {
+        suffixes
+            .iter()
+            .any(|suffix| suffix.eq_ignore_ascii_case(ext))
+    })
+}
+
+/// A Watchman wildmatch pattern; `/` only matches `/` in whole names
+fn glob(pattern: &str, whole: bool, fold_case: bool) -> Result<GlobMatcher, String> {
+    GlobBuilder::new(pattern)
+        .literal_separator(whole)
+        .case_insensitive(fold_case)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| format!("invalid pattern {pattern:?}: {e}"))
+}
+
+/// Watchman's letter for the type of `file`
+fn file_type(file: &FileState) -> &'static str {
+    match file.meta.map(|meta| meta.mode & libc::S_IFMT) {
+        Some(libc::S_IFLNK) => "l",
+        Some(libc::S_IFSOCK) => "s",
+        Some(libc::S_IFIFO) => "p",
+        Some(libc::S_IFBLK) => "b",
+        Some(libc::S_IFCHR) => "c",
+        _ if file.is_dir => "d",
+        _ => "f",
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Whether `path` lies inside a version control directory
+fn in_vcs_dir(path: &Path) -> bool {
+    path.parent().is_some_and(|dir| {
+        dir.components()
+            .any(|component| VCS_DIRS.iter().any(|vcs| component.as_os_str() == *vcs))
+    })
+}
+
+fn not_watched(root: &Path) -> String {
+    format!(
+        "unable to resolve root {}: directory is not watched",
+        root.display()
+    )
+}
+
+/// How a request was encoded, and so its responses are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Json,
+    Bser(bser::Version),
+}
+
+impl Encoding {
+    fn encode(self, value: &Value) -> Vec<u8> {
+        match self {
+            Encoding::Json => {
+                let mut line = value.to_string().into_bytes();
+                line.push(b'\n');
+                line
+            }
+            Encoding::Bser(version) => bser::encode_pdu(value, version),
+        }
+    }
+}
+
+/// Accept Watchman clients on `socket` until shutdown
+pub async fn serve(
+    socket: PathBuf,
+    state: Arc<DaemonState>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> color_eyre::Result<()> {
+    let Some(watchman) = state.watchman().cloned() else {
+        return Ok(());
+    };
+    clear_stale_socket(&socket).await?;
+    if let Some(parent) = socket.parent()
+        && !parent.exists()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(&socket)?;
+    let bound = file_id(&socket);
+    tracing::info!(socket = %socket.display(), "Watchman endpoint listening");
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let (reader, mut writer) = stream.into_split();
+                    let (outbox, mut outgoing) = mpsc::channel::<Vec<u8>>(OUTBOX);
+                    connections.spawn(async move {
+                        while let Some(pdu) = outgoing.recv().await {
+                            if writer.write_all(&pdu).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    let mut connection = Connection {
+                        watchman: Arc::clone(&watchman),
+                        state: Arc::clone(&state),
+                        socket: socket.clone(),
+                        outbox,
+                        subscriptions: HashMap::new(),
+                    };
+                    connections.spawn(async move { connection.run(reader).await });
+                }
+                Err(e) => tracing::error!(error = %e, "Watchman accept error"),
+            },
+            Some(_) = connections.join_next() => {}
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+
+    connections.shutdown().await;
+    // Unless something else has taken the path over since
+    if bound.is_some() && file_id(&socket) == bound {
+        let _ = std::fs::remove_file(&socket);
+    }
+    Ok(())
+}
+
+/// Remove the socket left at `socket` by an endpoint that is gone
+///
+/// One that still answers, such as a real Watchman's, is left alone, and
+/// so is anything that is not a socket.
+async fn clear_stale_socket(socket: &Path) -> color_eyre::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let meta = match std::fs::symlink_metadata(socket) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !meta.file_type().is_socket() {
+        color_eyre::eyre::bail!("{} exists and is not a socket", socket.display());
+    }
+    if tokio::net::UnixStream::connect(socket).await.is_ok() {
+        color_eyre::eyre::bail!("Watchman socket {} is already in use", socket.display());
+    }
+    tracing::debug!(socket = %socket.display(), "Removing stale Watchman socket");
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+/// Device and inode of `path`, to tell whether it is still the same file
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|meta| (meta.dev(), meta.ino()))
+}
+
+/// One Watchman client
+struct Connection {
+    watchman: Arc<Watchman>,
+    state: Arc<DaemonState>,
+    socket: PathBuf,
+    outbox: mpsc::Sender<Vec<u8>>,
+    /// Subscription tasks by root and name
+    subscriptions: HashMap<(PathBuf, String), JoinHandle<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for task in self.subscriptions.values() {
+            task.abort();
+        }
+    }
+}
+
+impl Connection {
+    /// Answer requests until the client hangs up
+    async fn run(&mut self, reader: OwnedReadHalf) {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let (request, encoding) = match read_request(&mut reader).await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::debug!(error = %e, "Dropping Watchman client");
+                    return;
+                }
+            };
+            let response = match request {
+                Ok(request) => self.handle(&request, encoding).await,
+                Err(e) => Err(e),
+            };
+            let response = match response {
+                Ok(Some(response)) => response,
+                // Answered already
+                Ok(None) => continue,
+                Err(error) => json!({ "error": error }),
+            };
+            let mut response = match response {
+                Value::Object(response) => response,
+                _ => Map::new(),
+            };
+            response.insert("version".into(), json!(VERSION));
+            if self
+                .outbox
+                .send(encoding.encode(&Value::Object(response)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Carry out `request`; `None` if the response has been sent
+    async fn handle(
+        &mut self,
+        request: &Value,
+        encoding: Encoding,
+    ) -> Result<Option<Value>, String> {
+        let Some((Value::String(command), args)) =
+            request.as_array().and_then(|request| request.split_first())
+        else {
+            return Err("invalid command (expected an array with some elements!)".into());
+        };
+        let path_arg = |index: usize| {
+            args.get(index)
+                .and_then(Value::as_str)
+                .map(|path| PathBuf::from(path.trim_end_matches('/')))
+                .filter(|path| path.is_absolute())
+                .ok_or_else(|| format!("{command} needs an absolute path"))
+        };
+        let watched_root = |index: usize| {
+            let root = path_arg(index)?;
+            if self.watchman.is_watched(&root) {
+                Ok(root)
+            } else {
+                Err(not_watched(&root))
+            }
+        };
+
+        match command.as_str() {
+            "version" => version(args.first()).map(Some),
+            "get-sockname" => Ok(Some(json!({
+                "sockname": self.socket,
+                "unix_domain": self.socket,
+            }))),
+            "watch-project" | "watch" => {
+                let path = path_arg(0)?;
+                let (root, relative) = self.resolve(&path, command == "watch-project").await?;
+                self.watchman.watch(&root).await;
+                let mut response = json!({ "watch": root, "watcher": WATCHER });
+                if !relative.as_os_str().is_empty() {
+                    response["relative_path"] = json!(relative);
+                }
+                Ok(Some(response))
+            }
+            "watch-list" => Ok(Some(json!({ "roots": self.watchman.watched() }))),
+            "watch-del" => {
+                let root = path_arg(0)?;
+                self.subscriptions.retain(|(path, _), task| {
+                    let keep = *path != root;
+                    if !keep {
+                        task.abort();
+                    }
+                    keep
+                });
+                Ok(Some(
+                    json!({ "watch-del": self.watchman.unwatch(&root), "root": root }),
+                ))
+            }
+            "clock" => {
+                let root = path_arg(0)?;
+                let clock = self
+                    .watchman
+                    .current_clock(&root)
+                    .ok_or_else(|| not_watched(&root))?;
+                Ok(Some(json!({ "clock": clock })))
+            }
+            "query" => {
+                let root = watched_root(0)?;
+                let query = Query::parse(args.get(1).unwrap_or(&Value::Null))?;
+                let results = self.watchman.query(&root, &query).await?;
+                Ok(Some(json!({
+                    "clock": results.clock,
+                    "is_fresh_instance": results.fresh,
+                    "files": results.files,
+                })))
+            }
+            "subscribe" => {
+                let root = watched_root(0)?;
+                let name = args
+                    .get(1)
+                    .and_then(Value::as_str)
+                    .ok_or("subscribe needs a name")?
+                    .to_string();
+                let query = Query::parse(args.get(2).unwrap_or(&Value::Null))?;
+                let clock = self.watchman.current_clock(&root).unwrap_or_default();
+                // Answer before the first update goes out
+                let response = encoding.encode(&json!({
+                    "version": VERSION,
+                    "subscribe": name,
+                    "clock": clock,
+                }));
+                self.outbox
+                    .send(response)
+                    .await
+                    .map_err(|_| "client went away".to_string())?;
+                let task = self.watchman.subscribe(
+                    root.clone(),
+                    name.clone(),
+                    query,
+                    encoding,
+                    self.outbox.clone(),
+                );
+                if let Some(old) = self.subscriptions.insert((root, name), task) {
+                    old.abort();
+                }
+                Ok(None)
+            }
+            "unsubscribe" => {
+                let root = path_arg(0)?;
+                let name = args
+                    .get(1)
+                    .and_then(Value::as_str)
+                    .ok_or("unsubscribe needs a name")?;
+                let task = self.subscriptions.remove(&(root, name.to_string()));
+                if let Some(task) = &task {
+                    task.abort();
+                }
+                Ok(Some(
+                    json!({ "unsubscribe": name, "deleted": task.is_some() }),
+                ))
+            }
+            _ => Err(format!("unknown command {command}")),
+        }
+    }
+
+    /// The root to watch for `path` and where `path` lies under it: the
+    /// nearest directory holding one of [`ROOT_FILES`] for `watch-project`,
+    /// `path` itself otherwise
+    async fn resolve(&self, path: &Path, project: bool) -> Result<(PathBuf, PathBuf), String> {
+        let path = self.state.on_disk_case(path);
+        let Some(covering) = self
+            .state
+            .root_configs()
+            .into_iter()
+            .filter(|root| root.recursive)
+            .map(|root| root.path)
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+        else {
+            return Err(format!(
+                "{} is not under a recursive [[watch]] of this daemon",
+                path.display()
+            ));
+        };
+
+        tokio::task::spawn_blocking(move || {
+            if !path.is_dir() {
+                return Err(format!("{} is not a directory", path.display()));
+            }
+            let root = if project {
+                path.ancestors()
+                    .take_while(|dir| dir.starts_with(&covering))
+                    .find(|dir| ROOT_FILES.iter().any(|file| dir.join(file).exists()))
+                    .unwrap_or(&path)
+                    .to_path_buf()
+            } else {
+                path.clone()
+            };
+            let relative = path
+                .strip_prefix(&root)
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            Ok((root, relative))
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
+
+/// Answer `version`, checking the capabilities the client names
+fn version(args: Option<&Value>) -> Result<Value, String> {
+    let wanted = |key: &str| {
+        args.and_then(|args| args.get(key))
+            .and_then(|names| strings(names, key).ok())
+            .unwrap_or_default()
+    };
+    let (optional, required) = (wanted("optional"), wanted("required"));
+    let capabilities: Map<String, Value> = optional
+        .iter()
+        .chain(&required)
+        .map(|name| (name.clone(), json!(CAPABILITIES.contains(&name.as_str()))))
+        .collect();
+    let mut response = json!({ "capabilities": capabilities });
+    if let Some(missing) = required
+        .iter()
+        .find(|name| !CAPABILITIES.contains(&name.as_str()))
+    {
+        response["error"] = json!(format!(
+            "client required capability `{missing}` is not supported by this server"
+        ));
+    }
+    Ok(response)
+}
+
+/// Read the next request: a BSER PDU or a line of JSON. A request that
+/// is not valid JSON is an error to answer, one that cannot be framed ends
+/// the connection.
+async fn read_request(
+    reader: &mut BufReader<OwnedReadHalf>,
+) -> io::Result<Option<(Result<Value, String>, Encoding)>> {
+    loop {
+        let Some(&first) = reader.fill_buf().await?.first() else {
+            return Ok(None);
+        };
+        if first == 0 {
+            let (request, version) = bser::read_pdu(reader, MAX_REQUEST).await?;
+            return Ok(Some((Ok(request), Encoding::Bser(version))));
+        }
+
+        let mut line = Vec::new();
+        (&mut *reader)
+            .take(MAX_REQUEST as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if !line.ends_with(b"\n") && line.len() >= MAX_REQUEST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let request = serde_json::from_slice(&line).map_err(|e| format!("invalid JSON: {e}"));
+        return Ok(Some((request, Encoding::Json)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::FsSource;
+    use notify::event::{CreateKind, RemoveKind};
+
+    fn event(path: PathBuf, kind: EventKind) -> WatcherEvent {
+        WatcherEvent {
+            path,
+            kind,
+            is_dir: false,
+            unlinked: false,
+            ino: 0,
+        }
+    }
+
+    fn query(spec: Value) -> Query {
+        Query::parse(&spec).unwrap()
+    }
+
+    fn names(results: &Results) -> Vec<&str> {
+        let mut names: Vec<_> = results
+            .files
+            .iter()
+            .map(|file| {
+                file.as_str()
+                    .unwrap_or_else(|| file["name"].as_str().unwrap())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_serve_takes_over_only_stale_sockets() {
+        let socket =
+            std::env::temp_dir().join(format!("fakenotify-watchman-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let state =
+            Arc::new(DaemonState::new().with_watchman(Arc::new(Watchman::new(Arc::new(FsSource)))));
+        let (shutdown, _) = broadcast::channel(1);
+
+        // Something already answers there
+        let other = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let refused = serve(socket.clone(), Arc::clone(&state), shutdown.subscribe()).await;
+        assert!(refused.is_err());
+        assert!(socket.exists());
+
+        // Its socket outlives it, and nothing answers any more
+        drop(other);
+        let served = tokio::spawn(serve(socket.clone(), state, shutdown.subscribe()));
+        for _ in 0..100 {
+            if tokio::net::UnixStream::connect(&socket).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::net::UnixStream::connect(&socket).await.unwrap();
+        shutdown.send(()).unwrap();
+        served.await.unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn test_query_since_clock_and_cursor() {
+        let root = std::env::temp_dir().join(format!("fakenotify-watchman-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join(".git/objects")).unwrap();
+        std::fs::write(root.join("src/a.js"), "a").unwrap();
+        std::fs::write(root.join("src/b.ts"), "b").unwrap();
+        std::fs::write(root.join("README"), "r").unwrap();
+
+        let watchman = Arc::new(Watchman::new(Arc::new(FsSource)));
+        watchman.watch(&root).await;
+        let all = watchman
+            .query(&root, &query(json!({ "fields": ["name"] })))
+            .await
+            .unwrap();
+        assert!(!all.fresh);
+        assert_eq!(
+            names(&all),
+            [".git", "README", "src", "src/a.js", "src/b.ts"]
+        );
+        // Nothing yet for a named cursor's first use but a fresh instance
+        let cursor = query(json!({ "since": "n:jest", "fields": ["name"] }));
+        assert!(watchman.query(&root, &cursor).await.unwrap().fresh);
+
+        std::fs::write(root.join("src/c.js"), "cc").unwrap();
+        watchman.observe(&event(
+            root.join("src/c.js"),
+            EventKind::Create(CreateKind::File),
+        ));
+        std::fs::remove_file(root.join("src/a.js")).unwrap();
+        watchman.observe(&event(
+            root.join("src/a.js"),
+            EventKind::Remove(RemoveKind::File),
+        ));
+        watchman.observe(&event(
+            PathBuf::from("/elsewhere/x.js"),
+            EventKind::Create(CreateKind::File),
+        ));
+
+        let changed = watchman
+            .query(
+                &root,
+                &query(json!({
+                    "since": all.clock,
+                    "relative_root": "src",
+                    "expression": ["allof", ["type", "f"], ["suffix", ["js"]]],
+                    "fields": ["name", "exists", "new", "size"],
+                })),
+            )
+            .await
+            .unwrap();
+        assert!(!changed.fresh);
+        let mut files = changed.files.clone();
+        files.sort_by_key(|file| file["name"].as_str().unwrap().to_string());
+        assert_eq!(
+            files,
+            vec![
+                json!({ "name": "a.js", "exists": false, "new": false, "size": 0 }),
+                json!({ "name": "c.js", "exists": true, "new": true, "size": 2 }),
+            ]
+        );
+        let since_cursor = watchman.query(&root, &cursor).await.unwrap();
+        assert!(!since_cursor.fresh);
+        assert_eq!(names(&since_cursor), ["src/a.js", "src/c.js"]);
+        assert!(
+            watchman
+                .query(&root, &cursor)
+                .await
+                .unwrap()
+                .files
+                .is_empty()
+        );
+
+        // A clock from another run of the daemon
+        let foreign = query(json!({ "since": "c:1:1:5", "fields": ["name"] }));
+        let fresh = watchman.query(&root, &foreign).await.unwrap();
+        assert!(fresh.fresh);
+        assert_eq!(
+            names(&fresh),
+            [".git", "README", "src", "src/b.ts", "src/c.js"]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_expression_terms() {
+        let candidate = |name: &str, is_dir: bool| Candidate {
+            path: PathBuf::from(name),
+            name: name.to_string(),
+            file: FileState {
+                exists: true,
+                is_dir,
+                meta: None,
+                cclock: 1,
+                oclock: 1,
+            },
+        };
+        let expr = |term: Value| Expr::parse(&term).unwrap();
+        let file = candidate("lib/util/a.test.JS", false);
+
+        assert!(expr(json!(["suffix", "js"])).matches(&file));
+        assert!(expr(json!(["match", "*.test.*"])).matches(&file));
+        assert!(!expr(json!(["match", "lib/*.JS", "wholename"])).matches(&file));
+        assert!(expr(json!(["match", "lib/**/*.JS", "wholename"])).matches(&file));
+        assert!(expr(json!(["imatch", "*.js"])).matches(&file));
+        assert!(expr(json!(["name", ["x", "a.test.JS"]])).matches(&file));
+        assert!(expr(json!(["dirname", "lib"])).matches(&file));
+        assert!(expr(json!(["dirname", "lib", ["depth", "eq", 1]])).matches(&file));
+        assert!(!expr(json!(["dirname", "li"])).matches(&file));
+        assert!(expr(json!(["anyof", ["type", "d"], ["not", "false"]])).matches(&file));
+        assert!(!expr(json!(["allof", "exists", ["type", "d"]])).matches(&file));
+        assert!(expr(json!(["type", "d"])).matches(&candidate("lib", true)));
+        assert!(Expr::parse(&json!(["bogus"])).is_err());
+        assert!(Query::parse(&json!({ "fields": ["name", "bogus"] })).is_err());
+    }
+
+    #[test]
+    fn test_version_checks_capabilities() {
+        let response = version(Some(&json!({
+            "optional": ["term-dirname"],
+            "required": ["relative_root"],
+        })))
+        .unwrap();
+        assert_eq!(
+            response["capabilities"],
+            json!({ "term-dirname": true, "relative_root": true })
+        );
+        assert!(response.get("error").is_none());
+
+        let response = version(Some(&json!({ "required": ["field-content.sha1hex"] }))).unwrap();
+        assert!(response["error"].as_str().unwrap().contains("sha1hex"));
+    }
+}
@@ -0,0 +1,326 @@
+//! BSER, the binary encoding Watchman clients use by default.
+//!
+//! A PDU is the magic `\0\x01` (or `\0\x02` and four bytes of capabilities),
+//! the payload's length as an encoded integer, then the payload. Values map
+//! onto JSON, except that strings need not be UTF-8; those are decoded
+//! lossily. Integers are little-endian.
+
+use serde_json::{Map, Number, Value};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const ARRAY: u8 = 0x00;
+const OBJECT: u8 = 0x01;
+const STRING: u8 = 0x02;
+const INT8: u8 = 0x03;
+const INT16: u8 = 0x04;
+const INT32: u8 = 0x05;
+const INT64: u8 = 0x06;
+const REAL: u8 = 0x07;
+const TRUE: u8 = 0x08;
+const FALSE: u8 = 0x09;
+const NULL: u8 = 0x0a;
+const TEMPLATE: u8 = 0x0b;
+const SKIP: u8 = 0x0c;
+const UTF8_STRING: u8 = 0x0d;
+
+/// Nesting allowed in a decoded value
+const MAX_DEPTH: usize = 64;
+
+/// The PDU header a peer used, so replies can match it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+/// `value` as a complete PDU
+pub fn encode_pdu(value: &Value, version: Version) -> Vec<u8> {
+    let mut payload = Vec::new();
+    encode(value, &mut payload);
+
+    let mut pdu = match version {
+        Version::V1 => vec![0, 1],
+        Version::V2 => {
+            let mut header = vec![0, 2];
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header
+        }
+    };
+    encode_int(payload.len() as i64, &mut pdu);
+    pdu.extend_from_slice(&payload);
+    pdu
+}
+
+fn encode(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(NULL),
+        Value::Bool(true) => out.push(TRUE),
+        Value::Bool(false) => out.push(FALSE),
+        Value::Number(number) => match number.as_i64() {
+            Some(int) => encode_int(int, out),
+            None => {
+                out.push(REAL);
+                out.extend_from_slice(&number.as_f64().unwrap_or_default().to_le_bytes());
+            }
+        },
+        Value::String(string) => encode_str(string, out),
+        Value::Array(items) => {
+            out.push(ARRAY);
+            encode_int(items.len() as i64, out);
+            for item in items {
+                encode(item, out);
+            }
+        }
+        Value::Object(map) => {
+            out.push(OBJECT);
+            encode_int(map.len() as i64, out);
+            for (key, value) in map {
+                encode_str(key, out);
+                encode(value, out);
+            }
+        }
+    }
+}
+
+fn encode_str(string: &str, out: &mut Vec<u8>) {
+    out.push(STRING);
+    encode_int(string.len() as i64, out);
+    out.extend_from_slice(string.as_bytes());
+}
+
+/// `int` in the fewest bytes that hold it
+fn encode_int(int: i64, out: &mut Vec<u8>) {
+    if let Ok(int) = i8::try_from(int) {
+        out.push(INT8);
+        out.extend_from_slice(&int.to_le_bytes());
+    } else if let Ok(int) = i16::try_from(int) {
+        out.push(INT16);
+        out.extend_from_slice(&int.to_le_bytes());
+    } else if let Ok(int) = i32::try_from(int) {
+        out.push(INT32);
+        out.extend_from_slice(&int.to_le_bytes());
+    } else {
+        out.push(INT64);
+        out.extend_from_slice(&int.to_le_bytes());
+    }
+}
+
+/// Read one PDU of at most `limit` payload bytes
+pub async fn read_pdu(
+    reader: &mut (impl AsyncRead + Unpin),
+    limit: usize,
+) -> io::Result<(Value, Version)> {
+    let mut magic = [0u8; 2];
+    reader.read_exact(&mut magic).await?;
+    let version = match magic {
+        [0, 1] => Version::V1,
+        [0, 2] => {
+            let mut capabilities = [0u8; 4];
+            reader.read_exact(&mut capabilities).await?;
+            Version::V2
+        }
+        _ => return Err(invalid("not a BSER PDU")),
+    };
+
+    let tag = reader.read_u8().await?;
+    let mut int = [0u8; 8];
+    let width = int_width(tag)?;
+    reader.read_exact(&mut int[..width]).await?;
+    let len = Decoder::new(&int[..width]).int_of(tag)?;
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= limit)
+        .ok_or_else(|| invalid("BSER PDU too large"))?;
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok((decode(&payload)?, version))
+}
+
+/// Decode a PDU's payload
+pub fn decode(payload: &[u8]) -> io::Result<Value> {
+    Decoder::new(payload).value(0)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Bytes following an integer's type byte
+fn int_width(tag: u8) -> io::Result<usize> {
+    match tag {
+        INT8 => Ok(1),
+        INT16 => Ok(2),
+        INT32 => Ok(4),
+        INT64 => Ok(8),
+        _ => Err(invalid("expected a BSER integer")),
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| invalid("truncated BSER value"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    /// The integer after type byte `tag`
+    fn int_of(&mut self, tag: u8) -> io::Result<i64> {
+        let bytes = self.take(int_width(tag)?)?;
+        Ok(match bytes.len() {
+            1 => i8::from_le_bytes([bytes[0]]).into(),
+            2 => i16::from_le_bytes([bytes[0], bytes[1]]).into(),
+            4 => i32::from_le_bytes(bytes.try_into().unwrap_or_default()).into(),
+            _ => i64::from_le_bytes(bytes.try_into().unwrap_or_default()),
+        })
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        let tag = self.byte()?;
+        usize::try_from(self.int_of(tag)?).map_err(|_| invalid("negative BSER length"))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        match self.byte()? {
+            STRING | UTF8_STRING => {
+                let len = self.len()?;
+                Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+            }
+            _ => Err(invalid("expected a BSER string")),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("BSER value nested too deeply"));
+        }
+        let tag = self.peek().ok_or_else(|| invalid("truncated BSER value"))?;
+        match tag {
+            STRING | UTF8_STRING => return self.string().map(Value::String),
+            _ => self.pos += 1,
+        }
+        match tag {
+            ARRAY => {
+                let len = self.len()?;
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    items.push(self.value(depth + 1)?);
+                }
+                Ok(Value::Array(items))
+            }
+            OBJECT => {
+                let len = self.len()?;
+                let mut map = Map::new();
+                for _ in 0..len {
+                    let key = self.string()?;
+                    map.insert(key, self.value(depth + 1)?);
+                }
+                Ok(Value::Object(map))
+            }
+            INT8 | INT16 | INT32 | INT64 => Ok(Value::from(self.int_of(tag)?)),
+            REAL => {
+                let bytes = self.take(8)?;
+                let real = f64::from_le_bytes(bytes.try_into().unwrap_or_default());
+                Ok(Number::from_f64(real).map_or(Value::Null, Value::Number))
+            }
+            TRUE => Ok(Value::Bool(true)),
+            FALSE => Ok(Value::Bool(false)),
+            NULL => Ok(Value::Null),
+            TEMPLATE => {
+                let Value::Array(keys) = self.value(depth + 1)? else {
+                    return Err(invalid("BSER template keys are not an array"));
+                };
+                let len = self.len()?;
+                let mut rows = Vec::new();
+                for _ in 0..len {
+                    let mut row = Map::new();
+                    for key in &keys {
+                        if self.peek() == Some(SKIP) {
+                            self.pos += 1;
+                            continue;
+                        }
+                        let key = key.as_str().unwrap_or_default().to_string();
+                        row.insert(key, self.value(depth + 1)?);
+                    }
+                    rows.push(Value::Object(row));
+                }
+                Ok(Value::Array(rows))
+            }
+            _ => Err(invalid("unknown BSER type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_encodes_like_watchman() {
+        let pdu = encode_pdu(&json!(["watch-list"]), Version::V1);
+        let mut expected = vec![0, 1, INT8, 16, ARRAY, INT8, 1, STRING, INT8, 10];
+        expected.extend_from_slice(b"watch-list");
+        assert_eq!(pdu, expected);
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let value = json!({
+            "files": [{ "name": "a.js", "size": 70000, "mtime_ms": 1_700_000_000_000i64 }],
+            "fresh": false,
+            "clock": null,
+            "ratio": 0.5,
+            "small": -3,
+        });
+        for version in [Version::V1, Version::V2] {
+            let pdu = encode_pdu(&value, version);
+            let (decoded, read) = read_pdu(&mut pdu.as_slice(), 1024).await.unwrap();
+            assert_eq!(decoded, value);
+            assert_eq!(read, version);
+        }
+        assert!(
+            read_pdu(&mut encode_pdu(&value, Version::V1).as_slice(), 8)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_decodes_templates_and_utf8_strings() {
+        let mut payload = vec![TEMPLATE, ARRAY, INT8, 2];
+        payload.extend_from_slice(&[STRING, INT8, 4]);
+        payload.extend_from_slice(b"name");
+        payload.extend_from_slice(&[STRING, INT8, 4]);
+        payload.extend_from_slice(b"size");
+        payload.extend_from_slice(&[INT8, 2]);
+        payload.extend_from_slice(&[UTF8_STRING, INT8, 1, b'a', INT16, 0x00, 0x01]);
+        payload.extend_from_slice(&[UTF8_STRING, INT8, 1, b'b', SKIP]);
+        assert_eq!(
+            decode(&payload).unwrap(),
+            json!([{ "name": "a", "size": 256 }, { "name": "b" }])
+        );
+        assert!(decode(&[ARRAY, INT8, 2, TRUE]).is_err());
+    }
+}
@@ -0,0 +1,475 @@
+//! FakeNotify interposition library for macOS
+//!
+//! macOS has no inotify, but software ported from Linux often links
+//! libinotify, a kqueue-backed shim for the inotify API. kqueue only sees
+//! changes made through the local kernel, so on NFS and SMB mounts those
+//! programs miss everything done on the server or by other clients.
+//!
+//! Inserted with `DYLD_INSERT_LIBRARIES` (and `DYLD_FORCE_FLAT_NAMESPACE=1`
+//! so our symbols win over libinotify's), this library serves
+//! `inotify_init`, `inotify_init1`, `inotify_add_watch` and
+//! `inotify_rm_watch` from fakenotifyd, speaking the same protocol as the
+//! Linux preload.
+//!
+//! # How it works
+//!
+//! 1. App calls `inotify_init()` -> We connect to the daemon and return one
+//!    end of an `AF_UNIX` datagram pair
+//! 2. A pump thread writes each daemon event to the other end as one
+//!    datagram, so plain `read()`, `select()` and `kevent()` on the fd see
+//!    whole `inotify_event` structs without us interposing them
+//! 3. `inotify_add_watch`/`inotify_rm_watch` on that fd are forwarded to the
+//!    daemon; calls on any other fd go to libinotify
+//!
+//! Programs using FSEvents or kqueue directly are not affected.
+//!
+//! # Safety
+//!
+//! As with the Linux preload: no panics across the FFI boundary, no work at
+//! load time, and all state behind locks.
+
+#![cfg(target_os = "macos")]
+
+use fakenotify_protocol::{
+    Capabilities, ClientInfo, ErrorCode, FrameKind, FramedMessage, PROTOCOL_VERSION, Request,
+    Response, get_socket_path_with_xdg_fallback,
+};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::ffi::{CStr, c_char, c_int};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, OnceLock, mpsc};
+use std::thread;
+use std::time::Duration;
+
+/// Set to pass every call through to libinotify
+const DISABLE_ENV_VAR: &str = "FAKENOTIFY_DISABLE";
+
+/// How long to wait for the daemon's greeting and replies
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Optional protocol features this library can decode
+const CAPABILITIES: Capabilities = Capabilities::empty();
+
+/// Socket buffer size for the event pair, so bursts do not stall the pump
+const EVENT_BUFFER_SIZE: c_int = 256 * 1024;
+
+// ============================================================================
+// libinotify functions (resolved via dlsym)
+// ============================================================================
+
+type InotifyInitFn = unsafe extern "C" fn() -> c_int;
+type InotifyInit1Fn = unsafe extern "C" fn(c_int) -> c_int;
+type InotifyAddWatchFn = unsafe extern "C" fn(c_int, *const c_char, u32) -> c_int;
+type InotifyRmWatchFn = unsafe extern "C" fn(c_int, c_int) -> c_int;
+
+static REAL_INOTIFY_INIT: OnceLock<Option<InotifyInitFn>> = OnceLock::new();
+static REAL_INOTIFY_INIT1: OnceLock<Option<InotifyInit1Fn>> = OnceLock::new();
+static REAL_INOTIFY_ADD_WATCH: OnceLock<Option<InotifyAddWatchFn>> = OnceLock::new();
+static REAL_INOTIFY_RM_WATCH: OnceLock<Option<InotifyRmWatchFn>> = OnceLock::new();
+
+/// Look up the next definition of `name` after ours, once
+fn real<F: Copy>(cell: &OnceLock<Option<F>>, name: &CStr) -> Option<F> {
+    *cell.get_or_init(|| {
+        // SAFETY: name is a valid C string
+        let sym = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr()) };
+        // SAFETY: F is the function pointer type of the symbol we looked up
+        (!sym.is_null()).then(|| unsafe { std::mem::transmute_copy(&sym) })
+    })
+}
+
+fn set_errno(err: c_int) {
+    // SAFETY: __error returns a valid pointer to the thread-local errno
+    unsafe { *libc::__error() = err };
+}
+
+fn disabled() -> bool {
+    static DISABLED: OnceLock<bool> = OnceLock::new();
+    *DISABLED
+        .get_or_init(|| std::env::var(DISABLE_ENV_VAR).is_ok_and(|v| !v.is_empty() && v != "0"))
+}
+
+// ============================================================================
+// Instances
+// ============================================================================
+
+/// One emulated inotify instance
+struct Instance {
+    /// Identity of the app's end, to notice the fd number being reused
+    key: (u64, u64),
+    /// Daemon connection used for requests; the pump thread reads a clone
+    stream: Mutex<UnixStream>,
+    /// Responses split off the daemon stream by the pump thread
+    responses: Mutex<mpsc::Receiver<Response>>,
+}
+
+impl Instance {
+    fn request(&self, request: &Request) -> Option<Response> {
+        let payload = request.to_bytes().ok()?;
+        self.stream
+            .lock()
+            .write_all(&FramedMessage::frame(&payload))
+            .ok()?;
+        self.responses.lock().recv_timeout(REQUEST_TIMEOUT).ok()
+    }
+}
+
+/// Instances by the fd the app holds
+static INSTANCES: LazyLock<Mutex<HashMap<c_int, Arc<Instance>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn fstat_key(fd: RawFd) -> Option<(u64, u64)> {
+    // SAFETY: stat is plain old data and fstat fills it in
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    // SAFETY: st is a valid, writable stat buffer
+    if unsafe { libc::fstat(fd, &mut st) } != 0 {
+        return None;
+    }
+    Some((st.st_dev as u64, st.st_ino))
+}
+
+/// The instance behind `fd`, if it is still the socket we handed out
+fn instance_for(fd: c_int) -> Option<Arc<Instance>> {
+    let instance = INSTANCES.lock().get(&fd).cloned()?;
+    (fstat_key(fd) == Some(instance.key)).then_some(instance)
+}
+
+// ============================================================================
+// Daemon connection
+// ============================================================================
+
+fn read_frame(stream: &mut UnixStream) -> std::io::Result<(FrameKind, Vec<u8>)> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix)?;
+    let (kind, len) =
+        FramedMessage::read_header(&prefix).ok_or(std::io::ErrorKind::UnexpectedEof)?;
+    let len = len as usize;
+    if len > FramedMessage::MAX_SIZE {
+        return Err(std::io::ErrorKind::InvalidData.into());
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((kind, payload))
+}
+
+fn set_flag(fd: RawFd, get: c_int, set: c_int, flag: c_int) {
+    // SAFETY: fd is a valid descriptor we own
+    unsafe {
+        let current = libc::fcntl(fd, get);
+        libc::fcntl(fd, set, current | flag);
+    }
+}
+
+fn set_buffer_sizes(fd: RawFd) {
+    let size = EVENT_BUFFER_SIZE;
+    for opt in [libc::SO_SNDBUF, libc::SO_RCVBUF] {
+        // SAFETY: the option value is a valid c_int
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                (&raw const size).cast(),
+                size_of::<c_int>() as libc::socklen_t,
+            )
+        };
+    }
+}
+
+/// Deliver one event datagram, waiting while the app's queue is full
+///
+/// Darwin fails datagram sends to a full receiver with `ENOBUFS` instead of
+/// blocking. Returns false once the app has closed its end.
+fn deliver(ours: RawFd, payload: &[u8]) -> bool {
+    loop {
+        // SAFETY: payload is a valid buffer; one datagram per event
+        if unsafe { libc::send(ours, payload.as_ptr().cast(), payload.len(), 0) } >= 0 {
+            return true;
+        }
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENOBUFS | libc::EAGAIN) => thread::sleep(Duration::from_millis(1)),
+            Some(libc::EINTR) => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Forward events from the daemon to the app until either side goes away
+fn pump(
+    mut daemon: UnixStream,
+    ours: OwnedFd,
+    responses: mpsc::Sender<Response>,
+    fd: c_int,
+    key: (u64, u64),
+) {
+    while let Ok((kind, payload)) = read_frame(&mut daemon) {
+        if kind == FrameKind::Event {
+            if !deliver(ours.as_raw_fd(), &payload) {
+                break;
+            }
+        } else if let Ok(Response::Heartbeat) = Response::from_bytes(&payload) {
+            // One small write, so it cannot interleave with a request frame
+            if let Ok(ack) = Request::HeartbeatAck.to_bytes() {
+                let _ = daemon.write_all(&FramedMessage::frame(&ack));
+            }
+        } else if let Ok(response) = Response::from_bytes(&payload) {
+            let _ = responses.send(response);
+        }
+    }
+
+    // Unblock any request still waiting before touching the map
+    drop(responses);
+    let _ = daemon.shutdown(std::net::Shutdown::Both);
+    let mut instances = INSTANCES.lock();
+    if instances.get(&fd).is_some_and(|i| i.key == key) {
+        instances.remove(&fd);
+    }
+}
+
+/// This process as it describes itself to the daemon
+fn client_info() -> ClientInfo {
+    let name = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()));
+    ClientInfo {
+        name,
+        pid: Some(std::process::id() as i32),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    }
+}
+
+/// Connect to the daemon and hand back the app's end of a new instance
+fn init_instance(flags: c_int) -> Result<c_int, c_int> {
+    let mut daemon = UnixStream::connect(get_socket_path_with_xdg_fallback())
+        .map_err(|e| e.raw_os_error().unwrap_or(libc::ECONNREFUSED))?;
+    daemon
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|_| libc::EIO)?;
+    let greeting = read_frame(&mut daemon)
+        .ok()
+        .and_then(|(_, payload)| Response::from_bytes(&payload).ok());
+    match greeting {
+        Some(Response::ClientRegistered { .. }) => {}
+        // The kernel reports max_user_instances as EMFILE
+        Some(Response::LimitExceeded { .. }) => return Err(libc::EMFILE),
+        _ => return Err(libc::EIO),
+    }
+    // Name the instance for `fakenotifyd clients`; a daemon speaking
+    // another protocol version refuses it, and nothing else it sends could
+    // be read either
+    let request = Request::RegisterClient {
+        protocol_version: PROTOCOL_VERSION,
+        info: client_info(),
+        capabilities: CAPABILITIES.bits(),
+    };
+    if let Ok(payload) = request.to_bytes()
+        && daemon.write_all(&FramedMessage::frame(&payload)).is_ok()
+    {
+        let answer = read_frame(&mut daemon)
+            .ok()
+            .and_then(|(_, payload)| Response::from_bytes(&payload).ok());
+        if let Some(Response::Error {
+            code: code @ ErrorCode::VersionMismatch,
+            ..
+        }) = answer
+        {
+            return Err(code.errno());
+        }
+    }
+    daemon.set_read_timeout(None).map_err(|_| libc::EIO)?;
+    let reader = daemon.try_clone().map_err(|_| libc::EMFILE)?;
+
+    let mut fds = [0 as c_int; 2];
+    // SAFETY: fds has room for the two descriptors
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(libc::EMFILE);
+    }
+    // SAFETY: socketpair just returned these fds and nothing else owns them
+    let (app, ours) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    set_flag(
+        ours.as_raw_fd(),
+        libc::F_GETFD,
+        libc::F_SETFD,
+        libc::FD_CLOEXEC,
+    );
+    // A closed app end must fail send() rather than raise SIGPIPE
+    // SAFETY: the option value is a valid c_int
+    unsafe {
+        let on: c_int = 1;
+        libc::setsockopt(
+            ours.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_NOSIGPIPE,
+            (&raw const on).cast(),
+            size_of::<c_int>() as libc::socklen_t,
+        );
+    }
+    set_buffer_sizes(ours.as_raw_fd());
+    set_buffer_sizes(app.as_raw_fd());
+    // libinotify defines IN_NONBLOCK/IN_CLOEXEC as the O_* flags
+    if flags & libc::O_NONBLOCK != 0 {
+        set_flag(
+            app.as_raw_fd(),
+            libc::F_GETFL,
+            libc::F_SETFL,
+            libc::O_NONBLOCK,
+        );
+    }
+    if flags & libc::O_CLOEXEC != 0 {
+        set_flag(
+            app.as_raw_fd(),
+            libc::F_GETFD,
+            libc::F_SETFD,
+            libc::FD_CLOEXEC,
+        );
+    }
+
+    let key = fstat_key(app.as_raw_fd()).ok_or(libc::EMFILE)?;
+    let (tx, rx) = mpsc::channel();
+    let fd = app.into_raw_fd();
+    INSTANCES.lock().insert(
+        fd,
+        Arc::new(Instance {
+            key,
+            stream: Mutex::new(daemon),
+            responses: Mutex::new(rx),
+        }),
+    );
+    thread::spawn(move || pump(reader, ours, tx, fd, key));
+    Ok(fd)
+}
+
+// ============================================================================
+// Interposed functions
+// ============================================================================
+
+fn call_real_inotify_init1(flags: c_int) -> c_int {
+    // SAFETY: Passing through to libinotify with the caller's arguments
+    unsafe {
+        if let Some(f) = real(&REAL_INOTIFY_INIT1, c"inotify_init1") {
+            f(flags)
+        } else if let Some(f) = real(&REAL_INOTIFY_INIT, c"inotify_init") {
+            f()
+        } else {
+            set_errno(libc::ENOSYS);
+            -1
+        }
+    }
+}
+
+/// Intercepted inotify_init()
+#[unsafe(no_mangle)]
+pub extern "C" fn inotify_init() -> c_int {
+    inotify_init1(0)
+}
+
+/// Intercepted inotify_init1()
+///
+/// Falls back to libinotify if the daemon cannot be reached.
+#[unsafe(no_mangle)]
+pub extern "C" fn inotify_init1(flags: c_int) -> c_int {
+    std::panic::catch_unwind(|| {
+        if disabled() {
+            return call_real_inotify_init1(flags);
+        }
+        match init_instance(flags) {
+            Ok(fd) => fd,
+            Err(libc::EMFILE) => {
+                set_errno(libc::EMFILE);
+                -1
+            }
+            Err(_) => call_real_inotify_init1(flags),
+        }
+    })
+    .unwrap_or_else(|_| {
+        set_errno(libc::EIO);
+        -1
+    })
+}
+
+/// Intercepted inotify_add_watch()
+///
+/// # Safety
+///
+/// The pathname must be a valid C string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int {
+    std::panic::catch_unwind(|| {
+        let Some(instance) = instance_for(fd) else {
+            // SAFETY: Passing through to libinotify with the caller's arguments
+            return match real(&REAL_INOTIFY_ADD_WATCH, c"inotify_add_watch") {
+                Some(f) => unsafe { f(fd, pathname, mask) },
+                None => {
+                    set_errno(libc::EBADF);
+                    -1
+                }
+            };
+        };
+
+        // SAFETY: Caller guarantees pathname is a valid C string
+        let path = match unsafe { CStr::from_ptr(pathname) }.to_str() {
+            Ok(s) => PathBuf::from(s),
+            Err(_) => {
+                set_errno(libc::EINVAL);
+                return -1;
+            }
+        };
+
+        match instance.request(&Request::AddWatch { path, mask }) {
+            Some(Response::WatchAdded { wd }) => wd,
+            Some(Response::LimitExceeded { .. }) => {
+                // The kernel reports max_user_watches as ENOSPC
+                set_errno(libc::ENOSPC);
+                -1
+            }
+            Some(Response::Error { code, .. }) => {
+                set_errno(code.errno());
+                -1
+            }
+            _ => {
+                set_errno(libc::EIO);
+                -1
+            }
+        }
+    })
+    .unwrap_or_else(|_| {
+        set_errno(libc::EIO);
+        -1
+    })
+}
+
+/// Intercepted inotify_rm_watch()
+#[unsafe(no_mangle)]
+pub extern "C" fn inotify_rm_watch(fd: c_int, wd: c_int) -> c_int {
+    std::panic::catch_unwind(|| {
+        let Some(instance) = instance_for(fd) else {
+            // SAFETY: Passing through to libinotify with the caller's arguments
+            return match real(&REAL_INOTIFY_RM_WATCH, c"inotify_rm_watch") {
+                Some(f) => unsafe { f(fd, wd) },
+                None => {
+                    set_errno(libc::EBADF);
+                    -1
+                }
+            };
+        };
+
+        match instance.request(&Request::RemoveWatch { wd }) {
+            Some(Response::WatchRemoved) => 0,
+            Some(Response::Error { code, .. }) => {
+                set_errno(code.errno());
+                -1
+            }
+            _ => {
+                set_errno(libc::EIO);
+                -1
+            }
+        }
+    })
+    .unwrap_or_else(|_| {
+        set_errno(libc::EIO);
+        -1
+    })
+}
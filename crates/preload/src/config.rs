@@ -0,0 +1,621 @@
+//! Environment-driven routing configuration for the preload library.
+//!
+//! - `FAKENOTIFY_DISABLE=1` passes everything through to real inotify
+//! - `FAKENOTIFY_ONLY_PATHS=/mnt/nfs:/mnt/media` sends only these prefixes
+//!   to the daemon
+//! - `FAKENOTIFY_EXCLUDE_PATHS=/mnt/media/cache` keeps these prefixes on
+//!   real inotify, even inside an ONLY prefix
+//! - `FAKENOTIFY_HYBRID=1` sends only paths on network/FUSE mounts to the
+//!   daemon and keeps local paths on real inotify; the prefix rules still
+//!   apply on top
+//! - `FAKENOTIFY_CONNECT_TIMEOUT_MS=2000` bounds how long `inotify_init`
+//!   waits for the daemon (default 60000)
+//! - `FAKENOTIFY_RETRY=0` caps the number of reconnect attempts within that
+//!   time (default unlimited)
+//! - `FAKENOTIFY_FALLBACK=real|fail` picks what happens when the daemon
+//!   cannot be reached: use real inotify (default) or fail the call
+//! - `FAKENOTIFY_PATH_MAP=/data=/srv/media` tells the daemon where paths
+//!   seen inside a container live on its side
+//! - `FAKENOTIFY_EXTENSIONS=mkv,jpg` and `FAKENOTIFY_NAME_REGEX=^[^.]` ask
+//!   the daemon to send only matching file events
+//! - `FAKENOTIFY_AUTOSPAWN=1` starts a per-user daemon when none can be
+//!   reached; `FAKENOTIFY_DAEMON=/path/to/fakenotifyd` picks the binary
+//!   (default: `fakenotifyd` on `PATH`)
+//! - `FAKENOTIFY_BUFFER_EVENTS=16384` caps the events queued per fd while
+//!   the app is not reading; beyond that they are dropped and the app gets
+//!   IN_Q_OVERFLOW (0 = unbounded)
+//! - `FAKENOTIFY_ERRNO_MAP=not_found=EACCES,max_user_watches=ENOSPC`
+//!   overrides the errno reported when the daemon refuses a request
+//! - `FAKENOTIFY_CONTROL_CONNECTION=1` sends watch requests over a
+//!   short-lived connection of their own, leaving the fd's connection to
+//!   events
+//! - `FAKENOTIFY_NAMESPACE=media` puts the process's watches in that
+//!   namespace on a multi-tenant daemon
+//! - `FAKENOTIFY_EXEC=close|reattach|keep` picks what exec does to inotify
+//!   fds the app left open across it: close them (default), hand them over
+//!   to the new image, or leave them as they are
+//!
+//! The environment is read once, on first use.
+
+use fakenotify_protocol::{
+    EXTENSIONS_ENV_VAR, ErrorCode, NAME_REGEX_ENV_VAR, NameFilter, PATH_MAP_ENV_VAR, PathMapping,
+    Response, parse_extensions, parse_path_map,
+};
+use std::ffi::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Disable the daemon entirely for this process
+pub const DISABLE_ENV_VAR: &str = "FAKENOTIFY_DISABLE";
+/// Colon-separated prefixes that go to the daemon
+pub const ONLY_PATHS_ENV_VAR: &str = "FAKENOTIFY_ONLY_PATHS";
+/// Colon-separated prefixes that stay on real inotify
+pub const EXCLUDE_PATHS_ENV_VAR: &str = "FAKENOTIFY_EXCLUDE_PATHS";
+/// Route by mount type instead of sending everything to the daemon
+pub const HYBRID_ENV_VAR: &str = "FAKENOTIFY_HYBRID";
+/// How long to keep trying to reach the daemon, in milliseconds
+pub const CONNECT_TIMEOUT_ENV_VAR: &str = "FAKENOTIFY_CONNECT_TIMEOUT_MS";
+/// Maximum reconnect attempts after the first failure
+pub const RETRY_ENV_VAR: &str = "FAKENOTIFY_RETRY";
+/// `real` or `fail` when the daemon is unreachable
+pub const FALLBACK_ENV_VAR: &str = "FAKENOTIFY_FALLBACK";
+/// Start a per-user daemon when none is reachable
+pub const AUTOSPAWN_ENV_VAR: &str = "FAKENOTIFY_AUTOSPAWN";
+/// Daemon binary started by autospawn
+pub const DAEMON_ENV_VAR: &str = "FAKENOTIFY_DAEMON";
+/// Most events queued per fd before dropping
+pub const BUFFER_EVENTS_ENV_VAR: &str = "FAKENOTIFY_BUFFER_EVENTS";
+/// Comma-separated `key=errno` overrides for refused requests
+pub const ERRNO_MAP_ENV_VAR: &str = "FAKENOTIFY_ERRNO_MAP";
+/// Send watch requests over separate control connections
+pub const CONTROL_CONNECTION_ENV_VAR: &str = "FAKENOTIFY_CONTROL_CONNECTION";
+/// Namespace to join on a multi-tenant daemon
+pub const NAMESPACE_ENV_VAR: &str = "FAKENOTIFY_NAMESPACE";
+/// `close`, `reattach` or `keep` for managed fds that survive exec
+pub const EXEC_ENV_VAR: &str = "FAKENOTIFY_EXEC";
+
+/// Errno map key for a daemon that did not answer at all
+pub const UNAVAILABLE: &str = "unavailable";
+
+/// Default time `inotify_init` waits for the daemon
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default per-fd event queue size, matching the kernel's
+/// `max_queued_events`
+pub const DEFAULT_BUFFER_EVENTS: usize = 16384;
+
+static CONFIG: OnceLock<PreloadConfig> = OnceLock::new();
+
+/// Where a watch is served from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Emulated by fakenotifyd
+    Daemon,
+    /// Real kernel inotify
+    Kernel,
+}
+
+/// What `inotify_init` does when the daemon cannot be reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fallback {
+    /// Silently hand out a real inotify fd
+    #[default]
+    Real,
+    /// Fail the call with the connect error
+    Fail,
+}
+
+/// What exec does to managed fds opened without IN_CLOEXEC
+///
+/// Only the fd the app holds can survive exec: the connection behind it is
+/// always close-on-exec, so left alone the fd would be a bare eventfd in the
+/// new image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecPolicy {
+    /// Close them, so the new image gets EBADF rather than a dead fd
+    #[default]
+    Close,
+    /// Hand the connection and queued events over to the new image
+    Reattach,
+    /// Leave them alone
+    Keep,
+}
+
+impl ExecPolicy {
+    /// Parse a variable value, falling back to the default
+    pub fn parse(value: Option<&str>) -> Self {
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            Some("reattach") => Self::Reattach,
+            Some("keep") => Self::Keep,
+            _ => Self::Close,
+        }
+    }
+}
+
+/// How hard to try to reach the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectPolicy {
+    /// Give up once this much time has passed
+    pub timeout: Duration,
+    /// Give up after this many retries (`None` = until the timeout)
+    pub retries: Option<u32>,
+    /// What to do after giving up
+    pub fallback: Fallback,
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+            retries: None,
+            fallback: Fallback::Real,
+        }
+    }
+}
+
+impl ConnectPolicy {
+    /// Parse from raw variable values, ignoring malformed ones
+    pub fn from_vars(
+        timeout_ms: Option<&str>,
+        retry: Option<&str>,
+        fallback: Option<&str>,
+    ) -> Self {
+        let defaults = Self::default();
+        Self {
+            timeout: timeout_ms
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.timeout),
+            retries: retry.and_then(|v| v.trim().parse().ok()),
+            fallback: match fallback.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+                Some("fail" | "error") => Fallback::Fail,
+                _ => Fallback::Real,
+            },
+        }
+    }
+}
+
+/// The errno reported for each way the daemon can refuse a request
+///
+/// Keys are [`ErrorCode`] names (`not_found`, `no_such_watch`, ...), which
+/// include the limits (`max_user_watches`, `max_user_instances`), and
+/// `unavailable` for no answer. Keys without an override get what real inotify would report.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrnoMap {
+    overrides: Vec<(String, c_int)>,
+}
+
+impl ErrnoMap {
+    /// Parse `key=errno` pairs, where errno is a name (`ENOENT`) or number;
+    /// malformed pairs are ignored
+    pub fn parse(value: &str) -> Self {
+        Self {
+            overrides: value
+                .split(',')
+                .filter_map(|pair| {
+                    let (key, errno) = pair.split_once('=')?;
+                    Some((key.trim().to_ascii_lowercase(), parse_errno(errno)?))
+                })
+                .collect(),
+        }
+    }
+
+    /// The errno for `key`
+    pub fn errno(&self, key: &str) -> c_int {
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map_or_else(|| default_errno(key), |&(_, errno)| errno)
+    }
+
+    /// The errno for a request answered with `response` instead of success
+    pub fn for_response(&self, response: Option<&Response>) -> c_int {
+        match response {
+            Some(Response::Error { code, .. }) => self.errno(code.name()),
+            Some(Response::LimitExceeded { limit }) => self.errno(limit),
+            _ => self.errno(UNAVAILABLE),
+        }
+    }
+}
+
+/// What the kernel reports for `key`
+fn default_errno(key: &str) -> c_int {
+    match key {
+        UNAVAILABLE => libc::EIO,
+        _ => ErrorCode::from_name(key).map_or(libc::EINVAL, ErrorCode::errno),
+    }
+}
+
+/// Errnos that make sense for inotify calls, by name
+const ERRNO_NAMES: &[(&str, c_int)] = &[
+    ("EACCES", libc::EACCES),
+    ("EBADF", libc::EBADF),
+    ("EFAULT", libc::EFAULT),
+    ("EINVAL", libc::EINVAL),
+    ("EIO", libc::EIO),
+    ("ELOOP", libc::ELOOP),
+    ("EMFILE", libc::EMFILE),
+    ("ENAMETOOLONG", libc::ENAMETOOLONG),
+    ("ENFILE", libc::ENFILE),
+    ("ENODEV", libc::ENODEV),
+    ("ENOENT", libc::ENOENT),
+    ("ENOMEM", libc::ENOMEM),
+    ("ENOSPC", libc::ENOSPC),
+    ("ENOSYS", libc::ENOSYS),
+    ("ENOTDIR", libc::ENOTDIR),
+    ("EPERM", libc::EPERM),
+];
+
+/// Parse an errno given by name or number
+fn parse_errno(value: &str) -> Option<c_int> {
+    let value = value.trim();
+    if let Ok(errno) = value.parse::<c_int>() {
+        return (errno > 0).then_some(errno);
+    }
+    ERRNO_NAMES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+        .map(|&(_, errno)| errno)
+}
+
+/// Routing rules parsed from the environment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreloadConfig {
+    /// Bypass the daemon for every call
+    pub disabled: bool,
+    /// If non-empty, only these prefixes go to the daemon
+    pub only_paths: Vec<PathBuf>,
+    /// Prefixes that never go to the daemon
+    pub exclude_paths: Vec<PathBuf>,
+    /// Only send paths on remote filesystems to the daemon
+    pub hybrid: bool,
+    /// Daemon connection behavior
+    pub connect: ConnectPolicy,
+    /// Client-to-host path translation sent to the daemon on connect
+    pub path_map: Vec<PathMapping>,
+    /// Event name filter sent to the daemon on connect
+    pub filter: NameFilter,
+    /// Most events queued per fd (0 = unbounded)
+    pub buffer_events: usize,
+    /// Daemon binary to start when none is reachable, if autospawn is on
+    pub autospawn: Option<PathBuf>,
+    /// Errno reported when the daemon refuses a request
+    pub errno_map: ErrnoMap,
+    /// Send watch requests over control connections, if the daemon
+    /// supports them
+    pub control_connection: bool,
+    /// Namespace sent to the daemon on connect
+    pub namespace: Option<String>,
+    /// What exec does to managed fds that would survive it
+    pub exec: ExecPolicy,
+}
+
+impl PreloadConfig {
+    /// Parse from raw variable values
+    pub fn from_vars(disable: Option<&str>, only: Option<&str>, exclude: Option<&str>) -> Self {
+        Self {
+            disabled: disable.is_some_and(is_truthy),
+            only_paths: parse_path_list(only),
+            exclude_paths: parse_path_list(exclude),
+            hybrid: false,
+            connect: ConnectPolicy::default(),
+            path_map: Vec::new(),
+            filter: NameFilter::default(),
+            buffer_events: DEFAULT_BUFFER_EVENTS,
+            autospawn: None,
+            errno_map: ErrnoMap::default(),
+            control_connection: false,
+            namespace: None,
+            exec: ExecPolicy::default(),
+        }
+    }
+
+    /// Enable or disable hybrid (mount-type) routing
+    pub fn with_hybrid(mut self, hybrid: bool) -> Self {
+        self.hybrid = hybrid;
+        self
+    }
+
+    /// Set the daemon connection behavior
+    pub fn with_connect(mut self, connect: ConnectPolicy) -> Self {
+        self.connect = connect;
+        self
+    }
+
+    /// Set the path translation to send to the daemon
+    pub fn with_path_map(mut self, path_map: Vec<PathMapping>) -> Self {
+        self.path_map = path_map;
+        self
+    }
+
+    /// Set the event name filter to send to the daemon
+    pub fn with_filter(mut self, filter: NameFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Set the per-fd event queue size
+    pub fn with_buffer_events(mut self, buffer_events: usize) -> Self {
+        self.buffer_events = buffer_events;
+        self
+    }
+
+    /// Start `daemon` when no daemon is reachable (`None` disables)
+    pub fn with_autospawn(mut self, daemon: Option<PathBuf>) -> Self {
+        self.autospawn = daemon;
+        self
+    }
+
+    /// Set the errno overrides for refused requests
+    pub fn with_errno_map(mut self, errno_map: ErrnoMap) -> Self {
+        self.errno_map = errno_map;
+        self
+    }
+
+    /// Send watch requests over control connections
+    pub fn with_control_connection(mut self, control_connection: bool) -> Self {
+        self.control_connection = control_connection;
+        self
+    }
+
+    /// Join `namespace` on multi-tenant daemons
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Set what exec does to managed fds
+    pub fn with_exec(mut self, exec: ExecPolicy) -> Self {
+        self.exec = exec;
+        self
+    }
+
+    /// Parse from the process environment
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self::from_vars(
+            var(DISABLE_ENV_VAR).as_deref(),
+            var(ONLY_PATHS_ENV_VAR).as_deref(),
+            var(EXCLUDE_PATHS_ENV_VAR).as_deref(),
+        )
+        .with_hybrid(var(HYBRID_ENV_VAR).as_deref().is_some_and(is_truthy))
+        .with_connect(ConnectPolicy::from_vars(
+            var(CONNECT_TIMEOUT_ENV_VAR).as_deref(),
+            var(RETRY_ENV_VAR).as_deref(),
+            var(FALLBACK_ENV_VAR).as_deref(),
+        ))
+        .with_path_map(parse_path_map(
+            var(PATH_MAP_ENV_VAR).as_deref().unwrap_or_default(),
+        ))
+        .with_filter(NameFilter {
+            extensions: parse_extensions(var(EXTENSIONS_ENV_VAR).as_deref().unwrap_or_default()),
+            regex: var(NAME_REGEX_ENV_VAR).filter(|regex| !regex.is_empty()),
+        })
+        .with_buffer_events(
+            var(BUFFER_EVENTS_ENV_VAR)
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(DEFAULT_BUFFER_EVENTS),
+        )
+        .with_autospawn(
+            var(AUTOSPAWN_ENV_VAR)
+                .as_deref()
+                .is_some_and(is_truthy)
+                .then(|| PathBuf::from(var(DAEMON_ENV_VAR).unwrap_or("fakenotifyd".to_string()))),
+        )
+        .with_errno_map(ErrnoMap::parse(
+            var(ERRNO_MAP_ENV_VAR).as_deref().unwrap_or_default(),
+        ))
+        .with_control_connection(
+            var(CONTROL_CONNECTION_ENV_VAR)
+                .as_deref()
+                .is_some_and(is_truthy),
+        )
+        .with_namespace(var(NAMESPACE_ENV_VAR).filter(|namespace| !namespace.is_empty()))
+        .with_exec(ExecPolicy::parse(var(EXEC_ENV_VAR).as_deref()))
+    }
+
+    /// Whether any path could be served by real inotify
+    pub fn has_path_rules(&self) -> bool {
+        self.hybrid || !self.only_paths.is_empty() || !self.exclude_paths.is_empty()
+    }
+
+    /// Decide where a watch on `path` is served from
+    ///
+    /// Matching is by whole path components, so `/mnt/nfs` does not match
+    /// `/mnt/nfs2`. Relative paths are resolved against the current directory.
+    pub fn route(&self, path: &Path) -> Route {
+        self.route_with(path, |p| crate::mounts::is_remote_fs(p).unwrap_or(false))
+    }
+
+    /// [`route`](Self::route) with an injectable remote-filesystem check
+    ///
+    /// Paths that cannot be stat'ed count as local in hybrid mode, so the
+    /// kernel reports the error (e.g. ENOENT) exactly as it would without us.
+    pub fn route_with(&self, path: &Path, is_remote: impl Fn(&Path) -> bool) -> Route {
+        if self.disabled {
+            return Route::Kernel;
+        }
+        if !self.has_path_rules() {
+            return Route::Daemon;
+        }
+
+        let absolute;
+        let path = if path.is_relative() {
+            absolute = std::env::current_dir()
+                .map(|cwd| cwd.join(path))
+                .unwrap_or_else(|_| path.to_path_buf());
+            absolute.as_path()
+        } else {
+            path
+        };
+
+        if !self.only_paths.is_empty() && !self.only_paths.iter().any(|p| path.starts_with(p)) {
+            return Route::Kernel;
+        }
+        if self.exclude_paths.iter().any(|p| path.starts_with(p)) {
+            return Route::Kernel;
+        }
+        if self.hybrid && !is_remote(path) {
+            return Route::Kernel;
+        }
+        Route::Daemon
+    }
+}
+
+/// The process-wide configuration, read from the environment on first use
+pub fn config() -> &'static PreloadConfig {
+    CONFIG.get_or_init(PreloadConfig::from_env)
+}
+
+fn is_truthy(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+fn parse_path_list(value: Option<&str>) -> Vec<PathBuf> {
+    value
+        .unwrap_or_default()
+        .split(':')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_routes_everything_to_daemon() {
+        let config = PreloadConfig::from_vars(None, None, None);
+        assert!(!config.disabled);
+        assert_eq!(config.route(Path::new("/home/user")), Route::Daemon);
+    }
+
+    #[test]
+    fn test_disable() {
+        for value in ["1", "true", "YES", "on"] {
+            let config = PreloadConfig::from_vars(Some(value), None, None);
+            assert_eq!(
+                config.route(Path::new("/mnt/nfs")),
+                Route::Kernel,
+                "{value}"
+            );
+        }
+        let config = PreloadConfig::from_vars(Some("0"), None, None);
+        assert!(!config.disabled);
+    }
+
+    #[test]
+    fn test_only_paths() {
+        let config = PreloadConfig::from_vars(None, Some("/mnt/nfs:/mnt/media:"), None);
+        assert_eq!(config.only_paths.len(), 2);
+        assert_eq!(config.route(Path::new("/mnt/nfs/tv")), Route::Daemon);
+        assert_eq!(config.route(Path::new("/mnt/media")), Route::Daemon);
+        assert_eq!(config.route(Path::new("/mnt/nfs2")), Route::Kernel);
+        assert_eq!(config.route(Path::new("/home")), Route::Kernel);
+    }
+
+    #[test]
+    fn test_exclude_wins_over_only() {
+        let config = PreloadConfig::from_vars(None, Some("/mnt/media"), Some("/mnt/media/cache"));
+        assert_eq!(config.route(Path::new("/mnt/media/movies")), Route::Daemon);
+        assert_eq!(config.route(Path::new("/mnt/media/cache/x")), Route::Kernel);
+    }
+
+    #[test]
+    fn test_hybrid_routes_by_mount_type() {
+        let is_remote = |p: &Path| p.starts_with("/mnt/nfs");
+        let config =
+            PreloadConfig::from_vars(None, None, Some("/mnt/nfs/scratch")).with_hybrid(true);
+
+        assert_eq!(
+            config.route_with(Path::new("/mnt/nfs/tv"), is_remote),
+            Route::Daemon
+        );
+        assert_eq!(
+            config.route_with(Path::new("/home/user"), is_remote),
+            Route::Kernel
+        );
+        assert_eq!(
+            config.route_with(Path::new("/mnt/nfs/scratch/a"), is_remote),
+            Route::Kernel
+        );
+    }
+
+    #[test]
+    fn test_connect_policy() {
+        assert_eq!(
+            ConnectPolicy::from_vars(None, None, None),
+            ConnectPolicy::default()
+        );
+
+        let policy = ConnectPolicy::from_vars(Some("250"), Some("0"), Some("FAIL"));
+        assert_eq!(policy.timeout, Duration::from_millis(250));
+        assert_eq!(policy.retries, Some(0));
+        assert_eq!(policy.fallback, Fallback::Fail);
+
+        // Malformed values keep the defaults
+        let policy = ConnectPolicy::from_vars(Some("soon"), Some("-1"), Some("maybe"));
+        assert_eq!(policy, ConnectPolicy::default());
+    }
+
+    #[test]
+    fn test_exec_policy() {
+        assert_eq!(ExecPolicy::parse(None), ExecPolicy::Close);
+        assert_eq!(ExecPolicy::parse(Some(" Reattach ")), ExecPolicy::Reattach);
+        assert_eq!(ExecPolicy::parse(Some("keep")), ExecPolicy::Keep);
+        assert_eq!(ExecPolicy::parse(Some("sometimes")), ExecPolicy::Close);
+    }
+
+    #[test]
+    fn test_exclude_only() {
+        let config = PreloadConfig::from_vars(None, None, Some("/tmp"));
+        assert_eq!(config.route(Path::new("/tmp/x")), Route::Kernel);
+        assert_eq!(config.route(Path::new("/mnt/nfs")), Route::Daemon);
+    }
+
+    #[test]
+    fn test_errno_defaults_match_kernel() {
+        let map = ErrnoMap::default();
+        let refusal = |code| Response::error_with(code, "refused");
+        let cases = [
+            (refusal(ErrorCode::NotFound), libc::ENOENT),
+            (refusal(ErrorCode::NotDirectory), libc::ENOTDIR),
+            (refusal(ErrorCode::NoSuchWatch), libc::EINVAL),
+            (refusal(ErrorCode::PermissionDenied), libc::EACCES),
+            (refusal(ErrorCode::Invalid), libc::EINVAL),
+            (refusal(ErrorCode::UnsupportedMask), libc::EINVAL),
+            (
+                Response::LimitExceeded {
+                    limit: "max_user_watches".to_string(),
+                },
+                libc::ENOSPC,
+            ),
+            (
+                Response::LimitExceeded {
+                    limit: "max_user_instances".to_string(),
+                },
+                libc::EMFILE,
+            ),
+        ];
+        for (response, errno) in cases {
+            assert_eq!(map.for_response(Some(&response)), errno, "{response:?}");
+        }
+        assert_eq!(map.for_response(None), libc::EIO);
+    }
+
+    #[test]
+    fn test_errno_overrides() {
+        let map = ErrnoMap::parse("not_found=EACCES, max_user_watches = 12,bogus,x=EWHAT");
+        assert_eq!(map.errno("not_found"), libc::EACCES);
+        assert_eq!(map.errno("max_user_watches"), 12);
+        assert_eq!(map.errno("not_directory"), libc::ENOTDIR);
+        assert_eq!(map.errno("x"), libc::EINVAL);
+    }
+}
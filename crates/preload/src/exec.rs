@@ -0,0 +1,632 @@
+//! Managed fds across exec.
+//!
+//! An inotify fd opened without IN_CLOEXEC outlives exec, but the
+//! connection behind our stand-in does not, and the new image's copy of
+//! this library knows nothing of it. The exec interposers settle that first,
+//! as `FAKENOTIFY_EXEC` says:
+//!
+//! - `close` marks such fds close-on-exec, so the new image finds them closed
+//! - `reattach` lets the connection and companion kernel fd survive as well,
+//!   and writes the fd's queued events and any partly received frame to a
+//!   memfd. `FAKENOTIFY_INHERIT=fd:socket:kernel:state,...` in the new
+//!   image's environment names them all. Its copy of this library takes
+//!   that out of the environment in the ctor, marking the fds close-on-exec
+//!   again so they do not leak into anything it starts, and adopts them on
+//!   its first intercepted call. The app fd is left readable, so an app that
+//!   waits on it before reading still gets that far.
+//! - `keep` leaves them alone
+//!
+//! If exec fails, the fds are put back as they were. A forked child shares
+//! its parent's connection, so it only ever closes the fds.
+
+use crate::config::ExecPolicy;
+use crate::identity::FileId;
+use crate::trace::trace;
+use crate::{
+    FD_BUFFERS, FdBuffer, INITIALIZED, raise_ready, register_fd, start_drain, with_buffer,
+};
+use fakenotify_protocol::FramedMessage;
+use parking_lot::MutexGuard;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::{CString, c_char, c_int};
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::sync::Once;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Names the fds handed over to a new image
+pub const INHERIT_ENV_VAR: &str = "FAKENOTIFY_INHERIT";
+
+/// Name of the memfds holding handed-over state
+const STATE_NAME: &str = "fakenotify-exec";
+
+/// Most fds one image can hand over
+const MAX_INHERITED: usize = 16;
+
+/// Handed-over fds found by the ctor, as `fd, socket, kernel, state`
+static INHERITED: [[AtomicI32; 4]; MAX_INHERITED] =
+    [const { [const { AtomicI32::new(-1) }; 4] }; MAX_INHERITED];
+
+/// Number of entries in `INHERITED`
+static INHERITED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs the adoption of handed-over fds once per image
+static ADOPT: Once = Once::new();
+
+thread_local! {
+    /// Set on the thread adopting fds, whose own intercepted calls (reading
+    /// the state, say) must not wait for that to finish
+    static ADOPTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe extern "C" {
+    static environ: *const *const c_char;
+}
+
+/// One managed fd handed to a new image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Handoff {
+    /// The fd the app holds
+    fd: c_int,
+    /// Connection to the daemon
+    socket: c_int,
+    /// Companion kernel inotify fd
+    kernel_fd: Option<c_int>,
+    /// memfd holding the queued events and partial frame
+    state: c_int,
+}
+
+impl Handoff {
+    /// Format as the value of [`INHERIT_ENV_VAR`]
+    fn format(handoffs: &[Handoff]) -> String {
+        handoffs
+            .iter()
+            .map(|h| {
+                format!(
+                    "{}:{}:{}:{}",
+                    h.fd,
+                    h.socket,
+                    h.kernel_fd.unwrap_or(-1),
+                    h.state
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Call `f` with each well-formed entry of an [`INHERIT_ENV_VAR`]
+    /// value; does not allocate, as the ctor uses it
+    fn each(value: &[u8], mut f: impl FnMut(Handoff)) {
+        for entry in value.split(|&b| b == b',') {
+            let mut fields = entry.split(|&b| b == b':').map(|field| {
+                std::str::from_utf8(field)
+                    .ok()
+                    .and_then(|field| field.trim().parse::<c_int>().ok())
+            });
+            let (
+                Some(Some(fd)),
+                Some(Some(socket)),
+                Some(Some(kernel_fd)),
+                Some(Some(state)),
+                None,
+            ) = (
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+                fields.next(),
+            )
+            else {
+                continue;
+            };
+            if fd >= 0 && socket >= 0 && state >= 0 {
+                f(Handoff {
+                    fd,
+                    socket,
+                    kernel_fd: (kernel_fd >= 0).then_some(kernel_fd),
+                    state,
+                });
+            }
+        }
+    }
+}
+
+/// The fd flags of `fd`, if it is open
+fn fd_flags(fd: c_int) -> Option<c_int> {
+    // SAFETY: fcntl F_GETFD has no memory-safety preconditions
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    (flags >= 0).then_some(flags)
+}
+
+/// Set or clear FD_CLOEXEC on `fd`, returning its previous fd flags
+pub fn set_cloexec(fd: c_int, on: bool) -> Option<c_int> {
+    let flags = fd_flags(fd)?;
+    // SAFETY: fcntl F_SETFD has no memory-safety preconditions
+    unsafe {
+        let wanted = if on {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        if wanted != flags {
+            libc::fcntl(fd, libc::F_SETFD, wanted);
+        }
+        Some(flags)
+    }
+}
+
+/// Managed fds made ready for an exec; put back as they were when dropped,
+/// which only happens if exec returns
+pub struct Prepared {
+    /// Held until exec when reattaching, so no thread takes bytes off a
+    /// connection after its state was saved
+    buffers: Option<MutexGuard<'static, Option<HashMap<c_int, FdBuffer>>>>,
+    /// fd flags to put back
+    flags: Vec<(c_int, c_int)>,
+    /// State memfds to close
+    memfds: Vec<c_int>,
+    /// `FAKENOTIFY_INHERIT=...` for the new image
+    handle: Option<CString>,
+}
+
+impl Prepared {
+    /// The environment entry naming handed-over fds, if any
+    pub fn handle(&self) -> Option<&CString> {
+        self.handle.as_ref()
+    }
+}
+
+impl Drop for Prepared {
+    fn drop(&mut self) {
+        // SAFETY: __errno_location returns a valid pointer to the thread-local errno
+        let errno = unsafe { libc::__errno_location() };
+        // SAFETY: errno is valid for this thread; the fds are ours
+        unsafe {
+            // Keep the errno the failed exec left
+            let saved = *errno;
+            for &(fd, flags) in &self.flags {
+                libc::fcntl(fd, libc::F_SETFD, flags);
+            }
+            for &memfd in &self.memfds {
+                libc::syscall(libc::SYS_close, memfd as libc::c_long);
+            }
+            *errno = saved;
+        }
+        self.buffers.take();
+    }
+}
+
+/// Get managed fds that would survive exec ready for it, per `policy`
+pub fn prepare(policy: ExecPolicy) -> Prepared {
+    let mut prepared = Prepared {
+        buffers: None,
+        flags: Vec::new(),
+        memfds: Vec::new(),
+        handle: None,
+    };
+    if policy == ExecPolicy::Keep {
+        return prepared;
+    }
+
+    // A vfork or fork child may have been made while another thread held
+    // the lock, in which case it is never released here
+    let Some(mut buffers) = FD_BUFFERS.try_lock_for(Duration::from_millis(100)) else {
+        trace!("exec: managed fds are locked, leaving them as they are");
+        return prepared;
+    };
+    let mut handoffs = Vec::new();
+    let pid = std::process::id();
+    for (&fd, buffer) in buffers.iter_mut().flatten() {
+        // A number now held by another file is none of our business
+        if !FileId::holds(buffer.app_id, fd) {
+            continue;
+        }
+        let Some(flags) = fd_flags(fd) else {
+            continue;
+        };
+        if flags & libc::FD_CLOEXEC != 0 {
+            // Closed by exec along with the connection; nothing to do
+            continue;
+        }
+        let state = match policy {
+            // A forked child leaves the connection to its parent
+            ExecPolicy::Reattach if buffer.pid == pid => save_state(buffer),
+            _ => None,
+        };
+        let Some(state) = state else {
+            set_cloexec(fd, true);
+            prepared.flags.push((fd, flags));
+            trace!("exec: closing fd={fd}");
+            continue;
+        };
+        for own in std::iter::once(buffer.socket).chain(buffer.kernel_fd) {
+            if let Some(flags) = set_cloexec(own, false) {
+                prepared.flags.push((own, flags));
+            }
+        }
+        prepared.memfds.push(state);
+        // Wake an app that waits on the fd before its first read
+        raise_ready(fd, buffer);
+        handoffs.push(Handoff {
+            fd,
+            socket: buffer.socket,
+            kernel_fd: buffer.kernel_fd,
+            state,
+        });
+        trace!("exec: handing over fd={fd}");
+    }
+
+    if !handoffs.is_empty() {
+        let entry = format!("{INHERIT_ENV_VAR}={}", Handoff::format(&handoffs));
+        prepared.handle = CString::new(entry).ok();
+        prepared.buffers = Some(buffers);
+    }
+    prepared
+}
+
+/// Write the events queued on a fd, then the frame partly received on it,
+/// to a fresh memfd that survives exec
+///
+/// Both go back in as they came off the socket, so the new image rebuilds
+/// the same queue and carries on with the same stream.
+fn save_state(buffer: &FdBuffer) -> Option<c_int> {
+    let name = CString::new(STATE_NAME).ok()?;
+    // SAFETY: name is a valid C string
+    let memfd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+    if memfd < 0 {
+        return None;
+    }
+    // SAFETY: memfd was just created and nothing else owns it
+    let mut file = unsafe { File::from_raw_fd(memfd) };
+    let mut state = Vec::new();
+    for event in &buffer.events {
+        state.extend(FramedMessage::frame_event(event));
+    }
+    state.extend_from_slice(buffer.partial.buffered());
+    file.write_all(&state).ok()?;
+    file.rewind().ok()?;
+    Some(file.into_raw_fd())
+}
+
+/// The environment to exec with: `envp` without any earlier handle, plus
+/// `handle`
+///
+/// Returns `None` when `envp` can be passed on as it is. A null `envp`
+/// stands for the process environment.
+pub fn environment(
+    envp: *const *const c_char,
+    handle: Option<&CString>,
+) -> Option<Vec<*const c_char>> {
+    let envp = if envp.is_null() {
+        // SAFETY: environ is the process environment, set up by libc
+        unsafe { environ }
+    } else {
+        envp
+    };
+    let prefix = format!("{INHERIT_ENV_VAR}=");
+    let mut entries = Vec::new();
+    let mut stale = false;
+    if !envp.is_null() {
+        // SAFETY: envp is a null-terminated array of C strings, as exec requires
+        unsafe {
+            let mut entry = envp;
+            while !(*entry).is_null() {
+                if std::ffi::CStr::from_ptr(*entry)
+                    .to_bytes()
+                    .starts_with(prefix.as_bytes())
+                {
+                    stale = true;
+                } else {
+                    entries.push(*entry);
+                }
+                entry = entry.add(1);
+            }
+        }
+    }
+    if handle.is_none() && !stale {
+        return None;
+    }
+    entries.extend(handle.map(|handle| handle.as_ptr()));
+    entries.push(std::ptr::null());
+    Some(entries)
+}
+
+/// Take the handle a previous image left in the environment out of it,
+/// and keep the fds it names from leaking into processes this one starts
+///
+/// Runs in the ctor, before anything else could start one, so it stays
+/// within what that allows: no allocation and no IO beyond fcntl.
+pub fn take_inherited() {
+    let name = c"FAKENOTIFY_INHERIT";
+    // SAFETY: name is a valid C string; the value is copied out below
+    // before the environment changes
+    let value = unsafe { libc::getenv(name.as_ptr()) };
+    if value.is_null() {
+        return;
+    }
+    // SAFETY: getenv returned a valid C string
+    remember(unsafe { std::ffi::CStr::from_ptr(value) }.to_bytes());
+    // SAFETY: name is a valid C string; the ctor runs before the app has
+    // threads that could read the environment at the same time
+    unsafe { libc::unsetenv(name.as_ptr()) };
+}
+
+/// Note the fds named by a handle for adoption, marking them close-on-exec
+fn remember(value: &[u8]) {
+    Handoff::each(value, |handoff| {
+        let index = INHERITED_COUNT.load(Ordering::Relaxed);
+        if index == MAX_INHERITED {
+            return;
+        }
+        let slot = &INHERITED[index];
+        for (field, fd) in slot.iter().zip([
+            handoff.fd,
+            handoff.socket,
+            handoff.kernel_fd.unwrap_or(-1),
+            handoff.state,
+        ]) {
+            field.store(fd, Ordering::Relaxed);
+        }
+        INHERITED_COUNT.store(index + 1, Ordering::Release);
+        for own in [handoff.socket, handoff.state]
+            .into_iter()
+            .chain(handoff.kernel_fd)
+        {
+            set_cloexec(own, true);
+        }
+    });
+}
+
+/// Adopt the fds the previous image handed over, once per image
+///
+/// Called on entry to the intercepted calls that take an fd, before they
+/// check whether it is ours.
+pub fn adopt_inherited() {
+    if ADOPT.is_completed() || !INITIALIZED.load(Ordering::SeqCst) || ADOPTING.get() {
+        return;
+    }
+    ADOPT.call_once(|| {
+        ADOPTING.set(true);
+        for slot in &INHERITED[..INHERITED_COUNT.load(Ordering::Acquire)] {
+            let [fd, socket, kernel_fd, state] =
+                slot.each_ref().map(|fd| fd.load(Ordering::Relaxed));
+            let handoff = Handoff {
+                fd,
+                socket,
+                kernel_fd: (kernel_fd >= 0).then_some(kernel_fd),
+                state,
+            };
+            let adopted = adopt(handoff);
+            trace!("exec: adopting fd={fd} -> {adopted}");
+        }
+        ADOPTING.set(false);
+    });
+}
+
+/// Take over one handed-over fd
+///
+/// The state memfd is closed once read, so a handle that made it into the
+/// environment of a later exec anyway names nothing.
+fn adopt(handoff: Handoff) -> bool {
+    let is_state =
+        std::fs::read_link(format!("/proc/self/fd/{}", handoff.state)).is_ok_and(|target| {
+            // Such as `/memfd:fakenotify-exec (deleted)`
+            target
+                .to_string_lossy()
+                .starts_with(&format!("/memfd:{STATE_NAME}"))
+        });
+    if !is_state {
+        return false;
+    }
+    // SAFETY: the memfd was handed to this image and only we know of it
+    let mut file = unsafe { File::from_raw_fd(handoff.state) };
+    let mut state = Vec::new();
+    if file.read_to_end(&mut state).is_err() {
+        return false;
+    }
+    drop(file);
+
+    register_fd(handoff.fd, handoff.socket);
+    with_buffer(handoff.fd, |b| {
+        b.kernel_fd = handoff.kernel_fd;
+        // Chosen by the previous image
+        b.instance_checked = true;
+        // Raised before exec
+        b.ready = true;
+        b.push_bytes(&state);
+    });
+    start_drain(handoff.fd);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MANAGED_FDS, is_managed_fd, read_managed, unregister_fd};
+    use fakenotify_protocol::{EventMask, InotifyEvent};
+    use std::os::unix::net::UnixStream;
+
+    /// Serializes tests that change fd flags across all managed fds
+    static EXEC_LOCK: parking_lot::Mutex<()> = parking_lot::Mutex::new(());
+
+    fn cloexec(fd: c_int) -> bool {
+        fd_flags(fd).is_some_and(|flags| flags & libc::FD_CLOEXEC != 0)
+    }
+
+    /// A managed fd opened without IN_CLOEXEC, and the daemon's end
+    fn managed() -> (c_int, UnixStream) {
+        let (ours, daemon) = UnixStream::pair().unwrap();
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, 0) };
+        register_fd(fd, ours.into_raw_fd());
+        (fd, daemon)
+    }
+
+    fn parse(value: &str) -> Vec<Handoff> {
+        let mut handoffs = Vec::new();
+        Handoff::each(value.as_bytes(), |handoff| handoffs.push(handoff));
+        handoffs
+    }
+
+    #[test]
+    fn test_handle_round_trip() {
+        let handoffs = [
+            Handoff {
+                fd: 5,
+                socket: 6,
+                kernel_fd: None,
+                state: 7,
+            },
+            Handoff {
+                fd: 8,
+                socket: 9,
+                kernel_fd: Some(10),
+                state: 11,
+            },
+        ];
+        let value = Handoff::format(&handoffs);
+        assert_eq!(value, "5:6:-1:7,8:9:10:11");
+        assert_eq!(parse(&value), handoffs);
+        assert!(parse("5:6,x:1:2:3,-1:2:3:4,1:2:3:4:5").is_empty());
+    }
+
+    #[test]
+    fn test_handle_is_remembered_close_on_exec() {
+        let (socket, _daemon) = UnixStream::pair().unwrap();
+        let socket = socket.into_raw_fd();
+        let mut pipe = [0; 2];
+        // SAFETY: pipe is a valid array of two fds
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+        for fd in [socket, pipe[0]] {
+            set_cloexec(fd, false);
+        }
+
+        // The "state" is a pipe, so adopting it elsewhere fails harmlessly
+        remember(format!("1000:{socket}:-1:{}", pipe[0]).as_bytes());
+        assert!(cloexec(socket) && cloexec(pipe[0]));
+        let count = INHERITED_COUNT.load(Ordering::Acquire);
+        let slot = INHERITED[..count]
+            .iter()
+            .map(|slot| slot.each_ref().map(|fd| fd.load(Ordering::Relaxed)))
+            .find(|slot| slot[0] == 1000);
+        assert_eq!(slot, Some([1000, socket, -1, pipe[0]]));
+        for fd in [socket, pipe[0], pipe[1]] {
+            // SAFETY: fd is ours
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    #[test]
+    fn test_environment_replaces_stale_handle() {
+        let entries = [c"PATH=/usr/bin", c"FAKENOTIFY_INHERIT=1:2:-1:3", c"HOME=/"];
+        let mut envp: Vec<*const c_char> = entries.iter().map(|entry| entry.as_ptr()).collect();
+        envp.push(std::ptr::null());
+
+        let handle = CString::new("FAKENOTIFY_INHERIT=4:5:-1:6").unwrap();
+        let env = environment(envp.as_ptr(), Some(&handle)).unwrap();
+        // SAFETY: env holds pointers into entries and handle, both alive
+        let env: Vec<&str> = env[..env.len() - 1]
+            .iter()
+            .map(|&entry| unsafe { std::ffi::CStr::from_ptr(entry) }.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            env,
+            ["PATH=/usr/bin", "HOME=/", "FAKENOTIFY_INHERIT=4:5:-1:6"]
+        );
+
+        // Nothing to change
+        let envp = [c"PATH=/usr/bin".as_ptr(), std::ptr::null()];
+        assert!(environment(envp.as_ptr(), None).is_none());
+    }
+
+    #[test]
+    fn test_connection_is_close_on_exec() {
+        let (ours, _daemon) = UnixStream::pair().unwrap();
+        set_cloexec(std::os::unix::io::AsRawFd::as_raw_fd(&ours), false);
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        let socket = ours.into_raw_fd();
+        register_fd(fd, socket);
+        assert!(cloexec(socket));
+        // SAFETY: fd is ours
+        assert_eq!(unsafe { crate::close(fd) }, 0);
+    }
+
+    #[test]
+    fn test_close_policy_marks_fds_until_exec_fails() {
+        let _guard = EXEC_LOCK.lock();
+        let (fd, _daemon) = managed();
+        assert!(!cloexec(fd));
+
+        let prepared = prepare(ExecPolicy::Close);
+        assert!(prepared.handle().is_none());
+        assert!(cloexec(fd));
+        drop(prepared);
+        assert!(!cloexec(fd));
+
+        drop(prepare(ExecPolicy::Keep));
+        assert!(!cloexec(fd));
+        // SAFETY: fd is ours
+        assert_eq!(unsafe { crate::close(fd) }, 0);
+    }
+
+    #[test]
+    fn test_reattach_carries_queue_and_partial_frame() {
+        let _guard = EXEC_LOCK.lock();
+        let (fd, mut daemon) = managed();
+        let events: Vec<Vec<u8>> = [b"a.mkv".as_slice(), b"b.mkv", b"c.mkv"]
+            .iter()
+            .map(|name| {
+                InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(name)
+            })
+            .collect();
+        // Two events queued and the third cut off mid-frame
+        let third = FramedMessage::frame_event(&events[2]);
+        let (head, tail) = third.split_at(7);
+        with_buffer(fd, |b| {
+            assert!(b.push_bytes(&FramedMessage::frame_event(&events[0])));
+            assert!(b.push_bytes(&FramedMessage::frame_event(&events[1])));
+            assert!(b.push_bytes(head));
+        });
+
+        let mut prepared = prepare(ExecPolicy::Reattach);
+        let handle = prepared.handle().unwrap().to_str().unwrap().to_string();
+        let value = handle.strip_prefix("FAKENOTIFY_INHERIT=").unwrap();
+        let handoff = *parse(value)
+            .iter()
+            .find(|handoff| handoff.fd == fd)
+            .unwrap();
+        assert!(!cloexec(handoff.socket));
+
+        // What exec would do: the state survives, this image's view of the fd does not
+        let memfds = std::mem::take(&mut prepared.memfds);
+        drop(prepared);
+        assert!(unregister_fd(fd).is_some());
+        assert!(!is_managed_fd(fd));
+
+        assert!(adopt(handoff));
+        assert!(is_managed_fd(fd) && !MANAGED_FDS.is_empty());
+        assert!(cloexec(handoff.socket));
+        // Used up, so a stale handle cannot adopt again
+        assert!(!adopt(handoff));
+        for memfd in memfds.into_iter().filter(|&memfd| memfd != handoff.state) {
+            // SAFETY: memfd is a state memfd of another test fd
+            unsafe { libc::close(memfd) };
+        }
+
+        daemon.write_all(tail).unwrap();
+        let mut out = [0u8; 256];
+        let mut read = Vec::new();
+        while read.len() < 3 * events[0].len() {
+            let n = read_managed(fd, &mut out);
+            assert!(n > 0);
+            read.extend_from_slice(&out[..n as usize]);
+        }
+        assert_eq!(read, events.concat());
+        // SAFETY: fd is ours
+        assert_eq!(unsafe { crate::close(fd) }, 0);
+    }
+}
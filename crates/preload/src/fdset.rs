@@ -0,0 +1,158 @@
+//! The set of fds managed by us.
+//!
+//! Every intercepted `read()` and `close()` in the process asks whether its
+//! fd is ours, so the answer must not take a lock. fds below
+//! [`BITMAP_FDS`], which covers the usual `RLIMIT_NOFILE`, are a bit each in
+//! a fixed atomic bitmap; the rare higher ones go to a locked set, which is
+//! only consulted for fds that high.
+
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::ffi::c_int;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// fds tracked in the bitmap
+pub const BITMAP_FDS: usize = 4096;
+
+const WORDS: usize = BITMAP_FDS / 64;
+
+/// A set of fds, lock-free below [`BITMAP_FDS`]
+pub struct FdSet {
+    bits: [AtomicU64; WORDS],
+    /// Members at or above `BITMAP_FDS`
+    above: RwLock<Option<HashSet<c_int>>>,
+    len: AtomicUsize,
+}
+
+impl FdSet {
+    pub const fn new() -> Self {
+        Self {
+            bits: [const { AtomicU64::new(0) }; WORDS],
+            above: RwLock::new(None),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// The word and bit for `fd`, if it is in the bitmap's range
+    fn slot(fd: c_int) -> Option<(usize, u64)> {
+        let fd = usize::try_from(fd).ok()?;
+        (fd < BITMAP_FDS).then(|| (fd / 64, 1 << (fd % 64)))
+    }
+
+    pub fn contains(&self, fd: c_int) -> bool {
+        if fd < 0 {
+            return false;
+        }
+        match Self::slot(fd) {
+            Some((word, bit)) => self.bits[word].load(Ordering::Acquire) & bit != 0,
+            None => self
+                .above
+                .read()
+                .as_ref()
+                .is_some_and(|set| set.contains(&fd)),
+        }
+    }
+
+    /// Add `fd`, returning whether it was new
+    pub fn insert(&self, fd: c_int) -> bool {
+        if fd < 0 {
+            return false;
+        }
+        let added = match Self::slot(fd) {
+            Some((word, bit)) => self.bits[word].fetch_or(bit, Ordering::AcqRel) & bit == 0,
+            None => self
+                .above
+                .write()
+                .get_or_insert_with(HashSet::new)
+                .insert(fd),
+        };
+        if added {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+        added
+    }
+
+    /// Remove `fd`, returning whether it was there
+    pub fn remove(&self, fd: c_int) -> bool {
+        if fd < 0 {
+            return false;
+        }
+        let removed = match Self::slot(fd) {
+            Some((word, bit)) => self.bits[word].fetch_and(!bit, Ordering::AcqRel) & bit != 0,
+            None => self
+                .above
+                .write()
+                .as_mut()
+                .is_some_and(|set| set.remove(&fd)),
+        };
+        if removed {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+        removed
+    }
+
+    /// Whether no fd is in the set, so hot paths can skip even the bitmap
+    pub fn is_empty(&self) -> bool {
+        self.len.load(Ordering::Relaxed) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitmap_and_high_fds() {
+        let set = FdSet::new();
+        assert!(set.is_empty());
+        for fd in [
+            0,
+            63,
+            64,
+            BITMAP_FDS as c_int - 1,
+            BITMAP_FDS as c_int,
+            1 << 20,
+        ] {
+            assert!(!set.contains(fd), "{fd}");
+            assert!(set.insert(fd), "{fd}");
+            assert!(!set.insert(fd), "{fd}");
+            assert!(set.contains(fd), "{fd}");
+        }
+        assert!(!set.contains(1) && !set.contains(65) && !set.contains(BITMAP_FDS as c_int + 1));
+        assert!(!set.insert(-1) && !set.contains(-1));
+
+        for fd in [
+            0,
+            63,
+            64,
+            BITMAP_FDS as c_int - 1,
+            BITMAP_FDS as c_int,
+            1 << 20,
+        ] {
+            assert!(set.remove(fd), "{fd}");
+            assert!(!set.remove(fd), "{fd}");
+            assert!(!set.contains(fd), "{fd}");
+        }
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_updates_to_one_word() {
+        let set = FdSet::new();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let set = &set;
+                scope.spawn(move || {
+                    for round in 0..1000 {
+                        // Threads share words but never an fd
+                        let fd = (round % 16) * 4 + thread;
+                        assert!(set.insert(fd));
+                        assert!(set.contains(fd));
+                        assert!(set.remove(fd));
+                    }
+                });
+            }
+        });
+        assert!(set.is_empty());
+    }
+}
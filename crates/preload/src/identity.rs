@@ -0,0 +1,81 @@
+//! Telling our fds from ones that reused their numbers.
+//!
+//! A managed fd closed behind our back (a raw `close` syscall, `dup2()` onto
+//! it, a close we never saw) frees its number for the next file the app
+//! opens, while our entry still claims it. So each entry records which files
+//! its app fd and connection were when registered, and intercepted calls
+//! check that before routing anything to them.
+//!
+//! Files are told apart by device and inode. Every socket has an inode of
+//! its own, which also tells apart two connections to the same daemon where
+//! `SO_PEERCRED` would not. Eventfds all share one anonymous inode, so an app
+//! fd whose number went to a regular file, pipe or socket is noticed, but not
+//! one that went to another eventfd or inotify fd; a number handed out by
+//! our own `inotify_init()` again replaces the old entry anyway.
+
+use std::ffi::c_int;
+use std::mem::MaybeUninit;
+
+/// The open file behind an fd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileId {
+    /// The file `fd` refers to now, if it is open
+    pub fn of(fd: c_int) -> Option<Self> {
+        if fd < 0 {
+            return None;
+        }
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: stat is a valid buffer for fstat to fill
+        if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        // SAFETY: fstat succeeded, so it filled stat in
+        let stat = unsafe { stat.assume_init() };
+        Some(Self {
+            dev: stat.st_dev,
+            ino: stat.st_ino,
+        })
+    }
+
+    /// Whether `fd` still refers to the file recorded as `recorded`; an fd
+    /// that could not be looked up when recorded is taken on trust
+    pub fn holds(recorded: Option<Self>, fd: c_int) -> bool {
+        recorded.is_none_or(|id| Self::of(fd) == Some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_reused_number_is_told_apart() {
+        let (first, _peer) = UnixStream::pair().unwrap();
+        let fd = first.as_raw_fd();
+        let recorded = FileId::of(fd);
+        assert!(recorded.is_some());
+        assert!(FileId::holds(recorded, fd));
+
+        // Another connection, then a regular file, behind the same number
+        let (second, _other) = UnixStream::pair().unwrap();
+        // SAFETY: fd is first's, which is replaced but stays owned by it
+        assert_eq!(unsafe { libc::dup2(second.as_raw_fd(), fd) }, fd);
+        assert!(!FileId::holds(recorded, fd));
+        let file = std::fs::File::open("/proc/self/status").unwrap();
+        // SAFETY: as above
+        assert_eq!(unsafe { libc::dup2(file.as_raw_fd(), fd) }, fd);
+        assert!(!FileId::holds(recorded, fd));
+
+        drop(first);
+        assert!(!FileId::holds(recorded, fd));
+        assert!(FileId::holds(None, fd));
+        assert_eq!(FileId::of(-1), None);
+    }
+}
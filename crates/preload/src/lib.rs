@@ -6,30 +6,62 @@
 //!
 //! # How it works
 //!
-//! 1. App calls `inotify_init()` -> We connect to daemon, return our socket fd
-//! 2. App calls `inotify_add_watch(fd, path, mask)` -> We send AddWatch to daemon
-//! 3. App calls `read(fd, ...)` -> Reads from our socket, gets inotify_event structs
-//! 4. App thinks it's using real inotify
+//! 1. App calls `inotify_init()` -> We connect to daemon and return an
+//!    eventfd standing in for the connection, which stays private
+//! 2. App calls `inotify_add_watch(fd, path, mask)` -> We send AddWatch to
+//!    daemon and start a thread that drains the connection into a bounded
+//!    per-fd event queue, so the daemon never waits on a slow reader
+//! 3. App calls `read(fd, ...)` -> We hand back whole inotify_event structs
+//!    from that queue
+//!    (and `ioctl(fd, FIONREAD)` reports how many bytes they come to)
+//! 4. App waits with `poll`/`epoll`/`select` -> The eventfd is readable
+//!    exactly while a whole event is queued
+//! 5. App thinks it's using real inotify
 //!
 //! # Safety
 //!
 //! This library is loaded into arbitrary processes via LD_PRELOAD.
 //! We must be extremely careful about:
 //! - No panics (use catch_unwind everywhere)
-//! - Minimal allocations during init
-//! - Thread safety (all state behind RwLock)
+//! - No work at load time: the ctor only flips a flag, while libc symbols and
+//!   global state are resolved lazily on first use
+//! - Thread safety (all state behind locks or atomics, no `static mut`; the
+//!   managed-fd check and symbol lookups on every `read()` take no lock)
 //! - No interference with app's own operations
-
-use fakenotify_protocol::{FramedMessage, Request, Response, get_socket_path_with_xdg_fallback};
-use parking_lot::RwLock;
-use std::collections::HashSet;
-use std::ffi::{CStr, c_char, c_int};
+//!
+//! Linux only; macOS is served by the `fakenotify-preload-macos` crate.
+
+#![cfg(target_os = "linux")]
+
+mod config;
+mod exec;
+mod fdset;
+mod identity;
+mod mounts;
+mod spawn;
+mod trace;
+
+use config::{Fallback, Route, config};
+use fakenotify_protocol::{
+    AddWatchRef, Capabilities, ClientInfo, Endpoint, ErrorCode, EventBuffer, EventMask,
+    FrameDecoder, FrameKind, FramedMessage, InotifyEvent, PROTOCOL_VERSION, Request, Response,
+    discover_socket_paths,
+};
+use fdset::FdSet;
+use identity::FileId;
+use parking_lot::{Condvar, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{CStr, c_char, c_int, c_uint, c_void};
 use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::fd::OwnedFd;
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use trace::trace;
 
 // ============================================================================
 // Original function pointers (resolved via dlsym)
@@ -40,96 +72,210 @@ type InotifyInit1Fn = unsafe extern "C" fn(c_int) -> c_int;
 type InotifyAddWatchFn = unsafe extern "C" fn(c_int, *const c_char, u32) -> c_int;
 type InotifyRmWatchFn = unsafe extern "C" fn(c_int, c_int) -> c_int;
 type CloseFn = unsafe extern "C" fn(c_int) -> c_int;
+type CloseRangeFn = unsafe extern "C" fn(c_uint, c_uint, c_int) -> c_int;
+type ClosefromFn = unsafe extern "C" fn(c_int);
+type ReadFn = unsafe extern "C" fn(c_int, *mut c_void, usize) -> isize;
+type IoctlFn = unsafe extern "C" fn(c_int, libc::Ioctl, *mut c_void) -> c_int;
+type ExecveFn =
+    unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int;
+type ExecvFn = unsafe extern "C" fn(*const c_char, *const *const c_char) -> c_int;
+type FexecveFn = unsafe extern "C" fn(c_int, *const *const c_char, *const *const c_char) -> c_int;
+
+static REAL_INOTIFY_INIT: RealFn<InotifyInitFn> = RealFn::new(c"inotify_init");
+static REAL_INOTIFY_INIT1: RealFn<InotifyInit1Fn> = RealFn::new(c"inotify_init1");
+static REAL_INOTIFY_ADD_WATCH: RealFn<InotifyAddWatchFn> = RealFn::new(c"inotify_add_watch");
+static REAL_INOTIFY_RM_WATCH: RealFn<InotifyRmWatchFn> = RealFn::new(c"inotify_rm_watch");
+static REAL_CLOSE: RealFn<CloseFn> = RealFn::new(c"close");
+static REAL_CLOSE_RANGE: RealFn<CloseRangeFn> = RealFn::new(c"close_range");
+static REAL_CLOSEFROM: RealFn<ClosefromFn> = RealFn::new(c"closefrom");
+static REAL_READ: RealFn<ReadFn> = RealFn::new(c"read");
+static REAL_IOCTL: RealFn<IoctlFn> = RealFn::new(c"ioctl");
+static REAL_EXECVE: RealFn<ExecveFn> = RealFn::new(c"execve");
+static REAL_EXECV: RealFn<ExecvFn> = RealFn::new(c"execv");
+static REAL_EXECVP: RealFn<ExecvFn> = RealFn::new(c"execvp");
+static REAL_EXECVPE: RealFn<ExecveFn> = RealFn::new(c"execvpe");
+static REAL_FEXECVE: RealFn<FexecveFn> = RealFn::new(c"fexecve");
+
+/// Optional protocol features this library can decode
+const CAPABILITIES: Capabilities = Capabilities::EVENT_BATCH
+    .union(Capabilities::REQUEST_IDS)
+    .union(Capabilities::CONTROL);
+
+/// A libc function resolved via `dlsym(RTLD_NEXT, ...)` on first use
+///
+/// dlsym runs once per symbol; every later call is a single load, and a
+/// missing symbol is remembered as such.
+struct RealFn<F> {
+    name: &'static CStr,
+    resolved: OnceLock<Option<F>>,
+}
 
-static mut REAL_INOTIFY_INIT: Option<InotifyInitFn> = None;
-static mut REAL_INOTIFY_INIT1: Option<InotifyInit1Fn> = None;
-static mut REAL_INOTIFY_ADD_WATCH: Option<InotifyAddWatchFn> = None;
-static mut REAL_INOTIFY_RM_WATCH: Option<InotifyRmWatchFn> = None;
-static mut REAL_CLOSE: Option<CloseFn> = None;
+impl<F: Copy> RealFn<F> {
+    const fn new(name: &'static CStr) -> Self {
+        Self {
+            name,
+            resolved: OnceLock::new(),
+        }
+    }
+
+    /// Get the real function, resolving it if needed
+    fn get(&self) -> Option<F> {
+        *self.resolved.get_or_init(|| {
+            // SAFETY: dlsym is safe to call with RTLD_NEXT and a valid C string
+            let ptr = unsafe { libc::dlsym(libc::RTLD_NEXT, self.name.as_ptr()) };
+            // SAFETY: F is the function pointer type declared for this symbol name
+            (!ptr.is_null()).then(|| unsafe { std::mem::transmute_copy(&ptr) })
+        })
+    }
+}
 
 // ============================================================================
 // Global state
 // ============================================================================
 
-/// Set of file descriptors that are managed by us (daemon connections)
-static MANAGED_FDS: RwLock<Option<HashSet<c_int>>> = RwLock::new(None);
+/// File descriptors that are managed by us (daemon connections)
+static MANAGED_FDS: FdSet = FdSet::new();
+
+/// Deframing state for each managed fd
+static FD_BUFFERS: Mutex<Option<HashMap<c_int, FdBuffer>>> = Mutex::new(None);
 
-/// Whether initialization has completed
+/// Whether our ctor has run
+///
+/// Before that we may be running inside another library's static
+/// initializer, where blocking on a daemon connection could hang the loader,
+/// so inotify_init passes straight through to the kernel.
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 // ============================================================================
 // Initialization
 // ============================================================================
 
-/// Initialize the preload library
+/// Mark the preload library as loaded
 ///
-/// This runs automatically when the library is loaded via ctor.
-/// We resolve the original libc functions via dlsym.
+/// This runs automatically when the library is loaded via ctor. It must stay
+/// trivially safe: no allocation, no locks, no dlsym, no IO. Everything else
+/// is set up on first use, except for taking over fds handed down across
+/// exec, which must not leak into anything the app starts before that.
 #[ctor::ctor]
 fn init() {
-    // Wrap everything in catch_unwind to prevent panics from propagating
-    let _ = std::panic::catch_unwind(|| {
-        // SAFETY: We're in initialization, before any threads are created.
-        // These function pointers are only written here and read later.
-        unsafe {
-            REAL_INOTIFY_INIT = resolve_symbol(b"inotify_init\0");
-            REAL_INOTIFY_INIT1 = resolve_symbol(b"inotify_init1\0");
-            REAL_INOTIFY_ADD_WATCH = resolve_symbol(b"inotify_add_watch\0");
-            REAL_INOTIFY_RM_WATCH = resolve_symbol(b"inotify_rm_watch\0");
-            REAL_CLOSE = resolve_symbol(b"close\0");
-        }
-
-        // Initialize the managed FDs set
-        *MANAGED_FDS.write() = Some(HashSet::new());
-
-        INITIALIZED.store(true, Ordering::SeqCst);
-    });
-}
-
-/// Resolve a symbol from the next library in the chain
-///
-/// # Safety
-///
-/// The returned function pointer must match the expected signature.
-unsafe fn resolve_symbol<T>(name: &[u8]) -> Option<T> {
-    // SAFETY: dlsym is safe to call with RTLD_NEXT and a valid C string
-    let ptr = unsafe { libc::dlsym(libc::RTLD_NEXT, name.as_ptr() as *const c_char) };
-    if ptr.is_null() {
-        None
-    } else {
-        // SAFETY: Caller ensures the type T matches the actual function signature
-        Some(unsafe { std::mem::transmute_copy(&ptr) })
-    }
+    exec::take_inherited();
+    INITIALIZED.store(true, Ordering::SeqCst);
 }
 
 // ============================================================================
 // Helper functions
 // ============================================================================
 
-/// Get the socket path from environment or use default with XDG fallback
-fn get_socket_path() -> PathBuf {
-    get_socket_path_with_xdg_fallback()
+/// Sockets to try, from the environment or the instance directory
+fn socket_paths() -> Vec<PathBuf> {
+    discover_socket_paths()
 }
 
 /// Check if a file descriptor is managed by us
 fn is_managed_fd(fd: c_int) -> bool {
-    MANAGED_FDS
-        .read()
-        .as_ref()
-        .is_some_and(|set| set.contains(&fd))
+    MANAGED_FDS.contains(fd)
+}
+
+/// Register a file descriptor as managed by us, talking to the daemon over
+/// `socket`
+///
+/// The connection is always close-on-exec, whatever the app asked for its
+/// fd; see [`exec`] for what happens to that across exec. An entry still
+/// held for `fd` belongs to an fd closed without us seeing it, and is let go
+/// of first.
+fn register_fd(fd: c_int, socket: c_int) {
+    exec::set_cloexec(socket, true);
+    if let Some(stale) = unregister_fd(fd) {
+        trace!(
+            "fd={fd}: handed out again, dropping stale socket={}",
+            stale.socket
+        );
+        release(stale);
+    }
+    // The state goes in first, so it is there once the fd counts as ours
+    FD_BUFFERS.lock().get_or_insert_with(HashMap::new).insert(
+        fd,
+        FdBuffer {
+            socket,
+            app_id: FileId::of(fd),
+            socket_id: FileId::of(socket),
+            capacity: config().buffer_events,
+            pid: std::process::id(),
+            ..FdBuffer::default()
+        },
+    );
+    MANAGED_FDS.insert(fd);
+}
+
+/// Unregister a file descriptor, returning its state
+fn unregister_fd(fd: c_int) -> Option<FdBuffer> {
+    MANAGED_FDS.remove(fd);
+    FD_BUFFERS.lock().as_mut()?.remove(&fd)
 }
 
-/// Register a file descriptor as managed by us
-fn register_fd(fd: c_int) {
-    if let Some(ref mut set) = *MANAGED_FDS.write() {
-        set.insert(fd);
+/// Check that `fd` is managed and still the file we handed out
+///
+/// An fd whose number has moved on to another file is let go of, so calls
+/// on that file pass through. A connection whose socket number moved on is
+/// treated as lost, like a daemon that went away.
+fn is_ours(fd: c_int) -> bool {
+    if !is_managed_fd(fd) {
+        return false;
+    }
+    let mut buffers = FD_BUFFERS.lock();
+    let Some(buffer) = buffers.as_mut().and_then(|b| b.get_mut(&fd)) else {
+        return false;
+    };
+    if !FileId::holds(buffer.app_id, fd) {
+        drop(buffers);
+        trace!("fd={fd}: now another file, dropping it");
+        if let Some(stale) = unregister_fd(fd) {
+            release(stale);
+        }
+        return false;
+    }
+    if buffer.socket >= 0 && !FileId::holds(buffer.socket_id, buffer.socket) {
+        trace!(
+            "fd={fd}: socket={} now another file, disconnecting",
+            buffer.socket
+        );
+        buffer.socket = -1;
+        buffer.hangup.get_or_insert(0);
+        raise_ready(fd, buffer);
+        drop(buffers);
+        RESPONSE_READY.notify_all();
     }
+    true
 }
 
-/// Unregister a file descriptor
-fn unregister_fd(fd: c_int) {
-    if let Some(ref mut set) = *MANAGED_FDS.write() {
-        set.remove(&fd);
+/// Let go of the connection and companion kernel fd of an unregistered fd
+///
+/// Waits for the drain thread, so it is done with the socket before the
+/// number can be reused. A socket whose number moved on to another file is
+/// left alone, and so is a forked child's copy of its parent's connection,
+/// which is closed but not shut down.
+fn release(mut buffer: FdBuffer) {
+    let socket = Some(buffer.socket)
+        .filter(|&socket| socket >= 0 && FileId::holds(buffer.socket_id, socket));
+    let own_process = buffer.pid == std::process::id();
+    if let Some(socket) = socket
+        && own_process
+    {
+        // Wake the drain thread
+        // SAFETY: socket is our own connection to the daemon
+        unsafe { libc::shutdown(socket, libc::SHUT_RD) };
+    }
+    match buffer.drainer.take() {
+        Some((pid, handle)) if pid == std::process::id() && socket.is_some() => {
+            let _ = handle.join();
+        }
+        // Nothing would wake it; it finds the entry gone once it does wake
+        Some((_, stale)) => std::mem::forget(stale),
+        None => {}
+    }
+    for own in socket.into_iter().chain(buffer.kernel_fd) {
+        // SAFETY: own is our socket or companion inotify fd
+        unsafe { libc::syscall(libc::SYS_close, own as libc::c_long) };
     }
 }
 
@@ -141,65 +287,776 @@ fn set_errno(err: c_int) {
     }
 }
 
+/// Longest a blocking read of a daemon connection waits
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest a blocking write to a daemon connection waits
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open one connection to the daemon
+///
+/// A `tcp://host:port` socket setting reaches a daemon on another OS, such as
+/// a Windows host serving WSL, and `vsock://cid:port` one on a VM's host;
+/// either way the app gets a plain socket fd.
+fn connect_once(endpoint: &Endpoint) -> std::io::Result<OwnedFd> {
+    let read_timeout = Some(READ_TIMEOUT);
+    let write_timeout = Some(WRITE_TIMEOUT);
+    Ok(match endpoint {
+        Endpoint::Unix(path) => {
+            let stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(read_timeout)?;
+            stream.set_write_timeout(write_timeout)?;
+            stream.into()
+        }
+        Endpoint::Tcp(addr) => {
+            let stream = TcpStream::connect(addr.as_str())?;
+            // Requests are small and latency-bound
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(read_timeout)?;
+            stream.set_write_timeout(write_timeout)?;
+            stream.into()
+        }
+        &Endpoint::Vsock { cid, port } => {
+            // A stream socket like any other once connected
+            let stream = UnixStream::from(fakenotify_protocol::connect_vsock(cid, port)?);
+            stream.set_read_timeout(read_timeout)?;
+            stream.set_write_timeout(write_timeout)?;
+            stream.into()
+        }
+    })
+}
+
 /// Connect to the daemon with retry logic
 ///
-/// This blocks until connection succeeds (per user requirement).
-fn connect_to_daemon() -> Option<UnixStream> {
-    let socket_path = get_socket_path();
+/// Each attempt tries every discovered socket in order and takes the first
+/// that accepts. If none does and autospawn is on, a per-user daemon is
+/// started once. Retries with exponential backoff until the configured
+/// connect timeout or retry budget runs out. On success, returns the socket
+/// path along with the connection; on failure, the errno of the last attempt.
+fn connect_to_daemon() -> Result<(OwnedFd, PathBuf), c_int> {
+    let candidates = socket_paths();
+    let policy = config().connect;
+    let deadline = Instant::now() + policy.timeout;
     let mut attempt = 0u32;
+    let mut spawned = false;
 
     loop {
-        match UnixStream::connect(&socket_path) {
-            Ok(stream) => {
-                // Set reasonable timeouts
-                let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
-                let _ = stream.set_write_timeout(Some(Duration::from_secs(10)));
-                return Some(stream);
+        let mut err = libc::ECONNREFUSED;
+        for path in &candidates {
+            match connect_once(&Endpoint::from_path(path)) {
+                Ok(stream) => return Ok((stream, path.clone())),
+                Err(e) => err = e.raw_os_error().unwrap_or(libc::ECONNREFUSED),
             }
-            Err(_) => {
-                attempt = attempt.saturating_add(1);
+        }
+
+        // Nothing is running: start our own daemon once, then try again
+        if !spawned && let Some(daemon) = &config().autospawn {
+            spawned = true;
+            if let Some(socket) = spawn::spawn_user_daemon(daemon) {
+                trace!(
+                    "connect: started {} on {}",
+                    daemon.display(),
+                    socket.display()
+                );
+                continue;
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || policy.retries.is_some_and(|max| attempt >= max) {
+            return Err(err);
+        }
+        attempt = attempt.saturating_add(1);
+
+        // Exponential backoff: 200ms, 400ms, 800ms, 1s, 1s, 1s...
+        let delay = Duration::from_millis(std::cmp::min(100 << std::cmp::min(attempt, 4), 1000));
+        thread::sleep(delay.min(remaining));
+    }
+}
+
+/// Whether a daemon advertising `roots` serves `path`
+fn covers(roots: &[PathBuf], path: &Path) -> bool {
+    roots.iter().any(|root| path.starts_with(root))
+}
+
+/// `path` as the daemon sees it, through the configured path map
+fn host_view(path: &Path) -> PathBuf {
+    config()
+        .path_map
+        .iter()
+        .find_map(|mapping| {
+            let rest = path.strip_prefix(&mapping.client).ok()?;
+            Some(mapping.host.join(rest))
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Read one response off a blocking connection, skipping heartbeats
+fn read_response(stream: &mut impl Read) -> Option<Response> {
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).ok()?;
+        let len = FramedMessage::read_length(&len)? as usize;
+        if len > FramedMessage::MAX_SIZE {
+            return None;
+        }
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).ok()?;
+        match Response::from_bytes(&payload).ok()? {
+            Response::Heartbeat => {}
+            response => return Some(response),
+        }
+    }
+}
+
+/// Connect to the instance at `path` and keep the connection if it
+/// advertises a root covering `target`
+///
+/// The probe's short timeouts give way to those of any other connection
+/// before it is returned.
+fn probe_instance(path: &Path, target: &Path) -> Option<UnixStream> {
+    let mut stream = UnixStream::connect(path).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+    stream
+        .set_write_timeout(Some(Duration::from_secs(5)))
+        .ok()?;
+
+    if !matches!(
+        read_response(&mut stream)?,
+        Response::ClientRegistered { .. }
+    ) {
+        return None;
+    }
+    stream
+        .write_all(&FramedMessage::frame(&Request::Hello.to_bytes().ok()?))
+        .ok()?;
+    match read_response(&mut stream)? {
+        Response::Hello { roots, .. } if covers(&roots, target) => {
+            stream.set_read_timeout(Some(READ_TIMEOUT)).ok()?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok()?;
+            Some(stream)
+        }
+        _ => None,
+    }
+}
+
+// ============================================================================
+// Deframing
+// ============================================================================
+
+/// Per-fd deframing state for a daemon connection
+///
+/// The socket carries length-prefixed frames holding either raw inotify
+/// events or bincode responses. Bytes are pulled off the socket into
+/// `partial`, split into frames, and sorted into `events` (handed to read())
+/// or `responses` (handed to whoever is waiting on a request).
+///
+/// The fd the app holds is an eventfd, kept readable exactly while `events`
+/// is non-empty (or the connection is gone), so the app can wait on it with
+/// any of poll, epoll or select.
+#[derive(Default)]
+struct FdBuffer {
+    /// Connection to the daemon, -1 once lost to another file
+    socket: c_int,
+    /// The file the app fd was when registered
+    app_id: Option<FileId>,
+    /// The file the socket was when registered
+    socket_id: Option<FileId>,
+    /// Socket path the connection was made to
+    endpoint: PathBuf,
+    /// Whether the instance has been checked against the first watch path
+    instance_checked: bool,
+    /// Bytes that do not yet form a complete frame
+    partial: FrameDecoder,
+    /// Complete inotify events, in arrival order
+    events: VecDeque<Vec<u8>>,
+    /// Most events kept in `events` (0 = unbounded)
+    capacity: usize,
+    /// Responses not yet claimed by a request
+    responses: VecDeque<Response>,
+    /// Whether the daemon agreed to tag requests with IDs, so threads
+    /// sharing the fd can have requests in flight at once
+    request_ids: bool,
+    /// ID given to the last tagged request
+    last_request_id: u64,
+    /// Answers to tagged requests, by ID, not yet claimed
+    answers: HashMap<u64, Response>,
+    /// Tagged requests whose caller gave up; their answers are dropped
+    abandoned: HashSet<u64>,
+    /// Process whose thread is waiting on the socket for a response
+    ///
+    /// Other requesters wait for it to hand theirs over instead of
+    /// polling themselves, where they would sleep through it.
+    reader: Option<u32>,
+    /// Held while writing a frame, so frames of concurrent requests do
+    /// not interleave
+    writing: Arc<Mutex<()>>,
+    /// Held by a read() while it takes events and pulls from the socket,
+    /// so threads reading the fd at once take turns
+    reading: Arc<Mutex<()>>,
+    /// Session to attach control connections to, when watch requests go
+    /// over those
+    control_session: Option<String>,
+    /// Daemon heartbeats received but not yet acknowledged
+    heartbeats: usize,
+    /// Real inotify fd serving watches routed to the kernel, created lazily
+    kernel_fd: Option<c_int>,
+    /// Whether the app fd has been made readable
+    ready: bool,
+    /// Set once the connection is gone: 0 for EOF, otherwise the errno
+    hangup: Option<c_int>,
+    /// Thread draining the socket, with the pid it was started in
+    drainer: Option<(u32, thread::JoinHandle<()>)>,
+    /// Process the connection belongs to; a forked child shares it
+    pid: u32,
+}
 
-                // Exponential backoff: 100ms, 200ms, 400ms, 800ms, 1s, 1s, 1s...
-                let delay_ms = std::cmp::min(100 * (1 << std::cmp::min(attempt, 4)), 1000);
-                thread::sleep(Duration::from_millis(delay_ms as u64));
+impl FdBuffer {
+    /// Append raw socket bytes and split off any complete frames
+    ///
+    /// Returns false if the stream is corrupt (oversized or undecodable frame).
+    fn push_bytes(&mut self, bytes: &[u8]) -> bool {
+        self.partial.push(bytes);
+        // Frames borrow the decoder, while sorting them needs the rest
+        let mut decoder = std::mem::take(&mut self.partial);
+        let intact = self.sort_frames(&mut decoder);
+        self.partial = decoder;
+        intact
+    }
 
-                // After 60 seconds of trying, give up and return None
-                // This prevents infinite blocking if daemon is truly unavailable
-                if attempt > 60 {
-                    return None;
+    /// Sort every complete frame in `decoder` into events and responses
+    fn sort_frames(&mut self, decoder: &mut FrameDecoder) -> bool {
+        loop {
+            let (kind, payload) = match decoder.next_kind_and_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return true,
+                Err(_) => return false,
+            };
+            if kind == FrameKind::Event {
+                self.queue_event(payload.to_vec());
+                continue;
+            }
+            match Response::from_bytes(payload) {
+                Ok(Response::Heartbeat) => self.heartbeats += 1,
+                Ok(Response::EventBatch { events, .. }) => {
+                    let mut buffer = EventBuffer::new(&events);
+                    while let Some((_, raw)) = buffer.next_raw() {
+                        self.queue_event(raw.to_vec());
+                    }
+                }
+                Ok(Response::Tagged { id, response }) => {
+                    if !self.abandoned.remove(&id) {
+                        self.answers.insert(id, *response);
+                    }
                 }
+                Ok(response) => self.responses.push_back(response),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Append events read from the kernel fd, tagging their wds
+    fn push_kernel_events(&mut self, bytes: &[u8]) {
+        let mut buffer = EventBuffer::new(bytes);
+        while let Some((header, raw)) = buffer.next_raw() {
+            let mut event = raw.to_vec();
+            // wd -1 (IN_Q_OVERFLOW) is not tied to a watch and stays as is
+            if header.wd >= 0 {
+                event[0..4].copy_from_slice(&kernel_wd_to_app(header.wd).to_ne_bytes());
+            }
+            self.queue_event(event);
+        }
+    }
+
+    /// Queue one event, or drop it if the queue is full
+    ///
+    /// Like the kernel, a full queue gets a single IN_Q_OVERFLOW event (wd
+    /// -1) at its tail, so the app knows to rescan.
+    fn queue_event(&mut self, event: Vec<u8>) {
+        if self.capacity == 0 || self.events.len() < self.capacity {
+            self.events.push_back(event);
+            return;
+        }
+        let overflow = InotifyEvent::new(-1, EventMask::IN_Q_OVERFLOW.bits(), 0).header_to_bytes();
+        if self.events.back().map(Vec::as_slice) != Some(&overflow[..]) {
+            trace!(
+                "event queue full ({} events), dropping events",
+                self.capacity
+            );
+            self.events.push_back(overflow.to_vec());
+        }
+    }
+
+    /// Copy as many whole events as fit into `buf`
+    ///
+    /// Returns `None` if no events are buffered, `Some(0)` if the first event
+    /// does not fit (the kernel reports EINVAL in that case).
+    fn take_events(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.events.front()?;
+
+        let mut written = 0;
+        while let Some(event) = self.events.front() {
+            if written + event.len() > buf.len() {
+                break;
             }
+            buf[written..written + event.len()].copy_from_slice(event);
+            written += event.len();
+            self.events.pop_front();
+        }
+        Some(written)
+    }
+
+    /// Whether a drain thread of this process owns the socket
+    ///
+    /// A forked child inherits the state but not the thread.
+    fn is_drained(&self) -> bool {
+        self.drainer
+            .as_ref()
+            .is_some_and(|(pid, _)| *pid == std::process::id())
+    }
+
+    /// Whether another thread of this process is reading responses
+    fn is_read_elsewhere(&self) -> bool {
+        self.reader == Some(std::process::id())
+    }
+
+    /// ID for the next request, or `None` to send it untagged
+    fn next_request_id(&mut self) -> Option<u64> {
+        self.request_ids.then(|| {
+            self.last_request_id += 1;
+            self.last_request_id
+        })
+    }
+
+    /// Claim the answer to request `id`, or the oldest untagged response
+    fn take_response(&mut self, id: Option<u64>) -> Option<Response> {
+        match id {
+            Some(id) => self.answers.remove(&id),
+            None => self.responses.pop_front(),
         }
     }
 }
 
-/// Send a request and receive a response
-fn send_request(stream: &mut UnixStream, request: &Request) -> Option<Response> {
-    // Serialize the request
-    let payload = request.to_bytes().ok()?;
+/// Outcome of pulling bytes off a managed socket
+enum Fill {
+    /// Some bytes were read and deframed
+    Data,
+    /// Nothing available within the wait time
+    WouldBlock,
+    /// The daemon closed the connection
+    Eof,
+    /// Read failed with the given errno
+    Error(c_int),
+}
 
-    // Frame it with length prefix
-    let framed = FramedMessage::frame(&payload);
+/// Signalled whenever a drain thread queues a response or hangs up
+static RESPONSE_READY: Condvar = Condvar::new();
 
-    // Send it
-    stream.write_all(&framed).ok()?;
+/// Run `f` against the buffer for `fd`, if it is still managed
+fn with_buffer<R>(fd: c_int, f: impl FnOnce(&mut FdBuffer) -> R) -> Option<R> {
+    FD_BUFFERS.lock().as_mut()?.get_mut(&fd).map(f)
+}
+
+/// Make the app fd readable, waking anyone waiting on it
+///
+/// Every call bumps the eventfd, so edge-triggered epoll sees each batch.
+fn raise_ready(fd: c_int, buffer: &mut FdBuffer) {
+    if !FileId::holds(buffer.app_id, fd) {
+        return;
+    }
+    let one = 1u64;
+    // SAFETY: fd is our eventfd and one is a valid 8-byte buffer
+    unsafe { libc::write(fd, (&raw const one).cast(), 8) };
+    buffer.ready = true;
+}
+
+/// Make the app fd unreadable again once nothing is left to read
+fn clear_ready(fd: c_int, buffer: &mut FdBuffer) {
+    if !buffer.ready
+        || !buffer.events.is_empty()
+        || buffer.hangup.is_some()
+        || !FileId::holds(buffer.app_id, fd)
+    {
+        return;
+    }
+    let mut count = 0u64;
+    // SAFETY: fd is our eventfd, which is readable since `ready` is set
+    unsafe { call_real_read(fd, (&raw mut count).cast(), 8) };
+    buffer.ready = false;
+}
+
+/// Wait up to `wait_ms` (-1 = forever) for the socket of `fd` or its
+/// companion kernel fd to become readable, returning the poll results for
+/// both
+fn wait_sources(fd: c_int, wait_ms: c_int) -> Result<[libc::pollfd; 2], Fill> {
+    let Some((socket, kernel_fd)) = with_buffer(fd, |b| (b.socket, b.kernel_fd)) else {
+        return Err(Fill::Error(libc::EBADF));
+    };
+    if socket < 0 {
+        return Err(Fill::Eof);
+    }
+
+    let mut pfds = [
+        libc::pollfd {
+            fd: socket,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: kernel_fd.unwrap_or(-1),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let nfds = if kernel_fd.is_some() { 2 } else { 1 };
+    // SAFETY: pfds holds at least nfds valid pollfd entries
+    let ready = unsafe { libc::poll(pfds.as_mut_ptr(), nfds, wait_ms) };
+    if ready < 0 {
+        // SAFETY: __errno_location returns a valid pointer to the thread-local errno
+        return Err(Fill::Error(unsafe { *libc::__errno_location() }));
+    }
+    if ready == 0 {
+        return Err(Fill::WouldBlock);
+    }
+    Ok(pfds)
+}
+
+/// Wait up to `wait_ms` (-1 = forever) for the socket (or the companion
+/// kernel fd) to become readable, then move whatever is available into the
+/// fd's buffer.
+///
+/// Works regardless of the socket's O_NONBLOCK flag, and never holds the
+/// buffer lock while blocked. Bytes are read and deframed under the lock, so
+/// concurrent callers cannot reorder the stream.
+fn fill_buffer(fd: c_int, wait_ms: c_int) -> Fill {
+    let pfds = match wait_sources(fd, wait_ms) {
+        Ok(pfds) => pfds,
+        Err(fill) => return fill,
+    };
+    let [socket, kernel] = pfds;
+
+    let mut buffers = FD_BUFFERS.lock();
+    let Some(buffer) = buffers.as_mut().and_then(|b| b.get_mut(&fd)) else {
+        return Fill::Error(libc::EBADF);
+    };
+    let queued = buffer.events.len();
+    let answered = buffer.responses.len() + buffer.answers.len();
+    let mut filled = false;
+
+    if kernel.revents & libc::POLLIN != 0 {
+        let mut chunk = [0u8; 4096];
+        // SAFETY: chunk is a valid writable buffer and this is our non-blocking inotify fd
+        let n = unsafe { call_real_read(kernel.fd, chunk.as_mut_ptr().cast(), chunk.len()) };
+        if n > 0 {
+            buffer.push_kernel_events(&chunk[..n as usize]);
+            filled = true;
+        }
+    }
+
+    // The socket may have been lost while we waited
+    let outcome = if socket.revents == 0 || socket.fd != buffer.socket {
+        Fill::WouldBlock
+    } else {
+        let mut chunk = [0u8; 4096];
+        // SAFETY: chunk is a valid writable buffer of the given length
+        let n = unsafe {
+            libc::recv(
+                socket.fd,
+                chunk.as_mut_ptr().cast(),
+                chunk.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            // SAFETY: __errno_location returns a valid pointer to the thread-local errno
+            let err = unsafe { *libc::__errno_location() };
+            if err == libc::EAGAIN || err == libc::EWOULDBLOCK {
+                Fill::WouldBlock
+            } else {
+                Fill::Error(err)
+            }
+        } else if n == 0 {
+            Fill::Eof
+        } else if buffer.push_bytes(&chunk[..n as usize]) {
+            Fill::Data
+        } else {
+            Fill::Error(libc::EIO)
+        }
+    };
+
+    if buffer.events.len() != queued {
+        raise_ready(fd, buffer);
+    }
+    let responded = buffer.responses.len() + buffer.answers.len() != answered;
+    let heartbeats = std::mem::take(&mut buffer.heartbeats);
+    drop(buffers);
+
+    if responded {
+        RESPONSE_READY.notify_all();
+    }
+    // Let the daemon know we are still alive
+    for _ in 0..heartbeats {
+        send_frame(fd, &Request::HeartbeatAck);
+    }
+
+    match outcome {
+        Fill::Data => Fill::Data,
+        _ if filled => Fill::Data,
+        other => other,
+    }
+}
+
+/// Start the thread draining the socket of `fd`, unless one is running
+///
+/// The thread keeps pulling events off the connection even while the app is
+/// not reading, so they back up in the bounded queue rather than in the
+/// socket, where they would stall the daemon.
+fn start_drain(fd: c_int) {
+    let mut buffers = FD_BUFFERS.lock();
+    let Some(buffer) = buffers.as_mut().and_then(|b| b.get_mut(&fd)) else {
+        return;
+    };
+    if buffer.is_drained() {
+        return;
+    }
+    let spawned = thread::Builder::new()
+        .name("fakenotify-drain".to_string())
+        .spawn(move || drain(fd));
+    match spawned {
+        Ok(handle) => {
+            // A handle inherited across fork names no thread of ours
+            if let Some((_, stale)) = buffer.drainer.replace((std::process::id(), handle)) {
+                std::mem::forget(stale);
+            }
+        }
+        // read() keeps pulling from the socket itself
+        Err(e) => trace!("drain fd={fd}: failed to start thread: {e}"),
+    }
+}
+
+/// Body of the drain thread for `fd`
+fn drain(fd: c_int) {
+    loop {
+        // Until a kernel-routed watch creates the companion fd, look for it
+        // now and then; it is not in the poll set yet
+        let wait_ms = match with_buffer(fd, |b| b.kernel_fd) {
+            None => return,
+            Some(None) if config().has_path_rules() => 100,
+            Some(_) => -1,
+        };
+        let end = match fill_buffer(fd, wait_ms) {
+            Fill::Data | Fill::WouldBlock | Fill::Error(libc::EINTR) => continue,
+            Fill::Eof => 0,
+            Fill::Error(err) => err,
+        };
+        trace!("drain fd={fd}: connection closed ({end})");
+        with_buffer(fd, |b| {
+            b.hangup = Some(end);
+            raise_ready(fd, b);
+        });
+        RESPONSE_READY.notify_all();
+        return;
+    }
+}
+
+// ============================================================================
+// Kernel passthrough
+// ============================================================================
+
+/// Bit set on wds handed out for kernel-routed watches
+///
+/// Kernel wds are small per-fd counters and daemon wds would need a billion
+/// watches to reach this bit, so the two ranges never collide and the app
+/// still only ever sees positive wds.
+const KERNEL_WD_FLAG: c_int = 1 << 30;
+
+fn kernel_wd_to_app(wd: c_int) -> c_int {
+    wd | KERNEL_WD_FLAG
+}
+
+fn app_wd_to_kernel(wd: c_int) -> Option<c_int> {
+    (wd > 0 && wd & KERNEL_WD_FLAG != 0).then_some(wd & !KERNEL_WD_FLAG)
+}
+
+/// Get the companion kernel inotify fd for a managed fd, creating it if needed
+///
+/// Its events are queued alongside the daemon's, so they wake the app through
+/// the same eventfd.
+fn kernel_fd_for(fd: c_int) -> Option<c_int> {
+    if let Some(existing) = with_buffer(fd, |b| b.kernel_fd)? {
+        return Some(existing);
+    }
+
+    // SAFETY: inotify_init1 has no memory-safety preconditions
+    let kfd = unsafe {
+        match REAL_INOTIFY_INIT1.get() {
+            Some(f) => f(libc::IN_NONBLOCK | libc::IN_CLOEXEC),
+            None => libc::syscall(
+                libc::SYS_inotify_init1,
+                (libc::IN_NONBLOCK | libc::IN_CLOEXEC) as libc::c_long,
+            ) as c_int,
+        }
+    };
+    if kfd < 0 {
+        return None;
+    }
+    with_buffer(fd, |b| b.kernel_fd = Some(kfd));
+    Some(kfd)
+}
+
+/// Wait for the answer to request `id` on `fd` (or for the next untagged
+/// response), buffering any events that arrive first
+fn recv_response(fd: c_int, id: Option<u64>) -> Option<Response> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+
+    loop {
+        let mut buffers = FD_BUFFERS.lock();
+        let buffer = buffers.as_mut()?.get_mut(&fd)?;
+        if let Some(response) = buffer.take_response(id) {
+            return Some(response);
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || buffer.hangup.is_some() {
+            if let Some(id) = id {
+                buffer.abandoned.insert(id);
+            }
+            return None;
+        }
+        if buffer.is_drained() || buffer.is_read_elsewhere() {
+            // The thread owning the socket hands responses over
+            RESPONSE_READY.wait_for(&mut buffers, remaining);
+            continue;
+        }
+        buffer.reader = Some(std::process::id());
+        drop(buffers);
+        let fill = fill_buffer(fd, remaining.as_millis() as c_int);
+        with_buffer(fd, |b| b.reader = None);
+        // Whoever is still waiting takes over reading
+        RESPONSE_READY.notify_all();
+        match fill {
+            Fill::Data | Fill::WouldBlock => {}
+            Fill::Error(libc::EINTR) => {}
+            Fill::Eof | Fill::Error(_) => return None,
+        }
+    }
+}
+
+/// Send a request and receive its response
+///
+/// Once the daemon has agreed to request IDs, the request goes out tagged
+/// and only its own answer is taken, so threads sharing the fd need not
+/// take turns. Otherwise answers are matched to requests by order.
+fn send_request(fd: c_int, request: &Request) -> Option<Response> {
+    let id = with_buffer(fd, FdBuffer::next_request_id)?;
+    match id {
+        Some(id) => send_frame(
+            fd,
+            &Request::Tagged {
+                id,
+                request: Box::new(request.clone()),
+            },
+        )?,
+        None => send_frame(fd, request)?,
+    }
+    recv_response(fd, id)
+}
 
-    // Read the response length (4 bytes, little-endian)
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).ok()?;
-    let len = FramedMessage::read_length(&len_buf)? as usize;
+/// Send a watch request, over a control connection if the fd uses them
+fn send_watch_request(fd: c_int, request: &Request) -> Option<Response> {
+    let control = with_buffer(fd, |b| {
+        b.control_session
+            .clone()
+            .map(|session| (b.endpoint.clone(), session))
+    })?;
+    match control {
+        Some((endpoint, session)) => send_control_request(&endpoint, session, request),
+        None => send_request(fd, request),
+    }
+}
+
+/// Longest AddWatch frame built on the stack: a path of PATH_MAX bytes and
+/// the rest of a tagged request
+const WATCH_FRAME_MAX: usize = libc::PATH_MAX as usize + 64;
+
+/// Send an AddWatch for `path`, over a control connection if the fd uses
+/// them
+///
+/// Otherwise the request is framed on the stack, so watch-heavy startups
+/// allocate nothing per watch for it.
+fn send_add_watch(fd: c_int, path: &Path, mask: u32) -> Option<Response> {
+    let id = with_buffer(fd, |b| {
+        b.control_session.is_none().then(|| b.next_request_id())
+    })?;
+    let Some(id) = id else {
+        let request = Request::AddWatch {
+            path: path.to_path_buf(),
+            mask,
+        };
+        return send_watch_request(fd, &request);
+    };
+    let mut stack = [0u8; WATCH_FRAME_MAX];
+    // A path too long for the kernel still goes to the daemon to judge
+    let mut heap = Vec::new();
+    let frame = if path.as_os_str().len() + 64 <= WATCH_FRAME_MAX {
+        &mut stack[..]
+    } else {
+        heap.resize(path.as_os_str().len() + 64, 0);
+        &mut heap[..]
+    };
+    let len = AddWatchRef { path, mask, id }.frame_into(frame).ok()?;
+    send_bytes(fd, &frame[..len])?;
+    recv_response(fd, id)
+}
 
-    // Validate length
-    if len > FramedMessage::MAX_SIZE {
+/// Send a request over a fresh connection attached to `session`
+///
+/// The connection lives for this one request, so its answer never has to
+/// be picked out of the event stream.
+fn send_control_request(endpoint: &Path, session: String, request: &Request) -> Option<Response> {
+    let mut stream = std::fs::File::from(connect_once(&Endpoint::from_path(endpoint)).ok()?);
+    if !matches!(
+        read_response(&mut stream)?,
+        Response::ClientRegistered { .. }
+    ) {
         return None;
     }
+    // The daemon answers in order, so both can go out at once
+    let mut framed = Vec::new();
+    FramedMessage::encode_into(&Request::AttachControl { session }, &mut framed).ok()?;
+    FramedMessage::encode_into(request, &mut framed).ok()?;
+    stream.write_all(&framed).ok()?;
+    match read_response(&mut stream)? {
+        Response::ControlAttached { .. } => read_response(&mut stream),
+        response => {
+            trace!("control connection refused: {response:?}");
+            None
+        }
+    }
+}
 
-    // Read the response payload
-    let mut payload = vec![0u8; len];
-    stream.read_exact(&mut payload).ok()?;
+/// Send a request without waiting for a response
+fn send_frame(fd: c_int, request: &Request) -> Option<()> {
+    let mut framed = Vec::new();
+    FramedMessage::encode_into(request, &mut framed).ok()?;
+    send_bytes(fd, &framed)
+}
 
-    // Deserialize the response
-    Response::from_bytes(&payload).ok()
+/// Send one whole frame
+fn send_bytes(fd: c_int, framed: &[u8]) -> Option<()> {
+    let (socket, writing) = with_buffer(fd, |b| (b.socket, Arc::clone(&b.writing)))?;
+    // The connection is gone once its number went to another file
+    if socket < 0 {
+        return None;
+    }
+    let _writing = writing.lock();
+    // SAFETY: socket is a valid socket fd that we own
+    use std::os::unix::io::FromRawFd;
+    let mut stream = unsafe { UnixStream::from_raw_fd(socket) };
+    let sent = stream.write_all(framed);
+    // Don't let stream drop close the fd
+    std::mem::forget(stream);
+    sent.ok()
 }
 
 // ============================================================================
@@ -208,8 +1065,8 @@ fn send_request(stream: &mut UnixStream, request: &Request) -> Option<Response>
 
 /// Intercepted inotify_init()
 ///
-/// Instead of creating a real inotify fd, we connect to the daemon
-/// and return the socket fd.
+/// Instead of creating a real inotify fd, we connect to the daemon and
+/// return an eventfd standing in for the connection.
 ///
 /// # Safety
 ///
@@ -240,65 +1097,209 @@ pub unsafe extern "C" fn inotify_init1(flags: c_int) -> c_int {
 
 /// Implementation for both inotify_init and inotify_init1
 fn inotify_init_impl(flags: c_int) -> c_int {
-    // If not initialized, fall back to real inotify
+    // If not initialized or disabled for this process, fall back to real inotify
     if !INITIALIZED.load(Ordering::SeqCst) {
-        return call_real_inotify_init1(flags);
+        let fd = call_real_inotify_init1(flags);
+        trace!("init flags={flags:#x} -> real fd={fd} (before ctor)");
+        return fd;
+    }
+    if config().disabled {
+        let fd = call_real_inotify_init1(flags);
+        trace!("init flags={flags:#x} -> real fd={fd} (disabled)");
+        return fd;
     }
 
     // Connect to daemon
-    let mut stream = match connect_to_daemon() {
-        Some(s) => s,
-        None => {
+    let (stream, endpoint) = match connect_to_daemon() {
+        Ok(connected) => connected,
+        Err(err) if config().connect.fallback == Fallback::Fail => {
+            trace!("init flags={flags:#x} -> errno {err} (daemon unavailable, fallback=fail)");
+            set_errno(err);
+            return -1;
+        }
+        Err(_) => {
             // Daemon unavailable, fall back to real inotify
-            return call_real_inotify_init1(flags);
+            let fd = call_real_inotify_init1(flags);
+            trace!("init flags={flags:#x} -> real fd={fd} (daemon unavailable)");
+            return fd;
         }
     };
 
-    // Register with daemon
-    let response = match send_request(&mut stream, &Request::RegisterClient) {
-        Some(r) => r,
-        None => {
-            set_errno(libc::EIO);
-            return -1;
-        }
+    // The app gets an eventfd standing in for the connection; IN_NONBLOCK
+    // and IN_CLOEXEC have the same values as the eventfd flags
+    // SAFETY: eventfd has no memory-safety preconditions
+    let fd = unsafe { libc::eventfd(0, flags & (libc::EFD_NONBLOCK | libc::EFD_CLOEXEC)) };
+    if fd < 0 {
+        trace!("init flags={flags:#x} -> -1 (no eventfd)");
+        return -1;
+    }
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    // SAFETY: fd was just created and nothing else owns it
+    let ready = unsafe { OwnedFd::from_raw_fd(fd) };
+    register_fd(fd, stream.as_raw_fd());
+    with_buffer(fd, |b| b.endpoint = endpoint);
+
+    // The daemon registers us on connect and greets us with our client ID
+    let response = match recv_response(fd, None) {
+        Some(Response::ClientRegistered { .. }) => match configure_connection(fd) {
+            Ok(()) => {
+                // Leak both fds so they stay open
+                // They will be closed when the app calls close()
+                std::mem::forget(stream);
+                std::mem::forget(ready);
+
+                trace!("init flags={flags:#x} -> daemon fd={fd}");
+                return fd;
+            }
+            Err(refusal) => Some(*refusal),
+        },
+        response => response,
     };
+    let err = config().errno_map.for_response(response.as_ref());
+    trace!("init flags={flags:#x} -> errno {err} (registration failed: {response:?})");
+    unregister_fd(fd);
+    set_errno(err);
+    -1
+}
 
-    // Check response
-    match response {
-        Response::ClientRegistered { .. } => {
-            // Get the socket's file descriptor
-            use std::os::unix::io::AsRawFd;
-            let fd = stream.as_raw_fd();
-
-            // Apply flags
-            // SAFETY: fd is valid and fcntl is safe to call
-            if flags & libc::O_NONBLOCK != 0 {
-                let current = unsafe { libc::fcntl(fd, libc::F_GETFL) };
-                unsafe { libc::fcntl(fd, libc::F_SETFL, current | libc::O_NONBLOCK) };
-            }
-            if flags & libc::O_CLOEXEC != 0 {
-                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
-            }
+/// Send the per-process settings to a freshly registered connection
+///
+/// Fails with the daemon's refusal if it speaks another protocol version,
+/// as nothing else it sends could be read either.
+fn configure_connection(fd: c_int) -> Result<(), Box<Response>> {
+    // Name the connection so `fakenotifyd clients` can tell apps apart
+    let request = Request::RegisterClient {
+        protocol_version: PROTOCOL_VERSION,
+        info: client_info(),
+        capabilities: CAPABILITIES.bits(),
+    };
+    match send_request(fd, &request) {
+        Some(Response::ClientRegistered {
+            session,
+            capabilities,
+            ..
+        }) => {
+            let capabilities = Capabilities::from_bits_truncate(capabilities);
+            let control =
+                config().control_connection && capabilities.contains(Capabilities::CONTROL);
+            with_buffer(fd, |b| {
+                b.request_ids = capabilities.contains(Capabilities::REQUEST_IDS);
+                b.control_session = control.then_some(session);
+            });
+        }
+        Some(
+            refusal @ Response::Error {
+                code: ErrorCode::VersionMismatch,
+                ..
+            },
+        ) => return Err(Box::new(refusal)),
+        _ => trace!("init fd={fd}: daemon did not accept the client info"),
+    }
+    // Before any watch, as they belong to the namespace
+    if let Some(namespace) = &config().namespace {
+        let request = Request::HelloNamespace {
+            namespace: namespace.clone(),
+        };
+        if !matches!(send_request(fd, &request), Some(Response::Hello { .. })) {
+            trace!("init fd={fd}: daemon did not accept namespace {namespace}");
+        }
+    }
+    // Tell the daemon where our paths live on its side. A daemon
+    // that rejects this still serves paths it can see directly.
+    if !config().path_map.is_empty() {
+        let request = Request::SetPathMap {
+            mappings: config().path_map.clone(),
+        };
+        if !matches!(send_request(fd, &request), Some(Response::PathMapSet)) {
+            trace!("init fd={fd}: daemon did not accept the path map");
+        }
+    }
+    if !config().filter.is_empty() {
+        let request = Request::SetFilter {
+            filter: config().filter.clone(),
+        };
+        if !matches!(send_request(fd, &request), Some(Response::FilterSet)) {
+            trace!("init fd={fd}: daemon did not accept the name filter");
+        }
+    }
+    Ok(())
+}
 
-            // Register this fd as managed by us
-            register_fd(fd);
+/// This process as it describes itself to the daemon
+fn client_info() -> ClientInfo {
+    let name = std::fs::read_to_string("/proc/self/comm")
+        .ok()
+        .map(|comm| comm.trim_end().to_string())
+        .filter(|comm| !comm.is_empty());
+    ClientInfo {
+        name,
+        pid: Some(std::process::id() as i32),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    }
+}
 
-            // Leak the stream so the fd stays open
-            // The fd will be closed when the app calls close()
-            std::mem::forget(stream);
+/// Before the first daemon watch on `fd`, move it to another discovered
+/// instance if its own does not serve `path` and the other one does
+///
+/// The new connection is swapped in under the same socket number, so no
+/// other state has to follow it.
+fn choose_instance(fd: c_int, path: &Path) {
+    let current = with_buffer(fd, |b| {
+        let unchecked = !std::mem::replace(&mut b.instance_checked, true);
+        (unchecked && !b.is_drained()).then(|| b.endpoint.clone())
+    });
+    let Some(Some(current)) = current else {
+        return;
+    };
+    let candidates = socket_paths();
+    if candidates.len() < 2 {
+        return;
+    }
 
-            fd
-        }
-        Response::Error { message } => {
-            // Log error if possible, but don't panic
-            let _ = message;
-            set_errno(libc::EIO);
-            -1
-        }
-        _ => {
-            set_errno(libc::EIO);
-            -1
+    let target = host_view(path);
+    if let Some(Response::Hello { roots, .. }) = send_request(fd, &Request::Hello)
+        && covers(&roots, &target)
+    {
+        return;
+    }
+    for candidate in candidates.iter().filter(|&c| *c != current) {
+        let Some(stream) = probe_instance(candidate, &target) else {
+            continue;
+        };
+        use std::os::unix::io::IntoRawFd;
+        let new = stream.into_raw_fd();
+        let swapped = with_buffer(fd, |b| {
+            // SAFETY: both are our own sockets; dup3 closes the old connection
+            let ok = unsafe { libc::dup3(new, b.socket, libc::O_CLOEXEC) } >= 0;
+            if ok {
+                b.partial.clear();
+                b.responses.clear();
+                // The new daemon is asked afresh whether it takes IDs
+                b.request_ids = false;
+                b.control_session = None;
+                b.answers.clear();
+                b.abandoned.clear();
+                b.heartbeats = 0;
+                b.endpoint = candidate.clone();
+            }
+            ok
+        });
+        // SAFETY: new is the probe connection, now duplicated or unused
+        unsafe { libc::syscall(libc::SYS_close, new as libc::c_long) };
+        if swapped == Some(true) {
+            trace!(
+                "add_watch fd={fd}: {} is served by {}",
+                path.display(),
+                candidate.display()
+            );
+            if let Err(refusal) = configure_connection(fd) {
+                trace!(
+                    "add_watch fd={fd}: {} refused the connection: {refusal:?}",
+                    candidate.display()
+                );
+            }
         }
+        return;
     }
 }
 
@@ -306,9 +1307,9 @@ fn inotify_init_impl(flags: c_int) -> c_int {
 fn call_real_inotify_init1(flags: c_int) -> c_int {
     // SAFETY: We're calling the original libc functions with valid arguments
     unsafe {
-        if let Some(f) = REAL_INOTIFY_INIT1 {
+        if let Some(f) = REAL_INOTIFY_INIT1.get() {
             f(flags)
-        } else if let Some(f) = REAL_INOTIFY_INIT {
+        } else if let Some(f) = REAL_INOTIFY_INIT.get() {
             f()
         } else {
             set_errno(libc::ENOSYS);
@@ -329,12 +1330,13 @@ fn call_real_inotify_init1(flags: c_int) -> c_int {
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int {
     std::panic::catch_unwind(|| {
+        exec::adopt_inherited();
         // Check if this is our fd
-        if !is_managed_fd(fd) {
+        if !is_ours(fd) {
             // Not ours, call real function
             // SAFETY: Passing through to original function
             unsafe {
-                if let Some(f) = REAL_INOTIFY_ADD_WATCH {
+                if let Some(f) = REAL_INOTIFY_ADD_WATCH.get() {
                     return f(fd, pathname, mask);
                 } else {
                     set_errno(libc::ENOSYS);
@@ -346,32 +1348,63 @@ pub unsafe extern "C" fn inotify_add_watch(fd: c_int, pathname: *const c_char, m
         // Convert pathname to Rust string
         // SAFETY: Caller guarantees pathname is a valid C string
         let path = match unsafe { CStr::from_ptr(pathname) }.to_str() {
-            Ok(s) => PathBuf::from(s),
+            Ok(s) => Path::new(s),
             Err(_) => {
+                trace!("add_watch fd={fd} mask={mask:#x} -> EINVAL (non-UTF-8 path)");
                 set_errno(libc::EINVAL);
                 return -1;
             }
         };
 
-        // Create a temporary stream from the fd
-        // SAFETY: fd is a valid socket fd that we own
-        use std::os::unix::io::FromRawFd;
-        let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+        // Paths kept on real inotify go to this fd's companion kernel fd
+        if config().route(path) == Route::Kernel {
+            let Some(kfd) = kernel_fd_for(fd) else {
+                trace!(
+                    "add_watch fd={fd} path={} -> EMFILE (no kernel fd)",
+                    path.display()
+                );
+                set_errno(libc::EMFILE);
+                return -1;
+            };
+            // SAFETY: Passing through to original function with our kernel fd
+            let wd = unsafe {
+                match REAL_INOTIFY_ADD_WATCH.get() {
+                    Some(f) => f(kfd, pathname, mask),
+                    None => {
+                        set_errno(libc::ENOSYS);
+                        return -1;
+                    }
+                }
+            };
+            let wd = if wd < 0 {
+                wd
+            } else {
+                start_drain(fd);
+                kernel_wd_to_app(wd)
+            };
+            trace!(
+                "add_watch fd={fd} path={} mask={mask:#x} -> kernel wd={wd}",
+                path.display()
+            );
+            return wd;
+        }
+
+        choose_instance(fd, path);
 
         // Send the request
-        let result = send_request(&mut stream, &Request::AddWatch { path, mask });
-
-        // Don't let stream drop close the fd
-        std::mem::forget(stream);
+        let result = send_add_watch(fd, path, mask);
+        trace!(
+            "add_watch fd={fd} path={} mask={mask:#x} -> daemon {result:?}",
+            path.display()
+        );
 
         match result {
-            Some(Response::WatchAdded { wd }) => wd,
-            Some(Response::Error { .. }) => {
-                set_errno(libc::EINVAL);
-                -1
+            Some(Response::WatchAdded { wd }) => {
+                start_drain(fd);
+                wd
             }
-            _ => {
-                set_errno(libc::EIO);
+            response => {
+                set_errno(config().errno_map.for_response(response.as_ref()));
                 -1
             }
         }
@@ -393,12 +1426,13 @@ pub unsafe extern "C" fn inotify_add_watch(fd: c_int, pathname: *const c_char, m
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn inotify_rm_watch(fd: c_int, wd: c_int) -> c_int {
     std::panic::catch_unwind(|| {
+        exec::adopt_inherited();
         // Check if this is our fd
-        if !is_managed_fd(fd) {
+        if !is_ours(fd) {
             // Not ours, call real function
             // SAFETY: Passing through to original function
             unsafe {
-                if let Some(f) = REAL_INOTIFY_RM_WATCH {
+                if let Some(f) = REAL_INOTIFY_RM_WATCH.get() {
                     return f(fd, wd);
                 } else {
                     set_errno(libc::ENOSYS);
@@ -407,25 +1441,31 @@ pub unsafe extern "C" fn inotify_rm_watch(fd: c_int, wd: c_int) -> c_int {
             }
         }
 
-        // Create a temporary stream from the fd
-        // SAFETY: fd is a valid socket fd that we own
-        use std::os::unix::io::FromRawFd;
-        let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+        if let Some(kernel_wd) = app_wd_to_kernel(wd) {
+            let Some(kfd) = with_buffer(fd, |b| b.kernel_fd).flatten() else {
+                set_errno(libc::EINVAL);
+                return -1;
+            };
+            // SAFETY: Passing through to original function with our kernel fd
+            return unsafe {
+                match REAL_INOTIFY_RM_WATCH.get() {
+                    Some(f) => f(kfd, kernel_wd),
+                    None => {
+                        set_errno(libc::ENOSYS);
+                        -1
+                    }
+                }
+            };
+        }
 
         // Send the request
-        let result = send_request(&mut stream, &Request::RemoveWatch { wd });
-
-        // Don't let stream drop close the fd
-        std::mem::forget(stream);
+        let result = send_watch_request(fd, &Request::RemoveWatch { wd });
+        trace!("rm_watch fd={fd} wd={wd} -> daemon {result:?}");
 
         match result {
             Some(Response::WatchRemoved) => 0,
-            Some(Response::Error { .. }) => {
-                set_errno(libc::EINVAL);
-                -1
-            }
-            _ => {
-                set_errno(libc::EIO);
+            response => {
+                set_errno(config().errno_map.for_response(response.as_ref()));
                 -1
             }
         }
@@ -447,17 +1487,24 @@ pub unsafe extern "C" fn inotify_rm_watch(fd: c_int, wd: c_int) -> c_int {
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn close(fd: c_int) -> c_int {
     std::panic::catch_unwind(|| {
+        exec::adopt_inherited();
         // Check if this is our fd and unregister it
-        if is_managed_fd(fd) {
+        if is_ours(fd)
+            && let Some(buffer) = unregister_fd(fd)
+        {
             // Just unregister - no need to send anything to daemon,
             // it will detect the disconnect
-            unregister_fd(fd);
+            trace!(
+                "close fd={fd} socket={} kernel_fd={:?}",
+                buffer.socket, buffer.kernel_fd
+            );
+            release(buffer);
         }
 
         // Always call real close
         // SAFETY: Calling original close with valid fd
         unsafe {
-            if let Some(f) = REAL_CLOSE {
+            if let Some(f) = REAL_CLOSE.get() {
                 f(fd)
             } else {
                 // Last resort: use syscall directly
@@ -472,6 +1519,481 @@ pub unsafe extern "C" fn close(fd: c_int) -> c_int {
     })
 }
 
+/// Intercepted close_range()
+///
+/// Managed fds in the range are let go of as with close(). The connections
+/// and companion kernel fds behind the managed fds left open are ours, not
+/// the app's, so the range is closed around them. Setting close-on-exec
+/// (`CLOSE_RANGE_CLOEXEC`) goes straight through, as ours already are.
+///
+/// # Safety
+///
+/// This function is called by libc as a replacement for close_range.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn close_range(first: c_uint, last: c_uint, flags: c_int) -> c_int {
+    std::panic::catch_unwind(|| {
+        exec::adopt_inherited();
+        close_range_impl(first, last, flags)
+    })
+    .unwrap_or_else(|_| call_real_close_range(first, last, flags))
+}
+
+/// Intercepted closefrom()
+///
+/// Closes through close_range() like glibc's own, so managed fds are let go
+/// of the same way; only without close_range in the kernel does it fall
+/// back to the real closefrom.
+///
+/// # Safety
+///
+/// This function is called by libc as a replacement for closefrom.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn closefrom(lowfd: c_int) {
+    std::panic::catch_unwind(|| {
+        exec::adopt_inherited();
+        if MANAGED_FDS.is_empty()
+            || lowfd < 0
+            || close_range_impl(lowfd as c_uint, c_uint::MAX, 0) != 0
+        {
+            call_real_closefrom(lowfd);
+        }
+    })
+    .unwrap_or_else(|_| call_real_closefrom(lowfd))
+}
+
+fn close_range_impl(first: c_uint, last: c_uint, flags: c_int) -> c_int {
+    if MANAGED_FDS.is_empty() || flags as c_uint & libc::CLOSE_RANGE_CLOEXEC != 0 || first > last {
+        return call_real_close_range(first, last, flags);
+    }
+    let in_range = |fd: c_int| c_uint::try_from(fd).is_ok_and(|fd| (first..=last).contains(&fd));
+
+    // A fork child may have been made while another thread held the lock,
+    // in which case it is never released here
+    let Some(mut buffers) = FD_BUFFERS.try_lock_for(Duration::from_millis(100)) else {
+        trace!("close_range {first}..={last}: managed fds are locked, closing them all");
+        return call_real_close_range(first, last, flags);
+    };
+    let Some(map) = buffers.as_mut() else {
+        drop(buffers);
+        return call_real_close_range(first, last, flags);
+    };
+    let closing: Vec<c_int> = map.keys().copied().filter(|&fd| in_range(fd)).collect();
+    let released: Vec<(c_int, FdBuffer)> = closing
+        .into_iter()
+        .filter_map(|fd| map.remove(&fd).map(|buffer| (fd, buffer)))
+        .collect();
+    let mut keep: Vec<c_uint> = map
+        .values()
+        .flat_map(|b| std::iter::once(b.socket).chain(b.kernel_fd))
+        .filter(|&own| in_range(own))
+        .map(|own| own as c_uint)
+        .collect();
+    drop(buffers);
+
+    for (fd, buffer) in released {
+        MANAGED_FDS.remove(fd);
+        trace!(
+            "close_range fd={fd} socket={} kernel_fd={:?}",
+            buffer.socket, buffer.kernel_fd
+        );
+        release(buffer);
+    }
+
+    keep.sort_unstable();
+    keep.dedup();
+    let mut result = 0;
+    let mut next = Some(first);
+    for own in keep {
+        if let Some(from) = next
+            && from < own
+        {
+            result = result.min(call_real_close_range(from, own - 1, flags));
+        }
+        next = own.checked_add(1);
+    }
+    if let Some(from) = next
+        && from <= last
+    {
+        result = result.min(call_real_close_range(from, last, flags));
+    }
+    result
+}
+
+/// Call the real close_range
+fn call_real_close_range(first: c_uint, last: c_uint, flags: c_int) -> c_int {
+    // SAFETY: close_range takes plain integers
+    unsafe {
+        if let Some(f) = REAL_CLOSE_RANGE.get() {
+            f(first, last, flags)
+        } else {
+            libc::syscall(libc::SYS_close_range, first, last, flags) as c_int
+        }
+    }
+}
+
+/// Call the real closefrom, or close_range where libc has none
+fn call_real_closefrom(lowfd: c_int) {
+    match REAL_CLOSEFROM.get() {
+        // SAFETY: closefrom takes a plain integer
+        Some(f) => unsafe { f(lowfd) },
+        None => {
+            call_real_close_range(lowfd.max(0) as c_uint, c_uint::MAX, 0);
+        }
+    }
+}
+
+/// Intercepted read()
+///
+/// For our fds, return whole inotify events deframed from the daemon stream.
+/// Like the kernel, a buffer too small for the next event fails with EINVAL
+/// and an event is never split across reads.
+///
+/// # Safety
+///
+/// This function is called by libc as a replacement for read.
+/// `buf` must point to at least `count` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize {
+    exec::adopt_inherited();
+    // Fast path: nothing of ours is open
+    if MANAGED_FDS.is_empty() || !is_managed_fd(fd) {
+        // SAFETY: Passing through to original function
+        return unsafe { call_real_read(fd, buf, count) };
+    }
+
+    std::panic::catch_unwind(|| {
+        if !is_ours(fd) {
+            // SAFETY: Passing through to original function
+            return unsafe { call_real_read(fd, buf, count) };
+        }
+        if buf.is_null() {
+            set_errno(libc::EFAULT);
+            return -1;
+        }
+        // SAFETY: Caller guarantees buf has count writable bytes
+        let out = unsafe { std::slice::from_raw_parts_mut(buf.cast::<u8>(), count) };
+        let n = read_managed(fd, out);
+        trace!("read fd={fd} count={count} -> {n}");
+        n
+    })
+    .unwrap_or_else(|_| {
+        set_errno(libc::EIO);
+        -1
+    })
+}
+
+/// Call the real read
+///
+/// # Safety
+///
+/// Same contract as read(2).
+unsafe fn call_real_read(fd: c_int, buf: *mut c_void, count: usize) -> isize {
+    // SAFETY: Calling the original function with the caller's arguments
+    unsafe {
+        if let Some(f) = REAL_READ.get() {
+            f(fd, buf, count)
+        } else {
+            libc::syscall(libc::SYS_read, fd as libc::c_long, buf, count) as isize
+        }
+    }
+}
+
+/// Intercepted ioctl()
+///
+/// FIONREAD on our fds reports the bytes of the whole events read() would
+/// hand out, as it does for an inotify fd; Qt sizes its read buffer by it,
+/// and gives up on the fd when it fails. Every other request goes to the
+/// eventfd.
+///
+/// # Safety
+///
+/// Same contract as ioctl(2). The variadic argument is taken as a pointer,
+/// which the Linux ABIs pass the same way.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ioctl(fd: c_int, request: libc::Ioctl, arg: *mut c_void) -> c_int {
+    exec::adopt_inherited();
+    if request != libc::FIONREAD || MANAGED_FDS.is_empty() || !is_managed_fd(fd) {
+        // SAFETY: Passing through to original function
+        return unsafe { call_real_ioctl(fd, request, arg) };
+    }
+
+    std::panic::catch_unwind(|| {
+        if !is_ours(fd) {
+            // SAFETY: Passing through to original function
+            return unsafe { call_real_ioctl(fd, request, arg) };
+        }
+        if arg.is_null() {
+            set_errno(libc::EFAULT);
+            return -1;
+        }
+        let Some(queued) = queued_bytes(fd) else {
+            set_errno(libc::EBADF);
+            return -1;
+        };
+        trace!("ioctl FIONREAD fd={fd} -> {queued}");
+        // SAFETY: FIONREAD's argument points to an int
+        unsafe { *arg.cast::<c_int>() = queued.min(c_int::MAX as usize) as c_int };
+        0
+    })
+    .unwrap_or_else(|_| {
+        set_errno(libc::EIO);
+        -1
+    })
+}
+
+/// Call the real ioctl
+///
+/// # Safety
+///
+/// Same contract as ioctl(2).
+unsafe fn call_real_ioctl(fd: c_int, request: libc::Ioctl, arg: *mut c_void) -> c_int {
+    // SAFETY: Calling the original function with the caller's arguments
+    unsafe {
+        if let Some(f) = REAL_IOCTL.get() {
+            f(fd, request, arg)
+        } else {
+            libc::syscall(libc::SYS_ioctl, fd as libc::c_long, request, arg) as c_int
+        }
+    }
+}
+
+/// Bytes of the events queued for `fd`, first pulling whatever the socket
+/// holds unless the drain thread does that
+fn queued_bytes(fd: c_int) -> Option<usize> {
+    if !with_buffer(fd, |b| b.is_drained())? {
+        while let Fill::Data = fill_buffer(fd, 0) {}
+    }
+    with_buffer(fd, |b| b.events.iter().map(Vec::len).sum())
+}
+
+/// Run an exec call with managed fds made ready for it
+///
+/// `envp` is the environment the call passes on (null for the process
+/// environment); `call` gets a replacement when that had to change. Without
+/// managed fds the call goes straight through, as it may be running in a
+/// vfork child where allocating is unsafe.
+fn exec_with(
+    envp: *const *const c_char,
+    call: impl FnOnce(Option<*const *const c_char>) -> c_int,
+) -> c_int {
+    if MANAGED_FDS.is_empty() {
+        return call(None);
+    }
+    match std::panic::catch_unwind(|| {
+        let prepared = exec::prepare(config().exec);
+        let env = exec::environment(envp, prepared.handle());
+        (prepared, env)
+    }) {
+        Ok((prepared, env)) => {
+            let ret = call(env.as_ref().map(|env| env.as_ptr()));
+            // Still here, so exec failed: put the fds back
+            drop(prepared);
+            ret
+        }
+        Err(_) => call(None),
+    }
+}
+
+/// Report a missing libc symbol from an exec call
+fn no_exec() -> c_int {
+    set_errno(libc::ENOSYS);
+    -1
+}
+
+/// Intercepted execve()
+///
+/// Managed fds that would survive the exec are closed or handed over first,
+/// as `FAKENOTIFY_EXEC` says.
+///
+/// # Safety
+///
+/// Same contract as execve(2).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    exec_with(envp, |env| match REAL_EXECVE.get() {
+        // SAFETY: Passing through to the original function
+        Some(f) => unsafe { f(path, argv, env.unwrap_or(envp)) },
+        None => no_exec(),
+    })
+}
+
+/// Intercepted execv(), which libc runs without going through execve()
+///
+/// # Safety
+///
+/// Same contract as execv(3).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    exec_with(std::ptr::null(), |env| match (env, REAL_EXECVE.get()) {
+        // SAFETY: Passing through to the original function
+        (Some(env), Some(f)) => unsafe { f(path, argv, env) },
+        _ => match REAL_EXECV.get() {
+            // SAFETY: Passing through to the original function
+            Some(f) => unsafe { f(path, argv) },
+            None => no_exec(),
+        },
+    })
+}
+
+/// Intercepted execvp()
+///
+/// # Safety
+///
+/// Same contract as execvp(3).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    exec_with(std::ptr::null(), |env| match (env, REAL_EXECVPE.get()) {
+        // SAFETY: Passing through to the original function
+        (Some(env), Some(f)) => unsafe { f(file, argv, env) },
+        _ => match REAL_EXECVP.get() {
+            // SAFETY: Passing through to the original function
+            Some(f) => unsafe { f(file, argv) },
+            None => no_exec(),
+        },
+    })
+}
+
+/// Intercepted execvpe()
+///
+/// # Safety
+///
+/// Same contract as execvpe(3).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn execvpe(
+    file: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    exec_with(envp, |env| match REAL_EXECVPE.get() {
+        // SAFETY: Passing through to the original function
+        Some(f) => unsafe { f(file, argv, env.unwrap_or(envp)) },
+        None => no_exec(),
+    })
+}
+
+/// Intercepted fexecve()
+///
+/// # Safety
+///
+/// Same contract as fexecve(3).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fexecve(
+    fd: c_int,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    exec_with(envp, |env| match REAL_FEXECVE.get() {
+        // SAFETY: Passing through to the original function
+        Some(f) => unsafe { f(fd, argv, env.unwrap_or(envp)) },
+        None => no_exec(),
+    })
+}
+
+/// read() for a managed fd, honoring the fd's O_NONBLOCK flag
+///
+/// Once the drain thread runs, this only takes from the queue and waits on
+/// the eventfd; before that it pulls from the socket itself. Threads reading
+/// the fd at once take turns under its read lock, but wait outside it, so a
+/// blocked reader never holds up a nonblocking one.
+fn read_managed(fd: c_int, out: &mut [u8]) -> isize {
+    // SAFETY: fcntl F_GETFL on a valid fd has no side effects
+    let nonblocking = unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_NONBLOCK != 0;
+    let Some(reading) = with_buffer(fd, |b| Arc::clone(&b.reading)) else {
+        set_errno(libc::EBADF);
+        return -1;
+    };
+
+    loop {
+        let turn = {
+            let _reading = reading.lock();
+            take_turn(fd, out)
+        };
+        let drained = match turn {
+            Turn::Done(n) => return n,
+            Turn::Empty { drained } => drained,
+        };
+        if nonblocking {
+            set_errno(libc::EAGAIN);
+            return -1;
+        }
+
+        if drained {
+            let mut pfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: pfd is a valid pollfd; errno (e.g. EINTR) is left for the caller
+            if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                return -1;
+            }
+        } else if let Err(Fill::Error(err)) = wait_sources(fd, -1) {
+            set_errno(err);
+            return -1;
+        }
+    }
+}
+
+/// What one turn at reading a managed fd came to
+enum Turn {
+    /// read() returns this, with errno set if it is -1
+    Done(isize),
+    /// No whole event is queued; whether the drain thread is filling the
+    /// queue, rather than the reader
+    Empty { drained: bool },
+}
+
+/// Take whole events into `out`, first pulling whatever the socket holds
+/// unless the drain thread does that
+///
+/// Called under the fd's read lock: events are taken in stream order, each
+/// by exactly one reader, and never split. Never waits, so a frame that has
+/// only partly arrived comes back as [`Turn::Empty`] rather than blocking a
+/// nonblocking reader.
+fn take_turn(fd: c_int, out: &mut [u8]) -> Turn {
+    loop {
+        let taken = with_buffer(fd, |b| {
+            let taken = b.take_events(out);
+            clear_ready(fd, b);
+            (taken, b.hangup, b.is_drained())
+        });
+        match taken {
+            Some((Some(0), ..)) => {
+                set_errno(libc::EINVAL);
+                return Turn::Done(-1);
+            }
+            Some((Some(n), ..)) => return Turn::Done(n as isize),
+            Some((None, Some(0), _)) => return Turn::Done(0),
+            Some((None, Some(err), _)) => {
+                set_errno(err);
+                return Turn::Done(-1);
+            }
+            Some((None, None, true)) => return Turn::Empty { drained: true },
+            Some((None, None, false)) => {}
+            None => {
+                set_errno(libc::EBADF);
+                return Turn::Done(-1);
+            }
+        }
+
+        // Keep pulling until the socket runs dry, as a frame can take
+        // several reads to come in whole
+        match fill_buffer(fd, 0) {
+            Fill::Data => {}
+            Fill::WouldBlock => return Turn::Empty { drained: false },
+            Fill::Eof => return Turn::Done(0),
+            Fill::Error(err) => {
+                set_errno(err);
+                return Turn::Done(-1);
+            }
+        }
+    }
+}
+
 // ============================================================================
 // Tests
 // ============================================================================
@@ -479,6 +2001,7 @@ pub unsafe extern "C" fn close(fd: c_int) -> c_int {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use fakenotify_protocol::InotifyEvent;
     use std::sync::Mutex;
 
     /// Mutex to serialize tests that manipulate environment variables.
@@ -487,18 +2010,676 @@ mod tests {
 
     #[test]
     fn test_managed_fds() {
-        // Initialize the set
-        *MANAGED_FDS.write() = Some(HashSet::new());
-
         assert!(!is_managed_fd(42));
 
-        register_fd(42);
+        register_fd(42, -1);
         assert!(is_managed_fd(42));
 
         unregister_fd(42);
         assert!(!is_managed_fd(42));
     }
 
+    #[test]
+    fn test_real_fn_resolves_lazily() {
+        static REAL_GETPID: RealFn<unsafe extern "C" fn() -> libc::pid_t> = RealFn::new(c"getpid");
+        static MISSING_FN: RealFn<unsafe extern "C" fn()> =
+            RealFn::new(c"fakenotify_no_such_symbol");
+
+        assert!(REAL_GETPID.resolved.get().is_none());
+        let getpid = REAL_GETPID.get().expect("getpid should resolve");
+        // SAFETY: getpid has no preconditions
+        assert_eq!(unsafe { getpid() }, std::process::id() as libc::pid_t);
+
+        assert!(MISSING_FN.get().is_none());
+        assert_eq!(MISSING_FN.resolved.get(), Some(&None));
+    }
+
+    #[test]
+    fn test_fd_buffer_splits_events_and_responses() {
+        use fakenotify_protocol::{EventMask, InotifyEvent};
+
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        let mut stream = FramedMessage::frame_event(&event);
+        stream.extend(FramedMessage::frame(
+            &Response::Heartbeat.to_bytes().unwrap(),
+        ));
+        stream.extend(FramedMessage::frame(
+            &Response::WatchAdded { wd: 2 }.to_bytes().unwrap(),
+        ));
+
+        // Feed one byte at a time to exercise partial frames
+        let mut buffer = FdBuffer::default();
+        for byte in &stream {
+            assert!(buffer.push_bytes(std::slice::from_ref(byte)));
+        }
+
+        assert_eq!(buffer.events.len(), 1);
+        assert_eq!(buffer.heartbeats, 1);
+        assert_eq!(
+            buffer.responses.pop_front(),
+            Some(Response::WatchAdded { wd: 2 })
+        );
+        assert!(buffer.responses.is_empty());
+        assert!(buffer.partial.is_empty());
+    }
+
+    #[test]
+    fn test_fd_buffer_unpacks_event_batches() {
+        let events = [b"a.mkv".as_slice(), b"b.mkv"].map(|name| {
+            InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(name)
+        });
+        let batch = Response::EventBatch {
+            count: 2,
+            events: events.concat(),
+        };
+        let mut buffer = FdBuffer::default();
+        assert!(buffer.push_bytes(&FramedMessage::frame(&batch.to_bytes().unwrap())));
+
+        assert_eq!(buffer.events, events);
+        assert!(buffer.responses.is_empty());
+    }
+
+    #[test]
+    fn test_fd_buffer_never_splits_events() {
+        use fakenotify_protocol::{EventMask, InotifyEvent};
+
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        let mut buffer = FdBuffer::default();
+        buffer.events.push_back(event.clone());
+        buffer.events.push_back(event.clone());
+
+        // Too small for even one event
+        let mut small = [0u8; 8];
+        assert_eq!(buffer.take_events(&mut small), Some(0));
+
+        // Room for one and a half events returns exactly one
+        let mut out = vec![0u8; event.len() + event.len() / 2];
+        assert_eq!(buffer.take_events(&mut out), Some(event.len()));
+        assert_eq!(buffer.events.len(), 1);
+
+        buffer.events.clear();
+        assert_eq!(buffer.take_events(&mut out), None);
+    }
+
+    #[test]
+    fn test_full_queue_ends_in_one_overflow() {
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        let mut buffer = FdBuffer {
+            capacity: 2,
+            ..FdBuffer::default()
+        };
+        for _ in 0..5 {
+            buffer.queue_event(event.clone());
+        }
+
+        assert_eq!(buffer.events.len(), 3);
+        let overflow = InotifyEvent::from_bytes(&buffer.events[2]).unwrap();
+        assert_eq!(overflow.wd, -1);
+        assert_eq!(overflow.mask, EventMask::IN_Q_OVERFLOW.bits());
+    }
+
+    #[test]
+    fn test_drain_thread_queues_events_and_acks_heartbeats() {
+        use std::io::Read;
+        use std::os::unix::io::IntoRawFd;
+
+        let (ours, mut daemon) = UnixStream::pair().unwrap();
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        register_fd(fd, ours.into_raw_fd());
+        start_drain(fd);
+
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        let mut stream = FramedMessage::frame(&Response::Heartbeat.to_bytes().unwrap());
+        stream.extend(FramedMessage::frame_event(&event));
+        daemon.write_all(&stream).unwrap();
+
+        // The heartbeat is answered without the app reading
+        let ack = FramedMessage::frame(&Request::HeartbeatAck.to_bytes().unwrap());
+        let mut received = vec![0u8; ack.len()];
+        daemon.read_exact(&mut received).unwrap();
+        assert_eq!(received, ack);
+
+        let mut out = [0u8; 256];
+        assert_eq!(read_managed(fd, &mut out), event.len() as isize);
+        assert_eq!(&out[..event.len()], &event[..]);
+
+        // Nothing left: the eventfd is no longer readable
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: pfd is a valid pollfd
+        assert_eq!(unsafe { libc::poll(&mut pfd, 1, 0) }, 0);
+
+        // The daemon going away wakes the app with EOF
+        drop(daemon);
+        assert_eq!(read_managed(fd, &mut out), 0);
+
+        // SAFETY: fd is ours; close joins the drain thread
+        assert_eq!(unsafe { close(fd) }, 0);
+        assert!(!is_managed_fd(fd));
+    }
+
+    /// Register an eventfd at `fd` whose connection sits at `socket`,
+    /// returning the daemon's end; tests keep to numbers of their own
+    fn managed_at(fd: c_int, socket: c_int) -> UnixStream {
+        use std::os::unix::io::AsRawFd;
+
+        let (ours, daemon) = UnixStream::pair().unwrap();
+        // SAFETY: eventfd and dup3 have no memory-safety preconditions
+        unsafe {
+            let ready = libc::eventfd(0, libc::EFD_CLOEXEC);
+            assert_eq!(libc::dup3(ready, fd, libc::O_CLOEXEC), fd);
+            assert_eq!(
+                libc::dup3(ours.as_raw_fd(), socket, libc::O_CLOEXEC),
+                socket
+            );
+            libc::syscall(libc::SYS_close, ready as libc::c_long);
+        }
+        register_fd(fd, socket);
+        daemon
+    }
+
+    fn is_open(fd: c_int) -> bool {
+        // SAFETY: F_GETFD has no side effects
+        unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+    }
+
+    /// Whether the daemon's end sees the connection closed
+    fn hung_up(daemon: &mut UnixStream) -> bool {
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        matches!(daemon.read(&mut [0u8; 64]), Ok(0))
+    }
+
+    #[test]
+    fn test_close_range_keeps_connections_of_open_fds() {
+        // 900 and its connection at 902 go, while 910 stays open with its
+        // connection at 901, inside the range
+        let mut first = managed_at(900, 902);
+        let mut second = managed_at(910, 901);
+
+        // SAFETY: the range holds only the fds above
+        assert_eq!(unsafe { close_range(900, 905, 0) }, 0);
+        assert!(!is_managed_fd(900) && !is_open(900) && !is_open(902));
+        assert!(hung_up(&mut first));
+        assert!(is_managed_fd(910) && is_open(910) && is_open(901));
+
+        // SAFETY: 910 is ours
+        assert_eq!(unsafe { close(910) }, 0);
+        assert!(!is_open(901));
+        assert!(hung_up(&mut second));
+    }
+
+    #[test]
+    fn test_closefrom_lets_go_of_managed_fds() {
+        let mut daemon = managed_at(960, 961);
+        start_drain(960);
+
+        // SAFETY: nothing but the fds above is at 960 or higher
+        unsafe { closefrom(960) };
+        assert!(!is_managed_fd(960) && !is_open(960) && !is_open(961));
+        assert!(hung_up(&mut daemon));
+    }
+
+    #[test]
+    fn test_reused_app_fd_passes_through() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut daemon = managed_at(920, 921);
+        start_drain(920);
+
+        // The app fd is closed behind our back and a file gets its number
+        let path = std::env::temp_dir().join(format!("fakenotify-reuse-{}", std::process::id()));
+        std::fs::write(&path, b"hello").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        // SAFETY: dup3 has no memory-safety preconditions
+        assert_eq!(
+            unsafe { libc::dup3(file.as_raw_fd(), 920, libc::O_CLOEXEC) },
+            920
+        );
+
+        // Reads reach the file, and our connection is let go of
+        let mut out = [0u8; 16];
+        // SAFETY: out is a valid buffer of the given length
+        assert_eq!(unsafe { read(920, out.as_mut_ptr().cast(), out.len()) }, 5);
+        assert_eq!(&out[..5], b"hello");
+        assert!(!is_managed_fd(920) && !is_open(921));
+        assert!(hung_up(&mut daemon));
+
+        // SAFETY: 920 is the file's now
+        assert_eq!(unsafe { close(920) }, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fionread_counts_queued_events() {
+        let mut daemon = managed_at(930, 931);
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        for _ in 0..2 {
+            daemon
+                .write_all(&FramedMessage::frame_event(&event))
+                .unwrap();
+        }
+
+        let ioctl_fionread = || {
+            let mut queued: c_int = -1;
+            // SAFETY: queued is a valid int for FIONREAD to fill in
+            let ret = unsafe { ioctl(930, libc::FIONREAD, (&raw mut queued).cast()) };
+            assert_eq!(ret, 0);
+            queued as usize
+        };
+        assert_eq!(ioctl_fionread(), 2 * event.len());
+        let mut out = [0u8; 256];
+        // SAFETY: out is a valid buffer of the given length
+        let n = unsafe { read(930, out.as_mut_ptr().cast(), out.len()) };
+        assert_eq!(n as usize, 2 * event.len());
+        assert_eq!(ioctl_fionread(), 0);
+
+        // Other requests reach the eventfd
+        let mut on: c_int = 1;
+        // SAFETY: on is a valid int for FIONBIO to read
+        assert_eq!(
+            unsafe { ioctl(930, libc::FIONBIO, (&raw mut on).cast()) },
+            0
+        );
+
+        // SAFETY: 930 is ours
+        assert_eq!(unsafe { close(930) }, 0);
+        assert!(hung_up(&mut daemon));
+    }
+
+    #[test]
+    fn test_lost_socket_is_left_alone() {
+        let _daemon = managed_at(925, 926);
+
+        // Our connection is closed behind our back and a pipe gets its number
+        let mut pipe = [0; 2];
+        // SAFETY: pipe is a valid array of two fds
+        unsafe {
+            assert_eq!(libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC), 0);
+            assert_eq!(libc::dup3(pipe[0], 926, libc::O_CLOEXEC), 926);
+        }
+
+        // The fd reads like one whose daemon went away
+        let mut out = [0u8; 64];
+        // SAFETY: out is a valid buffer of the given length
+        assert_eq!(unsafe { read(925, out.as_mut_ptr().cast(), out.len()) }, 0);
+        assert!(is_managed_fd(925));
+
+        // Watches fail without touching the pipe
+        assert!(send_bytes(925, b"").is_none());
+        let path = std::ffi::CString::new("/tmp").unwrap();
+        // SAFETY: path is a valid C string
+        let wd = unsafe { inotify_add_watch(925, path.as_ptr(), EventMask::IN_CREATE.bits()) };
+        assert_eq!(wd, -1);
+        // SAFETY: 925 is ours
+        assert_eq!(unsafe { inotify_rm_watch(925, 1) }, -1);
+
+        // Closing it leaves the pipe open
+        // SAFETY: 925 is ours
+        assert_eq!(unsafe { close(925) }, 0);
+        assert!(is_open(926));
+        for fd in [pipe[0], pipe[1], 926] {
+            // SAFETY: the pipe's fds are this test's own
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    #[test]
+    fn test_concurrent_readers_get_whole_events_in_order() {
+        use std::os::unix::io::IntoRawFd;
+
+        // Readers pulling from the socket themselves, then behind a drain thread
+        for drained in [false, true] {
+            let (ours, mut daemon) = UnixStream::pair().unwrap();
+            // SAFETY: eventfd has no memory-safety preconditions
+            let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+            register_fd(fd, ours.into_raw_fd());
+            if drained {
+                start_drain(fd);
+            }
+
+            // Names of varying length, so events straddle reads and writes;
+            // the cookie numbers them
+            const COUNT: u32 = 2000;
+            let stream: Vec<u8> = (0..COUNT)
+                .flat_map(|i| {
+                    let name = "x".repeat(i as usize % 40);
+                    let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), i)
+                        .to_bytes_with_name(name.as_bytes());
+                    FramedMessage::frame_event(&event)
+                })
+                .collect();
+
+            let readers: Vec<_> = [97, 256]
+                .into_iter()
+                .map(|size| {
+                    thread::spawn(move || {
+                        let mut cookies = Vec::new();
+                        let mut out = vec![0u8; size];
+                        loop {
+                            let n = read_managed(fd, &mut out);
+                            assert!(n >= 0, "read failed");
+                            if n == 0 {
+                                return cookies;
+                            }
+                            let mut events = EventBuffer::new(&out[..n as usize]);
+                            while let Some((header, _)) = events.next_raw() {
+                                cookies.push(header.cookie);
+                            }
+                            assert!(events.remainder().is_empty(), "split event");
+                        }
+                    })
+                })
+                .collect();
+
+            // Odd-sized writes, so frames arrive in pieces
+            for piece in stream.chunks(61) {
+                daemon.write_all(piece).unwrap();
+            }
+            drop(daemon);
+
+            let mut all = Vec::new();
+            for reader in readers {
+                let cookies = reader.join().unwrap();
+                assert!(cookies.is_sorted(), "events reordered");
+                all.extend(cookies);
+            }
+            all.sort_unstable();
+            assert_eq!(all, (0..COUNT).collect::<Vec<_>>());
+
+            // SAFETY: fd is ours
+            assert_eq!(unsafe { close(fd) }, 0);
+        }
+    }
+
+    #[test]
+    fn test_nonblocking_read_waits_out_partial_frames() {
+        use std::os::unix::io::IntoRawFd;
+
+        let (ours, mut daemon) = UnixStream::pair().unwrap();
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        register_fd(fd, ours.into_raw_fd());
+        let mut out = [0u8; 4096];
+        let read_again = |out: &mut [u8]| {
+            set_errno(0);
+            let n = read_managed(fd, out);
+            // SAFETY: __errno_location returns a valid pointer to the thread-local errno
+            (n, unsafe { *libc::__errno_location() })
+        };
+
+        // Nothing sent yet, then half a frame: EAGAIN, not a wait
+        assert_eq!(read_again(&mut out), (-1, libc::EAGAIN));
+        let event = InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        let framed = FramedMessage::frame_event(&event);
+        let (head, tail) = framed.split_at(framed.len() / 2);
+        daemon.write_all(head).unwrap();
+        assert_eq!(read_again(&mut out), (-1, libc::EAGAIN));
+
+        // The rest completes it
+        daemon.write_all(tail).unwrap();
+        assert_eq!(read_again(&mut out).0, event.len() as isize);
+        assert_eq!(&out[..event.len()], &event[..]);
+        assert_eq!(read_again(&mut out), (-1, libc::EAGAIN));
+
+        // A batch larger than one pull off the socket arrives whole
+        let events: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                InotifyEvent::new(1, EventMask::IN_CREATE.bits(), i).to_bytes_with_name(b"ep.mkv")
+            })
+            .collect();
+        let batch = Response::EventBatch {
+            count: events.len() as u32,
+            events: events.concat(),
+        };
+        daemon
+            .write_all(&FramedMessage::frame(&batch.to_bytes().unwrap()))
+            .unwrap();
+        let (n, _) = read_again(&mut out);
+        assert_eq!(n as usize, out.len() / events[0].len() * events[0].len());
+
+        drop(daemon);
+        // SAFETY: fd is ours
+        assert_eq!(unsafe { close(fd) }, 0);
+    }
+
+    #[test]
+    fn test_concurrent_requests_get_their_own_answers() {
+        use std::os::unix::io::IntoRawFd;
+
+        let (ours, mut daemon) = UnixStream::pair().unwrap();
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        register_fd(fd, ours.into_raw_fd());
+        with_buffer(fd, |b| b.request_ids = true);
+
+        let requesters: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|wd| thread::spawn(move || send_request(fd, &Request::RemoveWatch { wd })))
+            .collect();
+
+        // Take both requests, then answer them in the opposite order
+        let mut pending = Vec::new();
+        for _ in 0..2 {
+            let mut len = [0u8; 4];
+            daemon.read_exact(&mut len).unwrap();
+            let mut payload = vec![0u8; FramedMessage::read_length(&len).unwrap() as usize];
+            daemon.read_exact(&mut payload).unwrap();
+            let Request::Tagged { id, request } = Request::from_bytes(&payload).unwrap() else {
+                panic!("expected Tagged");
+            };
+            let Request::RemoveWatch { wd } = *request else {
+                panic!("expected RemoveWatch");
+            };
+            pending.push((id, wd));
+        }
+        assert_ne!(pending[0].0, pending[1].0);
+        for (id, wd) in pending.into_iter().rev() {
+            let answer = Response::Tagged {
+                id,
+                response: Box::new(Response::error(wd.to_string())),
+            };
+            daemon
+                .write_all(&FramedMessage::frame(&answer.to_bytes().unwrap()))
+                .unwrap();
+        }
+
+        for (wd, requester) in [1, 2].into_iter().zip(requesters) {
+            assert_eq!(
+                requester.join().unwrap(),
+                Some(Response::error(wd.to_string()))
+            );
+        }
+        // SAFETY: fd is ours
+        assert_eq!(unsafe { close(fd) }, 0);
+    }
+
+    #[test]
+    fn test_add_watch_reaches_daemon_tagged_or_not() {
+        use std::os::unix::io::IntoRawFd;
+
+        let (ours, mut daemon) = UnixStream::pair().unwrap();
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // SAFETY: eventfd has no memory-safety preconditions
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        register_fd(fd, ours.into_raw_fd());
+
+        let long = format!("/tmp/{}", "a".repeat(WATCH_FRAME_MAX));
+        for (tagged, path) in [(false, "/tmp/a"), (true, "/tmp/b"), (true, long.as_str())] {
+            with_buffer(fd, |b| b.request_ids = tagged);
+            let path = PathBuf::from(path);
+            let requester = {
+                let path = path.clone();
+                thread::spawn(move || send_add_watch(fd, &path, 0x100))
+            };
+
+            let mut len = [0u8; 4];
+            daemon.read_exact(&mut len).unwrap();
+            let mut payload = vec![0u8; FramedMessage::read_length(&len).unwrap() as usize];
+            daemon.read_exact(&mut payload).unwrap();
+            let (id, request) = match Request::from_bytes(&payload).unwrap() {
+                Request::Tagged { id, request } => (Some(id), *request),
+                request => (None, request),
+            };
+            assert_eq!(id.is_some(), tagged);
+            assert_eq!(request, Request::AddWatch { path, mask: 0x100 });
+
+            let answer = Response::WatchAdded { wd: 3 };
+            let answer = match id {
+                Some(id) => Response::Tagged {
+                    id,
+                    response: Box::new(answer),
+                },
+                None => answer,
+            };
+            daemon
+                .write_all(&FramedMessage::frame(&answer.to_bytes().unwrap()))
+                .unwrap();
+            assert_eq!(
+                requester.join().unwrap(),
+                Some(Response::WatchAdded { wd: 3 })
+            );
+        }
+        // SAFETY: fd is ours
+        assert_eq!(unsafe { close(fd) }, 0);
+    }
+
+    #[test]
+    fn test_control_request_attaches_to_session() {
+        use std::os::unix::net::UnixListener;
+
+        let path =
+            std::env::temp_dir().join(format!("fakenotify-control-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let greeting = Response::ClientRegistered {
+                client_id: 2,
+                session: String::new(),
+                capabilities: 0,
+            };
+            stream
+                .write_all(&FramedMessage::frame(&greeting.to_bytes().unwrap()))
+                .unwrap();
+            let mut read_request = || {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut payload = vec![0u8; FramedMessage::read_length(&len).unwrap() as usize];
+                stream.read_exact(&mut payload).unwrap();
+                Request::from_bytes(&payload).unwrap()
+            };
+            assert_eq!(
+                read_request(),
+                Request::AttachControl {
+                    session: "5f0c8e2a".to_string()
+                }
+            );
+            assert_eq!(read_request(), Request::RemoveWatch { wd: 3 });
+            for response in [
+                Response::ControlAttached { client_id: 1 },
+                Response::WatchRemoved,
+            ] {
+                stream
+                    .write_all(&FramedMessage::frame(&response.to_bytes().unwrap()))
+                    .unwrap();
+            }
+        });
+
+        assert_eq!(
+            send_control_request(
+                &path,
+                "5f0c8e2a".to_string(),
+                &Request::RemoveWatch { wd: 3 }
+            ),
+            Some(Response::WatchRemoved)
+        );
+        daemon.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_probe_keeps_instance_serving_path() {
+        use std::os::unix::net::UnixListener;
+
+        let path =
+            std::env::temp_dir().join(format!("fakenotify-probe-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let daemon = thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                for response in [
+                    Response::ClientRegistered {
+                        client_id: 1,
+                        session: String::new(),
+                        capabilities: 0,
+                    },
+                    Response::Heartbeat,
+                ] {
+                    let framed = FramedMessage::frame(&response.to_bytes().unwrap());
+                    stream.write_all(&framed).unwrap();
+                }
+                let mut request = vec![0u8; 4];
+                stream.read_exact(&mut request).unwrap();
+                let mut payload = vec![0u8; FramedMessage::read_length(&request).unwrap() as usize];
+                stream.read_exact(&mut payload).unwrap();
+                assert_eq!(Request::from_bytes(&payload).unwrap(), Request::Hello);
+                let hello = Response::Hello {
+                    roots: vec![PathBuf::from("/mnt/media")],
+                    capabilities: 0,
+                };
+                let framed = FramedMessage::frame(&hello.to_bytes().unwrap());
+                stream.write_all(&framed).unwrap();
+            }
+        });
+
+        // The kept connection times out like any other, not like the probe
+        let kept = probe_instance(&path, Path::new("/mnt/media/tv")).unwrap();
+        assert_eq!(kept.read_timeout().unwrap(), Some(READ_TIMEOUT));
+        assert_eq!(kept.write_timeout().unwrap(), Some(WRITE_TIMEOUT));
+        assert!(probe_instance(&path, Path::new("/mnt/mediaX")).is_none());
+        daemon.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_kernel_wd_mapping() {
+        assert_eq!(app_wd_to_kernel(kernel_wd_to_app(1)), Some(1));
+        assert_eq!(app_wd_to_kernel(7), None);
+        assert_eq!(app_wd_to_kernel(-1), None);
+        assert!(kernel_wd_to_app(1) > 0);
+    }
+
+    #[test]
+    fn test_push_kernel_events_tags_wds() {
+        use fakenotify_protocol::EventMask;
+
+        let mut raw = InotifyEvent::new(3, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"f");
+        raw.extend(InotifyEvent::new(-1, EventMask::IN_Q_OVERFLOW.bits(), 0).header_to_bytes());
+
+        let mut buffer = FdBuffer::default();
+        buffer.push_kernel_events(&raw);
+
+        let first = InotifyEvent::from_bytes(&buffer.events[0]).unwrap();
+        assert_eq!(first.wd, kernel_wd_to_app(3));
+        let overflow = InotifyEvent::from_bytes(&buffer.events[1]).unwrap();
+        assert_eq!(overflow.wd, -1);
+    }
+
     #[test]
     fn test_socket_path_uses_xdg() {
         let _guard = ENV_LOCK.lock().unwrap();
@@ -509,8 +2690,8 @@ mod tests {
             std::env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
         }
 
-        let path = get_socket_path();
-        assert_eq!(path, PathBuf::from("/run/user/1000/fakenotify.sock"));
+        let paths = socket_paths();
+        assert_eq!(paths[0], PathBuf::from("/run/user/1000/fakenotify.sock"));
 
         // Clean up
         // SAFETY: Tests run serially (protected by ENV_LOCK)
@@ -528,8 +2709,8 @@ mod tests {
             std::env::set_var("FAKENOTIFY_SOCKET", "/tmp/test.sock");
         }
 
-        let path = get_socket_path();
-        assert_eq!(path, PathBuf::from("/tmp/test.sock"));
+        let paths = socket_paths();
+        assert_eq!(paths, vec![PathBuf::from("/tmp/test.sock")]);
 
         // Clean up
         // SAFETY: Tests run serially (protected by ENV_LOCK)
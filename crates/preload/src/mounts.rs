@@ -0,0 +1,63 @@
+//! Filesystem type detection for hybrid routing.
+//!
+//! Kernel inotify works on local filesystems but is silent on network and
+//! FUSE mounts, where changes can happen on another host. Hybrid mode sends
+//! only the latter to the daemon.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// `f_type` magic numbers (see statfs(2)) of filesystems where kernel
+/// inotify misses remote changes
+const REMOTE_FS_MAGIC: &[i64] = &[
+    0x6969,      // NFS_SUPER_MAGIC
+    0xFF53_4D42, // CIFS_MAGIC_NUMBER
+    0xFE53_4D42, // SMB2_MAGIC_NUMBER
+    0x517B,      // SMB_SUPER_MAGIC
+    0x6573_5546, // FUSE_SUPER_MAGIC
+    0x0102_1997, // V9FS_MAGIC
+    0x00C3_6400, // CEPH_SUPER_MAGIC
+    0x5346_414F, // AFS_SUPER_MAGIC
+    0x6B41_4653, // AFS_FS_MAGIC (kAFS)
+    0x0BD0_0BD0, // LUSTRE_SUPER_MAGIC
+    0x4750_4653, // GPFS_SUPER_MAGIC
+];
+
+/// Whether an `f_type` value belongs to a remote filesystem
+pub fn is_remote_magic(magic: i64) -> bool {
+    REMOTE_FS_MAGIC.contains(&magic)
+}
+
+/// Whether `path` lives on a remote filesystem
+///
+/// Returns `None` if the path cannot be stat'ed (e.g. it does not exist).
+pub fn is_remote_fs(path: &Path) -> Option<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statfs is plain old data, so all-zero is a valid value
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid C string and buf is writable
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return None;
+    }
+    // f_type is u32 on some targets; compare as the unsigned magic
+    Some(is_remote_magic(buf.f_type as u32 as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_magic() {
+        assert!(is_remote_magic(0x6969));
+        assert!(is_remote_magic(0xFF53_4D42));
+        assert!(!is_remote_magic(0xEF53)); // ext4
+        assert!(!is_remote_magic(0x0102_1994)); // tmpfs
+    }
+
+    #[test]
+    fn test_missing_path() {
+        assert_eq!(is_remote_fs(Path::new("/nonexistent/fakenotify")), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! On-demand start of a per-user daemon.
+//!
+//! With `FAKENOTIFY_AUTOSPAWN=1`, an `inotify_init` that finds no daemon
+//! starts `fakenotifyd` for the current user on
+//! `$XDG_RUNTIME_DIR/fakenotify.sock`, the way ssh-agent or dbus are launched
+//! on demand. The daemon detaches itself (`start --daemonize`), so it
+//! outlives the process that started it; the process starting it leaves
+//! the app's session and files behind first. A lock file in the instance
+//! directory serializes racing processes: whoever gets it second finds the
+//! first one's daemon answering and starts nothing.
+
+use fakenotify_protocol::{INSTANCE_DIR, SOCKET_ENV_VAR};
+use std::fs::{DirBuilder, File};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long a freshly started daemon may take to accept connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start a daemon for this user unless one is already answering
+///
+/// Returns the socket it listens on, or `None` if an explicit socket is set,
+/// there is no runtime directory, or the daemon did not come up.
+pub fn spawn_user_daemon(daemon: &Path) -> Option<PathBuf> {
+    if std::env::var_os(SOCKET_ENV_VAR).is_some() {
+        return None;
+    }
+    let runtime_dir = PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?);
+    spawn_in(&runtime_dir, daemon)
+}
+
+/// [`spawn_user_daemon`] with the runtime directory passed in
+fn spawn_in(runtime_dir: &Path, daemon: &Path) -> Option<PathBuf> {
+    let socket = runtime_dir.join("fakenotify.sock");
+    let instance_dir = runtime_dir.join(INSTANCE_DIR);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&instance_dir)
+        .ok()?;
+
+    // Held until we return, so the next process sees our daemon answering
+    let lock = File::create(instance_dir.join("autospawn.lock")).ok()?;
+    // SAFETY: flock on an fd we own
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return None;
+    }
+    if UnixStream::connect(&socket).is_ok() {
+        return Some(socket);
+    }
+
+    // Keep the preload out of the daemon
+    let mut command = Command::new(daemon);
+    command
+        .arg("start")
+        .arg("--daemonize")
+        .arg("--socket")
+        .arg(&socket)
+        .env_remove("LD_PRELOAD")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: detach only makes async-signal-safe calls
+    unsafe { command.pre_exec(detach) };
+    let started = command.status().is_ok_and(|status| status.success());
+    if !started {
+        return None;
+    }
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Instant::now() < deadline {
+        if UnixStream::connect(&socket).is_ok() {
+            return Some(socket);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+/// Leave the app's session, so its terminal's signals do not reach the
+/// daemon, and mark the app's fds close-on-exec, so the daemon does not
+/// hold its files open
+///
+/// Runs between fork and exec, so it only makes async-signal-safe calls.
+fn detach() -> std::io::Result<()> {
+    // SAFETY: setsid, syscall and fcntl take plain integers
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::syscall(
+            libc::SYS_close_range,
+            3,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        ) == 0
+        {
+            return Ok(());
+        }
+        // Kernels before 5.11
+        let max = libc::sysconf(libc::_SC_OPEN_MAX).clamp(3, 65536) as libc::c_int;
+        for fd in 3..max {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_running_daemon_is_reused() {
+        let runtime_dir =
+            std::env::temp_dir().join(format!("fakenotify-spawn-{}", std::process::id()));
+        std::fs::create_dir_all(&runtime_dir).unwrap();
+        let missing = Path::new("/nonexistent/fakenotifyd");
+
+        // Nothing answering and nothing to start
+        assert_eq!(spawn_in(&runtime_dir, missing), None);
+        assert!(
+            runtime_dir
+                .join(INSTANCE_DIR)
+                .join("autospawn.lock")
+                .exists()
+        );
+
+        let socket = runtime_dir.join("fakenotify.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert_eq!(spawn_in(&runtime_dir, missing), Some(socket));
+
+        std::fs::remove_dir_all(&runtime_dir).unwrap();
+    }
+
+    #[test]
+    fn test_detach_leaves_session_and_files_behind() {
+        // An fd the app left inheritable
+        let file = File::open("/proc/self/exe").unwrap();
+        // SAFETY: dup has no memory-safety preconditions
+        let leaked = unsafe { libc::dup(file.as_raw_fd()) };
+        assert!(leaked > 2);
+
+        // The shell leads its own session and holds nothing past stdio
+        let script = format!(
+            "read -r _ _ _ _ _ sid _ < /proc/$$/stat; [ \"$sid\" = $$ ] && [ ! -e /proc/$$/fd/{leaked} ]"
+        );
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(script);
+        // SAFETY: detach only makes async-signal-safe calls
+        unsafe { command.pre_exec(detach) };
+        assert!(command.status().unwrap().success());
+
+        // SAFETY: leaked is this test's own fd
+        unsafe { libc::close(leaked) };
+    }
+}
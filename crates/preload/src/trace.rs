@@ -0,0 +1,195 @@
+//! Debug tracing for the preload library.
+//!
+//! `FAKENOTIFY_DEBUG=/path/to/log` appends one line per intercepted call to
+//! that file; `FAKENOTIFY_DEBUG=2` (any bare number) writes to that already
+//! open fd instead. Lines are formatted into a fixed stack buffer and emitted
+//! with a single `write(2)`, so tracing never allocates or takes a lock and
+//! concurrent lines do not interleave.
+//!
+//! The target is resolved once, on first use.
+
+use std::ffi::{CString, c_int};
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Where to send trace lines (file path or fd number)
+pub const DEBUG_ENV_VAR: &str = "FAKENOTIFY_DEBUG";
+
+/// Longest line written; anything beyond is cut off
+const LINE_MAX: usize = 512;
+
+/// `TRACE_FD` before the environment has been read
+const UNRESOLVED: c_int = -2;
+/// `TRACE_FD` when tracing is off
+const OFF: c_int = -1;
+
+static TRACE_FD: AtomicI32 = AtomicI32::new(UNRESOLVED);
+
+/// The fd trace lines go to, if tracing is enabled
+pub fn fd() -> Option<c_int> {
+    let mut fd = TRACE_FD.load(Ordering::Acquire);
+    if fd == UNRESOLVED {
+        let opened = open_target(std::env::var(DEBUG_ENV_VAR).ok().as_deref());
+        fd = match TRACE_FD.compare_exchange(
+            UNRESOLVED,
+            opened,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => opened,
+            Err(winner) => {
+                // Another thread got there first; drop our duplicate
+                if opened >= 0 && opened != winner {
+                    // SAFETY: opened is an fd we just opened and nobody else has seen
+                    unsafe { libc::syscall(libc::SYS_close, opened as libc::c_long) };
+                }
+                winner
+            }
+        };
+    }
+    (fd >= 0).then_some(fd)
+}
+
+/// Turn the variable's value into an fd, or [`OFF`]
+///
+/// Files we open ourselves get `O_CLOEXEC` so they do not leak into children;
+/// numeric fds belong to the application and are used as-is.
+fn open_target(value: Option<&str>) -> c_int {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return OFF;
+    };
+    if let Ok(fd) = value.parse::<c_int>() {
+        return if fd >= 0 { fd } else { OFF };
+    }
+    let Ok(path) = CString::new(value) else {
+        return OFF;
+    };
+    // SAFETY: path is a valid C string
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT | libc::O_CLOEXEC,
+            0o644 as libc::c_uint,
+        )
+    };
+    if fd < 0 { OFF } else { fd }
+}
+
+/// A fixed-size line buffer that silently truncates
+struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LINE_MAX],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Keep one byte spare for the newline
+        let room = LINE_MAX - 1 - self.len;
+        let n = s.len().min(room);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Format a line as `fakenotify[pid]: ...` into `line`
+fn format_line(line: &mut Line, args: fmt::Arguments<'_>) {
+    // SAFETY: getpid has no preconditions
+    let pid = unsafe { libc::getpid() };
+    let _ = write!(line, "fakenotify[{pid}]: ");
+    let _ = line.write_fmt(args);
+    line.buf[line.len] = b'\n';
+    line.len += 1;
+}
+
+/// Write one pre-formatted line to `fd`
+///
+/// errno is left as it was, since lines are often traced between a failing
+/// call and the app reading why (EAGAIN from a nonblocking read, say).
+pub fn emit(fd: c_int, args: fmt::Arguments<'_>) {
+    let mut line = Line::new();
+    format_line(&mut line, args);
+    let bytes = line.as_bytes();
+    // SAFETY: __errno_location returns a valid pointer to the thread-local errno
+    let errno = unsafe { libc::__errno_location() };
+    // SAFETY: errno is valid for this thread; bytes is a valid buffer and
+    // write errors are deliberately ignored
+    unsafe {
+        let saved = *errno;
+        libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+        *errno = saved;
+    }
+}
+
+/// Emit a trace line if `FAKENOTIFY_DEBUG` is set
+///
+/// Arguments are only evaluated when tracing is enabled.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if let Some(fd) = $crate::trace::fd() {
+            $crate::trace::emit(fd, format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use trace;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let mut line = Line::new();
+        format_line(
+            &mut line,
+            format_args!("add_watch path={} mask={:#x}", "/mnt/nfs", 0x100),
+        );
+        let text = std::str::from_utf8(line.as_bytes()).unwrap();
+        assert!(text.starts_with("fakenotify["));
+        assert!(text.ends_with("]: add_watch path=/mnt/nfs mask=0x100\n"));
+    }
+
+    #[test]
+    fn test_long_lines_are_truncated() {
+        let long = "x".repeat(LINE_MAX * 2);
+        let mut line = Line::new();
+        format_line(&mut line, format_args!("{long}"));
+        assert_eq!(line.as_bytes().len(), LINE_MAX);
+        assert_eq!(line.as_bytes().last(), Some(&b'\n'));
+    }
+
+    #[test]
+    fn test_emit_keeps_errno() {
+        // SAFETY: __errno_location returns a valid pointer to the thread-local errno
+        let errno = unsafe { libc::__errno_location() };
+        // SAFETY: errno is valid for this thread
+        unsafe { *errno = libc::EAGAIN };
+        // Writing to a closed fd fails with EBADF
+        emit(-1, format_args!("read fd=3 count=4096 -> -1"));
+        // SAFETY: errno is valid for this thread
+        assert_eq!(unsafe { *errno }, libc::EAGAIN);
+    }
+
+    #[test]
+    fn test_open_target() {
+        assert_eq!(open_target(None), OFF);
+        assert_eq!(open_target(Some("  ")), OFF);
+        assert_eq!(open_target(Some("2")), 2);
+        assert_eq!(open_target(Some("-5")), OFF);
+        assert_eq!(open_target(Some("/nonexistent/dir/trace.log")), OFF);
+    }
+}
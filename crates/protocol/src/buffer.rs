@@ -0,0 +1,113 @@
+//! Parsing of raw inotify event buffers.
+//!
+//! A `read()` on an inotify fd returns a run of variable-length events. The
+//! buffer carries no alignment guarantee once it has been copied around, and
+//! a short read can end in the middle of an event.
+
+use crate::event::InotifyEvent;
+use std::ffi::OsString;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStringExt;
+
+/// Iterator over the events in a buffer read from an inotify fd.
+///
+/// Yields each event header together with its name, with the NUL padding
+/// stripped. Headers are read byte-wise, so the buffer need not be aligned.
+/// Iteration stops at the first truncated event; its bytes are left in
+/// [`remainder`](Self::remainder) for the caller to complete.
+#[derive(Debug, Clone)]
+pub struct EventBuffer<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> EventBuffer<'a> {
+    /// Start parsing `buf` from its first byte.
+    #[must_use]
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Take the next whole event as its header and raw bytes, name included.
+    pub fn next_raw(&mut self) -> Option<(InotifyEvent, &'a [u8])> {
+        let header = InotifyEvent::from_bytes(self.buf)?;
+        let raw = self.buf.get(..header.total_size())?;
+        self.buf = &self.buf[raw.len()..];
+        Some((header, raw))
+    }
+
+    /// Bytes not yet consumed: empty, or the start of a truncated event.
+    #[must_use]
+    pub const fn remainder(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+impl Iterator for EventBuffer<'_> {
+    type Item = (InotifyEvent, Option<OsString>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (header, raw) = self.next_raw()?;
+        let name = &raw[InotifyEvent::HEADER_SIZE..];
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        let name = (name_len > 0).then(|| name_from_bytes(&name[..name_len]));
+        Some((header, name))
+    }
+}
+
+/// A name as the bytes the kernel reported it in.
+#[cfg(unix)]
+fn name_from_bytes(name: &[u8]) -> OsString {
+    OsString::from_vec(name.to_vec())
+}
+
+/// A name as text, where names are not bytes; anything that is not UTF-8
+/// is replaced.
+#[cfg(not(unix))]
+fn name_from_bytes(name: &[u8]) -> OsString {
+    String::from_utf8_lossy(name).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventMask;
+
+    #[test]
+    fn test_event_buffer_names() {
+        let mut bytes =
+            InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a");
+        bytes.extend_from_slice(
+            &InotifyEvent::new(2, EventMask::IN_DELETE_SELF.bits(), 0).header_to_bytes(),
+        );
+        bytes.extend(
+            InotifyEvent::new(3, EventMask::IN_MOVED_TO.bits(), 7).to_bytes_with_name(b"four"),
+        );
+
+        let events: Vec<_> = EventBuffer::new(&bytes)
+            .map(|(header, name)| (header.wd, header.cookie, name))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (1, 0, Some(OsString::from("a"))),
+                (2, 0, None),
+                (3, 7, Some(OsString::from("four"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_buffer_unaligned_and_truncated() {
+        let event = InotifyEvent::new(5, EventMask::IN_MODIFY.bits(), 0).to_bytes_with_name(b"log");
+        let mut bytes = vec![0xff];
+        bytes.extend_from_slice(&event);
+        bytes.extend_from_slice(&event[..event.len() - 2]);
+
+        let mut buffer = EventBuffer::new(&bytes[1..]);
+        let (header, name) = buffer.next().unwrap();
+        assert_eq!(header.wd, 5);
+        assert_eq!(name, Some(OsString::from("log")));
+        assert!(buffer.next().is_none());
+        assert_eq!(buffer.remainder(), &event[..event.len() - 2]);
+    }
+}
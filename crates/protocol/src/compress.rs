@@ -0,0 +1,73 @@
+//! zstd compression of frame payloads.
+//!
+//! A peer that negotiated [`Capabilities::COMPRESSION`](crate::Capabilities)
+//! may receive any frame as a [`Response::Compressed`](crate::Response)
+//! whose payload decompresses to the event or response payload the frame
+//! would otherwise have carried, of the [`FrameKind`] it names.
+
+use crate::{FrameKind, FramedMessage, ProtocolError, Response};
+
+/// Level frames are compressed at; bursts of events favour speed
+const LEVEL: i32 = 3;
+
+/// Compress the payload of a frame of `kind` into the payload of a
+/// [`Response::Compressed`] frame, or `None` if that would not be smaller.
+#[must_use]
+pub fn compress_payload(kind: FrameKind, payload: &[u8]) -> Option<Vec<u8>> {
+    let compressed = zstd::bulk::compress(payload, LEVEL).ok()?;
+    let wrapped = Response::Compressed {
+        kind,
+        payload: compressed,
+    }
+    .to_bytes()
+    .ok()?;
+    (wrapped.len() < payload.len()).then_some(wrapped)
+}
+
+/// Recover the payload carried by a [`Response::Compressed`].
+///
+/// # Errors
+///
+/// Returns an error if the data is not valid zstd or expands past
+/// [`FramedMessage::MAX_SIZE`], the limit on an uncompressed frame.
+pub fn decompress_payload(compressed: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    zstd::bulk::decompress(compressed, FramedMessage::MAX_SIZE).map_err(ProtocolError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InotifyEvent;
+
+    #[test]
+    fn test_compressed_frame_round_trip() {
+        let mut payload = Vec::new();
+        for i in 0..200 {
+            payload.extend(
+                InotifyEvent::new(1, 0x100, 0).to_bytes_with_name(format!("{i}.mkv").as_bytes()),
+            );
+        }
+        let wrapped = compress_payload(FrameKind::Event, &payload).unwrap();
+        assert!(wrapped.len() < payload.len());
+
+        let Response::Compressed {
+            kind: FrameKind::Event,
+            payload: compressed,
+        } = Response::from_bytes(&wrapped).unwrap()
+        else {
+            panic!("expected Compressed");
+        };
+        assert_eq!(decompress_payload(&compressed).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_incompressible_payload_is_left_alone() {
+        assert!(
+            compress_payload(
+                FrameKind::Event,
+                &InotifyEvent::new(1, 0x100, 0).header_to_bytes()
+            )
+            .is_none()
+        );
+    }
+}
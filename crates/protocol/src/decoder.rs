@@ -0,0 +1,212 @@
+//! Incremental frame decoding.
+//!
+//! Reads off a socket end wherever the kernel likes, often in the middle of
+//! a length prefix. [`FrameDecoder`] takes the bytes as they come and hands
+//! out each frame's payload once it is complete, reusing one buffer for the
+//! whole stream, so a reader never has to wait for a whole frame in a
+//! single call or allocate per message.
+
+use crate::{FrameKind, FramedMessage, ProtocolError};
+
+/// Where the decoder is within the frame at the front of its buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for the 4-byte length prefix
+    Length,
+    /// Waiting for this many payload bytes of this kind after the prefix
+    Payload(FrameKind, usize),
+}
+
+/// Splits a byte stream into length-prefixed frames as it arrives.
+///
+/// ```rust
+/// use fakenotify_protocol::{FrameDecoder, FramedMessage};
+///
+/// let stream = FramedMessage::frame(b"hello");
+/// let mut decoder = FrameDecoder::new();
+/// decoder.push(&stream[..3]);
+/// assert_eq!(decoder.next_frame().unwrap(), None);
+/// decoder.push(&stream[3..]);
+/// assert_eq!(decoder.next_frame().unwrap(), Some(&b"hello"[..]));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Start of the bytes not yet handed out
+    start: usize,
+    state: State,
+    max_size: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    /// Create a decoder refusing frames over [`FramedMessage::MAX_SIZE`].
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            state: State::Length,
+            max_size: FramedMessage::MAX_SIZE,
+        }
+    }
+
+    /// Refuse frames over `max_size` bytes instead.
+    #[must_use]
+    pub const fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Append bytes read off the stream.
+    pub fn push(&mut self, bytes: &[u8]) {
+        // Frames handed out are no longer borrowed, so their space is free
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Take the payload of the next complete frame, if one has arrived.
+    ///
+    /// Fails on a length prefix over the maximum size, after which the
+    /// stream cannot be trusted.
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>, ProtocolError> {
+        Ok(self.next_kind_and_frame()?.map(|(_, payload)| payload))
+    }
+
+    /// [`next_frame`](Self::next_frame), with what the frame carries.
+    pub fn next_kind_and_frame(&mut self) -> Result<Option<(FrameKind, &[u8])>, ProtocolError> {
+        if self.state == State::Length {
+            let Some((kind, len)) = FramedMessage::read_header(self.buffered()) else {
+                return Ok(None);
+            };
+            let len = len as usize;
+            if len > self.max_size {
+                return Err(ProtocolError::InvalidMessage(format!(
+                    "frame of {len} bytes exceeds the maximum of {}",
+                    self.max_size
+                )));
+            }
+            self.state = State::Payload(kind, len);
+        }
+        let State::Payload(kind, len) = self.state else {
+            return Ok(None);
+        };
+        if self.buffered().len() < 4 + len {
+            return Ok(None);
+        }
+        let payload = self.start + 4;
+        self.start = payload + len;
+        self.state = State::Length;
+        Ok(Some((kind, &self.buf[payload..payload + len])))
+    }
+
+    /// Bytes received but not yet handed out, starting with the frame in
+    /// progress.
+    #[must_use]
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    /// Drop the first `n` buffered bytes, for streams that carry something
+    /// other than a frame there.
+    pub fn consume(&mut self, n: usize) {
+        self.start += n.min(self.buffered().len());
+        self.state = State::Length;
+    }
+
+    /// Whether no bytes are buffered, i.e. the stream is between frames.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.buffered().is_empty()
+    }
+
+    /// Forget everything buffered, as when the stream is replaced.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+        self.state = State::Length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_split_anywhere() {
+        let mut stream = Vec::new();
+        for payload in [&b"one"[..], b"", &[7u8; 300]] {
+            FramedMessage::frame_into(payload, &mut stream);
+        }
+
+        // Byte by byte, then in uneven chunks
+        for chunk in [1, 5, stream.len()] {
+            let mut decoder = FrameDecoder::new();
+            let mut frames = Vec::new();
+            for bytes in stream.chunks(chunk) {
+                decoder.push(bytes);
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    frames.push(frame.to_vec());
+                }
+            }
+            assert_eq!(frames, [b"one".to_vec(), Vec::new(), vec![7u8; 300]]);
+            assert!(decoder.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_frames_keep_their_kind() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&FramedMessage::frame(b"answer"));
+        decoder.push(&FramedMessage::frame_event(b"event"));
+        assert_eq!(
+            decoder.next_kind_and_frame().unwrap(),
+            Some((FrameKind::Message, &b"answer"[..]))
+        );
+        assert_eq!(
+            decoder.next_kind_and_frame().unwrap(),
+            Some((FrameKind::Event, &b"event"[..]))
+        );
+    }
+
+    #[test]
+    fn test_partial_frame_stays_buffered() {
+        let stream = FramedMessage::frame(b"hello");
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&stream[..6]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        assert_eq!(decoder.buffered(), &stream[..6]);
+
+        decoder.clear();
+        assert!(decoder.is_empty());
+        decoder.push(&stream);
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_oversized_frame_is_refused() {
+        let mut decoder = FrameDecoder::new().with_max_size(4);
+        decoder.push(&FramedMessage::frame(b"four"));
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"four"[..]));
+        decoder.push(&FramedMessage::frame(b"fives"));
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_consume_skips_unframed_bytes() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(b"{}\n");
+        decoder.push(&FramedMessage::frame(b"after"));
+        let line = decoder.buffered().iter().position(|&b| b == b'\n').unwrap();
+        decoder.consume(line + 1);
+        assert_eq!(decoder.next_frame().unwrap(), Some(&b"after"[..]));
+    }
+}
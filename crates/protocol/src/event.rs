@@ -4,6 +4,12 @@
 //! and the standard event mask constants.
 
 use bitflags::bitflags;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
 
 bitflags! {
     /// inotify event mask flags.
@@ -61,6 +67,8 @@ bitflags! {
         const IN_ONLYDIR = 0x0100_0000;
         /// Don't follow symlinks.
         const IN_DONT_FOLLOW = 0x0200_0000;
+        /// Don't report events for children after they are unlinked.
+        const IN_EXCL_UNLINK = 0x0400_0000;
         /// Add to existing watch mask rather than replacing.
         const IN_MASK_ADD = 0x2000_0000;
         /// Only send event once, then remove watch.
@@ -78,6 +86,133 @@ bitflags! {
     }
 }
 
+/// An event name that [`EventMask::from_names`] does not recognize.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("unknown inotify event name: {0}")]
+pub struct UnknownEventName(pub String);
+
+impl EventMask {
+    /// Build a mask from flag names.
+    ///
+    /// Names are matched case-insensitively with or without the `IN_`
+    /// prefix, so `IN_CREATE`, `create` and `delete_self` all work; `all`
+    /// stands for `IN_ALL_EVENTS`.
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<Self, UnknownEventName> {
+        names.iter().try_fold(Self::empty(), |mask, name| {
+            Ok(mask | Self::parse_name(name.as_ref())?)
+        })
+    }
+
+    /// Look up a single flag by any of its accepted spellings.
+    fn parse_name(name: &str) -> Result<Self, UnknownEventName> {
+        let upper = name.trim().to_ascii_uppercase();
+        if upper == "ALL" {
+            return Ok(Self::IN_ALL_EVENTS);
+        }
+        let full = if upper.starts_with("IN_") {
+            upper
+        } else {
+            format!("IN_{upper}")
+        };
+        Self::from_name(&full).ok_or_else(|| UnknownEventName(name.to_string()))
+    }
+}
+
+/// Prints the set flags as `IN_CREATE|IN_ISDIR`, unknown bits in hex, and
+/// `0` for an empty mask.
+impl fmt::Display for EventMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("0");
+        }
+        let mut first = true;
+        for (name, _) in self.iter_names() {
+            if !first {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        let unknown = self.bits() & !Self::all().bits();
+        if unknown != 0 {
+            if !first {
+                f.write_str("|")?;
+            }
+            write!(f, "{unknown:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses names separated by `|` or `,`, as accepted by
+/// [`EventMask::from_names`].
+impl FromStr for EventMask {
+    type Err = UnknownEventName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names: Vec<&str> = s
+            .split(['|', ','])
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        Self::from_names(&names)
+    }
+}
+
+/// Serializes as the list of set flag names.
+impl Serialize for EventMask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names: Vec<&str> = self.iter_names().map(|(name, _)| name).collect();
+        let mut seq = serializer.serialize_seq(Some(names.len()))?;
+        for name in names {
+            seq.serialize_element(name)?;
+        }
+        seq.end()
+    }
+}
+
+/// Deserializes from a list of names, a `|`-separated string, or the raw
+/// integer value.
+impl<'de> Deserialize<'de> for EventMask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MaskVisitor;
+
+        impl<'de> Visitor<'de> for MaskVisitor {
+            type Value = EventMask;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a list of inotify event names, a string, or an integer mask")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<EventMask, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<EventMask, E> {
+                u32::try_from(v)
+                    .map(EventMask::from_bits_retain)
+                    .map_err(|_| E::custom("event mask does not fit in 32 bits"))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<EventMask, E> {
+                u64::try_from(v)
+                    .map_err(|_| E::custom("event mask cannot be negative"))
+                    .and_then(|v| self.visit_u64(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<EventMask, A::Error> {
+                let mut mask = EventMask::empty();
+                while let Some(name) = seq.next_element::<String>()? {
+                    mask |= EventMask::parse_name(&name).map_err(de::Error::custom)?;
+                }
+                Ok(mask)
+            }
+        }
+
+        deserializer.deserialize_any(MaskVisitor)
+    }
+}
+
 /// Raw inotify event structure.
 ///
 /// This is binary-compatible with the kernel's `struct inotify_event`.
@@ -202,6 +337,53 @@ pub const fn event_size_with_name(name_len: usize) -> usize {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_event_mask_names() {
+        assert_eq!(
+            EventMask::from_names(&["IN_CREATE", "modify", "delete_self"]).unwrap(),
+            EventMask::IN_CREATE | EventMask::IN_MODIFY | EventMask::IN_DELETE_SELF
+        );
+        assert_eq!(
+            EventMask::from_names(&["all"]).unwrap(),
+            EventMask::IN_ALL_EVENTS
+        );
+        assert_eq!(
+            EventMask::from_names(&["created"]),
+            Err(UnknownEventName("created".to_string()))
+        );
+
+        let mask = EventMask::IN_CREATE | EventMask::IN_ISDIR;
+        assert_eq!(mask.to_string(), "IN_CREATE|IN_ISDIR");
+        assert_eq!("IN_CREATE | isdir".parse::<EventMask>().unwrap(), mask);
+        assert_eq!(EventMask::IN_MOVE.to_string(), "IN_MOVED_FROM|IN_MOVED_TO");
+        assert_eq!(EventMask::empty().to_string(), "0");
+        assert_eq!(
+            EventMask::from_bits_retain(0x0000_1000 | 0x100).to_string(),
+            "IN_CREATE|0x1000"
+        );
+    }
+
+    #[test]
+    fn test_event_mask_serde() {
+        let mask = EventMask::IN_CREATE | EventMask::IN_ISDIR;
+        let json = serde_json::to_string(&mask).unwrap();
+        assert_eq!(json, r#"["IN_CREATE","IN_ISDIR"]"#);
+        assert_eq!(serde_json::from_str::<EventMask>(&json).unwrap(), mask);
+        assert_eq!(
+            serde_json::from_str::<EventMask>(r#"["create", "isdir"]"#).unwrap(),
+            mask
+        );
+        assert_eq!(
+            serde_json::from_str::<EventMask>(r#""create|isdir""#).unwrap(),
+            mask
+        );
+        assert_eq!(
+            serde_json::from_str::<EventMask>(&mask.bits().to_string()).unwrap(),
+            mask
+        );
+        assert!(serde_json::from_str::<EventMask>(r#"["nope"]"#).is_err());
+    }
+
     #[test]
     fn test_event_header_size() {
         // inotify_event header is always 16 bytes
@@ -0,0 +1,64 @@
+//! Event name filters.
+//!
+//! A media indexer watching a share with busy scratch directories only
+//! cares about a few file types. Clients send a [`NameFilter`] with
+//! [`Request::SetFilter`](crate::Request::SetFilter) and the daemon drops
+//! non-matching events before queueing them, so they never cost socket
+//! bandwidth or queue space.
+
+use serde::{Deserialize, Serialize};
+
+/// Environment variable holding a client's extension allowlist, separated by
+/// commas, e.g. `FAKENOTIFY_EXTENSIONS=mkv,mp4,jpg`.
+pub const EXTENSIONS_ENV_VAR: &str = "FAKENOTIFY_EXTENSIONS";
+
+/// Environment variable holding a regex that event names must match, e.g.
+/// `FAKENOTIFY_NAME_REGEX=^[^.]`.
+pub const NAME_REGEX_ENV_VAR: &str = "FAKENOTIFY_NAME_REGEX";
+
+/// Which file events a client wants to receive.
+///
+/// Both conditions must hold. They apply to files only: directory events
+/// and events on the watched path itself always pass, so clients still see
+/// new subdirectories.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameFilter {
+    /// File extensions to keep, without the dot and matched
+    /// case-insensitively; empty keeps every extension.
+    pub extensions: Vec<String>,
+    /// Regular expression the event name (the path relative to the watch)
+    /// must match.
+    pub regex: Option<String>,
+}
+
+impl NameFilter {
+    /// Whether this filter lets every event through.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty() && self.regex.is_none()
+    }
+}
+
+/// Parse an [`EXTENSIONS_ENV_VAR`] value, dropping leading dots and empty
+/// entries.
+#[must_use]
+pub fn parse_extensions(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.'))
+        .filter(|ext| !ext.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_extensions() {
+        assert_eq!(parse_extensions("mkv, .jpg,,"), vec!["mkv", "jpg"]);
+        assert!(parse_extensions("").is_empty());
+        assert!(NameFilter::default().is_empty());
+    }
+}
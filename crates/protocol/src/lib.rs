@@ -2,14 +2,25 @@
 //!
 //! This crate provides:
 //! - [`Request`] and [`Response`] message types for client-daemon communication
+//!   ([`AddWatchRef`] frames a watch request without allocating)
 //! - [`InotifyEvent`] structure matching the kernel's binary format
-//! - [`EventMask`] bitflags for inotify event masks
-//! - Socket path helpers via [`get_socket_path`]
+//! - [`EventMask`] bitflags for inotify event masks, readable as flag names
+//! - [`EventBuffer`] for walking the events in a raw inotify read buffer
+//! - Socket path helpers via [`get_socket_path`] and instance discovery via
+//!   [`discover_socket_paths`]
+//! - Client-to-host path translation entries ([`PathMapping`])
+//! - Per-client event name filters ([`NameFilter`])
+//! - Positions in the daemon's event journal ([`Cursor`])
+//! - [`FrameDecoder`] for splitting a byte stream into frames as it arrives
+//! - Optional feature negotiation flags ([`Capabilities`]), and with the
+//!   `compression` feature, zstd frame compression (`compress_payload`)
 //!
 //! # Wire Format
 //!
 //! Messages are serialized using [bincode](https://docs.rs/bincode) for efficiency.
 //! Each message is length-prefixed with a 4-byte little-endian u32.
+//! Events travel as raw inotify records, in frames whose length prefix has
+//! its top bit set ([`FrameKind::Event`]).
 //!
 //! # Example
 //!
@@ -30,21 +41,47 @@
 //! let decoded = Request::from_bytes(&bytes).unwrap();
 //! ```
 
+mod buffer;
+#[cfg(feature = "compression")]
+mod compress;
+mod decoder;
 mod event;
+mod filter;
 mod message;
+mod path_map;
 mod socket;
 
 // Re-export main types at crate root
-pub use event::{EventMask, InotifyEvent, event_size_with_name};
-pub use message::{FramedMessage, ProtocolError, Request, Response};
+pub use buffer::EventBuffer;
+#[cfg(feature = "compression")]
+pub use compress::{compress_payload, decompress_payload};
+pub use decoder::FrameDecoder;
+pub use event::{EventMask, InotifyEvent, UnknownEventName, event_size_with_name};
+pub use filter::{EXTENSIONS_ENV_VAR, NAME_REGEX_ENV_VAR, NameFilter, parse_extensions};
+pub use message::{
+    AddWatchRef, Capabilities, ChangeDetection, ClientInfo, ClientSummary, Cursor, DegradedMount,
+    ErrorCode, FrameKind, FramedMessage, LoggedError, MountStatus, PathRate, ProtocolError,
+    Request, Response, UserLimits, UserUsage, WatchEntry, WatchInfo, WatchRoot, WatchSummary,
+    is_valid_tag,
+};
+pub use path_map::{PATH_MAP_ENV_VAR, PathMapping, parse_path_map};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use socket::connect_vsock;
 pub use socket::{
-    DEFAULT_SOCKET_PATH, SOCKET_ENV_VAR, get_socket_path, get_socket_path_with_xdg_fallback,
+    DEFAULT_SOCKET_PATH, Endpoint, INSTANCE_DIR, SOCKET_ENV_VAR, TCP_SCHEME, VSOCK_SCHEME,
+    discover_socket_paths, get_socket_path, get_socket_path_with_xdg_fallback, socket_candidates,
 };
 
 /// Protocol version for compatibility checking.
 ///
-/// Increment this when making breaking changes to the wire format.
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Increment this when making breaking changes to the wire format. Clients
+/// send it in [`Request::RegisterClient`], and the daemon refuses other
+/// versions with [`ErrorCode::VersionMismatch`].
+///
+/// Version 2 marks event frames in the length prefix and gives
+/// `RegisterClient` its fields; version 3 gives `Response::Error` its
+/// [`ErrorCode`].
+pub const PROTOCOL_VERSION: u32 = 3;
 
 #[cfg(test)]
 mod tests {
@@ -2,8 +2,14 @@
 //!
 //! These types are serialized using bincode for efficient wire format.
 
+use crate::{NameFilter, PathMapping};
+use bitflags::bitflags;
+use serde::ser::{SerializeStructVariant, Serializer};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use thiserror::Error;
 
 /// Error type for protocol operations.
@@ -20,14 +26,419 @@ pub enum ProtocolError {
     /// Invalid message received.
     #[error("invalid message: {0}")]
     InvalidMessage(String),
+
+    /// The peer registered with another wire protocol version.
+    #[error("peer speaks protocol version {0}, not {ours}", ours = crate::PROTOCOL_VERSION)]
+    VersionMismatch(u32),
+}
+
+/// One watch in a [`Request::AddWatchBatch`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchEntry {
+    /// Path to watch.
+    pub path: PathBuf,
+    /// Event mask (combination of EventMask flags).
+    pub mask: u32,
+}
+
+/// A position in the event journal of a watched path.
+///
+/// Sequence numbers count the events under one path and start over when
+/// the daemon does, which changes `epoch`. Written as `epoch:seq`, with
+/// `epoch` in hex.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    /// Daemon run the sequence numbers belong to.
+    pub epoch: u64,
+    /// Sequence number of the last event seen; 0 before the first.
+    pub seq: u64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}:{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProtocolError::InvalidMessage(format!("invalid cursor: {s:?}"));
+        let (epoch, seq) = s.trim().split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            epoch: u64::from_str_radix(epoch, 16).map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Events seen under one path during the daemon's rate window.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PathRate {
+    /// Watched root or directory.
+    pub path: PathBuf,
+    /// Events within the window.
+    pub events: u64,
+    /// Whether the path is above the daemon's noisy threshold.
+    pub noisy: bool,
+    /// Tags of the watch; empty for directories.
+    pub tags: Vec<String>,
+}
+
+/// A mount whose health probes time out, so scans under it are paused.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DegradedMount {
+    /// Mount point, or the watched root if it is not on a known remote mount.
+    pub path: PathBuf,
+    /// Seconds since the first probe that timed out.
+    pub degraded_secs: u64,
+    /// Probes that have timed out in a row.
+    pub failed_probes: u32,
+}
+
+/// Usage counted against one uid's limits, as reported by [`Request::Stats`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserUsage {
+    /// User the clients run as.
+    pub uid: u32,
+    /// Connected clients, counted against `max_user_instances`.
+    pub instances: u32,
+    /// Watch subscriptions across those clients, counted against
+    /// `max_user_watches`.
+    pub watches: u32,
+    /// Events waiting to be written, summed over those clients.
+    pub queued_events: u64,
+}
+
+/// The per-user limits the daemon enforces.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserLimits {
+    /// Watches a single user may hold across all connections.
+    pub max_user_watches: u64,
+    /// Connections a single user may hold.
+    pub max_user_instances: u64,
+    /// Events queued per connection before it overflows.
+    pub max_queued_events: u64,
+}
+
+/// A mount watched roots live on, as reported by [`Request::Status`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MountStatus {
+    /// Mount point, or the watched root if it is not on a known remote mount.
+    pub path: PathBuf,
+    /// Watched roots on the mount.
+    pub roots: u32,
+    /// Seconds since the mount stopped answering; `None` while it is healthy.
+    pub degraded_secs: Option<u64>,
+    /// Probes that have timed out in a row.
+    pub failed_probes: u32,
+}
+
+/// The last warning or error the daemon logged.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LoggedError {
+    /// Log level, `WARN` or `ERROR`.
+    pub level: String,
+    /// Message with its fields, as written to the log.
+    pub message: String,
+    /// Seconds since it was logged.
+    pub age_secs: u64,
+}
+
+/// One daemon watch, as reported by [`Request::ListWatches`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchSummary {
+    /// Watched path on the host.
+    pub path: PathBuf,
+    /// Watch descriptor.
+    pub wd: i32,
+    /// Event mask (combination of EventMask flags).
+    pub mask: u32,
+    /// Whether subdirectories are watched too.
+    pub recursive: bool,
+    /// Clients subscribed to the watch.
+    pub clients: u32,
+    /// Tags set on the watch and on the roots it lies under, sorted.
+    pub tags: Vec<String>,
+}
+
+/// One daemon watch in detail, as reported by [`Request::GetWatchInfo`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchInfo {
+    /// Watch descriptor.
+    pub wd: i32,
+    /// Watched path on the host.
+    pub path: PathBuf,
+    /// Event mask of all subscribers together (combination of EventMask
+    /// flags).
+    pub mask: u32,
+    /// Whether subdirectories are watched too.
+    pub recursive: bool,
+    /// Clients subscribed to the watch.
+    pub clients: u32,
+    /// The watched root whose events reach the watch, if any does.
+    pub root: Option<PathBuf>,
+    /// Entries in the latest snapshot of the scan covering that root.
+    pub snapshot_entries: u64,
+    /// Milliseconds since that scan last finished; `None` if it has not yet
+    /// or the root is not polled.
+    pub last_scan_ms_ago: Option<u64>,
+    /// Tags set on the watch and on the roots it lies under, sorted.
+    pub tags: Vec<String>,
+}
+
+/// Why the daemon refused a request, carried by [`Response::Error`].
+///
+/// Clients emulating inotify report each code as the errno the kernel would
+/// have returned for the same mistake.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// Malformed request or argument.
+    #[default]
+    Invalid,
+    /// The path does not exist.
+    NotFound,
+    /// `IN_ONLYDIR` was given for something that is not a directory.
+    NotDirectory,
+    /// No watch with that descriptor belongs to the client.
+    NoSuchWatch,
+    /// The caller may not do this.
+    PermissionDenied,
+    /// The mask asks for events the daemon cannot observe, such as
+    /// `IN_OPEN` on a polled path, and it is set to refuse those.
+    UnsupportedMask,
+    /// The caller's emulated `max_user_watches` is used up.
+    WatchLimit,
+    /// The caller's emulated `max_user_instances` is used up.
+    InstanceLimit,
+    /// The client speaks another wire protocol version than the daemon.
+    VersionMismatch,
+}
+
+impl ErrorCode {
+    /// Every code, in declaration order.
+    pub const ALL: [Self; 9] = [
+        Self::Invalid,
+        Self::NotFound,
+        Self::NotDirectory,
+        Self::NoSuchWatch,
+        Self::PermissionDenied,
+        Self::UnsupportedMask,
+        Self::WatchLimit,
+        Self::InstanceLimit,
+        Self::VersionMismatch,
+    ];
+
+    /// The code named `name`, which for limits is the name carried by
+    /// [`Response::LimitExceeded`].
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|code| code.name() == name)
+    }
+
+    /// Short `snake_case` name, as used in errno mapping overrides.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Invalid => "invalid",
+            Self::NotFound => "not_found",
+            Self::NotDirectory => "not_directory",
+            Self::NoSuchWatch => "no_such_watch",
+            Self::PermissionDenied => "permission_denied",
+            Self::UnsupportedMask => "unsupported_mask",
+            Self::WatchLimit => "max_user_watches",
+            Self::InstanceLimit => "max_user_instances",
+            Self::VersionMismatch => "version_mismatch",
+        }
+    }
+
+    /// The errno real inotify reports for the same failure.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn errno(self) -> i32 {
+        match self {
+            Self::Invalid | Self::NoSuchWatch | Self::UnsupportedMask => libc::EINVAL,
+            Self::NotFound => libc::ENOENT,
+            Self::NotDirectory => libc::ENOTDIR,
+            Self::PermissionDenied => libc::EACCES,
+            // The kernel reports max_user_watches as ENOSPC
+            Self::WatchLimit => libc::ENOSPC,
+            Self::InstanceLimit => libc::EMFILE,
+            Self::VersionMismatch => libc::EPROTO,
+        }
+    }
+}
+
+/// How a client describes itself in [`Request::RegisterClient`].
+///
+/// Purely informational, so operators can tell connections apart; nothing
+/// here is trusted for access control.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Program name, e.g. the process `comm`.
+    pub name: Option<String>,
+    /// Process ID as the client sees it, which differs from the peer pid
+    /// across pid namespaces or over TCP.
+    pub pid: Option<i32>,
+    /// Version of the client software.
+    pub version: Option<String>,
+}
+
+bitflags! {
+    /// Optional protocol features a peer understands.
+    ///
+    /// Clients list theirs in [`Request::RegisterClient`]; the daemon lists
+    /// its own in [`Response::Hello`] and answers registration with the
+    /// features both sides share, which are the only ones it then uses
+    /// with that client. Unknown bits are ignored, so either side can be
+    /// newer.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct Capabilities: u16 {
+        /// Several events may arrive in one frame.
+        const EVENT_BATCH = 1 << 0;
+        /// Event frames may arrive zstd-compressed.
+        const COMPRESSION = 1 << 1;
+        /// Requests may be sent as [`Request::Tagged`], and are then
+        /// answered with [`Response::Tagged`] carrying the same ID.
+        const REQUEST_IDS = 1 << 2;
+        /// Requests may also come over control connections attached with
+        /// [`Request::AttachControl`].
+        const CONTROL = 1 << 3;
+        /// A client whose connection drops is kept for a while, and a new
+        /// connection can take it over with [`Request::Resume`].
+        const RESUME = 1 << 4;
+        /// Watches may be added with [`Request::AddWatchSince`], to be
+        /// sent what happened under them since a [`Cursor`].
+        const JOURNAL = 1 << 5;
+        /// Events and answers arrive as newline-delimited JSON instead of
+        /// frames, for consumers without a client library; rules out
+        /// batching and compression.
+        const JSON = 1 << 6;
+        /// A watch added for fewer events than asked for, because the
+        /// daemon denies the rest, is answered with
+        /// [`Response::WatchClamped`] instead of `WatchAdded`.
+        const MASK_CLAMP = 1 << 7;
+        /// The client answers [`Response::Heartbeat`] from a background
+        /// reader, even while its user is busy, so the daemon may drop it
+        /// once it stays silent for the heartbeat timeout. Clients without
+        /// it are only dropped when writes to them fail.
+        const HEARTBEAT = 1 << 8;
+    }
+}
+
+/// One connected client, as reported by [`Request::ListClients`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientSummary {
+    /// Namespace the client's watches belong to; see
+    /// [`Request::HelloNamespace`].
+    pub namespace: String,
+    /// Daemon-assigned client ID.
+    pub id: u64,
+    /// Peer user ID.
+    pub uid: u32,
+    /// Peer process ID, if the transport reports it.
+    pub pid: Option<i32>,
+    /// Watches the client holds.
+    pub watches: u32,
+    /// Events queued for the client but not yet written.
+    pub queued_events: u32,
+    /// Seconds since the client connected.
+    pub connected_secs: u64,
+    /// What the client said about itself, if it registered.
+    pub info: ClientInfo,
+    /// Features negotiated with the client (combination of
+    /// [`Capabilities`] flags).
+    pub capabilities: u16,
+}
+
+/// What counts as a file modification when a root is polled.
+///
+/// Servers with one-second mtime resolution hide overwrites that land in
+/// the same second and keep the size; the stricter modes catch those at the
+/// cost of false positives or extra reads.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeDetection {
+    /// Size or mtime changed.
+    #[default]
+    Mtime,
+    /// Also a ctime change, to the nanosecond, that is not explained by a
+    /// metadata change.
+    Ctime,
+    /// Also a hash of the first and last blocks of the file.
+    Hash,
+}
+
+/// A root the daemon scans, as exported and imported between daemons.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WatchRoot {
+    /// Root path on the daemon's host.
+    pub path: PathBuf,
+    /// Time between scans in milliseconds.
+    pub poll_interval_ms: u64,
+    /// Whether subdirectories are scanned too.
+    pub recursive: bool,
+    /// Use the OS notification API instead of polling.
+    pub native: bool,
+    /// Events reported for the root (combination of EventMask flags).
+    pub mask: u32,
+    /// Only report files with these extensions.
+    pub extensions: Vec<String>,
+    /// Only report files whose path under the root matches this regex.
+    pub name_regex: Option<String>,
+    /// How file modifications are detected.
+    pub change_detection: ChangeDetection,
+    /// Revalidate cached directory attributes before every scan.
+    pub revalidate: bool,
+    /// Compare paths without regard to case; `None` decides by filesystem.
+    pub case_insensitive: Option<bool>,
+    /// Consecutive scans an entry must be missing before it is reported
+    /// deleted.
+    pub confirm_deletes: u32,
+    /// Stat missing entries again and report them deleted only on ENOENT.
+    pub restat_deletes: bool,
+    /// Labels naming the team or application the root belongs to.
+    pub tags: Vec<String>,
+    /// Events clients may not watch for under the root (combination of
+    /// EventMask flags).
+    pub denied_mask: u32,
+    /// Scans a written file must stay unchanged for before its writes are
+    /// reported, with IN_CLOSE_WRITE (0 reports them as seen).
+    pub settle_scans: u32,
+    /// Name endings of temporary files renamed to their final name once
+    /// complete; such files report nothing.
+    pub partial_suffixes: Vec<String>,
+}
+
+/// Whether `tag` may label a watch: non-empty, without whitespace or
+/// commas, so lists of tags can be written comma-separated.
+#[must_use]
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty() && !tag.contains(|c: char| c == ',' || c.is_whitespace())
 }
 
 /// Request messages sent from client (LD_PRELOAD) to daemon.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Request {
-    /// Register a new client connection.
-    /// The daemon responds with a unique client ID.
-    RegisterClient,
+    /// Describe this connection to the daemon.
+    ///
+    /// The daemon registers every connection as it accepts it; this only
+    /// attaches the client's name, pid and version, shown by `ListClients`,
+    /// and negotiates optional features. Answered with
+    /// [`Response::ClientRegistered`], or an [`ErrorCode::VersionMismatch`]
+    /// error if the daemon speaks another protocol version.
+    RegisterClient {
+        /// Wire protocol version the client speaks,
+        /// [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION). First, so every
+        /// version can read it whatever follows.
+        protocol_version: u32,
+        /// What the client says about itself.
+        info: ClientInfo,
+        /// Features the client understands (combination of
+        /// [`Capabilities`] flags).
+        capabilities: u16,
+    },
 
     /// Add a watch for filesystem events.
     AddWatch {
@@ -45,6 +456,193 @@ pub enum Request {
 
     /// Keepalive ping.
     Ping,
+
+    /// Replace this client's path translation table.
+    ///
+    /// Paths in later `AddWatch` requests that fall under a mapping's
+    /// `client` prefix are watched at the corresponding `host` path.
+    SetPathMap {
+        /// Client-to-host prefixes; empty clears the table.
+        mappings: Vec<PathMapping>,
+    },
+
+    /// Add several watches in one round trip.
+    ///
+    /// Answered with [`Response::Batch`] holding, per entry and in order,
+    /// the response a single `AddWatch` would have got.
+    AddWatchBatch {
+        /// Watches to add.
+        entries: Vec<WatchEntry>,
+    },
+
+    /// Remove several watches in one round trip.
+    ///
+    /// Answered with [`Response::Batch`] holding, per descriptor and in
+    /// order, the response a single `RemoveWatch` would have got.
+    RemoveWatchBatch {
+        /// Watch descriptors to remove.
+        wds: Vec<i32>,
+    },
+
+    /// Answer to [`Response::Heartbeat`]. The daemon sends nothing back.
+    HeartbeatAck,
+
+    /// Remove every watch whose path no longer exists, for all clients.
+    ///
+    /// Subscribed clients receive `IN_IGNORED` for each removed watch.
+    Prune,
+
+    /// Replace the filter applied to this client's events.
+    ///
+    /// Answered with [`Response::FilterSet`], or an error if the regex does
+    /// not compile.
+    SetFilter {
+        /// Names to keep; an empty filter lets everything through.
+        filter: NameFilter,
+    },
+
+    /// Report daemon counters and the busiest paths.
+    Stats {
+        /// How many watches and directories to list.
+        top: u32,
+    },
+
+    /// Ask which paths this daemon serves, so a client that found several
+    /// instances can pick the right one.
+    ///
+    /// Answered with [`Response::Hello`].
+    Hello,
+
+    /// List every watch, ordered by descriptor.
+    ListWatches,
+
+    /// List every connected client, ordered by ID.
+    ListClients,
+
+    /// Report the roots the daemon scans, ordered by path.
+    ///
+    /// Answered with [`Response::Roots`].
+    ExportRoots,
+
+    /// Start scanning more roots; roots already scanned are left as they are.
+    ///
+    /// Answered with [`Response::Imported`]. Only root and the daemon's own
+    /// user may do this.
+    ImportRoots {
+        /// Roots to add.
+        roots: Vec<WatchRoot>,
+    },
+
+    /// Shut the daemon down gracefully, as on SIGTERM.
+    ///
+    /// Answered with [`Response::ShuttingDown`] before the shutdown starts.
+    /// Only root and the daemon's own user may do this.
+    Shutdown,
+
+    /// Report the daemon's version, queues, mounts and last error.
+    ///
+    /// Answered with [`Response::Status`].
+    Status,
+
+    /// Describe one watch in detail.
+    ///
+    /// Answered with [`Response::WatchInfo`], or an error with
+    /// [`ErrorCode::NoSuchWatch`].
+    GetWatchInfo {
+        /// Watch descriptor to describe.
+        wd: i32,
+    },
+
+    /// A request carrying an ID its answer is matched by, so several can
+    /// be in flight on one connection from different threads.
+    ///
+    /// Answered with [`Response::Tagged`] wrapping what the inner request
+    /// is answered with, or nothing if it has no answer. Only for clients
+    /// that negotiated [`Capabilities::REQUEST_IDS`]; tags do not nest.
+    Tagged {
+        /// Chosen by the client, echoed in the answer.
+        id: u64,
+        /// The request itself.
+        request: Box<Request>,
+    },
+
+    /// Make this connection a control connection of the client holding
+    /// `session`.
+    ///
+    /// Its later requests act for that client, as if sent on that client's
+    /// own connection, which then carries little but events. Must be the
+    /// first request on the connection. Answered with
+    /// [`Response::ControlAttached`].
+    AttachControl {
+        /// Session from the client's [`Response::ClientRegistered`].
+        session: String,
+    },
+
+    /// Take over the client holding `session`, whose connection dropped.
+    ///
+    /// This connection becomes that client's: it keeps its watches and
+    /// settings, and is sent the events queued while it was away, up to
+    /// the daemon's queue limit. Must be the first request on the
+    /// connection. Answered with [`Response::Resumed`].
+    Resume {
+        /// Session from the client's [`Response::ClientRegistered`].
+        session: String,
+    },
+
+    /// Add a watch whose events are journaled, first sending the journaled
+    /// events after `since`.
+    ///
+    /// Its events then arrive as [`Response::Sequenced`], numbered so a
+    /// consumer can store its [`Cursor`] and, after a restart, resume where
+    /// it left off. Answered with [`Response::WatchAddedSince`]. Only for
+    /// daemons offering [`Capabilities::JOURNAL`].
+    AddWatchSince {
+        /// Path to watch.
+        path: PathBuf,
+        /// Event mask (combination of EventMask flags).
+        mask: u32,
+        /// Where the consumer left off; `None` starts from now.
+        since: Option<Cursor>,
+    },
+
+    /// Replace the tags of a watch, which `ListWatches` and `Stats` show
+    /// and [`Request::RemoveTagged`] selects by.
+    ///
+    /// Answered with [`Response::WatchTagged`]. Clients may tag their own
+    /// watches; root and the daemon's own user may tag any.
+    TagWatch {
+        /// Watch descriptor to tag.
+        wd: i32,
+        /// The new tags; empty clears them. Tags are non-empty and hold
+        /// no whitespace or commas.
+        tags: Vec<String>,
+    },
+
+    /// Remove every watch carrying `tag`, for all clients, whether set on
+    /// the watch or on a root it lies under.
+    ///
+    /// Subscribed clients receive `IN_IGNORED` for each removed watch.
+    /// Answered with [`Response::Removed`]. Only root and the daemon's own
+    /// user may do this.
+    RemoveTagged {
+        /// Tag to select watches by.
+        tag: String,
+    },
+
+    /// [`Request::Hello`], also placing this client's watches in
+    /// `namespace`.
+    ///
+    /// On a multi-tenant daemon a client sees, and may change, only the
+    /// watches of clients in its namespace: by default the one named after
+    /// its uid. A name picked here is kept apart from other users' by
+    /// prefixing the uid, unless the client is root or the daemon's own
+    /// user, who may join any namespace, e.g. to act for another user.
+    /// Send it before adding watches. Answered with [`Response::Hello`], or
+    /// an error if the name is empty or holds whitespace or commas.
+    HelloNamespace {
+        /// Namespace to join.
+        namespace: String,
+    },
 }
 
 /// Response messages sent from daemon to client (LD_PRELOAD).
@@ -54,6 +652,12 @@ pub enum Response {
     ClientRegistered {
         /// Unique client identifier.
         client_id: u64,
+        /// Secret naming this client to [`Request::AttachControl`] and
+        /// [`Request::Resume`].
+        session: String,
+        /// Features in use for this client (combination of
+        /// [`Capabilities`] flags); none until it registers its own.
+        capabilities: u16,
     },
 
     /// Watch added successfully.
@@ -67,12 +671,234 @@ pub enum Response {
 
     /// Error response.
     Error {
+        /// What went wrong, for programs.
+        code: ErrorCode,
         /// Human-readable error message.
         message: String,
     },
 
     /// Pong response to a ping.
     Pong,
+
+    /// An emulated per-user inotify limit would be exceeded.
+    ///
+    /// Clients map this to the errno the kernel uses for the same limit
+    /// (`EMFILE` for instances, `ENOSPC` for watches).
+    LimitExceeded {
+        /// Name of the limit, as in `/proc/sys/fs/inotify` (e.g. `max_user_watches`).
+        limit: String,
+    },
+
+    /// Path translation table replaced.
+    PathMapSet,
+
+    /// Per-entry results of a batch request, in request order.
+    Batch {
+        /// Number of entries in `results`.
+        count: u32,
+        /// One response per entry.
+        results: Vec<Response>,
+    },
+
+    /// Unsolicited liveness probe, not tied to any request.
+    ///
+    /// Clients answer with [`Request::HeartbeatAck`]. A client that
+    /// negotiated [`Capabilities::HEARTBEAT`] and that the daemon has not
+    /// heard from within its heartbeat timeout is disconnected and its
+    /// watches are removed.
+    Heartbeat,
+
+    /// Watches removed by [`Request::Prune`].
+    Pruned {
+        /// Paths of the removed watches.
+        paths: Vec<PathBuf>,
+    },
+
+    /// Event filter replaced.
+    FilterSet,
+
+    /// Answer to [`Request::Stats`].
+    Stats {
+        /// Connected clients.
+        clients: u32,
+        /// Seconds since the daemon started.
+        uptime_secs: u64,
+        /// Active watches.
+        watches: u32,
+        /// Length of the rate window in seconds; 0 if rate tracking is off.
+        window_secs: u32,
+        /// Busiest watches, busiest first.
+        watch_rates: Vec<PathRate>,
+        /// Busiest directories, busiest first.
+        dir_rates: Vec<PathRate>,
+        /// Mounts that stopped responding, ordered by path.
+        degraded_mounts: Vec<DegradedMount>,
+        /// Scanner reads abandoned as stuck in IO since startup.
+        stuck_scans: u64,
+        /// Events dropped since startup because dispatch fell behind.
+        dropped_events: u64,
+        /// Roots scanned as part of an enclosing root rather than on their
+        /// own.
+        shared_roots: u32,
+        /// Scans of those roots skipped since startup.
+        scans_saved: u64,
+        /// Per-user limits, for comparison with `users`.
+        limits: UserLimits,
+        /// Current usage per uid, ordered by uid.
+        users: Vec<UserUsage>,
+    },
+
+    /// Answer to [`Request::Hello`].
+    Hello {
+        /// Configured watch roots, as the daemon sees them.
+        roots: Vec<PathBuf>,
+        /// Features the daemon understands (combination of
+        /// [`Capabilities`] flags).
+        capabilities: u16,
+    },
+
+    /// Answer to [`Request::ListWatches`].
+    Watches {
+        /// Every watch, ordered by descriptor.
+        watches: Vec<WatchSummary>,
+    },
+
+    /// Answer to [`Request::ListClients`].
+    Clients {
+        /// Every connected client, ordered by ID.
+        clients: Vec<ClientSummary>,
+    },
+
+    /// Answer to [`Request::ExportRoots`].
+    Roots {
+        /// Every scanned root, ordered by path.
+        roots: Vec<WatchRoot>,
+    },
+
+    /// Answer to [`Request::ImportRoots`].
+    Imported {
+        /// Roots now being scanned.
+        added: Vec<PathBuf>,
+        /// Roots that were already being scanned.
+        existing: Vec<PathBuf>,
+        /// Roots that could not be added, with the reason.
+        failed: Vec<(PathBuf, String)>,
+    },
+
+    /// Answer to [`Request::Shutdown`].
+    ShuttingDown {
+        /// Daemon process ID, to wait for or signal if it hangs.
+        pid: u32,
+    },
+
+    /// Answer to [`Request::Status`].
+    Status {
+        /// Daemon version.
+        version: String,
+        /// Wire protocol version the daemon speaks.
+        protocol_version: u32,
+        /// Events waiting to be dispatched to clients.
+        event_queue: u32,
+        /// Events the dispatch queue holds before dropping.
+        event_queue_capacity: u32,
+        /// Events each client may have queued before it overflows.
+        client_queue_capacity: u32,
+        /// Mounts the watched roots live on, ordered by path.
+        mounts: Vec<MountStatus>,
+        /// The last warning or error logged, if any.
+        last_error: Option<LoggedError>,
+    },
+
+    /// Answer to [`Request::GetWatchInfo`].
+    WatchInfo {
+        /// The watch.
+        info: WatchInfo,
+    },
+
+    /// A zstd-compressed frame payload, sent in place of any event or
+    /// response only to clients that negotiated
+    /// [`Capabilities::COMPRESSION`].
+    Compressed {
+        /// Kind of the frame this replaces, which decides how to read the
+        /// payload once decompressed.
+        kind: FrameKind,
+        /// Compressed bytes of the payload the frame would have carried.
+        payload: Vec<u8>,
+    },
+
+    /// Several events in one frame, sent during bursts only to clients
+    /// that negotiated [`Capabilities::EVENT_BATCH`].
+    EventBatch {
+        /// Number of events in `events`.
+        count: u32,
+        /// The events back to back, laid out as a read of an inotify fd
+        /// returns them.
+        events: Vec<u8>,
+    },
+
+    /// This connection now acts for another client.
+    ControlAttached {
+        /// The client requests on this connection act for.
+        client_id: u64,
+    },
+
+    /// This connection now belongs to a resumed client.
+    Resumed {
+        /// The resumed client's ID.
+        client_id: u64,
+        /// Features in use for the resumed client.
+        capabilities: u16,
+    },
+
+    /// Answer to a [`Request::Tagged`].
+    Tagged {
+        /// ID of the request this answers.
+        id: u64,
+        /// The answer itself.
+        response: Box<Response>,
+    },
+
+    /// Answer to [`Request::AddWatchSince`].
+    WatchAddedSince {
+        /// Watch descriptor for the new watch.
+        wd: i32,
+        /// Journaled events after `since` sent ahead of this answer.
+        replayed: u64,
+        /// The journal's position: the number of the last event journaled
+        /// under the path, which any replayed events lead up to.
+        cursor: Cursor,
+        /// Events after `since` are missing, because the daemon restarted
+        /// or its journal no longer reaches back that far; the consumer
+        /// should rescan what it tracks.
+        gap: bool,
+    },
+
+    /// An event on a watch added with [`Request::AddWatchSince`], with its
+    /// place in the journal.
+    Sequenced {
+        /// Sequence number of the event under the watched path.
+        seq: u64,
+        /// The event, laid out as a read of an inotify fd returns it.
+        event: Vec<u8>,
+    },
+
+    /// Answer to [`Request::TagWatch`].
+    WatchTagged,
+
+    /// Watches removed by [`Request::RemoveTagged`].
+    Removed {
+        /// Paths of the removed watches.
+        paths: Vec<PathBuf>,
+    },
+
+    /// Watch added, but without the events the daemon denies on its path;
+    /// sent in place of `WatchAdded` to clients that negotiated
+    /// [`Capabilities::MASK_CLAMP`].
+    WatchClamped {
+        wd: i32,
+        /// The mask the watch was added with.
+        mask: u32,
+    },
 }
 
 impl Request {
@@ -81,9 +907,95 @@ impl Request {
         bincode::serialize(self).map_err(Into::into)
     }
 
+    /// Serialize this request straight into `writer`, such as a socket, a
+    /// `Vec<u8>` or a `&mut [u8]`.
+    pub fn write_to(&self, writer: impl Write) -> Result<(), ProtocolError> {
+        bincode::serialize_into(writer, self).map_err(Into::into)
+    }
+
     /// Deserialize a request from bytes.
+    ///
+    /// A [`Request::RegisterClient`] of another protocol version that does
+    /// not decode fails with [`ProtocolError::VersionMismatch`].
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        bincode::deserialize(bytes).map_err(Into::into)
+        bincode::deserialize(bytes).map_err(|e| match Self::registration_version(bytes) {
+            Some(version) if version != crate::PROTOCOL_VERSION => {
+                ProtocolError::VersionMismatch(version)
+            }
+            _ => e.into(),
+        })
+    }
+
+    /// The protocol version a [`Request::RegisterClient`] was sent with,
+    /// read without decoding the rest
+    ///
+    /// It is variant 0 in every version; version 1 sent it without fields.
+    fn registration_version(bytes: &[u8]) -> Option<u32> {
+        match bytes {
+            [0, 0, 0, 0] => Some(1),
+            [0, 0, 0, 0, version @ ..] => {
+                Some(u32::from_le_bytes(version.get(..4)?.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A borrowed [`Request::AddWatch`], for callers that must not allocate.
+///
+/// Serializes to the same bytes as the owned request, wrapped in
+/// [`Request::Tagged`] when it carries an ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddWatchRef<'a> {
+    /// Path to watch.
+    pub path: &'a Path,
+    /// Event mask (combination of EventMask flags).
+    pub mask: u32,
+    /// ID to tag the request with, if any.
+    pub id: Option<u64>,
+}
+
+impl AddWatchRef<'_> {
+    /// Index of [`Request::AddWatch`] among the request variants.
+    const ADD_WATCH: u32 = 1;
+    /// Index of [`Request::Tagged`] among the request variants.
+    const TAGGED: u32 = 19;
+
+    /// Write the request, framed, into `buf`, returning the frame's length.
+    ///
+    /// Fails if the frame does not fit.
+    pub fn frame_into(&self, buf: &mut [u8]) -> Result<usize, ProtocolError> {
+        FramedMessage::encode_into_slice(self, buf)
+    }
+}
+
+impl Serialize for AddWatchRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        /// The untagged request
+        struct AddWatch<'a>(&'a AddWatchRef<'a>);
+
+        impl Serialize for AddWatch<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut variant = serializer.serialize_struct_variant(
+                    "Request",
+                    AddWatchRef::ADD_WATCH,
+                    "AddWatch",
+                    2,
+                )?;
+                variant.serialize_field("path", self.0.path)?;
+                variant.serialize_field("mask", &self.0.mask)?;
+                variant.end()
+            }
+        }
+
+        let Some(id) = self.id else {
+            return AddWatch(self).serialize(serializer);
+        };
+        let mut variant =
+            serializer.serialize_struct_variant("Request", Self::TAGGED, "Tagged", 2)?;
+        variant.serialize_field("id", &id)?;
+        variant.serialize_field("request", &AddWatch(self))?;
+        variant.end()
     }
 }
 
@@ -93,25 +1005,82 @@ impl Response {
         bincode::serialize(self).map_err(Into::into)
     }
 
+    /// Serialize this response straight into `writer`, such as a socket, a
+    /// `Vec<u8>` or a `&mut [u8]`.
+    pub fn write_to(&self, writer: impl Write) -> Result<(), ProtocolError> {
+        bincode::serialize_into(writer, self).map_err(Into::into)
+    }
+
     /// Deserialize a response from bytes.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
-        bincode::deserialize(bytes).map_err(Into::into)
+        bincode::deserialize(bytes).map_err(|e| {
+            if Self::is_v1_error(bytes) {
+                ProtocolError::VersionMismatch(1)
+            } else {
+                e.into()
+            }
+        })
+    }
+
+    /// Whether `bytes` are a version 1 [`Response::Error`], which carried
+    /// only the message
+    ///
+    /// They never decode as the current layout, which reads the message
+    /// length from the middle of the old one.
+    fn is_v1_error(bytes: &[u8]) -> bool {
+        match bytes {
+            [3, 0, 0, 0, len @ ..] => len
+                .get(..8)
+                .and_then(|len| len.try_into().ok())
+                .is_some_and(|len| u64::from_le_bytes(len) == bytes.len() as u64 - 12),
+            _ => false,
+        }
     }
 
-    /// Create an error response.
+    /// Create an error response for a malformed request.
     #[must_use]
     pub fn error(message: impl Into<String>) -> Self {
+        Self::error_with(ErrorCode::Invalid, message)
+    }
+
+    /// Create an error response with a specific code.
+    #[must_use]
+    pub fn error_with(code: ErrorCode, message: impl Into<String>) -> Self {
         Self::Error {
+            code,
             message: message.into(),
         }
     }
+
+    /// Create the answer to a batch request from its per-entry results.
+    #[must_use]
+    pub fn batch(results: Vec<Response>) -> Self {
+        Self::Batch {
+            count: results.len() as u32,
+            results,
+        }
+    }
+}
+
+/// What a frame carries, as marked in its length prefix.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    /// A bincode-encoded [`Request`] or [`Response`].
+    Message,
+    /// Raw inotify events, laid out as the kernel writes them.
+    Event,
 }
 
 /// A length-prefixed message wrapper for framing.
 ///
 /// Messages are sent as:
-/// - 4 bytes: message length (u32, little-endian)
+/// - 4 bytes: message length (u32, little-endian), with
+///   [`EVENT_FLAG`](Self::EVENT_FLAG) set for [`FrameKind::Event`] frames
 /// - N bytes: message payload
+///
+/// Events and responses share the daemon's side of a connection, and the
+/// flag is the only thing telling them apart; nothing about a payload's
+/// bytes is.
 #[derive(Debug, Clone)]
 pub struct FramedMessage;
 
@@ -119,41 +1088,214 @@ impl FramedMessage {
     /// Maximum message size (1 MB).
     pub const MAX_SIZE: usize = 1024 * 1024;
 
+    /// Length prefix bit marking a [`FrameKind::Event`] frame, far above
+    /// [`MAX_SIZE`](Self::MAX_SIZE).
+    pub const EVENT_FLAG: u32 = 1 << 31;
+
     /// Frame a message with a length prefix.
     pub fn frame(payload: &[u8]) -> Vec<u8> {
-        let len = payload.len() as u32;
         let mut buf = Vec::with_capacity(4 + payload.len());
-        buf.extend_from_slice(&len.to_le_bytes());
-        buf.extend_from_slice(payload);
+        Self::frame_into(payload, &mut buf);
+        buf
+    }
+
+    /// Append a message, framed, to `out`.
+    pub fn frame_into(payload: &[u8], out: &mut Vec<u8>) {
+        out.reserve(4 + payload.len());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+    }
+
+    /// Frame a raw inotify event, marked as [`FrameKind::Event`].
+    pub fn frame_event(payload: &[u8]) -> Vec<u8> {
+        let mut buf = Self::frame(payload);
+        buf[..4].copy_from_slice(&(payload.len() as u32 | Self::EVENT_FLAG).to_le_bytes());
         buf
     }
 
-    /// Read the length prefix from a buffer.
+    /// Write a message, framed, into `buf`, returning the frame's length.
+    ///
+    /// Fails if the frame does not fit.
+    pub fn frame_into_slice(payload: &[u8], buf: &mut [u8]) -> Result<usize, ProtocolError> {
+        let len = 4 + payload.len();
+        let Some(frame) = buf.get_mut(..len) else {
+            return Err(Self::no_room());
+        };
+        frame[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame[4..].copy_from_slice(payload);
+        Ok(len)
+    }
+
+    /// Serialize `message` and append it, framed, to `out`, with no
+    /// intermediate buffer.
+    pub fn encode_into(message: &impl Serialize, out: &mut Vec<u8>) -> Result<(), ProtocolError> {
+        let start = out.len();
+        out.extend_from_slice(&[0; 4]);
+        if let Err(e) = bincode::serialize_into(&mut *out, message) {
+            out.truncate(start);
+            return Err(e.into());
+        }
+        let len = (out.len() - start - 4) as u32;
+        out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        Ok(())
+    }
+
+    /// Serialize `message` into `buf`, framed, returning the frame's length.
+    ///
+    /// Fails if the frame does not fit.
+    pub fn encode_into_slice(
+        message: &impl Serialize,
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        if buf.len() < 4 {
+            return Err(Self::no_room());
+        }
+        let (prefix, body) = buf.split_at_mut(4);
+        let room = body.len();
+        let mut rest = body;
+        bincode::serialize_into(&mut rest, message)?;
+        let len = room - rest.len();
+        prefix.copy_from_slice(&(len as u32).to_le_bytes());
+        Ok(4 + len)
+    }
+
+    fn no_room() -> ProtocolError {
+        ProtocolError::InvalidMessage("frame does not fit the buffer".into())
+    }
+
+    /// Read the payload length from a buffer's length prefix.
     ///
     /// Returns `None` if the buffer is too small.
     #[must_use]
     pub fn read_length(buf: &[u8]) -> Option<u32> {
-        if buf.len() < 4 {
-            return None;
-        }
-        Some(u32::from_le_bytes(buf[0..4].try_into().ok()?))
+        Self::read_header(buf).map(|(_, len)| len)
+    }
+
+    /// Read what the frame at the start of a buffer carries.
+    ///
+    /// Returns `None` if the buffer is too small.
+    #[must_use]
+    pub fn read_kind(buf: &[u8]) -> Option<FrameKind> {
+        Self::read_header(buf).map(|(kind, _)| kind)
+    }
+
+    /// Read both the kind and the payload length of the frame at the start
+    /// of a buffer.
+    ///
+    /// Returns `None` if the buffer is too small.
+    #[must_use]
+    pub fn read_header(buf: &[u8]) -> Option<(FrameKind, u32)> {
+        let prefix = Self::read_prefix(buf)?;
+        let kind = if prefix & Self::EVENT_FLAG == 0 {
+            FrameKind::Message
+        } else {
+            FrameKind::Event
+        };
+        Some((kind, prefix & !Self::EVENT_FLAG))
+    }
+
+    fn read_prefix(buf: &[u8]) -> Option<u32> {
+        Some(u32::from_le_bytes(buf.get(..4)?.try_into().ok()?))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::InotifyEvent;
 
     #[test]
     fn test_request_roundtrip() {
         let requests = vec![
-            Request::RegisterClient,
+            Request::RegisterClient {
+                protocol_version: crate::PROTOCOL_VERSION,
+                info: ClientInfo {
+                    name: Some("sonarr".to_string()),
+                    pid: Some(17),
+                    version: None,
+                },
+                capabilities: Capabilities::EVENT_BATCH.bits(),
+            },
             Request::AddWatch {
                 path: PathBuf::from("/tmp/test"),
                 mask: 0x100,
             },
             Request::RemoveWatch { wd: 42 },
             Request::Ping,
+            Request::SetPathMap {
+                mappings: vec![PathMapping {
+                    host: PathBuf::from("/srv/media"),
+                    client: PathBuf::from("/data"),
+                }],
+            },
+            Request::AddWatchBatch {
+                entries: vec![WatchEntry {
+                    path: PathBuf::from("/tmp/a"),
+                    mask: 0x100,
+                }],
+            },
+            Request::RemoveWatchBatch { wds: vec![1, 2] },
+            Request::HeartbeatAck,
+            Request::Prune,
+            Request::SetFilter {
+                filter: NameFilter {
+                    extensions: vec!["mkv".to_string()],
+                    regex: Some("^[^.]".to_string()),
+                },
+            },
+            Request::Stats { top: 10 },
+            Request::Hello,
+            Request::ListWatches,
+            Request::ListClients,
+            Request::ExportRoots,
+            Request::ImportRoots {
+                roots: vec![WatchRoot {
+                    path: PathBuf::from("/mnt/media"),
+                    poll_interval_ms: 5000,
+                    recursive: true,
+                    native: false,
+                    mask: 0x100,
+                    extensions: vec!["mkv".to_string()],
+                    name_regex: None,
+                    change_detection: ChangeDetection::Ctime,
+                    revalidate: true,
+                    case_insensitive: None,
+                    confirm_deletes: 1,
+                    restat_deletes: false,
+                    tags: vec!["media".to_string()],
+                    denied_mask: 0x1,
+                    settle_scans: 2,
+                    partial_suffixes: vec![".part".to_string()],
+                }],
+            },
+            Request::Shutdown,
+            Request::Status,
+            Request::GetWatchInfo { wd: 3 },
+            Request::Tagged {
+                id: 9,
+                request: Box::new(Request::RemoveWatch { wd: 2 }),
+            },
+            Request::AttachControl {
+                session: "5f0c8e2a9b1d4c7e8f3a6b2d1c0e9f8a".to_string(),
+            },
+            Request::Resume {
+                session: "5f0c8e2a9b1d4c7e8f3a6b2d1c0e9f8a".to_string(),
+            },
+            Request::AddWatchSince {
+                path: PathBuf::from("/mnt/media"),
+                mask: 0x100,
+                since: Some(Cursor { epoch: 7, seq: 42 }),
+            },
+            Request::TagWatch {
+                wd: 3,
+                tags: vec!["ci".to_string(), "team-media".to_string()],
+            },
+            Request::RemoveTagged {
+                tag: "ci".to_string(),
+            },
+            Request::HelloNamespace {
+                namespace: "team-media".to_string(),
+            },
         ];
 
         for req in requests {
@@ -166,13 +1308,161 @@ mod tests {
     #[test]
     fn test_response_roundtrip() {
         let responses = vec![
-            Response::ClientRegistered { client_id: 12345 },
+            Response::ClientRegistered {
+                client_id: 12345,
+                session: "5f0c8e2a9b1d4c7e8f3a6b2d1c0e9f8a".to_string(),
+                capabilities: 0,
+            },
+            Response::ControlAttached { client_id: 12345 },
+            Response::Resumed {
+                client_id: 12345,
+                capabilities: 1,
+            },
             Response::WatchAdded { wd: 1 },
             Response::WatchRemoved,
             Response::Error {
+                code: ErrorCode::NotFound,
                 message: "test error".to_string(),
             },
             Response::Pong,
+            Response::LimitExceeded {
+                limit: "max_user_watches".to_string(),
+            },
+            Response::PathMapSet,
+            Response::batch(vec![
+                Response::WatchAdded { wd: 3 },
+                Response::error("gone"),
+            ]),
+            Response::Heartbeat,
+            Response::Pruned {
+                paths: vec![PathBuf::from("/mnt/gone")],
+            },
+            Response::FilterSet,
+            Response::Stats {
+                uptime_secs: 60,
+                clients: 2,
+                watches: 3,
+                window_secs: 10,
+                watch_rates: vec![PathRate {
+                    path: PathBuf::from("/mnt/media"),
+                    events: 120,
+                    noisy: false,
+                    tags: vec!["media".to_string()],
+                }],
+                dir_rates: Vec::new(),
+                degraded_mounts: vec![DegradedMount {
+                    path: PathBuf::from("/mnt/nas"),
+                    degraded_secs: 42,
+                    failed_probes: 3,
+                }],
+                stuck_scans: 1,
+                dropped_events: 0,
+                shared_roots: 1,
+                scans_saved: 40,
+                limits: UserLimits {
+                    max_user_watches: 8192,
+                    max_user_instances: 128,
+                    max_queued_events: 16384,
+                },
+                users: vec![UserUsage {
+                    uid: 1000,
+                    instances: 2,
+                    watches: 3,
+                    queued_events: 7,
+                }],
+            },
+            Response::Hello {
+                roots: vec![PathBuf::from("/mnt/media")],
+                capabilities: Capabilities::all().bits(),
+            },
+            Response::Watches {
+                watches: vec![WatchSummary {
+                    wd: 1,
+                    path: PathBuf::from("/mnt/media"),
+                    mask: 0x100,
+                    recursive: true,
+                    clients: 2,
+                    tags: vec!["ci".to_string()],
+                }],
+            },
+            Response::Clients {
+                clients: vec![ClientSummary {
+                    id: 7,
+                    uid: 1000,
+                    pid: Some(4242),
+                    watches: 1,
+                    queued_events: 0,
+                    connected_secs: 30,
+                    info: ClientInfo::default(),
+                    capabilities: 0,
+                    namespace: "1000".to_string(),
+                }],
+            },
+            Response::Roots { roots: Vec::new() },
+            Response::Imported {
+                added: vec![PathBuf::from("/mnt/media")],
+                existing: Vec::new(),
+                failed: vec![(PathBuf::from("/mnt/gone"), "not found".to_string())],
+            },
+            Response::ShuttingDown { pid: 4242 },
+            Response::Status {
+                version: "0.1.0".to_string(),
+                protocol_version: 1,
+                event_queue: 12,
+                event_queue_capacity: 65536,
+                client_queue_capacity: 16384,
+                mounts: vec![MountStatus {
+                    path: PathBuf::from("/mnt/nas"),
+                    roots: 2,
+                    degraded_secs: Some(42),
+                    failed_probes: 3,
+                }],
+                last_error: Some(LoggedError {
+                    level: "WARN".to_string(),
+                    message: "Mount is not responding, pausing scans".to_string(),
+                    age_secs: 42,
+                }),
+            },
+            Response::WatchInfo {
+                info: WatchInfo {
+                    wd: 3,
+                    path: PathBuf::from("/mnt/media/show"),
+                    mask: 0x100,
+                    recursive: false,
+                    clients: 1,
+                    root: Some(PathBuf::from("/mnt/media")),
+                    snapshot_entries: 1200,
+                    last_scan_ms_ago: Some(800),
+                    tags: Vec::new(),
+                },
+            },
+            Response::Compressed {
+                kind: FrameKind::Event,
+                payload: vec![0x28, 0xb5, 0x2f, 0xfd],
+            },
+            Response::EventBatch {
+                count: 1,
+                events: InotifyEvent::new(1, 0x100, 0).to_bytes_with_name(b"a.mkv"),
+            },
+            Response::Tagged {
+                id: 9,
+                response: Box::new(Response::WatchAdded { wd: 3 }),
+            },
+            Response::WatchAddedSince {
+                wd: 3,
+                replayed: 2,
+                cursor: Cursor { epoch: 7, seq: 44 },
+                gap: false,
+            },
+            Response::Sequenced {
+                seq: 44,
+                event: InotifyEvent::new(3, 0x100, 0).to_bytes_with_name(b"a.mkv"),
+            },
+            Response::WatchTagged,
+            Response::Removed {
+                paths: vec![PathBuf::from("/mnt/ci")],
+            },
+            Response::WatchClamped { wd: 3, mask: 0x2 },
         ];
 
         for resp in responses {
@@ -182,6 +1472,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_valid_tags() {
+        assert!(is_valid_tag("team-media"));
+        assert!(!is_valid_tag(""));
+        assert!(!is_valid_tag("ci,nightly"));
+        assert!(!is_valid_tag("two words"));
+    }
+
+    #[test]
+    fn test_cursor_text() {
+        let cursor = Cursor {
+            epoch: 0x5f0c_8e2a,
+            seq: 42,
+        };
+        assert_eq!(cursor.to_string(), "000000005f0c8e2a:42");
+        assert_eq!("000000005f0c8e2a:42\n".parse::<Cursor>().unwrap(), cursor);
+        assert!("5f0c8e2a".parse::<Cursor>().is_err());
+        assert!("x:1".parse::<Cursor>().is_err());
+    }
+
     #[test]
     fn test_framed_message() {
         let payload = b"hello world";
@@ -193,13 +1503,170 @@ mod tests {
             Some(payload.len() as u32)
         );
         assert_eq!(&framed[4..], payload);
+        assert_eq!(FramedMessage::read_kind(&framed), Some(FrameKind::Message));
+    }
+
+    #[test]
+    fn test_event_frames_are_marked() {
+        let event = InotifyEvent::new(1, 0x100, 0).to_bytes_with_name(b"a.mkv");
+        let framed = FramedMessage::frame_event(&event);
+        assert_eq!(FramedMessage::read_kind(&framed), Some(FrameKind::Event));
+        assert_eq!(
+            FramedMessage::read_length(&framed),
+            Some(event.len() as u32)
+        );
+        assert_eq!(&framed[4..], event);
+
+        // Sixteen bytes with a zero where an event keeps its name length,
+        // yet still a message
+        let lookalike = Response::batch(Vec::new()).to_bytes().unwrap();
+        assert_eq!(lookalike.len(), InotifyEvent::HEADER_SIZE);
+        let framed = FramedMessage::frame(&lookalike);
+        assert_eq!(FramedMessage::read_kind(&framed), Some(FrameKind::Message));
+        assert_eq!(FramedMessage::read_kind(&framed[..3]), None);
+    }
+
+    #[test]
+    fn test_add_watch_ref_matches_owned_request() {
+        let path = Path::new("/tmp/watched");
+        for id in [None, Some(7)] {
+            let owned = Request::AddWatch {
+                path: path.to_path_buf(),
+                mask: 0x100,
+            };
+            let owned = match id {
+                Some(id) => Request::Tagged {
+                    id,
+                    request: Box::new(owned),
+                },
+                None => owned,
+            };
+            let borrowed = AddWatchRef {
+                path,
+                mask: 0x100,
+                id,
+            };
+
+            let mut buf = [0u8; 128];
+            let len = borrowed.frame_into(&mut buf).unwrap();
+            assert_eq!(
+                &buf[..len],
+                FramedMessage::frame(&owned.to_bytes().unwrap())
+            );
+            // One byte short
+            assert!(borrowed.frame_into(&mut buf[..len - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_frame_into_buffers() {
+        let request = Request::RemoveWatch { wd: 3 };
+        let expected = FramedMessage::frame(&request.to_bytes().unwrap());
+
+        let mut out = b"prefix".to_vec();
+        FramedMessage::encode_into(&request, &mut out).unwrap();
+        assert_eq!(&out[6..], expected);
+        let mut out = Vec::new();
+        FramedMessage::frame_into(&request.to_bytes().unwrap(), &mut out);
+        assert_eq!(out, expected);
+
+        let mut buf = [0u8; 64];
+        let len = FramedMessage::encode_into_slice(&request, &mut buf).unwrap();
+        assert_eq!(&buf[..len], expected);
+        let len = FramedMessage::frame_into_slice(&request.to_bytes().unwrap(), &mut buf).unwrap();
+        assert_eq!(&buf[..len], expected);
+        assert!(FramedMessage::encode_into_slice(&request, &mut buf[..len - 1]).is_err());
+        assert!(FramedMessage::frame_into_slice(b"12345", &mut buf[..8]).is_err());
+
+        let mut written = Vec::new();
+        request.write_to(&mut written).unwrap();
+        assert_eq!(written, request.to_bytes().unwrap());
+        let mut slice = &mut buf[..];
+        Response::Pong.write_to(&mut slice).unwrap();
+        let len = 64 - slice.len();
+        assert_eq!(&buf[..len], Response::Pong.to_bytes().unwrap());
+        assert_eq!(Response::from_bytes(&buf[..len]).unwrap(), Response::Pong);
+    }
+
+    #[test]
+    fn test_other_protocol_versions_are_named() {
+        // Version 1 registered with the bare variant
+        assert!(matches!(
+            Request::from_bytes(&[0, 0, 0, 0]),
+            Err(ProtocolError::VersionMismatch(1))
+        ));
+
+        // A later version still decodes, and the daemon checks the number
+        let mut register = Request::RegisterClient {
+            protocol_version: crate::PROTOCOL_VERSION + 1,
+            info: ClientInfo::default(),
+            capabilities: 0,
+        }
+        .to_bytes()
+        .unwrap();
+        assert!(matches!(
+            Request::from_bytes(&register),
+            Ok(Request::RegisterClient {
+                protocol_version,
+                ..
+            }) if protocol_version == crate::PROTOCOL_VERSION + 1
+        ));
+        // Unless it laid the rest out differently
+        register.truncate(8);
+        assert!(matches!(
+            Request::from_bytes(&register),
+            Err(ProtocolError::VersionMismatch(v)) if v == crate::PROTOCOL_VERSION + 1
+        ));
+
+        // Requests of this version that do not decode are just invalid
+        assert!(matches!(
+            Request::from_bytes(&[0, 0, 0, 0, 2, 0]),
+            Err(ProtocolError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_version_1_errors_are_refused() {
+        // Variant 3, then the message with its u64 length
+        let mut v1 = vec![3, 0, 0, 0];
+        v1.extend_from_slice(&5u64.to_le_bytes());
+        v1.extend_from_slice(b"no fd");
+        assert!(matches!(
+            Response::from_bytes(&v1),
+            Err(ProtocolError::VersionMismatch(1))
+        ));
+        let mut empty = vec![3, 0, 0, 0];
+        empty.extend_from_slice(&0u64.to_le_bytes());
+        assert!(matches!(
+            Response::from_bytes(&empty),
+            Err(ProtocolError::VersionMismatch(1))
+        ));
+
+        // Current errors still decode, whatever their code and message
+        for code in ErrorCode::ALL {
+            for message in ["", "no fd"] {
+                let error = Response::error_with(code, message);
+                let bytes = error.to_bytes().unwrap();
+                assert_eq!(Response::from_bytes(&bytes).unwrap(), error);
+            }
+        }
+        // And a broken one is only broken
+        let mut truncated = Response::error("no fd").to_bytes().unwrap();
+        truncated.pop();
+        assert!(matches!(
+            Response::from_bytes(&truncated),
+            Err(ProtocolError::Serialization(_))
+        ));
     }
 
     #[test]
     fn test_response_error_helper() {
         let resp = Response::error("something went wrong");
         match resp {
-            Response::Error { message } => assert_eq!(message, "something went wrong"),
+            Response::Error { code, message } => {
+                assert_eq!(code, ErrorCode::Invalid);
+                assert_eq!(message, "something went wrong");
+            }
             _ => panic!("expected Error variant"),
         }
     }
@@ -0,0 +1,64 @@
+//! Client-to-host path translation.
+//!
+//! A containerized client often sees a volume at a different path than the
+//! daemon does (`/data` inside, `/var/lib/kubelet/pods/...` on the host).
+//! Clients describe the difference with [`PathMapping`]s, either sent with
+//! [`Request::SetPathMap`](crate::Request::SetPathMap) or configured on the
+//! daemon.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Environment variable holding a client's path map, as
+/// `client=host` pairs separated by colons, e.g.
+/// `FAKENOTIFY_PATH_MAP=/data=/srv/media:/config=/srv/config`.
+pub const PATH_MAP_ENV_VAR: &str = "FAKENOTIFY_PATH_MAP";
+
+/// The same directory as the client and the daemon name it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathMapping {
+    /// Path as the daemon sees it.
+    pub host: PathBuf,
+    /// Path as the client sees it.
+    pub client: PathBuf,
+}
+
+/// Parse a [`PATH_MAP_ENV_VAR`] value, skipping malformed entries.
+#[must_use]
+pub fn parse_path_map(value: &str) -> Vec<PathMapping> {
+    value
+        .split(':')
+        .filter_map(|entry| {
+            let (client, host) = entry.trim().split_once('=')?;
+            let (client, host) = (client.trim(), host.trim());
+            (!client.is_empty() && !host.is_empty()).then(|| PathMapping {
+                host: PathBuf::from(host),
+                client: PathBuf::from(client),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_map() {
+        let map = parse_path_map("/data=/srv/media: /config = /srv/config :bogus:=/x");
+        assert_eq!(
+            map,
+            vec![
+                PathMapping {
+                    host: PathBuf::from("/srv/media"),
+                    client: PathBuf::from("/data"),
+                },
+                PathMapping {
+                    host: PathBuf::from("/srv/config"),
+                    client: PathBuf::from("/config"),
+                },
+            ]
+        );
+        assert!(parse_path_map("").is_empty());
+    }
+}
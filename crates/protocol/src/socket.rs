@@ -1,17 +1,23 @@
 //! Socket path helpers for the FakeNotify IPC.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Default socket path for the FakeNotify daemon.
+#[cfg(not(target_os = "macos"))]
 pub const DEFAULT_SOCKET_PATH: &str = "/run/fakenotify/fakenotify.sock";
 
+/// Default socket path for the FakeNotify daemon on macOS, which has no
+/// `/run` and empties `/var/run` at boot, so the socket sits directly in it.
+#[cfg(target_os = "macos")]
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/fakenotify.sock";
+
 /// Environment variable to override the socket path.
 pub const SOCKET_ENV_VAR: &str = "FAKENOTIFY_SOCKET";
 
 /// Get the socket path to use for IPC.
 ///
 /// Checks the `FAKENOTIFY_SOCKET` environment variable first,
-/// falling back to the default path `/run/fakenotify/fakenotify.sock`.
+/// falling back to [`DEFAULT_SOCKET_PATH`].
 #[must_use]
 pub fn get_socket_path() -> PathBuf {
     std::env::var(SOCKET_ENV_VAR)
@@ -24,7 +30,7 @@ pub fn get_socket_path() -> PathBuf {
 /// Resolution order:
 /// 1. `FAKENOTIFY_SOCKET` environment variable
 /// 2. `$XDG_RUNTIME_DIR/fakenotify.sock` (if XDG_RUNTIME_DIR is set)
-/// 3. Default: `/run/fakenotify/fakenotify.sock`
+/// 3. Default: [`DEFAULT_SOCKET_PATH`]
 ///
 /// This is useful for unprivileged users who cannot write to `/run`.
 #[must_use]
@@ -43,10 +49,189 @@ pub fn get_socket_path_with_xdg_fallback() -> PathBuf {
     PathBuf::from(DEFAULT_SOCKET_PATH)
 }
 
+/// Directory under `$XDG_RUNTIME_DIR` where daemon instances put their
+/// sockets, one `<name>.sock` per instance.
+pub const INSTANCE_DIR: &str = "fakenotify";
+
+/// Every socket a client could try, in order of preference.
+///
+/// 1. `FAKENOTIFY_SOCKET` alone, if set
+/// 2. `$XDG_RUNTIME_DIR/fakenotify/*.sock`, sorted by name
+/// 3. `$XDG_RUNTIME_DIR/fakenotify.sock`
+/// 4. [`DEFAULT_SOCKET_PATH`]
+///
+/// Sockets in the instance directory are listed whether or not a daemon is
+/// listening on them; callers skip the ones that refuse connections.
+#[must_use]
+pub fn discover_socket_paths() -> Vec<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+    socket_candidates(
+        std::env::var(SOCKET_ENV_VAR).ok().as_deref(),
+        runtime_dir.as_deref(),
+    )
+}
+
+/// [`discover_socket_paths`] with the environment passed in
+#[must_use]
+pub fn socket_candidates(socket_var: Option<&str>, runtime_dir: Option<&Path>) -> Vec<PathBuf> {
+    if let Some(path) = socket_var {
+        return vec![PathBuf::from(path)];
+    }
+
+    let mut candidates = Vec::new();
+    if let Some(runtime_dir) = runtime_dir {
+        if let Ok(entries) = std::fs::read_dir(runtime_dir.join(INSTANCE_DIR)) {
+            let mut instances: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "sock"))
+                .collect();
+            instances.sort();
+            candidates.extend(instances);
+        }
+        candidates.push(runtime_dir.join("fakenotify.sock"));
+    }
+    candidates.push(PathBuf::from(DEFAULT_SOCKET_PATH));
+    candidates
+}
+
+/// Prefix that marks a socket setting as a TCP address instead of a path,
+/// e.g. `FAKENOTIFY_SOCKET=tcp://127.0.0.1:7878`.
+pub const TCP_SCHEME: &str = "tcp://";
+
+/// Prefix that marks a socket setting as an `AF_VSOCK` address,
+/// `vsock://cid:port`, e.g. `FAKENOTIFY_SOCKET=vsock://2:7878` for a VM guest
+/// reaching a daemon on its host.
+pub const VSOCK_SCHEME: &str = "vsock://";
+
+/// Where a client reaches the daemon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Unix domain socket at this path.
+    Unix(PathBuf),
+    /// TCP listener at this `host:port`, for a daemon on another OS (such as
+    /// a Windows host serving WSL).
+    Tcp(String),
+    /// `AF_VSOCK` listener, for a VM guest reaching a daemon on its host
+    /// (CID 2) without networking.
+    Vsock { cid: u32, port: u32 },
+}
+
+impl Endpoint {
+    /// Interpret a socket setting: `tcp://host:port`, `vsock://cid:port` or
+    /// a socket path.
+    ///
+    /// A malformed `vsock://` address is taken as a path, which then fails
+    /// to connect.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        let Some(setting) = path.to_str() else {
+            return Self::Unix(path.to_path_buf());
+        };
+        if let Some(addr) = setting.strip_prefix(TCP_SCHEME) {
+            return Self::Tcp(addr.to_string());
+        }
+        let vsock = setting
+            .strip_prefix(VSOCK_SCHEME)
+            .and_then(|addr| addr.split_once(':'))
+            .and_then(|(cid, port)| Some((cid.parse().ok()?, port.parse().ok()?)));
+        match vsock {
+            Some((cid, port)) => Self::Vsock { cid, port },
+            None => Self::Unix(path.to_path_buf()),
+        }
+    }
+}
+
+/// Connect a blocking `AF_VSOCK` stream socket to `cid:port`.
+///
+/// # Errors
+///
+/// Returns the error of creating the socket or connecting it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn connect_vsock(cid: u32, port: u32) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    // SAFETY: plain socket(2) call; the result is checked before use
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: fd is a freshly created socket nobody else owns
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_vm is plain data, valid when zeroed
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    // SAFETY: addr is a valid sockaddr_vm and the length matches it
+    let connected = unsafe {
+        libc::connect(
+            fd,
+            (&raw const addr).cast(),
+            std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if connected < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_endpoint_from_path() {
+        assert_eq!(
+            Endpoint::from_path(Path::new("/run/fakenotify/fakenotify.sock")),
+            Endpoint::Unix(PathBuf::from("/run/fakenotify/fakenotify.sock"))
+        );
+        assert_eq!(
+            Endpoint::from_path(Path::new("tcp://127.0.0.1:7878")),
+            Endpoint::Tcp("127.0.0.1:7878".to_string())
+        );
+        assert_eq!(
+            Endpoint::from_path(Path::new("vsock://2:7878")),
+            Endpoint::Vsock { cid: 2, port: 7878 }
+        );
+        assert_eq!(
+            Endpoint::from_path(Path::new("vsock://host")),
+            Endpoint::Unix(PathBuf::from("vsock://host"))
+        );
+    }
+
+    #[test]
+    fn test_socket_candidates() {
+        let runtime_dir =
+            std::env::temp_dir().join(format!("fakenotify-candidates-{}", std::process::id()));
+        let instances = runtime_dir.join(INSTANCE_DIR);
+        std::fs::create_dir_all(&instances).unwrap();
+        for name in ["tv.sock", "movies.sock", "notes.txt"] {
+            std::fs::write(instances.join(name), b"").unwrap();
+        }
+
+        assert_eq!(
+            socket_candidates(Some("/tmp/explicit.sock"), Some(&runtime_dir)),
+            vec![PathBuf::from("/tmp/explicit.sock")]
+        );
+        assert_eq!(
+            socket_candidates(None, Some(&runtime_dir)),
+            vec![
+                instances.join("movies.sock"),
+                instances.join("tv.sock"),
+                runtime_dir.join("fakenotify.sock"),
+                PathBuf::from(DEFAULT_SOCKET_PATH),
+            ]
+        );
+        assert_eq!(
+            socket_candidates(None, None),
+            vec![PathBuf::from(DEFAULT_SOCKET_PATH)]
+        );
+
+        std::fs::remove_dir_all(&runtime_dir).unwrap();
+    }
+
     // Note: In Rust 2024 edition, set_var and remove_var are unsafe because
     // they can cause data races in multi-threaded programs. For tests that
     // need to modify environment variables, we use unsafe blocks and run
@@ -54,6 +239,7 @@ mod tests {
     // functionality without actual env manipulation.
 
     #[test]
+    #[cfg(not(target_os = "macos"))]
     fn test_default_socket_path_constant() {
         // Test that the default path constant is correct
         assert_eq!(DEFAULT_SOCKET_PATH, "/run/fakenotify/fakenotify.sock");
@@ -0,0 +1,149 @@
+"""Python client for fakenotifyd.
+
+Blocking use::
+
+    from fakenotify import Watcher, IN_CREATE, IN_DELETE
+
+    watcher = Watcher()
+    watcher.add_watch("/mnt/media", IN_CREATE | IN_DELETE)
+    for event in watcher:
+        print(event.wd, hex(event.mask), event.name)
+
+asyncio use::
+
+    from fakenotify import AsyncWatcher
+
+    async with AsyncWatcher() as watcher:
+        await watcher.add_watch("/mnt/media")
+        async for event in watcher:
+            print(event)
+"""
+
+from __future__ import annotations
+
+import asyncio
+import os
+from concurrent.futures import ThreadPoolExecutor
+from typing import AsyncIterator, Optional, Union
+
+from . import _native
+from ._native import Event, FakeNotifyError, Watcher
+
+__all__ = ["AsyncWatcher", "Event", "FakeNotifyError", "Watcher"]
+
+# Re-export the IN_* mask constants at package level
+for _name in dir(_native):
+    if _name.startswith("IN_"):
+        globals()[_name] = getattr(_native, _name)
+        __all__.append(_name)
+del _name
+
+
+class AsyncWatcher:
+    """asyncio wrapper around :class:`Watcher`.
+
+    Requests run on a dedicated worker thread so the event loop is never
+    stalled. Events are read on the event loop itself, and only once the
+    socket is readable, so cancelling a read (``asyncio.wait_for``, a task
+    group timeout, cancelling ``async for``) takes effect at once and never
+    loses an event. While a request holds the connection, reads wait for it
+    to finish instead of blocking the loop.
+    """
+
+    def __init__(self, socket: Optional[Union[str, os.PathLike]] = None) -> None:
+        self._socket = socket
+        self._watcher: Optional[Watcher] = None
+        self._executor = ThreadPoolExecutor(max_workers=1, thread_name_prefix="fakenotify")
+        self._closed = False
+        self._readable: Optional[asyncio.Future] = None
+        self._requesting: Optional[asyncio.Future] = None
+
+    async def _run(self, func, *args):
+        loop = asyncio.get_running_loop()
+        try:
+            return await loop.run_in_executor(self._executor, func, *args)
+        finally:
+            self._request_done()
+
+    async def connect(self) -> "AsyncWatcher":
+        if self._watcher is None:
+            self._watcher = await self._run(Watcher, self._socket)
+        return self
+
+    def _require(self) -> Watcher:
+        if self._watcher is None:
+            raise RuntimeError("AsyncWatcher is not connected; use 'async with' or await connect()")
+        return self._watcher
+
+    async def add_watch(self, path: Union[str, os.PathLike], mask: Optional[int] = None) -> int:
+        watcher = self._require()
+        if mask is None:
+            return await self._run(watcher.add_watch, os.fspath(path))
+        return await self._run(watcher.add_watch, os.fspath(path), mask)
+
+    async def remove_watch(self, wd: int) -> None:
+        await self._run(self._require().remove_watch, wd)
+
+    async def read_event(self) -> Event:
+        watcher = self._require()
+        while not self._closed:
+            # Events that came in with an answer leave the socket unreadable,
+            # so take what has arrived before waiting
+            try:
+                event = watcher.try_read_event()
+            except BlockingIOError:
+                # A request holds the connection; events it reads along the
+                # way are ours once it is done
+                await self._wait_request()
+                continue
+            if event is not None:
+                return event
+            await self._wait_readable(watcher.fileno())
+        raise StopAsyncIteration
+
+    async def _wait_readable(self, fd: int) -> None:
+        # Concurrent readers share one wait, which outlives any of them
+        # being cancelled
+        if self._readable is None:
+            loop = asyncio.get_running_loop()
+            self._readable = loop.create_future()
+            loop.add_reader(fd, self._wake, fd)
+        await asyncio.shield(self._readable)
+
+    async def _wait_request(self) -> None:
+        if self._requesting is None:
+            self._requesting = asyncio.get_running_loop().create_future()
+        await asyncio.shield(self._requesting)
+
+    def _request_done(self) -> None:
+        requesting, self._requesting = self._requesting, None
+        if requesting is not None and not requesting.done():
+            requesting.set_result(None)
+        # The request may have read events off the socket before the loop
+        # saw it readable
+        if self._readable is not None and self._watcher is not None:
+            self._wake(self._watcher.fileno())
+
+    def _wake(self, fd: int) -> None:
+        asyncio.get_running_loop().remove_reader(fd)
+        readable, self._readable = self._readable, None
+        if readable is not None and not readable.done():
+            readable.set_result(None)
+
+    def __aiter__(self) -> AsyncIterator[Event]:
+        return self
+
+    async def __anext__(self) -> Event:
+        return await self.read_event()
+
+    async def close(self) -> None:
+        self._closed = True
+        self._request_done()
+        self._watcher = None
+        self._executor.shutdown(wait=False)
+
+    async def __aenter__(self) -> "AsyncWatcher":
+        return await self.connect()
+
+    async def __aexit__(self, *exc) -> None:
+        await self.close()
@@ -0,0 +1,438 @@
+//! FakeNotify Python bindings
+//!
+//! Native half of the `fakenotify` Python package. It wraps
+//! [`fakenotify_client::Client`] in a blocking `Watcher` class; the asyncio
+//! API lives in `python/fakenotify/__init__.py` on top of it.
+//!
+//! Blocking calls release the GIL so other Python threads keep running.
+
+use bitflags::Flags;
+use fakenotify_client::{Client, ClientError, Event as ClientEvent};
+use fakenotify_protocol::EventMask;
+use parking_lot::Mutex;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyBlockingIOError, PyException, PyOSError, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+create_exception!(
+    _native,
+    FakeNotifyError,
+    PyException,
+    "The daemon rejected a request or sent a malformed message."
+);
+
+fn to_py_err(err: ClientError) -> PyErr {
+    match err {
+        ClientError::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            PyTimeoutError::new_err("timed out waiting for the daemon")
+        }
+        ClientError::Io(e) => match e.raw_os_error() {
+            Some(errno) => PyOSError::new_err((errno, e.to_string())),
+            None => PyOSError::new_err(e.to_string()),
+        },
+        other => FakeNotifyError::new_err(other.to_string()),
+    }
+}
+
+/// A single filesystem event.
+#[pyclass(frozen, module = "fakenotify")]
+#[derive(Debug)]
+pub struct Event {
+    /// Watch descriptor the event belongs to.
+    #[pyo3(get)]
+    wd: i32,
+    /// Raw `IN_*` event mask.
+    #[pyo3(get)]
+    mask: u32,
+    /// Cookie pairing rename halves (0 if unused).
+    #[pyo3(get)]
+    cookie: u32,
+    /// Name relative to the watched path, if any.
+    #[pyo3(get)]
+    name: Option<OsString>,
+}
+
+#[pymethods]
+impl Event {
+    /// Whether the subject of the event is a directory.
+    #[getter]
+    fn is_dir(&self) -> bool {
+        EventMask::from_bits_retain(self.mask).contains(EventMask::IN_ISDIR)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Event(wd={}, mask={:#x}, cookie={}, name={:?})",
+            self.wd, self.mask, self.cookie, self.name
+        )
+    }
+}
+
+impl From<ClientEvent> for Event {
+    fn from(event: ClientEvent) -> Self {
+        Self {
+            wd: event.wd,
+            mask: event.mask.bits(),
+            cookie: event.cookie,
+            name: event.name,
+        }
+    }
+}
+
+/// Blocking connection to fakenotifyd.
+///
+/// Iterating a `Watcher` yields events forever. A pending read holds the
+/// connection, so calls from other threads wait until an event arrives.
+#[pyclass(frozen, module = "fakenotify")]
+pub struct Watcher {
+    client: Mutex<Client>,
+    /// The connection's socket, readable without waiting for the lock
+    fd: i32,
+}
+
+#[pymethods]
+impl Watcher {
+    /// Connect to the daemon. `socket` defaults to the standard resolution
+    /// (`FAKENOTIFY_SOCKET`, `$XDG_RUNTIME_DIR`, `/run/fakenotify`).
+    #[new]
+    #[pyo3(signature = (socket = None))]
+    fn new(py: Python<'_>, socket: Option<PathBuf>) -> PyResult<Self> {
+        let client = py
+            .allow_threads(|| match socket {
+                Some(path) => Client::connect_to(path),
+                None => Client::connect(),
+            })
+            .map_err(to_py_err)?;
+        Ok(Self {
+            fd: client.as_raw_fd(),
+            client: Mutex::new(client),
+        })
+    }
+
+    /// The client ID assigned by the daemon.
+    #[getter]
+    fn client_id(&self) -> u64 {
+        self.client.lock().client_id()
+    }
+
+    /// The underlying socket fd.
+    fn fileno(&self) -> i32 {
+        self.fd
+    }
+
+    /// Watch `path` for the events in `mask` and return the watch descriptor.
+    #[pyo3(signature = (path, mask = EventMask::IN_ALL_EVENTS.bits()))]
+    fn add_watch(&self, py: Python<'_>, path: PathBuf, mask: u32) -> PyResult<i32> {
+        py.allow_threads(|| {
+            self.client
+                .lock()
+                .add_watch(&path, EventMask::from_bits_retain(mask))
+        })
+        .map_err(to_py_err)
+    }
+
+    /// Stop watching the given watch descriptor.
+    fn remove_watch(&self, py: Python<'_>, wd: i32) -> PyResult<()> {
+        py.allow_threads(|| self.client.lock().remove_watch(wd))
+            .map_err(to_py_err)
+    }
+
+    /// Block until the next event. Raises `TimeoutError` if `timeout`
+    /// seconds pass first; a timeout of 0 only takes an event that has
+    /// already arrived.
+    #[pyo3(signature = (timeout = None))]
+    fn read_event(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Event> {
+        let timeout = timeout
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs)
+                    .map_err(|_| PyValueError::new_err(format!("invalid timeout: {secs}")))
+            })
+            .transpose()?;
+        py.allow_threads(|| self.client.lock().read_event_timeout(timeout))
+            .map_err(to_py_err)?
+            .map(Event::from)
+            .ok_or_else(|| PyTimeoutError::new_err("timed out waiting for the daemon"))
+    }
+
+    /// Take an event that has already arrived, or return `None`, without
+    /// waiting on the daemon or on other threads. Raises `BlockingIOError`
+    /// while a call from another thread holds the connection.
+    fn try_read_event(&self) -> PyResult<Option<Event>> {
+        let Some(mut client) = self.client.try_lock() else {
+            return Err(PyBlockingIOError::new_err(
+                "connection in use by another call",
+            ));
+        };
+        client
+            .read_event_timeout(Some(Duration::ZERO))
+            .map(|event| event.map(Event::from))
+            .map_err(to_py_err)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Event> {
+        self.read_event(py, None)
+    }
+}
+
+#[pymodule]
+fn _native(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Event>()?;
+    m.add_class::<Watcher>()?;
+    m.add("FakeNotifyError", m.py().get_type::<FakeNotifyError>())?;
+
+    for flag in EventMask::FLAGS {
+        m.add(flag.name(), flag.value().bits())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fakenotify_protocol::{FramedMessage, InotifyEvent, Request, Response};
+    use pyo3::types::PyDict;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::{self, Sender};
+    use std::thread::{self, JoinHandle};
+
+    fn send(stream: &mut impl Write, payload: &[u8]) {
+        stream.write_all(&FramedMessage::frame(payload)).unwrap();
+    }
+
+    fn send_event(stream: &mut impl Write, event: &[u8]) {
+        stream
+            .write_all(&FramedMessage::frame_event(event))
+            .unwrap();
+    }
+
+    fn recv_request(stream: &mut impl Read) -> Option<Request> {
+        let mut len_buf = [0u8; 4];
+        stream.read_exact(&mut len_buf).ok()?;
+        let mut payload = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        stream.read_exact(&mut payload).ok()?;
+        Request::from_bytes(&payload).ok()
+    }
+
+    /// A daemon that answers one `AddWatch` with wd 1 and sends a create
+    /// event for `a.mkv` `delay` later, holding the connection open until
+    /// the returned sender is dropped
+    fn fake_daemon(name: &str, delay: Duration) -> (PathBuf, Sender<()>, JoinHandle<()>) {
+        let path = std::env::temp_dir().join(format!(
+            "fakenotify-python-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (done, finished) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let registered = Response::ClientRegistered {
+                client_id: 9,
+                session: String::new(),
+                capabilities: 0,
+            };
+            send(&mut stream, &registered.to_bytes().unwrap());
+            assert!(matches!(
+                recv_request(&mut stream),
+                Some(Request::AddWatch { .. })
+            ));
+            send(
+                &mut stream,
+                &Response::WatchAdded { wd: 1 }.to_bytes().unwrap(),
+            );
+            thread::sleep(delay);
+            send_event(
+                &mut stream,
+                &InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a.mkv"),
+            );
+            let _ = finished.recv();
+        });
+        (path, done, server)
+    }
+
+    /// The `fakenotify` package, with this build as its native half
+    fn import_package(py: Python<'_>) -> PyResult<()> {
+        let modules = py.import("sys")?.getattr("modules")?;
+        modules.set_item("fakenotify._native", pyo3::wrap_pymodule!(_native)(py))?;
+        let globals = PyDict::new(py);
+        globals.set_item(
+            "init",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/python/fakenotify/__init__.py"),
+        )?;
+        py.run(
+            cr#"
+import importlib.util, os, sys
+spec = importlib.util.spec_from_file_location(
+    "fakenotify", init, submodule_search_locations=[os.path.dirname(init)]
+)
+package = importlib.util.module_from_spec(spec)
+sys.modules["fakenotify"] = package
+spec.loader.exec_module(package)
+"#,
+            Some(&globals),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_watcher_reads_with_timeouts() {
+        let (path, done, server) = fake_daemon("blocking", Duration::from_millis(200));
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let watcher = Watcher::new(py, Some(path.clone())).unwrap();
+            assert_eq!(watcher.client_id(), 9);
+            let wd = watcher
+                .add_watch(py, "/mnt/media".into(), EventMask::IN_CREATE.bits())
+                .unwrap();
+            assert_eq!(wd, 1);
+
+            let err = watcher.read_event(py, Some(0.0)).unwrap_err();
+            assert!(err.is_instance_of::<PyTimeoutError>(py));
+            let err = watcher.read_event(py, Some(-1.0)).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+
+            let event = watcher.read_event(py, Some(5.0)).unwrap();
+            assert_eq!((event.wd, event.mask), (1, EventMask::IN_CREATE.bits()));
+            assert_eq!(event.name, Some(OsString::from("a.mkv")));
+        });
+        drop(done);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_cancelled_async_read_loses_no_event() {
+        let (path, done, server) = fake_daemon("async", Duration::from_millis(300));
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            import_package(py).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("socket", &path).unwrap();
+            py.run(
+                cr#"
+import asyncio
+from fakenotify import AsyncWatcher
+
+async def main():
+    async with AsyncWatcher(socket) as watcher:
+        assert await watcher.add_watch("/mnt/media") == 1
+        # Cancelled while the event is still on its way
+        try:
+            await asyncio.wait_for(watcher.read_event(), 0.05)
+        except asyncio.TimeoutError:
+            pass
+        else:
+            raise AssertionError("read did not time out")
+        event = await asyncio.wait_for(watcher.read_event(), 5)
+        assert event.name == "a.mkv", event
+
+        # Closing ends a read waiting for the next event
+        reader = asyncio.ensure_future(watcher.read_event())
+        await asyncio.sleep(0.05)
+        await watcher.close()
+        try:
+            await asyncio.wait_for(reader, 5)
+        except StopAsyncIteration:
+            pass
+        else:
+            raise AssertionError("read outlived close")
+
+asyncio.run(main())
+"#,
+                Some(&globals),
+                None,
+            )
+            .unwrap_or_else(|e| panic!("{e}"));
+        });
+        drop(done);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_async_read_waits_out_a_request() {
+        let path = std::env::temp_dir().join(format!(
+            "fakenotify-python-overlap-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (done, finished) = mpsc::channel::<()>();
+        // Sends an event while the answer to `AddWatch` is held back
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let registered = Response::ClientRegistered {
+                client_id: 9,
+                session: String::new(),
+                capabilities: 0,
+            };
+            send(&mut stream, &registered.to_bytes().unwrap());
+            assert!(matches!(
+                recv_request(&mut stream),
+                Some(Request::AddWatch { .. })
+            ));
+            send_event(
+                &mut stream,
+                &InotifyEvent::new(1, EventMask::IN_CREATE.bits(), 0).to_bytes_with_name(b"a.mkv"),
+            );
+            thread::sleep(Duration::from_millis(500));
+            send(
+                &mut stream,
+                &Response::WatchAdded { wd: 1 }.to_bytes().unwrap(),
+            );
+            let _ = finished.recv();
+        });
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            import_package(py).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("socket", &path).unwrap();
+            py.run(
+                cr#"
+import asyncio, time
+from fakenotify import AsyncWatcher
+
+async def main():
+    async with AsyncWatcher(socket) as watcher:
+        reader = asyncio.ensure_future(watcher.read_event())
+        await asyncio.sleep(0.05)
+        adding = asyncio.ensure_future(watcher.add_watch("/mnt/media"))
+
+        # The loop keeps turning while the request holds the connection
+        longest, last = 0.0, time.monotonic()
+        while not adding.done():
+            await asyncio.sleep(0.01)
+            now = time.monotonic()
+            longest, last = max(longest, now - last), now
+        assert longest < 0.25, longest
+        assert await adding == 1
+
+        # The event the request read on its way is handed over after it
+        event = await asyncio.wait_for(reader, 5)
+        assert event.name == "a.mkv", event
+
+asyncio.run(main())
+"#,
+                Some(&globals),
+                None,
+            )
+            .unwrap_or_else(|e| panic!("{e}"));
+        });
+        drop(done);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
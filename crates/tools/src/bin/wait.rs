@@ -0,0 +1,236 @@
+//! `fakenotify-wait`: `inotifywait`, with its watches served by the daemon.
+
+use clap::{ArgAction, Parser};
+use fakenotify_protocol::EventMask;
+use fakenotify_tools::format::{self, Format};
+use fakenotify_tools::{
+    EXIT_FAILURE, EXIT_OK, EXIT_TIMEOUT, PathFilter, Received, Session, Targets, WatchOptions,
+};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+/// Wait for changes to files, like inotifywait, through the fakenotify
+/// daemon (FAKENOTIFY_SOCKET)
+#[derive(Debug, Parser)]
+#[command(name = "fakenotify-wait", version, about)]
+struct Args {
+    /// Keep listening instead of exiting after the first event
+    #[arg(short, long)]
+    monitor: bool,
+
+    /// Watch every directory below the given ones
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Print less; twice, print nothing but errors
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// Append events to this file instead of printing them
+    #[arg(short, long, value_name = "FILE")]
+    outfile: Option<PathBuf>,
+
+    /// Listen for this event only; repeatable or comma-separated
+    #[arg(short, long = "event", value_name = "EVENT")]
+    events: Vec<EventMask>,
+
+    /// Exit with status 2 after this many seconds without an event
+    /// (0: never)
+    #[arg(short, long, default_value = "0")]
+    timeout: f64,
+
+    /// Print events as CSV
+    #[arg(short, long)]
+    csv: bool,
+
+    /// Print events in this format: %w watched path, %f name, %e events,
+    /// %Xe events separated by X, %T time (see --timefmt), %c cookie
+    #[arg(long, value_name = "FMT")]
+    format: Option<String>,
+
+    /// strftime format of %T
+    #[arg(long, value_name = "FMT")]
+    timefmt: Option<String>,
+
+    /// Don't end events printed with --format with a newline
+    #[arg(long)]
+    no_newline: bool,
+
+    /// Don't follow symlinks
+    #[arg(short = 'P', long)]
+    no_dereference: bool,
+
+    /// Leave out paths matching this regular expression
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Option<String>,
+
+    /// Like --exclude, ignoring case
+    #[arg(long, value_name = "PATTERN")]
+    excludei: Option<String>,
+
+    /// Only report paths matching this regular expression
+    #[arg(long, value_name = "PATTERN")]
+    include: Option<String>,
+
+    /// Like --include, ignoring case
+    #[arg(long, value_name = "PATTERN")]
+    includei: Option<String>,
+
+    /// Read the paths to watch from this file, one per line (`-` for stdin)
+    #[arg(long, value_name = "FILE")]
+    fromfile: Option<PathBuf>,
+
+    /// Paths to watch; `@path` leaves a path out of recursive watches
+    files: Vec<String>,
+}
+
+fn main() {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            // Usage errors are status 1, as with inotifywait
+            std::process::exit(if e.use_stderr() {
+                EXIT_FAILURE
+            } else {
+                EXIT_OK
+            });
+        }
+    };
+    std::process::exit(run(args));
+}
+
+fn run(args: Args) -> i32 {
+    let mut targets = Targets::new(&args.files);
+    if let Some(file) = &args.fromfile
+        && let Err(e) = targets.read_from(file)
+    {
+        eprintln!("Couldn't read {}: {e}", file.display());
+        return EXIT_FAILURE;
+    }
+    if targets.paths.is_empty() {
+        eprintln!("No files specified to watch!");
+        return EXIT_FAILURE;
+    }
+    let filter = match PathFilter::new(
+        args.exclude.as_deref(),
+        args.excludei.as_deref(),
+        args.include.as_deref(),
+        args.includei.as_deref(),
+    ) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    };
+    let asked = match args
+        .events
+        .iter()
+        .fold(EventMask::empty(), |mask, m| mask | *m)
+    {
+        mask if mask.is_empty() => EventMask::IN_ALL_EVENTS,
+        mask => mask,
+    };
+    let mut mask = asked;
+    if args.no_dereference {
+        mask |= EventMask::IN_DONT_FOLLOW;
+    }
+
+    let options = WatchOptions {
+        mask,
+        recursive: args.recursive,
+        excluded: targets.excluded,
+        filter,
+    };
+    let mut session = match Session::connect("fakenotify-wait", options) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Couldn't reach the fakenotify daemon: {e}");
+            return EXIT_FAILURE;
+        }
+    };
+    if args.quiet == 0 {
+        if args.recursive {
+            eprintln!("Setting up watches.  Beware: since -r was given, this may take a while!");
+        } else {
+            eprintln!("Setting up watches.");
+        }
+    }
+    for path in &targets.paths {
+        if let Err(e) = session.watch(path) {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    }
+    if args.quiet == 0 {
+        eprintln!("Watches established.");
+    }
+
+    let mut out: Box<dyn Write> = match &args.outfile {
+        Some(file) => match OpenOptions::new().create(true).append(true).open(file) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Couldn't open {}: {e}", file.display());
+                return EXIT_FAILURE;
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+    let format = args.format.as_deref().map(Format::parse);
+    let timeout = (args.timeout > 0.0).then(|| Duration::from_secs_f64(args.timeout));
+
+    let events = session.spawn();
+    loop {
+        let received = match timeout {
+            Some(timeout) => match events.recv_timeout(timeout) {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => return EXIT_TIMEOUT,
+                Err(RecvTimeoutError::Disconnected) => return EXIT_FAILURE,
+            },
+            None => match events.recv() {
+                Ok(received) => received,
+                Err(_) => return EXIT_FAILURE,
+            },
+        };
+        let event = match received {
+            Received::Event(event) => event,
+            // Every watch is gone
+            Received::Closed(None) => return EXIT_OK,
+            Received::Closed(Some(e)) => {
+                eprintln!("Lost the fakenotify daemon: {e}");
+                return EXIT_FAILURE;
+            }
+        };
+
+        if args.quiet < 2 {
+            let line = match &format {
+                Some(format) => format.render(&event, args.timefmt.as_deref()),
+                None if args.csv => format::csv(&event),
+                None => Format::standard().render(&event, None),
+            };
+            let end = if format.is_some() && args.no_newline {
+                ""
+            } else {
+                "\n"
+            };
+            if write!(out, "{line}{end}")
+                .and_then(|()| out.flush())
+                .is_err()
+            {
+                return EXIT_FAILURE;
+            }
+        }
+        if !args.monitor {
+            // An event not asked for, such as the watch going away
+            return if event.mask.intersects(asked) {
+                EXIT_OK
+            } else {
+                EXIT_FAILURE
+            };
+        }
+    }
+}
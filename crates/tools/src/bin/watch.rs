@@ -0,0 +1,193 @@
+//! `fakenotify-watch`: `inotifywatch`, with its watches served by the
+//! daemon.
+
+use clap::Parser;
+use fakenotify_protocol::EventMask;
+use fakenotify_tools::stats::{Sort, Stats};
+use fakenotify_tools::{
+    EXIT_FAILURE, EXIT_OK, PathFilter, Received, Session, Targets, WatchOptions, catch_interrupts,
+    interrupted,
+};
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+/// How often to look for SIGINT while no events arrive
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// Count changes to files, like inotifywatch, through the fakenotify daemon
+/// (FAKENOTIFY_SOCKET)
+#[derive(Debug, Parser)]
+#[command(name = "fakenotify-watch", version, about)]
+struct Args {
+    /// Listen for this event only; repeatable or comma-separated
+    #[arg(short, long = "event", value_name = "EVENT")]
+    events: Vec<EventMask>,
+
+    /// Sort ascending by this event's count, or `total`
+    #[arg(short, long, value_name = "EVENT")]
+    ascending: Option<String>,
+
+    /// Sort descending by this event's count, or `total`
+    #[arg(short, long, value_name = "EVENT")]
+    descending: Option<String>,
+
+    /// Listen for this many seconds (0: until interrupted)
+    #[arg(short, long, default_value = "0")]
+    timeout: f64,
+
+    /// Watch every directory below the given ones
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Also list rows and columns without events
+    #[arg(short, long)]
+    zero: bool,
+
+    /// Don't follow symlinks
+    #[arg(short = 'P', long)]
+    no_dereference: bool,
+
+    /// Leave out paths matching this regular expression
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Option<String>,
+
+    /// Like --exclude, ignoring case
+    #[arg(long, value_name = "PATTERN")]
+    excludei: Option<String>,
+
+    /// Only count paths matching this regular expression
+    #[arg(long, value_name = "PATTERN")]
+    include: Option<String>,
+
+    /// Like --include, ignoring case
+    #[arg(long, value_name = "PATTERN")]
+    includei: Option<String>,
+
+    /// Read the paths to watch from this file, one per line (`-` for stdin)
+    #[arg(long, value_name = "FILE")]
+    fromfile: Option<PathBuf>,
+
+    /// Paths to watch; `@path` leaves a path out of recursive watches
+    files: Vec<String>,
+}
+
+fn main() {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(if e.use_stderr() {
+                EXIT_FAILURE
+            } else {
+                EXIT_OK
+            });
+        }
+    };
+    std::process::exit(run(args));
+}
+
+fn run(args: Args) -> i32 {
+    let mut targets = Targets::new(&args.files);
+    if let Some(file) = &args.fromfile
+        && let Err(e) = targets.read_from(file)
+    {
+        eprintln!("Couldn't read {}: {e}", file.display());
+        return EXIT_FAILURE;
+    }
+    if targets.paths.is_empty() {
+        eprintln!("No files specified to watch!");
+        return EXIT_FAILURE;
+    }
+    let sort = match (&args.ascending, &args.descending) {
+        (Some(event), _) => Sort::by(event, true).ok_or(event),
+        (None, Some(event)) => Sort::by(event, false).ok_or(event),
+        (None, None) => Ok(Sort::default()),
+    };
+    let sort = match sort {
+        Ok(sort) => sort,
+        Err(event) => {
+            eprintln!("'{event}' is not a valid event!");
+            return EXIT_FAILURE;
+        }
+    };
+    let filter = match PathFilter::new(
+        args.exclude.as_deref(),
+        args.excludei.as_deref(),
+        args.include.as_deref(),
+        args.includei.as_deref(),
+    ) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    };
+    let asked = match args
+        .events
+        .iter()
+        .fold(EventMask::empty(), |mask, m| mask | *m)
+    {
+        mask if mask.is_empty() => EventMask::IN_ALL_EVENTS,
+        mask => mask,
+    };
+    let mut mask = asked;
+    if args.no_dereference {
+        mask |= EventMask::IN_DONT_FOLLOW;
+    }
+
+    let options = WatchOptions {
+        mask,
+        recursive: args.recursive,
+        excluded: targets.excluded,
+        filter,
+    };
+    let mut session = match Session::connect("fakenotify-watch", options) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Couldn't reach the fakenotify daemon: {e}");
+            return EXIT_FAILURE;
+        }
+    };
+    catch_interrupts();
+    eprintln!("Establishing watches...");
+    for path in &targets.paths {
+        if let Err(e) = session.watch(path) {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    }
+    eprintln!("Finished establishing watches, now collecting statistics.");
+    let deadline = (args.timeout > 0.0).then(|| {
+        eprintln!("Will listen for events for {} seconds.", args.timeout);
+        Instant::now() + Duration::from_secs_f64(args.timeout)
+    });
+
+    let mut stats = Stats::new(session.watched());
+    let events = session.spawn();
+    while !interrupted() {
+        let wait = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left.min(INTERRUPT_POLL),
+                None => break,
+            },
+            None => INTERRUPT_POLL,
+        };
+        match events.recv_timeout(wait) {
+            Ok(Received::Event(event)) => stats.record(&event),
+            Ok(Received::Closed(None)) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(Received::Closed(Some(e))) => {
+                eprintln!("Lost the fakenotify daemon: {e}");
+                return EXIT_FAILURE;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+
+    if stats.is_empty() && !args.zero {
+        println!("No events occurred.");
+    } else {
+        print!("{}", stats.table(asked, sort, args.zero));
+    }
+    EXIT_OK
+}
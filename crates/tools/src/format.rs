@@ -0,0 +1,229 @@
+//! Printing events the way `inotifywait` does.
+//!
+//! By default an event is printed as `%w %e %f`: the watched path, the
+//! events separated by commas, and the name. `--csv` prints the same fields
+//! as a CSV row, and `--format` takes a format of its own, with `%T` as the
+//! time formatted by `--timefmt`.
+
+use crate::NamedEvent;
+use fakenotify_protocol::EventMask;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Event names in the order inotify-tools print them.
+const NAMES: &[(EventMask, &str)] = &[
+    (EventMask::IN_ACCESS, "ACCESS"),
+    (EventMask::IN_MODIFY, "MODIFY"),
+    (EventMask::IN_ATTRIB, "ATTRIB"),
+    (EventMask::IN_CLOSE_WRITE, "CLOSE_WRITE"),
+    (EventMask::IN_CLOSE_NOWRITE, "CLOSE_NOWRITE"),
+    (EventMask::IN_OPEN, "OPEN"),
+    (EventMask::IN_MOVED_FROM, "MOVED_FROM"),
+    (EventMask::IN_MOVED_TO, "MOVED_TO"),
+    (EventMask::IN_CREATE, "CREATE"),
+    (EventMask::IN_DELETE, "DELETE"),
+    (EventMask::IN_DELETE_SELF, "DELETE_SELF"),
+    (EventMask::IN_UNMOUNT, "UNMOUNT"),
+    (EventMask::IN_Q_OVERFLOW, "Q_OVERFLOW"),
+    (EventMask::IN_IGNORED, "IGNORED"),
+    (EventMask::IN_CLOSE, "CLOSE"),
+    (EventMask::IN_MOVE_SELF, "MOVE_SELF"),
+    (EventMask::IN_ISDIR, "ISDIR"),
+    (EventMask::IN_ONESHOT, "ONESHOT"),
+];
+
+/// The events in `mask`, e.g. `CLOSE_WRITE,CLOSE`, joined by `sep`.
+pub fn event_names(mask: EventMask, sep: &str) -> String {
+    NAMES
+        .iter()
+        .filter(|(flag, _)| mask.intersects(*flag))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+/// A `--format` string, parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Format(Vec<Part>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// `%w`
+    Watched,
+    /// `%f`
+    Name,
+    /// `%e`, or `%Xe` with separator `X`
+    Events(String),
+    /// `%T`
+    Time,
+    /// `%c`
+    Cookie,
+}
+
+impl Format {
+    /// The format `inotifywait` uses without `--format`.
+    pub fn standard() -> Self {
+        Self::parse("%w %e %f")
+    }
+
+    /// Parse `format`; unknown conversions are printed as they are.
+    pub fn parse(format: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            let part = match chars.next() {
+                Some('w') => Part::Watched,
+                Some('f') => Part::Name,
+                Some('e') => Part::Events(",".to_string()),
+                Some('T') => Part::Time,
+                Some('c') => Part::Cookie,
+                Some('%') => {
+                    text.push('%');
+                    continue;
+                }
+                Some(sep) if chars.peek() == Some(&'e') => {
+                    chars.next();
+                    Part::Events(sep.to_string())
+                }
+                Some(other) => {
+                    text.push('%');
+                    text.push(other);
+                    continue;
+                }
+                None => {
+                    text.push('%');
+                    continue;
+                }
+            };
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(part);
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Self(parts)
+    }
+
+    /// `event` in this format, `%T` formatted with `timefmt`.
+    pub fn render(&self, event: &NamedEvent, timefmt: Option<&str>) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Watched => out.push_str(&event.watched),
+                Part::Name => out.push_str(&event.name),
+                Part::Events(sep) => out.push_str(&event_names(event.mask, sep)),
+                Part::Time => {
+                    if let Some(timefmt) = timefmt {
+                        out.push_str(&strftime(timefmt, event.time));
+                    }
+                }
+                Part::Cookie => out.push_str(&event.cookie.to_string()),
+            }
+        }
+        out
+    }
+}
+
+/// `event` as a `--csv` row.
+pub fn csv(event: &NamedEvent) -> String {
+    [
+        csv_field(&event.watched),
+        csv_field(&event_names(event.mask, ",")),
+        csv_field(&event.name),
+    ]
+    .join(",")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// `time` in local time, formatted with strftime's `format`.
+pub fn strftime(format: &str, time: SystemTime) -> String {
+    let Ok(format) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as libc::time_t;
+    let mut buf = [0u8; 256];
+    // SAFETY: `tm` is fully written by localtime_r before use, and strftime
+    // writes at most `buf.len()` bytes, returning how many
+    let len = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return String::new();
+        }
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(watched: &str, name: &str, mask: EventMask) -> NamedEvent {
+        NamedEvent {
+            watched: watched.to_string(),
+            name: name.to_string(),
+            mask,
+            cookie: 7,
+            time: UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_event_names() {
+        assert_eq!(
+            event_names(EventMask::IN_CLOSE_WRITE, ","),
+            "CLOSE_WRITE,CLOSE"
+        );
+        assert_eq!(
+            event_names(EventMask::IN_CREATE | EventMask::IN_ISDIR, ":"),
+            "CREATE:ISDIR"
+        );
+    }
+
+    #[test]
+    fn test_formats_like_inotifywait() {
+        let create = event("/mnt/drop/", "a b.csv", EventMask::IN_CREATE);
+        assert_eq!(
+            Format::standard().render(&create, None),
+            "/mnt/drop/ CREATE a b.csv"
+        );
+        assert_eq!(
+            Format::parse("%w%f|%:e|%c|100%%|%T").render(&create, None),
+            "/mnt/drop/a b.csv|CREATE|7|100%|"
+        );
+
+        let closed = event("/mnt/drop/", "x,\"y\"", EventMask::IN_CLOSE_WRITE);
+        assert_eq!(
+            csv(&closed),
+            "/mnt/drop/,\"CLOSE_WRITE,CLOSE\",\"x,\"\"y\"\"\""
+        );
+    }
+
+    #[test]
+    fn test_strftime() {
+        assert_eq!(
+            strftime(
+                "%Y",
+                UNIX_EPOCH + std::time::Duration::from_secs(86400 * 400)
+            ),
+            "1971"
+        );
+    }
+}
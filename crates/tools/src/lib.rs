@@ -0,0 +1,453 @@
+//! FakeNotify Tools - `inotifywait` and `inotifywatch` work-alikes.
+//!
+//! `fakenotify-wait` and `fakenotify-watch` take the common inotify-tools
+//! flags and print the same output, but their watches are served by the
+//! daemon, so scripts written for `inotifywait` keep working on NFS mounts
+//! where the kernel never reports remote changes. Only the binary name
+//! changes:
+//!
+//! ```sh
+//! fakenotify-wait -m -r -e close_write --format '%w%f' /mnt/drop |
+//!     while read -r file; do ./import.sh "$file"; done
+//! ```
+//!
+//! This library holds what both share: choosing the paths to watch
+//! ([`Targets`], [`PathFilter`]), watching them through the daemon
+//! ([`Session`]), and printing events the way inotify-tools do
+//! ([`format`], [`stats`]).
+
+pub mod format;
+pub mod stats;
+
+use fakenotify_client::{Client, ClientError};
+use fakenotify_protocol::{ClientInfo, EventMask};
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// Exit status after an event, or a normal end.
+pub const EXIT_OK: i32 = 0;
+
+/// Exit status after an error, or an event that was not asked for, such as
+/// the watched path being deleted.
+pub const EXIT_FAILURE: i32 = 1;
+
+/// Exit status when the timeout passed without an event.
+pub const EXIT_TIMEOUT: i32 = 2;
+
+/// Error type for the tools.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The daemon refused a watch, or the connection failed.
+    #[error("{0}")]
+    Client(#[from] ClientError),
+
+    /// Reading a path list failed.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    /// An `--exclude` or `--include` pattern does not compile.
+    #[error("invalid regular expression: {0}")]
+    Regex(#[from] regex::Error),
+
+    /// The daemon could not watch a path.
+    #[error("Couldn't watch {path}: {source}")]
+    Watch {
+        path: String,
+        #[source]
+        source: ClientError,
+    },
+}
+
+/// The paths given on the command line or with `--fromfile`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Targets {
+    /// Paths to watch.
+    pub paths: Vec<String>,
+    /// Paths given as `@path`, left out of recursive watches.
+    pub excluded: Vec<PathBuf>,
+}
+
+impl Targets {
+    /// Sort `args` into paths and `@`-prefixed exclusions.
+    pub fn new<S: AsRef<str>>(args: &[S]) -> Self {
+        let mut targets = Self::default();
+        for arg in args {
+            targets.push(arg.as_ref());
+        }
+        targets
+    }
+
+    /// Add the paths listed in `file`, one per line; `-` reads stdin.
+    pub fn read_from(&mut self, file: &Path) -> io::Result<()> {
+        let reader: Box<dyn BufRead> = if file == Path::new("-") {
+            Box::new(BufReader::new(io::stdin()))
+        } else {
+            Box::new(BufReader::new(std::fs::File::open(file)?))
+        };
+        for line in reader.lines() {
+            let line = line?;
+            if !line.is_empty() {
+                self.push(&line);
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, arg: &str) {
+        match arg.strip_prefix('@') {
+            Some(excluded) => self.excluded.push(PathBuf::from(excluded)),
+            None => self.paths.push(arg.to_string()),
+        }
+    }
+}
+
+/// `--exclude` and `--include` patterns, matched against full paths.
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    exclude: Option<Regex>,
+    include: Option<Regex>,
+}
+
+impl PathFilter {
+    /// Compile the patterns; a `*i` variant matches case-insensitively and
+    /// takes precedence over its plain one.
+    pub fn new(
+        exclude: Option<&str>,
+        excludei: Option<&str>,
+        include: Option<&str>,
+        includei: Option<&str>,
+    ) -> Result<Self, Error> {
+        let compile = |plain: Option<&str>, folded: Option<&str>| {
+            let (pattern, fold) = match (plain, folded) {
+                (_, Some(pattern)) => (pattern, true),
+                (Some(pattern), None) => (pattern, false),
+                (None, None) => return Ok(None),
+            };
+            RegexBuilder::new(pattern)
+                .case_insensitive(fold)
+                .build()
+                .map(Some)
+        };
+        Ok(Self {
+            exclude: compile(exclude, excludei)?,
+            include: compile(include, includei)?,
+        })
+    }
+
+    /// Whether events on `path` are reported.
+    pub fn allows(&self, path: &str) -> bool {
+        self.exclude.as_ref().is_none_or(|re| !re.is_match(path))
+            && self.include.as_ref().is_none_or(|re| re.is_match(path))
+    }
+}
+
+/// How a [`Session`] watches its paths.
+#[derive(Debug)]
+pub struct WatchOptions {
+    /// Events to watch for.
+    pub mask: EventMask,
+    /// Also watch every directory below the paths, and new ones as they
+    /// appear.
+    pub recursive: bool,
+    /// Directories left out of recursive watches.
+    pub excluded: Vec<PathBuf>,
+    /// Paths whose events are reported.
+    pub filter: PathFilter,
+}
+
+/// An event, named the way inotify-tools name it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedEvent {
+    /// The watched path as given, with a trailing `/` for directories
+    /// (`%w`).
+    pub watched: String,
+    /// Name within the watched directory, empty for events on the watched
+    /// path itself (`%f`).
+    pub name: String,
+    pub mask: EventMask,
+    pub cookie: u32,
+    /// When the event was read.
+    pub time: SystemTime,
+}
+
+impl NamedEvent {
+    /// The path the event happened to.
+    pub fn path(&self) -> String {
+        format!("{}{}", self.watched, self.name)
+    }
+}
+
+/// What a [`Session`] reads.
+#[derive(Debug)]
+pub enum Received {
+    /// An event the filter allows.
+    Event(NamedEvent),
+    /// Reading stopped: every watch is gone, or the connection failed.
+    Closed(Option<ClientError>),
+}
+
+/// Watches on the daemon, held by one connection.
+pub struct Session {
+    client: Client,
+    options: WatchOptions,
+    /// Watched path as printed, per watch descriptor.
+    watches: HashMap<i32, String>,
+    /// The last event, if an `IN_MODIFY` was reported as `IN_CLOSE_WRITE`
+    /// for it: roots that settle writes send the real one right after.
+    stood_in: Option<(i32, Option<std::ffi::OsString>)>,
+}
+
+impl Session {
+    /// Connect to the daemon as `name` (see `fakenotifyd clients`).
+    pub fn connect(name: &str, options: WatchOptions) -> Result<Self, Error> {
+        let mut client = Client::connect()?;
+        client.set_info(ClientInfo {
+            name: Some(name.to_string()),
+            pid: Some(std::process::id() as i32),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        })?;
+        Ok(Self {
+            client,
+            options,
+            watches: HashMap::new(),
+            stood_in: None,
+        })
+    }
+
+    /// Watch `path`, and with `recursive` every directory below it.
+    pub fn watch(&mut self, path: &str) -> Result<(), Error> {
+        let dir = Path::new(path).is_dir();
+        let shown = if dir && !path.ends_with('/') {
+            format!("{path}/")
+        } else {
+            path.to_string()
+        };
+        // The daemon resolves relative paths against its own directory
+        let absolute = std::path::absolute(path)?;
+        let wd = self
+            .client
+            .add_watch(&absolute, daemon_mask(self.options.mask))
+            .map_err(|source| Error::Watch {
+                path: path.to_string(),
+                source,
+            })?;
+        self.watches.insert(wd, shown.clone());
+
+        if dir && self.options.recursive {
+            for entry in std::fs::read_dir(&absolute)?.flatten() {
+                // Like inotifywait, symlinked directories are not followed
+                if !entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                    continue;
+                }
+                let child = format!("{shown}{}", entry.file_name().to_string_lossy());
+                if self.excluded(&child) {
+                    continue;
+                }
+                self.watch(&child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The watched paths as printed, sorted.
+    pub fn watched(&self) -> Vec<String> {
+        let mut watched: Vec<String> = self.watches.values().cloned().collect();
+        watched.sort();
+        watched
+    }
+
+    fn excluded(&self, path: &str) -> bool {
+        let trimmed = Path::new(path.trim_end_matches('/'));
+        // --include picks files to report, not directories to descend into
+        self.options
+            .excluded
+            .iter()
+            .any(|excluded| trimmed.starts_with(excluded))
+            || self
+                .options
+                .filter
+                .exclude
+                .as_ref()
+                .is_some_and(|re| re.is_match(path))
+    }
+
+    /// Block until the next event the filter allows.
+    pub fn next_event(&mut self) -> Received {
+        loop {
+            if self.watches.is_empty() {
+                return Received::Closed(None);
+            }
+            let event = match self.client.read_event() {
+                Ok(event) => event,
+                Err(e) => return Received::Closed(Some(e)),
+            };
+            let Some(mut watched) = self.watches.get(&event.wd).cloned() else {
+                continue;
+            };
+            let mask = as_asked(self.options.mask, event.mask);
+            let stood_in = self.stood_in.take();
+            if mask != event.mask {
+                self.stood_in = Some((event.wd, event.name.clone()));
+            } else if event.mask.contains(EventMask::IN_CLOSE_WRITE)
+                && stood_in == Some((event.wd, event.name.clone()))
+            {
+                continue;
+            }
+            let mut name = event
+                .name
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            // Daemon watches cover whole trees; inotify reports only a
+            // directory's own entries, each against its own directory
+            if let Some((dir, base)) = name.rsplit_once('/') {
+                if !self.options.recursive {
+                    continue;
+                }
+                watched = format!("{watched}{dir}/");
+                name = base.to_string();
+            }
+            if event.mask.contains(EventMask::IN_IGNORED) {
+                self.watches.remove(&event.wd);
+            }
+            let named = NamedEvent {
+                watched,
+                name,
+                mask,
+                cookie: event.cookie,
+                time: SystemTime::now(),
+            };
+            let path = named.path();
+            if self.options.recursive
+                && named.mask.contains(EventMask::IN_ISDIR)
+                && named
+                    .mask
+                    .intersects(EventMask::IN_CREATE | EventMask::IN_MOVED_TO)
+                && !self.excluded(&path)
+                && let Err(e) = self.watch(&path)
+            {
+                eprintln!("{e}");
+            }
+            if self.options.filter.allows(&path) {
+                return Received::Event(named);
+            }
+        }
+    }
+
+    /// Read on a thread of its own, so the caller can stop waiting on a
+    /// timeout or a signal; the last thing sent is [`Received::Closed`].
+    pub fn spawn(mut self) -> Receiver<Received> {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            loop {
+                let received = self.next_event();
+                let closed = matches!(received, Received::Closed(_));
+                if tx.send(received).is_err() || closed {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+}
+
+/// The mask to watch with for `asked`. Polling sees neither opens nor
+/// closes; it reports a write as `IN_MODIFY` once the file has settled
+/// between scans, which then stands in for `IN_CLOSE_WRITE`. Roots with
+/// `settle_scans` follow it with a real `IN_CLOSE_WRITE`, which is dropped
+/// as a repeat.
+fn daemon_mask(asked: EventMask) -> EventMask {
+    if asked.contains(EventMask::IN_CLOSE_WRITE) {
+        asked | EventMask::IN_MODIFY
+    } else {
+        asked
+    }
+}
+
+/// `mask` as reported for a watch asking for `asked`, see [`daemon_mask`].
+fn as_asked(asked: EventMask, mask: EventMask) -> EventMask {
+    if mask.contains(EventMask::IN_MODIFY)
+        && asked.contains(EventMask::IN_CLOSE_WRITE)
+        && !asked.contains(EventMask::IN_MODIFY)
+    {
+        (mask - EventMask::IN_MODIFY) | EventMask::IN_CLOSE_WRITE
+    } else {
+        mask
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Note SIGINT and SIGTERM instead of dying on them, for
+/// [`interrupted`] to report.
+pub fn catch_interrupts() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is
+        // async-signal-safe
+        unsafe { libc::signal(signal, on_signal as *const () as libc::sighandler_t) };
+    }
+}
+
+/// Whether SIGINT or SIGTERM arrived since [`catch_interrupts`].
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets_and_exclusions() {
+        let mut targets = Targets::new(&["/mnt/drop", "@/mnt/drop/tmp"]);
+        let list = std::env::temp_dir().join(format!("fakenotify-tools-{}", std::process::id()));
+        std::fs::write(&list, "/mnt/media\n\n@/mnt/media/.cache\n").unwrap();
+        targets.read_from(&list).unwrap();
+        std::fs::remove_file(&list).unwrap();
+
+        assert_eq!(
+            targets,
+            Targets {
+                paths: vec!["/mnt/drop".to_string(), "/mnt/media".to_string()],
+                excluded: vec![
+                    PathBuf::from("/mnt/drop/tmp"),
+                    PathBuf::from("/mnt/media/.cache")
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_modify_stands_in_for_close_write() {
+        let asked = EventMask::IN_CLOSE_WRITE | EventMask::IN_CREATE;
+        assert_eq!(daemon_mask(asked), asked | EventMask::IN_MODIFY);
+        assert_eq!(
+            as_asked(asked, EventMask::IN_MODIFY),
+            EventMask::IN_CLOSE_WRITE
+        );
+        assert_eq!(
+            as_asked(EventMask::IN_ALL_EVENTS, EventMask::IN_MODIFY),
+            EventMask::IN_MODIFY
+        );
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(Some(r"\.part$"), None, None, Some(r"\.CSV")).unwrap();
+        assert!(filter.allows("/mnt/drop/a.csv"));
+        assert!(!filter.allows("/mnt/drop/a.csv.part"));
+        assert!(!filter.allows("/mnt/drop/a.txt"));
+
+        assert!(PathFilter::default().allows("/anything"));
+        assert!(PathFilter::new(Some("("), None, None, None).is_err());
+    }
+}
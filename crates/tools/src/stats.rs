@@ -0,0 +1,202 @@
+//! Counting events the way `inotifywatch` does.
+//!
+//! Events are counted per watched path, and the table printed at the end
+//! has a row per path and a column per event that occurred, sorted by the
+//! total unless `-a` or `-d` name another column.
+
+use crate::NamedEvent;
+use fakenotify_protocol::EventMask;
+use std::collections::BTreeMap;
+
+/// The columns, in the order `inotifywatch` prints them.
+pub const COLUMNS: &[(EventMask, &str)] = &[
+    (EventMask::IN_ACCESS, "access"),
+    (EventMask::IN_MODIFY, "modify"),
+    (EventMask::IN_ATTRIB, "attrib"),
+    (EventMask::IN_CLOSE_WRITE, "close_write"),
+    (EventMask::IN_CLOSE_NOWRITE, "close_nowrite"),
+    (EventMask::IN_OPEN, "open"),
+    (EventMask::IN_MOVED_FROM, "moved_from"),
+    (EventMask::IN_MOVED_TO, "moved_to"),
+    (EventMask::IN_MOVE_SELF, "move_self"),
+    (EventMask::IN_CREATE, "create"),
+    (EventMask::IN_DELETE, "delete"),
+    (EventMask::IN_DELETE_SELF, "delete_self"),
+    (EventMask::IN_UNMOUNT, "unmount"),
+];
+
+/// The column rows are sorted by; by default the total, descending.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    /// Index into [`COLUMNS`], `None` for the total.
+    pub column: Option<usize>,
+    pub ascending: bool,
+}
+
+impl Sort {
+    /// The column named `name` (`total` or an event), in `ascending` order.
+    pub fn by(name: &str, ascending: bool) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        let column = if name == "total" {
+            None
+        } else {
+            Some(COLUMNS.iter().position(|(_, column)| *column == name)?)
+        };
+        Some(Self { column, ascending })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Row {
+    total: u64,
+    counts: [u64; COLUMNS.len()],
+}
+
+impl Row {
+    fn get(&self, column: Option<usize>) -> u64 {
+        column.map_or(self.total, |column| self.counts[column])
+    }
+}
+
+/// Event counts per watched path.
+#[derive(Debug, Default)]
+pub struct Stats {
+    rows: BTreeMap<String, Row>,
+}
+
+impl Stats {
+    /// Counts for `watched`, all zero, so `-z` lists them even if nothing
+    /// happens there.
+    pub fn new(watched: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            rows: watched
+                .into_iter()
+                .map(|path| (path, Row::default()))
+                .collect(),
+        }
+    }
+
+    /// Count `event` against its watched path.
+    pub fn record(&mut self, event: &NamedEvent) {
+        let row = self.rows.entry(event.watched.clone()).or_default();
+        row.total += 1;
+        for (count, (flag, _)) in row.counts.iter_mut().zip(COLUMNS) {
+            if event.mask.contains(*flag) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Whether no event was counted.
+    pub fn is_empty(&self) -> bool {
+        self.rows.values().all(|row| row.total == 0)
+    }
+
+    /// The table, with columns for the events in `mask`; `zero` keeps rows
+    /// and columns without events.
+    pub fn table(&self, mask: EventMask, sort: Sort, zero: bool) -> String {
+        let columns: Vec<usize> = (0..COLUMNS.len())
+            .filter(|&column| mask.contains(COLUMNS[column].0))
+            .filter(|&column| zero || self.rows.values().any(|row| row.counts[column] > 0))
+            .collect();
+        let mut rows: Vec<(&String, &Row)> = self
+            .rows
+            .iter()
+            .filter(|(_, row)| zero || row.total > 0)
+            .collect();
+        // Stable, so equal rows stay in path order
+        rows.sort_by(|(_, a), (_, b)| {
+            let order = a.get(sort.column).cmp(&b.get(sort.column));
+            if sort.ascending {
+                order
+            } else {
+                order.reverse()
+            }
+        });
+
+        let mut header = vec!["total".to_string()];
+        header.extend(columns.iter().map(|&column| COLUMNS[column].1.to_string()));
+        let body: Vec<Vec<String>> = rows
+            .iter()
+            .map(|(_, row)| {
+                let mut cells = vec![row.total.to_string()];
+                cells.extend(columns.iter().map(|&column| row.counts[column].to_string()));
+                cells
+            })
+            .collect();
+        let widths: Vec<usize> = (0..header.len())
+            .map(|i| {
+                body.iter()
+                    .map(|cells| cells[i].len())
+                    .chain([header[i].len()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let line = |cells: &[String], filename: &str| {
+            let mut line = String::new();
+            for (cell, width) in cells.iter().zip(&widths) {
+                line.push_str(&format!("{cell:<width$}  "));
+            }
+            line.push_str(filename);
+            line.push('\n');
+            line
+        };
+        let mut table = line(&header, "filename");
+        for ((path, _), cells) in rows.iter().zip(&body) {
+            table.push_str(&line(cells, path));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn event(watched: &str, mask: EventMask) -> NamedEvent {
+        NamedEvent {
+            watched: watched.to_string(),
+            name: "f".to_string(),
+            mask,
+            cookie: 0,
+            time: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_table_like_inotifywatch() {
+        let mut stats = Stats::new(["/mnt/a/".to_string(), "/mnt/b/".to_string()]);
+        assert!(stats.is_empty());
+        for _ in 0..12 {
+            stats.record(&event("/mnt/b/", EventMask::IN_MODIFY));
+        }
+        stats.record(&event("/mnt/b/", EventMask::IN_CLOSE_WRITE));
+        stats.record(&event(
+            "/mnt/c/",
+            EventMask::IN_CREATE | EventMask::IN_ISDIR,
+        ));
+        stats.record(&event("/mnt/c/", EventMask::IN_CREATE));
+
+        assert_eq!(
+            stats.table(EventMask::IN_ALL_EVENTS, Sort::default(), false),
+            "total  modify  close_write  create  filename\n\
+             13     12      1            0       /mnt/b/\n\
+             2      0       0            2       /mnt/c/\n"
+        );
+        assert_eq!(
+            stats.table(
+                EventMask::IN_MODIFY | EventMask::IN_DELETE,
+                Sort::by("modify", true).unwrap(),
+                true
+            ),
+            "total  modify  delete  filename\n\
+             0      0       0       /mnt/a/\n\
+             2      0       0       /mnt/c/\n\
+             13     12      0       /mnt/b/\n"
+        );
+        assert_eq!(Sort::by("bogus", false), None);
+    }
+}
@@ -94,6 +94,13 @@ curl -sSL "$RELEASE_URL/libfakenotify_preload-$TARGET.so" -o "$TMP_DIR/libfakeno
 echo "Installing binaries..."
 install -Dm755 "$TMP_DIR/fakenotifyd" "$INSTALL_DIR/bin/fakenotifyd"
 install -Dm755 "$TMP_DIR/libfakenotify_preload.so" "$INSTALL_DIR/lib/libfakenotify_preload.so"
+install -Dm755 "$TMP_DIR/libfakenotify_preload.so" "$INSTALL_DIR/lib/fakenotify/$ARCH/libfakenotify_preload.so"
+
+# 32-bit preload for multilib hosts (optional; older releases lack it)
+if [ "$ARCH" = "x86_64" ] && \
+    curl -fsSL "$RELEASE_URL/libfakenotify_preload-i686-unknown-linux-gnu.so" -o "$TMP_DIR/libfakenotify_preload-i686.so"; then
+    install -Dm755 "$TMP_DIR/libfakenotify_preload-i686.so" "$INSTALL_DIR/lib/fakenotify/i686/libfakenotify_preload.so"
+fi
 
 # Download and install systemd service
 echo "Installing systemd service..."
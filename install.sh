@@ -9,6 +9,23 @@ SYSTEMD_DIR="/etc/systemd/system"
 CONFIG_DIR="/etc/fakenotify"
 OLD_PRELOAD_PATH="/run/fakenotify/libfakenotify_preload.so"
 NEW_PRELOAD_PATH="/usr/local/lib/libfakenotify_preload.so"
+PRELOAD_ARCH_DIR="/usr/local/lib/fakenotify"
+
+# Extra preload builds for multilib hosts, e.g.
+#   PRELOAD_TARGETS="i686-unknown-linux-gnu" sudo -E ./install.sh
+# Each is installed as $PRELOAD_ARCH_DIR/<platform>/libfakenotify_preload.so
+# so `fakenotifyd exec` can let the loader pick one per process.
+PRELOAD_TARGETS="${PRELOAD_TARGETS:-}"
+
+# Map a Rust target triple to the loader's $PLATFORM value
+target_platform() {
+    case "$1" in
+        x86_64-*)          echo "x86_64" ;;
+        i686-*|i586-*)     echo "i686" ;;
+        aarch64-*)         echo "aarch64" ;;
+        *)                 return 1 ;;
+    esac
+}
 
 # Migration function to detect and warn about old paths
 migrate_old_paths() {
@@ -63,8 +80,40 @@ migrate_old_paths() {
 
 echo "Installing FakeNotify..."
 
+# macOS: interposition dylib plus a socket-activated launchd job
+if [ "$(uname -s)" = "Darwin" ]; then
+    cargo build --release -p fakenotifyd -p fakenotify-preload-macos -p fakenotify-tools
+    install -d "$INSTALL_DIR/bin" "$INSTALL_DIR/lib" "$CONFIG_DIR"
+    install -m755 target/release/fakenotifyd "$INSTALL_DIR/bin/fakenotifyd"
+    install -m755 target/release/fakenotify-wait "$INSTALL_DIR/bin/fakenotify-wait"
+    install -m755 target/release/fakenotify-watch "$INSTALL_DIR/bin/fakenotify-watch"
+    install -m755 target/release/libfakenotify_preload_macos.dylib "$INSTALL_DIR/lib/libfakenotify_preload_macos.dylib"
+    if [ ! -f "$CONFIG_DIR/config.toml" ]; then
+        cat > "$CONFIG_DIR/config.toml" << 'EOF'
+[daemon]
+socket = "/var/run/fakenotify.sock"
+log_level = "info"
+
+# Add your NFS/SMB paths here:
+# [[watch]]
+# path = "/Volumes/media"
+# poll_interval = 5
+# recursive = true
+EOF
+    fi
+    install -m644 com.zachhandley.fakenotifyd.plist /Library/LaunchDaemons/com.zachhandley.fakenotifyd.plist
+    launchctl bootout system/com.zachhandley.fakenotifyd 2>/dev/null || true
+    launchctl bootstrap system /Library/LaunchDaemons/com.zachhandley.fakenotifyd.plist
+
+    echo ""
+    echo "Installation complete! The daemon starts on the first connection."
+    echo "Run applications with:"
+    echo "  fakenotifyd exec -- <command>"
+    exit 0
+fi
+
 # Check if built
-if [ ! -f "target/release/fakenotifyd" ] || [ ! -f "target/release/libfakenotify_preload.so" ]; then
+if [ ! -f "target/release/fakenotifyd" ] || [ ! -f "target/release/libfakenotify_preload.so" ] || [ ! -f "target/release/fakenotify-wait" ]; then
     echo "Building first..."
     cargo build --release
 fi
@@ -72,7 +121,22 @@ fi
 # Install binaries
 echo "Installing binaries to $INSTALL_DIR..."
 install -Dm755 target/release/fakenotifyd "$INSTALL_DIR/bin/fakenotifyd"
+install -Dm755 target/release/fakenotify-wait "$INSTALL_DIR/bin/fakenotify-wait"
+install -Dm755 target/release/fakenotify-watch "$INSTALL_DIR/bin/fakenotify-watch"
 install -Dm755 target/release/libfakenotify_preload.so "$INSTALL_DIR/lib/libfakenotify_preload.so"
+install -Dm755 target/release/libfakenotify_preload.so "$PRELOAD_ARCH_DIR/$(uname -m)/libfakenotify_preload.so"
+
+# Install extra preload architectures
+for target in $PRELOAD_TARGETS; do
+    if ! platform=$(target_platform "$target"); then
+        echo "  WARNING: Unsupported preload target $target, skipping"
+        continue
+    fi
+    echo "Building preload for $target..."
+    cargo build --release -p fakenotify-preload --target "$target"
+    install -Dm755 "target/$target/release/libfakenotify_preload.so" \
+        "$PRELOAD_ARCH_DIR/$platform/libfakenotify_preload.so"
+done
 
 # Install systemd service
 echo "Installing systemd service..."